@@ -0,0 +1,104 @@
+#ifndef PAYTOY_H
+#define PAYTOY_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit it by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The transaction was applied
+#define PAYTOY_OK 0
+
+// The transaction was rejected, `paytoy_engine_last_error` tells why
+#define PAYTOY_REJECTED 1
+
+// The engine is null, or the type or the amount can't be parsed
+#define PAYTOY_INVALID_ARGUMENT -1
+
+// The transaction types, as given to `paytoy_engine_push`
+#define PAYTOY_DEPOSIT 0
+
+#define PAYTOY_WITHDRAWAL 1
+
+#define PAYTOY_DISPUTE 2
+
+#define PAYTOY_RESOLVE 3
+
+#define PAYTOY_CHARGEBACK 4
+
+// Only accepted by an engine from `paytoy_engine_new_with_pending_withdrawals`
+#define PAYTOY_SETTLE 5
+
+// Size of the amount buffers of `PaytoyAccount`, enough for any decimal and its terminator
+#define PAYTOY_AMOUNT_SIZE 48
+
+// An engine created by one of the `paytoy_engine_new` functions, opaque to C
+typedef struct PaytoyEngine PaytoyEngine;
+
+// The balances of an account, the amounts are nul-terminated decimal strings
+typedef struct PaytoyAccount {
+  uint16_t client;
+  char available[PAYTOY_AMOUNT_SIZE];
+  char held[PAYTOY_AMOUNT_SIZE];
+  // Withdrawn but not settled yet, always 0 without pending withdrawals
+  char pending_out[PAYTOY_AMOUNT_SIZE];
+  char total[PAYTOY_AMOUNT_SIZE];
+  bool locked;
+} PaytoyAccount;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Creates an engine without any account, to be released with `paytoy_engine_free`
+PaytoyEngine *paytoy_engine_new(void);
+
+// Creates an engine whose withdrawals keep their funds in `pending_out` until a
+// `PAYTOY_SETTLE` with the id of the withdrawal, like `--settle-withdrawals`
+PaytoyEngine *paytoy_engine_new_with_pending_withdrawals(void);
+
+// Applies a transaction to the accounts of the engine
+// `amount` is a decimal string, it may be null for the disputes, resolves and chargebacks.
+// A dispute with an amount only disputes that part of the deposit.
+// Returns `PAYTOY_OK`, `PAYTOY_REJECTED` or `PAYTOY_INVALID_ARGUMENT`
+//
+// # Safety
+// `engine` must come from a `paytoy_engine_new` function and `amount` must be null or a
+// nul-terminated string
+int paytoy_engine_push(PaytoyEngine *engine,
+                       int tx_type,
+                       uint16_t client,
+                       uint32_t tx,
+                       const char *amount);
+
+// The reason code of the last push that failed (e.g. `insufficient_funds`), null if the last
+// push succeeded. The string is owned by the engine and valid until the next push
+//
+// # Safety
+// `engine` must be null or come from a `paytoy_engine_new` function
+const char *paytoy_engine_last_error(const PaytoyEngine *engine);
+
+// Copies the balances of the `client` to `account`
+// Returns false if the engine has no such account, `account` is left untouched then
+//
+// # Safety
+// `engine` must be null or come from a `paytoy_engine_new` function, and `account` must be
+// null or point to a `PaytoyAccount`
+bool paytoy_engine_get_account(const PaytoyEngine *engine,
+                               uint16_t client,
+                               PaytoyAccount *account);
+
+// Releases an engine and all its accounts, null is ignored
+//
+// # Safety
+// `engine` must be null or come from a `paytoy_engine_new` function, and not be used
+// afterwards
+void paytoy_engine_free(PaytoyEngine *engine);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PAYTOY_H */
@@ -1,159 +1,1758 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Instant,
+};
+
+use crossbeam_channel::{Receiver, Sender};
 use hashbrown::HashMap;
 
 use log::*;
+use rust_decimal::Decimal;
 
 use crate::{
-    client_account::ClientAccount,
-    records::{ClientId, TransactionRecord},
+    account_store::{AccountStore, HashMapAccountStore, NewAccountStore},
+    affinity::CoreList,
+    bloom::BloomFilter,
+    cancellation::CancellationToken,
+    client_account::{
+        ClientAccount, DisputeExpiry, DisputePolicy, DomainEvent, SharedHistoryStore,
+        HISTORY_ENTRY_SIZE,
+    },
+    client_filter::ClientFilter,
+    dispatch_schedule::DispatchSchedule,
+    errors::TransactionError,
+    events::{AccountEvent, EventKind, EventSink},
+    history_store::FileHistoryStore,
+    latency::ApplyLatencies,
+    memory::MemoryBudget,
+    merchants::MerchantLedger,
+    period_report::{PeriodReport, PeriodTracker},
+    profile::{Profile, Stage, StageTimer},
+    records::{AccountKey, TransactionId, TransactionRecord, TransactionType},
+    report::{Reject, Report},
+    report_stream::{ReportStream, ShardTurn},
+    sharded_output::ShardedOutput,
+    snapshot::{Snapshot, SnapshotAccount},
+    stats::{PipelineStats, QueueGauge, WorkerStats},
+    trace::TxTrace,
     transactions_reader::TransactionsStream,
+    tx_registry::TxRegistry,
 };
 
-/// The final report after executing all the transactions
-pub struct Report {
-    accounts: HashMap<ClientId, ClientAccount>,
+/// How often (in records) the managers publish their statistics
+const STATS_BATCH: u64 = 64 * 1024;
+/// How many history entries can be added or removed before the memory budget is updated
+const HISTORY_BATCH: i64 = 1024;
+/// How many dispatches in a row must find the queue of a worker full before its new clients
+/// are spilled to the other workers
+const SPILL_AFTER: u32 = 64;
+/// A queue this deep counts as full: the worker takes a record off a full queue before the
+/// dispatcher can add the next one, so it's rarely seen exactly full
+const SPILL_DEPTH: usize = WORKER_QUEUE_SIZE * 9 / 10;
+
+/// The transaction types the locked accounts still accept, none by default
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct LockedPolicy {
+    /// One bit per transaction type
+    allowed: u8,
+}
+
+impl LockedPolicy {
+    /// Accepts the `types` on the locked accounts, e.g. the resolves of their disputes
+    pub fn allowing(types: &[TransactionType]) -> Self {
+        Self {
+            allowed: types
+                .iter()
+                .fold(0, |allowed, tr_type| allowed | 1 << *tr_type as u8),
+        }
+    }
+
+    pub fn allows(&self, tr_type: TransactionType) -> bool {
+        self.allowed & 1 << tr_type as u8 != 0
+    }
+}
+
+/// The transaction types rejected by a run, e.g. the withdrawals of a read-only replay
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct DisabledTypes {
+    /// One bit per transaction type
+    disabled: u8,
 }
 
-impl Report {
-    pub fn report(&self) {
-        // formatting should be nice if the values are not extremly large
-        println!("client,     available,          held,         total,   locked");
-        // since row ordering doens't matter, just report from individual accounts
-        for (_, account) in &self.accounts {
-            println!("{}", account);
+impl DisabledTypes {
+    pub fn of(types: &[TransactionType]) -> Self {
+        Self {
+            disabled: types
+                .iter()
+                .fold(0, |disabled, tr_type| disabled | 1 << *tr_type as u8),
         }
     }
+
+    pub fn contains(&self, tr_type: TransactionType) -> bool {
+        self.disabled & 1 << tr_type as u8 != 0
+    }
+}
+
+/// What happens to the records of a client over its limit
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum OverLimit {
+    /// Apply them, the client is only flagged in the report
+    Flag,
+    /// Reject them as `too_many_transactions`, the client is flagged too
+    Reject,
 }
 
 pub trait AccountManager {
+    /// Applies a single transaction record to the managed accounts
+    /// Returns the reason in case the transaction is rejected. A manager that queues the
+    /// records for later may only report the failures to queue them here, its rejections are
+    /// then in the reports of `finish` and `snapshot`
+    fn apply(&mut self, record: TransactionRecord) -> Result<(), TransactionError>;
+
+    /// Stops accepting transactions and returns the report of all accounts
+    fn finish(self) -> Report;
+
+    /// The current balances of the accounts and the rejects so far, while more transactions
+    /// can still be applied
+    fn snapshot(&mut self) -> Report;
+
+    /// The whole state of the accounts, with the history their disputes need, for the
+    /// snapshots a later run can be restored from
+    fn state(&mut self) -> Vec<SnapshotAccount>;
+
+    /// Executes the transactions on the stream, the rejected ones are logged
+    fn execute(&mut self, transactions: &mut dyn Iterator<Item = TransactionRecord>) {
+        for record in transactions {
+            let (client, tx) = (record.client, record.tx);
+            if let Err(err) = self.apply(record) {
+                error!(client, tx, reason = err.code(); "Transaction failed. {}", err);
+            }
+        }
+    }
+
     /// Executes the transactions on the stream and return the report of all accounts
-    fn execute_transactions(self, transactions: TransactionsStream) -> Report;
+    fn execute_transactions(mut self, mut transactions: TransactionsStream) -> Report
+    where
+        Self: Sized,
+    {
+        self.execute(&mut transactions);
+        self.finish()
+    }
 }
 
 /// Manages client accounts by processing transactions
 pub struct STAccountManager {
     /// A "database" of client accounts
-    accounts: HashMap<ClientId, ClientAccount>,
+    accounts: Box<dyn AccountStore>,
+
+    /// Optional live statistics, published every `STATS_BATCH` records
+    stats: Option<(Arc<PipelineStats>, Arc<WorkerStats>)>,
+    applied: u64,
+    rejected: u64,
+    /// The latencies of the records since they were last published, if they're measured
+    latencies: Option<ApplyLatencies>,
+
+    /// Transactions that couldn't be applied, kept for the report
+    rejects: Vec<Reject>,
+
+    profile: Option<Arc<Profile>>,
+
+    /// Optional memory budget of the transaction history
+    memory: Option<Arc<MemoryBudget>>,
+    /// History entries added since the budget was last updated
+    history_entries: i64,
+
+    /// Optional bound on the history of each account, with the store of the evicted entries
+    history_limit: Option<(usize, SharedHistoryStore)>,
+    /// Detects the duplicates without keeping the history
+    seen: Option<Arc<BloomFilter>>,
+    /// Rejects the ids already used by other clients
+    registry: Option<Arc<TxRegistry>>,
+    dispute_policy: DisputePolicy,
+    locked_policy: LockedPolicy,
+    disabled_types: DisabledTypes,
+    /// Rejects the records of the blocked clients
+    client_filter: Option<Arc<ClientFilter>>,
+    /// How many records of the run each client gets, and what happens to the others
+    transaction_limit: Option<(u64, OverLimit)>,
+    /// Logs the outcome of the records of a transaction id
+    trace: Option<TxTrace>,
+    /// Keep the journal of the accounts
+    journal: bool,
+    /// Publishes the changes of the accounts
+    events: Option<Arc<dyn EventSink>>,
+    /// Where the accounts are written at the end instead of the report
+    sharded_output: Option<Arc<ShardedOutput>>,
+    /// Where the accounts are written in turn at the end instead of the report
+    report_stream: Option<ShardTurn>,
+    /// How long the disputes stay open, in the unit of the timestamps, and what happens then
+    dispute_expiry: Option<(u64, DisputeExpiry)>,
+    /// The disputes that may expire, by the time they do, with the id of the dispute
+    dispute_deadlines: BinaryHeap<Reverse<(u64, AccountKey, TransactionId, u64)>>,
+    /// The latest timestamp of the records, the time of the stream
+    latest_timestamp: Option<u64>,
+    /// Counts the movements of the accounts in the current accounting period
+    periods: Option<PeriodTracker>,
+    /// The withdrawals wait for a `settle` before their funds leave the accounts
+    pending_withdrawals: bool,
+    /// How long the withdrawals wait before they're settled anyway, in the unit of the timestamps
+    settlement_delay: Option<u64>,
+    /// The withdrawals that may be waiting, by the time they're settled
+    settlement_deadlines: BinaryHeap<Reverse<(u64, AccountKey, TransactionId)>>,
+    /// The merchants credited by the payments
+    merchants: Arc<MerchantLedger>,
+    /// Check the accounts after every record, panicking on the first broken invariant
+    check_invariants: bool,
+    /// The schedule a worker records or follows, with the id of the worker
+    schedule: Option<(usize, Arc<DispatchSchedule>)>,
+    /// Stops applying the records once cancelled
+    cancellation: Option<CancellationToken>,
+    /// Sends the outcome of each record executed, to the dispatcher of the FIFO mode
+    acknowledge: Option<Sender<Result<(), TransactionError>>>,
 }
 
 /// A single threaded account manager
 /// One single threaded (the thread where this function is called)
 /// will execute all the transactions
 impl AccountManager for STAccountManager {
-    fn execute_transactions(mut self, transactions: TransactionsStream) -> Report {
-        for record in transactions {
+    fn apply(&mut self, record: TransactionRecord) -> Result<(), TransactionError> {
+        self.process_counted(&record)
+    }
+
+    fn finish(mut self) -> Report {
+        // the end of the stream is as late as its latest record
+        if let Some(now) = self.latest_timestamp {
+            self.expire_disputes(now);
+            self.settle_withdrawals(now);
+        }
+        self.publish_stats();
+        self.publish_history();
+        let cancelled = self.is_cancelled();
+        if let Some(turn) = self.report_stream.take() {
+            turn.write(self.accounts.iter().collect::<Vec<_>>().into_iter());
+            return Report::new(HashMap::new(), self.rejects).with_cancelled(cancelled);
+        }
+        let report = match &self.sharded_output {
+            Some(output) => {
+                output.write_accounts(self.accounts.iter());
+                Report::new(HashMap::new(), self.rejects)
+            }
+            None => Report::new(self.accounts.into_map(), self.rejects),
+        };
+        report.with_cancelled(cancelled)
+    }
+
+    fn snapshot(&mut self) -> Report {
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|account| (account.key(), account.snapshot()))
+            .collect();
+        Report::new(accounts, self.rejects.clone())
+    }
+
+    fn state(&mut self) -> Vec<SnapshotAccount> {
+        self.accounts.iter().map(SnapshotAccount::of).collect()
+    }
+
+    fn execute(&mut self, transactions: &mut dyn Iterator<Item = TransactionRecord>) {
+        let mut timer = StageTimer::start(self.profile.as_ref(), Stage::Apply);
+        // checked before taking a record, a record taken is always applied
+        while !self.is_cancelled() {
+            let record = match transactions.next() {
+                Some(record) => record,
+                None => break,
+            };
             debug!("Processing transaction record: {:?}", record);
-            let client = self.get_or_create_account(record.client);
 
-            if client.is_locked() {
-                warn!(
-                    "Account {} is locked and cannot accept more transactions | {:?}",
-                    client, record
-                );
-                continue;
-            }
-
-            // Just match the proper transaction and log if there's an error
-            let processing_result = match record.tr_type {
-                crate::records::TransactionType::Deposit => match record.amount {
-                    Some(amount) => client.deposit(record.tx, amount),
-                    None => Err(anyhow::anyhow!("Transaction failed due to missing amount")),
-                },
-                crate::records::TransactionType::Withdrawal => match record.amount {
-                    Some(amount) => client.withdraw(record.tx, amount),
-                    None => Err(anyhow::anyhow!("Transaction failed due to missing amount")),
-                },
-                crate::records::TransactionType::Dispute => client.dispute(record.tx),
-                crate::records::TransactionType::Resolve => client.resolve(record.tx),
-                crate::records::TransactionType::ChargeBack => client.chargeback(record.tx),
-            };
+            let result = match self.schedule.clone() {
+                Some((worker, schedule)) => schedule.apply(worker, &record, || {
+                    timer.time(|| self.process_counted(&record))
+                }),
+                None => timer.time(|| self.process_counted(&record)),
+            };
+            if let Some(acknowledge) = &self.acknowledge {
+                let _ = acknowledge.send(result.clone());
+            }
+            if let Err(err) = result {
+                error!(
+                    client = record.client, tx = record.tx, reason = err.code();
+                    "Transaction failed. {} | {:?}", err, record
+                );
+            }
+        }
+    }
+}
+
+impl STAccountManager {
+    pub fn new() -> Self {
+        Self {
+            accounts: Box::new(HashMapAccountStore::new()),
+            stats: None,
+            applied: 0,
+            rejected: 0,
+            latencies: None,
+            rejects: Vec::new(),
+            profile: None,
+            memory: None,
+            history_entries: 0,
+            history_limit: None,
+            seen: None,
+            registry: None,
+            dispute_policy: DisputePolicy::RequireAvailable,
+            locked_policy: LockedPolicy::default(),
+            disabled_types: DisabledTypes::default(),
+            client_filter: None,
+            transaction_limit: None,
+            trace: None,
+            journal: false,
+            events: None,
+            sharded_output: None,
+            report_stream: None,
+            dispute_expiry: None,
+            dispute_deadlines: BinaryHeap::new(),
+            latest_timestamp: None,
+            periods: None,
+            pending_withdrawals: false,
+            settlement_delay: None,
+            settlement_deadlines: BinaryHeap::new(),
+            merchants: Arc::new(MerchantLedger::new()),
+            check_invariants: false,
+            schedule: None,
+            cancellation: None,
+            acknowledge: None,
+        }
+    }
+
+    /// Publish the processing counters and top accounts to the run statistics
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        let worker = stats.register_worker();
+        self.stats = Some((stats, worker));
+        self
+    }
+
+    /// Time the application of the records, in `execute_transactions` only
+    pub fn with_profile(mut self, profile: Arc<Profile>) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Measure the latency of every record, by transaction type, published with the statistics
+    pub fn with_apply_latencies(mut self) -> Self {
+        self.latencies = Some(ApplyLatencies::new());
+        self
+    }
+
+    /// Account the memory taken by the transaction history in the budget
+    pub fn with_memory(mut self, memory: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Keep at most `limit` transactions in the memory of each account, the older ones
+    /// are moved to the `store`
+    pub fn with_history_limit(mut self, limit: usize, store: SharedHistoryStore) -> Self {
+        self.history_limit = Some((limit, store));
+        self
+    }
+
+    /// Don't keep the history of the accounts, the duplicate ids are detected with the
+    /// `seen` filter and the disputes are rejected
+    pub fn with_bloom_filter(mut self, seen: Arc<BloomFilter>) -> Self {
+        self.seen = Some(seen);
+        self
+    }
+
+    /// Reject the deposits and withdrawals reusing the id of another client
+    pub fn with_tx_registry(mut self, registry: Arc<TxRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Picks where the funds held by the disputes come from, `RequireAvailable` by default
+    pub fn with_dispute_policy(mut self, policy: DisputePolicy) -> Self {
+        self.dispute_policy = policy;
+        self
+    }
+
+    /// Picks the transactions still accepted by the locked accounts
+    pub fn with_locked_policy(mut self, policy: LockedPolicy) -> Self {
+        self.locked_policy = policy;
+        self
+    }
+
+    /// Rejects the records of the `disabled` types as `type_disabled`, without applying them
+    /// The resolves of the expired disputes and the settlements after a delay still happen
+    pub fn with_disabled_types(mut self, disabled: DisabledTypes) -> Self {
+        self.disabled_types = disabled;
+        self
+    }
+
+    /// Rejects the records of the clients blocked by `filter` as `client_blocked`
+    pub fn with_client_filter(mut self, filter: Arc<ClientFilter>) -> Self {
+        self.client_filter = Some(filter);
+        self
+    }
+
+    /// Flags the accounts that get more than `limit` records in the run, and rejects the
+    /// records over the limit with `OverLimit::Reject`
+    pub fn with_transaction_limit(mut self, limit: u64, action: OverLimit) -> Self {
+        self.transaction_limit = Some((limit, action));
+        self
+    }
+
+    /// Log every step of the records of the `tx` id
+    pub fn with_trace_tx(mut self, tx: TransactionId) -> Self {
+        self.trace = Some(TxTrace::new(tx));
+        self
+    }
+
+    /// Keep the applied transactions of every account in its journal
+    pub fn with_journal(mut self) -> Self {
+        self.journal = true;
+        self
+    }
+
+    /// Publish an event when a transaction changes the balances of an account or locks it
+    pub fn with_events(mut self, events: Arc<dyn EventSink>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Write the accounts to the shards of `output` when finishing, the report only has the
+    /// rejects then
+    pub fn with_sharded_output(mut self, output: Arc<ShardedOutput>) -> Self {
+        self.sharded_output = Some(output);
+        self
+    }
+
+    /// Write the accounts to `stream` when finishing, the report only has the rejects then
+    pub fn with_report_stream(mut self, stream: Arc<ReportStream>) -> Self {
+        self.report_stream = stream.turns(1).pop();
+        self
+    }
+
+    /// Settle the disputes that get no resolve or chargeback within `period`, in the unit of
+    /// the timestamps of the records. The time only moves with the records that have one
+    pub fn with_dispute_expiry(mut self, period: u64, expiry: DisputeExpiry) -> Self {
+        self.dispute_expiry = Some((period, expiry));
+        self
+    }
+
+    /// Report the movements of the accounts for each period to `report`
+    pub fn with_period_report(mut self, report: Arc<PeriodReport>) -> Self {
+        self.periods = Some(report.tracker());
+        self
+    }
+
+    /// Keep the funds of the withdrawals in `pending_out` until they're settled by a `settle`,
+    /// or after `delay` in the unit of the timestamps of the records
+    pub fn with_pending_withdrawals(mut self, delay: Option<u64>) -> Self {
+        self.pending_withdrawals = true;
+        self.settlement_delay = delay;
+        self
+    }
+
+    /// Credit the merchants of the payments in `ledger`, a ledger of its own by default
+    pub fn with_merchant_ledger(mut self, ledger: Arc<MerchantLedger>) -> Self {
+        self.merchants = ledger;
+        self
+    }
+
+    /// Check the invariants of the account after every record, and panic with the record
+    /// and the balances before and after it when one doesn't hold
+    pub fn with_check_invariants(mut self) -> Self {
+        self.check_invariants = true;
+        self
+    }
+
+    /// Keep the accounts in `store` instead of a hash map, call it before the accounts are
+    /// restored from a snapshot
+    pub fn with_account_store(mut self, store: Box<dyn AccountStore>) -> Self {
+        self.accounts = store;
+        self
+    }
+
+    /// Stop applying the records once `token` is cancelled, the report is then flagged
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Start from the accounts of a snapshot
+    /// Call it after the other options, the accounts are created with them
+    pub fn with_snapshot(mut self, snapshot: &Snapshot) -> Self {
+        self.restore(&snapshot.accounts);
+        self
+    }
+
+    pub(crate) fn restore(&mut self, accounts: &[SnapshotAccount]) {
+        for account in accounts {
+            account.restore(self.get_or_create_account(account.key()));
+        }
+    }
+
+    /// Get the current state of a client account, if the client has been seen
+    pub fn account(&self, key: impl Into<AccountKey>) -> Option<&ClientAccount> {
+        self.accounts.get(key.into())
+    }
+
+    /// Iterate over the current state of all the accounts
+    pub fn accounts(&self) -> impl Iterator<Item = &ClientAccount> {
+        self.accounts.iter()
+    }
+
+    fn process_counted(&mut self, record: &TransactionRecord) -> Result<(), TransactionError> {
+        let started = self.latencies.is_some().then(Instant::now);
+        if let Some(now) = record.timestamp {
+            self.advance_time(now);
+        }
+        let history_len = match &self.memory {
+            Some(_) => self.history_len(AccountKey::of(record)),
+            None => 0,
+        };
+        let result = self.process(record);
+        if let Err(err) = &result {
+            self.rejects.push(Reject::new(record, err.clone()));
+        }
+        if let (Some((period, _)), TransactionType::Dispute, Ok(())) =
+            (self.dispute_expiry, record.tr_type, &result)
+        {
+            // a dispute without a timestamp was opened at the time of the previous record
+            if let Some(opened) = record.timestamp.or(self.latest_timestamp) {
+                let deadline = opened.saturating_add(period);
+                let key = AccountKey::of(record);
+                // the deadline is of this dispute, not of a later one of the same transaction
+                let dispute = self
+                    .accounts
+                    .get(key)
+                    .map_or(0, ClientAccount::latest_dispute);
+                self.dispute_deadlines
+                    .push(Reverse((deadline, key, record.tx, dispute)));
+            }
+        }
+        if let (Some(delay), TransactionType::Withdrawal, Ok(())) =
+            (self.settlement_delay, record.tr_type, &result)
+        {
+            if let Some(made) = record.timestamp.or(self.latest_timestamp) {
+                let deadline = made.saturating_add(delay);
+                self.settlement_deadlines.push(Reverse((
+                    deadline,
+                    AccountKey::of(record),
+                    record.tx,
+                )));
+            }
+        }
+        if let Some(trace) = self.trace.filter(|trace| trace.matches(record)) {
+            self.trace_outcome(trace, record, &result);
+        }
+
+        if let (Some(latencies), Some(started)) = (&mut self.latencies, started) {
+            latencies.record(record.tr_type, started.elapsed().as_nanos() as u64);
+        }
+        if self.stats.is_some() {
+            match result {
+                Ok(_) => self.applied += 1,
+                Err(_) => self.rejected += 1,
+            }
+            if self.applied + self.rejected >= STATS_BATCH {
+                self.publish_stats();
+            }
+        }
+
+        if self.memory.is_some() {
+            // the deposits are kept until their dispute is settled, or moved to the store
+            self.history_entries +=
+                self.history_len(AccountKey::of(record)) as i64 - history_len as i64;
+            if self.history_entries.abs() >= HISTORY_BATCH {
+                self.publish_history();
+            }
+        }
+
+        result
+    }
+
+    fn trace_outcome(
+        &self,
+        trace: TxTrace,
+        record: &TransactionRecord,
+        result: &Result<(), TransactionError>,
+    ) {
+        match (result, self.accounts.get(AccountKey::of(record))) {
+            (Err(err), _) => {
+                trace.step(record, format_args!("rejected as {}. {}", err.code(), err))
+            }
+            (Ok(()), Some(account)) => trace.step(
+                record,
+                format_args!(
+                    "applied, the account has {} available and {} held{}",
+                    account.available(),
+                    account.held(),
+                    if account.is_locked() {
+                        " and is locked"
+                    } else {
+                        ""
+                    }
+                ),
+            ),
+            (Ok(()), None) => trace.step(record, format_args!("applied")),
+        }
+    }
+
+    /// Moves the time of the stream to `now`, settling the disputes that expired, the
+    /// withdrawals and the merchants due by then, and closing the periods that are over.
+    /// The time never goes back
+    pub(crate) fn advance_time(&mut self, now: u64) {
+        let now = self.latest_timestamp.map_or(now, |latest| latest.max(now));
+        self.latest_timestamp = Some(now);
+        self.expire_disputes(now);
+        self.settle_withdrawals(now);
+        self.merchants.advance(now);
+        if let Some(periods) = &mut self.periods {
+            periods.advance(now);
+        }
+    }
+
+    /// Settles the disputes that expired by `now`
+    /// The disputes already settled are skipped, their resolve or chargeback came in time
+    fn expire_disputes(&mut self, now: u64) {
+        let expiry = match self.dispute_expiry {
+            Some((_, expiry)) => expiry,
+            None => return,
+        };
+        while let Some(Reverse((deadline, key, tx, id))) = self.dispute_deadlines.peek().copied() {
+            if deadline > now {
+                break;
+            }
+            self.dispute_deadlines.pop();
+            // settled in time, or a dispute opened before it that expired
+            let dispute = match self
+                .accounts
+                .get(key)
+                .and_then(|account| account.dispute_in_progress(tx, id))
+            {
+                Some(dispute) => dispute,
+                None => continue,
+            };
+            let history_len = self.history_len(key);
+            let expired = match expiry {
+                DisputeExpiry::Resolve => {
+                    // the oldest dispute of its amount, the one that expired
+                    let resolve = TransactionRecord {
+                        tr_type: TransactionType::Resolve,
+                        client: key.client,
+                        tenant: key.tenant,
+                        merchant: None,
+                        tx,
+                        amount: Some(dispute.amount),
+                        timestamp: Some(deadline),
+                        source: None,
+                    };
+                    self.process_account(&resolve)
+                }
+                DisputeExpiry::Expire => self.get_or_create_account(key).expire(tx),
+            };
+            self.history_entries += self.history_len(key) as i64 - history_len as i64;
+            match expired {
+                Ok(()) => {
+                    debug!(client = key.client, tx; "The dispute expired");
+                    match (self.events.clone(), expiry) {
+                        // the expiry is an event of the account, the dispute stays
+                        (Some(events), DisputeExpiry::Expire) => {
+                            let account = self.get_or_create_account(key);
+                            let changes = account.take_events();
+                            publish_events(
+                                events.as_ref(),
+                                TransactionType::Dispute,
+                                account,
+                                changes,
+                            );
+                        }
+                        (Some(events), DisputeExpiry::Resolve) => {
+                            if let Some(account) = self.accounts.get(key) {
+                                events.publish(&AccountEvent {
+                                    kind: EventKind::DisputeExpired,
+                                    account: key,
+                                    tr_type: TransactionType::Resolve,
+                                    tx,
+                                    available: account.available(),
+                                    held: account.held(),
+                                    locked: account.is_locked(),
+                                    change: None,
+                                });
+                            }
+                        }
+                        (None, _) => {}
+                    }
+                }
+                // settled in time
+                Err(TransactionError::NotDisputed | TransactionError::UnknownTransaction) => {}
+                Err(err) => {
+                    warn!(
+                        client = key.client, tx, reason = err.code();
+                        "The expired dispute couldn't be resolved. {}", err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Settles the withdrawals due by `now`
+    /// The withdrawals already settled by a `settle` are skipped
+    fn settle_withdrawals(&mut self, now: u64) {
+        while let Some(Reverse((deadline, key, tx))) = self.settlement_deadlines.peek().copied() {
+            if deadline > now {
+                break;
+            }
+            self.settlement_deadlines.pop();
+            let settle = TransactionRecord {
+                tr_type: TransactionType::Settle,
+                client: key.client,
+                tenant: key.tenant,
+                merchant: None,
+                tx,
+                amount: None,
+                timestamp: Some(deadline),
+                source: None,
+            };
+            match self.process_account(&settle) {
+                Ok(()) => debug!(client = key.client, tx; "The withdrawal was settled"),
+                Err(TransactionError::NotPending) => {}
+                Err(err) => {
+                    warn!(
+                        client = key.client, tx, reason = err.code();
+                        "The withdrawal couldn't be settled. {}", err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Number of transactions in the memory of an account
+    fn history_len(&self, key: AccountKey) -> usize {
+        self.accounts
+            .get(key)
+            .map_or(0, |account| account.history_len())
+    }
+
+    fn publish_history(&mut self) {
+        if let Some(memory) = &self.memory {
+            memory.add_history(self.history_entries * HISTORY_ENTRY_SIZE);
+            self.history_entries = 0;
+        }
+    }
+
+    fn publish_stats(&mut self) {
+        if let Some((stats, worker)) = &self.stats {
+            stats.add_processed(self.applied, self.rejected);
+            worker.add_records(self.applied + self.rejected);
+            worker.set_accounts(self.accounts.len());
+            worker.publish_top_accounts(self.accounts.iter());
+            self.applied = 0;
+            self.rejected = 0;
+            if let Some(latencies) = &mut self.latencies {
+                stats.add_latencies(latencies);
+                *latencies = ApplyLatencies::new();
+            }
+        }
+    }
+
+    fn process(&mut self, record: &TransactionRecord) -> Result<(), TransactionError> {
+        if self.disabled_types.contains(record.tr_type) {
+            return Err(TransactionError::TypeDisabled);
+        }
+        if let Some(filter) = &self.client_filter {
+            if filter.is_blocked(record.client) {
+                return Err(TransactionError::ClientBlocked);
+            }
+        }
+        if let Some((_, action)) = self.transaction_limit {
+            let within = self
+                .get_or_create_account(AccountKey::of(record))
+                .count_transaction();
+            if !within && action == OverLimit::Reject {
+                return Err(TransactionError::TooManyTransactions);
+            }
+        }
+        // only the deposits, withdrawals and payments create an id, the disputes refer to one
+        let claimed = match record.tr_type {
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Payment
+                if self.registry.is_some() =>
+            {
+                // the account is reported even if all its transactions are rejected
+                self.get_or_create_account(AccountKey::of(record));
+                match &self.registry {
+                    Some(registry) => registry.claim(record.tx, AccountKey::of(record))?,
+                    None => false,
+                }
+            }
+            _ => false,
+        };
+        let result = self.process_account(record);
+        if let (true, Err(_), Some(registry)) = (claimed, &result, &self.registry) {
+            // the id stays free for a valid transaction
+            registry.release(record.tx, AccountKey::of(record));
+        }
+        result
+    }
+
+    fn process_account(&mut self, record: &TransactionRecord) -> Result<(), TransactionError> {
+        let locked_policy = self.locked_policy;
+        let check = self.check_invariants;
+        let events = self.events.clone();
+        let merchants = self.merchants.clone();
+        let client = self.get_or_create_account(AccountKey::of(record));
+
+        // the withdrawal was accepted before the account was locked, it still has to settle
+        if client.is_locked()
+            && record.tr_type != TransactionType::Settle
+            && !locked_policy.allows(record.tr_type)
+        {
+            return Err(TransactionError::AccountLocked);
+        }
+
+        let before = (client.available(), client.held());
+        let pending_before = client.pending_out();
+        let was_locked = client.is_locked();
+        // Just match the proper transaction
+        let result = match record.tr_type {
+            TransactionType::Deposit => match record.amount {
+                Some(amount) => client.deposit(record.tx, amount),
+                None => Err(TransactionError::MissingAmount),
+            },
+            TransactionType::Withdrawal => match record.amount {
+                Some(amount) => client.withdraw(record.tx, amount),
+                None => Err(TransactionError::MissingAmount),
+            },
+            TransactionType::Dispute => match record.amount {
+                Some(amount) => client.dispute_amount(record.tx, amount),
+                None => client.dispute(record.tx),
+            },
+            // with an amount, the dispute of that amount when the transaction has several
+            TransactionType::Resolve => match record.amount {
+                Some(amount) => client.resolve_amount(record.tx, amount),
+                None => client.resolve(record.tx),
+            },
+            TransactionType::ChargeBack => match record.amount {
+                Some(amount) => client.chargeback_amount(record.tx, amount),
+                None => client.chargeback(record.tx),
+            },
+            TransactionType::Settle => client.settle(record.tx),
+            // the merchant is credited with the debit of the client, or not at all
+            TransactionType::Payment => match (record.amount, record.merchant) {
+                (None, _) => Err(TransactionError::MissingAmount),
+                (_, None) => Err(TransactionError::MissingMerchant),
+                (Some(amount), Some(merchant)) => client.pay(record.tx, amount).map(|()| {
+                    let merchant = AccountKey::new(record.tenant, merchant);
+                    merchants.credit(merchant, record.tx, amount, record.timestamp)
+                }),
+            },
+        };
+        let total = client.total();
+        if check {
+            let before = (before.0, before.1, pending_before);
+            check_invariants(record, &result, locked_policy, client, before, was_locked);
+        }
+        if result.is_ok() {
+            client.add_to_journal(record, before);
+        }
+        if let Some(events) = &events {
+            let changes = client.take_events();
+            publish_events(events.as_ref(), record.tr_type, client, changes);
+        }
+        if let (Ok(()), Some(periods)) = (&result, &mut self.periods) {
+            periods.add(
+                AccountKey::of(record),
+                record.tr_type,
+                before.0 + before.1,
+                total,
+            );
+        }
+        result
+    }
+
+    fn get_or_create_account(&mut self, key: AccountKey) -> &mut ClientAccount {
+        // the fields are borrowed apart, the store is borrowed mutably
+        let Self {
+            accounts,
+            dispute_policy,
+            history_limit,
+            seen,
+            journal,
+            pending_withdrawals,
+            transaction_limit,
+            events,
+            ..
+        } = self;
+        accounts.get_or_create(key, &mut || {
+            let mut account = ClientAccount::new(key.client)
+                .with_tenant(key.tenant)
+                .with_dispute_policy(*dispute_policy);
+            if let Some((limit, store)) = history_limit {
+                account = account.with_history_limit(*limit, store.clone());
+            }
+            if let Some(seen) = seen {
+                account = account.with_bloom_filter(seen.clone());
+            }
+            if *journal {
+                account = account.with_journal();
+            }
+            if *pending_withdrawals {
+                account = account.with_pending_withdrawals();
+            }
+            if events.is_some() {
+                account = account.with_event_log();
+            }
+            if let Some((limit, _)) = transaction_limit {
+                account = account.with_transaction_limit(*limit);
+            }
+            account
+        })
+    }
+}
+
+/// Publishes the domain events of a transaction applied to the account, with its state after
+/// the transaction
+fn publish_events(
+    events: &dyn EventSink,
+    tr_type: TransactionType,
+    account: &ClientAccount,
+    changes: Vec<DomainEvent>,
+) {
+    for change in changes {
+        let kind = match change {
+            DomainEvent::AccountLocked { .. } => EventKind::AccountLocked,
+            DomainEvent::DisputeExpired { .. } => EventKind::DisputeExpired,
+            _ if change.moves_balances() => EventKind::BalanceChanged,
+            _ => EventKind::AccountUpdated,
+        };
+        events.publish(&AccountEvent {
+            kind,
+            account: account.key(),
+            tr_type,
+            tx: change.tx(),
+            available: account.available(),
+            held: account.held(),
+            locked: account.is_locked(),
+            change: Some(change),
+        });
+    }
+}
+
+/// Checks the account after a record, see `ClientAccount::check_invariants` for the checks of
+/// the balances. The record must not unlock the account, and only move funds if it's applied:
+/// the disputes and resolves don't change the total, and the locked accounts only move with
+/// the records their policy allows
+/// Panics with the record and the balances before and after it when an invariant doesn't hold
+fn check_invariants(
+    record: &TransactionRecord,
+    result: &Result<(), TransactionError>,
+    locked_policy: LockedPolicy,
+    account: &ClientAccount,
+    before: (Decimal, Decimal, Decimal),
+    was_locked: bool,
+) {
+    let after = (account.available(), account.held(), account.pending_out());
+    let moved = after != before;
+    let violation = match account.check_invariants() {
+        Err(violation) => Some(violation),
+        Ok(()) if was_locked && !account.is_locked() => Some("the account was unlocked".into()),
+        Ok(()) if result.is_err() && moved => Some("a rejected record moved funds".into()),
+        Ok(())
+            if was_locked
+                && moved
+                && record.tr_type != TransactionType::Settle
+                && !locked_policy.allows(record.tr_type) =>
+        {
+            Some("a record not allowed by the policy moved the funds of a locked account".into())
+        }
+        Ok(())
+            if matches!(
+                record.tr_type,
+                TransactionType::Dispute | TransactionType::Resolve
+            ) && account.total() != before.0 + before.1 + before.2 =>
+        {
+            Some(format!("a {} changed the total", record.tr_type))
+        }
+        Ok(()) => None,
+    };
+    if let Some(violation) = violation {
+        panic!(
+            "Invariant violated: {}. Record {:?} {}, account {:?} {}: \
+             available {}, held {}, pending out {}, locked {} before; \
+             available {}, held {}, pending out {}, locked {} after",
+            violation,
+            record,
+            match result {
+                Ok(()) => "applied".to_string(),
+                Err(err) => format!("rejected as {}", err.code()),
+            },
+            account.tenant(),
+            account.id(),
+            before.0,
+            before.1,
+            before.2,
+            was_locked,
+            after.0,
+            after.1,
+            after.2,
+            account.is_locked()
+        );
+    }
+}
+
+const WORKER_QUEUE_SIZE: usize = 10000;
+
+/// What the multithreaded manager sends to its workers
+enum WorkerMessage {
+    Record(TransactionRecord),
+    /// Asks for a snapshot of the accounts of the worker
+    Snapshot(Sender<Report>),
+    /// Asks for the whole state of the accounts of the worker
+    State(Sender<Vec<SnapshotAccount>>),
+    /// The latest timestamp of all the records, for the disputes to expire, the withdrawals to
+    /// settle and the periods to close at the end
+    Time(u64),
+}
+
+/// A worker thread of the multithreaded manager, with its input queue
+struct Worker {
+    queue: Sender<WorkerMessage>,
+    handle: JoinHandle<Report>,
+    gauge: Option<Arc<QueueGauge>>,
+    /// Acknowledges each record once it's applied with its outcome, in the FIFO mode
+    applied: Option<Receiver<Result<(), TransactionError>>>,
+}
+
+/// Routes the clients never seen before away from the workers whose queue stays full
+/// A client seen once stays with its worker, which has its account and applies its records in
+/// order. Only the new clients have nothing to conflict with on any worker, so only they move
+struct ClientSpill {
+    /// The worker of each client seen
+    owners: HashMap<AccountKey, usize>,
+    /// How many dispatches in a row found the queue of each worker full
+    full_streaks: Vec<u32>,
+    /// The clients sent to another worker than their shard
+    spilled: u64,
+}
+
+impl ClientSpill {
+    fn new(num_threads: usize) -> Self {
+        Self {
+            owners: HashMap::new(),
+            full_streaks: vec![0; num_threads],
+            spilled: 0,
+        }
+    }
+
+    /// The worker of a client whose shard is `home`, the worker with the shortest queue if
+    /// the client is new and the queue of its shard stays full
+    fn route(&mut self, key: AccountKey, home: usize, queue_len: impl Fn(usize) -> usize) -> usize {
+        if let Some(owner) = self.owners.get(&key) {
+            return *owner;
+        }
+        let mut owner = home;
+        if self.full_streaks[home] >= SPILL_AFTER {
+            // only to a worker with room to spare, not from a full queue to another
+            if let Some((worker, len)) = (0..self.full_streaks.len())
+                .map(|worker| (worker, queue_len(worker)))
+                .min_by_key(|(_, len)| *len)
+            {
+                if len < WORKER_QUEUE_SIZE / 2 {
+                    owner = worker;
+                }
+            }
+        }
+        if owner != home {
+            trace!(
+                "Spilling client {} from worker {} to {}",
+                key.client,
+                home,
+                owner
+            );
+            self.spilled += 1;
+        }
+        self.owners.insert(key, owner);
+        owner
+    }
+
+    /// Counts the dispatches to a worker that found its queue full
+    fn dispatched(&mut self, worker: usize, full: bool) {
+        let streak = &mut self.full_streaks[worker];
+        *streak = if full { streak.saturating_add(1) } else { 0 };
+    }
+}
+
+/// Account manager, but multithreaded
+/// Assigns to each thread a subset of clients, so the work can be distributed more evenly
+/// Workers are started on the first applied transaction
+/// Rejected transactions are only logged by the workers, so `apply` can't report them
+pub struct MTAccountManager {
+    num_threads: usize,
+    workers: Vec<Worker>,
+    stats: Option<Arc<PipelineStats>>,
+    dispatched: u64,
+    profile: Option<Arc<Profile>>,
+    memory: Option<Arc<MemoryBudget>>,
+    /// History limit of the accounts, with the store of each worker
+    history_limit: Option<(usize, Vec<SharedHistoryStore>)>,
+    /// Creates the account store of each worker, a hash map if None
+    account_stores: Option<NewAccountStore>,
+    /// Filter of the transaction ids, shared by the workers
+    seen: Option<Arc<BloomFilter>>,
+    registry: Option<Arc<TxRegistry>>,
+    dispute_policy: DisputePolicy,
+    locked_policy: LockedPolicy,
+    disabled_types: DisabledTypes,
+    client_filter: Option<Arc<ClientFilter>>,
+    transaction_limit: Option<(u64, OverLimit)>,
+    /// Logs the worker the records of a transaction id are routed to
+    trace: Option<TxTrace>,
+    journal: bool,
+    events: Option<Arc<dyn EventSink>>,
+    sharded_output: Option<Arc<ShardedOutput>>,
+    report_stream: Option<Arc<ReportStream>>,
+    /// The accounts of a snapshot for each worker, until the workers are started
+    restored: Option<Vec<Vec<SnapshotAccount>>>,
+    dispute_expiry: Option<(u64, DisputeExpiry)>,
+    period_report: Option<Arc<PeriodReport>>,
+    pending_withdrawals: bool,
+    settlement_delay: Option<u64>,
+    merchants: Arc<MerchantLedger>,
+    check_invariants: bool,
+    /// Measure the latency of the records in the workers
+    latencies: bool,
+    /// Apply a record only once the previous one is applied
+    fifo: bool,
+    schedule: Option<Arc<DispatchSchedule>>,
+    /// The latest timestamp of the records sent to any worker
+    latest_timestamp: Option<u64>,
+    /// Times the dispatching, on the thread that applies the records
+    dispatch_timer: StageTimer,
+    cores: Option<CoreList>,
+    /// The cores of each NUMA node, the workers are spread over the nodes
+    numa_nodes: Option<Vec<CoreList>>,
+    /// Stops dispatching once cancelled, the workers still apply the records queued
+    cancellation: Option<CancellationToken>,
+    /// Sends the new clients of the workers whose queue stays full to the other workers
+    spill: Option<ClientSpill>,
+}
+
+impl AccountManager for MTAccountManager {
+    fn apply(&mut self, record: TransactionRecord) -> Result<(), TransactionError> {
+        if self.workers.is_empty() {
+            self.start_workers();
+        }
+
+        // use a simple round robin strategy, but make sure the same client is always managed by the same thread
+        let key = AccountKey::of(&record);
+        let mut worker_id = key.shard(self.num_threads);
+        if let Some(spill) = &mut self.spill {
+            let workers = &self.workers;
+            worker_id = spill.route(key, worker_id, |worker| workers[worker].queue.len());
+            spill.dispatched(worker_id, workers[worker_id].queue.len() >= SPILL_DEPTH);
+        }
+        if let Some(timestamp) = record.timestamp {
+            self.latest_timestamp = Some(
+                self.latest_timestamp
+                    .map_or(timestamp, |latest| latest.max(timestamp)),
+            );
+        }
+        trace!("Dispatching record {:?} to worker {}", record, worker_id);
+        if let Some(trace) = &self.trace {
+            trace.step(&record, format_args!("routed to worker {}", worker_id));
+        }
+        if let Some(schedule) = &self.schedule {
+            schedule.dispatched(worker_id, &record);
+        }
+        let worker = &self.workers[worker_id];
+        self.dispatch_timer
+            .time(|| worker.queue.send(WorkerMessage::Record(record)))
+            .map_err(|_| TransactionError::WorkerStopped)?;
+        let outcome = match &worker.applied {
+            Some(applied) => applied
+                .recv()
+                .map_err(|_| TransactionError::WorkerStopped)?,
+            None => Ok(()),
+        };
+
+        self.dispatched += 1;
+        if self.dispatched.is_multiple_of(1024) {
+            for worker in &self.workers {
+                if let Some(gauge) = &worker.gauge {
+                    gauge.set(worker.queue.len());
+                }
+            }
+        }
+
+        outcome
+    }
+
+    fn finish(mut self) -> Report {
+        // the restored accounts are reported even if no record was applied
+        if self.workers.is_empty() && self.restored.is_some() {
+            self.start_workers();
+        }
+        if let Some(spill) = &self.spill {
+            info!(
+                "{} new clients spilled from the workers whose queue stayed full",
+                spill.spilled
+            );
+            if let Some(stats) = &self.stats {
+                stats.add_spilled(spill.spilled);
+            }
+        }
+        let mut full_report = Report::new(HashMap::with_capacity(1000), Vec::new())
+            .with_cancelled(self.is_cancelled());
+
+        // a worker only knows the time of its own records
+        if let Some(now) = self.latest_timestamp {
+            for worker in &self.workers {
+                let _ = worker.queue.send(WorkerMessage::Time(now));
+            }
+        }
+
+        // the workers waiting for records that won't come go on
+        if let Some(schedule) = &self.schedule {
+            schedule.end_of_dispatch();
+        }
+
+        // tell the workers that there's no more work
+        let handles: Vec<_> = self
+            .workers
+            .into_iter()
+            .map(|worker| {
+                drop(worker.queue);
+                worker.handle
+            })
+            .collect();
+
+        for handle in handles {
+            match handle.join() {
+                Ok(report) => full_report.merge(report),
+                // the broken invariant is the point of the check, it shall abort the run
+                Err(panic) if self.check_invariants => std::panic::resume_unwind(panic),
+                Err(_) => error!("A manager panicked. Information lost"),
+            }
+        }
+
+        full_report
+    }
+
+    fn snapshot(&mut self) -> Report {
+        let mut full_report = Report::new(HashMap::with_capacity(1000), Vec::new());
+        for report in self.ask_workers(WorkerMessage::Snapshot) {
+            full_report.merge(report);
+        }
+        full_report
+    }
+
+    fn state(&mut self) -> Vec<SnapshotAccount> {
+        self.ask_workers(WorkerMessage::State)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn execute(&mut self, transactions: &mut dyn Iterator<Item = TransactionRecord>) {
+        // checked before taking a record, a record taken is always dispatched
+        while !self.is_cancelled() {
+            let record = match transactions.next() {
+                Some(record) => record,
+                None => break,
+            };
+            let (client, tx) = (record.client, record.tx);
+            // the rejections are logged by the workers
+            if let Err(err @ TransactionError::WorkerStopped) = self.apply(record) {
+                error!(client, tx, reason = err.code(); "Transaction failed. {}", err);
+            }
+        }
+    }
+}
+
+impl MTAccountManager {
+    /// Sends a request to all the workers and waits for their replies
+    /// The requests are queued after the records already dispatched, so the replies cover them
+    fn ask_workers<T>(&mut self, request: impl Fn(Sender<T>) -> WorkerMessage) -> Vec<T> {
+        if self.workers.is_empty() && self.restored.is_some() {
+            self.start_workers();
+        }
+        let replies: Vec<_> = self
+            .workers
+            .iter()
+            .filter_map(|worker| {
+                let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+                worker.queue.send(request(reply_tx)).ok().map(|_| reply_rx)
+            })
+            .collect();
+        replies
+            .into_iter()
+            .filter_map(|reply| match reply.recv() {
+                Ok(reply) => Some(reply),
+                Err(_) => {
+                    error!("A manager stopped before replying. Information lost");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn new(num_threads: usize) -> Self {
+        Self {
+            num_threads,
+            workers: Vec::new(),
+            stats: None,
+            dispatched: 0,
+            profile: None,
+            memory: None,
+            history_limit: None,
+            account_stores: None,
+            seen: None,
+            registry: None,
+            dispute_policy: DisputePolicy::RequireAvailable,
+            locked_policy: LockedPolicy::default(),
+            disabled_types: DisabledTypes::default(),
+            client_filter: None,
+            transaction_limit: None,
+            trace: None,
+            journal: false,
+            events: None,
+            sharded_output: None,
+            report_stream: None,
+            restored: None,
+            dispute_expiry: None,
+            period_report: None,
+            pending_withdrawals: false,
+            settlement_delay: None,
+            merchants: Arc::new(MerchantLedger::new()),
+            check_invariants: false,
+            latencies: false,
+            fifo: false,
+            schedule: None,
+            latest_timestamp: None,
+            dispatch_timer: StageTimer::start(None, Stage::Dispatch),
+            cores: None,
+            numa_nodes: None,
+            cancellation: None,
+            spill: None,
+        }
+    }
+
+    /// Publish the queue depths and the statistics of all workers to the run statistics
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Time the dispatching of the records and their application by each worker
+    pub fn with_profile(mut self, profile: Arc<Profile>) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Account the memory taken by the transaction history of all workers in the budget
+    pub fn with_memory(mut self, memory: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Keep at most `limit` transactions in the memory of each account, the older ones
+    /// are moved to a file of the `dir` directory per worker
+    pub fn with_history_limit(mut self, limit: usize, dir: &Path) -> std::io::Result<Self> {
+        let stores = (0..self.num_threads)
+            .map(|_| -> std::io::Result<SharedHistoryStore> {
+                Ok(Arc::new(Mutex::new(FileHistoryStore::create(dir)?)))
+            })
+            .collect::<std::io::Result<_>>()?;
+        self.history_limit = Some((limit, stores));
+        Ok(self)
+    }
+
+    /// Don't keep the history of the accounts, the duplicate ids are detected with the
+    /// `seen` filter and the disputes are rejected
+    pub fn with_bloom_filter(mut self, seen: Arc<BloomFilter>) -> Self {
+        self.seen = Some(seen);
+        self
+    }
+
+    /// Reject the deposits and withdrawals reusing the id of another client, the registry
+    /// is shared by the workers
+    pub fn with_tx_registry(mut self, registry: Arc<TxRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Picks where the funds held by the disputes come from, `RequireAvailable` by default
+    pub fn with_dispute_policy(mut self, policy: DisputePolicy) -> Self {
+        self.dispute_policy = policy;
+        self
+    }
+
+    /// Picks the transactions still accepted by the locked accounts
+    pub fn with_locked_policy(mut self, policy: LockedPolicy) -> Self {
+        self.locked_policy = policy;
+        self
+    }
+
+    /// Rejects the records of the `disabled` types as `type_disabled`, without applying them
+    /// The resolves of the expired disputes and the settlements after a delay still happen
+    pub fn with_disabled_types(mut self, disabled: DisabledTypes) -> Self {
+        self.disabled_types = disabled;
+        self
+    }
+
+    /// Rejects the records of the clients blocked by `filter` as `client_blocked`, the filter
+    /// is shared by the workers
+    pub fn with_client_filter(mut self, filter: Arc<ClientFilter>) -> Self {
+        self.client_filter = Some(filter);
+        self
+    }
+
+    /// Flags the accounts that get more than `limit` records in the run, and rejects the
+    /// records over the limit with `OverLimit::Reject`
+    pub fn with_transaction_limit(mut self, limit: u64, action: OverLimit) -> Self {
+        self.transaction_limit = Some((limit, action));
+        self
+    }
+
+    /// Log every step of the records of the `tx` id
+    pub fn with_trace_tx(mut self, tx: TransactionId) -> Self {
+        self.trace = Some(TxTrace::new(tx));
+        self
+    }
+
+    /// Keep the applied transactions of every account in its journal
+    pub fn with_journal(mut self) -> Self {
+        self.journal = true;
+        self
+    }
+
+    /// Publish an event when a transaction changes the balances of an account or locks it,
+    /// the sink is shared by the workers
+    pub fn with_events(mut self, events: Arc<dyn EventSink>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Have each worker write its accounts to the shards of `output` when finishing, instead
+    /// of merging them in the report
+    pub fn with_sharded_output(mut self, output: Arc<ShardedOutput>) -> Self {
+        self.sharded_output = Some(output);
+        self
+    }
+
+    /// Have the workers write their accounts to `stream` in turn when finishing, instead of
+    /// merging them in the report
+    pub fn with_report_stream(mut self, stream: Arc<ReportStream>) -> Self {
+        self.report_stream = Some(stream);
+        self
+    }
+
+    /// Settle the disputes that get no resolve or chargeback within `period`, in the unit of
+    /// the timestamps of the records. Each worker only sees the time move with its own records
+    /// until the end of the stream: with the records in time order that doesn't change the
+    /// balances, but may delay the events
+    pub fn with_dispute_expiry(mut self, period: u64, expiry: DisputeExpiry) -> Self {
+        self.dispute_expiry = Some((period, expiry));
+        self
+    }
+
+    /// Report the movements of the accounts for each period to `report`, each worker closes
+    /// the periods of its own accounts
+    pub fn with_period_report(mut self, report: Arc<PeriodReport>) -> Self {
+        self.period_report = Some(report);
+        self
+    }
+
+    /// Keep the funds of the withdrawals in `pending_out` until they're settled by a `settle`,
+    /// or after `delay` in the unit of the timestamps. Like for the disputes, each worker only
+    /// sees the time move with its own records until the end of the stream
+    pub fn with_pending_withdrawals(mut self, delay: Option<u64>) -> Self {
+        self.pending_withdrawals = true;
+        self.settlement_delay = delay;
+        self
+    }
+
+    /// Credit the merchants of the payments in `ledger`, shared by the workers
+    /// A payment is routed to the worker of its client like the other records, which debits
+    /// the client and credits the merchant at once, so the merchants don't need a worker
+    pub fn with_merchant_ledger(mut self, ledger: Arc<MerchantLedger>) -> Self {
+        self.merchants = ledger;
+        self
+    }
+
+    /// Check the invariants of the accounts after every record, a broken one panics the
+    /// worker and then `finish`
+    pub fn with_check_invariants(mut self) -> Self {
+        self.check_invariants = true;
+        self
+    }
+
+    /// Measure the latency of every record in the workers, published with the statistics
+    pub fn with_apply_latencies(mut self) -> Self {
+        self.latencies = true;
+        self
+    }
 
-            if let Err(err) = processing_result {
-                error!("Transaction failed. {} | {:?}", err, record);
-            }
-        }
+    /// Record the order the workers apply the records in to `schedule`, or follow the order
+    /// it recorded. Its number of workers must be the number of threads of the manager
+    pub fn with_dispatch_schedule(mut self, schedule: Arc<DispatchSchedule>) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
 
-        Report {
-            accounts: self.accounts,
-        }
+    /// Dispatch a record only once the previous one is applied, whatever its worker, so the
+    /// logs and the events of the records come in their order. The workers no longer run in
+    /// parallel, it's meant to reproduce the bugs of the concurrency
+    pub fn with_fifo(mut self) -> Self {
+        self.fifo = true;
+        self
     }
-}
 
-impl STAccountManager {
-    pub fn new() -> Self {
-        Self {
-            accounts: HashMap::new(),
-        }
+    /// When the queue of a worker is found full on many dispatches in a row, send the clients
+    /// never seen before to the worker with the shortest queue instead of their shard. A client
+    /// stays with the worker it was first sent to, so its records are still applied in order
+    /// by the worker that has its account
+    pub fn with_client_spill(mut self) -> Self {
+        self.spill = Some(ClientSpill::new(self.num_threads));
+        self
     }
 
-    fn get_or_create_account(&mut self, client_id: ClientId) -> &mut ClientAccount {
-        if !self.accounts.contains_key(&client_id) {
-            self.accounts
-                .insert(client_id, ClientAccount::new(client_id));
-        }
+    /// Pin the workers to these cores, in turn
+    pub fn with_worker_cores(mut self, cores: CoreList) -> Self {
+        self.cores = Some(cores);
+        self
+    }
 
-        self.accounts
-            .get_mut(&client_id)
-            .expect("Invariant: we always have an account since we insert one before that")
+    /// Spread the workers over the NUMA nodes with these cores, each worker running on any
+    /// core of its node. The queue and the accounts of a worker are allocated by the worker
+    /// itself, so the first-touch policy of the kernel puts them in the memory of its node
+    pub fn with_numa_nodes(mut self, nodes: Vec<CoreList>) -> Self {
+        self.numa_nodes = Some(nodes).filter(|nodes| !nodes.is_empty());
+        self
     }
-}
 
-/// Account manager, but multithreaded
-/// Assigns to each thread a subset of clients, so the work can be distributed more evenly
-pub struct MTAccountManager {
-    num_threads: usize,
-}
+    /// Each worker keeps its accounts in a store created by `new_store`, on the thread of
+    /// the worker
+    pub fn with_account_stores(mut self, new_store: NewAccountStore) -> Self {
+        self.account_stores = Some(new_store);
+        self
+    }
 
-impl AccountManager for MTAccountManager {
-    fn execute_transactions(self, transactions: TransactionsStream) -> Report {
-        let mut handles = Vec::new();
-        let mut tx_queues = Vec::new();
-        for _ in 0..self.num_threads {
-            let (queue_tx, queue_rx) = crossbeam_channel::bounded::<TransactionRecord>(10000);
-            tx_queues.push(queue_tx);
-            let handle = std::thread::spawn(move || {
-                // use the single threaded manager here
-                let manager = STAccountManager::new();
-                let report = manager.execute_transactions(Box::new(queue_rx.into_iter()));
-
-                // return the accounts managed the single threaded managers
-                report
-            });
+    /// Stop dispatching the records once `token` is cancelled, the workers apply the records
+    /// already in their queues (at most 10,000 each) and the report is flagged
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
 
-            handles.push(handle);
-        }
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
 
-        // use a simple round robin strategy, but make sure the same client is always managed by the same thread
-        for record in transactions {
-            let worker_id = (record.client % self.num_threads as u16) as usize;
-            trace!("Dispatching record {:?} to worker {}", record, worker_id);
-            if tx_queues[worker_id].send(record).is_err() {
-                break;
-            };
+    /// Start from the accounts of a snapshot, each worker gets the accounts it owns
+    pub fn with_snapshot(mut self, snapshot: &Snapshot) -> Self {
+        let mut shards = vec![Vec::new(); self.num_threads];
+        for account in &snapshot.accounts {
+            shards[account.key().shard(self.num_threads)].push(account.clone());
         }
-        // tell the workers that there's no more work
-        drop(tx_queues);
-
-        let mut full_report = Report {
-            accounts: HashMap::with_capacity(1000),
-        };
+        self.restored = Some(shards);
+        self
+    }
 
-        for handle in handles {
-            if let Ok(report) = handle.join() {
-                for (client_id, account) in report.accounts {
-                    full_report.accounts.insert(client_id, account);
+    fn start_workers(&mut self) {
+        self.dispatch_timer = StageTimer::start(self.profile.as_ref(), Stage::Dispatch);
+        // the restored clients stay with the workers of their shard
+        if let (Some(spill), Some(restored)) = (&mut self.spill, &self.restored) {
+            for (worker_id, accounts) in restored.iter().enumerate() {
+                for account in accounts {
+                    spill.owners.insert(account.key(), worker_id);
                 }
-            } else {
-                error!("A manager panicked. Information lost");
             }
         }
+        let mut turns = self
+            .report_stream
+            .as_ref()
+            .map(|stream| stream.turns(self.num_threads).into_iter());
+        for worker_id in 0..self.num_threads {
+            let (started_tx, started_rx) = crossbeam_channel::bounded(1);
+            let stats = self.stats.clone();
+            let gauge = self.stats.as_ref().map(|stats| {
+                stats.register_queue(format!("worker {}", worker_id), WORKER_QUEUE_SIZE)
+            });
+            let (applied_tx, applied_rx) = self.fifo.then(|| crossbeam_channel::bounded(1)).unzip();
+            let profile = self.profile.clone();
+            let memory = self.memory.clone();
+            let history_limit = self
+                .history_limit
+                .as_ref()
+                .map(|(limit, stores)| (*limit, stores[worker_id].clone()));
+            let account_stores = self.account_stores.clone();
+            let seen = self.seen.clone();
+            let registry = self.registry.clone();
+            let dispute_policy = self.dispute_policy;
+            let locked_policy = self.locked_policy;
+            let disabled_types = self.disabled_types;
+            let client_filter = self.client_filter.clone();
+            let transaction_limit = self.transaction_limit;
+            let trace = self.trace;
+            let journal = self.journal;
+            let events = self.events.clone();
+            let sharded_output = self.sharded_output.clone();
+            let dispute_expiry = self.dispute_expiry;
+            let pending_withdrawals = self.pending_withdrawals;
+            let settlement_delay = self.settlement_delay;
+            let merchants = self.merchants.clone();
+            let check_invariants = self.check_invariants;
+            let latencies = self.latencies;
+            let schedule = self.schedule.clone().map(|schedule| (worker_id, schedule));
+            // registered before the worker starts, so no period is written without it
+            let periods = self.period_report.as_ref().map(|report| report.tracker());
+            let report_stream = turns.as_mut().and_then(Iterator::next);
+            let cores = self.cores.clone();
+            let node = self
+                .numa_nodes
+                .as_ref()
+                .map(|nodes| nodes[worker_id % nodes.len()].clone());
+            let restored = self
+                .restored
+                .as_mut()
+                .map(|shards| std::mem::take(&mut shards[worker_id]));
+            let handle = std::thread::Builder::new()
+                .name(format!("worker {}", worker_id))
+                .spawn(move || {
+                    if let Some(cores) = cores {
+                        cores.pin(worker_id);
+                    }
+                    if let Some(node) = node {
+                        node.pin_any();
+                    }
+                    // allocated by the worker, in the memory of its NUMA node
+                    let (queue_tx, queue_rx) =
+                        crossbeam_channel::bounded::<WorkerMessage>(WORKER_QUEUE_SIZE);
+                    let _ = started_tx.send(queue_tx);
+                    // use the single threaded manager here
+                    let mut manager = STAccountManager::new()
+                        .with_dispute_policy(dispute_policy)
+                        .with_locked_policy(locked_policy)
+                        .with_disabled_types(disabled_types);
+                    if let Some(new_store) = account_stores {
+                        manager = manager.with_account_store(new_store());
+                    }
+                    if let Some(stats) = stats {
+                        manager = manager.with_stats(stats);
+                    }
+                    if let Some(profile) = profile {
+                        manager = manager.with_profile(profile);
+                    }
+                    if let Some(memory) = memory {
+                        manager = manager.with_memory(memory);
+                    }
+                    if let Some((limit, store)) = history_limit {
+                        manager = manager.with_history_limit(limit, store);
+                    }
+                    if let Some(seen) = seen {
+                        manager = manager.with_bloom_filter(seen);
+                    }
+                    if let Some(registry) = registry {
+                        manager = manager.with_tx_registry(registry);
+                    }
+                    if let Some(filter) = client_filter {
+                        manager = manager.with_client_filter(filter);
+                    }
+                    if let Some(trace) = trace {
+                        manager.trace = Some(trace);
+                    }
+                    manager.journal = journal;
+                    manager.events = events.map(|events| events.for_worker().unwrap_or(events));
+                    manager.sharded_output = sharded_output;
+                    manager.report_stream = report_stream;
+                    manager.dispute_expiry = dispute_expiry;
+                    manager.transaction_limit = transaction_limit;
+                    manager.pending_withdrawals = pending_withdrawals;
+                    manager.settlement_delay = settlement_delay;
+                    manager.merchants = merchants;
+                    manager.check_invariants = check_invariants;
+                    if latencies {
+                        manager = manager.with_apply_latencies();
+                    }
+                    manager.schedule = schedule;
+                    manager.periods = periods;
+                    if let Some(restored) = restored {
+                        manager.restore(&restored);
+                    }
 
-        full_report
-    }
-}
+                    let worker_stats = manager.stats.as_ref().map(|(_, worker)| worker.clone());
+                    if let Some(worker) = &worker_stats {
+                        worker.set_id(worker_id);
+                    }
+                    manager.acknowledge = applied_tx;
+                    let mut messages = std::iter::from_fn(move || {
+                        // only the waits on an empty queue are timed
+                        match (queue_rx.try_recv(), &worker_stats) {
+                            (Ok(message), _) => Some(message),
+                            (Err(_), Some(worker)) => {
+                                let waiting = Instant::now();
+                                let message = queue_rx.recv().ok();
+                                worker.add_queue_wait(waiting.elapsed());
+                                message
+                            }
+                            (Err(_), None) => queue_rx.recv().ok(),
+                        }
+                    });
+                    loop {
+                        // apply the records until a snapshot or the state is asked for
+                        let mut request = None;
+                        // the records of a cancelled run already queued are still applied,
+                        // the dispatcher counts them as applied once it handed them over
+                        manager.execute(&mut messages.by_ref().map_while(
+                            |message| match message {
+                                WorkerMessage::Record(record) => Some(record),
+                                message => {
+                                    request = Some(message);
+                                    None
+                                }
+                            },
+                        ));
+                        match request {
+                            Some(WorkerMessage::Snapshot(reply)) => {
+                                let _ = reply.send(manager.snapshot());
+                            }
+                            Some(WorkerMessage::State(reply)) => {
+                                let _ = reply.send(manager.state());
+                            }
+                            Some(WorkerMessage::Time(now)) => manager.advance_time(now),
+                            _ => break,
+                        }
+                    }
 
-impl MTAccountManager {
-    pub fn new(num_threads: usize) -> Self {
-        Self { num_threads }
+                    // return the accounts managed the single threaded managers
+                    manager.finish()
+                })
+                .expect("Failed to start an account manager worker");
+            let queue_tx = started_rx
+                .recv()
+                .expect("An account manager worker stopped before starting");
+
+            self.workers.push(Worker {
+                queue: queue_tx,
+                handle,
+                gauge,
+                applied: applied_rx,
+            });
+        }
     }
 }
 
@@ -162,7 +1761,14 @@ mod tests {
     use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
 
-    use crate::transactions_reader::{self, TransactionCSVReader, TransactionsStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{
+        client_account::DisputeProgress,
+        manager_conformance::{self, ManagerConformance},
+        records::ClientId,
+        transactions_reader::{self, TransactionCSVReader, TransactionsStream},
+    };
 
     use super::*;
 
@@ -175,20 +1781,20 @@ mod tests {
     fn test_basic_transactions(manager: impl AccountManager, transactions: TransactionsStream) {
         let report = manager.execute_transactions(transactions);
 
-        let account1 = report.accounts.get(&1).unwrap();
-        let account2 = report.accounts.get(&2).unwrap();
+        let account1 = report.account(1).unwrap();
+        let account2 = report.account(2).unwrap();
 
         assert_eq!(account1.id(), 1);
         assert_eq!(account1.available(), dec!(1.5));
         assert_eq!(account1.held(), dec!(0.0));
         assert_eq!(account1.total(), dec!(1.5));
-        assert_eq!(account1.is_locked(), false);
+        assert!(!account1.is_locked());
 
         assert_eq!(account2.id(), 2);
         assert_eq!(account2.available(), dec!(2.0));
         assert_eq!(account2.held(), dec!(0.0));
         assert_eq!(account2.total(), dec!(2.0));
-        assert_eq!(account2.is_locked(), false);
+        assert!(!account2.is_locked());
     }
 
     #[test]
@@ -215,13 +1821,13 @@ mod tests {
     fn test_locked_client(manager: impl AccountManager, transactions: TransactionsStream) {
         let report = manager.execute_transactions(transactions);
 
-        let account = report.accounts.get(&1).unwrap();
+        let account = report.account(1).unwrap();
 
         assert_eq!(account.id(), 1);
         assert_eq!(account.available(), dec!(2.5));
         assert_eq!(account.held(), dec!(0.0));
         assert_eq!(account.total(), dec!(2.5));
-        assert_eq!(account.is_locked(), true);
+        assert!(account.is_locked());
     }
 
     #[test]
@@ -260,16 +1866,919 @@ mod tests {
 
         let mt_report = manager.execute_transactions(transactions);
 
-        for client_id in 1..u16::max_value() {
+        for client_id in 1..u16::MAX as ClientId {
             let expected = Decimal::from(client_id);
-            assert_eq!(
-                st_report.accounts.get(&client_id).unwrap().total(),
-                expected
-            );
-            assert_eq!(
-                mt_report.accounts.get(&client_id).unwrap().total(),
-                expected
-            );
+            assert_eq!(st_report.account(client_id).unwrap().total(), expected);
+            assert_eq!(mt_report.account(client_id).unwrap().total(), expected);
+        }
+    }
+
+    #[test]
+    fn test_conformance_mt() {
+        ManagerConformance::new(|| MTAccountManager::new(4)).check_all();
+        ManagerConformance::new(|| MTAccountManager::new(4).with_client_spill()).check_all();
+        // the locked accounts accept the settlements of their disputes
+        ManagerConformance::new(|| {
+            MTAccountManager::new(3)
+                .with_dispute_policy(DisputePolicy::AllowNegativeAvailable)
+                .with_locked_policy(LockedPolicy::allowing(&[TransactionType::Resolve]))
+        })
+        .with_reference(|| {
+            STAccountManager::new()
+                .with_dispute_policy(DisputePolicy::AllowNegativeAvailable)
+                .with_locked_policy(LockedPolicy::allowing(&[TransactionType::Resolve]))
+        })
+        .check_all();
+    }
+
+    #[test]
+    fn test_client_spill() {
+        let mut spill = ClientSpill::new(3);
+        let lens = [WORKER_QUEUE_SIZE, WORKER_QUEUE_SIZE / 4, 3];
+        let queue_len = |worker: usize| lens[worker];
+        assert_eq!(spill.route(1.into(), 0, queue_len), 0);
+        for _ in 0..SPILL_AFTER {
+            spill.dispatched(0, true);
+        }
+        // a new client goes to the shortest queue, a known one stays
+        assert_eq!(spill.route(2.into(), 0, queue_len), 2);
+        assert_eq!(spill.route(1.into(), 0, queue_len), 0);
+        assert_eq!(spill.route(2.into(), 0, |_| 0), 2);
+        // the clients of the other shards don't move
+        assert_eq!(spill.route(3.into(), 1, queue_len), 1);
+        assert_eq!(spill.spilled, 1);
+
+        // the queue emptied
+        spill.dispatched(0, false);
+        assert_eq!(spill.route(4.into(), 0, queue_len), 0);
+
+        // no worker has room to spare
+        for _ in 0..SPILL_AFTER {
+            spill.dispatched(0, true);
+        }
+        assert_eq!(spill.route(5.into(), 0, |_| WORKER_QUEUE_SIZE), 0);
+        assert_eq!(spill.spilled, 1);
+    }
+
+    /// Counts the accounts created in the stores of a manager
+    struct CountingStore {
+        inner: HashMapAccountStore,
+        created: Arc<AtomicUsize>,
+    }
+
+    impl AccountStore for CountingStore {
+        fn get_or_create(
+            &mut self,
+            key: AccountKey,
+            create: &mut dyn FnMut() -> ClientAccount,
+        ) -> &mut ClientAccount {
+            let created = &self.created;
+            self.inner.get_or_create(key, &mut || {
+                created.fetch_add(1, Ordering::Relaxed);
+                create()
+            })
+        }
+
+        fn get(&self, key: AccountKey) -> Option<&ClientAccount> {
+            self.inner.get(key)
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = &ClientAccount> + '_> {
+            self.inner.iter()
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        fn into_map(self: Box<Self>) -> HashMap<AccountKey, ClientAccount> {
+            Box::new(self.inner).into_map()
+        }
+    }
+
+    /// Takes its time with the account of client 1, so the queue of its worker stays full
+    struct SlowStore {
+        inner: HashMapAccountStore,
+    }
+
+    impl AccountStore for SlowStore {
+        fn get_or_create(
+            &mut self,
+            key: AccountKey,
+            create: &mut dyn FnMut() -> ClientAccount,
+        ) -> &mut ClientAccount {
+            if key.client == 1 {
+                std::thread::sleep(std::time::Duration::from_micros(20));
+            }
+            self.inner.get_or_create(key, create)
+        }
+
+        fn get(&self, key: AccountKey) -> Option<&ClientAccount> {
+            self.inner.get(key)
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = &ClientAccount> + '_> {
+            self.inner.iter()
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        fn into_map(self: Box<Self>) -> HashMap<AccountKey, ClientAccount> {
+            Box::new(self.inner).into_map()
+        }
+    }
+
+    #[test]
+    fn test_spilled_clients() {
+        // every other record is on the hot client, the others on a new client each
+        let records: Vec<_> = (1..=30_000)
+            .map(|tx| {
+                let client = if tx % 2 == 0 { 1 } else { 2 + tx / 2 };
+                record(
+                    TransactionType::Deposit,
+                    client as ClientId,
+                    tx,
+                    Some(dec!(1)),
+                )
+            })
+            .collect();
+        let stats = Arc::new(PipelineStats::new());
+        let report = MTAccountManager::new(2)
+            .with_account_stores(Arc::new(|| {
+                Box::new(SlowStore {
+                    inner: HashMapAccountStore::new(),
+                })
+            }))
+            .with_stats(stats.clone())
+            .with_client_spill()
+            .execute_transactions(Box::new(records.clone().into_iter()));
+        assert!(stats.clients_spilled() > 0);
+        // the spilled clients are on the other worker, with their records
+        let hot = AccountKey::from(1 as ClientId).shard(2);
+        let other = stats.workers()[1 - hot].accounts();
+        let homed = (2..=15_001)
+            .filter(|&client| AccountKey::from(client as ClientId).shard(2) != hot)
+            .count();
+        assert_eq!(other as u64, homed as u64 + stats.clients_spilled());
+        let expected = STAccountManager::new().execute_transactions(Box::new(records.into_iter()));
+        manager_conformance::assert_same_reports("spilled", &report, &expected);
+
+        // an even load keeps every client on its shard
+        let records = (1..=30_000).map(|tx| {
+            record(
+                TransactionType::Deposit,
+                (tx % 1000 + 1) as ClientId,
+                tx,
+                Some(dec!(1)),
+            )
+        });
+        let stats = Arc::new(PipelineStats::new());
+        MTAccountManager::new(2)
+            .with_stats(stats.clone())
+            .with_client_spill()
+            .execute_transactions(Box::new(records));
+        assert_eq!(stats.clients_spilled(), 0);
+    }
+
+    #[test]
+    fn test_account_stores() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let new_store: NewAccountStore = {
+            let created = created.clone();
+            Arc::new(move || {
+                Box::new(CountingStore {
+                    inner: HashMapAccountStore::new(),
+                    created: created.clone(),
+                })
+            })
+        };
+        let st_store = new_store.clone();
+        ManagerConformance::new(move || STAccountManager::new().with_account_store(st_store()))
+            .check_all();
+        let st_created = created.swap(0, Ordering::Relaxed);
+        assert!(st_created > 0);
+        ManagerConformance::new(move || {
+            MTAccountManager::new(3).with_account_stores(new_store.clone())
+        })
+        .check_all();
+        // the same accounts, spread over the stores of the workers
+        assert_eq!(created.load(Ordering::Relaxed), st_created);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_numa_nodes() {
+        // two nodes of the same core, the workers alternate between them
+        let node = CoreList::parse("0").unwrap();
+        let manager =
+            move || MTAccountManager::new(3).with_numa_nodes(vec![node.clone(), node.clone()]);
+        ManagerConformance::new(manager).check_all();
+    }
+
+    fn record(
+        tr_type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount,
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    // Push transactions one by one and check the rejections are reported
+    fn test_incremental_apply(mut manager: impl AccountManager) {
+        assert!(manager
+            .apply(record(TransactionType::Deposit, 1, 1, Some(dec!(5.0))))
+            .is_ok());
+        assert!(manager
+            .apply(record(TransactionType::Deposit, 2, 2, Some(dec!(3.0))))
+            .is_ok());
+        assert!(manager
+            .apply(record(TransactionType::Dispute, 1, 1, None))
+            .is_ok());
+        assert!(manager
+            .apply(record(TransactionType::ChargeBack, 1, 1, None))
+            .is_ok());
+
+        let report = manager.finish();
+
+        let account1 = report.account(1).unwrap();
+        assert_eq!(account1.total(), dec!(0.0));
+        assert!(account1.is_locked());
+
+        let account2 = report.account(2).unwrap();
+        assert_eq!(account2.available(), dec!(3.0));
+    }
+
+    #[test]
+    fn test_incremental_apply_st() {
+        let mut manager = STAccountManager::new();
+
+        assert!(manager
+            .apply(record(TransactionType::Deposit, 1, 1, Some(dec!(5.0))))
+            .is_ok());
+        assert_eq!(manager.account(1).unwrap().available(), dec!(5.0));
+        assert!(manager.account(2).is_none());
+
+        assert_eq!(
+            manager.apply(record(TransactionType::Withdrawal, 1, 2, None)),
+            Err(TransactionError::MissingAmount)
+        );
+        assert_eq!(
+            manager.apply(record(TransactionType::Deposit, 1, 1, Some(dec!(5.0)))),
+            Err(TransactionError::DuplicateTransaction)
+        );
+
+        test_incremental_apply(STAccountManager::new());
+    }
+
+    #[test]
+    fn test_incremental_apply_mt() {
+        test_incremental_apply(MTAccountManager::new(2));
+    }
+
+    // Resolve a dispute of an account locked by the chargeback of another one
+    fn test_locked_policy(mut manager: impl AccountManager) {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(5.0))),
+            record(TransactionType::Deposit, 1, 2, Some(dec!(3.0))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 1, 2, None),
+            record(TransactionType::ChargeBack, 1, 1, None),
+            record(TransactionType::Resolve, 1, 2, None),
+            record(TransactionType::Deposit, 1, 3, Some(dec!(1.0))),
+        ];
+        manager.execute(&mut records.into_iter());
+        let report = manager.finish();
+
+        let account = report.account(1).unwrap();
+        assert_eq!(account.available(), dec!(3.0));
+        assert_eq!(account.held(), dec!(0.0));
+        assert!(account.is_locked());
+        let rejects: Vec<_> = report.rejects().iter().map(|reject| reject.tx).collect();
+        assert_eq!(rejects, vec![3]);
+    }
+
+    #[test]
+    fn test_locked_policy_st() {
+        let policy =
+            LockedPolicy::allowing(&[TransactionType::Resolve, TransactionType::ChargeBack]);
+        assert!(policy.allows(TransactionType::Resolve));
+        assert!(!policy.allows(TransactionType::Deposit));
+        assert!(!LockedPolicy::default().allows(TransactionType::Resolve));
+
+        test_locked_policy(STAccountManager::new().with_locked_policy(policy));
+    }
+
+    #[test]
+    fn test_locked_policy_mt() {
+        let policy =
+            LockedPolicy::allowing(&[TransactionType::Resolve, TransactionType::ChargeBack]);
+        test_locked_policy(MTAccountManager::new(2).with_locked_policy(policy));
+    }
+
+    fn test_disabled_types(manager: impl AccountManager) {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(5.0))),
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(3.0))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Deposit, 2, 3, Some(dec!(1.0))),
+        ];
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+
+        let account = report.account(1).unwrap();
+        assert_eq!(account.available(), dec!(5.0));
+        assert_eq!(account.held(), dec!(0.0));
+        assert_eq!(report.account(2).unwrap().total(), dec!(1.0));
+        assert_eq!(report.reject_summary(0), vec![("type_disabled", 2)]);
+    }
+
+    #[test]
+    fn test_disabled_types_st() {
+        let disabled = DisabledTypes::of(&[TransactionType::Withdrawal, TransactionType::Dispute]);
+        assert!(disabled.contains(TransactionType::Dispute));
+        assert!(!disabled.contains(TransactionType::Deposit));
+
+        test_disabled_types(STAccountManager::new().with_disabled_types(disabled));
+    }
+
+    #[test]
+    fn test_disabled_types_mt() {
+        let disabled = DisabledTypes::of(&[TransactionType::Withdrawal, TransactionType::Dispute]);
+        test_disabled_types(MTAccountManager::new(2).with_disabled_types(disabled));
+    }
+
+    fn test_partial_dispute(manager: impl AccountManager) {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10.0))),
+            record(TransactionType::Dispute, 1, 1, Some(dec!(4.0))),
+            record(TransactionType::ChargeBack, 1, 1, None),
+            record(TransactionType::Deposit, 2, 2, Some(dec!(10.0))),
+            record(TransactionType::Dispute, 2, 2, Some(dec!(12.0))),
+        ];
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+
+        let account = report.account(1).unwrap();
+        assert_eq!(account.total(), dec!(6.0));
+        assert!(account.is_locked());
+        assert_eq!(report.account(2).unwrap().held(), dec!(0.0));
+        assert_eq!(
+            report.reject_summary(0),
+            vec![("invalid_dispute_amount", 1)]
+        );
+    }
+
+    #[test]
+    fn test_partial_dispute_st() {
+        test_partial_dispute(STAccountManager::new());
+    }
+
+    #[test]
+    fn test_partial_dispute_mt() {
+        test_partial_dispute(MTAccountManager::new(2));
+    }
+
+    fn test_check_invariants(manager: impl AccountManager) {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10.0))),
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(8.0))),
+            record(TransactionType::Dispute, 1, 1, Some(dec!(4.0))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Withdrawal, 1, 3, Some(dec!(1.0))),
+            record(TransactionType::Resolve, 1, 1, Some(dec!(4.0))),
+            record(TransactionType::ChargeBack, 1, 1, None),
+            record(TransactionType::Deposit, 1, 4, Some(dec!(1.0))),
+        ];
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+
+        let account = report.account(1).unwrap();
+        assert_eq!(account.available(), dec!(-4.0));
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn test_check_invariants_st() {
+        test_check_invariants(
+            STAccountManager::new()
+                .with_dispute_policy(DisputePolicy::AllowNegativeAvailable)
+                .with_check_invariants(),
+        );
+    }
+
+    #[test]
+    fn test_check_invariants_mt() {
+        test_check_invariants(
+            MTAccountManager::new(2)
+                .with_dispute_policy(DisputePolicy::AllowNegativeAvailable)
+                .with_check_invariants(),
+        );
+    }
+
+    /// An account holding funds without a dispute
+    fn broken_snapshot() -> Snapshot {
+        Snapshot {
+            engine: String::new(),
+            accounts: vec![SnapshotAccount {
+                tenant: None,
+                client: 1,
+                available: dec!(1.0),
+                held: dec!(5.0),
+                locked: false,
+                history: Vec::new(),
+                pending_withdrawals: Vec::new(),
+            }],
+            inputs: Vec::new(),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invariant violated: held funds 5.0 but the disputes hold 0")]
+    fn test_broken_invariant_st() {
+        let manager = STAccountManager::new()
+            .with_check_invariants()
+            .with_snapshot(&broken_snapshot());
+        let records = vec![record(TransactionType::Deposit, 1, 1, Some(dec!(1.0)))];
+        manager.execute_transactions(Box::new(records.into_iter()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invariant violated")]
+    fn test_broken_invariant_mt() {
+        let manager = MTAccountManager::new(2)
+            .with_check_invariants()
+            .with_snapshot(&broken_snapshot());
+        let records = vec![record(TransactionType::Deposit, 1, 1, Some(dec!(1.0)))];
+        manager.execute_transactions(Box::new(records.into_iter()));
+    }
+
+    /// Keeps the ids of the records that changed an account, in the order of the events
+    struct AppliedOrder(Mutex<Vec<TransactionId>>);
+
+    impl EventSink for AppliedOrder {
+        fn publish(&self, event: &AccountEvent) {
+            self.0.lock().unwrap().push(event.tx);
+        }
+
+        fn flush(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fifo_mt() {
+        let order = Arc::new(AppliedOrder(Mutex::new(Vec::new())));
+        let manager = MTAccountManager::new(4)
+            .with_fifo()
+            .with_events(order.clone());
+        let records: Vec<_> = (1..500)
+            .map(|tx| {
+                record(
+                    TransactionType::Deposit,
+                    (tx % 7) as ClientId,
+                    tx,
+                    Some(dec!(1.0)),
+                )
+            })
+            .collect();
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+
+        assert_eq!(report.account(3).unwrap().total(), dec!(71.0));
+        // the events of all the workers come in the order of the records
+        assert_eq!(*order.0.lock().unwrap(), (1..500).collect::<Vec<_>>());
+
+        // the rejections come back from the workers
+        let mut manager = MTAccountManager::new(2).with_fifo();
+        let deposit = record(TransactionType::Deposit, 1, 1, Some(dec!(2.0)));
+        assert_eq!(manager.apply(deposit), Ok(()));
+        let withdrawal = record(TransactionType::Withdrawal, 1, 2, Some(dec!(5.0)));
+        assert!(matches!(
+            manager.apply(withdrawal),
+            Err(TransactionError::InsufficientFunds { .. })
+        ));
+        let resolve = record(TransactionType::Resolve, 2, 3, None);
+        assert!(manager.apply(resolve).is_err());
+        assert_eq!(manager.finish().rejects().len(), 2);
+    }
+
+    fn test_client_filter(manager: impl AccountManager) {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(5.0))),
+            record(TransactionType::Deposit, 2, 2, Some(dec!(3.0))),
+            record(TransactionType::Deposit, 3, 3, Some(dec!(1.0))),
+            record(TransactionType::Dispute, 2, 2, None),
+        ];
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+
+        assert_eq!(report.account(1).unwrap().total(), dec!(5.0));
+        // the blocked clients don't get an account
+        assert!(report.account(2).is_none());
+        assert!(report.account(3).is_none());
+        assert_eq!(report.reject_summary(0), vec![("client_blocked", 3)]);
+    }
+
+    #[test]
+    fn test_client_filter_st() {
+        let filter = ClientFilter::new()
+            .with_allowed(vec![1, 2])
+            .with_blocked(vec![2]);
+        test_client_filter(STAccountManager::new().with_client_filter(Arc::new(filter)));
+    }
+
+    #[test]
+    fn test_client_filter_mt() {
+        let filter = ClientFilter::new()
+            .with_allowed(vec![1, 2])
+            .with_blocked(vec![2]);
+        test_client_filter(MTAccountManager::new(2).with_client_filter(Arc::new(filter)));
+    }
+
+    fn test_apply_latencies(manager: impl AccountManager, stats: &PipelineStats) {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(5.0))),
+            record(TransactionType::Deposit, 2, 2, Some(dec!(3.0))),
+            record(TransactionType::Withdrawal, 2, 3, Some(dec!(9.0))),
+            record(TransactionType::Dispute, 1, 1, None),
+        ];
+        manager.execute_transactions(Box::new(records.into_iter()));
+
+        // the rejected records are measured too
+        let latencies = stats.latencies();
+        let counts: Vec<_> = latencies
+            .histograms()
+            .map(|(tr_type, histogram)| (tr_type, histogram.count()))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (TransactionType::Deposit, 2),
+                (TransactionType::Withdrawal, 1),
+                (TransactionType::Dispute, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_latencies_st() {
+        let stats = Arc::new(PipelineStats::new());
+        let manager = STAccountManager::new()
+            .with_stats(stats.clone())
+            .with_apply_latencies();
+        test_apply_latencies(manager, &stats);
+    }
+
+    #[test]
+    fn test_apply_latencies_mt() {
+        let stats = Arc::new(PipelineStats::new());
+        let manager = MTAccountManager::new(2)
+            .with_stats(stats.clone())
+            .with_apply_latencies();
+        test_apply_latencies(manager, &stats);
+    }
+
+    #[test]
+    fn test_worker_stats_mt() {
+        let stats = Arc::new(PipelineStats::new());
+        let manager = MTAccountManager::new(2).with_stats(stats.clone());
+        let records = (1..=10)
+            .map(|tx| {
+                record(
+                    TransactionType::Deposit,
+                    tx as ClientId % 3,
+                    tx,
+                    Some(dec!(1)),
+                )
+            })
+            .collect::<Vec<_>>();
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+
+        let workers = stats.workers();
+        let ids: Vec<_> = workers.iter().map(|worker| worker.id()).collect();
+        assert_eq!(ids, vec![0, 1]);
+        let records: u64 = workers.iter().map(|worker| worker.records()).sum();
+        assert_eq!(records, 10);
+        let accounts: usize = workers.iter().map(|worker| worker.accounts()).sum();
+        assert_eq!(accounts, report.accounts().count());
+        for worker in &workers {
+            let shard = (0..3)
+                .filter(|&client| AccountKey::from(client as ClientId).shard(2) == worker.id())
+                .count();
+            assert_eq!(worker.accounts(), shard);
+        }
+    }
+
+    fn test_transaction_limit(manager: impl AccountManager, action: OverLimit) {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(5.0))),
+            record(TransactionType::Deposit, 1, 2, Some(dec!(3.0))),
+            record(TransactionType::Deposit, 1, 3, Some(dec!(1.0))),
+            record(TransactionType::Deposit, 2, 4, Some(dec!(1.0))),
+        ];
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+
+        assert!(report.account(1).unwrap().is_over_limit());
+        assert!(!report.account(2).unwrap().is_over_limit());
+        match action {
+            OverLimit::Flag => {
+                assert_eq!(report.account(1).unwrap().total(), dec!(9.0));
+                assert!(report.rejects().is_empty());
+            }
+            OverLimit::Reject => {
+                assert_eq!(report.account(1).unwrap().total(), dec!(8.0));
+                assert_eq!(report.reject_summary(0), vec![("too_many_transactions", 1)]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_transaction_limit_st() {
+        for action in [OverLimit::Flag, OverLimit::Reject] {
+            let manager = STAccountManager::new().with_transaction_limit(2, action);
+            test_transaction_limit(manager, action);
+        }
+    }
+
+    #[test]
+    fn test_transaction_limit_mt() {
+        for action in [OverLimit::Flag, OverLimit::Reject] {
+            let manager = MTAccountManager::new(2).with_transaction_limit(2, action);
+            test_transaction_limit(manager, action);
+        }
+    }
+
+    // The same client and tx ids under two tenants are separate accounts and transactions
+    fn test_tenants(mut manager: impl AccountManager) {
+        let tenant = |tenant, mut record: TransactionRecord| {
+            record.tenant = tenant;
+            record
+        };
+        for &(tenant_id, amount) in &[
+            (None, dec!(1.0)),
+            (Some(1), dec!(2.0)),
+            (Some(2), dec!(3.0)),
+        ] {
+            assert!(manager
+                .apply(tenant(
+                    tenant_id,
+                    record(TransactionType::Deposit, 1, 1, Some(amount))
+                ))
+                .is_ok());
+        }
+        assert!(manager
+            .apply(tenant(
+                Some(1),
+                record(TransactionType::Dispute, 1, 1, None)
+            ))
+            .is_ok());
+        assert!(manager
+            .apply(tenant(
+                Some(1),
+                record(TransactionType::ChargeBack, 1, 1, None)
+            ))
+            .is_ok());
+
+        let report = manager.finish();
+        assert_eq!(report.accounts().count(), 3);
+        assert_eq!(report.account(1).unwrap().total(), dec!(1.0));
+        let locked = report.account(AccountKey::new(Some(1), 1)).unwrap();
+        assert_eq!(locked.total(), dec!(0.0));
+        assert!(locked.is_locked());
+        let other = report.account(AccountKey::new(Some(2), 1)).unwrap();
+        assert_eq!(other.available(), dec!(3.0));
+        assert!(!other.is_locked());
+    }
+
+    #[test]
+    fn test_tenants_st() {
+        test_tenants(STAccountManager::new());
+    }
+
+    #[test]
+    fn test_tenants_mt() {
+        test_tenants(MTAccountManager::new(2));
+    }
+
+    // Client 1 doesn't settle the dispute of tx 1 in time, client 2 only at the end of the stream
+    fn test_dispute_expiry(manager: impl AccountManager) -> Report {
+        let at = |timestamp, mut record: TransactionRecord| {
+            record.timestamp = Some(timestamp);
+            record
+        };
+        let records = vec![
+            at(0, record(TransactionType::Deposit, 1, 1, Some(dec!(10.0)))),
+            at(0, record(TransactionType::Deposit, 1, 2, Some(dec!(5.0)))),
+            at(10, record(TransactionType::Dispute, 1, 1, None)),
+            at(20, record(TransactionType::Dispute, 1, 2, None)),
+            at(25, record(TransactionType::Resolve, 1, 2, None)),
+            at(45, record(TransactionType::Deposit, 2, 3, Some(dec!(1.0)))),
+            at(46, record(TransactionType::Dispute, 2, 3, None)),
+            at(50, record(TransactionType::ChargeBack, 1, 1, None)),
+            at(100, record(TransactionType::Deposit, 1, 4, Some(dec!(1.0)))),
+        ];
+        manager.execute_transactions(Box::new(records.into_iter()))
+    }
+
+    #[test]
+    fn test_dispute_expiry_resolve() {
+        let check = |report: Report| {
+            let account = report.account(1).unwrap();
+            assert_eq!(account.available(), dec!(16.0));
+            assert_eq!(account.held(), dec!(0.0));
+            assert!(!account.is_locked());
+            assert_eq!(report.account(2).unwrap().available(), dec!(1.0));
+            assert_eq!(report.account(2).unwrap().held(), dec!(0.0));
+            // the chargeback came too late
+            let rejects: Vec<_> = report.rejects().iter().map(|reject| reject.tx).collect();
+            assert_eq!(rejects, vec![1]);
+        };
+        check(test_dispute_expiry(
+            STAccountManager::new().with_dispute_expiry(30, DisputeExpiry::Resolve),
+        ));
+        check(test_dispute_expiry(
+            MTAccountManager::new(2).with_dispute_expiry(30, DisputeExpiry::Resolve),
+        ));
+    }
+
+    #[test]
+    fn test_dispute_expiry_expire() {
+        let check = |report: Report| {
+            // the late chargeback still settles the expired dispute
+            let account = report.account(1).unwrap();
+            assert_eq!(account.available(), dec!(5.0));
+            assert_eq!(account.held(), dec!(0.0));
+            assert!(account.is_locked());
+            assert_eq!(report.account(2).unwrap().held(), dec!(1.0));
+            let rejects: Vec<_> = report.rejects().iter().map(|reject| reject.tx).collect();
+            assert_eq!(rejects, vec![4]);
+        };
+        check(test_dispute_expiry(
+            STAccountManager::new().with_dispute_expiry(30, DisputeExpiry::Expire),
+        ));
+        check(test_dispute_expiry(
+            MTAccountManager::new(2).with_dispute_expiry(30, DisputeExpiry::Expire),
+        ));
+    }
+
+    // The first dispute of tx 1 is resolved in time, a second one is opened before the first
+    // one would have expired
+    fn redisputed_expiry(manager: impl AccountManager, end: u64) -> Report {
+        let at = |timestamp, mut record: TransactionRecord| {
+            record.timestamp = Some(timestamp);
+            record
+        };
+        let records = vec![
+            at(0, record(TransactionType::Deposit, 1, 1, Some(dec!(100)))),
+            at(0, record(TransactionType::Dispute, 1, 1, Some(dec!(30)))),
+            at(10, record(TransactionType::Resolve, 1, 1, Some(dec!(30)))),
+            at(90, record(TransactionType::Dispute, 1, 1, Some(dec!(20)))),
+            at(end, record(TransactionType::Deposit, 1, 2, Some(dec!(1)))),
+        ];
+        manager.execute_transactions(Box::new(records.into_iter()))
+    }
+
+    #[test]
+    fn test_redisputed_expiry() {
+        for expiry in [DisputeExpiry::Resolve, DisputeExpiry::Expire] {
+            // the deadline of the resolved dispute leaves the second one alone
+            let check = |report: Report| {
+                let account = report.account(1).unwrap();
+                assert_eq!(account.available(), dec!(81));
+                assert_eq!(account.held(), dec!(20));
+                let disputes = &account.history().next().unwrap().disputes;
+                assert_eq!(disputes[0].state, DisputeProgress::InProgress);
+            };
+            check(redisputed_expiry(
+                STAccountManager::new().with_dispute_expiry(100, expiry),
+                100,
+            ));
+            check(redisputed_expiry(
+                MTAccountManager::new(2).with_dispute_expiry(100, expiry),
+                100,
+            ));
+        }
+
+        // which expires on its own deadline
+        let report = redisputed_expiry(
+            STAccountManager::new().with_dispute_expiry(100, DisputeExpiry::Resolve),
+            190,
+        );
+        assert_eq!(report.account(1).unwrap().available(), dec!(101));
+        assert_eq!(report.account(1).unwrap().held(), dec!(0));
+        let report = redisputed_expiry(
+            STAccountManager::new().with_dispute_expiry(100, DisputeExpiry::Expire),
+            190,
+        );
+        let account = report.account(1).unwrap();
+        assert_eq!(account.held(), dec!(20));
+        let disputes = &account.history().next().unwrap().disputes;
+        assert_eq!(disputes[0].state, DisputeProgress::Expired);
+    }
+
+    // Client 1 settles its first withdrawal, the second one is due at the end of the stream
+    fn test_pending_withdrawals(manager: impl AccountManager) -> Report {
+        let at = |timestamp, mut record: TransactionRecord| {
+            record.timestamp = Some(timestamp);
+            record
+        };
+        let records = vec![
+            at(0, record(TransactionType::Deposit, 1, 1, Some(dec!(10.0)))),
+            at(
+                5,
+                record(TransactionType::Withdrawal, 1, 2, Some(dec!(3.0))),
+            ),
+            at(
+                6,
+                record(TransactionType::Withdrawal, 1, 3, Some(dec!(2.0))),
+            ),
+            at(10, record(TransactionType::Settle, 1, 2, None)),
+            at(12, record(TransactionType::Settle, 1, 2, None)),
+            at(40, record(TransactionType::Deposit, 2, 4, Some(dec!(1.0)))),
+            at(
+                50,
+                record(TransactionType::Withdrawal, 1, 5, Some(dec!(1.0))),
+            ),
+        ];
+        manager.execute_transactions(Box::new(records.into_iter()))
+    }
+
+    #[test]
+    fn test_settlement_delay() {
+        let check = |report: Report| {
+            // the withdrawal 3 was settled after the delay, the withdrawal 5 isn't due yet
+            let account = report.account(1).unwrap();
+            assert_eq!(account.available(), dec!(4.0));
+            assert_eq!(account.pending_out(), dec!(1.0));
+            assert_eq!(account.total(), dec!(5.0));
+            let rejects: Vec<_> = report
+                .rejects()
+                .iter()
+                .map(|reject| (reject.tx, reject.reason.clone()))
+                .collect();
+            assert_eq!(rejects, vec![(2, TransactionError::NotPending)]);
+        };
+        check(test_pending_withdrawals(
+            STAccountManager::new().with_pending_withdrawals(Some(30)),
+        ));
+        check(test_pending_withdrawals(
+            MTAccountManager::new(2).with_pending_withdrawals(Some(30)),
+        ));
+
+        // without a delay, the withdrawals wait for a settle
+        let report =
+            test_pending_withdrawals(STAccountManager::new().with_pending_withdrawals(None));
+        assert_eq!(report.account(1).unwrap().pending_out(), dec!(3.0));
+        assert_eq!(report.account(1).unwrap().total(), dec!(7.0));
+    }
+
+    #[test]
+    fn test_tx_registry() {
+        let registry = Arc::new(TxRegistry::new());
+        let mut manager = STAccountManager::new().with_tx_registry(registry.clone());
+
+        assert!(manager
+            .apply(record(TransactionType::Deposit, 1, 1, Some(dec!(5.0))))
+            .is_ok());
+        assert_eq!(
+            manager.apply(record(TransactionType::Withdrawal, 2, 1, Some(dec!(1.0)))),
+            Err(TransactionError::TxIdReused { owner: 1 })
+        );
+        // a rejected transaction doesn't keep the id
+        assert_eq!(
+            manager.apply(record(TransactionType::Withdrawal, 2, 2, Some(dec!(1.0)))),
+            Err(TransactionError::InsufficientFunds {
+                requested: dec!(1.0),
+                available: dec!(0.0)
+            })
+        );
+        assert!(manager
+            .apply(record(TransactionType::Deposit, 1, 2, Some(dec!(1.0))))
+            .is_ok());
+        // the disputes refer to the id of the client
+        assert!(manager
+            .apply(record(TransactionType::Dispute, 1, 1, None))
+            .is_ok());
+
+        // the workers share the registry
+        let mut manager = MTAccountManager::new(2).with_tx_registry(registry);
+        for client in 1..=2 {
+            assert!(manager
+                .apply(record(TransactionType::Deposit, client, 3, Some(dec!(1.0))))
+                .is_ok());
         }
+        let report = manager.finish();
+        // either worker may claim the id first, but only one of them
+        let total: Decimal = (1..=2)
+            .map(|client| report.account(client).unwrap().total())
+            .sum();
+        assert_eq!(total, dec!(1.0));
     }
 }
@@ -0,0 +1,108 @@
+/// Where the account managers keep their accounts
+/// The managers only get, create and iterate the accounts through an `AccountStore`, so
+/// other layouts (a dense array of the client ids, a concurrent map, an embedded database)
+/// can be plugged in without changing how the records are applied. The default one is a
+/// hash map in memory
+use std::sync::Arc;
+
+use hashbrown::HashMap;
+
+use crate::{client_account::ClientAccount, records::AccountKey};
+
+/// The accounts of a manager, each one owned by the manager while it's applying a record
+pub trait AccountStore: Send {
+    /// The account of `key`, created with `create` if it doesn't exist yet
+    fn get_or_create(
+        &mut self,
+        key: AccountKey,
+        create: &mut dyn FnMut() -> ClientAccount,
+    ) -> &mut ClientAccount;
+
+    fn get(&self, key: AccountKey) -> Option<&ClientAccount>;
+
+    /// All the accounts, in no particular order
+    fn iter(&self) -> Box<dyn Iterator<Item = &ClientAccount> + '_>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes the accounts out of the store, for the report
+    fn into_map(self: Box<Self>) -> HashMap<AccountKey, ClientAccount>;
+}
+
+/// Creates the store of each worker of the multithreaded manager
+pub type NewAccountStore = Arc<dyn Fn() -> Box<dyn AccountStore> + Send + Sync>;
+
+/// The accounts in a hash map
+#[derive(Default)]
+pub struct HashMapAccountStore {
+    accounts: HashMap<AccountKey, ClientAccount>,
+}
+
+impl HashMapAccountStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AccountStore for HashMapAccountStore {
+    fn get_or_create(
+        &mut self,
+        key: AccountKey,
+        create: &mut dyn FnMut() -> ClientAccount,
+    ) -> &mut ClientAccount {
+        self.accounts.entry(key).or_insert_with(create)
+    }
+
+    fn get(&self, key: AccountKey) -> Option<&ClientAccount> {
+        self.accounts.get(&key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &ClientAccount> + '_> {
+        Box::new(self.accounts.values())
+    }
+
+    fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    fn into_map(self: Box<Self>) -> HashMap<AccountKey, ClientAccount> {
+        self.accounts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_hash_map_store() {
+        let mut store: Box<dyn AccountStore> = Box::new(HashMapAccountStore::new());
+        assert!(store.is_empty());
+        let mut created = 0;
+        for tx in 1..=2 {
+            store
+                .get_or_create(1.into(), &mut || {
+                    created += 1;
+                    ClientAccount::new(1)
+                })
+                .deposit(tx, dec!(1))
+                .unwrap();
+        }
+        // created once, the second deposit is on the same account
+        assert_eq!(created, 1);
+        store.get_or_create(AccountKey::new(Some(2), 1), &mut || {
+            ClientAccount::new(1).with_tenant(Some(2))
+        });
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(1.into()).unwrap().total(), dec!(2));
+        assert!(store.get(2.into()).is_none());
+        assert_eq!(store.iter().count(), 2);
+        assert_eq!(store.into_map().len(), 2);
+    }
+}
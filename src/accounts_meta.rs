@@ -0,0 +1,119 @@
+/// Descriptive fields of the accounts, joined into the reports for `--accounts-meta`
+/// The file is a CSV with the `client`, `name`, `tier` and `country` columns, and a `tenant`
+/// column for the inputs with tenants. The reports get the three fields as extra columns, empty
+/// for the accounts missing from the file, so they can be read without joining them elsewhere
+use std::{io::Read, path::Path};
+
+use anyhow::Context;
+use csv::{ReaderBuilder, Trim};
+use hashbrown::HashMap;
+use serde::Deserialize;
+
+use crate::records::{AccountKey, ClientId, TenantId};
+
+/// Headers of the columns added to the reports
+pub const META_HEADERS: [&str; 3] = ["name", "tier", "country"];
+
+/// The descriptive fields of an account
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct AccountMeta {
+    pub name: String,
+    pub tier: String,
+    pub country: String,
+}
+
+impl AccountMeta {
+    /// The fields in the order of `META_HEADERS`
+    pub fn cells(&self) -> [&str; 3] {
+        [&self.name, &self.tier, &self.country]
+    }
+}
+
+#[derive(Deserialize)]
+struct MetaRecord {
+    client: ClientId,
+    #[serde(default)]
+    tenant: Option<TenantId>,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    tier: String,
+    #[serde(default)]
+    country: String,
+}
+
+/// The metadata of the accounts listed in the file
+#[derive(Debug, Default)]
+pub struct AccountsMeta {
+    accounts: HashMap<AccountKey, AccountMeta>,
+}
+
+impl AccountsMeta {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::from_reader(file)
+            .with_context(|| format!("Invalid accounts metadata {}", path.display()))
+    }
+
+    /// Parses the CSV, an account listed twice is an error
+    pub fn from_reader(reader: impl Read) -> anyhow::Result<Self> {
+        let mut csv_reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+        let mut accounts = HashMap::new();
+        for record in csv_reader.deserialize::<MetaRecord>() {
+            let record = record?;
+            let key = AccountKey::new(record.tenant, record.client);
+            let meta = AccountMeta {
+                name: record.name,
+                tier: record.tier,
+                country: record.country,
+            };
+            if accounts.insert(key, meta).is_some() {
+                return Err(anyhow::anyhow!("Client {} is listed twice", record.client));
+            }
+        }
+        Ok(Self { accounts })
+    }
+
+    pub fn get(&self, key: AccountKey) -> Option<&AccountMeta> {
+        self.accounts.get(&key)
+    }
+
+    /// The fields of the account, empty if it isn't listed
+    pub fn cells(&self, key: AccountKey) -> [&str; 3] {
+        self.get(key).map_or([""; 3], AccountMeta::cells)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accounts_meta() {
+        let meta = AccountsMeta::from_reader(
+            "client, name, tier, country\n\
+             1, Alice, gold, FR\n\
+             2,\"Bob, Jr\", , US\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(meta.cells(AccountKey::from(1)), ["Alice", "gold", "FR"]);
+        assert_eq!(meta.cells(AccountKey::from(2)), ["Bob, Jr", "", "US"]);
+        assert_eq!(meta.cells(AccountKey::from(3)), ["", "", ""]);
+        assert!(meta.get(AccountKey::new(Some(1), 1)).is_none());
+
+        let meta = AccountsMeta::from_reader(
+            "client, tenant, name\n\
+             1, 4, Carol\n\
+             1, , Dave\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(meta.get(AccountKey::new(Some(4), 1)).unwrap().name, "Carol");
+        assert_eq!(meta.get(AccountKey::from(1)).unwrap().name, "Dave");
+
+        assert!(AccountsMeta::from_reader("client, name\n1, a\n1, b\n".as_bytes()).is_err());
+        assert!(AccountsMeta::from_reader("client, name\nx, a\n".as_bytes()).is_err());
+    }
+}
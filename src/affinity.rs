@@ -0,0 +1,193 @@
+/// Pins the threads of the pipeline to cores, for `--reader-cores`, `--parser-cores` and
+/// `--worker-cores`: the threads of a kind are pinned to the cores of their list in turn, so
+/// the perf traces of a production box stay readable and the threads don't bounce between
+/// the sockets of a NUMA machine. With `--numa`, the workers are spread over the NUMA nodes
+/// instead, each one free to run on any core of its node. Only supported on Linux
+use std::path::Path;
+
+use log::*;
+
+/// The cores `sched_setaffinity` can pin to
+const MAX_CORES: usize = 1024;
+/// The sysfs directory of the NUMA nodes
+const NODES_DIR: &str = "/sys/devices/system/node";
+
+/// A list of cores like `0-3,8`
+#[derive(PartialEq, Debug, Clone)]
+pub struct CoreList(Vec<usize>);
+
+impl CoreList {
+    pub fn parse(list: &str) -> Result<Self, String> {
+        if !cfg!(target_os = "linux") {
+            return Err("the threads can only be pinned on Linux".to_string());
+        }
+        let core = |core: &str| -> Result<usize, String> {
+            match core.trim().parse::<usize>() {
+                Ok(core) if core < MAX_CORES => Ok(core),
+                _ => Err(format!("`{}` is not a core", core.trim())),
+            }
+        };
+        let mut cores = Vec::new();
+        for range in list.split(',') {
+            match range.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (core(first)?, core(last)?);
+                    if first > last {
+                        return Err(format!("`{}` is an empty range", range.trim()));
+                    }
+                    cores.extend(first..=last);
+                }
+                None => cores.push(core(range)?),
+            }
+        }
+        Ok(Self(cores))
+    }
+
+    pub fn cores(&self) -> &[usize] {
+        &self.0
+    }
+
+    /// Pins the current thread, the `index`-th of its kind, to its core
+    pub fn pin(&self, index: usize) {
+        pin_current(&[self.0[index % self.0.len()]]);
+    }
+
+    /// Pins the current thread to all the cores of the list, e.g. the cores of a NUMA node
+    pub fn pin_any(&self) {
+        pin_current(&self.0);
+    }
+}
+
+/// The cores of each NUMA node, in the order of the nodes
+pub fn numa_nodes() -> std::io::Result<Vec<CoreList>> {
+    read_nodes(Path::new(NODES_DIR))
+}
+
+fn read_nodes(dir: &Path) -> std::io::Result<Vec<CoreList>> {
+    let mut nodes = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let node = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|node| node.parse::<usize>().ok());
+        let node = match node {
+            Some(node) => node,
+            None => continue,
+        };
+        let cores = std::fs::read_to_string(entry.path().join("cpulist"))?;
+        // the nodes with memory but no cores
+        if cores.trim().is_empty() {
+            continue;
+        }
+        let cores = CoreList::parse(cores.trim())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        nodes.push((node, cores));
+    }
+    nodes.sort_by_key(|(node, _)| *node);
+    Ok(nodes.into_iter().map(|(_, cores)| cores).collect())
+}
+
+/// A thread that can't be pinned runs anywhere, the run goes on
+fn pin_current(cores: &[usize]) {
+    if let Err(err) = set_affinity(cores) {
+        warn!(
+            "Failed to pin the thread {} to the cores {:?}: {}",
+            std::thread::current().name().unwrap_or("unnamed"),
+            cores,
+            err
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cores: &[usize]) -> std::io::Result<()> {
+    // SAFETY: the set is a plain bitmask, and the cores are below its size
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for core in cores {
+            libc::CPU_SET(*core, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cores: &[usize]) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "only supported on Linux",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(CoreList::parse("0-3,8").unwrap().cores(), [0, 1, 2, 3, 8]);
+        assert_eq!(CoreList::parse(" 2 , 5-5").unwrap().cores(), [2, 5]);
+        assert!(CoreList::parse("3-1").is_err());
+        assert!(CoreList::parse("x").is_err());
+        assert!(CoreList::parse("1,").is_err());
+        assert!(CoreList::parse("1024").is_err());
+    }
+
+    /// The cores the current thread can run on
+    fn affinity() -> Vec<usize> {
+        // SAFETY: the set is a plain bitmask
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+            (0..MAX_CORES)
+                .filter(|core| libc::CPU_ISSET(*core, &set))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_pin() {
+        let cores = CoreList::parse("0").unwrap();
+        let pinned = std::thread::spawn(move || {
+            cores.pin(3);
+            affinity()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(pinned, [0]);
+
+        // the cores that don't exist are left out of the set
+        let cores = CoreList::parse("0,1000").unwrap();
+        let pinned = std::thread::spawn(move || {
+            cores.pin_any();
+            affinity()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(pinned, [0]);
+    }
+
+    #[test]
+    fn test_numa_nodes() {
+        let dir = std::env::temp_dir().join("paytoy_test_numa_nodes");
+        let _ = std::fs::remove_dir_all(&dir);
+        for (node, cores) in [("node1", "8-15\n"), ("node0", "0-7\n"), ("node2", "\n")] {
+            std::fs::create_dir_all(dir.join(node)).unwrap();
+            std::fs::write(dir.join(node).join("cpulist"), cores).unwrap();
+        }
+        std::fs::write(dir.join("online"), "0-2\n").unwrap();
+        let nodes = read_nodes(&dir).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].cores(), (0..8).collect::<Vec<_>>());
+        assert_eq!(nodes[1].cores(), (8..16).collect::<Vec<_>>());
+
+        // every machine has a node, even without NUMA
+        assert!(!numa_nodes().unwrap().is_empty());
+    }
+}
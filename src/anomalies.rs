@@ -0,0 +1,141 @@
+/// The accounts needing a manual review at the end of a run, for `--anomalies`
+/// The accounts are scanned once all the records are applied, an account gets a row for each
+/// anomaly it has, with its balances. None of them should happen with the default options:
+/// the available funds only go negative with `--dispute-hold allow-negative-available`, and a
+/// locked account only keeps held funds when its other disputes weren't settled
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+
+use crate::client_account::ClientAccount;
+
+/// Something off in the balances of an account
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Anomaly {
+    NegativeAvailable,
+    /// More funds held than the account has
+    HeldOverTotal,
+    /// A locked account with held funds, whose disputes can't be settled anymore
+    LockedWithHeld,
+}
+
+impl Anomaly {
+    pub fn code(self) -> &'static str {
+        match self {
+            Anomaly::NegativeAvailable => "negative_available",
+            Anomaly::HeldOverTotal => "held_over_total",
+            Anomaly::LockedWithHeld => "locked_with_held",
+        }
+    }
+
+    /// The anomalies of an account, none for most of them
+    pub fn of(account: &ClientAccount) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+        if account.available() < Decimal::ZERO {
+            anomalies.push(Anomaly::NegativeAvailable);
+        }
+        if account.held() > account.total() {
+            anomalies.push(Anomaly::HeldOverTotal);
+        }
+        if account.is_locked() && !account.held().is_zero() {
+            anomalies.push(Anomaly::LockedWithHeld);
+        }
+        anomalies
+    }
+}
+
+/// Writes a row for each anomaly of the accounts, ordered by account
+/// Returns the number of anomalies
+pub fn write_anomalies<'a>(
+    writer: &mut impl Write,
+    accounts: impl Iterator<Item = &'a ClientAccount>,
+) -> std::io::Result<usize> {
+    let mut accounts: Vec<_> = accounts
+        .map(|account| (account, Anomaly::of(account)))
+        .filter(|(_, anomalies)| !anomalies.is_empty())
+        .collect();
+    accounts.sort_by_key(|(account, _)| account.key());
+    writeln!(
+        writer,
+        "tenant, client,            anomaly,     available,          held,         total,   locked"
+    )?;
+    let mut count = 0;
+    for (account, anomalies) in accounts {
+        let tenant = account
+            .tenant()
+            .map_or_else(String::new, |tenant| tenant.to_string());
+        for anomaly in anomalies {
+            writeln!(
+                writer,
+                "{:>6}, {:6}, {:>18}, {:13.4}, {:13.4}, {:13.4}, {:>8}",
+                tenant,
+                account.id(),
+                anomaly.code(),
+                account.available(),
+                account.held(),
+                account.total(),
+                account.is_locked()
+            )?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Writes the anomalies to a file, returns their number
+pub fn write_file<'a>(
+    path: &Path,
+    accounts: impl Iterator<Item = &'a ClientAccount>,
+) -> anyhow::Result<usize> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write_anomalies(&mut writer, accounts)
+        .and_then(|count| writer.flush().map(|()| count))
+        .with_context(|| format!("Failed to write the anomalies {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{client_account::DisputePolicy, records::AccountKey};
+
+    use super::*;
+
+    #[test]
+    fn test_anomalies() {
+        // the deposit was withdrawn before its dispute
+        let mut negative =
+            ClientAccount::new(2).with_dispute_policy(DisputePolicy::AllowNegativeAvailable);
+        negative.deposit(1, dec!(10)).unwrap();
+        negative.withdraw(2, dec!(8)).unwrap();
+        negative.dispute(1).unwrap();
+        assert_eq!(
+            Anomaly::of(&negative),
+            vec![Anomaly::NegativeAvailable, Anomaly::HeldOverTotal]
+        );
+
+        let locked =
+            ClientAccount::from_balances(AccountKey::new(Some(3), 1), dec!(1), dec!(2), true);
+        assert_eq!(Anomaly::of(&locked), vec![Anomaly::LockedWithHeld]);
+        let healthy = ClientAccount::from_balances(AccountKey::from(1), dec!(1), dec!(2), false);
+        assert!(Anomaly::of(&healthy).is_empty());
+
+        let mut output = Vec::new();
+        let count = write_anomalies(&mut output, [locked, healthy, negative].iter()).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tenant, client,            anomaly,     available,          held,         total,   locked\n\
+             \x20     ,      2, negative_available,       -8.0000,       10.0000,        2.0000,    false\n\
+             \x20     ,      2,    held_over_total,       -8.0000,       10.0000,        2.0000,    false\n\
+             \x20    3,      1,   locked_with_held,        1.0000,        2.0000,        3.0000,     true\n"
+        );
+    }
+}
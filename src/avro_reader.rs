@@ -0,0 +1,488 @@
+/// Reads transactions from Avro container files
+/// The records must have the `type`, `client` and `tx` fields and optionally `amount`,
+/// any other field is skipped. Like for Parquet, the field types don't have to match exactly
+use std::{
+    convert::TryFrom,
+    io::{BufReader, Read},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context};
+use avro_schema::{
+    file::FileMetadata,
+    read::{
+        fallible_streaming_iterator::FallibleStreamingIterator, read_metadata,
+        BlockStreamingIterator,
+    },
+    schema::{BytesLogical, Field, FixedLogical, Schema},
+};
+use log::*;
+use rust_decimal::Decimal;
+
+use crate::{
+    records::{RecordSource, TransactionRecord, TransactionType},
+    stats::PipelineStats,
+    transactions_reader::{TransactionCSVReader, TransactionsStream},
+};
+
+/// A single threaded reader, decoding one block at a time
+#[derive(Clone)]
+pub struct AvroReader {
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+}
+
+impl AvroReader {
+    pub fn new() -> Self {
+        Self {
+            stats: None,
+            source: None,
+        }
+    }
+
+    /// Publish the number of read records to the run statistics
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+}
+
+impl TransactionCSVReader for AvroReader {
+    fn read_from<R: Read + Send + 'static>(self, reader: R) -> anyhow::Result<TransactionsStream> {
+        let mut file = BufReader::new(reader);
+        let FileMetadata {
+            record,
+            compression,
+            marker,
+        } = read_metadata(&mut file).map_err(|err| anyhow!("Invalid Avro file: {:?}", err))?;
+
+        // fail early instead of on every block
+        for column in ["type", "client", "tx"] {
+            record
+                .fields
+                .iter()
+                .find(|field| field.name == column)
+                .with_context(|| format!("Missing field `{}`", column))?;
+        }
+
+        Ok(Box::new(AvroRecords {
+            blocks: BlockStreamingIterator::new(file, compression, marker),
+            fields: record.fields,
+            records: Vec::new().into_iter(),
+            rows: 0,
+            stats: self.stats,
+            source: self.source,
+        }))
+    }
+
+    fn with_source(mut self, input: Arc<str>) -> Self {
+        self.source = Some(input);
+        self
+    }
+}
+
+/// Iterates over the records of all the blocks of a file
+struct AvroRecords<R: Read> {
+    blocks: BlockStreamingIterator<BufReader<R>>,
+    fields: Vec<Field>,
+    records: std::vec::IntoIter<TransactionRecord>,
+    /// Number of rows in the blocks read so far
+    rows: u64,
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+}
+
+impl<R: Read> Iterator for AvroRecords<R> {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Some(record);
+            }
+
+            let block = match self.blocks.next() {
+                Ok(Some(block)) => block,
+                Ok(None) => return None,
+                Err(err) => {
+                    error!("Failed to read an Avro block: {:?}", err);
+                    if let Some(stats) = &self.stats {
+                        stats.add_input_error();
+                    }
+                    return None;
+                }
+            };
+
+            let first_row = self.rows + 1;
+            self.rows += block.number_of_rows as u64;
+            let source = self.source.as_ref();
+            let tag = |row| RecordSource::tag(source, first_row + row as u64);
+            match records_from_block(&self.fields, &block.data, block.number_of_rows, tag) {
+                Ok((records, parse_errors)) => {
+                    if let Some(stats) = &self.stats {
+                        stats.add_read(records.len() as u64, parse_errors);
+                    }
+                    self.records = records.into_iter();
+                }
+                Err(err) => {
+                    error!("Failed to decode an Avro block: {:?}", err);
+                    if let Some(stats) = &self.stats {
+                        stats.add_input_error();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A decoded value, only the ones that can be mapped to a record field are kept
+#[derive(PartialEq, Debug)]
+enum Value<'a> {
+    Null,
+    Long(i64),
+    Double(f64),
+    Bytes(&'a [u8]),
+    Symbol(&'a str),
+    Decimal(Decimal),
+    Other,
+}
+
+/// Converts a block to transaction records
+/// Returns the records and the number of rows that couldn't be converted
+fn records_from_block<'a>(
+    fields: &'a [Field],
+    mut data: &'a [u8],
+    rows: usize,
+    tag: impl Fn(usize) -> Option<RecordSource>,
+) -> anyhow::Result<(Vec<TransactionRecord>, u64)> {
+    let mut records = Vec::with_capacity(rows);
+    let mut parse_errors = 0;
+    for row in 0..rows {
+        let (mut tr_type, mut client, mut tx, mut amount, mut timestamp) = (
+            Value::Null,
+            Value::Null,
+            Value::Null,
+            Value::Null,
+            Value::Null,
+        );
+        for field in fields {
+            let value = decode(&field.schema, &mut data)?;
+            match field.name.as_str() {
+                "type" => tr_type = value,
+                "client" => client = value,
+                "tx" => tx = value,
+                "amount" => amount = value,
+                "timestamp" => timestamp = value,
+                _ => {}
+            }
+        }
+
+        match to_record(tr_type, client, tx, amount, timestamp) {
+            Some(mut record) => {
+                record.source = tag(row);
+                records.push(record)
+            }
+            None => parse_errors += 1,
+        }
+    }
+
+    Ok((records, parse_errors))
+}
+
+fn to_record(
+    tr_type: Value,
+    client: Value,
+    tx: Value,
+    amount: Value,
+    timestamp: Value,
+) -> Option<TransactionRecord> {
+    let tr_type = match tr_type {
+        Value::Symbol(symbol) => symbol,
+        Value::Bytes(bytes) => std::str::from_utf8(bytes).ok()?,
+        _ => return None,
+    };
+    let amount = match amount {
+        Value::Null => None,
+        Value::Decimal(amount) => Some(amount),
+        Value::Long(amount) => Some(Decimal::from(amount)),
+        Value::Double(amount) => Some(Decimal::from_str(&amount.to_string()).ok()?),
+        Value::Bytes(amount) => {
+            let amount = std::str::from_utf8(amount).ok()?.trim();
+            Some(
+                Decimal::from_str(amount)
+                    .or_else(|_| Decimal::from_scientific(amount))
+                    .ok()?,
+            )
+        }
+        _ => return None,
+    };
+
+    Some(TransactionRecord {
+        tr_type: TransactionType::from_str(tr_type.trim()).ok()?,
+        client: to_integer(client)?,
+        tenant: None,
+        merchant: None,
+        tx: to_integer(tx)?,
+        amount,
+        timestamp: match timestamp {
+            Value::Null => None,
+            timestamp => Some(to_integer(timestamp)?),
+        },
+        source: None,
+    })
+}
+
+fn to_integer<T: TryFrom<i64> + FromStr>(value: Value) -> Option<T> {
+    match value {
+        Value::Long(value) => T::try_from(value).ok(),
+        Value::Bytes(value) => std::str::from_utf8(value).ok()?.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Decodes a single value of the `schema` type and advances the data past it
+fn decode<'a>(schema: &'a Schema, data: &mut &'a [u8]) -> anyhow::Result<Value<'a>> {
+    Ok(match schema {
+        Schema::Null => Value::Null,
+        Schema::Boolean => {
+            take(data, 1)?;
+            Value::Other
+        }
+        Schema::Int(_) | Schema::Long(_) => Value::Long(read_long(data)?),
+        Schema::Float => {
+            let bytes = take(data, 4)?;
+            Value::Double(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64)
+        }
+        Schema::Double => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(take(data, 8)?);
+            Value::Double(f64::from_le_bytes(bytes))
+        }
+        Schema::Bytes(Some(BytesLogical::Decimal(_, scale))) => {
+            let len = read_len(data)?;
+            to_decimal(take(data, len)?, *scale)?
+        }
+        Schema::Bytes(None) | Schema::String(_) => {
+            let len = read_len(data)?;
+            Value::Bytes(take(data, len)?)
+        }
+        Schema::Fixed(fixed) => match fixed.logical {
+            Some(FixedLogical::Decimal(_, scale)) => to_decimal(take(data, fixed.size)?, scale)?,
+            _ => {
+                take(data, fixed.size)?;
+                Value::Other
+            }
+        },
+        Schema::Enum(schema) => {
+            let index = read_len(data)?;
+            let symbol = schema
+                .symbols
+                .get(index)
+                .with_context(|| format!("Enum index {} out of range", index))?;
+            Value::Symbol(symbol)
+        }
+        Schema::Union(schemas) => {
+            let index = read_len(data)?;
+            let schema = schemas
+                .get(index)
+                .with_context(|| format!("Union index {} out of range", index))?;
+            decode(schema, data)?
+        }
+        Schema::Record(record) => {
+            for field in &record.fields {
+                decode(&field.schema, data)?;
+            }
+            Value::Other
+        }
+        Schema::Array(items) => {
+            skip_blocks(data, |data| decode(items, data).map(|_| ()))?;
+            Value::Other
+        }
+        Schema::Map(values) => {
+            skip_blocks(data, |data| {
+                let len = read_len(data)?;
+                take(data, len)?;
+                decode(values, data).map(|_| ())
+            })?;
+            Value::Other
+        }
+    })
+}
+
+/// Skips the items of an array or a map, stored as blocks of items ending with an empty block
+fn skip_blocks<'a>(
+    data: &mut &'a [u8],
+    mut skip_item: impl FnMut(&mut &'a [u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    loop {
+        let count = read_long(data)?;
+        if count == 0 {
+            return Ok(());
+        }
+        if count < 0 {
+            // a negative count is followed by the size of the block in bytes
+            let size = read_len(data)?;
+            take(data, size)?;
+        } else {
+            for _ in 0..count {
+                skip_item(data)?;
+            }
+        }
+    }
+}
+
+/// Decimals are stored as big-endian two's complement integers
+fn to_decimal(bytes: &[u8], scale: usize) -> anyhow::Result<Value<'static>> {
+    if bytes.len() > 16 {
+        return Err(anyhow!("Decimal of {} bytes is too wide", bytes.len()));
+    }
+    let negative = bytes.first().is_some_and(|byte| byte & 0x80 != 0);
+    let mut buffer = if negative { [0xffu8; 16] } else { [0u8; 16] };
+    buffer[16 - bytes.len()..].copy_from_slice(bytes);
+    Ok(
+        match Decimal::try_from_i128_with_scale(i128::from_be_bytes(buffer), scale as u32) {
+            Ok(amount) => Value::Decimal(amount),
+            // doesn't fit, makes the row unparseable
+            Err(_) => Value::Other,
+        },
+    )
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(anyhow!("Unexpected end of block"));
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+/// Reads a zigzag encoded variable length integer
+fn read_long(data: &mut &[u8]) -> anyhow::Result<i64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = take(data, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    Err(anyhow!("Invalid variable length integer"))
+}
+
+fn read_len(data: &mut &[u8]) -> anyhow::Result<usize> {
+    let len = read_long(data)?;
+    usize::try_from(len).map_err(|_| anyhow!("Negative length {}", len))
+}
+
+#[cfg(test)]
+mod tests {
+    use avro_schema::{
+        file::{Block, CompressedBlock, Compression},
+        schema::Record,
+        write::{compress, encode::zigzag_encode, write_block, write_metadata},
+    };
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn write_file(path: &std::path::Path, fields: Vec<Field>, rows: usize, data: Vec<u8>) {
+        let mut file = std::fs::File::create(path).unwrap();
+        let compression = Some(Compression::Deflate);
+        write_metadata(&mut file, Record::new("transaction", fields), compression).unwrap();
+
+        let mut block = Block::new(rows, data);
+        let mut compressed = CompressedBlock::default();
+        compress(&mut block, &mut compressed, compression).unwrap();
+        write_block(&mut file, &compressed).unwrap();
+    }
+
+    fn write_string(data: &mut Vec<u8>, value: &str) {
+        zigzag_encode(value.len() as i64, data).unwrap();
+        data.extend_from_slice(value.as_bytes());
+    }
+
+    #[test]
+    fn test_avro_reader() {
+        let fields = vec![
+            Field::new("type", Schema::String(None)),
+            Field::new("client", Schema::Int(None)),
+            Field::new("tx", Schema::Long(None)),
+            Field::new("memo", Schema::Array(Box::new(Schema::String(None)))),
+            Field::new("amount", Schema::Union(vec![Schema::Null, Schema::Double])),
+        ];
+
+        let rows: [(&str, i64, i64, Option<f64>); 4] = [
+            ("deposit", 1, 1, Some(2.5)),
+            ("withdrawal", 1, 2, Some(1.25)),
+            ("bogus", 1, 3, None),
+            ("dispute", -1, 1, None),
+        ];
+        let mut data = Vec::new();
+        for (tr_type, client, tx, amount) in rows.iter() {
+            write_string(&mut data, tr_type);
+            zigzag_encode(*client, &mut data).unwrap();
+            zigzag_encode(*tx, &mut data).unwrap();
+            zigzag_encode(1, &mut data).unwrap();
+            write_string(&mut data, "skipped");
+            zigzag_encode(0, &mut data).unwrap();
+            match amount {
+                Some(amount) => {
+                    zigzag_encode(1, &mut data).unwrap();
+                    data.extend_from_slice(&amount.to_le_bytes());
+                }
+                None => zigzag_encode(0, &mut data).unwrap(),
+            }
+        }
+
+        let path = std::env::temp_dir().join("paytoy_test_input.avro");
+        write_file(&path, fields, rows.len(), data);
+
+        let stats = Arc::new(PipelineStats::new());
+        let records: Vec<TransactionRecord> = AvroReader::new()
+            .with_stats(stats.clone())
+            .read_csv(&path)
+            .unwrap()
+            .collect();
+
+        // the unknown type and the client id out of range are skipped
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].tr_type, TransactionType::Deposit);
+        assert_eq!(records[0].amount, Some(dec!(2.5)));
+        assert_eq!(records[1].tr_type, TransactionType::Withdrawal);
+        assert_eq!(records[1].tx, 2);
+        assert_eq!(records[1].amount, Some(dec!(1.25)));
+        assert_eq!(stats.parse_errors(), 2);
+    }
+
+    #[test]
+    fn test_decimal_amount() {
+        let schema = Schema::Bytes(Some(BytesLogical::Decimal(10, 4)));
+        let mut data = Vec::new();
+        zigzag_encode(2, &mut data).unwrap();
+        data.extend_from_slice(&(-15000i16).to_be_bytes());
+
+        let mut data = data.as_slice();
+        assert_eq!(
+            decode(&schema, &mut data).unwrap(),
+            Value::Decimal(dec!(-1.5))
+        );
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_missing_field() {
+        let path = std::env::temp_dir().join("paytoy_test_missing_field.avro");
+        let mut data = Vec::new();
+        write_string(&mut data, "deposit");
+        write_file(
+            &path,
+            vec![Field::new("type", Schema::String(None))],
+            1,
+            data,
+        );
+
+        assert!(AvroReader::new().read_csv(&path).is_err());
+    }
+}
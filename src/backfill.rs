@@ -0,0 +1,115 @@
+/// Bootstraps the accounts from a historical input before going on with the live ones
+/// The live source usually starts a little before the end of the history, so its first
+/// records repeat the last ones of the history. Those are dropped instead of being rejected
+/// as duplicates, or applied twice for the withdrawals and the disputes
+use std::{sync::Arc, time::Instant};
+
+use log::*;
+
+use crate::{
+    records::TransactionRecord, replay_window::ReplayWindow, stats::PipelineStats,
+    transactions_reader::TransactionsStream,
+};
+
+/// The records of `history`, then those of `live` but the ones repeating one of the last
+/// `overlap` records of the history (same type, client, id and amount)
+pub fn chain(
+    history: TransactionsStream,
+    live: TransactionsStream,
+    overlap: usize,
+    stats: Option<Arc<PipelineStats>>,
+) -> TransactionsStream {
+    Box::new(Backfill {
+        history: Some(history),
+        live,
+        window: ReplayWindow::new(overlap),
+        started: Instant::now(),
+        backfilled: 0,
+        stats,
+    })
+}
+
+struct Backfill {
+    /// None once the history is over
+    history: Option<TransactionsStream>,
+    live: TransactionsStream,
+    /// The last records of the history
+    window: ReplayWindow,
+    /// The window doesn't expire its records, they're all remembered at the same time
+    started: Instant,
+    backfilled: u64,
+    stats: Option<Arc<PipelineStats>>,
+}
+
+impl Iterator for Backfill {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        if let Some(history) = &mut self.history {
+            if let Some(record) = history.next() {
+                self.window.remember(&record, self.started);
+                self.backfilled += 1;
+                return Some(record);
+            }
+            info!(
+                "Backfilled {} records, going on with the live inputs",
+                self.backfilled
+            );
+            self.history = None;
+        }
+        for record in self.live.by_ref() {
+            if !self.window.contains(&record) {
+                return Some(record);
+            }
+            debug!("Dropping {:?}, already in the backfill", record);
+            if let Some(stats) = &self.stats {
+                stats.add_overlapping();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::records::{TransactionId, TransactionType};
+
+    use super::*;
+
+    fn deposit(tx: TransactionId) -> TransactionRecord {
+        TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount: Some(dec!(1)),
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_overlap() {
+        let history = (1..=4).map(deposit).collect::<Vec<_>>();
+        // the live source starts at the third record of the history, then repeats the first
+        let live = vec![3, 4, 5, 6, 1]
+            .into_iter()
+            .map(deposit)
+            .collect::<Vec<_>>();
+        let stats = Arc::new(PipelineStats::new());
+        let txs: Vec<_> = chain(
+            Box::new(history.into_iter()),
+            Box::new(live.into_iter()),
+            3,
+            Some(stats.clone()),
+        )
+        .map(|record| record.tx)
+        .collect();
+        // the first record isn't in the window of the last 3 anymore, the account rejects it
+        assert_eq!(txs, [1, 2, 3, 4, 5, 6, 1]);
+        assert_eq!(stats.records_overlapping(), 2);
+    }
+}
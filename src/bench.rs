@@ -1,97 +1,299 @@
-use std::io::{BufWriter, Read, Write};
+/// The throughput benchmarks of `paytoy bench`
+/// An input is generated for each workload profile, then applied with each combination of
+/// reader and account manager, for each thread count, and the wall time of every run is
+/// written as a table. With the same arguments on the same machine, two releases can be
+/// compared row by row
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
+use clap::ValueEnum;
 use log::*;
 
-use crate::{
+use paytoy::{
     account_manager::{MTAccountManager, STAccountManager},
+    fused::FusedPipeline,
     paytoy::PayToyApp,
     transactions_reader::{MTReader, STBulkReader, TransactionCSVReader},
+    workload::{self, WorkloadProfile},
 };
 
-// Benchmarking functions
+/// The seed of the generated inputs, the same for every release
+const SEED: u64 = 1;
 
-/// Generates a large file with records, suitable for benchmarking
-pub fn create_large_test_file(path: &str, num_records: usize, use_all_clients: bool) {
-    let mut writer = BufWriter::new(std::fs::File::create(path).unwrap());
+#[derive(ValueEnum, PartialEq, Debug, Clone, Copy)]
+pub enum BenchReader {
+    /// The single threaded bulk reader
+    St,
+    /// The multithreaded reader
+    Mt,
+}
 
-    writer
-        .write_fmt(format_args!("type,  client,  tx,  amount\n"))
-        .unwrap();
+#[derive(ValueEnum, PartialEq, Debug, Clone, Copy)]
+pub enum BenchManager {
+    /// The single threaded account manager
+    St,
+    /// The multithreaded account manager
+    Mt,
+    /// The rayon account manager (requires the `rayon` feature)
+    Rayon,
+    /// The fused pipeline, only with the multithreaded reader
+    Fused,
+}
 
-    for trans_id in (1..num_records + 1).step_by(2) {
-        let mut client_id = 1u16;
-        if use_all_clients {
-            client_id = (trans_id / 2 % 65536) as u16;
-        }
+#[derive(ValueEnum, PartialEq, Debug, Clone, Copy)]
+pub enum TableFormat {
+    Markdown,
+    Csv,
+}
 
-        let write_trans = &mut |tr_type: &str, trans_id, amount: &str| {
-            let _ = writer.write_fmt(format_args!(
-                "{},  {},  {},  {}\n",
-                tr_type, client_id, trans_id, amount
+/// The combinations to run, each list is swept in turn
+pub struct BenchMatrix {
+    pub records: usize,
+    pub readers: Vec<BenchReader>,
+    pub managers: Vec<BenchManager>,
+    pub threads: Vec<usize>,
+    pub workloads: Vec<WorkloadProfile>,
+}
+
+/// A run of the matrix, threads is None for the single threaded reader and manager
+struct BenchRun {
+    reader: BenchReader,
+    manager: BenchManager,
+    threads: Option<usize>,
+    workload: WorkloadProfile,
+    elapsed: Duration,
+}
+
+impl BenchMatrix {
+    /// The thread counts of `--matrix`: the powers of two below the cores, and the cores
+    pub fn thread_counts(cores: usize) -> Vec<usize> {
+        let mut threads: Vec<usize> = std::iter::successors(Some(1), |threads| Some(threads * 2))
+            .take_while(|threads| *threads < cores)
+            .collect();
+        threads.push(cores.max(1));
+        threads
+    }
+
+    /// Runs all the combinations and writes a row for each of them
+    pub fn run(&self, format: TableFormat, writer: &mut impl Write) -> anyhow::Result<()> {
+        write_header(format, writer)?;
+        for workload in &self.workloads {
+            let path = std::env::temp_dir().join(format!(
+                "paytoy_bench_{}_{}.csv",
+                workload.name(),
+                self.records
             ));
-        };
+            generate_input(&path, self.records, *workload)?;
+            let result = self.run_input(&path, *workload, format, writer);
+            let _ = std::fs::remove_file(&path);
+            result?;
+        }
+        Ok(())
+    }
 
-        write_trans("deposit", trans_id, "100000");
-        write_trans(
-            "deposit",
-            trans_id + 1,
-            (100000 - client_id as u32).to_string().as_ref(),
-        );
+    fn run_input(
+        &self,
+        path: &Path,
+        workload: WorkloadProfile,
+        format: TableFormat,
+        writer: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        for reader in &self.readers {
+            for manager in &self.managers {
+                let threads: Vec<Option<usize>> = match (reader, manager) {
+                    (BenchReader::St, BenchManager::Fused) => continue,
+                    // nothing to sweep
+                    (BenchReader::St, BenchManager::St) => vec![None],
+                    _ => self.threads.iter().copied().map(Some).collect(),
+                };
+                for threads in threads {
+                    info!(
+                        "Benchmarking the {:?} reader and the {:?} manager",
+                        reader, manager
+                    );
+                    let elapsed = run_once(path, *reader, *manager, threads.unwrap_or(1))?;
+                    let run = BenchRun {
+                        reader: *reader,
+                        manager: *manager,
+                        threads,
+                        workload,
+                        elapsed,
+                    };
+                    write_row(format, writer, &run, self.records)?;
+                    writer.flush()?;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
-pub fn st_bulk_transaction_reader(path: &str) {
-    let reader = STBulkReader::new();
-    let transactions = reader.read_csv(path).unwrap();
-    for _ in transactions {}
+/// Applies the input once, returns the wall time of the whole run
+fn run_once(
+    path: &Path,
+    reader: BenchReader,
+    manager: BenchManager,
+    threads: usize,
+) -> anyhow::Result<Duration> {
+    let started = Instant::now();
+    match reader {
+        _ if manager == BenchManager::Fused => {
+            let reader = MTReader::new().with_threads(threads);
+            FusedPipeline::new(reader, threads, STAccountManager::new).run(File::open(path)?)?;
+        }
+        BenchReader::St => apply(path, STBulkReader::new(), manager, threads)?,
+        BenchReader::Mt => apply(
+            path,
+            MTReader::new().with_threads(threads),
+            manager,
+            threads,
+        )?,
+    }
+    Ok(started.elapsed())
 }
 
-pub fn mt_transaction_reader(path: &str) {
-    let reader = MTReader::new();
-    let t = std::time::Instant::now();
-    let transactions = reader.read_csv(path).unwrap();
-    let mut num_transactions = 0;
-    for _ in transactions {
-        num_transactions += 1;
+fn apply(
+    path: &Path,
+    reader: impl TransactionCSVReader,
+    manager: BenchManager,
+    threads: usize,
+) -> anyhow::Result<()> {
+    match manager {
+        BenchManager::St => PayToyApp::run(path, reader, STAccountManager::new(), false),
+        BenchManager::Mt => PayToyApp::run(path, reader, MTAccountManager::new(threads), false),
+        BenchManager::Rayon => PayToyApp::run(path, reader, rayon_manager(threads)?, false),
+        BenchManager::Fused => unreachable!("the fused pipeline has its own reader"),
     }
-    info!(
-        "MTReader read {} records in {:?} {:.4} millions/second",
-        num_transactions,
-        t.elapsed(),
-        num_transactions as f32 / (1000000.0 * t.elapsed().as_secs_f32())
-    );
 }
 
-pub fn read_raw_file(path: &str) {
-    let t = std::time::Instant::now();
-    let mut file = std::fs::File::open(path).unwrap();
-    let mut buf = Vec::new();
-    let _ = file.read_to_end(&mut buf);
-    info!("Time to read the raw file: {:?}", t.elapsed());
+#[cfg(feature = "rayon")]
+fn rayon_manager(threads: usize) -> anyhow::Result<impl paytoy::account_manager::AccountManager> {
+    Ok(paytoy::rayon_manager::RayonAccountManager::new(
+        threads,
+        STAccountManager::new,
+    )?)
 }
 
-pub fn st_bulk_application(path: &str, num_transactions: usize) {
-    let t = std::time::Instant::now();
-    PayToyApp::run(path, STBulkReader::new(), STAccountManager::new(), false).unwrap();
-    info!(
-        "Single threaded application time: {:?} {:.4} millions/second",
-        t.elapsed(),
-        num_transactions as f32 / (1000000.0 * t.elapsed().as_secs_f32())
-    );
+#[cfg(not(feature = "rayon"))]
+fn rayon_manager(_threads: usize) -> anyhow::Result<STAccountManager> {
+    Err(paytoy::errors::missing_feature(
+        "the rayon manager",
+        "rayon",
+    ))
 }
 
-pub fn mt_application(path: &str, num_transactions: usize) {
-    let t = std::time::Instant::now();
-    PayToyApp::run(
-        path,
-        MTReader::new().with_threads(num_cpus::get() / 2),
-        MTAccountManager::new(num_cpus::get() / 2),
-        false,
-    )
-    .unwrap();
-    info!(
-        "Multi-threaded application time: {:?} {:.4} millions/second",
-        t.elapsed(),
-        num_transactions as f32 / (1000000.0 * t.elapsed().as_secs_f32())
-    );
+/// Generates the input of a profile
+pub fn generate_input(
+    path: &Path,
+    records: usize,
+    profile: WorkloadProfile,
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    workload::write_csv(&mut writer, profile, records, SEED, None).map(|_| ())
+}
+
+fn write_header(format: TableFormat, writer: &mut impl Write) -> std::io::Result<()> {
+    match format {
+        TableFormat::Markdown => {
+            writeln!(
+                writer,
+                "reader | manager | threads | workload | records | seconds | millions/second"
+            )?;
+            writeln!(writer, "--- | --- | --- | --- | --- | --- | ---")
+        }
+        TableFormat::Csv => writeln!(
+            writer,
+            "reader,manager,threads,workload,records,seconds,millions_per_second"
+        ),
+    }
+}
+
+fn write_row(
+    format: TableFormat,
+    writer: &mut impl Write,
+    run: &BenchRun,
+    records: usize,
+) -> std::io::Result<()> {
+    let seconds = run.elapsed.as_secs_f64();
+    let fields = [
+        run.reader
+            .to_possible_value()
+            .unwrap()
+            .get_name()
+            .to_string(),
+        run.manager
+            .to_possible_value()
+            .unwrap()
+            .get_name()
+            .to_string(),
+        run.threads
+            .map_or("-".to_string(), |threads| threads.to_string()),
+        run.workload.name().to_string(),
+        records.to_string(),
+        format!("{:.3}", seconds),
+        format!("{:.2}", records as f64 / seconds.max(1e-9) / 1e6),
+    ];
+    match format {
+        TableFormat::Markdown => writeln!(writer, "{}", fields.join(" | ")),
+        TableFormat::Csv => writeln!(writer, "{}", fields.join(",")),
+    }
+}
+
+/// Where `paytoy bench` writes its table
+pub fn open_output(output: Option<&PathBuf>) -> std::io::Result<Box<dyn Write>> {
+    Ok(match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_counts() {
+        assert_eq!(BenchMatrix::thread_counts(1), vec![1]);
+        assert_eq!(BenchMatrix::thread_counts(4), vec![1, 2, 4]);
+        assert_eq!(BenchMatrix::thread_counts(12), vec![1, 2, 4, 8, 12]);
+    }
+
+    #[test]
+    fn test_generate_input() {
+        let path = std::env::temp_dir().join("paytoy_test_bench_input.csv");
+        generate_input(&path, 1000, WorkloadProfile::HotSingleClient).unwrap();
+        let report = PayToyApp::process(
+            STBulkReader::new().read_csv(&path).unwrap(),
+            STAccountManager::new(),
+        );
+        assert_eq!(report.accounts().count(), 1);
+        // the withdrawals beyond the funds
+        assert!(!report.rejects().is_empty());
+    }
+
+    #[test]
+    fn test_matrix() {
+        let matrix = BenchMatrix {
+            records: 1000,
+            readers: vec![BenchReader::St, BenchReader::Mt],
+            managers: vec![BenchManager::St, BenchManager::Mt, BenchManager::Fused],
+            threads: vec![1, 2],
+            workloads: vec![WorkloadProfile::HotSingleClient, WorkloadProfile::Uniform],
+        };
+        let mut table = Vec::new();
+        matrix.run(TableFormat::Csv, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let rows: Vec<Vec<&str>> = table.lines().map(|row| row.split(',').collect()).collect();
+        // st+st once, st+mt, mt+st, mt+mt and mt+fused for each thread count
+        assert_eq!(rows.len(), 1 + 2 * (1 + 4 * 2), "{}", table);
+        assert_eq!(rows[0][0], "reader");
+        assert_eq!(rows[1][..5], ["st", "st", "-", "hot-single-client", "1000"]);
+        assert_eq!(rows[2][..4], ["st", "mt", "1", "hot-single-client"]);
+        assert_eq!(rows[9][..4], ["mt", "fused", "2", "hot-single-client"]);
+        assert_eq!(rows[10][..4], ["st", "st", "-", "uniform"]);
+    }
 }
@@ -0,0 +1,363 @@
+/// A compact fixed-width binary format for the transactions
+/// Parsing CSV dominates the ingest time, so files that are replayed many times
+/// (benchmarks, reruns) can be converted once with `paytoy convert` and read much faster
+///
+/// The file starts with the `MAGIC` bytes, followed by records of `RECORD_SIZE` bytes:
+/// type (u8) | flags (u8) | client (`ClientId` LE) | tx (`TransactionId` LE) | amount (16 bytes,
+/// `Decimal::serialize`). The last byte of the magic tells the widths of the ids, a file is
+/// only read by a build with the same ones
+/// The tenants, merchants and timestamps of the records aren't kept
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use log::*;
+use rust_decimal::Decimal;
+
+use crate::{
+    records::{ClientId, RecordSource, TransactionId, TransactionRecord, TransactionType},
+    stats::PipelineStats,
+    transactions_reader::{MTReader, TransactionCSVReader, TransactionsStream},
+};
+
+const CLIENT_SIZE: usize = std::mem::size_of::<ClientId>();
+const TX_SIZE: usize = std::mem::size_of::<TransactionId>();
+/// Where the amount starts
+const AMOUNT_OFFSET: usize = 2 + CLIENT_SIZE + TX_SIZE;
+const RECORD_SIZE: usize = AMOUNT_OFFSET + 16;
+const MAGIC_PREFIX: &[u8; 7] = b"PAYTOYB";
+const MAGIC: &[u8; 8] = match (CLIENT_SIZE, TX_SIZE) {
+    (2, 4) => b"PAYTOYB1",
+    (4, 4) => b"PAYTOYB2",
+    (8, 4) => b"PAYTOYB3",
+    (2, 8) => b"PAYTOYB4",
+    (4, 8) => b"PAYTOYB5",
+    (8, 8) => b"PAYTOYB6",
+    _ => panic!("unsupported widths of the ids"),
+};
+/// Number of records decoded at once
+const CHUNK_RECORDS: usize = 64 * 1024;
+/// Set in the flags if the record has an amount
+const HAS_AMOUNT: u8 = 1;
+
+/// Writes the records in the binary format, returns the number of written records
+pub fn write_records(
+    writer: impl Write,
+    records: impl Iterator<Item = TransactionRecord>,
+) -> std::io::Result<u64> {
+    let mut writer = BufWriter::new(writer);
+    writer.write_all(MAGIC)?;
+
+    let mut written = 0;
+    let mut buffer = [0u8; RECORD_SIZE];
+    for record in records {
+        encode_record(&record, &mut buffer);
+        writer.write_all(&buffer)?;
+        written += 1;
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Converts a CSV file to the binary format
+/// Returns the number of converted records and the number of the ones that couldn't be parsed
+pub fn convert_csv(input: &Path, output: &Path) -> anyhow::Result<(u64, u64)> {
+    let stats = Arc::new(PipelineStats::new());
+    let records = MTReader::new().with_stats(stats.clone()).read_csv(input)?;
+    let written = write_records(File::create(output)?, records)?;
+    Ok((written, stats.parse_errors()))
+}
+
+fn encode_record(record: &TransactionRecord, buffer: &mut [u8; RECORD_SIZE]) {
+    buffer[0] = match record.tr_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::ChargeBack => 4,
+        TransactionType::Settle => 5,
+        TransactionType::Payment => 6,
+    };
+    buffer[1] = if record.amount.is_some() {
+        HAS_AMOUNT
+    } else {
+        0
+    };
+    buffer[2..2 + CLIENT_SIZE].copy_from_slice(&record.client.to_le_bytes());
+    buffer[2 + CLIENT_SIZE..AMOUNT_OFFSET].copy_from_slice(&record.tx.to_le_bytes());
+    buffer[AMOUNT_OFFSET..].copy_from_slice(&record.amount.unwrap_or_default().serialize());
+}
+
+/// Returns `None` if the record is not valid
+fn decode_record(buffer: &[u8]) -> Option<TransactionRecord> {
+    let tr_type = match buffer[0] {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::ChargeBack,
+        5 => TransactionType::Settle,
+        6 => TransactionType::Payment,
+        _ => return None,
+    };
+    let amount = if buffer[1] & HAS_AMOUNT != 0 {
+        let amount = buffer[AMOUNT_OFFSET..RECORD_SIZE].try_into().unwrap();
+        Some(Decimal::deserialize(amount))
+    } else {
+        None
+    };
+
+    Some(TransactionRecord {
+        tr_type,
+        client: ClientId::from_le_bytes(buffer[2..2 + CLIENT_SIZE].try_into().unwrap()),
+        tenant: None,
+        merchant: None,
+        tx: TransactionId::from_le_bytes(
+            buffer[2 + CLIENT_SIZE..AMOUNT_OFFSET].try_into().unwrap(),
+        ),
+        amount,
+        // not stored in the binary format
+        timestamp: None,
+        source: None,
+    })
+}
+
+/// A single threaded reader, decoding is cheap enough to keep up with the account managers
+#[derive(Clone)]
+pub struct BinaryReader {
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+}
+
+impl BinaryReader {
+    pub fn new() -> Self {
+        Self {
+            stats: None,
+            source: None,
+        }
+    }
+
+    /// Publish the number of read records to the run statistics
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+}
+
+impl TransactionCSVReader for BinaryReader {
+    fn read_from<R: Read + Send + 'static>(self, reader: R) -> anyhow::Result<TransactionsStream> {
+        let mut reader = BufReader::new(reader);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic.starts_with(MAGIC_PREFIX) && &magic != MAGIC {
+            return Err(anyhow::anyhow!(
+                "The binary file was written by a build with other widths of the ids"
+            ));
+        }
+        if &magic != MAGIC {
+            return Err(anyhow::anyhow!(
+                "Not a paytoy binary file, create one with `paytoy convert`"
+            ));
+        }
+
+        Ok(Box::new(BinaryRecords {
+            reader,
+            buffer: vec![0u8; CHUNK_RECORDS * RECORD_SIZE],
+            records: Vec::new().into_iter(),
+            position: 0,
+            stats: self.stats,
+            source: self.source,
+        }))
+    }
+
+    fn with_source(mut self, input: Arc<str>) -> Self {
+        self.source = Some(input);
+        self
+    }
+}
+
+struct BinaryRecords<R: Read> {
+    reader: BufReader<R>,
+    buffer: Vec<u8>,
+    records: std::vec::IntoIter<TransactionRecord>,
+    /// Number of records decoded so far
+    position: u64,
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+}
+
+impl<R: Read> BinaryRecords<R> {
+    /// Fills the buffer as much as possible, returns the number of read bytes
+    fn read_chunk(&mut self) -> std::io::Result<usize> {
+        let mut len = 0;
+        while len < self.buffer.len() {
+            match self.reader.read(&mut self.buffer[len..])? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<R: Read> Iterator for BinaryRecords<R> {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        if let Some(record) = self.records.next() {
+            return Some(record);
+        }
+
+        let len = match self.read_chunk() {
+            Ok(0) => return None,
+            Ok(len) => len,
+            Err(err) => {
+                error!("Failed to read the binary file: {:?}", err);
+                if let Some(stats) = &self.stats {
+                    stats.add_input_error();
+                }
+                return None;
+            }
+        };
+        if len % RECORD_SIZE != 0 {
+            error!("The binary file ends with a truncated record");
+        }
+
+        let mut records = Vec::with_capacity(len / RECORD_SIZE);
+        let mut parse_errors = 0;
+        for buffer in self.buffer[..len].chunks_exact(RECORD_SIZE) {
+            self.position += 1;
+            match decode_record(buffer) {
+                Some(mut record) => {
+                    record.source = RecordSource::tag(self.source.as_ref(), self.position);
+                    records.push(record)
+                }
+                None => parse_errors += 1,
+            }
+        }
+        if let Some(stats) = &self.stats {
+            stats.add_read(records.len() as u64, parse_errors);
+        }
+
+        self.records = records.into_iter();
+        self.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::reader_conformance::{csv_records, ReaderConformance};
+
+    fn record(
+        tr_type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount,
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(2.5))),
+            record(
+                TransactionType::Withdrawal,
+                ClientId::MAX,
+                TransactionId::MAX,
+                Some(dec!(-0.0001)),
+            ),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::ChargeBack, 1, 1, None),
+        ];
+
+        let path = std::env::temp_dir().join("paytoy_test_roundtrip.bin");
+        let written = write_records(File::create(&path).unwrap(), records.into_iter()).unwrap();
+        assert_eq!(written, 4);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (MAGIC.len() + 4 * RECORD_SIZE) as u64
+        );
+
+        let stats = Arc::new(PipelineStats::new());
+        let records: Vec<TransactionRecord> = BinaryReader::new()
+            .with_stats(stats.clone())
+            .read_csv(&path)
+            .unwrap()
+            .collect();
+
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].tr_type, TransactionType::Deposit);
+        assert_eq!(records[0].amount, Some(dec!(2.5)));
+        assert_eq!(records[1].client, ClientId::MAX);
+        assert_eq!(records[1].tx, TransactionId::MAX);
+        assert_eq!(records[1].amount, Some(dec!(-0.0001)));
+        assert_eq!(records[2].tr_type, TransactionType::Dispute);
+        assert_eq!(records[2].amount, None);
+        assert_eq!(records[3].tr_type, TransactionType::ChargeBack);
+        assert_eq!(stats.records_read(), 4);
+    }
+
+    #[test]
+    fn test_other_widths() {
+        let error = |magic: &'static [u8]| {
+            BinaryReader::new()
+                .read_from(magic)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(error(b"PAYTOYB9").contains("other widths of the ids"));
+        assert!(error(b"PAYTOYXX").contains("Not a paytoy binary file"));
+    }
+
+    #[test]
+    fn test_convert_csv() {
+        let path = std::env::temp_dir().join("paytoy_test_convert.bin");
+        let (written, parse_errors) =
+            convert_csv(Path::new("tests/data/test_basic.csv"), &path).unwrap();
+        assert_eq!(written, 5);
+        assert_eq!(parse_errors, 0);
+
+        let records: Vec<TransactionRecord> =
+            BinaryReader::new().read_csv(&path).unwrap().collect();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[4].tr_type, TransactionType::Withdrawal);
+        assert_eq!(records[4].client, 2);
+        assert_eq!(records[4].amount, Some(dec!(3.0)));
+    }
+
+    #[test]
+    fn test_reader_conformance() {
+        ReaderConformance::new(BinaryReader::new)
+            .with_encoding(|csv| {
+                let mut output = Vec::new();
+                write_records(&mut output, csv_records(csv).into_iter()).unwrap();
+                output
+            })
+            .check_all();
+    }
+
+    #[test]
+    fn test_not_binary_file() {
+        assert!(BinaryReader::new()
+            .read_csv("tests/data/test_basic.csv")
+            .is_err());
+    }
+}
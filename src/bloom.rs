@@ -0,0 +1,101 @@
+/// A bloom filter of the transaction ids, for the runs that don't need the disputes
+/// Remembering the ids to detect the duplicates only takes a few bits per transaction,
+/// at the cost of rejecting a small rate of valid transactions as duplicates.
+/// The bits are atomic, so a single filter is shared by all the account manager workers
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::records::{wide_id, AccountKey, TransactionId};
+
+/// Bounds of the number of bits set per transaction
+const MAX_HASHES: u32 = 16;
+
+pub struct BloomFilter {
+    words: Vec<AtomicU64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sizes the filter so that `fp_rate` of the ids are reported as duplicates
+    /// after `capacity` ids were inserted
+    pub fn new(capacity: u64, fp_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let capacity = capacity.max(1) as f64;
+        let num_bits = (-capacity * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / capacity) * ln2).round() as u32;
+        let num_words = num_bits.div_ceil(64);
+        Self {
+            words: (0..num_words).map(|_| AtomicU64::new(0)).collect(),
+            num_bits: num_words * 64,
+            num_hashes: num_hashes.clamp(1, MAX_HASHES),
+        }
+    }
+
+    /// Checks if the transaction of the account may have been inserted
+    pub fn contains(&self, account: AccountKey, tx: TransactionId) -> bool {
+        self.bits(account, tx)
+            .all(|(word, mask)| self.words[word].load(Ordering::Relaxed) & mask != 0)
+    }
+
+    pub fn insert(&self, account: AccountKey, tx: TransactionId) {
+        for (word, mask) in self.bits(account, tx) {
+            self.words[word].fetch_or(mask, Ordering::Relaxed);
+        }
+    }
+
+    /// Memory taken by the bits of the filter
+    pub fn size_bytes(&self) -> u64 {
+        self.num_bits / 8
+    }
+
+    /// Word and mask of the bits of a transaction, with double hashing
+    fn bits(&self, account: AccountKey, tx: TransactionId) -> impl Iterator<Item = (usize, u64)> {
+        // the tenants are shifted by one, so the clients without a tenant have their own ids
+        let tenant = account.tenant.map_or(0, |tenant| tenant as u64 + 1);
+        let first = mix(mix(tenant << 48 ^ wide_id(account.client)) ^ wide_id(tx));
+        let second = mix(first) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |index| {
+            let hash = first.wrapping_add(index.wrapping_mul(second));
+            // maps the hash to [0, num_bits) without a division
+            let bit = ((hash as u128 * num_bits as u128) >> 64) as u64;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+}
+
+/// The finalizer of splitmix64, spreads the bits of the key over the whole hash
+fn mix(key: u64) -> u64 {
+    let mut hash = key.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let filter = BloomFilter::new(100_000, 0.01);
+        for tx in 0..100_000 {
+            filter.insert(1.into(), tx);
+        }
+        // no false negatives
+        assert!((0..100_000).all(|tx| filter.contains(1.into(), tx)));
+
+        let false_positives = (0..100_000)
+            .filter(|tx| {
+                filter.contains(2.into(), *tx) || filter.contains(AccountKey::new(Some(0), 1), *tx)
+            })
+            .count();
+        assert!(
+            false_positives < 4000,
+            "{} false positives",
+            false_positives
+        );
+        // about 9.6 bits per id at 1%
+        assert!(filter.size_bytes() < 100_000 * 10 / 8 + 8);
+    }
+}
@@ -0,0 +1,52 @@
+/// Stopping a run before the end of its input
+/// The readers, the parsers and the account managers given a `CancellationToken` look at it
+/// between records: once it's cancelled they stop reading and applying, and the report of the
+/// records applied so far is flagged as cancelled, see `Report::is_cancelled`
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Shared by the stages of a run, any clone cancels all of them
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancelled when the `flag` is set, e.g. by a signal handler
+    pub fn from_flag(flag: Arc<AtomicBool>) -> Self {
+        Self { cancelled: flag }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!token.is_cancelled());
+        clone.cancel();
+        assert!(token.is_cancelled());
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let token = CancellationToken::from_flag(flag.clone());
+        flag.store(true, Ordering::Relaxed);
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,782 @@
+use std::{ops::RangeInclusive, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use paytoy::{
+    account_manager::OverLimit,
+    accounts_meta::AccountsMeta,
+    affinity::CoreList,
+    client_account::{DisputeExpiry, DisputePolicy},
+    errors::FailOn,
+    input::StreamPolicy,
+    input_encoding::InputEncoding,
+    input_slice::InputSlice,
+    memory,
+    multi_input::ReadMode,
+    period_report::Period,
+    record_filter::{self, RecordFilter},
+    record_parser::AmountFormat,
+    records::{ClientId, TransactionId, TransactionType},
+    report::{ColorChoice, ReportFormat, ReportOptions},
+    report_merge::MergePolicy,
+    statement::StatementFormat,
+    workload::{Corruption, WorkloadProfile},
+};
+
+use crate::{
+    bench::{BenchManager, BenchReader, TableFormat},
+    logging::LogFormat,
+};
+
+/// Simulates transaction handling on a list of clients
+#[derive(Parser, Debug)]
+#[command(name = "paytoy", version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// File with the transactions to process, an http(s) URL (requires the `http` feature)
+    /// or an `s3://`, `gs://` or `az://` URL (requires the `object-store` feature).
+    /// `.gz` inputs are decompressed on the fly and all the CSV files of `.zip` archives are
+    /// processed in order (requires the `zip` feature).
+    /// Several inputs are processed as a single stream of transactions
+    pub inputs: Vec<PathBuf>,
+
+    /// How the records of several inputs are combined
+    #[arg(long, value_enum, default_value_t = ReadMode::Sequential)]
+    pub read_mode: ReadMode,
+
+    /// Encoding of the CSV inputs, the Latin-1 ones are transcoded to UTF-8 (a UTF-8 byte
+    /// order mark and the `\r` line endings are handled in both)
+    #[arg(long, value_enum, default_value_t = InputEncoding::Utf8)]
+    pub encoding: InputEncoding,
+
+    /// How the amounts of the CSV inputs are written, with thousands separators or a decimal
+    /// comma for the inputs from other locales
+    #[arg(long, value_enum, default_value_t = AmountFormat::Plain)]
+    pub amount_format: AmountFormat,
+
+    /// Strip the currency symbols (`$`, `€`, `£`) of the amounts and the spaces around them,
+    /// instead of rejecting the amounts, those fixed are counted in the summary
+    #[arg(long)]
+    pub lenient_amounts: bool,
+
+    /// Skip the first records of the CSV input, after `--start-offset`
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub skip_records: u64,
+
+    /// Only process this many records of the CSV input, parse errors included
+    #[arg(long, value_name = "N")]
+    pub limit: Option<u64>,
+
+    /// Only read the records of these transaction types from the CSV inputs, e.g.
+    /// `deposit,withdrawal`
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    pub only_types: Vec<TransactionType>,
+
+    /// Only read the records of these clients from the CSV inputs, e.g. `1000-1999`
+    #[arg(long, value_name = "FIRST-LAST", value_parser = record_filter::parse_client_range)]
+    pub client_range: Option<RangeInclusive<ClientId>>,
+
+    /// Only apply the records of this share of the accounts, picked at random, to smoke test a
+    /// new feed quickly. The run is marked as sampled in the summary
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub sample: Option<f64>,
+
+    /// Seed of `--sample`, another seed picks other accounts
+    #[arg(long, default_value_t = 1)]
+    pub seed: u64,
+
+    /// Start reading the CSV input at this byte offset, moved to the start of the next line
+    /// (the header line is still read)
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    pub start_offset: u64,
+
+    /// Exit with a nonzero code if records of this kind were dropped (the report is still written)
+    #[arg(long, value_enum, default_value_t = FailOn::None)]
+    pub fail_on: FailOn,
+
+    /// Copy the CSV lines that can't be parsed verbatim to this file, after the header
+    /// of the input, so they can be repaired and processed again
+    #[arg(long, value_name = "FILE")]
+    pub dead_letter: Option<PathBuf>,
+
+    /// Keep the input and the line of every record, to trace the rejects back to their origin
+    #[arg(long)]
+    pub tag_sources: bool,
+
+    /// Log every step of the records of this transaction id, from the input line they were
+    /// read at to the outcome of applying them (logged even when the logs are off)
+    #[arg(long, value_name = "TX")]
+    pub trace_tx: Option<TransactionId>,
+
+    /// Check the invariants of the account after every record, and abort the run with the
+    /// record and the balances before and after it on the first one broken
+    #[arg(long)]
+    pub check_invariants: bool,
+
+    /// Only process the entries of a zip archive matching this glob, e.g. `2021-07/*.csv`
+    #[arg(long)]
+    pub zip_entries: Option<String>,
+
+    /// How long the remote inputs wait to connect to their server
+    #[arg(long, value_name = "SECONDS", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub connect_timeout: u64,
+
+    /// How long the remote inputs wait for data before resuming the download
+    #[arg(long, value_name = "SECONDS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub read_timeout: u64,
+
+    /// How many times in a row a remote input is resumed before the run gives up on it
+    #[arg(long, value_name = "TIMES", default_value_t = 5)]
+    pub retries: u32,
+
+    /// Delay before resuming a remote input, doubled on each attempt in a row
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 500)]
+    pub retry_delay: u64,
+
+    /// The longest delay before resuming a remote input
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub max_retry_delay: u64,
+
+    /// Format of the input file
+    #[arg(long, value_enum, default_value_t = InputFormat::Csv)]
+    pub format: InputFormat,
+
+    /// Run the pipeline on a single thread or on thread pools
+    #[arg(long, value_enum, default_value_t = ExecutionMode::Auto)]
+    pub mode: ExecutionMode,
+
+    /// Give the same logs, events and report at every run of the same inputs, to reproduce
+    /// the bugs of the multithreaded mode: a single parser, as many workers on any machine,
+    /// each record applied once the previous one is, and the accounts of the report sorted
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Write the order the workers of the multithreaded mode apply the records in to this file
+    #[arg(long, value_name = "FILE", conflicts_with = "replay_schedule")]
+    pub record_schedule: Option<PathBuf>,
+
+    /// Apply the records in the order of a schedule written by `--record-schedule`, with as
+    /// many workers, to reproduce a run of the multithreaded mode
+    #[arg(long, value_name = "FILE", conflicts_with = "deterministic")]
+    pub replay_schedule: Option<PathBuf>,
+
+    /// Approximate memory budget, e.g. `2G`: the reading is held back when the blocks in flight
+    /// don't fit, and the run is aborted if the transaction history alone exceeds it
+    #[arg(long, value_name = "SIZE", value_parser = memory::parse_size)]
+    pub max_memory: Option<u64>,
+
+    /// Size of the blocks the multithreaded reader parses, e.g. `256K`, instead of the size
+    /// picked from the size of the inputs and the cores (see the profile for the picked one)
+    #[arg(long, value_name = "SIZE", value_parser = parse_block_size)]
+    pub block_size: Option<u64>,
+
+    /// Pin the reader thread of the multithreaded CSV reader to these cores, e.g. `0` or `0-1`
+    /// (the reorder thread gets the second core, if any). Linux only
+    #[arg(long, value_name = "CORES", value_parser = CoreList::parse)]
+    pub reader_cores: Option<CoreList>,
+
+    /// Pin the parser threads of the multithreaded CSV reader to these cores, in turn, e.g.
+    /// `2-5,8`. Linux only
+    #[arg(long, value_name = "CORES", value_parser = CoreList::parse)]
+    pub parser_cores: Option<CoreList>,
+
+    /// Pin the workers of the multithreaded and fused modes to these cores, in turn. Linux only
+    #[arg(long, value_name = "CORES", value_parser = CoreList::parse)]
+    pub worker_cores: Option<CoreList>,
+
+    /// Spread the workers of the multithreaded mode over the NUMA nodes, each worker running on
+    /// the cores of its node with its queue and accounts in the memory of the node. Linux only
+    #[arg(long, conflicts_with = "worker_cores")]
+    pub numa: bool,
+
+    /// Send the clients never seen before to the least busy worker of the multithreaded mode
+    /// when the queue of their shard stays full, instead of waiting for it. A client stays with
+    /// the worker it was first sent to, so its records are still applied in order
+    #[arg(
+        long,
+        conflicts_with_all = ["deterministic", "record_schedule", "replay_schedule"]
+    )]
+    pub spill_clients: bool,
+
+    /// Keep at most this many deposits in memory per account, the older ones are written to
+    /// a temporary file and read back when they are disputed
+    #[arg(long, value_name = "ENTRIES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub history_limit: Option<u64>,
+
+    /// Directory of the files of the deposits evicted by `--history-limit`
+    /// (the temporary directory by default)
+    #[arg(long, value_name = "DIR", requires = "history_limit")]
+    pub history_dir: Option<PathBuf>,
+
+    /// How the duplicate transaction ids are detected
+    #[arg(long, value_enum, default_value_t = Dedup::History)]
+    pub dedup: Dedup,
+
+    /// Rate of the valid transactions rejected as duplicates with `--dedup bloom`
+    #[arg(long, value_name = "RATE", default_value_t = 0.001, value_parser = parse_rate)]
+    pub bloom_fp_rate: f64,
+
+    /// Number of transactions the bloom filter is sized for, estimated from the size of the
+    /// inputs by default (set it for compressed or remote inputs)
+    #[arg(long, value_name = "TRANSACTIONS")]
+    pub bloom_capacity: Option<u64>,
+
+    /// Reject the deposits and withdrawals that reuse the transaction id of another client
+    #[arg(long)]
+    pub unique_tx_ids: bool,
+
+    /// Drop the records repeating one of the last RECORDS records (same type, client, id
+    /// and amount), for inputs from producers that deliver at least once
+    #[arg(long, value_name = "RECORDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub replay_window: Option<u64>,
+
+    /// Forget the records of the replay window after this many seconds
+    #[arg(long, value_name = "SECONDS", requires = "replay_window")]
+    pub replay_ttl: Option<u64>,
+
+    /// Apply this historical input first, then go on with the inputs, e.g. a live stream
+    /// starting a little before the end of the history
+    #[arg(long, value_name = "FILE")]
+    pub backfill: Option<PathBuf>,
+
+    /// Drop the records of the inputs repeating one of the last RECORDS records of the
+    /// backfill (same type, client, id and amount)
+    #[arg(
+        long,
+        value_name = "RECORDS",
+        default_value_t = 100_000,
+        requires = "backfill",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub backfill_overlap: u64,
+
+    /// Process at most this many records per second, from all the inputs
+    #[arg(long, value_name = "RECORDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub rate_limit: Option<u64>,
+
+    /// Read at most this many records per second from each input, so one input can't starve
+    /// the others with `--read-mode parallel`
+    #[arg(long, value_name = "RECORDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub input_rate_limit: Option<u64>,
+
+    /// Where the funds held by a dispute come from when the available funds don't cover it
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = DisputePolicy::RequireAvailable)]
+    pub dispute_hold: DisputePolicy,
+
+    /// Settle the disputes that get no resolve or chargeback within this period, in the unit
+    /// of the timestamps of the records
+    #[arg(long, value_name = "PERIOD")]
+    pub dispute_expiry: Option<u64>,
+
+    /// What happens to a dispute once its period is over
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = DisputeExpiry::Resolve, requires = "dispute_expiry")]
+    pub expiry_action: DisputeExpiry,
+
+    /// Keep the funds of the withdrawals pending out until a `settle` record settles them
+    #[arg(long)]
+    pub settle_withdrawals: bool,
+
+    /// Settle the pending withdrawals after this delay anyway, in the unit of the timestamps
+    #[arg(long, value_name = "DELAY", requires = "settle_withdrawals")]
+    pub settlement_delay: Option<u64>,
+
+    /// CSV file of recurring deposits and withdrawals (`client`, `type`, `amount`, `period`
+    /// and optionally `tenant` and `start`), injected at their timestamps into the records
+    #[arg(long, value_name = "FILE")]
+    pub schedule: Option<PathBuf>,
+
+    /// Transaction types still accepted by the locked accounts, e.g. `resolve,chargeback`
+    /// to settle their disputes in progress (none by default)
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    pub locked_allow: Vec<TransactionType>,
+
+    /// Transaction types rejected by the run, e.g. `withdrawal` for a read-only replay or
+    /// `dispute,resolve,chargeback` where the disputes aren't supported
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    pub disable_types: Vec<TransactionType>,
+
+    /// Flag the clients that get more records than this in the run, in the `over_limit`
+    /// column of the report
+    #[arg(long, value_name = "COUNT")]
+    pub max_client_transactions: Option<u64>,
+
+    /// What happens to the records of a client over its limit
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = OverLimit::Flag, requires = "max_client_transactions")]
+    pub over_limit: OverLimit,
+
+    /// File of the only client ids accepted, one per line, the records of the other clients
+    /// are rejected as `client_blocked`
+    #[arg(long, value_name = "FILE")]
+    pub allow_clients: Option<PathBuf>,
+
+    /// File of the client ids whose records are rejected as `client_blocked`, one per line
+    #[arg(long, value_name = "FILE")]
+    pub block_clients: Option<PathBuf>,
+
+    /// Print the report in the plain CSV-like format instead of a table
+    #[arg(long, conflicts_with = "report_format")]
+    pub plain: bool,
+
+    /// Format of the report
+    #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+    pub report_format: ReportFormat,
+
+    /// Write the report to a file instead of stdout, to a SQLite database with
+    /// `sqlite://path.db` (requires the `sqlite` feature) or upsert the accounts into
+    /// a PostgreSQL table with `postgres://user@host/db` (requires the `postgres` feature)
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+
+    /// Write an intermediate report every N transactions, to the output (replaced at once
+    /// if it's a file) or to stdout, so long runs produce partial results
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub checkpoint_every: Option<u64>,
+
+    /// Number of decimal places of the amounts in the report
+    #[arg(long, default_value_t = 4)]
+    pub precision: usize,
+
+    /// Table the accounts are upserted into for a PostgreSQL output
+    #[arg(long, default_value = "accounts")]
+    pub table: String,
+
+    /// CSV file with the `client`, `name`, `tier` and `country` of the accounts (and their
+    /// `tenant`), added as columns of the report and of the SQLite accounts table
+    #[arg(long, value_name = "FILE")]
+    pub accounts_meta: Option<PathBuf>,
+
+    /// Publish an event as JSON each time a transaction changes the balances of an account
+    /// or locks it, as lines of this file (or named pipe), or to a Kafka topic with
+    /// `kafka://broker:9092/topic` (requires the `kafka` feature)
+    #[arg(long, value_name = "TARGET")]
+    pub events: Option<String>,
+
+    /// Write a statement of every account to this directory, with all its applied
+    /// transactions (they are kept in memory until the end of the run)
+    #[arg(long, value_name = "DIR")]
+    pub statements: Option<PathBuf>,
+
+    /// Write the disputes still open at the end of the run to this file, with the number and
+    /// the amount of the disputes in progress and expired of each account
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["stream_report", "output_shards"]
+    )]
+    pub disputes_report: Option<PathBuf>,
+
+    /// Scan the accounts at the end of the run and write the ones needing a manual review to
+    /// this file: negative available funds, more held than their total, locked with held funds
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["stream_report", "output_shards"]
+    )]
+    pub anomalies: Option<PathBuf>,
+
+    /// Split the report into N files next to the output, `accounts.csv` becoming
+    /// `accounts-0.csv`, ..., partitioned by a hash of the accounts. Each worker writes its own
+    /// accounts, the files are in the plain format with the tenant column
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "output",
+        conflicts_with_all = ["checkpoint_every", "statements"]
+    )]
+    pub output_shards: Option<u64>,
+
+    /// Have each worker write its accounts to the report when it finishes, in turn, instead
+    /// of merging them first, so they aren't held twice. The report is in the plain format
+    #[arg(
+        long,
+        conflicts_with_all = ["report_format", "checkpoint_every", "statements", "output_shards"]
+    )]
+    pub stream_report: bool,
+
+    /// Close an accounting period at each day or month of the timestamps, taken as Unix
+    /// timestamps in seconds, and write the movements of the accounts in each of them
+    #[arg(long, value_enum, requires = "period_report")]
+    pub period: Option<Period>,
+
+    /// Where the report of the periods is written, with a row per account and period
+    #[arg(long, value_name = "FILE", requires = "period")]
+    pub period_report: Option<PathBuf>,
+
+    /// Where the ledger of the merchants credited by the `payment` records is written
+    #[arg(long, value_name = "FILE")]
+    pub merchant_ledger: Option<PathBuf>,
+
+    /// Pay out the merchants at every multiple of this period, in the unit of the timestamps
+    #[arg(long, value_name = "PERIOD", requires = "merchant_ledger", value_parser = clap::value_parser!(u64).range(1..))]
+    pub merchant_settlement: Option<u64>,
+
+    /// Save the state of the accounts to this file at the end of the run, with the history
+    /// their disputes need, so a later run can go on from it with `--restore`
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["history_limit", "stream_report", "output_shards"]
+    )]
+    pub snapshot: Option<PathBuf>,
+
+    /// Start from the accounts saved by `--snapshot`, the snapshots of older versions of
+    /// paytoy are upgraded
+    #[arg(long, value_name = "FILE")]
+    pub restore: Option<PathBuf>,
+
+    /// Also save the snapshot every SECS seconds during the run, the previous one is kept
+    /// next to it as `.prev`
+    #[arg(
+        long,
+        value_name = "SECS",
+        requires = "snapshot",
+        conflicts_with = "checkpoint_every",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub snapshot_every: Option<u64>,
+
+    /// Start from the latest valid snapshot of `--snapshot` if there's one, to recover from a
+    /// run that crashed
+    #[arg(long, requires = "snapshot", conflicts_with = "restore")]
+    pub resume: bool,
+
+    /// Process the inputs already applied to the restored snapshot again, they're skipped
+    /// otherwise so a file isn't posted twice
+    #[arg(long)]
+    pub reprocess: bool,
+
+    /// Write a resume token to this file at the end of the run, with the position of the last
+    /// record applied, so an interrupted run can go on with `--resume-from` (a single CSV
+    /// input). Ctrl-C stops the run cleanly
+    #[arg(long, value_name = "FILE")]
+    pub resume_token: Option<PathBuf>,
+
+    /// Go on with the input after the position of a resume token, once checked that the input
+    /// hasn't changed. The accounts are restored with `--restore` or `--resume`
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["start_offset", "skip_records"])]
+    pub resume_from: Option<PathBuf>,
+
+    /// Apply the records to a copy of the accounts in memory and save nothing, to preview the
+    /// effect of a file: the report, the `--events` file and the logs show each change and
+    /// rejection they would make. The snapshot of `--restore` is only read
+    #[arg(long, conflicts_with_all = ["snapshot", "resume_token"])]
+    pub simulate_only: bool,
+
+    /// Format of the statements
+    #[arg(long, value_enum, default_value_t = StatementFormat::Camt053)]
+    pub statement_format: StatementFormat,
+
+    /// Highlight the locked accounts in the table
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Show a live dashboard on stderr while processing (requires the `tui` feature)
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Format of the logs written on stderr, `RUST_LOG` sets the level
+    /// (the text logs are off by default, the JSON ones start at `info`)
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Log the state of the queues when no record is processed for this many seconds,
+    /// e.g. because of a hung reader or a wedged channel
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub watchdog: Option<u64>,
+
+    /// Abort the run (exit code 6) when the watchdog finds it stalled
+    #[arg(long, requires = "watchdog")]
+    pub watchdog_abort: bool,
+
+    /// Print the time spent by each thread in the read, parse, dispatch and apply stages
+    /// on stderr (only the CSV inputs time the reading and the parsing)
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Profile the run and write the timed spans in the chrome tracing format to this file
+    #[arg(long, value_name = "FILE")]
+    pub profile_trace: Option<PathBuf>,
+
+    /// Measure how long each record takes to apply, and print the percentiles of each
+    /// transaction type on stderr (and on the `--tui` dashboard, live)
+    #[arg(long)]
+    pub latency: bool,
+
+    /// Print the records, share of the records, accounts, throughput and queue wait of each
+    /// worker on stderr, to spot the shards getting most of the clients
+    #[arg(long)]
+    pub worker_stats: bool,
+}
+
+#[derive(ValueEnum, PartialEq, Debug, Clone, Copy)]
+pub enum InputFormat {
+    Csv,
+    /// Parquet file with the same columns as the CSV (requires the `parquet` feature)
+    Parquet,
+    /// Avro container file with the same fields as the CSV columns (requires the `avro` feature)
+    Avro,
+    /// Length-prefixed MessagePack frames (requires the `msgpack` feature)
+    #[value(name = "msgpack")]
+    MsgPack,
+    /// Binary file created by `paytoy convert`, the fastest to read
+    #[value(name = "bin")]
+    Binary,
+}
+
+/// Below that size, starting the thread pools takes longer than processing on a single thread
+const SMALL_INPUT_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(ValueEnum, PartialEq, Debug, Clone, Copy)]
+pub enum ExecutionMode {
+    /// Single threaded for small local inputs or with less than 4 cores, multithreaded otherwise
+    Auto,
+    /// Single threaded reader and account manager
+    Single,
+    /// Multithreaded reader and account manager
+    Multi,
+    /// Multithreaded reader, the records are applied at the end by the rayon threads
+    /// (`--features rayon`), for the bulk replays
+    Rayon,
+    /// Multithreaded reader whose parsers route the records straight to the workers, for a
+    /// single CSV input
+    Fused,
+}
+
+impl ExecutionMode {
+    /// Picks the mode for inputs of `size` bytes, unknown for remote inputs
+    pub fn resolve(self, size: Option<u64>, num_cores: usize) -> Self {
+        match self {
+            ExecutionMode::Auto => {
+                let small = size.is_some_and(|size| size < SMALL_INPUT_SIZE);
+                if small || num_cores < 4 {
+                    ExecutionMode::Single
+                } else {
+                    ExecutionMode::Multi
+                }
+            }
+            mode => mode,
+        }
+    }
+}
+
+#[derive(ValueEnum, PartialEq, Debug, Clone, Copy)]
+pub enum Dedup {
+    /// Keep the deposits of every account, so they can be disputed
+    History,
+    /// Only remember the ids in a bloom filter: much less memory, but no disputes and a small
+    /// rate of valid transactions rejected as duplicates
+    Bloom,
+}
+
+/// A size of at least 1 KiB
+fn parse_block_size(size: &str) -> Result<u64, String> {
+    match memory::parse_size(size)? {
+        size if size >= 1024 => Ok(size),
+        _ => Err(format!("`{}` is smaller than 1K", size)),
+    }
+}
+
+/// A rate strictly between 0 and 1
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate < 1.0 => Ok(rate),
+        _ => Err(format!("`{}` is not a rate between 0 and 1", rate)),
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Start an interactive session where transactions can be typed and accounts queried
+    Repl,
+    /// Convert a CSV file to the binary format, to speed up repeated runs over the same data
+    Convert {
+        /// CSV file with the transactions
+        input: PathBuf,
+        /// Binary file to create, read it back with `--format bin`
+        output: PathBuf,
+    },
+    /// Combine the reports of independent runs, e.g. of different regions, into one
+    MergeReports {
+        /// Reports in the plain or the table format
+        #[arg(required = true, num_args = 2..)]
+        reports: Vec<PathBuf>,
+        /// Write the merged report to a file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// What to do with an account found in several reports
+        #[arg(long, value_enum, default_value_t = MergePolicy::Sum)]
+        conflict: MergePolicy,
+        /// Format of the merged report
+        #[arg(long, value_enum, default_value_t = ReportFormat::Plain)]
+        report_format: ReportFormat,
+    },
+    /// Show the changes of the accounts between the reports of two runs
+    Delta {
+        /// Report of the earlier run, in the plain or the table format
+        #[arg(long)]
+        before: PathBuf,
+        /// Report of the later run
+        #[arg(long)]
+        after: PathBuf,
+        /// Write the changes to a file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Measure the throughput of the readers and the account managers on generated inputs
+    Bench {
+        /// Sweep the thread counts (the powers of two up to the cores) and all the workload
+        /// profiles, instead of all the cores on the uniform workload
+        #[arg(long)]
+        matrix: bool,
+        /// Records of each generated input
+        #[arg(long, default_value_t = 1_000_000)]
+        records: usize,
+        /// The readers to run, all of them by default
+        #[arg(long, value_enum, value_delimiter = ',')]
+        readers: Vec<BenchReader>,
+        /// The account managers to run, all of them by default (but `rayon` without the
+        /// `rayon` feature)
+        #[arg(long, value_enum, value_delimiter = ',')]
+        managers: Vec<BenchManager>,
+        /// The thread counts of the multithreaded readers and managers
+        #[arg(long, value_delimiter = ',', value_name = "N,...")]
+        threads: Vec<usize>,
+        /// The profiles of the generated inputs
+        #[arg(long, value_enum, value_delimiter = ',')]
+        workloads: Vec<WorkloadProfile>,
+        /// Format of the results table
+        #[arg(long, value_enum, default_value_t = TableFormat::Markdown)]
+        format: TableFormat,
+        /// Write the results table to a file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Write a synthetic CSV input, the same for a given profile and seed
+    Generate {
+        /// How the records are spread over the clients and the transaction types
+        #[arg(long, value_enum, default_value_t = WorkloadProfile::Uniform)]
+        profile: WorkloadProfile,
+        /// Records to write
+        #[arg(long, default_value_t = 1_000_000)]
+        records: usize,
+        /// Seed of the generator, another seed gives other records with the same profile
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+        /// Corrupt some of the lines, each with one of these corruptions
+        #[arg(long, value_enum, value_delimiter = ',')]
+        corrupt: Vec<Corruption>,
+        /// The share of the lines corrupted with `--corrupt`
+        #[arg(long, value_name = "RATE", default_value_t = 0.01, value_parser = parse_rate)]
+        corrupt_rate: f64,
+        /// Write the records to a file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Rebuild the accounts from the events of `--events`, and check that they match the
+    /// report of the run
+    ReplayEvents {
+        /// Events written with `--events`, as JSON lines
+        events: PathBuf,
+        /// Report of the run, in the plain or the table format
+        #[arg(long)]
+        report: Option<PathBuf>,
+        /// SHA-256 of the report of the run, from
+        /// `paytoy --deterministic --plain <input> | sha256sum`
+        #[arg(long, value_name = "SHA256")]
+        checksum: Option<String>,
+        /// Write the rebuilt accounts to a file, as a plain report
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Check the columns and a sample of the rows of an input before a long run
+    Inspect {
+        /// The input, a local file or any input paytoy reads
+        input: PathBuf,
+        /// Rows read after the header
+        #[arg(long, default_value_t = 1000)]
+        sample: usize,
+    },
+}
+
+impl Cli {
+    /// The records kept by the readers with `--only-types` and `--client-range`
+    pub fn record_filter(&self) -> RecordFilter {
+        let mut filter = RecordFilter::new();
+        if !self.only_types.is_empty() {
+            filter = filter.with_types(self.only_types.clone());
+        }
+        if let Some(clients) = &self.client_range {
+            filter = filter.with_clients(clients.clone());
+        }
+        filter
+    }
+
+    /// How the remote inputs wait for their server and resume their downloads
+    pub fn stream_policy(&self) -> StreamPolicy {
+        StreamPolicy {
+            connect_timeout: Duration::from_secs(self.connect_timeout),
+            read_timeout: Duration::from_secs(self.read_timeout),
+            retries: self.retries,
+            retry_delay: Duration::from_millis(self.retry_delay),
+            max_retry_delay: Duration::from_secs(self.max_retry_delay),
+        }
+    }
+
+    /// The records of the input read with `--skip-records`, `--limit` and `--start-offset`
+    pub fn input_slice(&self) -> InputSlice {
+        InputSlice {
+            start_offset: self.start_offset,
+            skip_records: self.skip_records,
+            limit: self.limit,
+        }
+    }
+
+    /// The options of the report, reading the accounts metadata
+    pub fn report_options(&self) -> anyhow::Result<ReportOptions> {
+        let meta = match &self.accounts_meta {
+            Some(path) => Some(Arc::new(AccountsMeta::read(path)?)),
+            None => None,
+        };
+        Ok(ReportOptions {
+            format: if self.plain {
+                ReportFormat::Plain
+            } else {
+                self.report_format
+            },
+            precision: self.precision,
+            color: self.color,
+            table: self.table.clone(),
+            meta,
+            sorted: self.deterministic,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_execution_mode() {
+        let big = Some(SMALL_INPUT_SIZE * 10);
+        assert_eq!(
+            ExecutionMode::Auto.resolve(Some(1000), 16),
+            ExecutionMode::Single
+        );
+        assert_eq!(ExecutionMode::Auto.resolve(big, 16), ExecutionMode::Multi);
+        assert_eq!(ExecutionMode::Auto.resolve(big, 2), ExecutionMode::Single);
+        // a remote input is assumed to be big
+        assert_eq!(ExecutionMode::Auto.resolve(None, 16), ExecutionMode::Multi);
+        assert_eq!(
+            ExecutionMode::Multi.resolve(Some(1000), 2),
+            ExecutionMode::Multi
+        );
+        assert_eq!(
+            ExecutionMode::Single.resolve(big, 16),
+            ExecutionMode::Single
+        );
+    }
+}
@@ -1,43 +1,219 @@
-use std::fmt::Display;
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
 
 use hashbrown::HashMap;
 
-use anyhow::Context;
+use log::*;
 use rust_decimal::Decimal;
 
-use crate::records::{ClientId, TransactionId};
+use crate::{
+    bloom::BloomFilter,
+    errors::TransactionError,
+    history_store::HistoryStore,
+    records::{AccountKey, ClientId, TenantId, TransactionId, TransactionRecord, TransactionType},
+    tx_history::{self, TransactionHist, TxHistory},
+};
+
+/// A history store shared by the accounts of a manager
+pub type SharedHistoryStore = Arc<Mutex<dyn HistoryStore>>;
 
 /// Represents a state of a transaction dispute
-#[derive(PartialEq, Debug)]
-enum DisputeProgress {
-    /// Transaction is not disputed
-    Idle,
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DisputeProgress {
     /// Transaction dispute in progress
     InProgress,
+    /// The dispute got no resolve or chargeback in time, its funds stay held until one comes
+    Expired,
 }
 
-/// A historical transaction stored in a database
-struct TransactionHist {
-    /// State of the transaction
-    state: DisputeProgress,
-    /// Amount of money involved
-    amount: Decimal,
+/// Where the funds held by a dispute come from, the payment processors don't all agree
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum DisputePolicy {
+    /// Reject the dispute if the available funds don't cover the disputed amount
+    RequireAvailable,
+    /// Hold the whole disputed amount, even if the available funds become negative
+    AllowNegativeAvailable,
+    /// Hold the available funds, up to the disputed amount
+    HoldUpToAvailable,
 }
 
-impl TransactionHist {
-    fn new(amount: Decimal) -> Self {
-        Self {
-            state: DisputeProgress::Idle,
-            amount,
+/// What happens to a dispute without a resolve or chargeback once its period is over
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum DisputeExpiry {
+    /// Release the held funds, like a resolve
+    Resolve,
+    /// Only mark the dispute as expired, a late resolve or chargeback still settles it
+    Expire,
+}
+
+/// A dispute of a part of a transaction, settled on its own
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct DisputeSlice {
+    /// Numbers the disputes of the account in the order they were opened, from 1, so a
+    /// dispute can be told from a later one of the same transaction
+    pub id: u64,
+    /// The disputed part of the amount of the transaction
+    pub amount: Decimal,
+    /// The funds held by the dispute, less than its amount with `HoldUpToAvailable`
+    pub hold: Decimal,
+    pub state: DisputeProgress,
+}
+
+/// A read-only view of a transaction of the history, for the embedders of the library
+#[derive(PartialEq, Debug, Clone)]
+pub struct HistoryEntry {
+    pub tx: TransactionId,
+    pub amount: Decimal,
+    /// The disputes not settled yet, oldest first
+    pub disputes: Vec<DisputeSlice>,
+}
+
+impl HistoryEntry {
+    /// Check if the transaction is currently disputed, even if the dispute expired
+    pub fn is_disputed(&self) -> bool {
+        !self.disputes.is_empty()
+    }
+
+    /// The part of the amount under dispute
+    pub fn disputed(&self) -> Decimal {
+        self.disputes.iter().map(|dispute| dispute.amount).sum()
+    }
+}
+
+/// A transaction applied to the account, with its effect on the balances
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct JournalEntry {
+    pub tr_type: TransactionType,
+    pub tx: TransactionId,
+    pub timestamp: Option<u64>,
+    /// Change of the available funds, negative when they decrease
+    pub available_change: Decimal,
+    /// Change of the held funds, negative when they decrease
+    pub held_change: Decimal,
+    /// The available funds after the transaction
+    pub available: Decimal,
+    /// The held funds after the transaction
+    pub held: Decimal,
+}
+
+impl JournalEntry {
+    /// Change of the total funds, zero for the disputes and resolves that only move them
+    pub fn total_change(&self) -> Decimal {
+        self.available_change + self.held_change
+    }
+}
+
+/// What an operation did to an account, the only way its state changes
+/// The operations check the transaction, then apply their events with `ClientAccount::apply`:
+/// the events of an account, applied in order to a new one, give back its balances and history
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DomainEvent {
+    FundsDeposited {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    /// `pending` if the funds wait in `pending_out` until the withdrawal is settled
+    FundsWithdrawn {
+        tx: TransactionId,
+        amount: Decimal,
+        pending: bool,
+    },
+    PaymentSent {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    WithdrawalSettled {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    /// A dispute of `amount` of the transaction, holding `hold` of the available funds
+    DisputeOpened {
+        tx: TransactionId,
+        amount: Decimal,
+        hold: Decimal,
+    },
+    /// The oldest dispute of `amount` of the transaction released its `hold`
+    DisputeResolved {
+        tx: TransactionId,
+        amount: Decimal,
+        hold: Decimal,
+    },
+    /// The oldest dispute of `amount` of the transaction took its `hold` out of the account
+    ChargedBack {
+        tx: TransactionId,
+        amount: Decimal,
+        hold: Decimal,
+    },
+    /// The oldest dispute in progress of the transaction expired, its funds stay held
+    DisputeExpired {
+        tx: TransactionId,
+    },
+    /// The account got locked by the transaction
+    AccountLocked {
+        tx: TransactionId,
+    },
+}
+
+impl DomainEvent {
+    /// The transaction of the event
+    pub fn tx(&self) -> TransactionId {
+        match *self {
+            DomainEvent::FundsDeposited { tx, .. }
+            | DomainEvent::FundsWithdrawn { tx, .. }
+            | DomainEvent::PaymentSent { tx, .. }
+            | DomainEvent::WithdrawalSettled { tx, .. }
+            | DomainEvent::DisputeOpened { tx, .. }
+            | DomainEvent::DisputeResolved { tx, .. }
+            | DomainEvent::ChargedBack { tx, .. }
+            | DomainEvent::DisputeExpired { tx }
+            | DomainEvent::AccountLocked { tx } => tx,
+        }
+    }
+
+    /// The name of the event in the published events, `funds_deposited`, ...
+    pub fn name(&self) -> &'static str {
+        match self {
+            DomainEvent::FundsDeposited { .. } => "funds_deposited",
+            DomainEvent::FundsWithdrawn { .. } => "funds_withdrawn",
+            DomainEvent::PaymentSent { .. } => "payment_sent",
+            DomainEvent::WithdrawalSettled { .. } => "withdrawal_settled",
+            DomainEvent::DisputeOpened { .. } => "dispute_opened",
+            DomainEvent::DisputeResolved { .. } => "dispute_resolved",
+            DomainEvent::ChargedBack { .. } => "charged_back",
+            DomainEvent::DisputeExpired { .. } => "dispute_expired",
+            DomainEvent::AccountLocked { .. } => "account_locked",
+        }
+    }
+
+    /// Check if the event changes the available or held funds
+    pub fn moves_balances(&self) -> bool {
+        match *self {
+            DomainEvent::FundsDeposited { amount, .. }
+            | DomainEvent::FundsWithdrawn { amount, .. }
+            | DomainEvent::PaymentSent { amount, .. } => !amount.is_zero(),
+            DomainEvent::DisputeOpened { hold, .. }
+            | DomainEvent::DisputeResolved { hold, .. }
+            | DomainEvent::ChargedBack { hold, .. } => !hold.is_zero(),
+            DomainEvent::WithdrawalSettled { .. }
+            | DomainEvent::DisputeExpired { .. }
+            | DomainEvent::AccountLocked { .. } => false,
         }
     }
 }
 
+/// Approximate memory taken by a transaction of the history, with the overhead of the hashmap
+pub const HISTORY_ENTRY_SIZE: i64 = ((tx_history::ENTRY_SIZE + 1) * 8 / 7) as i64;
+
 /// Represents a client account where transactions can be performed
 pub struct ClientAccount {
     /// Unique identifier for the client account
     /// Not really needed since the manager knows everything about ids
     id: ClientId,
+    /// The tenant of the client, if the records have one
+    tenant: Option<TenantId>,
     /// Total available funds (for trading etc.)
     available: Decimal,
     /// Total held funds
@@ -46,8 +222,28 @@ pub struct ClientAccount {
     locked: bool,
 
     /// Stores all the historical transactions since we should be able to dispute them
-    /// In reality that would be some kind of a database, but a hashmap should work for the moment
-    transaction_history: HashMap<TransactionId, TransactionHist>,
+    /// In reality that would be some kind of a database, but a packed map works for the moment
+    transaction_history: TxHistory,
+    /// Maximum number of transactions kept in memory, the oldest ones go to the store
+    history_limit: Option<(usize, SharedHistoryStore)>,
+    /// Ids of the history, oldest first. May also hold ids that were since removed
+    history_order: VecDeque<TransactionId>,
+    /// Without history, the ids of the deposits are only remembered by a bloom filter
+    seen: Option<Arc<BloomFilter>>,
+    dispute_policy: DisputePolicy,
+    /// The applied transactions in order, only kept if asked for
+    journal: Option<Vec<JournalEntry>>,
+    /// The events applied since they were last taken, only kept if asked for
+    event_log: Option<Vec<DomainEvent>>,
+    /// Funds of the withdrawals waiting for their settlement, still part of the total
+    pending_out: Decimal,
+    /// The withdrawals waiting for their settlement, if they're settled later
+    pending_withdrawals: Option<HashMap<TransactionId, Decimal>>,
+    /// The disputes opened so far, the id of the latest one
+    disputes_opened: u64,
+    /// The records of the run that reached the account, and how many it expects at most
+    transactions: u64,
+    transaction_limit: Option<u64>,
 }
 
 impl ClientAccount {
@@ -55,11 +251,188 @@ impl ClientAccount {
     pub fn new(id: ClientId) -> Self {
         Self {
             id,
+            tenant: None,
             available: Decimal::ZERO,
             held: Decimal::ZERO,
             locked: false,
 
-            transaction_history: HashMap::new(),
+            transaction_history: TxHistory::new(),
+            history_limit: None,
+            history_order: VecDeque::new(),
+            seen: None,
+            dispute_policy: DisputePolicy::RequireAvailable,
+            journal: None,
+            event_log: None,
+            pending_out: Decimal::ZERO,
+            pending_withdrawals: None,
+            disputes_opened: 0,
+            transactions: 0,
+            transaction_limit: None,
+        }
+    }
+
+    /// Puts the account in the partition of a tenant
+    pub fn with_tenant(mut self, tenant: Option<TenantId>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// Keeps at most `limit` transactions in memory, the older ones are moved to the `store`
+    /// The disputes in progress always stay in memory
+    pub fn with_history_limit(mut self, limit: usize, store: SharedHistoryStore) -> Self {
+        self.history_limit = Some((limit, store));
+        self
+    }
+
+    /// Doesn't keep the history, only detects the duplicate ids with the `seen` filter
+    /// The transactions can't be disputed then
+    pub fn with_bloom_filter(mut self, seen: Arc<BloomFilter>) -> Self {
+        self.seen = Some(seen);
+        self
+    }
+
+    /// Picks where the funds held by the disputes come from
+    pub fn with_dispute_policy(mut self, policy: DisputePolicy) -> Self {
+        self.dispute_policy = policy;
+        self
+    }
+
+    /// Keep the applied transactions, for the statements of the account
+    pub fn with_journal(mut self) -> Self {
+        self.journal = Some(Vec::new());
+        self
+    }
+
+    /// Keep the events of the operations until they're taken, for the event sinks
+    pub fn with_event_log(mut self) -> Self {
+        self.event_log = Some(Vec::new());
+        self
+    }
+
+    /// The withdrawals only move the funds to `pending_out`, they leave the account when the
+    /// withdrawal is settled
+    pub fn with_pending_withdrawals(mut self) -> Self {
+        self.pending_withdrawals = Some(HashMap::new());
+        self
+    }
+
+    /// Flags the account once more than `limit` records of the run reached it
+    pub fn with_transaction_limit(mut self, limit: u64) -> Self {
+        self.transaction_limit = Some(limit);
+        self
+    }
+
+    /// A copy of the balances of the account, without its history
+    pub fn snapshot(&self) -> ClientAccount {
+        ClientAccount {
+            available: self.available,
+            held: self.held,
+            locked: self.locked,
+            tenant: self.tenant,
+            pending_out: self.pending_out,
+            pending_withdrawals: self.pending_withdrawals.clone(),
+            transactions: self.transactions,
+            transaction_limit: self.transaction_limit,
+            ..ClientAccount::new(self.id)
+        }
+    }
+
+    /// An account with these balances and no history, like the accounts read back from a report
+    pub fn from_balances(key: AccountKey, available: Decimal, held: Decimal, locked: bool) -> Self {
+        ClientAccount {
+            available,
+            held,
+            locked,
+            tenant: key.tenant,
+            ..ClientAccount::new(key.client)
+        }
+    }
+
+    /// Number of transactions of the history kept in memory
+    pub fn history_len(&self) -> usize {
+        self.transaction_history.len()
+    }
+
+    /// Iterate over the transactions of the history kept in memory, in no particular order
+    /// With a history limit the oldest deposits are in the store and aren't listed, but the
+    /// disputed ones always are
+    pub fn history(&self) -> impl Iterator<Item = HistoryEntry> + '_ {
+        self.transaction_history
+            .iter()
+            .map(|(tx, transaction)| HistoryEntry {
+                tx,
+                amount: transaction.amount,
+                disputes: transaction.disputes,
+            })
+    }
+
+    /// Sets the balances of an account restored from a snapshot
+    pub fn restore_balances(&mut self, available: Decimal, held: Decimal, locked: bool) {
+        self.available = available;
+        self.held = held;
+        self.locked = locked;
+    }
+
+    /// Puts back a transaction of the history saved in a snapshot, with its disputes
+    pub fn restore_transaction(&mut self, entry: HistoryEntry) {
+        if let Some(seen) = &self.seen {
+            seen.insert(self.key(), entry.tx);
+            return;
+        }
+        if let Some(limit) = self.history_limit.as_ref().map(|(limit, _)| *limit) {
+            self.make_room(limit.saturating_sub(1));
+            self.history_order.push_back(entry.tx);
+        }
+        // the ids of the disputes are those of this account, not of the one saved
+        let mut disputes = entry.disputes;
+        for dispute in &mut disputes {
+            self.disputes_opened += 1;
+            dispute.id = self.disputes_opened;
+        }
+        self.transaction_history.insert(
+            entry.tx,
+            TransactionHist {
+                amount: entry.amount,
+                disputes,
+            },
+        );
+    }
+
+    /// Puts back a withdrawal waiting for its settlement saved in a snapshot
+    pub fn restore_pending_withdrawal(&mut self, transaction_id: TransactionId, amount: Decimal) {
+        let pending = self.pending_withdrawals.get_or_insert_with(HashMap::new);
+        if let Some(previous) = pending.insert(transaction_id, amount) {
+            self.pending_out -= previous;
+        }
+        self.pending_out += amount;
+    }
+
+    /// The applied transactions in order, empty unless the journal is kept
+    pub fn journal(&self) -> &[JournalEntry] {
+        self.journal.as_deref().unwrap_or_default()
+    }
+
+    /// The events applied since the last call, empty unless the event log is kept
+    pub fn take_events(&mut self) -> Vec<DomainEvent> {
+        self.event_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Adds an applied record to the journal, given the balances before it was applied
+    pub fn add_to_journal(&mut self, record: &TransactionRecord, before: (Decimal, Decimal)) {
+        let (available, held) = (self.available, self.held);
+        if let Some(journal) = &mut self.journal {
+            journal.push(JournalEntry {
+                tr_type: record.tr_type,
+                tx: record.tx,
+                timestamp: record.timestamp,
+                available_change: available - before.0,
+                held_change: held - before.1,
+                available,
+                held,
+            });
         }
     }
 
@@ -69,6 +442,16 @@ impl ClientAccount {
         self.id
     }
 
+    /// Get the tenant of the account
+    pub fn tenant(&self) -> Option<TenantId> {
+        self.tenant
+    }
+
+    /// The id of the account among all the tenants
+    pub fn key(&self) -> AccountKey {
+        AccountKey::new(self.tenant, self.id)
+    }
+
     /// Get the available funds
     pub fn available(&self) -> Decimal {
         self.available
@@ -79,9 +462,45 @@ impl ClientAccount {
         self.held
     }
 
-    /// Get the total funds
+    /// Get the funds of the withdrawals waiting for their settlement
+    pub fn pending_out(&self) -> Decimal {
+        self.pending_out
+    }
+
+    /// Check if the withdrawals of the account wait for their settlement
+    pub fn settles_withdrawals(&self) -> bool {
+        self.pending_withdrawals.is_some()
+    }
+
+    /// Iterate over the withdrawals waiting for their settlement, in no particular order
+    pub fn pending_withdrawals(&self) -> impl Iterator<Item = (TransactionId, Decimal)> + '_ {
+        self.pending_withdrawals
+            .iter()
+            .flatten()
+            .map(|(tx, amount)| (*tx, *amount))
+    }
+
+    /// Counts a record of the run reaching the account
+    /// Returns false once the records are over the limit
+    pub fn count_transaction(&mut self) -> bool {
+        self.transactions += 1;
+        !self.is_over_limit()
+    }
+
+    /// Check if the account has a limit of records, for the `over_limit` column of the report
+    pub fn has_transaction_limit(&self) -> bool {
+        self.transaction_limit.is_some()
+    }
+
+    /// Check if more records than the limit reached the account
+    pub fn is_over_limit(&self) -> bool {
+        self.transaction_limit
+            .is_some_and(|limit| self.transactions > limit)
+    }
+
+    /// Get the total funds, with the withdrawals that aren't settled yet
     pub fn total(&self) -> Decimal {
-        self.available + self.held
+        self.available + self.held + self.pending_out
     }
 
     /// Check if the account is frozen
@@ -89,134 +508,531 @@ impl ClientAccount {
         self.locked
     }
 
+    /// Checks the balances against each other and against the history, for
+    /// `--check-invariants`. Returns the first invariant that doesn't hold
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if self.held < Decimal::ZERO {
+            return Err(format!("negative held funds {}", self.held));
+        }
+        if self.pending_out < Decimal::ZERO {
+            return Err(format!("negative pending withdrawals {}", self.pending_out));
+        }
+        if self.available < Decimal::ZERO
+            && self.dispute_policy != DisputePolicy::AllowNegativeAvailable
+        {
+            return Err(format!(
+                "negative available funds {} with the {:?} dispute policy",
+                self.available, self.dispute_policy
+            ));
+        }
+        // without history, the disputes are rejected and only a snapshot holds funds
+        if self.seen.is_none() {
+            let holds: Decimal = self
+                .transaction_history
+                .iter()
+                .flat_map(|(_, transaction)| transaction.disputes)
+                .map(|dispute| dispute.hold)
+                .sum();
+            if holds != self.held {
+                return Err(format!(
+                    "held funds {} but the disputes hold {}",
+                    self.held, holds
+                ));
+            }
+        }
+        if let Some(pending) = &self.pending_withdrawals {
+            let pending: Decimal = pending.values().sum();
+            if pending != self.pending_out {
+                return Err(format!(
+                    "pending withdrawals of {} but {} pending out",
+                    pending, self.pending_out
+                ));
+            }
+        }
+        Ok(())
+    }
+
     /// Deposits `amount` to the account with a specific transaction id
     /// Returns an `Error` in case the transaction already exists
     pub fn deposit(
         &mut self,
         transaction_id: TransactionId,
         amount: Decimal,
-    ) -> anyhow::Result<()> {
-        if self.transaction_history.contains_key(&transaction_id) {
-            return Err(anyhow::anyhow!("Transaction already exists",));
+    ) -> Result<(), TransactionError> {
+        if self.is_known(transaction_id)? {
+            return Err(TransactionError::DuplicateTransaction);
         }
 
-        self.available += amount;
-        self.transaction_history
-            .insert(transaction_id, TransactionHist::new(amount));
-
-        Ok(())
+        self.emit(DomainEvent::FundsDeposited {
+            tx: transaction_id,
+            amount,
+        })
     }
 
     /// Withdraws `amount` from the account with a specific transaction id
+    /// With pending withdrawals, the funds wait in `pending_out` until the withdrawal is settled
     /// Returns an `Error` if no there are no sufficient funds or the transaction already exists
     pub fn withdraw(
         &mut self,
         transaction_id: TransactionId,
         amount: Decimal,
-    ) -> anyhow::Result<()> {
-        if self.transaction_history.contains_key(&transaction_id) {
-            return Err(anyhow::anyhow!("Transaction already exists",));
+    ) -> Result<(), TransactionError> {
+        self.check_debit(transaction_id, amount)?;
+        // No need to save history for withdrawals since they're not disputed
+        self.emit(DomainEvent::FundsWithdrawn {
+            tx: transaction_id,
+            amount,
+            pending: self.pending_withdrawals.is_some(),
+        })
+    }
+
+    /// Pays `amount` to a merchant with a specific transaction id, the funds leave the account
+    /// right away, even with pending withdrawals
+    /// Returns an `Error` if no there are no sufficient funds or the transaction already exists
+    pub fn pay(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        // not disputed either, like the withdrawals
+        self.check_debit(transaction_id, amount)?;
+        self.emit(DomainEvent::PaymentSent {
+            tx: transaction_id,
+            amount,
+        })
+    }
+
+    /// Checks that `amount` can be taken from the available funds for a new transaction id
+    fn check_debit(
+        &self,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let pending = self
+            .pending_withdrawals
+            .as_ref()
+            .is_some_and(|pending| pending.contains_key(&transaction_id));
+        if pending || self.is_known(transaction_id)? {
+            return Err(TransactionError::DuplicateTransaction);
         }
 
         if amount > self.available {
-            return Err(anyhow::anyhow!(
-                "Insufficient funds. Requested {} but available {}",
-                amount,
-                self.available
-            ));
+            return Err(TransactionError::InsufficientFunds {
+                requested: amount,
+                available: self.available,
+            });
         }
-
-        self.available -= amount;
-        // No need to save history for withdrawals since they're not disputed
-        // self.transaction_history
-        //     .insert(transaction_id, TransactionHist::new(amount));
-
         Ok(())
     }
 
+    /// Settles a pending withdrawal, its funds leave the account
+    /// Returns an `Error` if the transaction isn't a withdrawal waiting for its settlement
+    pub fn settle(&mut self, transaction_id: TransactionId) -> Result<(), TransactionError> {
+        let amount = self
+            .pending_withdrawals
+            .as_ref()
+            .and_then(|pending| pending.get(&transaction_id).copied())
+            .ok_or(TransactionError::NotPending)?;
+        self.emit(DomainEvent::WithdrawalSettled {
+            tx: transaction_id,
+            amount,
+        })
+    }
+
     /// Represents a client claim to reverse a transaction
     /// Makes available funds decrease by the disputed amount and held funds increase
+    /// The dispute takes what's left undisputed of the transaction
     /// Returns an `Error` in case there is no such transaction with the specified id
-    /// or if the transaction is already disputed
-    pub fn dispute(&mut self, transaction_id: TransactionId) -> anyhow::Result<()> {
+    /// or if all of the transaction is already disputed
+    pub fn dispute(&mut self, transaction_id: TransactionId) -> Result<(), TransactionError> {
+        self.open_dispute(transaction_id, None)
+    }
+
+    /// Disputes only `amount` of the transaction, the resolve or chargeback of the dispute
+    /// only moves that part. The other parts of the transaction can be disputed at the same
+    /// time, each dispute being settled on its own
+    /// Returns an `Error` if the amount isn't positive or is more than what's left undisputed
+    /// of the transaction, on top of the errors of `dispute`
+    pub fn dispute_amount(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.open_dispute(transaction_id, Some(amount))
+    }
+
+    fn open_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Result<(), TransactionError> {
+        self.load(transaction_id)?;
         let transaction = self
             .transaction_history
-            .get_mut(&transaction_id)
-            .with_context(|| "A deposit transaction with such id does not exist")?;
+            .get(transaction_id)
+            .ok_or(TransactionError::UnknownTransaction)?;
 
-        if transaction.state != DisputeProgress::Idle {
-            return Err(anyhow::anyhow!("Dispute already in progress or done"));
+        let undisputed = transaction.undisputed();
+        if !transaction.disputes.is_empty() && undisputed.is_zero() {
+            return Err(TransactionError::AlreadyDisputed);
         }
 
-        if transaction.amount > self.available {
-            return Err(anyhow::anyhow!("Not enough funds to open a dispute"));
-        }
+        let disputed = match amount {
+            Some(amount) if amount <= Decimal::ZERO || amount > undisputed => {
+                return Err(TransactionError::InvalidDisputeAmount {
+                    requested: amount,
+                    undisputed,
+                });
+            }
+            Some(amount) => amount,
+            None => undisputed,
+        };
+        let hold = match self.dispute_policy {
+            DisputePolicy::RequireAvailable if disputed > self.available => {
+                return Err(TransactionError::InsufficientFundsForDispute);
+            }
+            DisputePolicy::RequireAvailable | DisputePolicy::AllowNegativeAvailable => disputed,
+            DisputePolicy::HoldUpToAvailable => disputed.min(self.available.max(Decimal::ZERO)),
+        };
 
-        self.available -= transaction.amount;
-        self.held += transaction.amount;
-        transaction.state = DisputeProgress::InProgress;
-
-        Ok(())
+        self.emit(DomainEvent::DisputeOpened {
+            tx: transaction_id,
+            amount: disputed,
+            hold,
+        })
     }
 
     /// Represents a resolved dispute
     /// Makes available funds increase by the disputed amount and held funds decrease
+    /// With several disputes on the transaction, the oldest one is resolved
     /// Returns an `Error` in case there is no such transaction with the specified id
     /// or the transaction was not disputed in the first place
-    pub fn resolve(&mut self, transaction_id: TransactionId) -> anyhow::Result<()> {
-        let transaction = self
-            .transaction_history
-            .get_mut(&transaction_id)
-            .with_context(|| "Transaction does not exist")?;
+    pub fn resolve(&mut self, transaction_id: TransactionId) -> Result<(), TransactionError> {
+        self.resolve_dispute(transaction_id, None)
+    }
 
-        if transaction.state != DisputeProgress::InProgress {
-            return Err(anyhow::anyhow!(
-                "Cannot resolve a transaction that is not disputed"
-            ));
-        }
+    /// Resolves the oldest dispute of `amount` on the transaction
+    pub fn resolve_amount(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.resolve_dispute(transaction_id, Some(amount))
+    }
 
-        if transaction.amount > self.held {
-            return Err(anyhow::anyhow!(
-                "Not enough held funds to resolve a dispute"
-            ));
+    fn resolve_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Result<(), TransactionError> {
+        // the disputed amount was held, unless the dispute only held what was available
+        let dispute = self.find_dispute(transaction_id, amount)?.1;
+        if dispute.hold > self.held {
+            return Err(TransactionError::InsufficientHeldFunds);
         }
 
-        self.available += transaction.amount;
-        self.held -= transaction.amount;
-        let _ = self.transaction_history.remove(&transaction_id);
-
-        Ok(())
+        self.emit(DomainEvent::DisputeResolved {
+            tx: transaction_id,
+            amount: dispute.amount,
+            hold: dispute.hold,
+        })
     }
 
     /// Represents a chargeback for a dispute
     /// Final state of a dispute, funds that were held are being withdrawn
     /// Client's held funds and total funds shall decrease by the disputed amount
+    /// With several disputes on the transaction, the oldest one is charged back
     /// Returns an `Error` in case there is no such transaction with the specified id
     /// or the transaction was not disputed in the first place
-    pub fn chargeback(&mut self, transaction_id: TransactionId) -> anyhow::Result<()> {
+    pub fn chargeback(&mut self, transaction_id: TransactionId) -> Result<(), TransactionError> {
+        self.chargeback_dispute(transaction_id, None)
+    }
+
+    /// Charges back the oldest dispute of `amount` on the transaction
+    pub fn chargeback_amount(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.chargeback_dispute(transaction_id, Some(amount))
+    }
+
+    fn chargeback_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Result<(), TransactionError> {
+        // the disputed amount was held, unless the dispute only held what was available
+        let dispute = self.find_dispute(transaction_id, amount)?.1;
+        if dispute.hold > self.held {
+            return Err(TransactionError::InsufficientHeldFunds);
+        }
+
+        self.emit(DomainEvent::ChargedBack {
+            tx: transaction_id,
+            amount: dispute.amount,
+            hold: dispute.hold,
+        })?;
+        if self.locked {
+            return Ok(());
+        }
+        self.emit(DomainEvent::AccountLocked { tx: transaction_id })
+    }
+
+    /// The oldest dispute of the transaction, of `amount` if it's given, with its position
+    fn find_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Result<(usize, DisputeSlice), TransactionError> {
+        self.load(transaction_id)?;
         let transaction = self
             .transaction_history
-            .get_mut(&transaction_id)
-            .with_context(|| "Transaction does not exist")?;
+            .get(transaction_id)
+            .ok_or(TransactionError::UnknownTransaction)?;
+        transaction
+            .disputes
+            .into_iter()
+            .enumerate()
+            .find(|(_, dispute)| amount.is_none_or(|amount| dispute.amount == amount))
+            .ok_or(TransactionError::NotDisputed)
+    }
 
-        if transaction.state != DisputeProgress::InProgress {
-            return Err(anyhow::anyhow!(
-                "Cannot resolve a transaction that is not disputed"
-            ));
+    /// Forgets the oldest dispute of `amount` once it's settled, its part of the transaction
+    /// can't be disputed again. The transaction is forgotten too once all of it was disputed
+    fn close_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<DisputeSlice, TransactionError> {
+        let index = self.find_dispute(transaction_id, Some(amount))?.0;
+        let mut transaction = self
+            .transaction_history
+            .get(transaction_id)
+            .ok_or(TransactionError::UnknownTransaction)?;
+        let dispute = transaction.disputes.remove(index);
+        transaction.amount -= dispute.amount;
+        if transaction.amount.is_zero() && transaction.disputes.is_empty() {
+            self.transaction_history.remove(transaction_id);
+        } else {
+            self.transaction_history.insert(transaction_id, transaction);
         }
+        Ok(dispute)
+    }
 
-        if transaction.amount > self.held {
-            return Err(anyhow::anyhow!(
-                "Not enough held funds to chargeback a dispute"
-            ));
+    /// The id of the latest dispute opened on the account, 0 if none was
+    pub fn latest_dispute(&self) -> u64 {
+        self.disputes_opened
+    }
+
+    /// The dispute `id` of the transaction, if it's still in progress: neither settled nor
+    /// expired
+    pub fn dispute_in_progress(
+        &self,
+        transaction_id: TransactionId,
+        id: u64,
+    ) -> Option<DisputeSlice> {
+        self.transaction_history
+            .get(transaction_id)?
+            .disputes
+            .into_iter()
+            .find(|dispute| dispute.id == id && dispute.state == DisputeProgress::InProgress)
+    }
+
+    /// Marks the oldest dispute in progress of the transaction as expired, its funds stay held
+    /// Returns an `Error` if the transaction isn't disputed, or its disputes already expired
+    pub fn expire(&mut self, transaction_id: TransactionId) -> Result<(), TransactionError> {
+        self.emit(DomainEvent::DisputeExpired { tx: transaction_id })
+    }
+
+    /// Applies an event to the state of the account, and keeps it in the event log
+    fn emit(&mut self, event: DomainEvent) -> Result<(), TransactionError> {
+        self.apply(event)?;
+        if let Some(event_log) = &mut self.event_log {
+            event_log.push(event);
+        }
+        Ok(())
+    }
+
+    /// Applies an event to the state of the account, without checking the balances: the
+    /// operations checked them before emitting it. Replaying the events of an account gives
+    /// back its state
+    /// Returns an `Error` if the event refers to a transaction or a dispute the account doesn't
+    /// have, the state is left as it was then
+    pub fn apply(&mut self, event: DomainEvent) -> Result<(), TransactionError> {
+        match event {
+            DomainEvent::FundsDeposited { tx, amount } => {
+                self.available += amount;
+                self.remember(tx, amount);
+            }
+            DomainEvent::FundsWithdrawn {
+                tx,
+                amount,
+                pending,
+            } => {
+                self.available -= amount;
+                if pending {
+                    self.pending_withdrawals
+                        .get_or_insert_with(HashMap::new)
+                        .insert(tx, amount);
+                    self.pending_out += amount;
+                }
+            }
+            DomainEvent::PaymentSent { amount, .. } => self.available -= amount,
+            DomainEvent::WithdrawalSettled { tx, amount } => {
+                if let Some(pending) = &mut self.pending_withdrawals {
+                    pending.remove(&tx);
+                }
+                self.pending_out -= amount;
+            }
+            DomainEvent::DisputeOpened { tx, amount, hold } => {
+                self.load(tx)?;
+                let mut transaction = self
+                    .transaction_history
+                    .get(tx)
+                    .ok_or(TransactionError::UnknownTransaction)?;
+                self.disputes_opened += 1;
+                transaction.disputes.push(DisputeSlice {
+                    id: self.disputes_opened,
+                    amount,
+                    hold,
+                    state: DisputeProgress::InProgress,
+                });
+                self.transaction_history.insert(tx, transaction);
+                self.available -= hold;
+                self.held += hold;
+            }
+            DomainEvent::DisputeResolved { tx, amount, hold } => {
+                self.close_dispute(tx, amount)?;
+                self.available += hold;
+                self.held -= hold;
+            }
+            DomainEvent::ChargedBack { tx, amount, hold } => {
+                self.close_dispute(tx, amount)?;
+                self.held -= hold;
+            }
+            DomainEvent::DisputeExpired { tx } => {
+                let mut transaction = self
+                    .transaction_history
+                    .get(tx)
+                    .ok_or(TransactionError::UnknownTransaction)?;
+                let dispute = transaction
+                    .disputes
+                    .iter_mut()
+                    .find(|dispute| dispute.state == DisputeProgress::InProgress)
+                    .ok_or(TransactionError::NotDisputed)?;
+                dispute.state = DisputeProgress::Expired;
+                self.transaction_history.insert(tx, transaction);
+            }
+            DomainEvent::AccountLocked { .. } => self.locked = true,
+        }
+        Ok(())
+    }
+
+    /// Keeps a deposit in the history, or only its id without history
+    fn remember(&mut self, transaction_id: TransactionId, amount: Decimal) {
+        if let Some(seen) = &self.seen {
+            seen.insert(self.key(), transaction_id);
+            return;
+        }
+        if let Some(limit) = self.history_limit.as_ref().map(|(limit, _)| *limit) {
+            self.make_room(limit.saturating_sub(1));
+            self.history_order.push_back(transaction_id);
         }
+        self.transaction_history
+            .insert(transaction_id, TransactionHist::new(amount));
+    }
 
-        self.held -= transaction.amount;
-        self.locked = true;
-        let _ = self.transaction_history.remove(&transaction_id);
+    /// Checks if the transaction is in the history, in memory or in the store
+    fn is_known(&self, transaction_id: TransactionId) -> Result<bool, TransactionError> {
+        if let Some(seen) = &self.seen {
+            return Ok(seen.contains(self.key(), transaction_id));
+        }
+        if self.transaction_history.contains(transaction_id) {
+            return Ok(true);
+        }
+        match &self.history_limit {
+            Some((_, store)) => store
+                .lock()
+                .unwrap()
+                .contains(self.key(), transaction_id)
+                .map_err(|err| self.store_error(err)),
+            None => Ok(false),
+        }
+    }
 
+    /// Moves the transaction back to memory if it was in the store, before it's disputed
+    fn load(&mut self, transaction_id: TransactionId) -> Result<(), TransactionError> {
+        if self.seen.is_some() {
+            return Err(TransactionError::DisputesDisabled);
+        }
+        if self.transaction_history.contains(transaction_id) {
+            return Ok(());
+        }
+        let (limit, store) = match &self.history_limit {
+            Some((limit, store)) => (*limit, store.clone()),
+            None => return Ok(()),
+        };
+        let amount = store
+            .lock()
+            .unwrap()
+            .take(self.key(), transaction_id)
+            .map_err(|err| self.store_error(err))?;
+        if let Some(amount) = amount {
+            self.make_room(limit.saturating_sub(1));
+            self.transaction_history
+                .insert(transaction_id, TransactionHist::new(amount));
+            self.history_order.push_back(transaction_id);
+        }
         Ok(())
     }
+
+    /// Moves the oldest transactions to the store until at most `len` are left in memory
+    fn make_room(&mut self, len: usize) {
+        let store = match &self.history_limit {
+            Some((_, store)) => store.clone(),
+            None => return,
+        };
+        let mut store = store.lock().unwrap();
+        // each id is looked at once at most, in case all of them are disputed
+        let mut candidates = self.history_order.len();
+        while self.transaction_history.len() > len && candidates > 0 {
+            candidates -= 1;
+            let transaction_id = match self.history_order.pop_front() {
+                Some(transaction_id) => transaction_id,
+                None => break,
+            };
+            let transaction = match self.transaction_history.get(transaction_id) {
+                Some(transaction) => transaction,
+                // resolved or charged back since
+                None => continue,
+            };
+            if self.transaction_history.is_disputed(transaction_id) {
+                self.history_order.push_back(transaction_id);
+                continue;
+            }
+            if let Err(err) = store.put(self.key(), transaction_id, transaction.amount) {
+                // better keep it in memory than lose it
+                warn!(
+                    client = self.id, tx = transaction_id;
+                    "Failed to move a transaction to the history store: {}", err
+                );
+                self.history_order.push_back(transaction_id);
+                break;
+            }
+            self.transaction_history.remove(transaction_id);
+        }
+        // don't let the removed ids pile up
+        if self.history_order.len() > 2 * self.transaction_history.len().max(len) {
+            let history = &self.transaction_history;
+            self.history_order.retain(|id| history.contains(*id));
+        }
+    }
+
+    fn store_error(&self, err: std::io::Error) -> TransactionError {
+        error!(client = self.id; "The history store failed: {}", err);
+        TransactionError::HistoryUnavailable
+    }
 }
 
 impl Display for ClientAccount {
@@ -235,9 +1051,15 @@ impl Display for ClientAccount {
 #[cfg(test)]
 mod tests {
 
+    use std::sync::{Arc, Mutex};
+
     use rust_decimal_macros::dec;
 
-    use super::ClientAccount;
+    use crate::{bloom::BloomFilter, errors::TransactionError, history_store::FileHistoryStore};
+
+    use super::{
+        ClientAccount, DisputePolicy, DisputeProgress, DisputeSlice, DomainEvent, HistoryEntry,
+    };
 
     /*  Basic test case for deposits and withdrawal to the account
         User scenario:
@@ -255,14 +1077,14 @@ mod tests {
         assert_eq!(client.available(), dec!(55.00));
         assert_eq!(client.total(), dec!(55.00));
         assert_eq!(client.held(), dec!(0.00));
-        assert_eq!(client.is_locked(), false);
+        assert!(!client.is_locked());
 
         assert!(client.withdraw(3, dec!(24.00)).is_ok());
 
         assert_eq!(client.available(), dec!(31.00));
         assert_eq!(client.total(), dec!(31.00));
         assert_eq!(client.held(), dec!(0.00));
-        assert_eq!(client.is_locked(), false);
+        assert!(!client.is_locked());
 
         assert!(client.withdraw(4, dec!(44.00)).is_err());
 
@@ -271,7 +1093,7 @@ mod tests {
         assert_eq!(client.available(), dec!(31.00));
         assert_eq!(client.total(), dec!(31.00));
         assert_eq!(client.held(), dec!(0.00));
-        assert_eq!(client.is_locked(), false);
+        assert!(!client.is_locked());
     }
 
     /* User scenario:
@@ -295,7 +1117,7 @@ mod tests {
         assert_eq!(client.available(), dec!(35.00));
         assert_eq!(client.total(), dec!(55.00));
         assert_eq!(client.held(), dec!(20.00));
-        assert_eq!(client.is_locked(), false);
+        assert!(!client.is_locked());
 
         // Resolve step
         assert!(client.resolve(1).is_ok());
@@ -303,7 +1125,7 @@ mod tests {
         assert_eq!(client.available(), dec!(55.00));
         assert_eq!(client.total(), dec!(55.00));
         assert_eq!(client.held(), dec!(0.00));
-        assert_eq!(client.is_locked(), false);
+        assert!(!client.is_locked());
     }
 
     /* User scenario:
@@ -322,14 +1144,14 @@ mod tests {
         assert_eq!(client.available(), dec!(0.00));
         assert_eq!(client.total(), dec!(10.00));
         assert_eq!(client.held(), dec!(10.00));
-        assert_eq!(client.is_locked(), false);
+        assert!(!client.is_locked());
 
         assert!(client.chargeback(1).is_ok());
 
         assert_eq!(client.available(), dec!(0.00));
         assert_eq!(client.total(), dec!(0.00));
         assert_eq!(client.held(), dec!(0.00));
-        assert_eq!(client.is_locked(), true);
+        assert!(client.is_locked());
     }
 
     /* User scenario:
@@ -350,6 +1172,346 @@ mod tests {
         assert_eq!(client.available(), dec!(5.00));
         assert_eq!(client.total(), dec!(5.00));
         assert_eq!(client.held(), dec!(0.00));
-        assert_eq!(client.is_locked(), false);
+        assert!(!client.is_locked());
+    }
+
+    /* User scenario with at most 2 deposits in memory:
+        1) Deposit 1$, 2$ and 3$, the first deposit is moved to the store
+        2) Dispute the first deposit, it's loaded back and the second one is moved instead
+        3) The disputed deposit stays in memory while more deposits come in
+        4) The stored deposits are still resolvable and can't be deposited again
+    */
+    #[test]
+    fn test_history_limit() {
+        let store = FileHistoryStore::create(&std::env::temp_dir()).unwrap();
+        let mut client = ClientAccount::new(1).with_history_limit(2, Arc::new(Mutex::new(store)));
+
+        assert!(client.deposit(1, dec!(1.00)).is_ok());
+        assert!(client.deposit(2, dec!(2.00)).is_ok());
+        assert!(client.deposit(3, dec!(3.00)).is_ok());
+        assert_eq!(client.history_len(), 2);
+        assert_eq!(
+            client.deposit(1, dec!(5.00)),
+            Err(TransactionError::DuplicateTransaction)
+        );
+
+        assert!(client.dispute(1).is_ok());
+        assert_eq!(client.history_len(), 2);
+        assert_eq!(client.held(), dec!(1.00));
+
+        for tx in 4..10 {
+            assert!(client.deposit(tx, dec!(1.00)).is_ok());
+        }
+        assert_eq!(client.history_len(), 2);
+        assert!(client.resolve(1).is_ok());
+        assert!(client.dispute(2).is_ok());
+        assert!(client.chargeback(2).is_ok());
+
+        assert_eq!(client.available(), dec!(10.00));
+        assert_eq!(client.held(), dec!(0.00));
+        assert!(client.is_locked());
+    }
+
+    #[test]
+    fn test_bloom_filter_without_history() {
+        let seen = Arc::new(BloomFilter::new(1000, 0.001));
+        let mut client = ClientAccount::new(1).with_bloom_filter(seen);
+
+        assert!(client.deposit(1, dec!(10.00)).is_ok());
+        assert_eq!(
+            client.deposit(1, dec!(10.00)),
+            Err(TransactionError::DuplicateTransaction)
+        );
+        assert_eq!(
+            client.withdraw(1, dec!(1.00)),
+            Err(TransactionError::DuplicateTransaction)
+        );
+        assert!(client.withdraw(2, dec!(4.00)).is_ok());
+        assert_eq!(client.dispute(1), Err(TransactionError::DisputesDisabled));
+
+        assert_eq!(client.available(), dec!(6.00));
+        assert_eq!(client.history_len(), 0);
+    }
+
+    #[test]
+    fn test_history_view() {
+        let mut client = ClientAccount::new(1);
+        assert!(client.deposit(1, dec!(10.00)).is_ok());
+        assert!(client.deposit(2, dec!(5.00)).is_ok());
+        assert!(client.deposit(3, dec!(1.00)).is_ok());
+        assert!(client.dispute(2).is_ok());
+        assert!(client.dispute(3).is_ok());
+        assert!(client.resolve(3).is_ok());
+
+        let mut history: Vec<HistoryEntry> = client.history().collect();
+        history.sort_by_key(|entry| entry.tx);
+        assert_eq!(
+            history,
+            vec![
+                HistoryEntry {
+                    tx: 1,
+                    amount: dec!(10.00),
+                    disputes: vec![],
+                },
+                HistoryEntry {
+                    tx: 2,
+                    amount: dec!(5.00),
+                    disputes: vec![DisputeSlice {
+                        id: 1,
+                        amount: dec!(5.00),
+                        hold: dec!(5.00),
+                        state: DisputeProgress::InProgress
+                    }],
+                },
+            ]
+        );
+        assert!(!history[0].is_disputed());
+        assert!(history[1].is_disputed());
+    }
+
+    /*  The events of the operations rebuild the account
+        User scenario:
+            1) Deposit 10$ and 5$, withdraw 3$ and dispute 4$ of the first deposit
+            2) Resolve the dispute, then dispute the second deposit and charge it back
+            3) Replay the events on a new account
+    */
+    #[test]
+    fn test_event_replay() {
+        let mut client = ClientAccount::new(1)
+            .with_event_log()
+            .with_pending_withdrawals();
+        assert!(client.deposit(1, dec!(10.00)).is_ok());
+        assert!(client.deposit(2, dec!(5.00)).is_ok());
+        assert!(client.withdraw(3, dec!(3.00)).is_ok());
+        assert!(client.dispute_amount(1, dec!(4.00)).is_ok());
+        // the rejected operations emit nothing
+        assert!(client.withdraw(4, dec!(100.00)).is_err());
+        let events = client.take_events();
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[3],
+            DomainEvent::DisputeOpened {
+                tx: 1,
+                amount: dec!(4.00),
+                hold: dec!(4.00)
+            }
+        );
+        assert!(client.resolve(1).is_ok());
+        assert!(client.dispute(2).is_ok());
+        assert!(client.chargeback(2).is_ok());
+        let later = client.take_events();
+        assert_eq!(later.last(), Some(&DomainEvent::AccountLocked { tx: 2 }));
+        assert!(client.take_events().is_empty());
+
+        let mut replayed = ClientAccount::new(1);
+        for event in events.into_iter().chain(later) {
+            assert!(replayed.apply(event).is_ok());
+        }
+        assert_eq!(replayed.available(), client.available());
+        assert_eq!(replayed.held(), client.held());
+        assert_eq!(replayed.pending_out(), dec!(3.00));
+        assert!(replayed.is_locked());
+        assert_eq!(
+            replayed.history().collect::<Vec<_>>(),
+            client.history().collect::<Vec<_>>()
+        );
+        assert!(replayed.check_invariants().is_ok());
+        // an event of a dispute the account doesn't have
+        assert_eq!(
+            replayed.apply(DomainEvent::DisputeExpired { tx: 1 }),
+            Err(TransactionError::NotDisputed)
+        );
+    }
+
+    /*  The same dispute with each policy, after most of the deposit was withdrawn
+        User scenario:
+            1) Deposit 10$ and withdraw 8$
+            2) Dispute the deposit, then resolve it or charge it back
+    */
+    #[test]
+    fn test_dispute_policies() {
+        let client = |policy| {
+            let mut client = ClientAccount::new(1).with_dispute_policy(policy);
+            assert!(client.deposit(1, dec!(10.00)).is_ok());
+            assert!(client.withdraw(2, dec!(8.00)).is_ok());
+            client
+        };
+
+        let mut strict = client(DisputePolicy::RequireAvailable);
+        assert_eq!(
+            strict.dispute(1),
+            Err(TransactionError::InsufficientFundsForDispute)
+        );
+        assert_eq!(strict.available(), dec!(2.00));
+
+        let mut negative = client(DisputePolicy::AllowNegativeAvailable);
+        assert!(negative.dispute(1).is_ok());
+        assert_eq!(negative.available(), dec!(-8.00));
+        assert_eq!(negative.held(), dec!(10.00));
+        assert!(negative.resolve(1).is_ok());
+        assert_eq!(negative.available(), dec!(2.00));
+        let mut negative = client(DisputePolicy::AllowNegativeAvailable);
+        assert!(negative.dispute(1).is_ok());
+        assert!(negative.chargeback(1).is_ok());
+        assert_eq!(negative.total(), dec!(-8.00));
+
+        let mut partial = client(DisputePolicy::HoldUpToAvailable);
+        assert!(partial.dispute(1).is_ok());
+        assert_eq!(partial.available(), dec!(0.00));
+        assert_eq!(partial.held(), dec!(2.00));
+        assert!(partial.resolve(1).is_ok());
+        assert_eq!(partial.available(), dec!(2.00));
+        assert_eq!(partial.held(), dec!(0.00));
+        let mut partial = client(DisputePolicy::HoldUpToAvailable);
+        assert!(partial.dispute(1).is_ok());
+        assert!(partial.chargeback(1).is_ok());
+        assert_eq!(partial.total(), dec!(0.00));
+        assert!(partial.is_locked());
+    }
+
+    /*  Partial disputes of a deposit
+        User scenario:
+            1) Deposit 10$ and dispute 4$ of it, then resolve the dispute
+            2) Dispute 5$ of the 6$ left and charge it back
+            3) Only 1$ of the deposit is left to dispute
+    */
+    #[test]
+    fn test_partial_disputes() {
+        let mut client = ClientAccount::new(1);
+        assert!(client.deposit(1, dec!(10.00)).is_ok());
+        assert_eq!(
+            client.dispute_amount(1, dec!(0.00)),
+            Err(TransactionError::InvalidDisputeAmount {
+                requested: dec!(0.00),
+                undisputed: dec!(10.00)
+            })
+        );
+        assert!(client.dispute_amount(1, dec!(4.00)).is_ok());
+        assert_eq!(client.available(), dec!(6.00));
+        assert_eq!(client.held(), dec!(4.00));
+        assert!(client.resolve(1).is_ok());
+        assert_eq!(client.available(), dec!(10.00));
+        assert_eq!(client.held(), dec!(0.00));
+
+        assert_eq!(
+            client.dispute_amount(1, dec!(7.00)),
+            Err(TransactionError::InvalidDisputeAmount {
+                requested: dec!(7.00),
+                undisputed: dec!(6.00)
+            })
+        );
+        assert!(client.dispute_amount(1, dec!(5.00)).is_ok());
+        assert!(client.chargeback(1).is_ok());
+        assert_eq!(client.total(), dec!(5.00));
+        assert!(client.is_locked());
+
+        // the dispute without an amount takes what's left
+        assert!(client.dispute(1).is_ok());
+        assert_eq!(client.held(), dec!(1.00));
+        assert!(client.resolve(1).is_ok());
+        assert_eq!(client.dispute(1), Err(TransactionError::UnknownTransaction));
+    }
+
+    /*  Several disputes on the same deposit at once
+        User scenario:
+            1) Deposit 10$, dispute 2$ then 3$ of it, then the 5$ left
+            2) Resolve the 3$ dispute, charge back the oldest one, expire the last one
+    */
+    #[test]
+    fn test_dispute_slices() {
+        let mut client = ClientAccount::new(1);
+        assert!(client.deposit(1, dec!(10.00)).is_ok());
+        assert!(client.dispute_amount(1, dec!(2.00)).is_ok());
+        assert!(client.dispute_amount(1, dec!(3.00)).is_ok());
+        assert!(client.dispute(1).is_ok());
+        assert_eq!(client.held(), dec!(10.00));
+        assert_eq!(client.dispute(1), Err(TransactionError::AlreadyDisputed));
+        let entry = client.history().next().unwrap();
+        assert_eq!(entry.disputes.len(), 3);
+        assert_eq!(entry.disputed(), dec!(10.00));
+
+        assert_eq!(
+            client.resolve_amount(1, dec!(4.00)),
+            Err(TransactionError::NotDisputed)
+        );
+        assert!(client.resolve_amount(1, dec!(3.00)).is_ok());
+        assert_eq!(client.available(), dec!(3.00));
+        assert!(client.chargeback(1).is_ok());
+        assert_eq!(client.held(), dec!(5.00));
+        assert_eq!(client.total(), dec!(8.00));
+
+        assert!(client.expire(1).is_ok());
+        assert_eq!(client.expire(1), Err(TransactionError::NotDisputed));
+        assert!(client.chargeback_amount(1, dec!(5.00)).is_ok());
+        assert_eq!(client.total(), dec!(3.00));
+        assert_eq!(client.history_len(), 0);
+    }
+
+    #[test]
+    fn test_expired_dispute() {
+        let mut client = ClientAccount::new(1);
+        assert!(client.deposit(1, dec!(10.00)).is_ok());
+        assert_eq!(client.expire(1), Err(TransactionError::NotDisputed));
+        assert!(client.dispute(1).is_ok());
+        assert!(client.expire(1).is_ok());
+        assert_eq!(client.expire(1), Err(TransactionError::NotDisputed));
+
+        // still held, and still disputed
+        assert_eq!(client.held(), dec!(10.00));
+        assert!(client.history().next().unwrap().is_disputed());
+        assert_eq!(client.dispute(1), Err(TransactionError::AlreadyDisputed));
+
+        // a late chargeback settles it
+        assert!(client.chargeback(1).is_ok());
+        assert_eq!(client.total(), dec!(0.00));
+        assert!(client.is_locked());
+    }
+
+    /* User scenario with pending withdrawals:
+        1) Deposit 10$ and withdraw 4$, the 4$ are pending out and still in the total
+        2) The withdrawal can't be settled twice, nor reused
+        3) Once settled, the 4$ leave the account
+    */
+    #[test]
+    fn test_pending_withdrawals() {
+        let mut client = ClientAccount::new(1).with_pending_withdrawals();
+        assert!(client.deposit(1, dec!(10.00)).is_ok());
+        assert!(client.withdraw(2, dec!(4.00)).is_ok());
+
+        assert_eq!(client.available(), dec!(6.00));
+        assert_eq!(client.pending_out(), dec!(4.00));
+        assert_eq!(client.total(), dec!(10.00));
+        assert_eq!(
+            client.withdraw(2, dec!(1.00)),
+            Err(TransactionError::DuplicateTransaction)
+        );
+        assert_eq!(client.settle(1), Err(TransactionError::NotPending));
+
+        assert!(client.settle(2).is_ok());
+        assert_eq!(client.settle(2), Err(TransactionError::NotPending));
+        assert_eq!(client.pending_out(), dec!(0.00));
+        assert_eq!(client.total(), dec!(6.00));
+
+        // the withdrawals are final right away otherwise
+        let mut client = ClientAccount::new(1);
+        assert!(client.deposit(1, dec!(10.00)).is_ok());
+        assert!(client.withdraw(2, dec!(4.00)).is_ok());
+        assert_eq!(client.total(), dec!(6.00));
+        assert_eq!(client.settle(2), Err(TransactionError::NotPending));
+    }
+
+    #[test]
+    fn test_transaction_limit() {
+        let mut client = ClientAccount::new(1).with_transaction_limit(2);
+        assert!(client.count_transaction());
+        assert!(client.count_transaction());
+        assert!(!client.is_over_limit());
+        assert!(!client.count_transaction());
+        assert!(client.is_over_limit());
+        assert!(client.snapshot().is_over_limit());
+
+        let mut client = ClientAccount::new(1);
+        assert!(client.count_transaction());
+        assert!(!client.has_transaction_limit());
     }
 }
@@ -0,0 +1,95 @@
+/// Allow and block lists of the clients, for `--allow-clients` and `--block-clients`
+/// The lists are text files with a client id per line, the empty lines and the lines starting
+/// with `#` are skipped. A client of a list is blocked or allowed in all the tenants, its
+/// records are rejected as `client_blocked` before they reach its account
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+use anyhow::Context;
+use hashbrown::HashSet;
+
+use crate::records::ClientId;
+
+/// The clients whose records are rejected
+#[derive(Debug, Clone, Default)]
+pub struct ClientFilter {
+    /// Only these clients are accepted if there's a list
+    allowed: Option<HashSet<ClientId>>,
+    blocked: HashSet<ClientId>,
+}
+
+impl ClientFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blocks all the clients but these ones, can be given several times
+    pub fn with_allowed(mut self, clients: impl IntoIterator<Item = ClientId>) -> Self {
+        self.allowed
+            .get_or_insert_with(HashSet::new)
+            .extend(clients);
+        self
+    }
+
+    /// Blocks these clients, even if they're allowed
+    pub fn with_blocked(mut self, clients: impl IntoIterator<Item = ClientId>) -> Self {
+        self.blocked.extend(clients);
+        self
+    }
+
+    pub fn is_blocked(&self, client: ClientId) -> bool {
+        self.blocked.contains(&client)
+            || self
+                .allowed
+                .as_ref()
+                .is_some_and(|allowed| !allowed.contains(&client))
+    }
+}
+
+/// Reads the client ids of a list file
+pub fn read_clients(path: &Path) -> anyhow::Result<Vec<ClientId>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    parse_clients(file).with_context(|| format!("Invalid client list {}", path.display()))
+}
+
+/// Parses a client id per line, without the empty lines and the comments
+pub fn parse_clients(reader: impl Read) -> anyhow::Result<Vec<ClientId>> {
+    let mut clients = Vec::new();
+    for (index, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let client = line
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Line {}: invalid client id {}", index + 1, line))?;
+        clients.push(client);
+    }
+    Ok(clients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_lists() {
+        let clients = parse_clients("# sanctions\n3\n\n 7 \n".as_bytes()).unwrap();
+        assert_eq!(clients, vec![3, 7]);
+        assert!(parse_clients("3\nabc\n".as_bytes()).is_err());
+
+        let filter = ClientFilter::new().with_blocked(clients);
+        assert!(filter.is_blocked(3));
+        assert!(!filter.is_blocked(4));
+
+        let filter = filter.with_allowed(vec![3, 4]);
+        assert!(filter.is_blocked(3));
+        assert!(!filter.is_blocked(4));
+        assert!(filter.is_blocked(5));
+        assert!(!ClientFilter::new().is_blocked(5));
+    }
+}
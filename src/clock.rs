@@ -0,0 +1,76 @@
+/// The time as seen by the features that depend on it: rate limits, replay windows, snapshots
+/// The real clock is the system's, the mock one only moves when it's told to, so the tests and
+/// the simulations can go through hours of records without waiting for them
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A source of time that can also wait
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Waits for `duration`, a mock clock moves forward instead
+    fn sleep(&self, duration: Duration);
+}
+
+/// The time of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// The clock of a normal run
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that starts at its creation and only moves with `advance` and `sleep`
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(60));
+        clock.sleep(Duration::from_millis(500));
+        assert_eq!(clock.now() - start, Duration::from_millis(60_500));
+    }
+}
@@ -0,0 +1,172 @@
+/// A live terminal dashboard showing the statistics of a run
+/// Rendered on stderr, so the report on stdout can still be redirected to a file
+#[cfg(not(feature = "tui"))]
+pub fn start(
+    _stats: std::sync::Arc<paytoy::stats::PipelineStats>,
+) -> anyhow::Result<std::thread::JoinHandle<()>> {
+    Err(paytoy::errors::UsageError(
+        "paytoy was built without the dashboard, rebuild it with `--features tui`".to_string(),
+    )
+    .into())
+}
+
+#[cfg(feature = "tui")]
+pub use self::tui::start;
+
+#[cfg(feature = "tui")]
+mod tui {
+    use std::{io::Stderr, sync::Arc, thread::JoinHandle, time::Duration};
+
+    use log::*;
+    use ratatui::{
+        backend::CrosstermBackend,
+        crossterm::{
+            execute,
+            terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+        },
+        layout::{Constraint, Layout},
+        style::{Color, Style},
+        widgets::{Block, Borders, Gauge, Paragraph, Row, Table},
+        Frame, Terminal,
+    };
+
+    use paytoy::{latency, stats::PipelineStats};
+
+    const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// Starts rendering the dashboard until the run is marked as finished
+    pub fn start(stats: Arc<PipelineStats>) -> anyhow::Result<JoinHandle<()>> {
+        let mut stderr = std::io::stderr();
+        execute!(stderr, EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stderr))?;
+        terminal.clear()?;
+
+        let dashboard_thread = std::thread::Builder::new().name("dashboard".to_string());
+        Ok(dashboard_thread.spawn(move || {
+            if let Err(err) = render_loop(&mut terminal, &stats) {
+                error!("Dashboard failed: {:?}", err);
+            }
+            let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+        })?)
+    }
+
+    fn render_loop(
+        terminal: &mut Terminal<CrosstermBackend<Stderr>>,
+        stats: &PipelineStats,
+    ) -> anyhow::Result<()> {
+        let mut last_applied = 0;
+        let mut last_elapsed = 0.0;
+        loop {
+            let finished = stats.is_finished();
+
+            // throughput over the last refresh interval
+            let applied = stats.records_applied() + stats.records_rejected();
+            let elapsed = stats.elapsed_secs();
+            let rate = (applied - last_applied) as f64 / (elapsed - last_elapsed).max(1e-3);
+            last_applied = applied;
+            last_elapsed = elapsed;
+
+            terminal.draw(|frame| draw(frame, stats, rate))?;
+
+            if finished {
+                return Ok(());
+            }
+            std::thread::sleep(REFRESH_INTERVAL);
+        }
+    }
+
+    fn draw(frame: &mut Frame, stats: &PipelineStats, rate: f64) {
+        let queues = stats.queue_depths();
+        // only measured with --latency
+        let latencies = stats.latencies();
+        let latency_rows = latencies.histograms().count();
+        let [counters_area, queues_area, latency_area, top_area] = Layout::vertical([
+            Constraint::Length(8),
+            Constraint::Length(queues.len() as u16 + 2),
+            Constraint::Length(if latency_rows > 0 {
+                latency_rows as u16 + 3
+            } else {
+                0
+            }),
+            Constraint::Min(0),
+        ])
+        .areas(frame.area());
+
+        let elapsed = stats.elapsed_secs();
+        let processed = stats.records_applied() + stats.records_rejected();
+        let counters = Paragraph::new(vec![
+            format!("elapsed:      {:.1}s", elapsed).into(),
+            format!("throughput:   {:.2} millions/second", rate / 1e6).into(),
+            format!(
+                "average:      {:.2} millions/second",
+                processed as f64 / elapsed.max(1e-3) / 1e6
+            )
+            .into(),
+            format!("read:         {}", stats.records_read()).into(),
+            format!("applied:      {}", stats.records_applied()).into(),
+            format!(
+                "rejected:     {} (+{} unparseable)",
+                stats.records_rejected(),
+                stats.parse_errors()
+            )
+            .into(),
+        ])
+        .block(Block::default().borders(Borders::ALL).title(" paytoy "));
+        frame.render_widget(counters, counters_area);
+
+        let queue_block = Block::default().borders(Borders::ALL).title(" queues ");
+        let queue_rows = Layout::vertical(vec![Constraint::Length(1); queues.len()])
+            .split(queue_block.inner(queues_area));
+        frame.render_widget(queue_block, queues_area);
+        for ((name, depth, capacity), area) in queues.iter().zip(queue_rows.iter()) {
+            let ratio = (*depth as f64 / (*capacity).max(1) as f64).min(1.0);
+            let gauge = Gauge::default()
+                .gauge_style(Style::default().fg(Color::Cyan))
+                .ratio(ratio)
+                .label(format!("{}: {}/{}", name, depth, capacity));
+            frame.render_widget(gauge, *area);
+        }
+
+        if latency_rows > 0 {
+            let rows = latencies.histograms().map(|(tr_type, histogram)| {
+                let mut row = vec![tr_type.to_string(), histogram.count().to_string()];
+                for quantile in [0.5, 0.99, 0.999] {
+                    let nanos = histogram.quantile(quantile);
+                    row.push(format!("{:.2}µs", latency::micros(nanos)));
+                }
+                row.push(format!("{:.2}µs", latency::micros(histogram.max())));
+                Row::new(row)
+            });
+            let widths = [
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Min(10),
+            ];
+            let header = ["type", "records", "p50", "p99", "p99.9", "max"];
+            let table = Table::new(rows, widths)
+                .header(Row::new(header.to_vec()).style(Style::default().fg(Color::Yellow)))
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(" apply latency "),
+                );
+            frame.render_widget(table, latency_area);
+        }
+
+        let rows = stats
+            .top_accounts()
+            .into_iter()
+            .map(|(client, total)| Row::new(vec![client.to_string(), format!("{:.4}", total)]));
+        let top = Table::new(rows, [Constraint::Length(8), Constraint::Min(16)])
+            .header(Row::new(vec!["client", "total"]).style(Style::default().fg(Color::Yellow)))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(" top accounts "),
+            );
+        frame.render_widget(top, top_area);
+    }
+}
@@ -0,0 +1,114 @@
+/// Keeps the raw CSV lines that couldn't be parsed, so they can be repaired and replayed
+/// The lines are copied verbatim after the header of the input, so the file can be
+/// processed again once fixed
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+pub struct DeadLetters {
+    writer: Mutex<DeadLetterWriter>,
+}
+
+struct DeadLetterWriter {
+    file: BufWriter<File>,
+    /// The header is only written once, by the first input
+    has_header: bool,
+    lines: u64,
+}
+
+impl DeadLetters {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            writer: Mutex::new(DeadLetterWriter {
+                file: BufWriter::new(File::create(path)?),
+                has_header: false,
+                lines: 0,
+            }),
+        })
+    }
+
+    /// Writes the header line of an input, if no header was written yet
+    pub fn write_header(&self, header: &[u8]) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.has_header {
+            return Ok(());
+        }
+        writer.has_header = true;
+        writer.write_line(header)?;
+        writer.file.flush()
+    }
+
+    /// Writes the raw lines and flushes them, so they are on disk before
+    /// the records read with them are processed
+    pub fn write_lines<'a>(
+        &self,
+        lines: impl IntoIterator<Item = &'a [u8]>,
+    ) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for line in lines {
+            writer.write_line(line)?;
+            writer.lines += 1;
+        }
+        writer.file.flush()
+    }
+
+    /// Number of lines written so far, without the header
+    pub fn lines(&self) -> u64 {
+        self.writer.lock().unwrap().lines
+    }
+}
+
+/// Slices the raw line of a record out of the data it was read from
+/// The CSV reader reports a record as starting on the end of the previous `\r\n` terminator
+pub fn raw_line(data: &[u8], start: u64, end: u64) -> &[u8] {
+    let line = &data[start as usize..end as usize];
+    let terminator = line.iter().take_while(|&&byte| byte == b'\n').count();
+    &line[terminator..]
+}
+
+impl DeadLetterWriter {
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(line)?;
+        // the last line of an input may not be terminated
+        if !line.ends_with(b"\n") {
+            self.file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_letters() {
+        let path = std::env::temp_dir().join("paytoy_test_dead_letters.csv");
+        let dead_letters = DeadLetters::create(&path).unwrap();
+        dead_letters
+            .write_header(b"type, client, tx, amount\n")
+            .unwrap();
+        dead_letters
+            .write_lines([&b"deposit, x, 1, 1.0\r\n"[..], b"bogus, 1, 2, 1.0"])
+            .unwrap();
+        // from another input
+        dead_letters
+            .write_header(b"type,client,tx,amount\n")
+            .unwrap();
+        dead_letters
+            .write_lines([&b"deposit, 1, 3, abc\n"[..]])
+            .unwrap();
+
+        assert_eq!(dead_letters.lines(), 3);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type, client, tx, amount\n\
+             deposit, x, 1, 1.0\r\n\
+             bogus, 1, 2, 1.0\n\
+             deposit, 1, 3, abc\n"
+        );
+    }
+}
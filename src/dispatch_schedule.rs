@@ -0,0 +1,324 @@
+/// The order the workers of the multithreaded manager apply the records in, recorded by
+/// `--record-schedule` and enforced by `--replay-schedule`
+/// The records of an account always go to the same worker in their order, but the workers
+/// race each other, so the records sharing state across accounts (the ids of
+/// `--unique-tx-ids`, the merchants, the bloom filter) may be applied in another order at the
+/// next run. The schedule is a text file starting with the number of workers, then a line
+/// with the worker and the transaction id of each record, in the order they were applied.
+/// A replay runs as many workers, each one waiting for its turn in the schedule before
+/// applying a record, so the records are applied one at a time in the recorded order. If the
+/// records don't match the schedule anymore, the replay stops following it
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Condvar, Mutex},
+};
+
+use anyhow::Context;
+use log::*;
+
+use crate::records::{TransactionId, TransactionRecord};
+
+const HEADER: &str = "paytoy-schedule workers=";
+
+/// A schedule being recorded or replayed, shared by the workers
+pub struct DispatchSchedule {
+    workers: usize,
+    mode: Mode,
+}
+
+enum Mode {
+    Record(Mutex<Recording>),
+    Replay(Replay),
+}
+
+struct Recording {
+    writer: Box<dyn Write + Send>,
+    /// The first write that failed, reported by `finish`
+    error: Option<std::io::Error>,
+}
+
+struct Replay {
+    /// The worker and the id of each record, in the order they're applied
+    order: Vec<(usize, TransactionId)>,
+    /// For each worker, the positions of its records in `order`
+    by_worker: Vec<Vec<usize>>,
+    state: Mutex<ReplayState>,
+    turn_changed: Condvar,
+}
+
+struct ReplayState {
+    /// The position in `order` of the next record to apply
+    turn: usize,
+    /// For each worker, how many of its records were dispatched
+    dispatched: Vec<usize>,
+    /// Why the run stopped following the schedule
+    diverged: Option<String>,
+}
+
+impl DispatchSchedule {
+    /// Records the schedule of `workers` workers to `writer`
+    pub fn record(workers: usize, mut writer: Box<dyn Write + Send>) -> Self {
+        let error = writeln!(writer, "{}{}", HEADER, workers).err();
+        Self {
+            workers,
+            mode: Mode::Record(Mutex::new(Recording { writer, error })),
+        }
+    }
+
+    pub fn create(workers: usize, path: &Path) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self::record(workers, Box::new(BufWriter::new(file))))
+    }
+
+    /// Parses a recorded schedule to replay it
+    pub fn replay(reader: impl BufRead) -> anyhow::Result<Self> {
+        let mut lines = reader.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let workers: usize = header
+            .strip_prefix(HEADER)
+            .and_then(|workers| workers.parse().ok())
+            .filter(|workers| *workers > 0)
+            .ok_or_else(|| anyhow::anyhow!("Not a paytoy schedule"))?;
+        let mut order = Vec::new();
+        let mut by_worker = vec![Vec::new(); workers];
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            let entry = line.split_once(' ').and_then(|(worker, tx)| {
+                Some((
+                    worker.parse::<usize>().ok()?,
+                    tx.parse::<TransactionId>().ok()?,
+                ))
+            });
+            match entry {
+                Some((worker, tx)) if worker < workers => {
+                    by_worker[worker].push(order.len());
+                    order.push((worker, tx));
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Line {}: invalid entry {}",
+                        index + 2,
+                        line
+                    ))
+                }
+            }
+        }
+        Ok(Self {
+            workers,
+            mode: Mode::Replay(Replay {
+                order,
+                by_worker,
+                state: Mutex::new(ReplayState {
+                    turn: 0,
+                    dispatched: vec![0; workers],
+                    diverged: None,
+                }),
+                turn_changed: Condvar::new(),
+            }),
+        })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::replay(BufReader::new(file))
+            .with_context(|| format!("Invalid schedule {}", path.display()))
+    }
+
+    /// The number of workers of the schedule
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Checks a record sent to a worker against the schedule, by the dispatcher
+    pub fn dispatched(&self, worker: usize, record: &TransactionRecord) {
+        let replay = match &self.mode {
+            Mode::Replay(replay) => replay,
+            Mode::Record(_) => return,
+        };
+        let mut state = replay.state.lock().unwrap();
+        if state.diverged.is_some() {
+            return;
+        }
+        let count = state.dispatched[worker];
+        let expected = replay.by_worker[worker]
+            .get(count)
+            .map(|position| replay.order[*position].1);
+        if expected != Some(record.tx) {
+            replay.diverge(
+                &mut state,
+                format!(
+                    "the record {} of the worker {} is the transaction {} instead of {}",
+                    count + 1,
+                    worker,
+                    record.tx,
+                    expected.map_or_else(|| "none".to_string(), |tx| tx.to_string())
+                ),
+            );
+            return;
+        }
+        state.dispatched[worker] += 1;
+    }
+
+    /// All the records were dispatched, the rest of the schedule won't come
+    pub fn end_of_dispatch(&self) {
+        if let Mode::Replay(replay) = &self.mode {
+            let mut state = replay.state.lock().unwrap();
+            let missing = (0..self.workers)
+                .find(|worker| state.dispatched[*worker] < replay.by_worker[*worker].len());
+            if let (Some(worker), None) = (missing, &state.diverged) {
+                let reason = format!("the worker {} got fewer records than scheduled", worker);
+                replay.diverge(&mut state, reason);
+            }
+        }
+    }
+
+    /// Applies a record of the `worker` in its turn, recording or following the schedule
+    pub fn apply<T>(
+        &self,
+        worker: usize,
+        record: &TransactionRecord,
+        apply: impl FnOnce() -> T,
+    ) -> T {
+        match &self.mode {
+            Mode::Record(recording) => {
+                // applied under the lock, another worker could otherwise apply its next
+                // record before this one is and the schedule would have them swapped
+                let mut recording = recording.lock().unwrap();
+                if let Err(err) = writeln!(recording.writer, "{} {}", worker, record.tx) {
+                    recording.error.get_or_insert(err);
+                }
+                apply()
+            }
+            Mode::Replay(replay) => {
+                let mut state = replay.state.lock().unwrap();
+                while state.diverged.is_none()
+                    && replay.order.get(state.turn).map(|(owner, _)| *owner) != Some(worker)
+                {
+                    if state.turn >= replay.order.len() {
+                        replay.diverge(&mut state, "more records than scheduled".to_string());
+                        break;
+                    }
+                    state = replay.turn_changed.wait(state).unwrap();
+                }
+                let in_turn = state.diverged.is_none();
+                drop(state);
+                // the other workers wait for this record to be applied
+                let result = apply();
+                if in_turn {
+                    replay.state.lock().unwrap().turn += 1;
+                    replay.turn_changed.notify_all();
+                }
+                result
+            }
+        }
+    }
+
+    /// Why the replay stopped following the schedule, if it did
+    pub fn diverged(&self) -> Option<String> {
+        match &self.mode {
+            Mode::Replay(replay) => replay.state.lock().unwrap().diverged.clone(),
+            Mode::Record(_) => None,
+        }
+    }
+
+    /// Flushes the recorded schedule, once the workers are done
+    pub fn finish(&self) -> anyhow::Result<()> {
+        if let Mode::Record(recording) = &self.mode {
+            let mut recording = recording.lock().unwrap();
+            let flushed = recording.writer.flush();
+            if let Some(err) = recording.error.take().map_or(flushed, Err).err() {
+                return Err(anyhow::anyhow!("Failed to write the schedule: {}", err));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Replay {
+    fn diverge(&self, state: &mut ReplayState, reason: String) {
+        warn!("The run diverged from the schedule: {}", reason);
+        state.diverged = Some(reason);
+        self.turn_changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, MTAccountManager},
+        records::{ClientId, TransactionType},
+        report::Report,
+        report_stream::tests::SharedBuffer,
+        tx_registry::TxRegistry,
+    };
+
+    use super::*;
+
+    fn deposit(client: ClientId, tx: TransactionId) -> TransactionRecord {
+        TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount: Some(dec!(1)),
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    /// The clients reuse the ids of each other, the first one to apply its deposit keeps it
+    fn run(schedule: Arc<DispatchSchedule>, records: TransactionId) -> Report {
+        let manager = MTAccountManager::new(schedule.workers())
+            .with_tx_registry(Arc::new(TxRegistry::new()))
+            .with_dispatch_schedule(schedule);
+        let records = (0..records).map(|tx| deposit(tx as ClientId % 4, tx / 3));
+        manager.execute_transactions(Box::new(records))
+    }
+
+    fn owners(report: &Report) -> Vec<(ClientId, usize)> {
+        let mut owners: Vec<_> = report
+            .accounts()
+            .map(|account| (account.id(), account.history_len()))
+            .collect();
+        owners.sort();
+        owners
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let output = SharedBuffer::default();
+        let recorded = Arc::new(DispatchSchedule::record(4, Box::new(output.clone())));
+        let report = run(recorded.clone(), 2000);
+        recorded.finish().unwrap();
+
+        let schedule = output.contents();
+        assert!(schedule.starts_with("paytoy-schedule workers=4\n"));
+        assert_eq!(schedule.lines().count(), 2001);
+        for _ in 0..3 {
+            let replayed = Arc::new(DispatchSchedule::replay(schedule.as_bytes()).unwrap());
+            assert_eq!(owners(&run(replayed.clone(), 2000)), owners(&report));
+            assert_eq!(replayed.diverged(), None);
+        }
+
+        // another input stops following the schedule
+        let replayed = Arc::new(DispatchSchedule::replay(schedule.as_bytes()).unwrap());
+        run(replayed.clone(), 1000);
+        assert!(replayed.diverged().is_some());
+    }
+
+    #[test]
+    fn test_invalid_schedules() {
+        assert!(DispatchSchedule::replay("0 1\n".as_bytes()).is_err());
+        assert!(DispatchSchedule::replay("paytoy-schedule workers=2\n2 1\n".as_bytes()).is_err());
+        assert!(DispatchSchedule::replay("paytoy-schedule workers=2\n1 x\n".as_bytes()).is_err());
+    }
+}
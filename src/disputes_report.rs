@@ -0,0 +1,134 @@
+/// The disputes still open at the end of a run, for `--disputes-report`
+/// Each account with an open dispute gets a row with the number and the total amount of its
+/// disputes in progress, and of its expired ones waiting for a resolve or chargeback. They're
+/// counted from the history of the accounts, which always keeps the disputed transactions
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+
+use crate::client_account::{ClientAccount, DisputeProgress};
+
+/// The open disputes of an account
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct OpenDisputes {
+    pub in_progress: usize,
+    /// The disputed amount of the disputes in progress
+    pub in_progress_amount: Decimal,
+    pub expired: usize,
+    pub expired_amount: Decimal,
+}
+
+impl OpenDisputes {
+    pub fn of(account: &ClientAccount) -> Self {
+        let mut open = Self::default();
+        for dispute in account.history().flat_map(|entry| entry.disputes) {
+            match dispute.state {
+                DisputeProgress::InProgress => {
+                    open.in_progress += 1;
+                    open.in_progress_amount += dispute.amount;
+                }
+                DisputeProgress::Expired => {
+                    open.expired += 1;
+                    open.expired_amount += dispute.amount;
+                }
+            }
+        }
+        open
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_progress == 0 && self.expired == 0
+    }
+}
+
+/// Writes a row for each account with open disputes, ordered by account
+pub fn write_disputes<'a>(
+    writer: &mut impl Write,
+    accounts: impl Iterator<Item = &'a ClientAccount>,
+) -> std::io::Result<()> {
+    let mut rows: Vec<_> = accounts
+        .map(|account| (account.key(), OpenDisputes::of(account)))
+        .filter(|(_, open)| !open.is_empty())
+        .collect();
+    rows.sort_by_key(|(key, _)| *key);
+    writeln!(
+        writer,
+        "tenant, client, in_progress, in_progress_amount,  expired,  expired_amount"
+    )?;
+    for (key, open) in rows {
+        let tenant = key
+            .tenant
+            .map_or_else(String::new, |tenant| tenant.to_string());
+        writeln!(
+            writer,
+            "{:>6}, {:6}, {:11}, {:18.4}, {:8}, {:15.4}",
+            tenant,
+            key.client,
+            open.in_progress,
+            open.in_progress_amount,
+            open.expired,
+            open.expired_amount
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_file<'a>(
+    path: &Path,
+    accounts: impl Iterator<Item = &'a ClientAccount>,
+) -> anyhow::Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write_disputes(&mut writer, accounts)
+        .and_then(|()| writer.flush())
+        .with_context(|| format!("Failed to write the disputes report {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::records::AccountKey;
+
+    use super::*;
+
+    #[test]
+    fn test_disputes_report() {
+        let mut account =
+            ClientAccount::from_balances(AccountKey::new(Some(2), 7), dec!(0), dec!(0), false);
+        account.deposit(1, dec!(10)).unwrap();
+        account.deposit(2, dec!(5)).unwrap();
+        account.dispute_amount(1, dec!(4)).unwrap();
+        account.dispute_amount(1, dec!(2.5)).unwrap();
+        account.dispute(2).unwrap();
+        account.expire(2).unwrap();
+        assert_eq!(
+            OpenDisputes::of(&account),
+            OpenDisputes {
+                in_progress: 2,
+                in_progress_amount: dec!(6.5),
+                expired: 1,
+                expired_amount: dec!(5),
+            }
+        );
+
+        let mut settled = ClientAccount::new(1);
+        settled.deposit(3, dec!(1)).unwrap();
+        settled.dispute(3).unwrap();
+        settled.resolve(3).unwrap();
+
+        let mut output = Vec::new();
+        write_disputes(&mut output, [settled, account].iter()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tenant, client, in_progress, in_progress_amount,  expired,  expired_amount\n\
+             \x20    2,      7,           2,             6.5000,        1,          5.0000\n"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+
+use crate::records::ClientId;
+
+/// Reasons why a transaction could not be applied to an account
+#[derive(PartialEq, Debug, Clone)]
+pub enum TransactionError {
+    /// A deposit or withdrawal reuses an id that is already in the account history
+    DuplicateTransaction,
+    /// Not enough available funds to withdraw the requested amount
+    InsufficientFunds {
+        requested: Decimal,
+        available: Decimal,
+    },
+    /// Deposits and withdrawals must carry an amount
+    MissingAmount,
+    /// The referenced transaction does not exist in the account history
+    UnknownTransaction,
+    /// The transaction is already under dispute
+    AlreadyDisputed,
+    /// The amount of a dispute isn't positive, or is more than what's left of the transaction
+    InvalidDisputeAmount {
+        requested: Decimal,
+        undisputed: Decimal,
+    },
+    /// Not enough available funds to hold the disputed amount
+    InsufficientFundsForDispute,
+    /// Resolve or chargeback on a transaction that is not disputed
+    NotDisputed,
+    /// Not enough held funds to release the disputed amount
+    InsufficientHeldFunds,
+    /// The account is frozen and doesn't accept more transactions
+    AccountLocked,
+    /// The worker managing the account has stopped
+    WorkerStopped,
+    /// The history store of the account couldn't be read or written
+    HistoryUnavailable,
+    /// Disputes need the history, which isn't kept when the duplicates use a bloom filter
+    DisputesDisabled,
+    /// The transaction id is already used by another client
+    TxIdReused { owner: ClientId },
+    /// Settle of a transaction that isn't a withdrawal waiting for its settlement
+    NotPending,
+    /// Payments must name the merchant they credit
+    MissingMerchant,
+    /// The transaction type is disabled for the run
+    TypeDisabled,
+    /// The client is blocked, or isn't in the allowed clients
+    ClientBlocked,
+    /// The client got more records than its limit for the run
+    TooManyTransactions,
+}
+
+impl TransactionError {
+    /// A short stable code for the reason, to aggregate the rejects
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::DuplicateTransaction => "duplicate_tx",
+            TransactionError::InsufficientFunds { .. } => "insufficient_funds",
+            TransactionError::MissingAmount => "missing_amount",
+            TransactionError::UnknownTransaction => "unknown_tx",
+            TransactionError::AlreadyDisputed => "already_disputed",
+            TransactionError::InvalidDisputeAmount { .. } => "invalid_dispute_amount",
+            TransactionError::InsufficientFundsForDispute => "insufficient_funds_for_dispute",
+            TransactionError::NotDisputed => "not_disputed",
+            TransactionError::InsufficientHeldFunds => "insufficient_held_funds",
+            TransactionError::AccountLocked => "account_locked",
+            TransactionError::WorkerStopped => "worker_stopped",
+            TransactionError::HistoryUnavailable => "history_unavailable",
+            TransactionError::DisputesDisabled => "disputes_disabled",
+            TransactionError::TxIdReused { .. } => "tx_id_reused",
+            TransactionError::NotPending => "not_pending",
+            TransactionError::MissingMerchant => "missing_merchant",
+            TransactionError::TypeDisabled => "type_disabled",
+            TransactionError::ClientBlocked => "client_blocked",
+            TransactionError::TooManyTransactions => "too_many_transactions",
+        }
+    }
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::DuplicateTransaction => f.write_str("Transaction already exists"),
+            TransactionError::InsufficientFunds {
+                requested,
+                available,
+            } => f.write_fmt(format_args!(
+                "Insufficient funds. Requested {} but available {}",
+                requested, available
+            )),
+            TransactionError::MissingAmount => {
+                f.write_str("Transaction failed due to missing amount")
+            }
+            TransactionError::UnknownTransaction => f.write_str("Transaction does not exist"),
+            TransactionError::AlreadyDisputed => f.write_str("Dispute already in progress or done"),
+            TransactionError::InvalidDisputeAmount {
+                requested,
+                undisputed,
+            } => f.write_fmt(format_args!(
+                "Invalid dispute amount. Requested {} but {} can be disputed",
+                requested, undisputed
+            )),
+            TransactionError::InsufficientFundsForDispute => {
+                f.write_str("Not enough funds to open a dispute")
+            }
+            TransactionError::NotDisputed => {
+                f.write_str("Cannot resolve a transaction that is not disputed")
+            }
+            TransactionError::InsufficientHeldFunds => {
+                f.write_str("Not enough held funds to release a dispute")
+            }
+            TransactionError::AccountLocked => {
+                f.write_str("Account is locked and cannot accept more transactions")
+            }
+            TransactionError::WorkerStopped => f.write_str("The account worker has stopped"),
+            TransactionError::HistoryUnavailable => {
+                f.write_str("The transaction history store failed")
+            }
+            TransactionError::DisputesDisabled => {
+                f.write_str("Disputes are disabled, the transaction history is not kept")
+            }
+            TransactionError::TxIdReused { owner } => f.write_fmt(format_args!(
+                "Transaction id already used by client {}",
+                owner
+            )),
+            TransactionError::NotPending => {
+                f.write_str("The transaction is not a withdrawal waiting for its settlement")
+            }
+            TransactionError::MissingMerchant => {
+                f.write_str("Payment failed due to missing merchant")
+            }
+            TransactionError::TypeDisabled => {
+                f.write_str("The transaction type is disabled for this run")
+            }
+            TransactionError::ClientBlocked => f.write_str("The client is blocked"),
+            TransactionError::TooManyTransactions => {
+                f.write_str("The client is over its limit of transactions for this run")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// An error in the way the application is invoked, rather than in its inputs or environment
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Builds the error returned when a feature is needed but paytoy was built without it
+// not used when paytoy is built with all the features
+#[allow(dead_code)]
+pub fn missing_feature(name: &str, feature: &str) -> anyhow::Error {
+    UsageError(format!(
+        "paytoy was built without {} support, rebuild it with `--features {}`",
+        name, feature
+    ))
+    .into()
+}
+
+/// Exit codes of the application, so orchestration tools can react to the failures
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ExitCode {
+    Success = 0,
+    /// Invalid arguments, or a feature that is not built in (the same code as clap)
+    Usage = 2,
+    /// The inputs couldn't be read or the report couldn't be written
+    Io = 3,
+    /// Some records were dropped and the failure policy doesn't allow it
+    Validation = 4,
+    /// An input failed before its end, the report only covers a part of it
+    Partial = 5,
+    /// The pipeline made no progress for the watchdog timeout and was aborted
+    Stalled = 6,
+}
+
+impl ExitCode {
+    /// The exit code of a run that failed with `err`
+    pub fn of_error(err: &anyhow::Error) -> Self {
+        if err.chain().any(|cause| cause.is::<UsageError>()) {
+            ExitCode::Usage
+        } else {
+            ExitCode::Io
+        }
+    }
+}
+
+/// Which dropped records make the run fail
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum FailOn {
+    /// Records that couldn't be parsed
+    ParseError,
+    /// Records that couldn't be parsed or applied
+    Reject,
+    /// Never fail because of the records
+    None,
+}
+
+impl FailOn {
+    pub fn is_violated(&self, parse_errors: u64, rejects: usize) -> bool {
+        match self {
+            FailOn::ParseError => parse_errors > 0,
+            FailOn::Reject => parse_errors > 0 || rejects > 0,
+            FailOn::None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_exit_code_of_error() {
+        let usage: anyhow::Result<()> = Err(missing_feature("Parquet", "parquet"));
+        let usage = usage.context("Failed to open the input").unwrap_err();
+        assert_eq!(ExitCode::of_error(&usage), ExitCode::Usage);
+
+        let io = anyhow::Error::from(std::io::Error::other("disk full"));
+        assert_eq!(ExitCode::of_error(&io), ExitCode::Io);
+    }
+
+    #[test]
+    fn test_fail_on() {
+        assert!(!FailOn::None.is_violated(3, 2));
+        assert!(FailOn::ParseError.is_violated(1, 0));
+        assert!(!FailOn::ParseError.is_violated(0, 2));
+        assert!(FailOn::Reject.is_violated(0, 1));
+        assert!(FailOn::Reject.is_violated(1, 0));
+        assert!(!FailOn::Reject.is_violated(0, 0));
+    }
+}
@@ -0,0 +1,278 @@
+/// Rebuilding the accounts from the events of `--events`, for `paytoy replay-events`
+/// Each domain event is applied to its account with `ClientAccount::apply`, and the balances
+/// it was published with are checked as it goes. The rebuilt accounts are then compared with
+/// the report of the run, or with the checksum of that report: if they match, the events are
+/// enough to rebuild the accounts and no change of the run is missing from them
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use hashbrown::HashMap;
+
+use crate::{
+    client_account::ClientAccount,
+    events::AccountEvent,
+    processed_inputs,
+    records::AccountKey,
+    report::{self, Report},
+    report_delta::{self, AccountDelta},
+};
+
+/// The accounts rebuilt from the events read so far
+#[derive(Default)]
+pub struct EventReplay {
+    accounts: HashMap<AccountKey, ClientAccount>,
+    /// The domain events applied
+    applied: u64,
+    /// The events without a domain event, e.g. the expiry of a dispute that was resolved
+    skipped: u64,
+}
+
+impl EventReplay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies the events of a file, in order
+    pub fn replay_file(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::replay(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to replay {}", path.display()))
+    }
+
+    /// Applies the events of JSON lines, in order
+    pub fn replay(reader: impl BufRead) -> anyhow::Result<Self> {
+        let mut replay = Self::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            serde_json::from_str(&line)
+                .map_err(anyhow::Error::from)
+                .and_then(|event| AccountEvent::from_json(&event))
+                .and_then(|event| replay.apply(&event))
+                .with_context(|| format!("Invalid event on line {}", index + 1))?;
+        }
+        Ok(replay)
+    }
+
+    /// Applies the domain event of a published event to its account, then checks the account
+    /// against the balances it was published with
+    pub fn apply(&mut self, event: &AccountEvent) -> anyhow::Result<()> {
+        let change = match event.change {
+            Some(change) => change,
+            None => {
+                self.skipped += 1;
+                return Ok(());
+            }
+        };
+        let key = event.account;
+        let account = self
+            .accounts
+            .entry(key)
+            .or_insert_with(|| ClientAccount::new(key.client).with_tenant(key.tenant));
+        account.apply(change).map_err(|err| {
+            anyhow::anyhow!(
+                "The {} of tx {} doesn't apply to client {}: {}",
+                change.name(),
+                change.tx(),
+                key.client,
+                err
+            )
+        })?;
+        // the balances are published after all the changes of the transaction, which only
+        // lock the account after moving its funds
+        if (account.available(), account.held()) != (event.available, event.held)
+            || (account.is_locked() && !event.locked)
+        {
+            return Err(anyhow::anyhow!(
+                "Client {} has {} available and {} held after the {} of tx {}, the event has {} \
+                 and {}",
+                key.client,
+                account.available(),
+                account.held(),
+                change.name(),
+                change.tx(),
+                event.available,
+                event.held
+            ));
+        }
+        self.applied += 1;
+        Ok(())
+    }
+
+    /// The domain events applied
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// The events skipped since they don't change an account
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The rebuilt accounts
+    pub fn into_report(self) -> Report {
+        Report::new(self.accounts, Vec::new())
+    }
+}
+
+/// The SHA-256 of the report in the plain format, its accounts sorted by tenant and client,
+/// the same as `paytoy --deterministic --plain input.csv | sha256sum`
+pub fn checksum(report: &Report) -> String {
+    let mut accounts: Vec<&ClientAccount> = report.accounts().collect();
+    accounts.sort_by_key(|account| account.key());
+    let mut plain = Vec::new();
+    // writing to memory doesn't fail
+    let _ = report::write_accounts(&mut plain, accounts.iter().copied(), None);
+    // neither does reading it back
+    processed_inputs::digest(plain.as_slice()).unwrap_or_default()
+}
+
+/// The accounts that differ between the rebuilt report and the report of the run
+/// The accounts of the run whose records were all rejected have no events, they're only a
+/// mismatch if they're locked or have funds
+pub fn mismatches(replayed: &Report, expected: &Report) -> Vec<AccountDelta> {
+    report_delta::report_delta(replayed, expected)
+        .into_iter()
+        .filter(|delta| {
+            !delta.available.is_zero() || !delta.held.is_zero() || delta.locked.is_some()
+        })
+        .collect()
+}
+
+/// Writes the accounts that differ, in the format of `paytoy delta` from the rebuilt report
+/// to the report of the run
+pub fn write_mismatches(
+    writer: &mut impl Write,
+    mismatches: &[AccountDelta],
+) -> std::io::Result<()> {
+    report_delta::write_deltas(writer, mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, STAccountManager},
+        events::{EventSink, JsonLinesSink},
+        records::{ClientId, TransactionId, TransactionRecord, TransactionType},
+        report_reader,
+    };
+
+    use super::*;
+
+    fn record(
+        tr_type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<rust_decimal::Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount,
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    /// Runs the records with `--events`, returns the report and the events
+    fn run(name: &str, records: Vec<TransactionRecord>) -> (Report, String) {
+        let path = std::env::temp_dir().join(format!("paytoy_test_{}.jsonl", name));
+        let sink = std::sync::Arc::new(JsonLinesSink::create(&path).unwrap());
+        let manager = STAccountManager::new()
+            .with_events(sink.clone())
+            .with_pending_withdrawals(None);
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+        sink.flush().unwrap();
+        (report, std::fs::read_to_string(&path).unwrap())
+    }
+
+    #[test]
+    fn test_replay() {
+        let (report, events) = run(
+            "event_replay",
+            vec![
+                record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+                record(TransactionType::Deposit, 1, 2, Some(dec!(5))),
+                record(TransactionType::Withdrawal, 1, 3, Some(dec!(3))),
+                record(TransactionType::Settle, 1, 3, None),
+                record(TransactionType::Dispute, 1, 1, Some(dec!(4))),
+                record(TransactionType::Resolve, 1, 1, None),
+                record(TransactionType::Deposit, 2, 4, Some(dec!(7))),
+                record(TransactionType::Dispute, 2, 4, None),
+                record(TransactionType::ChargeBack, 2, 4, None),
+                // rejected, client 3 is in the report but has no events
+                record(TransactionType::Withdrawal, 3, 5, Some(dec!(1))),
+            ],
+        );
+
+        let replay = EventReplay::replay(events.as_bytes()).unwrap();
+        assert_eq!(replay.applied(), 10);
+        assert_eq!(replay.skipped(), 0);
+        let replayed = replay.into_report();
+        assert_eq!(replayed.account(1).unwrap().total(), dec!(12));
+        assert!(replayed.account(2).unwrap().is_locked());
+        assert!(mismatches(&replayed, &report).is_empty());
+
+        // the report read back from its plain file matches too
+        let mut plain = Vec::new();
+        report::write_accounts(&mut plain, report.accounts(), None).unwrap();
+        let written = report_reader::parse_report(plain.as_slice()).unwrap();
+        assert!(mismatches(&replayed, &written).is_empty());
+        // but not its checksum, with the account of client 3
+        assert_ne!(checksum(&replayed), checksum(&report));
+        assert_eq!(checksum(&replayed).len(), 64);
+    }
+
+    #[test]
+    fn test_incomplete_events() {
+        let (report, events) = run(
+            "incomplete_events",
+            vec![
+                record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+                record(TransactionType::Deposit, 2, 2, Some(dec!(5))),
+                record(TransactionType::Dispute, 1, 1, None),
+            ],
+        );
+
+        // the deposit of the disputed transaction is missing
+        let without_deposit: String = events
+            .lines()
+            .skip(1)
+            .map(|line| line.to_string() + "\n")
+            .collect();
+        let err = EventReplay::replay(without_deposit.as_bytes())
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", err).contains("line 2"));
+        assert!(format!("{:#}", err).contains("does not exist"));
+
+        // the last event is missing, its balances don't add up
+        let truncated: String = events
+            .lines()
+            .take(2)
+            .map(|line| line.to_string() + "\n")
+            .collect();
+        let replayed = EventReplay::replay(truncated.as_bytes())
+            .unwrap()
+            .into_report();
+        let mismatches = mismatches(&replayed, &report);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].key, 1.into());
+        assert_eq!(mismatches[0].held, dec!(10));
+
+        // an event with other balances than its change
+        let tampered = events.replacen("\"available\":\"10\"", "\"available\":\"11\"", 1);
+        assert!(EventReplay::replay(tampered.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,514 @@
+/// Events of the accounts published as the transactions are applied, for `--events`
+/// Downstream systems can react to the balances in near real time instead of waiting for the
+/// report. Each domain event of an account (see `DomainEvent`) is published with the balances
+/// after its transaction: `balance_changed` when it changes the balances, `account_locked` when
+/// it locks the account, `account_updated` otherwise. The events are JSON objects, written as lines to a file (or a
+/// pipe to any message bus) or published to a Kafka topic with the `kafka` feature, keyed by
+/// the account so the events of an account stay in order
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+
+use crate::{
+    client_account::DomainEvent,
+    records::{AccountKey, TransactionId, TransactionType},
+};
+
+/// Prefix of the Kafka targets, `kafka://broker:9092,other:9092/topic`
+const KAFKA_SCHEME: &str = "kafka://";
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum EventKind {
+    BalanceChanged,
+    AccountLocked,
+    /// A dispute got no resolve or chargeback in time, see `--dispute-expiry`
+    DisputeExpired,
+    /// A change of the account that doesn't move the available or held funds, e.g. a settled
+    /// withdrawal or a dispute that holds nothing
+    AccountUpdated,
+}
+
+impl EventKind {
+    const ALL: [EventKind; 4] = [
+        EventKind::BalanceChanged,
+        EventKind::AccountLocked,
+        EventKind::DisputeExpired,
+        EventKind::AccountUpdated,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::BalanceChanged => "balance_changed",
+            EventKind::AccountLocked => "account_locked",
+            EventKind::DisputeExpired => "dispute_expired",
+            EventKind::AccountUpdated => "account_updated",
+        }
+    }
+}
+
+/// The state of an account after the transaction that caused the event
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct AccountEvent {
+    pub kind: EventKind,
+    pub account: AccountKey,
+    pub tr_type: TransactionType,
+    pub tx: TransactionId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+    /// The domain event behind it, none for the expiry of a dispute that was resolved
+    pub change: Option<DomainEvent>,
+}
+
+impl AccountEvent {
+    /// The key of the event on the bus, `client` or `tenant-client`
+    pub fn key(&self) -> String {
+        match self.account.tenant {
+            Some(tenant) => format!("{}-{}", tenant, self.account.client),
+            None => self.account.client.to_string(),
+        }
+    }
+
+    /// The event as a JSON object, the amounts are strings so they don't lose precision
+    pub fn to_json(&self) -> Value {
+        let mut event = json!({
+            "event": self.kind.name(),
+            "client": self.account.client,
+            "type": self.tr_type.to_string(),
+            "tx": self.tx,
+            "available": self.available.normalize().to_string(),
+            "held": self.held.normalize().to_string(),
+            "total": (self.available + self.held).normalize().to_string(),
+            "locked": self.locked,
+        });
+        if let Some(fields) = event.as_object_mut() {
+            if let Some(tenant) = self.account.tenant {
+                fields.insert("tenant".to_string(), tenant.into());
+            }
+            if let Some(change) = &self.change {
+                fields.insert("change".to_string(), change_to_json(change));
+            }
+        }
+        event
+    }
+}
+
+impl AccountEvent {
+    /// Reads back an event written by `to_json`
+    pub fn from_json(event: &Value) -> anyhow::Result<Self> {
+        let name = string_field(event, "event")?;
+        let kind = EventKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown event `{}`", name))?;
+        let tenant = match event.get("tenant") {
+            Some(tenant) => Some(id_field(tenant, "tenant")?),
+            None => None,
+        };
+        let tx = id_field(&event["tx"], "tx")?;
+        Ok(Self {
+            kind,
+            account: AccountKey::new(tenant, id_field(&event["client"], "client")?),
+            tr_type: string_field(event, "type")?.parse()?,
+            tx,
+            available: amount_field(event, "available")?,
+            held: amount_field(event, "held")?,
+            locked: event["locked"]
+                .as_bool()
+                .ok_or_else(|| anyhow::anyhow!("Invalid locked `{}`", event["locked"]))?,
+            change: match event.get("change") {
+                Some(change) => Some(change_from_json(change, tx)?),
+                None => None,
+            },
+        })
+    }
+}
+
+fn string_field<'a>(object: &'a Value, field: &str) -> anyhow::Result<&'a str> {
+    object[field]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid {} `{}`", field, object[field]))
+}
+
+fn id_field<T: TryFrom<u64>>(value: &Value, field: &str) -> anyhow::Result<T> {
+    value
+        .as_u64()
+        .and_then(|id| T::try_from(id).ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid {} `{}`", field, value))
+}
+
+fn amount_field(object: &Value, field: &str) -> anyhow::Result<Decimal> {
+    string_field(object, field)?
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid {} `{}`", field, object[field]))
+}
+
+/// Reads back a domain event written by `change_to_json`, for the transaction `tx`
+fn change_from_json(change: &Value, tx: TransactionId) -> anyhow::Result<DomainEvent> {
+    let amount = || amount_field(change, "amount");
+    let hold = || amount_field(change, "hold");
+    Ok(match string_field(change, "type")? {
+        "funds_deposited" => DomainEvent::FundsDeposited {
+            tx,
+            amount: amount()?,
+        },
+        "funds_withdrawn" => DomainEvent::FundsWithdrawn {
+            tx,
+            amount: amount()?,
+            pending: change["pending"]
+                .as_bool()
+                .ok_or_else(|| anyhow::anyhow!("Invalid pending `{}`", change["pending"]))?,
+        },
+        "payment_sent" => DomainEvent::PaymentSent {
+            tx,
+            amount: amount()?,
+        },
+        "withdrawal_settled" => DomainEvent::WithdrawalSettled {
+            tx,
+            amount: amount()?,
+        },
+        "dispute_opened" => DomainEvent::DisputeOpened {
+            tx,
+            amount: amount()?,
+            hold: hold()?,
+        },
+        "dispute_resolved" => DomainEvent::DisputeResolved {
+            tx,
+            amount: amount()?,
+            hold: hold()?,
+        },
+        "charged_back" => DomainEvent::ChargedBack {
+            tx,
+            amount: amount()?,
+            hold: hold()?,
+        },
+        "dispute_expired" => DomainEvent::DisputeExpired { tx },
+        "account_locked" => DomainEvent::AccountLocked { tx },
+        name => return Err(anyhow::anyhow!("Unknown change `{}`", name)),
+    })
+}
+
+/// A domain event as a JSON object, with the amounts it moved
+fn change_to_json(change: &DomainEvent) -> Value {
+    let amount = |amount: &Decimal| amount.normalize().to_string();
+    match change {
+        DomainEvent::FundsDeposited { amount: value, .. }
+        | DomainEvent::PaymentSent { amount: value, .. }
+        | DomainEvent::WithdrawalSettled { amount: value, .. } => {
+            json!({ "type": change.name(), "amount": amount(value) })
+        }
+        DomainEvent::FundsWithdrawn {
+            amount: value,
+            pending,
+            ..
+        } => json!({ "type": change.name(), "amount": amount(value), "pending": pending }),
+        DomainEvent::DisputeOpened {
+            amount: value,
+            hold,
+            ..
+        }
+        | DomainEvent::DisputeResolved {
+            amount: value,
+            hold,
+            ..
+        }
+        | DomainEvent::ChargedBack {
+            amount: value,
+            hold,
+            ..
+        } => json!({ "type": change.name(), "amount": amount(value), "hold": amount(hold) }),
+        DomainEvent::DisputeExpired { .. } | DomainEvent::AccountLocked { .. } => {
+            json!({ "type": change.name() })
+        }
+    }
+}
+
+/// Where the events are published, shared by all the workers
+pub trait EventSink: Send + Sync {
+    /// Publishes an event, the failures are reported by `flush` so the transactions go on
+    fn publish(&self, event: &AccountEvent);
+
+    /// Waits until the published events are delivered, at the end of the run
+    fn flush(&self) -> anyhow::Result<()>;
+
+    /// A sink of its own for a worker of the multithreaded manager, None to share this one.
+    /// A sink that keeps state per event hands out a part of it, so the workers don't contend
+    fn for_worker(&self) -> Option<Arc<dyn EventSink>> {
+        None
+    }
+}
+
+/// Check if the target publishes the events to a message bus rather than a file
+pub fn is_bus(target: &str) -> bool {
+    target.starts_with(KAFKA_SCHEME)
+}
+
+/// Opens the sink of a `--events` target: a Kafka URL or a file
+pub fn open_sink(target: &str) -> anyhow::Result<Arc<dyn EventSink>> {
+    if let Some(kafka) = target.strip_prefix(KAFKA_SCHEME) {
+        return open_kafka(kafka);
+    }
+    Ok(Arc::new(JsonLinesSink::create(Path::new(target))?))
+}
+
+#[cfg(feature = "kafka")]
+fn open_kafka(target: &str) -> anyhow::Result<Arc<dyn EventSink>> {
+    Ok(Arc::new(crate::kafka_sink::KafkaSink::connect(target)?))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn open_kafka(_target: &str) -> anyhow::Result<Arc<dyn EventSink>> {
+    Err(crate::errors::missing_feature("Kafka", "kafka"))
+}
+
+/// Writes the events as JSON lines
+pub struct JsonLinesSink {
+    writer: Mutex<BufWriter<File>>,
+    /// The first write that failed, reported by `flush`
+    error: Mutex<Option<std::io::Error>>,
+}
+
+impl JsonLinesSink {
+    /// Creates the file, or opens a pipe to a bus producer
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::create(path).map_err(|err| {
+            anyhow::anyhow!(
+                "Failed to create the events file {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(file)),
+            error: Mutex::new(None),
+        })
+    }
+
+    fn fail(&self, err: std::io::Error) {
+        self.error.lock().unwrap().get_or_insert(err);
+    }
+}
+
+impl EventSink for JsonLinesSink {
+    fn publish(&self, event: &AccountEvent) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(err) = writeln!(writer, "{}", event.to_json()) {
+            self.fail(err);
+        }
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        if let Err(err) = self.writer.lock().unwrap().flush() {
+            self.fail(err);
+        }
+        match self.error.lock().unwrap().take() {
+            Some(err) => Err(anyhow::anyhow!("Failed to write the events: {}", err)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, MTAccountManager, STAccountManager},
+        client_account::DisputeExpiry,
+        records::TransactionRecord,
+    };
+
+    use super::*;
+
+    fn record(
+        tr_type: TransactionType,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount,
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    fn publish_events(
+        mut manager: impl AccountManager,
+        sink: &dyn EventSink,
+        path: &Path,
+    ) -> Vec<Value> {
+        for record in [
+            record(TransactionType::Deposit, 1, Some(dec!(2.5))),
+            record(TransactionType::Withdrawal, 2, Some(dec!(5))),
+            record(TransactionType::Dispute, 1, None),
+            record(TransactionType::ChargeBack, 1, None),
+        ] {
+            let _ = manager.apply(record);
+        }
+        manager.finish();
+        sink.flush().unwrap();
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_events() {
+        let path = std::env::temp_dir().join("paytoy_test_events_st.jsonl");
+        let sink = Arc::new(JsonLinesSink::create(&path).unwrap());
+        let manager = STAccountManager::new().with_events(sink.clone());
+        let events = publish_events(manager, sink.as_ref(), &path);
+
+        // the rejected withdrawal changes nothing
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[0],
+            json!({
+                "event": "balance_changed", "client": 1, "type": "deposit", "tx": 1,
+                "available": "2.5", "held": "0", "total": "2.5", "locked": false,
+                "change": { "type": "funds_deposited", "amount": "2.5" },
+            })
+        );
+        assert_eq!(events[1]["held"], "2.5");
+        assert_eq!(
+            events[1]["change"],
+            json!({ "type": "dispute_opened", "amount": "2.5", "hold": "2.5" })
+        );
+        assert_eq!(events[2]["event"], "balance_changed");
+        assert_eq!(events[2]["change"]["type"], "charged_back");
+        assert_eq!(events[3]["event"], "account_locked");
+        assert_eq!(events[3]["change"], json!({ "type": "account_locked" }));
+        assert_eq!(events[3]["total"], "0");
+
+        // the workers share the sink
+        let path = std::env::temp_dir().join("paytoy_test_events_mt.jsonl");
+        let sink = Arc::new(JsonLinesSink::create(&path).unwrap());
+        let manager = MTAccountManager::new(2).with_events(sink.clone());
+        assert_eq!(publish_events(manager, sink.as_ref(), &path), events);
+    }
+
+    #[test]
+    fn test_dispute_expired_event() {
+        let path = std::env::temp_dir().join("paytoy_test_events_expiry.jsonl");
+        let sink = Arc::new(JsonLinesSink::create(&path).unwrap());
+        let mut manager = STAccountManager::new()
+            .with_events(sink.clone())
+            .with_dispute_expiry(10, DisputeExpiry::Resolve);
+        for (tr_type, timestamp) in [(TransactionType::Deposit, 1), (TransactionType::Dispute, 2)] {
+            let mut record = record(tr_type, 1, Some(dec!(2.5)));
+            record.timestamp = Some(timestamp);
+            manager.apply(record).unwrap();
+        }
+        let mut later = record(TransactionType::Deposit, 2, Some(dec!(1)));
+        later.timestamp = Some(12);
+        manager.apply(later).unwrap();
+        manager.finish();
+        sink.flush().unwrap();
+
+        let events: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // the synthetic resolve is applied before the next deposit
+        let names: Vec<_> = events.iter().map(|event| event["event"].clone()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "balance_changed",
+                "balance_changed",
+                "balance_changed",
+                "dispute_expired",
+                "balance_changed"
+            ]
+        );
+        assert_eq!(events[3]["type"], "resolve");
+        assert_eq!(events[3]["available"], "2.5");
+        assert_eq!(events[2]["change"]["type"], "dispute_resolved");
+        assert!(events[3].get("change").is_none());
+    }
+
+    #[test]
+    fn test_account_updated_event() {
+        let path = std::env::temp_dir().join("paytoy_test_events_updated.jsonl");
+        let sink = Arc::new(JsonLinesSink::create(&path).unwrap());
+        let mut manager = STAccountManager::new()
+            .with_events(sink.clone())
+            .with_pending_withdrawals(None);
+        for record in [
+            record(TransactionType::Deposit, 1, Some(dec!(3))),
+            record(TransactionType::Withdrawal, 2, Some(dec!(1))),
+            record(TransactionType::Settle, 2, None),
+        ] {
+            manager.apply(record).unwrap();
+        }
+        manager.finish();
+        sink.flush().unwrap();
+
+        let events: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            events[1]["change"],
+            json!({ "type": "funds_withdrawn", "amount": "1", "pending": true })
+        );
+        // the settlement only moves the pending funds
+        assert_eq!(events[2]["event"], "account_updated");
+        assert_eq!(
+            events[2]["change"],
+            json!({ "type": "withdrawal_settled", "amount": "1" })
+        );
+    }
+
+    #[test]
+    fn test_event_key() {
+        let event = AccountEvent {
+            kind: EventKind::BalanceChanged,
+            account: AccountKey::new(Some(3), 7),
+            tr_type: TransactionType::Deposit,
+            tx: 1,
+            available: dec!(1),
+            held: dec!(0),
+            locked: false,
+            change: None,
+        };
+        assert_eq!(event.key(), "3-7");
+        assert_eq!(event.to_json()["tenant"], 3);
+        assert_eq!(AccountEvent::from_json(&event.to_json()).unwrap(), event);
+        let withdrawn = AccountEvent {
+            change: Some(DomainEvent::FundsWithdrawn {
+                tx: 1,
+                amount: dec!(1.25),
+                pending: true,
+            }),
+            ..event
+        };
+        assert_eq!(
+            AccountEvent::from_json(&withdrawn.to_json()).unwrap(),
+            withdrawn
+        );
+        let mut unknown = withdrawn.to_json();
+        unknown["change"]["type"] = "refunded".into();
+        assert!(AccountEvent::from_json(&unknown).is_err());
+        assert!(open_sink("/nonexistent/dir/events.jsonl").is_err());
+        assert!(is_bus("kafka://broker:9092/balances"));
+        assert!(!is_bus("events.jsonl"));
+    }
+}
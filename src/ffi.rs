@@ -0,0 +1,278 @@
+/// C bindings of the engine, to embed it in a native service instead of spawning the CLI
+/// An engine is a single threaded account manager, the transactions are pushed one at a time
+/// and the accounts can be read at any point. The amounts cross the boundary as decimal strings,
+/// like in the CSV files, so they don't lose precision. The header is `include/paytoy.h`,
+/// generated by cbindgen from this module with the default widths of the ids, it has to be
+/// generated again for a build with the `client-id-*` or `tx-id-u64` features
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    ptr,
+};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    account_manager::{AccountManager, STAccountManager},
+    records::{ClientId, TransactionId, TransactionRecord, TransactionType},
+};
+
+/// The transaction was applied
+pub const PAYTOY_OK: c_int = 0;
+/// The transaction was rejected, `paytoy_engine_last_error` tells why
+pub const PAYTOY_REJECTED: c_int = 1;
+/// The engine is null, or the type or the amount can't be parsed
+pub const PAYTOY_INVALID_ARGUMENT: c_int = -1;
+
+/// The transaction types, as given to `paytoy_engine_push`
+pub const PAYTOY_DEPOSIT: c_int = 0;
+pub const PAYTOY_WITHDRAWAL: c_int = 1;
+pub const PAYTOY_DISPUTE: c_int = 2;
+pub const PAYTOY_RESOLVE: c_int = 3;
+pub const PAYTOY_CHARGEBACK: c_int = 4;
+/// Only accepted by an engine from `paytoy_engine_new_with_pending_withdrawals`
+pub const PAYTOY_SETTLE: c_int = 5;
+
+/// Size of the amount buffers of `PaytoyAccount`, enough for any decimal and its terminator
+pub const PAYTOY_AMOUNT_SIZE: usize = 48;
+
+/// An engine created by one of the `paytoy_engine_new` functions, opaque to C
+pub struct PaytoyEngine {
+    manager: STAccountManager,
+    /// Reason of the last push that failed, kept alive until the next push
+    last_error: Option<CString>,
+}
+
+/// The balances of an account, the amounts are nul-terminated decimal strings
+#[repr(C)]
+pub struct PaytoyAccount {
+    pub client: ClientId,
+    pub available: [c_char; PAYTOY_AMOUNT_SIZE],
+    pub held: [c_char; PAYTOY_AMOUNT_SIZE],
+    /// Withdrawn but not settled yet, always 0 without pending withdrawals
+    pub pending_out: [c_char; PAYTOY_AMOUNT_SIZE],
+    pub total: [c_char; PAYTOY_AMOUNT_SIZE],
+    pub locked: bool,
+}
+
+/// Creates an engine without any account, to be released with `paytoy_engine_free`
+#[no_mangle]
+pub extern "C" fn paytoy_engine_new() -> *mut PaytoyEngine {
+    PaytoyEngine::create(STAccountManager::new())
+}
+
+/// Creates an engine whose withdrawals keep their funds in `pending_out` until a
+/// `PAYTOY_SETTLE` with the id of the withdrawal, like `--settle-withdrawals`
+#[no_mangle]
+pub extern "C" fn paytoy_engine_new_with_pending_withdrawals() -> *mut PaytoyEngine {
+    PaytoyEngine::create(STAccountManager::new().with_pending_withdrawals(None))
+}
+
+/// Applies a transaction to the accounts of the engine
+/// `amount` is a decimal string, it may be null for the disputes, resolves and chargebacks.
+/// A dispute with an amount only disputes that part of the deposit.
+/// Returns `PAYTOY_OK`, `PAYTOY_REJECTED` or `PAYTOY_INVALID_ARGUMENT`
+///
+/// # Safety
+/// `engine` must come from a `paytoy_engine_new` function and `amount` must be null or a
+/// nul-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn paytoy_engine_push(
+    engine: *mut PaytoyEngine,
+    tx_type: c_int,
+    client: ClientId,
+    tx: TransactionId,
+    amount: *const c_char,
+) -> c_int {
+    let engine = match engine.as_mut() {
+        Some(engine) => engine,
+        None => return PAYTOY_INVALID_ARGUMENT,
+    };
+    let amount = if amount.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(amount).to_str().ok().and_then(parse_amount) {
+            Some(amount) => Some(amount),
+            None => return engine.fail("invalid_amount", PAYTOY_INVALID_ARGUMENT),
+        }
+    };
+    let tr_type = match transaction_type(tx_type) {
+        Some(tr_type) => tr_type,
+        None => return engine.fail("invalid_type", PAYTOY_INVALID_ARGUMENT),
+    };
+
+    let record = TransactionRecord {
+        tr_type,
+        client,
+        tenant: None,
+        merchant: None,
+        tx,
+        amount,
+        timestamp: None,
+        source: None,
+    };
+    match engine.manager.apply(record) {
+        Ok(()) => {
+            engine.last_error = None;
+            PAYTOY_OK
+        }
+        Err(err) => engine.fail(err.code(), PAYTOY_REJECTED),
+    }
+}
+
+/// The reason code of the last push that failed (e.g. `insufficient_funds`), null if the last
+/// push succeeded. The string is owned by the engine and valid until the next push
+///
+/// # Safety
+/// `engine` must be null or come from a `paytoy_engine_new` function
+#[no_mangle]
+pub unsafe extern "C" fn paytoy_engine_last_error(engine: *const PaytoyEngine) -> *const c_char {
+    engine
+        .as_ref()
+        .and_then(|engine| engine.last_error.as_ref())
+        .map_or(ptr::null(), |err| err.as_ptr())
+}
+
+/// Copies the balances of the `client` to `account`
+/// Returns false if the engine has no such account, `account` is left untouched then
+///
+/// # Safety
+/// `engine` must be null or come from a `paytoy_engine_new` function, and `account` must be
+/// null or point to a `PaytoyAccount`
+#[no_mangle]
+pub unsafe extern "C" fn paytoy_engine_get_account(
+    engine: *const PaytoyEngine,
+    client: ClientId,
+    account: *mut PaytoyAccount,
+) -> bool {
+    let (engine, account) = match (engine.as_ref(), account.as_mut()) {
+        (Some(engine), Some(account)) => (engine, account),
+        _ => return false,
+    };
+    let found = match engine.manager.account(client) {
+        Some(found) => found,
+        None => return false,
+    };
+    account.client = client;
+    write_amount(&mut account.available, found.available());
+    write_amount(&mut account.held, found.held());
+    write_amount(&mut account.pending_out, found.pending_out());
+    write_amount(&mut account.total, found.total());
+    account.locked = found.is_locked();
+    true
+}
+
+/// Releases an engine and all its accounts, null is ignored
+///
+/// # Safety
+/// `engine` must be null or come from a `paytoy_engine_new` function, and not be used
+/// afterwards
+#[no_mangle]
+pub unsafe extern "C" fn paytoy_engine_free(engine: *mut PaytoyEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+impl PaytoyEngine {
+    fn create(manager: STAccountManager) -> *mut PaytoyEngine {
+        Box::into_raw(Box::new(PaytoyEngine {
+            manager,
+            last_error: None,
+        }))
+    }
+
+    fn fail(&mut self, reason: &str, status: c_int) -> c_int {
+        self.last_error = CString::new(reason).ok();
+        status
+    }
+}
+
+fn transaction_type(tx_type: c_int) -> Option<TransactionType> {
+    match tx_type {
+        PAYTOY_DEPOSIT => Some(TransactionType::Deposit),
+        PAYTOY_WITHDRAWAL => Some(TransactionType::Withdrawal),
+        PAYTOY_DISPUTE => Some(TransactionType::Dispute),
+        PAYTOY_RESOLVE => Some(TransactionType::Resolve),
+        PAYTOY_CHARGEBACK => Some(TransactionType::ChargeBack),
+        PAYTOY_SETTLE => Some(TransactionType::Settle),
+        _ => None,
+    }
+}
+
+fn parse_amount(amount: &str) -> Option<Decimal> {
+    amount.trim().parse().ok()
+}
+
+/// Writes the amount as a nul-terminated string, it always fits in the buffer
+fn write_amount(buffer: &mut [c_char; PAYTOY_AMOUNT_SIZE], amount: Decimal) {
+    let amount = amount.normalize().to_string();
+    for (dst, src) in buffer.iter_mut().zip(amount.bytes()) {
+        *dst = src as c_char;
+    }
+    buffer[amount.len().min(PAYTOY_AMOUNT_SIZE - 1)] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(buffer: &[c_char; PAYTOY_AMOUNT_SIZE]) -> &str {
+        unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap()
+    }
+
+    fn last_error(engine: *const PaytoyEngine) -> Option<&'static str> {
+        let err = unsafe { paytoy_engine_last_error(engine) };
+        (!err.is_null()).then(|| unsafe { CStr::from_ptr(err) }.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_engine() {
+        let engine = paytoy_engine_new();
+        let push = |tx_type, client, tx, amount: Option<&str>| {
+            let amount = amount.map(|amount| CString::new(amount).unwrap());
+            let amount = amount.as_deref().map_or(ptr::null(), CStr::as_ptr);
+            unsafe { paytoy_engine_push(engine, tx_type, client, tx, amount) }
+        };
+
+        assert_eq!(push(PAYTOY_DEPOSIT, 1, 1, Some("2.50")), PAYTOY_OK);
+        assert_eq!(push(PAYTOY_DEPOSIT, 1, 2, Some("1.0001")), PAYTOY_OK);
+        assert_eq!(push(PAYTOY_DISPUTE, 1, 1, None), PAYTOY_OK);
+        assert_eq!(last_error(engine), None);
+
+        assert_eq!(push(PAYTOY_WITHDRAWAL, 1, 3, Some("5")), PAYTOY_REJECTED);
+        assert_eq!(last_error(engine), Some("insufficient_funds"));
+        assert_eq!(
+            push(PAYTOY_DEPOSIT, 1, 4, Some("1.x")),
+            PAYTOY_INVALID_ARGUMENT
+        );
+        assert_eq!(last_error(engine), Some("invalid_amount"));
+        assert_eq!(push(7, 1, 4, None), PAYTOY_INVALID_ARGUMENT);
+        assert_eq!(last_error(engine), Some("invalid_type"));
+        assert_eq!(push(PAYTOY_SETTLE, 1, 2, None), PAYTOY_REJECTED);
+        assert_eq!(last_error(engine), Some("not_pending"));
+
+        let mut account = PaytoyAccount {
+            client: 0,
+            available: [0; PAYTOY_AMOUNT_SIZE],
+            held: [0; PAYTOY_AMOUNT_SIZE],
+            pending_out: [0; PAYTOY_AMOUNT_SIZE],
+            total: [0; PAYTOY_AMOUNT_SIZE],
+            locked: true,
+        };
+        assert!(unsafe { paytoy_engine_get_account(engine, 1, &mut account) });
+        assert_eq!(account.client, 1);
+        assert_eq!(amount(&account.available), "1.0001");
+        assert_eq!(amount(&account.held), "2.5");
+        assert_eq!(amount(&account.pending_out), "0");
+        assert_eq!(amount(&account.total), "3.5001");
+        assert!(!account.locked);
+        assert!(!unsafe { paytoy_engine_get_account(engine, 2, &mut account) });
+
+        unsafe { paytoy_engine_free(engine) };
+        unsafe { paytoy_engine_free(ptr::null_mut()) };
+        assert_eq!(
+            unsafe { paytoy_engine_push(ptr::null_mut(), PAYTOY_DEPOSIT, 1, 1, ptr::null()) },
+            PAYTOY_INVALID_ARGUMENT
+        );
+    }
+}
@@ -0,0 +1,206 @@
+/// The fused pipeline of `--mode fused`, for a single CSV input
+/// The parser threads of the multithreaded reader route each record straight to the queue of
+/// the shard owning its account, and the worker of each shard puts the blocks back in order
+/// by their sequence number before applying them. There's no reorder thread and no
+/// dispatcher in between, so two hops less than `--mode multi`, but the records don't go
+/// through a single stream either: the rate limits, the replay window and the other
+/// transformations of the stream can't be applied
+use std::io::Read;
+
+use hashbrown::HashMap;
+
+use crate::{
+    account_manager::{AccountManager, STAccountManager},
+    affinity::CoreList,
+    report::Report,
+    snapshot::Snapshot,
+    transactions_reader::MTReader,
+};
+
+pub struct FusedPipeline {
+    reader: MTReader,
+    /// The manager of each shard of the accounts, each one applied by its own worker
+    shards: Vec<STAccountManager>,
+    cores: Option<CoreList>,
+}
+
+impl FusedPipeline {
+    /// Parses with `reader` and applies the records on `num_workers` workers, `new_shard`
+    /// makes the manager of each worker with the options of the run
+    pub fn new(
+        reader: MTReader,
+        num_workers: usize,
+        new_shard: impl FnMut() -> STAccountManager,
+    ) -> Self {
+        Self {
+            reader,
+            shards: std::iter::repeat_with(new_shard)
+                .take(num_workers.max(1))
+                .collect(),
+            cores: None,
+        }
+    }
+
+    /// Pin the workers to these cores, in turn
+    pub fn with_worker_cores(mut self, cores: CoreList) -> Self {
+        self.cores = Some(cores);
+        self
+    }
+
+    /// Start from the accounts of a snapshot, each shard gets the accounts it owns
+    pub fn with_snapshot(mut self, snapshot: &Snapshot) -> Self {
+        let mut accounts = vec![Vec::new(); self.shards.len()];
+        for account in &snapshot.accounts {
+            accounts[account.key().shard(self.shards.len())].push(account.clone());
+        }
+        for (shard, accounts) in self.shards.iter_mut().zip(accounts) {
+            shard.restore(&accounts);
+        }
+        self
+    }
+
+    /// Reads the CSV input and applies its records, returns the report once they're all applied
+    pub fn run(self, input: impl Read + Send + 'static) -> anyhow::Result<Report> {
+        let streams = self.reader.read_shards(input, self.shards.len())?;
+        let cores = self.cores;
+        let workers = self
+            .shards
+            .into_iter()
+            .zip(streams)
+            .enumerate()
+            .map(|(worker_id, (mut shard, mut stream))| {
+                let cores = cores.clone();
+                std::thread::Builder::new()
+                    .name(format!("worker {}", worker_id))
+                    .spawn(move || {
+                        if let Some(cores) = cores {
+                            cores.pin(worker_id);
+                        }
+                        let mut latest_timestamp = None;
+                        shard.execute(&mut stream.by_ref().inspect(|record| {
+                            if let Some(timestamp) = record.timestamp {
+                                latest_timestamp = latest_timestamp.max(Some(timestamp));
+                            }
+                        }));
+                        (shard, latest_timestamp)
+                    })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut shards = Vec::with_capacity(workers.len());
+        for worker in workers {
+            match worker.join() {
+                Ok(shard) => shards.push(shard),
+                // an invariant broken with `--check-invariants` aborts the run
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        }
+
+        // a shard only knows the time of its own records
+        let now = shards.iter().filter_map(|(_, latest)| *latest).max();
+        let mut report = Report::new(HashMap::new(), Vec::new());
+        for (mut shard, _) in shards {
+            if let Some(now) = now {
+                shard.advance_time(now);
+            }
+            report.merge(shard.finish());
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use crate::{
+        account_manager::LockedPolicy,
+        client_account::DisputePolicy,
+        dead_letter::DeadLetters,
+        manager_conformance::{assert_same_reports, workloads},
+        records::{TransactionRecord, TransactionType},
+    };
+
+    use super::*;
+
+    /// The CSV lines of the records of a workload
+    fn to_csv(records: &[TransactionRecord]) -> String {
+        let mut csv = String::from("type,client,tenant,merchant,tx,amount\n");
+        for record in records {
+            let tr_type = match record.tr_type {
+                TransactionType::Deposit => "deposit",
+                TransactionType::Withdrawal => "withdrawal",
+                TransactionType::Dispute => "dispute",
+                TransactionType::Resolve => "resolve",
+                TransactionType::ChargeBack => "chargeback",
+                TransactionType::Settle => "settle",
+                TransactionType::Payment => "payment",
+            };
+            fn optional(value: Option<impl ToString>) -> String {
+                value.map_or_else(String::new, |value| value.to_string())
+            }
+            let amount = record
+                .amount
+                .map_or_else(String::new, |amount| amount.to_string());
+            csv += &format!(
+                "{},{},{},{},{},{}\n",
+                tr_type,
+                record.client,
+                optional(record.tenant),
+                optional(record.merchant),
+                record.tx,
+                amount
+            );
+        }
+        csv
+    }
+
+    #[test]
+    fn test_same_report_as_single_threaded() {
+        let new_shard = || {
+            STAccountManager::new()
+                .with_dispute_policy(DisputePolicy::AllowNegativeAvailable)
+                .with_locked_policy(LockedPolicy::allowing(&[TransactionType::Resolve]))
+        };
+        for (name, workload) in workloads() {
+            for seed in 0..2 {
+                let check = format!("{} (seed {})", name, seed);
+                let csv = to_csv(&workload(seed));
+                let expected =
+                    new_shard().execute_transactions(Box::new(workload(seed).into_iter()));
+                // small blocks, so the parsers route them out of order
+                let reader = MTReader::new().with_threads(3).block_size(256);
+                let report = FusedPipeline::new(reader, 4, new_shard)
+                    .run(Cursor::new(csv.into_bytes()))
+                    .unwrap();
+                assert_same_reports(&check, &report, &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dead_letters_in_order() {
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 1..=2000 {
+            csv += &format!("deposit,{},{},1.0\n", tx % 5, tx);
+            if tx % 100 == 0 {
+                csv += &format!("bogus,{},{},1.0\n", tx % 5, tx);
+            }
+        }
+        let output = std::env::temp_dir().join("paytoy_test_fused_dead_letters.csv");
+        let dead_letters = Arc::new(DeadLetters::create(&output).unwrap());
+        // small blocks, so the lines come from many of them
+        let reader = MTReader::new()
+            .with_threads(3)
+            .block_size(64)
+            .with_dead_letters(dead_letters);
+        let report = FusedPipeline::new(reader, 3, STAccountManager::new)
+            .run(Cursor::new(csv.into_bytes()))
+            .unwrap();
+        assert_eq!(report.accounts().count(), 5);
+        let expected: String = std::iter::once("type,client,tx,amount\n".to_string())
+            .chain((1..=20).map(|n| format!("bogus,0,{},1.0\n", n * 100)))
+            .collect();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), expected);
+    }
+}
@@ -0,0 +1,187 @@
+/// Storage for the deposits evicted from the in-memory history of the accounts
+/// An account with millions of deposits would otherwise keep all of them in memory, in case
+/// one of them is disputed. With a history limit, the oldest deposits are moved to a
+/// `HistoryStore` and loaded back when they are disputed
+use std::{
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rust_decimal::Decimal;
+
+use crate::records::{wide_id, AccountKey, ClientId, TransactionId};
+
+/// Deposits of the accounts that are not kept in memory
+pub trait HistoryStore: Send {
+    /// Stores the `amount` of a deposit that is not disputed
+    fn put(
+        &mut self,
+        account: AccountKey,
+        tx: TransactionId,
+        amount: Decimal,
+    ) -> std::io::Result<()>;
+
+    /// Removes a deposit from the store and returns its amount, if it was stored
+    fn take(&mut self, account: AccountKey, tx: TransactionId) -> std::io::Result<Option<Decimal>>;
+
+    /// Checks if a deposit is stored, to reject the duplicate transactions
+    fn contains(&mut self, account: AccountKey, tx: TransactionId) -> std::io::Result<bool>;
+}
+
+const CLIENT_SIZE: usize = std::mem::size_of::<ClientId>();
+/// Where the amount starts in a slot
+const AMOUNT_OFFSET: usize = 1 + 2 + CLIENT_SIZE;
+/// Size of a slot: a flag, the tenant, the client and the amount
+const SLOT_SIZE: u64 = AMOUNT_OFFSET as u64 + 16;
+/// Flags of the used slots, with or without a tenant
+const USED: u8 = 1;
+const USED_WITH_TENANT: u8 = 2;
+
+/// Used to give a distinct name to the files of the stores of a process
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// Keeps the deposits in a sparse file, with a fixed size slot at the offset of each
+/// transaction id, so no index has to be kept in memory. The holes of the file don't take any
+/// disk space. The file is removed when the store is dropped
+pub struct FileHistoryStore {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileHistoryStore {
+    /// Creates the file of the store in the `dir` directory
+    pub fn create(dir: &Path) -> std::io::Result<Self> {
+        let path = dir.join(format!(
+            "paytoy-history-{}-{}",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self { file, path })
+    }
+
+    /// Reads the slot of a transaction, `None` if it's empty
+    fn read_slot(&mut self, tx: TransactionId) -> std::io::Result<Option<(AccountKey, Decimal)>> {
+        let mut slot = [0u8; SLOT_SIZE as usize];
+        self.file.seek(SeekFrom::Start(slot_offset(tx)?))?;
+        match self.file.read_exact(&mut slot) {
+            Ok(()) => {}
+            // past the end of the file, nothing was stored there
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let tenant = match slot[0] {
+            USED => None,
+            USED_WITH_TENANT => Some(u16::from_le_bytes([slot[1], slot[2]])),
+            _ => return Ok(None),
+        };
+        let client = ClientId::from_le_bytes(slot[3..AMOUNT_OFFSET].try_into().unwrap());
+        let amount = slot[AMOUNT_OFFSET..].try_into().unwrap();
+        Ok(Some((
+            AccountKey::new(tenant, client),
+            Decimal::deserialize(amount),
+        )))
+    }
+
+    fn write_slot(&mut self, tx: TransactionId, slot: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(slot_offset(tx)?))?;
+        self.file.write_all(slot)
+    }
+}
+
+/// The slots of the widest transaction ids would be past the largest offset
+fn slot_offset(tx: TransactionId) -> std::io::Result<u64> {
+    wide_id(tx)
+        .checked_mul(SLOT_SIZE)
+        .ok_or_else(|| std::io::Error::other(format!("tx {} is too large to be stored", tx)))
+}
+
+impl HistoryStore for FileHistoryStore {
+    fn put(
+        &mut self,
+        account: AccountKey,
+        tx: TransactionId,
+        amount: Decimal,
+    ) -> std::io::Result<()> {
+        // the transaction ids are unique across the clients, except in invalid inputs
+        // (or across the tenants, the deposit stays in memory then)
+        if let Some((other, _)) = self.read_slot(tx)? {
+            if other != account {
+                return Err(std::io::Error::other(format!(
+                    "tx {} is already stored for client {}",
+                    tx, other.client
+                )));
+            }
+        }
+        let mut slot = [0u8; SLOT_SIZE as usize];
+        slot[0] = match account.tenant {
+            Some(_) => USED_WITH_TENANT,
+            None => USED,
+        };
+        slot[1..3].copy_from_slice(&account.tenant.unwrap_or_default().to_le_bytes());
+        slot[3..AMOUNT_OFFSET].copy_from_slice(&account.client.to_le_bytes());
+        slot[AMOUNT_OFFSET..].copy_from_slice(&amount.serialize());
+        self.write_slot(tx, &slot)
+    }
+
+    fn take(&mut self, account: AccountKey, tx: TransactionId) -> std::io::Result<Option<Decimal>> {
+        match self.read_slot(tx)? {
+            Some((stored, amount)) if stored == account => {
+                self.write_slot(tx, &[0])?;
+                Ok(Some(amount))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn contains(&mut self, account: AccountKey, tx: TransactionId) -> std::io::Result<bool> {
+        Ok(matches!(self.read_slot(tx)?, Some((stored, _)) if stored == account))
+    }
+}
+
+impl Drop for FileHistoryStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_file_history_store() {
+        let mut store = FileHistoryStore::create(&std::env::temp_dir()).unwrap();
+        let path = store.path.clone();
+
+        let tenant = AccountKey::new(Some(0), 1);
+        assert!(!store.contains(1.into(), 7).unwrap());
+        store.put(1.into(), 7, dec!(12.3456)).unwrap();
+        store.put(2.into(), 3_000_000, dec!(-0.5)).unwrap();
+        store.put(tenant, 8, dec!(3)).unwrap();
+        assert!(store.contains(1.into(), 7).unwrap());
+        // another client can't see it, nor store the same transaction
+        assert!(!store.contains(2.into(), 7).unwrap());
+        assert!(!store.contains(tenant, 7).unwrap());
+        assert!(store.put(2.into(), 7, dec!(1)).is_err());
+
+        assert_eq!(store.take(2.into(), 3_000_000).unwrap(), Some(dec!(-0.5)));
+        assert_eq!(store.take(2.into(), 3_000_000).unwrap(), None);
+        assert_eq!(store.take(1.into(), 7).unwrap(), Some(dec!(12.3456)));
+        assert!(!store.contains(1.into(), 7).unwrap());
+        assert_eq!(store.take(1.into(), 8).unwrap(), None);
+        assert_eq!(store.take(tenant, 8).unwrap(), Some(dec!(3)));
+
+        drop(store);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,268 @@
+/// Streams inputs from HTTP(S) URLs
+/// Big downloads are likely to be interrupted, so a dropped or silent connection is resumed
+/// with a range request from the last received byte instead of failing the run
+use std::io::{ErrorKind, Read};
+
+use log::*;
+use ureq::Agent;
+
+use crate::input::StreamPolicy;
+
+pub fn open(url: &str, policy: &StreamPolicy) -> anyhow::Result<Box<dyn Read + Send>> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(policy.connect_timeout)
+        .timeout_read(policy.read_timeout)
+        .build();
+    // fail early if the file can't be downloaded at all
+    let body = agent.get(url).call()?.into_reader();
+
+    Ok(Box::new(HttpReader {
+        agent,
+        url: url.to_string(),
+        body,
+        offset: 0,
+        policy: policy.clone(),
+    }))
+}
+
+struct HttpReader {
+    agent: Agent,
+    url: String,
+    body: Box<dyn Read + Send + Sync>,
+    /// Number of bytes received so far
+    offset: u64,
+    policy: StreamPolicy,
+}
+
+impl HttpReader {
+    /// Requests the rest of the file, starting from the current offset
+    /// Fails with `ErrorKind::Unsupported` if the server can't resume the download
+    fn resume(&mut self) -> std::io::Result<()> {
+        let response = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-", self.offset))
+            .call()
+            .map_err(std::io::Error::other)?;
+        // a server that ignores ranges sends the whole file again
+        if response.status() != 206 {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "The server doesn't support resuming downloads (status {})",
+                    response.status()
+                ),
+            ));
+        }
+        self.body = response.into_reader();
+        Ok(())
+    }
+}
+
+/// Whether the read failed because the upstream sent nothing for the read timeout
+fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            let mut err = match self.body.read(buf) {
+                Ok(len) => {
+                    self.offset += len as u64;
+                    return Ok(len);
+                }
+                Err(err) => err,
+            };
+
+            // reconnect until a request succeeds, the body is read again then
+            loop {
+                attempt += 1;
+                if attempt > self.policy.retries {
+                    let reason = if is_timeout(&err) {
+                        format!("no data for {:?}", self.policy.read_timeout)
+                    } else {
+                        err.to_string()
+                    };
+                    return Err(std::io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Download of {} failed at byte {} after {} retries: {}",
+                            self.url, self.offset, self.policy.retries, reason
+                        ),
+                    ));
+                }
+                let delay = self.policy.backoff(attempt);
+                if is_timeout(&err) {
+                    warn!(
+                        "No data from {} for {:?} at byte {}, reconnecting in {:?} ({}/{})",
+                        self.url,
+                        self.policy.read_timeout,
+                        self.offset,
+                        delay,
+                        attempt,
+                        self.policy.retries
+                    );
+                } else {
+                    warn!(
+                        "Download interrupted at byte {}, retrying in {:?} ({}/{}): {:?}",
+                        self.offset, delay, attempt, self.policy.retries, err
+                    );
+                }
+                std::thread::sleep(delay);
+
+                match self.resume() {
+                    Ok(()) => break,
+                    // not worth retrying if the server can't resume
+                    Err(err) if err.kind() == ErrorKind::Unsupported => return Err(err),
+                    Err(failed) => err = failed,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    /// Reads the request and writes the headers of the response, the rest of `data` after the
+    /// range asked for is returned to be sent as the body
+    fn respond(stream: &mut TcpStream, data: &'static [u8]) -> &'static [u8] {
+        let mut range_start = 0;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(range) = line.to_lowercase().strip_prefix("range: bytes=") {
+                range_start = range.trim().trim_end_matches('-').parse().unwrap();
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+
+        let body = &data[range_start..];
+        let status = if range_start > 0 {
+            "206 Partial Content"
+        } else {
+            "200 OK"
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        )
+        .unwrap();
+        body
+    }
+
+    /// Keeps the connection open without sending anything more
+    fn hold(stream: TcpStream) {
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(5));
+            drop(stream);
+        });
+    }
+
+    /// Serves `data`, the first connection stops halfway through the body: it's dropped, or
+    /// it goes silent if `stall`
+    fn serve_flaky(data: &'static [u8], stall: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for (attempt, stream) in listener.incoming().take(2).enumerate() {
+                let mut stream = stream.unwrap();
+                let body = respond(&mut stream, data);
+                if attempt == 0 {
+                    stream.write_all(&body[..body.len() / 2]).unwrap();
+                    if stall {
+                        hold(stream);
+                    }
+                } else {
+                    stream.write_all(body).unwrap();
+                }
+            }
+        });
+        format!("http://{}/transactions.csv", address)
+    }
+
+    fn quick_policy(retries: u32) -> StreamPolicy {
+        StreamPolicy {
+            read_timeout: Duration::from_millis(200),
+            retries,
+            retry_delay: Duration::from_millis(10),
+            ..StreamPolicy::default()
+        }
+    }
+
+    const DATA: &[u8] = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\n";
+
+    #[test]
+    fn test_resume_download() {
+        let url = serve_flaky(DATA, false);
+
+        let mut downloaded = Vec::new();
+        open(&url, &StreamPolicy::default())
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .unwrap();
+        assert_eq!(downloaded, DATA);
+    }
+
+    #[test]
+    fn test_silent_upstream() {
+        let url = serve_flaky(DATA, true);
+
+        let mut downloaded = Vec::new();
+        open(&url, &quick_policy(1))
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .unwrap();
+        assert_eq!(downloaded, DATA);
+    }
+
+    #[test]
+    fn test_upstream_never_recovers() {
+        // the first connection goes silent after a byte of the body, the others right away
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (attempt, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let body = respond(&mut stream, DATA);
+                if attempt == 0 {
+                    stream.write_all(&body[..1]).unwrap();
+                }
+                hold(stream);
+            }
+        });
+
+        let start = Instant::now();
+        let err = open(&url, &quick_policy(2))
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("at byte 1 after 2 retries: no data for"));
+        // the first read and the 2 reconnections time out, well before the server gives up
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_unreachable_url() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(open(&url, &StreamPolicy::default()).is_err());
+    }
+}
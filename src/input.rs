@@ -0,0 +1,313 @@
+/// Opens the transactions input, which can be a local file, a URL or an object in a bucket
+/// Gzip compressed inputs (`.gz`) are decompressed on the fly and the CSV files
+/// of zip archives (`.zip`) are read one after another
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use crossbeam_channel::Receiver;
+use flate2::read::MultiGzDecoder;
+
+use crate::transactions_reader::{TransactionCSVReader, TransactionsStream};
+
+/// URL prefixes of the inputs read from an object store
+const OBJECT_STORE_SCHEMES: [&str; 6] =
+    ["s3://", "s3a://", "gs://", "az://", "abfs://", "abfss://"];
+/// URL prefixes of the inputs downloaded over HTTP
+const HTTP_SCHEMES: [&str; 2] = ["http://", "https://"];
+
+/// How long the remote inputs wait for their upstream, and how they reconnect when it
+/// drops or goes silent
+#[derive(Clone, Debug)]
+pub struct StreamPolicy {
+    pub connect_timeout: Duration,
+    /// A read waiting longer than this for data fails, and the download is resumed
+    pub read_timeout: Duration,
+    /// How many times in a row a download can fail before giving up
+    pub retries: u32,
+    /// Delay before the first retry, doubled on each attempt up to `max_retry_delay`
+    pub retry_delay: Duration,
+    pub max_retry_delay: Duration,
+}
+
+impl Default for StreamPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(60),
+            retries: 5,
+            retry_delay: Duration::from_millis(500),
+            max_retry_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl StreamPolicy {
+    /// The delay before the retry `attempt`, counted from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(1 << (attempt.saturating_sub(1)).min(16))
+            .min(self.max_retry_delay)
+    }
+}
+
+pub struct Input {
+    /// Where the input was opened from, to tag the records with it
+    name: Arc<str>,
+    data: InputData,
+}
+
+enum InputData {
+    /// Plain local file, readers can seek in it
+    File(PathBuf),
+    /// Anything else is streamed
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Input {
+    pub fn open(location: &Path) -> anyhow::Result<Self> {
+        Self::open_entries(location, None)
+    }
+
+    /// Same as `open`, but only the entries of a zip archive matching the glob are read
+    pub fn open_entries(location: &Path, zip_entries: Option<&str>) -> anyhow::Result<Self> {
+        Self::open_with(location, zip_entries, &StreamPolicy::default())
+    }
+
+    /// Same as `open_entries`, the remote inputs wait for their upstream and reconnect
+    /// following the `policy`
+    pub fn open_with(
+        location: &Path,
+        zip_entries: Option<&str>,
+        policy: &StreamPolicy,
+    ) -> anyhow::Result<Self> {
+        let data = Self::open_data(location, zip_entries, policy)?;
+        Ok(Self {
+            name: location.to_string_lossy().into(),
+            data,
+        })
+    }
+
+    /// Checks if the input is read from an object store or over HTTP
+    pub fn is_remote(location: &Path) -> bool {
+        let name = location.to_string_lossy();
+        OBJECT_STORE_SCHEMES
+            .iter()
+            .chain(HTTP_SCHEMES.iter())
+            .any(|scheme| name.starts_with(scheme))
+    }
+
+    fn open_data(
+        location: &Path,
+        zip_entries: Option<&str>,
+        policy: &StreamPolicy,
+    ) -> anyhow::Result<InputData> {
+        let name = location.to_string_lossy();
+        let compressed = name.ends_with(".gz");
+        let archive = name.ends_with(".zip");
+
+        let stream: Box<dyn Read + Send> = if OBJECT_STORE_SCHEMES
+            .iter()
+            .any(|scheme| name.starts_with(scheme))
+        {
+            open_object(&name, policy)?
+        } else if HTTP_SCHEMES.iter().any(|scheme| name.starts_with(scheme)) {
+            open_http(&name, policy)?
+        } else if archive {
+            return Ok(InputData::Stream(open_zip(
+                File::open(location)?,
+                zip_entries,
+            )?));
+        } else if compressed {
+            Box::new(File::open(location)?)
+        } else {
+            return Ok(InputData::File(location.to_path_buf()));
+        };
+
+        Ok(InputData::Stream(if compressed {
+            Box::new(MultiGzDecoder::new(stream))
+        } else if archive {
+            // the central directory is at the end, so remote archives are buffered first
+            let mut data = Vec::new();
+            let mut stream = stream;
+            stream.read_to_end(&mut data)?;
+            open_zip(std::io::Cursor::new(data), zip_entries)?
+        } else {
+            stream
+        }))
+    }
+
+    /// Where the input was opened from
+    pub fn name(&self) -> &Arc<str> {
+        &self.name
+    }
+
+    /// The raw bytes of the input, for the pipelines parsing them without a reader
+    pub fn into_reader(self) -> anyhow::Result<Box<dyn Read + Send>> {
+        match self.data {
+            InputData::File(path) => {
+                Ok(Box::new(File::open(&path).with_context(|| {
+                    format!("Failed to open {}", path.display())
+                })?))
+            }
+            InputData::Stream(stream) => Ok(stream),
+        }
+    }
+
+    /// Reads the transactions with the `reader` of the input format
+    pub fn read_with(
+        self,
+        reader: impl TransactionCSVReader,
+    ) -> anyhow::Result<TransactionsStream> {
+        match self.data {
+            InputData::File(path) => reader.read_csv(path),
+            InputData::Stream(stream) => reader.read_from(stream),
+        }
+    }
+}
+
+/// Reads the chunks of data produced by another thread
+#[cfg_attr(
+    not(any(
+        feature = "zip",
+        feature = "object-store",
+        all(feature = "io-uring", target_os = "linux")
+    )),
+    allow(dead_code)
+)]
+pub struct ChannelReader {
+    chunks: Receiver<std::io::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl ChannelReader {
+    #[cfg_attr(
+        not(any(
+            feature = "zip",
+            feature = "object-store",
+            all(feature = "io-uring", target_os = "linux")
+        )),
+        allow(dead_code)
+    )]
+    pub fn new(chunks: Receiver<std::io::Result<Vec<u8>>>) -> Self {
+        Self {
+            chunks,
+            current: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.current.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.current = chunk?;
+                    self.position = 0;
+                }
+                // the producer is done
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.current.len() - self.position);
+        buf[..len].copy_from_slice(&self.current[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "zip")]
+fn open_zip<R: std::io::Read + std::io::Seek + Send + 'static>(
+    archive: R,
+    entries: Option<&str>,
+) -> anyhow::Result<Box<dyn Read + Send>> {
+    crate::zip_input::open(archive, entries)
+}
+
+#[cfg(not(feature = "zip"))]
+fn open_zip<R>(_archive: R, _entries: Option<&str>) -> anyhow::Result<Box<dyn Read + Send>> {
+    Err(crate::errors::missing_feature("zip", "zip"))
+}
+
+#[cfg(feature = "object-store")]
+fn open_object(url: &str, policy: &StreamPolicy) -> anyhow::Result<Box<dyn Read + Send>> {
+    crate::object_store_input::open(url, policy)
+}
+
+#[cfg(not(feature = "object-store"))]
+fn open_object(_url: &str, _policy: &StreamPolicy) -> anyhow::Result<Box<dyn Read + Send>> {
+    Err(crate::errors::missing_feature(
+        "object store",
+        "object-store",
+    ))
+}
+
+#[cfg(feature = "http")]
+fn open_http(url: &str, policy: &StreamPolicy) -> anyhow::Result<Box<dyn Read + Send>> {
+    crate::http_input::open(url, policy)
+}
+
+#[cfg(not(feature = "http"))]
+fn open_http(_url: &str, _policy: &StreamPolicy) -> anyhow::Result<Box<dyn Read + Send>> {
+    Err(crate::errors::missing_feature("HTTP", "http"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use crate::{records::TransactionRecord, transactions_reader::MTReader};
+
+    use super::*;
+
+    #[test]
+    fn test_gzip_input() {
+        let path = std::env::temp_dir().join("paytoy_test_input.csv.gz");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder
+            .write_all(&std::fs::read("tests/data/test_basic.csv").unwrap())
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let input = Input::open(&path).unwrap();
+        assert!(matches!(input.data, InputData::Stream(_)));
+        let records: Vec<TransactionRecord> = input
+            .read_with(MTReader::new().with_threads(2))
+            .unwrap()
+            .collect();
+        assert_eq!(records.len(), 5);
+    }
+
+    #[test]
+    fn test_plain_file_input() {
+        let input = Input::open(Path::new("tests/data/test_basic.csv")).unwrap();
+        assert!(matches!(input.data, InputData::File(_)));
+        assert_eq!(&*input.name, "tests/data/test_basic.csv");
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = StreamPolicy {
+            retry_delay: Duration::from_millis(500),
+            max_retry_delay: Duration::from_secs(3),
+            ..StreamPolicy::default()
+        };
+        let delays: Vec<_> = (1..=5).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 3000, 3000].map(Duration::from_millis)
+        );
+        // no overflow after many attempts
+        assert_eq!(policy.backoff(100), Duration::from_secs(3));
+    }
+}
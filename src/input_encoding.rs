@@ -0,0 +1,160 @@
+/// Decodes the CSV inputs before they're parsed, for the files exported by spreadsheets
+/// The UTF-8 byte order mark is dropped, the line endings of a lone `\r` (the CSV exports
+/// of Excel on old Macs) are read as `\n` so the multithreaded reader can split the lines,
+/// and the Latin-1 inputs are transcoded to UTF-8 when asked. The inputs that need none of
+/// it, the most common ones, are only read through a small buffer of their first bytes
+use std::io::{Cursor, Read};
+
+/// The UTF-8 encoding of U+FEFF
+const BOM: &[u8] = b"\xef\xbb\xbf";
+/// The bytes looked at for the line endings, a header line is much shorter
+const HEAD_SIZE: usize = 64 * 1024;
+
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum InputEncoding {
+    Utf8,
+    /// ISO-8859-1, every byte is the character of the same code point
+    Latin1,
+}
+
+/// The input, decoded to UTF-8 with `\n` line endings (or `\r\n` ones)
+pub fn decode<R: Read + Send + 'static>(
+    mut reader: R,
+    encoding: InputEncoding,
+) -> std::io::Result<Box<dyn Read + Send>> {
+    let mut head = Vec::new();
+    (&mut reader)
+        .take(HEAD_SIZE as u64)
+        .read_to_end(&mut head)?;
+    if head.starts_with(BOM) {
+        head.drain(..BOM.len());
+    }
+    let lone_cr = has_lone_cr(&head);
+    let reader = Cursor::new(head).chain(reader);
+    Ok(match (encoding, lone_cr) {
+        (InputEncoding::Utf8, false) => Box::new(reader),
+        (InputEncoding::Utf8, true) => Box::new(CrLines(reader)),
+        (InputEncoding::Latin1, false) => Box::new(Latin1::new(reader)),
+        (InputEncoding::Latin1, true) => Box::new(CrLines(Latin1::new(reader))),
+    })
+}
+
+/// Checks if the first line ends with a `\r` alone
+fn has_lone_cr(head: &[u8]) -> bool {
+    match head
+        .iter()
+        .position(|byte| *byte == b'\r' || *byte == b'\n')
+    {
+        Some(end) => head[end] == b'\r' && head.get(end + 1) != Some(&b'\n'),
+        None => false,
+    }
+}
+
+/// Reads the `\r` line endings as `\n`
+struct CrLines<R>(R);
+
+impl<R: Read> Read for CrLines<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.0.read(buf)?;
+        for byte in &mut buf[..len] {
+            if *byte == b'\r' {
+                *byte = b'\n';
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// Transcodes Latin-1 to UTF-8, the bytes above 127 take two bytes
+struct Latin1<R> {
+    inner: R,
+    latin1: Vec<u8>,
+    /// The second byte of a character that didn't fit in the last read
+    pending: Option<u8>,
+}
+
+impl<R> Latin1<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            latin1: Vec::new(),
+            pending: None,
+        }
+    }
+}
+
+impl<R: Read> Read for Latin1<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(byte) = self.pending.take() {
+            buf[0] = byte;
+            return Ok(1);
+        }
+        // every byte fits, but the last one of a single byte buffer
+        self.latin1.resize((buf.len() / 2).max(1), 0);
+        let len = self.inner.read(&mut self.latin1)?;
+        let mut written = 0;
+        for &byte in &self.latin1[..len] {
+            if byte < 0x80 {
+                buf[written] = byte;
+                written += 1;
+                continue;
+            }
+            let (first, second) = (0xc0 | byte >> 6, 0x80 | byte & 0x3f);
+            buf[written] = first;
+            match buf.get_mut(written + 1) {
+                Some(next) => *next = second,
+                None => self.pending = Some(second),
+            }
+            written = buf.len().min(written + 2);
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(input: &[u8], encoding: InputEncoding) -> Vec<u8> {
+        let mut output = Vec::new();
+        decode(Cursor::new(input.to_vec()), encoding)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_bom_and_line_endings() {
+        let utf8 = InputEncoding::Utf8;
+        assert_eq!(decoded(b"\xef\xbb\xbftype\nx\n", utf8), b"type\nx\n");
+        assert_eq!(decoded(b"type\r\nx\r\n", utf8), b"type\r\nx\r\n");
+        assert_eq!(decoded(b"\xef\xbb\xbftype\rx\ry", utf8), b"type\nx\ny");
+        // only the first line decides, a `\r` in a field of a unix file is kept
+        assert_eq!(decoded(b"type\nx\ry\n", utf8), b"type\nx\ry\n");
+        assert_eq!(decoded(b"", utf8), b"");
+        assert_eq!(decoded(b"\xef\xbb", utf8), b"\xef\xbb");
+    }
+
+    #[test]
+    fn test_latin1() {
+        let latin1 = InputEncoding::Latin1;
+        assert_eq!(
+            decoded(b"name\rRen\xe9 \xff\r", latin1),
+            "name\nRené ÿ\n".as_bytes()
+        );
+        // a long input read through a one byte buffer
+        let input: Vec<u8> = (0..=255u8).cycle().take(HEAD_SIZE * 2).collect();
+        let expected: String = input.iter().map(|byte| *byte as char).collect();
+        let mut reader = decode(Cursor::new(input), latin1).unwrap();
+        let mut output = Vec::new();
+        let mut byte = [0];
+        while reader.read(&mut byte).unwrap() == 1 {
+            output.push(byte[0]);
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
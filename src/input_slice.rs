@@ -0,0 +1,191 @@
+/// A slice of the records of a CSV input, to reprocess a part of a huge input when debugging
+/// The header line is always kept, then the input is read from a byte offset (moved to the
+/// start of the next line), the first records after it are skipped and the reading stops
+/// after a number of records. The records are counted in lines, the way the multithreaded
+/// reader splits its blocks, so both readers read the same slice, parse errors included
+use std::io::{BufRead, BufReader, Cursor, Read};
+
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct InputSlice {
+    /// The offset in the input where the slice starts, at the start of the next line
+    pub start_offset: u64,
+    /// The records skipped after the offset
+    pub skip_records: u64,
+    /// The records read, all the remaining ones if None
+    pub limit: Option<u64>,
+}
+
+impl InputSlice {
+    /// Checks if the whole input is read
+    pub fn is_whole(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The header line then the records of the slice, and the number of lines skipped after
+    /// the header to keep the line numbers of the records. The records before the slice are
+    /// read through, there's no seeking in the streams
+    pub fn apply(
+        &self,
+        reader: Box<dyn Read + Send>,
+    ) -> std::io::Result<(Box<dyn Read + Send>, u64)> {
+        if self.is_whole() {
+            return Ok((reader, 0));
+        }
+        let mut reader = BufReader::new(reader);
+        let mut header = Vec::new();
+        reader.read_until(b'\n', &mut header)?;
+        let offset = self.start_offset.saturating_sub(header.len() as u64);
+        let mut skipped = skip_bytes(&mut reader, offset)?;
+        skipped += skip_lines(&mut reader, self.skip_records)?;
+        let records: Box<dyn Read + Send> = match self.limit {
+            Some(limit) => Box::new(LineLimit {
+                inner: reader,
+                lines: limit,
+            }),
+            None => Box::new(reader),
+        };
+        Ok((Box::new(Cursor::new(header).chain(records)), skipped))
+    }
+}
+
+/// Skips `bytes`, then the rest of the line if it stops in the middle of one
+/// Returns the lines skipped, a line cut by the offset counts
+fn skip_bytes(reader: &mut impl BufRead, mut bytes: u64) -> std::io::Result<u64> {
+    let mut lines = 0;
+    let mut line_start = true;
+    while bytes > 0 {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(lines);
+        }
+        let len = available.len().min(bytes as usize);
+        lines += available[..len]
+            .iter()
+            .filter(|&&byte| byte == b'\n')
+            .count() as u64;
+        line_start = available[len - 1] == b'\n';
+        reader.consume(len);
+        bytes -= len as u64;
+    }
+    if !line_start {
+        lines += skip_lines(reader, 1)?;
+    }
+    Ok(lines)
+}
+
+/// Returns the lines skipped, less than `lines` at the end of the input
+fn skip_lines(reader: &mut impl BufRead, lines: u64) -> std::io::Result<u64> {
+    let mut skipped = 0;
+    while skipped < lines {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            break;
+        }
+        let mut len = available.len();
+        for (index, _) in available
+            .iter()
+            .enumerate()
+            .filter(|(_, &byte)| byte == b'\n')
+        {
+            skipped += 1;
+            if skipped == lines {
+                len = index + 1;
+                break;
+            }
+        }
+        reader.consume(len);
+    }
+    Ok(skipped)
+}
+
+/// Stops reading after a number of lines
+struct LineLimit<R> {
+    inner: R,
+    lines: u64,
+}
+
+impl<R: BufRead> Read for LineLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.lines == 0 {
+            return Ok(0);
+        }
+        let available = self.inner.fill_buf()?;
+        let mut len = available.len().min(buf.len());
+        for (index, byte) in available[..len].iter().enumerate() {
+            if *byte == b'\n' {
+                self.lines -= 1;
+                if self.lines == 0 {
+                    len = index + 1;
+                    break;
+                }
+            }
+        }
+        buf[..len].copy_from_slice(&available[..len]);
+        self.inner.consume(len);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "type,client,tx,amount\n\
+                         deposit,1,1,1.0\n\
+                         deposit,1,2,2.0\n\
+                         deposit,1,3,3.0\n\
+                         deposit,1,4,4.0";
+
+    fn sliced(slice: InputSlice) -> (String, u64) {
+        let (mut reader, skipped) = slice
+            .apply(Box::new(Cursor::new(INPUT.as_bytes().to_vec())))
+            .unwrap();
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        (output, skipped)
+    }
+
+    #[test]
+    fn test_slices() {
+        let header = "type,client,tx,amount\n";
+        assert_eq!(sliced(InputSlice::default()), (INPUT.to_string(), 0));
+        let skip = InputSlice {
+            skip_records: 1,
+            limit: Some(2),
+            ..InputSlice::default()
+        };
+        let expected = format!("{}deposit,1,2,2.0\ndeposit,1,3,3.0\n", header);
+        assert_eq!(sliced(skip), (expected, 1));
+        // in the middle of the first record, the slice starts at the second one
+        let offset = InputSlice {
+            start_offset: header.len() as u64 + 3,
+            ..InputSlice::default()
+        };
+        let expected = format!(
+            "{}deposit,1,2,2.0\ndeposit,1,3,3.0\ndeposit,1,4,4.0",
+            header
+        );
+        assert_eq!(sliced(offset), (expected.clone(), 1));
+        // at the start of a record
+        let offset = InputSlice {
+            start_offset: header.len() as u64 + 16,
+            ..InputSlice::default()
+        };
+        assert_eq!(sliced(offset), (expected, 1));
+        let beyond = InputSlice {
+            start_offset: 1000,
+            skip_records: 10,
+            limit: Some(1),
+        };
+        assert_eq!(sliced(beyond), (header.to_string(), 3));
+        let within_header = InputSlice {
+            start_offset: 5,
+            limit: Some(1),
+            ..InputSlice::default()
+        };
+        assert_eq!(
+            sliced(within_header),
+            (format!("{}deposit,1,1,1.0\n", header), 0)
+        );
+    }
+}
@@ -0,0 +1,493 @@
+/// The pre-flight checks of `paytoy inspect`
+/// The header and a sample of the rows of an input are read to find its delimiter, its
+/// columns and the type of their values, which are compared with the schema of the
+/// transactions with the same rules as the CSV readers. The rows of the whole input are
+/// estimated from the bytes of the sampled ones, so a long run can be checked in a moment
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use csv::{ByteRecord, ReaderBuilder, Trim};
+
+use crate::{
+    input::Input,
+    input_encoding::{self, InputEncoding},
+    record_parser::{self, RecordParser},
+    records::{ClientId, MerchantId, TenantId, TransactionId},
+};
+
+/// The delimiters looked for in the header line, the readers only read commas
+const DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+/// The columns of the transactions, if they're required, and the type of their values
+const SCHEMA: [(&str, bool, Expected); 7] = [
+    ("type", true, Expected::TransactionType),
+    ("client", true, Expected::Id),
+    ("tx", true, Expected::TxId),
+    ("amount", false, Expected::Amount),
+    ("tenant", false, Expected::TenantId),
+    ("merchant", false, Expected::Id),
+    ("timestamp", false, Expected::Timestamp),
+];
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Expected {
+    TransactionType,
+    /// A client or merchant id
+    Id,
+    TenantId,
+    TxId,
+    Amount,
+    Timestamp,
+}
+
+impl Expected {
+    fn accepts(&self, field: &[u8]) -> bool {
+        match self {
+            Expected::TransactionType => record_parser::parse_type(field).is_some(),
+            // the merchants have the same ids as the clients
+            Expected::Id => {
+                record_parser::parse_number::<ClientId>(field).is_some()
+                    && record_parser::parse_number::<MerchantId>(field).is_some()
+            }
+            Expected::TenantId => record_parser::parse_number::<TenantId>(field).is_some(),
+            Expected::TxId => record_parser::parse_number::<TransactionId>(field).is_some(),
+            Expected::Amount => record_parser::parse_amount(field).is_some(),
+            Expected::Timestamp => record_parser::parse_number::<u64>(field).is_some(),
+        }
+    }
+
+    /// The ranges of the ids depend on their widths in the build
+    fn name(&self) -> String {
+        match self {
+            Expected::TransactionType => "a transaction type".to_string(),
+            Expected::Id => format!("an id (0 to {})", ClientId::MAX),
+            Expected::TenantId => format!("a tenant id (0 to {})", TenantId::MAX),
+            Expected::TxId => format!("a transaction id (0 to {})", TransactionId::MAX),
+            Expected::Amount => "an amount".to_string(),
+            Expected::Timestamp => "a Unix timestamp".to_string(),
+        }
+    }
+}
+
+/// The type of the values of a column, the narrowest one all the sampled values fit in
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ValueKind {
+    /// Only empty values were sampled
+    Empty,
+    TransactionType,
+    Integer,
+    Decimal,
+    Text,
+}
+
+impl ValueKind {
+    fn of(field: &[u8]) -> Self {
+        if field.is_empty() {
+            ValueKind::Empty
+        } else if record_parser::parse_type(field).is_some() {
+            ValueKind::TransactionType
+        } else if record_parser::parse_number::<i128>(field).is_some() {
+            ValueKind::Integer
+        } else if record_parser::parse_amount(field).is_some() {
+            ValueKind::Decimal
+        } else {
+            ValueKind::Text
+        }
+    }
+
+    fn widen(self, other: Self) -> Self {
+        match (self, other) {
+            (kind, ValueKind::Empty) | (ValueKind::Empty, kind) => kind,
+            (kind, other) if kind == other => kind,
+            (ValueKind::Integer, ValueKind::Decimal) | (ValueKind::Decimal, ValueKind::Integer) => {
+                ValueKind::Decimal
+            }
+            _ => ValueKind::Text,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ValueKind::Empty => "empty",
+            ValueKind::TransactionType => "transaction type",
+            ValueKind::Integer => "integer",
+            ValueKind::Decimal => "decimal",
+            ValueKind::Text => "text",
+        }
+    }
+}
+
+/// What the readers do with a column
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ColumnRole {
+    Required,
+    Optional,
+    /// Not a column of the transactions, or a duplicate of an earlier one
+    Ignored,
+}
+
+/// A column of the input and the values sampled in it
+#[derive(Debug)]
+pub struct Column {
+    pub name: String,
+    pub role: ColumnRole,
+    pub kind: ValueKind,
+    /// The sampled rows where the value is empty or missing
+    pub empty: usize,
+    expected: Option<Expected>,
+    /// The sampled values the readers would reject, and the first of them
+    invalid: usize,
+    invalid_example: Option<String>,
+}
+
+/// The number of rows of the input
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RowCount {
+    /// The whole input was sampled
+    Exact(usize),
+    /// Estimated from the size of the input and the sampled rows
+    Estimated(u64),
+    /// A compressed or streamed input, only the sampled rows are known
+    AtLeast(usize),
+}
+
+/// What was found in an input, see `inspect`
+#[derive(Debug)]
+pub struct Inspection {
+    pub delimiter: u8,
+    pub columns: Vec<Column>,
+    pub sampled_rows: usize,
+    /// The sampled rows the readers would skip as parse errors
+    pub rejected_rows: usize,
+    /// The sampled rows that aren't valid UTF-8
+    pub non_utf8_rows: usize,
+    pub rows: RowCount,
+}
+
+/// Inspects the first `sample` rows of the input at `location`, a local file or any input
+/// `paytoy` reads
+pub fn inspect_input(location: &Path, sample: usize) -> anyhow::Result<Inspection> {
+    let name = location.to_string_lossy();
+    let size = if Input::is_remote(location) || name.ends_with(".gz") || name.ends_with(".zip") {
+        None
+    } else {
+        Some(
+            std::fs::metadata(location)
+                .with_context(|| format!("Failed to open {}", location.display()))?
+                .len(),
+        )
+    };
+    inspect(Input::open(location)?.into_reader()?, size, sample)
+}
+
+/// Inspects the first `sample` rows of the input, `size` is its length in bytes if known
+pub fn inspect<R: Read + Send + 'static>(
+    reader: R,
+    size: Option<u64>,
+    sample: usize,
+) -> anyhow::Result<Inspection> {
+    let mut reader = input_encoding::decode(reader, InputEncoding::Utf8)?;
+    let mut header_line = Vec::new();
+    let mut byte = [0];
+    while reader.read(&mut byte)? == 1 {
+        header_line.push(byte[0]);
+        if byte[0] == b'\n' {
+            break;
+        }
+    }
+    // the first of the most frequent, a comma if there are none
+    let count = |delimiter: &u8| header_line.iter().filter(|byte| *byte == delimiter).count();
+    let delimiter = *DELIMITERS
+        .iter()
+        .rev()
+        .max_by_key(|delimiter| count(delimiter))
+        .unwrap();
+
+    let mut csv_reader = ReaderBuilder::new()
+        .delimiter(delimiter)
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(std::io::Cursor::new(header_line).chain(reader));
+    let headers = csv_reader.byte_headers()?.clone();
+    let mut columns = columns(&headers);
+    let parser = RecordParser::new(&headers);
+    let header_end = csv_reader.position().byte();
+
+    let mut record = ByteRecord::new();
+    let (mut sampled_rows, mut rejected_rows, mut non_utf8_rows) = (0, 0, 0);
+    let mut complete = true;
+    while csv_reader.read_byte_record(&mut record)? {
+        if sampled_rows == sample {
+            complete = false;
+            break;
+        }
+        sampled_rows += 1;
+        if parser.parse(&record).is_none() {
+            rejected_rows += 1;
+        }
+        if std::str::from_utf8(record.as_slice()).is_err() {
+            non_utf8_rows += 1;
+        }
+        for (index, column) in columns.iter_mut().enumerate() {
+            column.sample(record.get(index).unwrap_or_default());
+        }
+    }
+
+    let rows = match size {
+        _ if complete => RowCount::Exact(sampled_rows),
+        Some(size) => {
+            // the position is at the start of the first row after the sample
+            let sampled_bytes =
+                record.position().map_or(0, |position| position.byte()) - header_end;
+            let row_size = sampled_bytes as f64 / sampled_rows.max(1) as f64;
+            RowCount::Estimated(((size - header_end) as f64 / row_size.max(1.0)).round() as u64)
+        }
+        None => RowCount::AtLeast(sampled_rows),
+    };
+    Ok(Inspection {
+        delimiter,
+        columns,
+        sampled_rows,
+        rejected_rows,
+        non_utf8_rows,
+        rows,
+    })
+}
+
+/// The columns of the headers, the first of the duplicates is the one read
+fn columns(headers: &ByteRecord) -> Vec<Column> {
+    let mut columns: Vec<Column> = Vec::with_capacity(headers.len());
+    for header in headers {
+        let name = String::from_utf8_lossy(header).to_string();
+        let duplicate = columns.iter().any(|column| column.name == name);
+        let schema = SCHEMA.iter().find(|(column, ..)| *column == name);
+        let (role, expected) = match schema {
+            Some((_, required, expected)) if !duplicate => (
+                if *required {
+                    ColumnRole::Required
+                } else {
+                    ColumnRole::Optional
+                },
+                Some(*expected),
+            ),
+            _ => (ColumnRole::Ignored, None),
+        };
+        columns.push(Column {
+            name,
+            role,
+            kind: ValueKind::Empty,
+            empty: 0,
+            expected,
+            invalid: 0,
+            invalid_example: None,
+        });
+    }
+    columns
+}
+
+impl Column {
+    fn sample(&mut self, field: &[u8]) {
+        self.kind = self.kind.widen(ValueKind::of(field));
+        if field.is_empty() {
+            self.empty += 1;
+            return;
+        }
+        match self.expected {
+            Some(expected) if !expected.accepts(field) => {
+                self.invalid += 1;
+                if self.invalid_example.is_none() {
+                    self.invalid_example = Some(String::from_utf8_lossy(field).to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Inspection {
+    /// The differences with the schema of the transactions, empty if the readers can read
+    /// the input as it is
+    pub fn mismatches(&self) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if self.delimiter != b',' {
+            mismatches.push(format!(
+                "the columns are delimited by {:?}, only commas are read",
+                self.delimiter as char
+            ));
+        }
+        for (name, ..) in SCHEMA.iter().filter(|(_, required, _)| *required) {
+            if !self.columns.iter().any(|column| column.name == *name) {
+                mismatches.push(format!("the required column `{}` is missing", name));
+            }
+        }
+        for (index, column) in self.columns.iter().enumerate() {
+            let first = self.columns[..index]
+                .iter()
+                .any(|other| other.name == column.name);
+            if first && SCHEMA.iter().any(|(name, ..)| *name == column.name) {
+                mismatches.push(format!(
+                    "the column `{}` is repeated, only the first one is read",
+                    column.name
+                ));
+            }
+            if let (Some(expected), Some(example)) = (column.expected, &column.invalid_example) {
+                mismatches.push(format!(
+                    "{} of the {} sampled values of `{}` aren't {}, e.g. {:?}",
+                    column.invalid,
+                    self.sampled_rows,
+                    column.name,
+                    expected.name(),
+                    example
+                ));
+            }
+            if column.role == ColumnRole::Required && column.empty > 0 {
+                mismatches.push(format!(
+                    "{} of the {} sampled values of `{}` are empty",
+                    column.empty, self.sampled_rows, column.name
+                ));
+            }
+        }
+        if self.non_utf8_rows > 0 {
+            mismatches.push(format!(
+                "{} of the {} sampled rows aren't UTF-8, try `--encoding latin1`",
+                self.non_utf8_rows, self.sampled_rows
+            ));
+        }
+        mismatches
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "delimiter: {:?}", self.delimiter as char)?;
+        writeln!(writer, "columns:")?;
+        let width = self
+            .columns
+            .iter()
+            .map(|column| column.name.len())
+            .max()
+            .unwrap_or(0);
+        for column in &self.columns {
+            let role = match column.role {
+                ColumnRole::Required => "required",
+                ColumnRole::Optional => "optional",
+                ColumnRole::Ignored => "ignored",
+            };
+            write!(
+                writer,
+                "  {:width$}  {:8}  {}",
+                column.name,
+                role,
+                column.kind.name(),
+                width = width
+            )?;
+            if column.empty > 0 && column.kind != ValueKind::Empty {
+                write!(writer, ", {} empty", column.empty)?;
+            }
+            writeln!(writer)?;
+        }
+        match self.rows {
+            RowCount::Exact(rows) => writeln!(writer, "rows: {}", rows)?,
+            RowCount::Estimated(rows) => writeln!(
+                writer,
+                "rows: about {} (estimated from {} sampled rows)",
+                rows, self.sampled_rows
+            )?,
+            RowCount::AtLeast(rows) => writeln!(writer, "rows: more than {}", rows)?,
+        }
+        writeln!(
+            writer,
+            "sampled rows rejected: {} of {}",
+            self.rejected_rows, self.sampled_rows
+        )?;
+        let mismatches = self.mismatches();
+        if mismatches.is_empty() {
+            writeln!(writer, "schema: ok")?;
+        } else {
+            writeln!(writer, "schema mismatches:")?;
+            for mismatch in mismatches {
+                writeln!(writer, "  {}", mismatch)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn inspected(data: &str, sample: usize) -> Inspection {
+        let size = data.len() as u64;
+        inspect(Cursor::new(data.as_bytes().to_vec()), Some(size), sample).unwrap()
+    }
+
+    #[test]
+    fn test_valid_input() {
+        let data = "type, client, tx, amount, note\n\
+                    deposit, 1, 1, 1.5, first\n\
+                    deposit, 2, 2, 2, \n\
+                    dispute, 1, 1, , \n";
+        let inspection = inspected(data, 10);
+        assert_eq!(inspection.delimiter, b',');
+        let columns: Vec<(&str, ColumnRole, ValueKind, usize)> = inspection
+            .columns
+            .iter()
+            .map(|column| (column.name.as_str(), column.role, column.kind, column.empty))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("type", ColumnRole::Required, ValueKind::TransactionType, 0),
+                ("client", ColumnRole::Required, ValueKind::Integer, 0),
+                ("tx", ColumnRole::Required, ValueKind::Integer, 0),
+                ("amount", ColumnRole::Optional, ValueKind::Decimal, 1),
+                ("note", ColumnRole::Ignored, ValueKind::Text, 2),
+            ]
+        );
+        assert_eq!(inspection.rows, RowCount::Exact(3));
+        assert_eq!(inspection.rejected_rows, 0);
+        assert!(inspection.mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_mismatches() {
+        let data = "type;client;amount;amount\n\
+                    deposit;99999999999999999999;1.5;2\n\
+                    refund;1;x;3\n";
+        let inspection = inspected(data, 10);
+        assert_eq!(inspection.delimiter, b';');
+        assert_eq!(inspection.rejected_rows, 2);
+        assert_eq!(
+            inspection.mismatches(),
+            vec![
+                "the columns are delimited by ';', only commas are read",
+                "the required column `tx` is missing",
+                "1 of the 2 sampled values of `type` aren't a transaction type, e.g. \"refund\"",
+                &format!(
+                    "1 of the 2 sampled values of `client` aren't an id (0 to {}), e.g. \
+                     \"99999999999999999999\"",
+                    ClientId::MAX
+                ),
+                "1 of the 2 sampled values of `amount` aren't an amount, e.g. \"x\"",
+                "the column `amount` is repeated, only the first one is read",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_row_estimate() {
+        let mut data = String::from("type,client,tx,amount\n");
+        for tx in 0..1000 {
+            data.push_str(&format!("deposit,1,{:04},1.0\n", tx));
+        }
+        let inspection = inspected(&data, 100);
+        assert_eq!(inspection.sampled_rows, 100);
+        assert_eq!(inspection.rows, RowCount::Estimated(1000));
+        let streamed = inspect(Cursor::new(data.into_bytes()), None, 100).unwrap();
+        assert_eq!(streamed.rows, RowCount::AtLeast(100));
+    }
+}
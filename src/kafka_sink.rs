@@ -0,0 +1,124 @@
+/// Publishes the events of the accounts to a Kafka topic
+/// The producer queues the events and a background thread sends them, so publishing doesn't
+/// wait for the brokers. The failed deliveries are counted and reported at the end of the run
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use rdkafka::{
+    config::ClientConfig,
+    error::{KafkaError, RDKafkaErrorCode},
+    producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer},
+    ClientContext,
+};
+
+use crate::{
+    errors::UsageError,
+    events::{AccountEvent, EventSink},
+};
+
+/// How long the end of the run waits for the queued events to be delivered
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Wait before retrying an event when the queue of the producer is full
+const QUEUE_FULL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Counts the events that couldn't be delivered
+#[derive(Default)]
+struct DeliveryCounter {
+    failed: AtomicU64,
+}
+
+impl ClientContext for DeliveryCounter {}
+
+impl ProducerContext for DeliveryCounter {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        if result.is_err() {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct KafkaSink {
+    producer: ThreadedProducer<DeliveryCounter>,
+    topic: String,
+}
+
+impl KafkaSink {
+    /// Connects to `broker:9092,other:9092/topic`
+    pub fn connect(target: &str) -> anyhow::Result<Self> {
+        let (brokers, topic) = parse_target(target)?;
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create_with_context(DeliveryCounter::default())?;
+        Ok(Self {
+            producer,
+            topic: topic.to_string(),
+        })
+    }
+}
+
+impl EventSink for KafkaSink {
+    fn publish(&self, event: &AccountEvent) {
+        let key = event.key();
+        let payload = event.to_json().to_string();
+        let mut record = BaseRecord::to(&self.topic).key(&key).payload(&payload);
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => return,
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), retry)) => {
+                    // the background thread is sending, wait for room in the queue
+                    record = retry;
+                    std::thread::sleep(QUEUE_FULL_BACKOFF);
+                }
+                Err(_) => {
+                    let counter = self.producer.context();
+                    counter.failed.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        self.producer.flush(FLUSH_TIMEOUT)?;
+        match self.producer.context().failed.load(Ordering::Relaxed) {
+            0 => Ok(()),
+            failed => Err(anyhow::anyhow!(
+                "{} events couldn't be published to {}",
+                failed,
+                self.topic
+            )),
+        }
+    }
+}
+
+/// Splits `broker:9092,other:9092/topic` into the brokers and the topic
+fn parse_target(target: &str) -> anyhow::Result<(&str, &str)> {
+    match target.split_once('/') {
+        Some((brokers, topic)) if !brokers.is_empty() && !topic.is_empty() => Ok((brokers, topic)),
+        _ => Err(UsageError(format!(
+            "Invalid Kafka target `kafka://{}`, expected `kafka://broker:9092/topic`",
+            target
+        ))
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target("a:9092,b:9092/balances").unwrap(),
+            ("a:9092,b:9092", "balances")
+        );
+        assert!(parse_target("a:9092").is_err());
+        assert!(parse_target("/balances").is_err());
+        assert!(parse_target("a:9092/").is_err());
+    }
+}
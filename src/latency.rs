@@ -0,0 +1,249 @@
+/// The latency of the application of the records, for `--latency`
+/// Each manager times its records into histograms of its own, one per transaction type, and
+/// merges them into the statistics of the run with its other counters. The histograms have
+/// logarithmic buckets, 16 per power of two, so a percentile is off by 6% at most whatever
+/// the range of the latencies, and a histogram keeps a fixed size however many records it
+/// counts
+use std::io::Write;
+
+use crate::records::TransactionType;
+
+/// Sub-buckets per power of two, as a power of two
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+/// The exact buckets of the smallest values, then `SUB_BUCKETS` per power of two
+const BUCKETS: usize = 2 * SUB_BUCKETS + (64 - SUB_BITS as usize - 1) * SUB_BUCKETS;
+
+/// The percentiles of the summary
+const PERCENTILES: [(&str, f64); 4] = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999)];
+
+/// All the transaction types, in the order of the summary
+const TYPES: [TransactionType; 7] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::ChargeBack,
+    TransactionType::Settle,
+    TransactionType::Payment,
+];
+
+/// A histogram of latencies in nanoseconds
+#[derive(Clone, Debug)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, nanos: u64) {
+        self.buckets[bucket(nanos)] += 1;
+        self.count += 1;
+        self.max = self.max.max(nanos);
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The latency `quantile` of the records are below, rounded up to the end of its bucket
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_end(index).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Exact below `2 * SUB_BUCKETS`, then the `SUB_BITS` bits after the highest one set
+fn bucket(nanos: u64) -> usize {
+    if nanos < 2 * SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let shift = 63 - nanos.leading_zeros() - SUB_BITS;
+    shift as usize * SUB_BUCKETS + (nanos >> shift) as usize
+}
+
+/// The highest latency of a bucket
+fn bucket_end(index: usize) -> u64 {
+    if index < 2 * SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let mantissa = (index % SUB_BUCKETS + SUB_BUCKETS) as u128;
+    // the end of the last bucket doesn't fit in 64 bits
+    (((mantissa + 1) << shift) - 1).min(u64::MAX as u128) as u64
+}
+
+/// The histograms of the latencies of each transaction type
+#[derive(Clone, Debug)]
+pub struct ApplyLatencies {
+    histograms: Vec<LatencyHistogram>,
+}
+
+impl Default for ApplyLatencies {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ApplyLatencies {
+    pub fn new() -> Self {
+        Self {
+            histograms: vec![LatencyHistogram::new(); TYPES.len()],
+        }
+    }
+
+    pub fn record(&mut self, tr_type: TransactionType, nanos: u64) {
+        self.histograms[tr_type as usize].record(nanos);
+    }
+
+    pub fn merge(&mut self, other: &ApplyLatencies) {
+        for (histogram, other) in self.histograms.iter_mut().zip(&other.histograms) {
+            histogram.merge(other);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.histograms
+            .iter()
+            .all(|histogram| histogram.count() == 0)
+    }
+
+    /// The histograms of the types that have records
+    pub fn histograms(&self) -> impl Iterator<Item = (TransactionType, &LatencyHistogram)> {
+        TYPES
+            .iter()
+            .zip(&self.histograms)
+            .filter(|(_, histogram)| histogram.count() > 0)
+            .map(|(tr_type, histogram)| (*tr_type, histogram))
+    }
+
+    /// Prints the percentiles of the latencies of each type, in microseconds
+    pub fn write_summary(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        write!(writer, "{:<10} | {:>10}", "type", "records")?;
+        for (name, _) in PERCENTILES.iter().chain([("max", 1.0)].iter()) {
+            write!(writer, " | {:>11}", name)?;
+        }
+        writeln!(writer)?;
+        write!(writer, "{}-+-{}", "-".repeat(10), "-".repeat(10))?;
+        for _ in 0..=PERCENTILES.len() {
+            write!(writer, "-+-{}", "-".repeat(11))?;
+        }
+        writeln!(writer)?;
+        for (tr_type, histogram) in self.histograms() {
+            write!(
+                writer,
+                "{:<10} | {:>10}",
+                tr_type.to_string(),
+                histogram.count()
+            )?;
+            for (_, quantile) in PERCENTILES {
+                write!(writer, " | {:>9.2}µs", micros(histogram.quantile(quantile)))?;
+            }
+            writeln!(writer, " | {:>9.2}µs", micros(histogram.max()))?;
+        }
+        Ok(())
+    }
+}
+
+/// For the summaries and the dashboard
+pub fn micros(nanos: u64) -> f64 {
+    nanos as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        let mut previous = 0;
+        for nanos in (0..100_000).chain([u64::MAX / 3, u64::MAX]) {
+            let index = bucket(nanos);
+            assert!(index < BUCKETS);
+            assert!(index >= previous, "{}", nanos);
+            previous = index;
+            // within the bucket, and at most 1/16th off
+            let end = bucket_end(index);
+            assert!(end >= nanos, "{}", nanos);
+            assert!(end - nanos <= nanos / SUB_BUCKETS as u64, "{}", nanos);
+        }
+        assert_eq!(bucket_end(BUCKETS - 1), u64::MAX);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let mut histogram = LatencyHistogram::new();
+        for nanos in 1..=1000 {
+            histogram.record(nanos);
+        }
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.quantile(0.0), 1);
+        let median = histogram.quantile(0.5);
+        assert!((500..=500 + 500 / 16).contains(&median), "{}", median);
+        let p99 = histogram.quantile(0.99);
+        assert!((990..=1000).contains(&p99), "{}", p99);
+        assert_eq!(histogram.quantile(1.0), 1000);
+
+        let mut slow = LatencyHistogram::new();
+        slow.record(1_000_000);
+        histogram.merge(&slow);
+        assert_eq!(histogram.max(), 1_000_000);
+        assert_eq!(histogram.quantile(1.0), 1_000_000);
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut latencies = ApplyLatencies::new();
+        assert!(latencies.is_empty());
+        for nanos in [400, 600, 2_000] {
+            latencies.record(TransactionType::Deposit, nanos);
+        }
+        let mut merged = ApplyLatencies::default();
+        merged.merge(&latencies);
+        latencies.record(TransactionType::Dispute, 1_500);
+        merged.merge(&latencies);
+        let mut summary = Vec::new();
+        merged.write_summary(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        let lines: Vec<_> = summary.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", summary);
+        assert!(lines[0].starts_with("type       |    records |         p50"));
+        assert!(lines[2].starts_with("deposit    |          6 |"));
+        assert!(lines[2].ends_with("|      2.00µs"));
+        assert!(lines[3].starts_with("dispute    |          1 |"));
+    }
+}
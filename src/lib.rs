@@ -0,0 +1,93 @@
+//! The engine of paytoy: the readers of the transactions, the accounts and their managers,
+//! and the report. The `paytoy` binary is the command line interface on top of it
+
+// the readers and managers are built from `new()` with their `with_*` options
+#![allow(clippy::new_without_default)]
+
+pub mod account_manager;
+pub mod account_store;
+pub mod accounts_meta;
+pub mod affinity;
+pub mod anomalies;
+#[cfg(feature = "avro")]
+pub mod avro_reader;
+pub mod backfill;
+pub mod binary_format;
+pub mod bloom;
+pub mod cancellation;
+pub mod client_account;
+pub mod client_filter;
+pub mod clock;
+pub mod dead_letter;
+pub mod dispatch_schedule;
+pub mod disputes_report;
+pub mod errors;
+pub mod event_replay;
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fused;
+pub mod history_store;
+#[cfg(feature = "http")]
+mod http_input;
+pub mod input;
+pub mod input_encoding;
+pub mod input_slice;
+pub mod inspect;
+#[cfg(feature = "kafka")]
+mod kafka_sink;
+pub mod latency;
+#[cfg(any(test, feature = "testing"))]
+pub mod manager_conformance;
+pub mod memory;
+pub mod merchants;
+#[cfg(feature = "msgpack")]
+pub mod msgpack_reader;
+pub mod multi_input;
+#[cfg(feature = "object-store")]
+mod object_store_input;
+#[cfg(feature = "parquet")]
+pub mod parquet_reader;
+#[cfg(feature = "parquet")]
+mod parquet_report;
+pub mod paytoy;
+pub mod period_report;
+#[cfg(feature = "postgres")]
+mod postgres_sink;
+pub mod processed_inputs;
+pub mod profile;
+pub mod rate_limit;
+#[cfg(feature = "rayon")]
+pub mod rayon_manager;
+#[cfg(any(test, feature = "testing"))]
+pub mod reader_conformance;
+pub mod record_filter;
+pub mod record_parser;
+pub mod records;
+pub mod replay_window;
+pub mod report;
+pub mod report_delta;
+pub mod report_merge;
+pub mod report_reader;
+pub mod report_stream;
+pub mod resume_token;
+pub mod sampling;
+pub mod schedule;
+pub mod sharded_output;
+pub mod simulation;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+mod sqlite_sink;
+pub mod statement;
+pub mod stats;
+pub mod trace;
+pub mod transactions_reader;
+pub mod tx_history;
+pub mod tx_registry;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring_input;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod workload;
+#[cfg(feature = "zip")]
+mod zip_input;
@@ -0,0 +1,135 @@
+/// Sets up the logger, writing on stderr as free text or as JSON lines
+/// A JSON event has the `level`, the `stage` (the module that logged it), the `message`
+/// and the fields attached to the event, such as the `client`, `tx` and `reason` of a rejection
+use std::io::Write;
+
+use log::{
+    kv::{self, Key, Value, VisitSource, VisitValue},
+    Record,
+};
+use serde_json::{Map, Value as JsonValue};
+
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Installs the global logger, the level can be changed with `RUST_LOG`
+/// With `trace`, the events of the traced transaction are logged whatever the level
+pub fn init(format: LogFormat, trace: bool) {
+    // the text logs are too verbose to be on by default, the JSON ones are meant to be collected
+    let default_level = match format {
+        LogFormat::Text => "off",
+        LogFormat::Json => "info",
+    };
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_level));
+    if trace {
+        builder.filter_module(paytoy::trace::TARGET, log::LevelFilter::Info);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_event(record)));
+    }
+    builder.init();
+}
+
+/// Builds the JSON object of a log event
+fn json_event(record: &Record) -> JsonValue {
+    let mut event = Map::new();
+    event.insert(
+        "level".into(),
+        record.level().as_str().to_lowercase().into(),
+    );
+    let target = record.target();
+    let stage = target.strip_prefix("paytoy::").unwrap_or(target);
+    event.insert("stage".into(), stage.into());
+    event.insert("message".into(), record.args().to_string().into());
+    // the fields of the event come last, so they can override the ones above
+    let _ = record.key_values().visit(&mut JsonFields(&mut event));
+    JsonValue::Object(event)
+}
+
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut field = JsonField(JsonValue::Null);
+        value.visit(&mut field)?;
+        self.0.insert(key.to_string(), field.0);
+        Ok(())
+    }
+}
+
+/// Keeps the numbers and booleans as such, anything else is written as a string
+struct JsonField(JsonValue);
+
+impl<'v> VisitValue<'v> for JsonField {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_json_event() {
+        let fields = [
+            ("client", Value::from(3u16)),
+            ("tx", Value::from(42u32)),
+            ("reason", Value::from("insufficient_funds")),
+        ];
+        let event = json_event(
+            &Record::builder()
+                .level(Level::Error)
+                .target("paytoy::account_manager")
+                .args(format_args!("Transaction failed. {}", "Insufficient funds"))
+                .key_values(&fields)
+                .build(),
+        );
+
+        assert_eq!(
+            event,
+            json!({
+                "level": "error",
+                "stage": "account_manager",
+                "message": "Transaction failed. Insufficient funds",
+                "client": 3,
+                "tx": 42,
+                "reason": "insufficient_funds",
+            })
+        );
+    }
+}
@@ -1,76 +1,1542 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::{CommandFactory, Parser};
 use log::*;
-use std::{self, env};
 
-use crate::{
-    account_manager::{MTAccountManager, STAccountManager},
-    bench::create_large_test_file,
+#[cfg(feature = "avro")]
+use paytoy::avro_reader;
+#[cfg(feature = "msgpack")]
+use paytoy::msgpack_reader;
+#[cfg(feature = "parquet")]
+use paytoy::parquet_reader;
+#[cfg(feature = "rayon")]
+use paytoy::rayon_manager::RayonAccountManager;
+use paytoy::{
+    account_manager::{
+        AccountManager, DisabledTypes, LockedPolicy, MTAccountManager, STAccountManager,
+    },
+    affinity, anomalies, backfill, binary_format,
+    bloom::BloomFilter,
+    cancellation::CancellationToken,
+    client_filter::{self, ClientFilter},
+    clock::{self, Clock},
+    dead_letter::DeadLetters,
+    dispatch_schedule::DispatchSchedule,
+    disputes_report,
+    errors::{ExitCode, UsageError},
+    event_replay::{self, EventReplay},
+    events::{self, EventSink},
+    fused::FusedPipeline,
+    history_store::FileHistoryStore,
+    input::Input,
+    input_slice::InputSlice,
+    inspect,
+    memory::{self, MemoryBudget},
+    merchants::MerchantLedger,
+    multi_input::{read_inputs, ReadMode},
     paytoy::PayToyApp,
-    transactions_reader::MTReader,
+    period_report::PeriodReport,
+    processed_inputs::{self, InputPlan},
+    profile::Profile,
+    rate_limit,
+    replay_window::ReplayWindow,
+    report::{self, Columns, Report, ReportFormat, ReportOptions},
+    report_delta, report_merge, report_reader,
+    report_stream::ReportStream,
+    resume_token::{ResumeProgress, ResumeToken},
+    sampling::Sampler,
+    schedule::{self, Schedule},
+    sharded_output::ShardedOutput,
+    simulation::Simulation,
+    snapshot::{self, Snapshot, SnapshotAccount},
+    statement,
+    stats::PipelineStats,
+    trace::TxTrace,
+    transactions_reader::{self, MTReader, STBulkReader, TransactionCSVReader, TransactionsStream},
+    tx_registry::TxRegistry,
+    workload::{self, Corrupter, WorkloadProfile},
+};
+
+use crate::{
+    bench::{BenchManager, BenchMatrix, BenchReader},
+    cli::{Cli, Command, Dedup, ExecutionMode, InputFormat},
+    repl::Repl,
+    watchdog::Watchdog,
 };
 
-mod account_manager;
 mod bench;
-mod client_account;
-mod paytoy;
-mod records;
-mod transactions_reader;
+mod cli;
+mod dashboard;
+mod logging;
+mod repl;
+mod watchdog;
 
-static LARGE_TEST_FILE_NAME: &'static str = "tests/data/test_large.csv";
-static NUM_RECORDS: usize = 10000000;
+// the allocator makes a big difference on large inputs, mimalloc wins if both are enabled
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-// A simple test function to run qualitative benchmarks
-#[allow(dead_code)]
-fn run_benchmarks(use_all_accounts: bool) {
-    create_large_test_file(LARGE_TEST_FILE_NAME, NUM_RECORDS, use_all_accounts);
+#[cfg(all(feature = "jemalloc", not(feature = "mimalloc")))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-    bench::read_raw_file(LARGE_TEST_FILE_NAME);
-    bench::st_bulk_transaction_reader(LARGE_TEST_FILE_NAME);
-    bench::mt_transaction_reader(LARGE_TEST_FILE_NAME);
+/// Size of the shortest CSV line of a transaction, to estimate the number of transactions
+const MIN_RECORD_SIZE: u64 = 16;
+/// Number of transactions the bloom filter is sized for when the inputs are remote
+const DEFAULT_BLOOM_CAPACITY: u64 = 100_000_000;
+/// The cores assumed by `--deterministic` on any machine, for 4 workers
+const DETERMINISTIC_CORES: usize = 8;
 
-    bench::st_bulk_application(LARGE_TEST_FILE_NAME, NUM_RECORDS);
-    bench::mt_application(LARGE_TEST_FILE_NAME, NUM_RECORDS);
+/// The state shared by the stages of a run
+struct RunContext {
+    stats: Arc<PipelineStats>,
+    profile: Option<Arc<Profile>>,
+    memory: Option<Arc<MemoryBudget>>,
+    events: Option<Arc<dyn EventSink>>,
+    report_options: ReportOptions,
+    sharded_output: Option<Arc<ShardedOutput>>,
+    report_stream: Option<Arc<ReportStream>>,
+    restored: Option<Snapshot>,
+    /// The digests of the inputs applied to the accounts once the run is over
+    processed_inputs: Vec<String>,
+    clock: Arc<dyn Clock>,
+    period_report: Option<Arc<PeriodReport>>,
+    merchants: Option<Arc<MerchantLedger>>,
+    client_filter: Option<Arc<ClientFilter>>,
+    dispatch_schedule: Option<Arc<DispatchSchedule>>,
+    /// The last record applied, for `--resume-token`
+    resume_progress: Option<Arc<ResumeProgress>>,
+    /// The token of `--resume-from`
+    resumed_from: Option<ResumeToken>,
+    sampler: Option<Arc<Sampler>>,
+    /// Tallies the changes of `--simulate-only`, in front of the events file
+    simulation: Option<Arc<Simulation>>,
+    /// Cancelled by Ctrl-C or SIGTERM, the readers and the managers stop and the report is
+    /// partial
+    cancellation: CancellationToken,
 }
 
-fn main() {
-    // TODO: disable logging in the test environment
-    // env_logger::builder()
-    //     .target(env_logger::Target::Stdout)
-    //     .filter_level(LevelFilter::Info)
-    //     .init();
-
-    let args: Vec<String> = env::args().collect();
-
-    // Make sure there is one and only one argument to the program
-    // TODO: maybe add some arguments with help or something
-    if args.len() != 2 {
-        error!("A file name argument must be provided as a single input argument");
-        std::process::exit(0);
-    }
-
-    let input_file = &args[1];
-    info!("Starting application on the file: {}", input_file);
-
-    // For the final application, use both multithreader CSV reader
-    // and multithreaded account manager for processing multiple clients in parallel
-    let num_cores = num_cpus::get();
-    if num_cores >= 4 {
-        let reader = MTReader::new().with_threads(num_cores / 2);
-        let manager = MTAccountManager::new(num_cores / 2);
-
-        if let Err(err) = PayToyApp::run(input_file, reader, manager, true) {
-            error!("Failed to run the application: {:?}", err);
-            std::process::exit(0);
+/// Picks the reader for the input format and runs the application
+fn run(cli: &Cli, inputs: &[PathBuf]) -> anyhow::Result<ExitCode> {
+    if cli.dead_letter.is_some() && cli.format != InputFormat::Csv {
+        return Err(UsageError("The dead letters are only kept for CSV inputs".to_string()).into());
+    }
+    if !cli.input_slice().is_whole() {
+        check_slice(cli, inputs)?;
+    }
+    if !cli.record_filter().keeps_all() && cli.format != InputFormat::Csv {
+        return Err(UsageError(
+            "--only-types and --client-range filter the records of the CSV readers".to_string(),
+        )
+        .into());
+    }
+    if cli.simulate_only {
+        check_simulation(cli)?;
+    }
+    let restored = match (&cli.snapshot, &cli.restore) {
+        (Some(path), _) if cli.resume => snapshot::load_latest(path)?,
+        (_, Some(path)) => Some(snapshot::load_snapshot(path)?),
+        _ => None,
+    };
+    let plan = input_plan(cli, inputs, restored.as_ref())?;
+    if plan.inputs.is_empty() {
+        eprintln!("All the inputs were already processed, nothing to do");
+        return Ok(ExitCode::Success);
+    }
+    let inputs = &plan.inputs[..];
+    // read before the run, so a bad metadata file doesn't waste it
+    let report_options = cli.report_options()?;
+    let sharded_output = match (cli.output_shards, &cli.output) {
+        (Some(_), Some(output)) if report::is_database(output) => {
+            return Err(
+                UsageError("--output-shards writes files, not to a database".to_string()).into(),
+            );
+        }
+        (Some(shards), Some(output)) => Some(Arc::new(ShardedOutput::create(
+            output,
+            shards as usize,
+            Columns {
+                tenants: true,
+                pending_out: cli.settle_withdrawals,
+                over_limit: cli.max_client_transactions.is_some(),
+            },
+            report_options.meta.clone(),
+        )?)),
+        _ => None,
+    };
+    let report_stream = if cli.stream_report {
+        let writer: Box<dyn Write + Send> = match &cli.output {
+            Some(output) if report::is_database(output) => {
+                return Err(UsageError(
+                    "--stream-report writes files, not to a database".to_string(),
+                )
+                .into());
+            }
+            Some(output) => {
+                Box::new(BufWriter::new(File::create(output).with_context(|| {
+                    format!("Failed to create {}", output.display())
+                })?))
+            }
+            None => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        Some(Arc::new(ReportStream::new(
+            writer,
+            report_options.meta.clone(),
+        )))
+    } else {
+        None
+    };
+    let mut events = cli.events.as_deref().map(events::open_sink).transpose()?;
+    let simulation = cli
+        .simulate_only
+        .then(|| Arc::new(Simulation::new(events.clone())));
+    if let Some(simulation) = &simulation {
+        events = Some(simulation.clone());
+    }
+    let mut context = RunContext {
+        // the statistics are cheap to keep, and the reject summary needs the parse errors
+        stats: Arc::new(PipelineStats::new()),
+        profile: if cli.profile || cli.profile_trace.is_some() {
+            Some(Arc::new(Profile::new(cli.profile_trace.is_some())))
+        } else {
+            None
+        },
+        memory: cli
+            .max_memory
+            .map(|limit| Arc::new(MemoryBudget::new(limit))),
+        events,
+        report_options,
+        sharded_output,
+        report_stream,
+        restored,
+        processed_inputs: plan.processed,
+        clock: clock::system(),
+        period_report: match (cli.period, &cli.period_report) {
+            (Some(period), Some(path)) => Some(Arc::new(PeriodReport::create(period, path)?)),
+            _ => None,
+        },
+        merchants: cli.merchant_ledger.as_ref().map(|_| {
+            let ledger = MerchantLedger::new();
+            Arc::new(match cli.merchant_settlement {
+                Some(period) => ledger.with_settlement(period),
+                None => ledger,
+            })
+        }),
+        client_filter: client_filter(cli)?.map(Arc::new),
+        dispatch_schedule: None,
+        resume_progress: cli
+            .resume_token
+            .as_ref()
+            .map(|_| Arc::new(ResumeProgress::default())),
+        resumed_from: None,
+        sampler: cli
+            .sample
+            .map(|rate| Arc::new(Sampler::new(rate, cli.seed))),
+        simulation,
+        cancellation: CancellationToken::from_flag(shutdown_flag()?),
+    };
+    let stats = &context.stats;
+    if cli.deterministic && cli.read_mode == ReadMode::Parallel {
+        return Err(UsageError(
+            "--deterministic can't be used with --read-mode parallel, the records of the inputs \
+             would be mixed in a different order at every run"
+                .to_string(),
+        )
+        .into());
+    }
+    let mode = cli.mode.resolve(inputs_size(inputs), num_cores(cli));
+    info!("Processing the inputs in the {:?} mode", mode);
+    context.dispatch_schedule = dispatch_schedule(cli, mode)?.map(Arc::new);
+    let pins_reader = cli.reader_cores.is_some() || cli.parser_cores.is_some();
+    if (pins_reader || cli.worker_cores.is_some())
+        && !matches!(mode, ExecutionMode::Multi | ExecutionMode::Fused)
+    {
+        return Err(UsageError(
+            "--reader-cores, --parser-cores and --worker-cores pin the threads of --mode multi \
+             or fused"
+                .to_string(),
+        )
+        .into());
+    }
+    if cli.numa && mode != ExecutionMode::Multi {
+        return Err(UsageError("--numa spreads the workers of --mode multi".to_string()).into());
+    }
+    if cli.spill_clients && mode != ExecutionMode::Multi {
+        return Err(
+            UsageError("--spill-clients routes the clients of --mode multi".to_string()).into(),
+        );
+    }
+    if pins_reader && cli.format != InputFormat::Csv {
+        return Err(UsageError(
+            "--reader-cores and --parser-cores pin the threads of the CSV reader".to_string(),
+        )
+        .into());
+    }
+    if cli.resume_token.is_some() || cli.resume_from.is_some() {
+        check_resume(cli, inputs, mode)?;
+    }
+    if let Some(path) = &cli.resume_from {
+        let token = ResumeToken::read(path)?;
+        token.validate(&inputs[0], cli.encoding)?;
+        context.resumed_from = Some(token);
+    }
+    let slice = match &context.resumed_from {
+        Some(token) => InputSlice {
+            limit: cli.limit,
+            ..token.slice()
+        },
+        None => cli.input_slice(),
+    };
+
+    match cli.format {
+        InputFormat::Csv => {
+            let dead_letters = match &cli.dead_letter {
+                Some(path) => Some(Arc::new(DeadLetters::create(path)?)),
+                None => None,
+            };
+            let code = if matches!(
+                mode,
+                ExecutionMode::Multi | ExecutionMode::Rayon | ExecutionMode::Fused
+            ) {
+                // For the final application, use both multithreader CSV reader
+                // and multithreaded account manager for processing multiple clients in parallel
+                let num_cores = num_cores(cli);
+                let mut num_threads = if num_cores >= 4 { num_cores / 2 } else { 2 };
+                // the parsing threads are shared by the pipelines of all the inputs
+                if cli.read_mode == ReadMode::Parallel {
+                    num_threads = (num_threads / inputs.len()).max(1);
+                }
+                // the blocks are parsed one after the other
+                if cli.deterministic {
+                    num_threads = 1;
+                }
+                let block_size = cli.block_size.map_or_else(
+                    || transactions_reader::auto_block_size(inputs_size(inputs), num_threads),
+                    |size| size as usize,
+                );
+                let mut reader = MTReader::new()
+                    .with_threads(num_threads)
+                    .block_size(block_size)
+                    .with_encoding(cli.encoding)
+                    .with_amount_format(cli.amount_format)
+                    .with_slice(slice)
+                    .with_filter(cli.record_filter())
+                    .with_stats(stats.clone())
+                    .with_cancellation(context.cancellation.clone());
+                if cli.lenient_amounts {
+                    reader = reader.with_lenient_amounts();
+                }
+                if let Some(dead_letters) = &dead_letters {
+                    reader = reader.with_dead_letters(dead_letters.clone());
+                }
+                if let Some(profile) = &context.profile {
+                    reader = reader.with_profile(profile.clone());
+                }
+                if let Some(memory) = &context.memory {
+                    reader = reader.with_memory(memory.clone());
+                }
+                if let Some(cores) = &cli.reader_cores {
+                    reader = reader.with_reader_cores(cores.clone());
+                }
+                if let Some(cores) = &cli.parser_cores {
+                    reader = reader.with_parser_cores(cores.clone());
+                }
+                if mode == ExecutionMode::Fused {
+                    run_fused(cli, inputs, reader, context)?
+                } else {
+                    run_with_reader(cli, inputs, reader, mode, context)?
+                }
+            } else {
+                let mut reader = STBulkReader::new()
+                    .with_encoding(cli.encoding)
+                    .with_amount_format(cli.amount_format)
+                    .with_slice(slice)
+                    .with_filter(cli.record_filter())
+                    .with_stats(stats.clone())
+                    .with_cancellation(context.cancellation.clone());
+                if cli.lenient_amounts {
+                    reader = reader.with_lenient_amounts();
+                }
+                if let Some(dead_letters) = &dead_letters {
+                    reader = reader.with_dead_letters(dead_letters.clone());
+                }
+                if let Some(profile) = &context.profile {
+                    reader = reader.with_profile(profile.clone());
+                }
+                run_with_reader(cli, inputs, reader, mode, context)?
+            };
+
+            if let (Some(dead_letters), Some(path)) = (dead_letters, &cli.dead_letter) {
+                if dead_letters.lines() > 0 {
+                    eprintln!(
+                        "{} unparseable lines copied to {}",
+                        dead_letters.lines(),
+                        path.display()
+                    );
+                }
+            }
+            Ok(code)
+        }
+        #[cfg(feature = "parquet")]
+        InputFormat::Parquet => {
+            let reader = parquet_reader::ParquetReader::new().with_stats(stats.clone());
+            run_with_reader(cli, inputs, reader, mode, context)
+        }
+        #[cfg(not(feature = "parquet"))]
+        InputFormat::Parquet => Err(paytoy::errors::missing_feature("Parquet", "parquet")),
+        InputFormat::Binary => {
+            let reader = binary_format::BinaryReader::new().with_stats(stats.clone());
+            run_with_reader(cli, inputs, reader, mode, context)
+        }
+        #[cfg(feature = "avro")]
+        InputFormat::Avro => {
+            let reader = avro_reader::AvroReader::new().with_stats(stats.clone());
+            run_with_reader(cli, inputs, reader, mode, context)
+        }
+        #[cfg(not(feature = "avro"))]
+        InputFormat::Avro => Err(paytoy::errors::missing_feature("Avro", "avro")),
+        #[cfg(feature = "msgpack")]
+        InputFormat::MsgPack => {
+            let reader = msgpack_reader::MsgPackReader::new().with_stats(stats.clone());
+            run_with_reader(cli, inputs, reader, mode, context)
+        }
+        #[cfg(not(feature = "msgpack"))]
+        InputFormat::MsgPack => Err(paytoy::errors::missing_feature("MessagePack", "msgpack")),
+    }
+}
+
+/// The cores the pipeline is sized for, the same on every machine with `--deterministic`
+fn num_cores(cli: &Cli) -> usize {
+    if cli.deterministic {
+        DETERMINISTIC_CORES
+    } else {
+        num_cpus::get()
+    }
+}
+
+/// The workers of the multithreaded account manager
+fn num_workers(cli: &Cli) -> usize {
+    (num_cores(cli) / 2).max(1)
+}
+
+/// The schedule recorded or replayed by the workers of the multithreaded mode
+fn dispatch_schedule(cli: &Cli, mode: ExecutionMode) -> anyhow::Result<Option<DispatchSchedule>> {
+    if cli.record_schedule.is_none() && cli.replay_schedule.is_none() {
+        return Ok(None);
+    }
+    if mode != ExecutionMode::Multi {
+        return Err(UsageError(
+            "The schedules are the order of the workers of the multithreaded mode, \
+             use --mode multi to record or replay one"
+                .to_string(),
+        )
+        .into());
+    }
+    match (&cli.record_schedule, &cli.replay_schedule) {
+        (Some(path), _) => Ok(Some(DispatchSchedule::create(num_workers(cli), path)?)),
+        (None, Some(path)) => Ok(Some(DispatchSchedule::load(path)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The filter of the clients allowed and blocked by the lists of the command line
+fn client_filter(cli: &Cli) -> anyhow::Result<Option<ClientFilter>> {
+    if cli.allow_clients.is_none() && cli.block_clients.is_none() {
+        return Ok(None);
+    }
+    let mut filter = ClientFilter::new();
+    if let Some(path) = &cli.allow_clients {
+        filter = filter.with_allowed(client_filter::read_clients(path)?);
+    }
+    if let Some(path) = &cli.block_clients {
+        filter = filter.with_blocked(client_filter::read_clients(path)?);
+    }
+    Ok(Some(filter))
+}
+
+/// The inputs not applied to the restored accounts yet
+/// The digests of the inputs are only taken when a snapshot is restored or saved
+fn input_plan(
+    cli: &Cli,
+    inputs: &[PathBuf],
+    restored: Option<&Snapshot>,
+) -> anyhow::Result<InputPlan> {
+    let processed = restored.map_or(&[][..], |snapshot| &snapshot.inputs);
+    if cli.snapshot.is_none() && processed.is_empty() {
+        return Ok(InputPlan {
+            inputs: inputs.to_vec(),
+            ..InputPlan::default()
+        });
+    }
+    let plan = processed_inputs::plan(inputs, processed, cli.reprocess)?;
+    for input in &plan.skipped {
+        eprintln!(
+            "{} was already processed, skipping it (--reprocess to process it again)",
+            input.display()
+        );
+    }
+    Ok(plan)
+}
+
+/// A slice is only read from a single CSV input, and isn't recorded as processed
+fn check_slice(cli: &Cli, inputs: &[PathBuf]) -> anyhow::Result<()> {
+    let usage = |message: &str| Err(UsageError(message.to_string()).into());
+    if cli.format != InputFormat::Csv {
+        return usage("--skip-records, --limit and --start-offset slice the CSV inputs");
+    }
+    if inputs.len() > 1 {
+        return usage("--skip-records, --limit and --start-offset slice a single input");
+    }
+    if cli.snapshot.is_some() {
+        return usage(
+            "A slice of an input can't be recorded as processed, remove --snapshot to read it",
+        );
+    }
+    Ok(())
+}
+
+/// The resume tokens are the positions in a single local CSV input, and go on with the
+/// restored accounts
+fn check_resume(cli: &Cli, inputs: &[PathBuf], mode: ExecutionMode) -> anyhow::Result<()> {
+    let usage = |message: &str| Err(UsageError(message.to_string()).into());
+    if cli.format != InputFormat::Csv || inputs.len() > 1 || cli.backfill.is_some() {
+        return usage("The resume tokens are positions in a single CSV input");
+    }
+    if Input::is_remote(&inputs[0]) {
+        return usage("The resume tokens are positions in a local input");
+    }
+    if mode == ExecutionMode::Fused {
+        return usage("--mode fused can't be used with the resume tokens");
+    }
+    if cli.resume_from.is_some() && cli.restore.is_none() && !cli.resume {
+        return usage(
+            "--resume-from goes on with the accounts of the interrupted run, restore them \
+             with --restore or --resume",
+        );
+    }
+    Ok(())
+}
+
+/// Refuses the options that save or publish the state of the accounts, which a simulation
+/// mustn't change
+fn check_simulation(cli: &Cli) -> anyhow::Result<()> {
+    let saved: Vec<_> = [
+        (
+            cli.output.as_deref().is_some_and(report::is_database),
+            "a database output",
+        ),
+        (
+            cli.events.as_deref().is_some_and(events::is_bus),
+            "the events of a message bus",
+        ),
+    ]
+    .iter()
+    .filter_map(|(used, option)| used.then_some(*option))
+    .collect();
+    if !saved.is_empty() {
+        return Err(UsageError(format!(
+            "--simulate-only saves nothing, it can't be used with {}",
+            saved.join(", ")
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Total size of the inputs, unknown if some of them are not local files
+fn inputs_size(inputs: &[PathBuf]) -> Option<u64> {
+    inputs
+        .iter()
+        .map(|input| std::fs::metadata(input).ok().map(|metadata| metadata.len()))
+        .sum()
+}
+
+/// Picks the account manager for the execution mode and runs the application
+fn run_with_reader(
+    cli: &Cli,
+    inputs: &[PathBuf],
+    reader: impl TransactionCSVReader + Clone + Send + 'static,
+    mode: ExecutionMode,
+    context: RunContext,
+) -> anyhow::Result<ExitCode> {
+    let seen = bloom_filter(cli, inputs, &context)?;
+    let registry = cli.unique_tx_ids.then(|| Arc::new(TxRegistry::new()));
+    if mode == ExecutionMode::Multi {
+        // a replay runs as many workers as the recorded run
+        let num_threads = context
+            .dispatch_schedule
+            .as_ref()
+            .map_or_else(|| num_workers(cli), |schedule| schedule.workers());
+        let mut manager = MTAccountManager::new(num_threads)
+            .with_stats(context.stats.clone())
+            .with_cancellation(context.cancellation.clone());
+        if cli.latency {
+            manager = manager.with_apply_latencies();
+        }
+        if cli.deterministic {
+            manager = manager.with_fifo();
+        }
+        if let Some(schedule) = &context.dispatch_schedule {
+            manager = manager.with_dispatch_schedule(schedule.clone());
+        }
+        if let Some(profile) = &context.profile {
+            manager = manager.with_profile(profile.clone());
+        }
+        if let Some(memory) = &context.memory {
+            manager = manager.with_memory(memory.clone());
+        }
+        if let Some(cores) = &cli.worker_cores {
+            manager = manager.with_worker_cores(cores.clone());
+        }
+        if cli.spill_clients {
+            manager = manager.with_client_spill();
+        }
+        if cli.numa {
+            let nodes = affinity::numa_nodes().context("Failed to read the NUMA nodes")?;
+            info!("Spreading the workers over {} NUMA nodes", nodes.len());
+            manager = manager.with_numa_nodes(nodes);
+        }
+        if let Some(limit) = cli.history_limit {
+            let dir = history_dir(cli);
+            manager = manager
+                .with_history_limit(limit as usize, &dir)
+                .with_context(|| {
+                    format!("Failed to create the history store in {}", dir.display())
+                })?;
+        }
+        if let Some(seen) = seen {
+            manager = manager.with_bloom_filter(seen);
+        }
+        if let Some(registry) = registry {
+            manager = manager.with_tx_registry(registry);
+        }
+        manager = manager
+            .with_dispute_policy(cli.dispute_hold)
+            .with_locked_policy(LockedPolicy::allowing(&cli.locked_allow))
+            .with_disabled_types(DisabledTypes::of(&cli.disable_types));
+        if let Some(filter) = &context.client_filter {
+            manager = manager.with_client_filter(filter.clone());
+        }
+        if let Some(limit) = cli.max_client_transactions {
+            manager = manager.with_transaction_limit(limit, cli.over_limit);
+        }
+        if let Some(period) = cli.dispute_expiry {
+            manager = manager.with_dispute_expiry(period, cli.expiry_action);
+        }
+        if cli.settle_withdrawals {
+            manager = manager.with_pending_withdrawals(cli.settlement_delay);
+        }
+        if let Some(tx) = cli.trace_tx {
+            manager = manager.with_trace_tx(tx);
+        }
+        if cli.check_invariants {
+            manager = manager.with_check_invariants();
+        }
+        if cli.statements.is_some() {
+            manager = manager.with_journal();
+        }
+        if let Some(events) = &context.events {
+            manager = manager.with_events(events.clone());
+        }
+        if let Some(output) = &context.sharded_output {
+            manager = manager.with_sharded_output(output.clone());
+        }
+        if let Some(stream) = &context.report_stream {
+            manager = manager.with_report_stream(stream.clone());
+        }
+        if let Some(report) = &context.period_report {
+            manager = manager.with_period_report(report.clone());
+        }
+        if let Some(ledger) = &context.merchants {
+            manager = manager.with_merchant_ledger(ledger.clone());
+        }
+        // after the other options, the restored accounts are created with them
+        if let Some(snapshot) = &context.restored {
+            manager = manager.with_snapshot(snapshot);
+        }
+        run_app(inputs, reader, manager, context, cli)
+    } else if mode == ExecutionMode::Rayon {
+        run_rayon(cli, inputs, reader, context, seen, registry)
+    } else if mode == ExecutionMode::Fused {
+        Err(UsageError("--mode fused only reads CSV inputs".to_string()).into())
+    } else {
+        let mut manager = st_manager(cli, &context, seen, registry);
+        if let Some(limit) = cli.history_limit {
+            let dir = history_dir(cli);
+            let store = FileHistoryStore::create(&dir).with_context(|| {
+                format!("Failed to create the history store in {}", dir.display())
+            })?;
+            manager = manager.with_history_limit(limit as usize, Arc::new(Mutex::new(store)));
+        }
+        // after the other options, the restored accounts are created with them
+        if let Some(snapshot) = &context.restored {
+            manager = manager.with_snapshot(snapshot);
+        }
+        run_app(inputs, reader, manager, context, cli)
+    }
+}
+
+/// The single threaded manager with the options of the run, but for the history limit and
+/// the restored accounts
+fn st_manager(
+    cli: &Cli,
+    context: &RunContext,
+    seen: Option<Arc<BloomFilter>>,
+    registry: Option<Arc<TxRegistry>>,
+) -> STAccountManager {
+    let mut manager = STAccountManager::new()
+        .with_stats(context.stats.clone())
+        .with_cancellation(context.cancellation.clone());
+    if cli.latency {
+        manager = manager.with_apply_latencies();
+    }
+    if let Some(profile) = &context.profile {
+        manager = manager.with_profile(profile.clone());
+    }
+    if let Some(memory) = &context.memory {
+        manager = manager.with_memory(memory.clone());
+    }
+    if let Some(seen) = seen {
+        manager = manager.with_bloom_filter(seen);
+    }
+    if let Some(registry) = registry {
+        manager = manager.with_tx_registry(registry);
+    }
+    manager = manager
+        .with_dispute_policy(cli.dispute_hold)
+        .with_locked_policy(LockedPolicy::allowing(&cli.locked_allow))
+        .with_disabled_types(DisabledTypes::of(&cli.disable_types));
+    if let Some(filter) = &context.client_filter {
+        manager = manager.with_client_filter(filter.clone());
+    }
+    if let Some(limit) = cli.max_client_transactions {
+        manager = manager.with_transaction_limit(limit, cli.over_limit);
+    }
+    if let Some(period) = cli.dispute_expiry {
+        manager = manager.with_dispute_expiry(period, cli.expiry_action);
+    }
+    if cli.settle_withdrawals {
+        manager = manager.with_pending_withdrawals(cli.settlement_delay);
+    }
+    if let Some(tx) = cli.trace_tx {
+        manager = manager.with_trace_tx(tx);
+    }
+    if cli.check_invariants {
+        manager = manager.with_check_invariants();
+    }
+    if cli.statements.is_some() {
+        manager = manager.with_journal();
+    }
+    if let Some(events) = &context.events {
+        manager = manager.with_events(events.clone());
+    }
+    if let Some(output) = &context.sharded_output {
+        manager = manager.with_sharded_output(output.clone());
+    }
+    if let Some(stream) = &context.report_stream {
+        manager = manager.with_report_stream(stream.clone());
+    }
+    if let Some(report) = &context.period_report {
+        manager = manager.with_period_report(report.clone());
+    }
+    if let Some(ledger) = &context.merchants {
+        manager = manager.with_merchant_ledger(ledger.clone());
+    }
+    manager
+}
+
+/// Applies the records with the rayon manager, whose shards are single threaded managers
+#[cfg(feature = "rayon")]
+fn run_rayon(
+    cli: &Cli,
+    inputs: &[PathBuf],
+    reader: impl TransactionCSVReader + Clone + Send + 'static,
+    context: RunContext,
+    seen: Option<Arc<BloomFilter>>,
+    registry: Option<Arc<TxRegistry>>,
+) -> anyhow::Result<ExitCode> {
+    if cli.history_limit.is_some() || cli.stream_report || cli.output_shards.is_some() {
+        return Err(UsageError(
+            "--mode rayon can't be used with --history-limit, --stream-report or --output-shards"
+                .to_string(),
+        )
+        .into());
+    }
+    let mut manager = RayonAccountManager::new(num_cores(cli), || {
+        st_manager(cli, &context, seen.clone(), registry.clone())
+    })
+    .context("Failed to start the rayon threads")?;
+    if let Some(snapshot) = &context.restored {
+        manager = manager.with_snapshot(snapshot);
+    }
+    run_app(inputs, reader, manager, context, cli)
+}
+
+#[cfg(not(feature = "rayon"))]
+fn run_rayon(
+    _cli: &Cli,
+    _inputs: &[PathBuf],
+    _reader: impl TransactionCSVReader,
+    _context: RunContext,
+    _seen: Option<Arc<BloomFilter>>,
+    _registry: Option<Arc<TxRegistry>>,
+) -> anyhow::Result<ExitCode> {
+    Err(paytoy::errors::missing_feature("rayon", "rayon"))
+}
+
+/// Parses the CSV input straight into the queues of the workers, see `FusedPipeline`
+fn run_fused(
+    cli: &Cli,
+    inputs: &[PathBuf],
+    reader: MTReader,
+    context: RunContext,
+) -> anyhow::Result<ExitCode> {
+    // the options working on the stream of records, which the fused pipeline doesn't have
+    let unsupported: Vec<_> = [
+        (inputs.len() > 1, "several inputs"),
+        (cli.schedule.is_some(), "--schedule"),
+        (cli.rate_limit.is_some(), "--rate-limit"),
+        (cli.input_rate_limit.is_some(), "--input-rate-limit"),
+        (cli.replay_window.is_some(), "--replay-window"),
+        (cli.backfill.is_some(), "--backfill"),
+        (cli.sample.is_some(), "--sample"),
+        (cli.trace_tx.is_some(), "--trace-tx"),
+        (cli.watchdog.is_some(), "--watchdog"),
+        (cli.max_memory.is_some(), "--max-memory"),
+        (cli.checkpoint_every.is_some(), "--checkpoint-every"),
+        (cli.snapshot_every.is_some(), "--snapshot-every"),
+        (cli.history_limit.is_some(), "--history-limit"),
+        (cli.stream_report, "--stream-report"),
+        (cli.output_shards.is_some(), "--output-shards"),
+        (cli.deterministic, "--deterministic"),
+        (cli.tui, "--tui"),
+    ]
+    .iter()
+    .filter_map(|(used, option)| used.then_some(*option))
+    .collect();
+    if !unsupported.is_empty() {
+        return Err(UsageError(format!(
+            "--mode fused can't be used with {}",
+            unsupported.join(", ")
+        ))
+        .into());
+    }
+    let seen = bloom_filter(cli, inputs, &context)?;
+    let registry = cli.unique_tx_ids.then(|| Arc::new(TxRegistry::new()));
+    let input = Input::open_with(&inputs[0], cli.zip_entries.as_deref(), &cli.stream_policy())?;
+    let reader = if cli.tag_sources {
+        reader.with_source(input.name().clone())
+    } else {
+        reader
+    };
+    let mut pipeline = FusedPipeline::new(reader, num_workers(cli), || {
+        st_manager(cli, &context, seen.clone(), registry.clone())
+    });
+    if let Some(cores) = &cli.worker_cores {
+        pipeline = pipeline.with_worker_cores(cores.clone());
+    }
+    if let Some(snapshot) = &context.restored {
+        pipeline = pipeline.with_snapshot(snapshot);
+    }
+    let report = input.into_reader().and_then(|input| pipeline.run(input));
+    context.stats.finish();
+    finish_run(cli, context, report?)
+}
+
+/// Set when the run is asked to stop with Ctrl-C or SIGTERM, a second Ctrl-C kills it
+#[cfg(unix)]
+fn shutdown_flag() -> anyhow::Result<Arc<AtomicBool>> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    let flag = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, flag.clone())?;
+        signal_hook::flag::register(signal, flag.clone())?;
+    }
+    Ok(flag)
+}
+
+#[cfg(not(unix))]
+fn shutdown_flag() -> anyhow::Result<Arc<AtomicBool>> {
+    Ok(Arc::new(AtomicBool::new(false)))
+}
+
+/// The filter of the transaction ids with `--dedup bloom`
+fn bloom_filter(
+    cli: &Cli,
+    inputs: &[PathBuf],
+    context: &RunContext,
+) -> anyhow::Result<Option<Arc<BloomFilter>>> {
+    if cli.dedup != Dedup::Bloom {
+        return Ok(None);
+    }
+    if cli.history_limit.is_some() {
+        return Err(UsageError(
+            "--history-limit can't be used with --dedup bloom, which keeps no history".to_string(),
+        )
+        .into());
+    }
+    let capacity = cli
+        .bloom_capacity
+        .or_else(|| inputs_size(inputs).map(|size| size / MIN_RECORD_SIZE))
+        .unwrap_or(DEFAULT_BLOOM_CAPACITY);
+    let seen = BloomFilter::new(capacity, cli.bloom_fp_rate);
+    info!(
+        "Bloom filter of {} for {} transactions",
+        memory::format_size(seen.size_bytes()),
+        capacity
+    );
+    if let Some(memory) = &context.memory {
+        memory.add_history(seen.size_bytes() as i64);
+    }
+    Ok(Some(Arc::new(seen)))
+}
+
+/// Directory of the history stores
+fn history_dir(cli: &Cli) -> PathBuf {
+    cli.history_dir.clone().unwrap_or_else(std::env::temp_dir)
+}
+
+/// Runs the application on the input files and prints the report
+/// The dashboard is shown while processing if it was asked for
+fn run_app(
+    inputs: &[PathBuf],
+    reader: impl TransactionCSVReader + Clone + Send + 'static,
+    manager: impl AccountManager,
+    context: RunContext,
+    cli: &Cli,
+) -> anyhow::Result<ExitCode> {
+    let stats = context.stats.clone();
+    let report_options = &context.report_options;
+    let clock = context.clock.clone();
+    let dashboard = if cli.tui {
+        Some(dashboard::start(stats.clone())?)
+    } else {
+        None
+    };
+
+    let watchdog = match cli.watchdog {
+        Some(timeout) => {
+            Watchdog::new(stats.clone(), Duration::from_secs(timeout)).start(cli.watchdog_abort)?;
+            Some(stats.clone())
+        }
+        None => None,
+    };
+
+    let cancellation = context.cancellation.clone();
+    let resume_progress = context.resume_progress.clone();
+    let sampler = context.sampler.clone();
+    let memory = context.memory.clone();
+    let replay_window = cli.replay_window.map(|capacity| {
+        let window = ReplayWindow::new(capacity as usize);
+        match cli.replay_ttl {
+            Some(ttl) => window.with_ttl(Duration::from_secs(ttl)),
+            None => window,
+        }
+    });
+    let trace = cli.trace_tx.map(TxTrace::new);
+    // the traced records tell where they were read, and the resume tokens need the line of
+    // the last one applied
+    let tag_sources = cli.tag_sources || cli.trace_tx.is_some() || cli.resume_token.is_some();
+    // the snapshots saved during the run don't have its inputs yet
+    let previous_inputs = context
+        .restored
+        .as_ref()
+        .map_or(&[][..], |snapshot| &snapshot.inputs);
+    // the history is read first, so the reader of the live inputs starts after it
+    let history = cli
+        .backfill
+        .as_ref()
+        .map(|path| {
+            let input = Input::open_with(path, cli.zip_entries.as_deref(), &cli.stream_policy())?;
+            read_inputs(
+                vec![input],
+                reader.clone(),
+                ReadMode::Sequential,
+                tag_sources,
+                cli.input_rate_limit,
+                clock.clone(),
+                Some(stats.clone()),
+            )
+        })
+        .transpose();
+    let report = history
+        .and_then(|history| {
+            let inputs = inputs
+                .iter()
+                .map(|input| {
+                    Input::open_with(input, cli.zip_entries.as_deref(), &cli.stream_policy())
+                })
+                .collect::<anyhow::Result<Vec<Input>>>()?;
+            let live = read_inputs(
+                inputs,
+                reader,
+                cli.read_mode,
+                tag_sources,
+                cli.input_rate_limit,
+                clock.clone(),
+                Some(stats.clone()),
+            )?;
+            Ok(match history {
+                Some(history) => backfill::chain(
+                    history,
+                    live,
+                    cli.backfill_overlap as usize,
+                    Some(stats.clone()),
+                ),
+                None => live,
+            })
+        })
+        .map(|transactions| -> TransactionsStream {
+            match sampler {
+                Some(sampler) => Box::new(transactions.filter(move |record| sampler.keeps(record))),
+                None => transactions,
+            }
+        })
+        .and_then(|transactions| match &cli.schedule {
+            Some(path) => Ok(schedule::inject(transactions, Schedule::read(path)?)),
+            None => Ok(transactions),
+        })
+        .map(|transactions| match cli.rate_limit {
+            Some(rate) => rate_limit::limit(transactions, rate, clock.clone()),
+            None => transactions,
+        })
+        .map(|transactions| -> TransactionsStream {
+            match trace {
+                Some(trace) => Box::new(
+                    transactions.inspect(move |record| trace.step(record, format_args!("read"))),
+                ),
+                None => transactions,
+            }
+        })
+        .map(|transactions| -> TransactionsStream {
+            match replay_window {
+                Some(mut window) => {
+                    let stats = stats.clone();
+                    let clock = clock.clone();
+                    Box::new(transactions.filter(move |record| {
+                        if !window.is_replay(record, clock.now()) {
+                            return true;
+                        }
+                        stats.add_replayed();
+                        if let Some(trace) = trace {
+                            trace.step(record, format_args!("dropped as a replay"));
+                        }
+                        false
+                    }))
+                }
+                None => transactions,
+            }
+        })
+        .map(|transactions| -> TransactionsStream {
+            match watchdog {
+                // the progress the watchdog looks at
+                Some(stats) => Box::new(transactions.inspect(move |_| stats.add_dispatched(1))),
+                None => transactions,
+            }
+        })
+        .map(|transactions| -> TransactionsStream {
+            match memory {
+                // stop feeding the account manager once the history doesn't fit anymore
+                Some(memory) => Box::new(transactions.take_while(move |_| !memory.is_exceeded())),
+                None => transactions,
+            }
+        })
+        .map(|transactions| -> TransactionsStream {
+            // for the readers and managers that don't look at the cancellation themselves
+            let cancellation = cancellation.clone();
+            Box::new(transactions.take_while(move |_| !cancellation.is_cancelled()))
+        })
+        .map(|transactions| -> TransactionsStream {
+            match resume_progress {
+                Some(progress) => Box::new(transactions.inspect(move |record| {
+                    progress.applied(record);
+                })),
+                None => transactions,
+            }
+        })
+        .and_then(
+            |transactions| match (cli.checkpoint_every, cli.snapshot_every, &cli.snapshot) {
+                (Some(every), _, _) => {
+                    PayToyApp::process_with_checkpoints(transactions, manager, every, |report| {
+                        report.write_atomic(cli.output.as_deref(), report_options)
+                    })
+                }
+                (None, Some(every), Some(path)) => PayToyApp::process_with_snapshots(
+                    transactions,
+                    manager,
+                    Duration::from_secs(every),
+                    clock.as_ref(),
+                    |state| snapshot::save_snapshot(path, state, previous_inputs),
+                ),
+                _ => Ok(PayToyApp::process_until_cancelled(
+                    transactions,
+                    manager,
+                    &cancellation,
+                )),
+            },
+        );
+
+    // the dashboard has to be closed before printing anything
+    stats.finish();
+    if let Some(dashboard) = dashboard {
+        let _ = dashboard.join();
+    }
+
+    finish_run(cli, context, report?)
+}
+
+/// Writes the report and the other outputs of a run once all its records are applied
+fn finish_run(cli: &Cli, context: RunContext, report: Report) -> anyhow::Result<ExitCode> {
+    let stats = &context.stats;
+    let report_options = &context.report_options;
+    let previous_inputs = context
+        .restored
+        .as_ref()
+        .map_or(&[][..], |snapshot| &snapshot.inputs);
+    if let Some(events) = &context.events {
+        events.flush()?;
+    }
+    if let Some(period_report) = &context.period_report {
+        period_report.finish()?;
+    }
+    if let Some(schedule) = &context.dispatch_schedule {
+        schedule.finish()?;
+        if let Some(reason) = schedule.diverged() {
+            eprintln!(
+                "The records didn't follow the schedule, {}: the run wasn't reproduced",
+                reason
+            );
+        }
+    }
+    if let (Some(ledger), Some(path)) = (&context.merchants, &cli.merchant_ledger) {
+        ledger.write_file(path)?;
+    }
+    if let Some(memory) = context.memory.filter(|memory| memory.is_exceeded()) {
+        return Err(anyhow::anyhow!(
+            "The transaction history ({}) doesn't fit in the memory budget of {}, \
+             the run was aborted. Raise --max-memory to process these inputs",
+            memory::format_size(memory.history()),
+            memory::format_size(memory.limit())
+        ));
+    }
+    if let Some(stream) = &context.report_stream {
+        stream.finish()?;
+    } else if let Some(output) = &context.sharded_output {
+        let shards = output.finish()?;
+        if let (Some(first), Some(last)) = (shards.first(), shards.last()) {
+            eprintln!(
+                "{} report shards written, {} to {}",
+                shards.len(),
+                first.display(),
+                last.display()
+            );
+        }
+    } else if cli.checkpoint_every.is_some() {
+        report.write_atomic(cli.output.as_deref(), report_options)?;
+    } else {
+        report.write(cli.output.as_deref(), report_options)?;
+    }
+    let interrupted = report.is_cancelled() || context.cancellation.is_cancelled();
+    if let Some(path) = &cli.snapshot {
+        // the inputs of a partial run are processed again by the next one
+        let inputs = if interrupted || stats.input_errors() > 0 {
+            previous_inputs
+        } else {
+            &context.processed_inputs
         };
+        snapshot::save_snapshot(
+            path,
+            report.accounts().map(SnapshotAccount::of).collect(),
+            inputs,
+        )?;
+        eprintln!(
+            "snapshot of {} accounts written to {}",
+            report.accounts().count(),
+            path.display()
+        );
+    }
+    if let (Some(path), Some(progress)) = (&cli.resume_token, &context.resume_progress) {
+        // nothing applied by a resumed run, the position is still the one it resumed from
+        let resumed_from = &context.resumed_from;
+        let last = progress
+            .last()
+            .or_else(|| resumed_from.as_ref().and_then(ResumeToken::last));
+        let token = ResumeToken::new(&cli.inputs[0], cli.encoding, last)?;
+        token.write(path)?;
+        eprintln!(
+            "resume token written to {}, after the line {}",
+            path.display(),
+            token.line
+        );
+    }
+    if let Some(dir) = &cli.statements {
+        let written = statement::write_statements(
+            dir,
+            &report,
+            cli.statement_format,
+            report_options.meta.as_deref(),
+        )?;
+        eprintln!("{} statements written to {}", written, dir.display());
+    }
+    if let Some(path) = &cli.disputes_report {
+        disputes_report::write_file(path, report.accounts())?;
+    }
+    if let Some(path) = &cli.anomalies {
+        let count = anomalies::write_file(path, report.accounts())?;
+        if count > 0 {
+            eprintln!("{} anomalies written to {}", count, path.display());
+        }
+    }
+
+    // on stderr, so it doesn't mix with the report
+    if let Some(sampler) = &context.sampler {
+        sampler.write_summary(&mut std::io::stderr())?;
+    }
+    if let Some(simulation) = &context.simulation {
+        simulation.write_summary(&mut std::io::stderr())?;
+    }
+    let summary = report.reject_summary(stats.parse_errors());
+    if !summary.is_empty() {
+        report::write_reject_summary(&mut std::io::stderr(), &summary)?;
+    }
+    if stats.records_filtered() > 0 {
+        eprintln!(
+            "{} records left out by --only-types and --client-range",
+            stats.records_filtered()
+        );
+    }
+    if stats.amounts_normalized() > 0 {
+        eprintln!(
+            "{} amounts read after stripping their currency symbol",
+            stats.amounts_normalized()
+        );
+    }
+    if let Some(profile) = &context.profile {
+        profile.write_breakdown(&mut std::io::stderr())?;
+        if let Some(path) = &cli.profile_trace {
+            profile.write_trace(BufWriter::new(File::create(path)?))?;
+        }
+    }
+    if cli.latency {
+        stats.latencies().write_summary(&mut std::io::stderr())?;
+    }
+    if cli.worker_stats {
+        stats.write_worker_summary(&mut std::io::stderr())?;
+    }
+
+    if stats.records_replayed() > 0 {
+        eprintln!("{} replayed records were dropped", stats.records_replayed());
+    }
+    if stats.clients_spilled() > 0 {
+        eprintln!(
+            "{} new clients were sent to less busy workers than their shard's",
+            stats.clients_spilled()
+        );
+    }
+    if stats.records_overlapping() > 0 {
+        eprintln!(
+            "{} records of the inputs repeating the end of the backfill were dropped",
+            stats.records_overlapping()
+        );
+    }
+    if interrupted {
+        eprintln!("Interrupted, the report only covers the records read before");
+        Ok(ExitCode::Partial)
+    } else if stats.input_errors() > 0 {
+        eprintln!("Some inputs couldn't be read to the end, the report is partial");
+        Ok(ExitCode::Partial)
+    } else if cli
+        .fail_on
+        .is_violated(stats.parse_errors(), report.rejects().len())
+    {
+        Ok(ExitCode::Validation)
+    } else {
+        Ok(ExitCode::Success)
+    }
+}
+
+/// The values given, or all of them when none are
+fn or_all<T: Clone>(given: &[T], all: Vec<T>) -> Vec<T> {
+    if given.is_empty() {
+        all
     } else {
-        let reader = MTReader::new().with_threads(2);
-        let manager = STAccountManager::new();
+        given.to_vec()
+    }
+}
 
-        if let Err(err) = PayToyApp::run(input_file, reader, manager, true) {
-            error!("Failed to run the application: {:?}", err);
-            std::process::exit(0);
+/// The combinations of `paytoy bench`, the lists not given default to the whole sweep
+fn bench_matrix(
+    matrix: bool,
+    records: usize,
+    readers: &[BenchReader],
+    managers: &[BenchManager],
+    threads: &[usize],
+    workloads: &[WorkloadProfile],
+) -> BenchMatrix {
+    let cores = num_cpus::get();
+    let mut all_managers = vec![BenchManager::St, BenchManager::Mt, BenchManager::Fused];
+    if cfg!(feature = "rayon") {
+        all_managers.insert(2, BenchManager::Rayon);
+    }
+    BenchMatrix {
+        records,
+        readers: or_all(readers, vec![BenchReader::St, BenchReader::Mt]),
+        managers: or_all(managers, all_managers),
+        threads: match (threads.is_empty(), matrix) {
+            (false, _) => threads.to_vec(),
+            (true, false) => vec![cores],
+            (true, true) => BenchMatrix::thread_counts(cores),
+        },
+        workloads: match (workloads.is_empty(), matrix) {
+            (false, _) => workloads.to_vec(),
+            (true, false) => vec![WorkloadProfile::Uniform],
+            (true, true) => WorkloadProfile::ALL.to_vec(),
+        },
+    }
+}
+
+/// Writes the changes of the accounts between two reports
+fn write_delta(before: &Path, after: &Path, output: Option<&Path>) -> anyhow::Result<()> {
+    let before = report_reader::read_report(before)?;
+    let after = report_reader::read_report(after)?;
+    let deltas = report_delta::report_delta(&before, &after);
+    match output {
+        Some(path) => {
+            let mut writer = BufWriter::new(
+                File::create(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?,
+            );
+            report_delta::write_deltas(&mut writer, &deltas)?;
+            writer.flush()?;
+        }
+        None => report_delta::write_deltas(&mut std::io::stdout().lock(), &deltas)?,
+    }
+    Ok(())
+}
+
+/// Rebuilds the accounts from the events, checks them against the report or the checksum of
+/// the run and prints the accounts that don't match
+/// Returns whether they match
+fn replay_events(
+    events: &Path,
+    expected: Option<&Path>,
+    expected_checksum: Option<&str>,
+    output: Option<&Path>,
+) -> anyhow::Result<bool> {
+    let replay = EventReplay::replay_file(events)?;
+    eprintln!(
+        "replayed {} events, skipped {} without a change",
+        replay.applied(),
+        replay.skipped()
+    );
+    let replayed = replay.into_report();
+    let checksum = event_replay::checksum(&replayed);
+    eprintln!(
+        "rebuilt {} accounts, checksum {}",
+        replayed.accounts().count(),
+        checksum
+    );
+    if let Some(output) = output {
+        let options = ReportOptions {
+            format: ReportFormat::Plain,
+            sorted: true,
+            ..ReportOptions::default()
         };
+        replayed.write(Some(output), &options)?;
     }
 
-    // For this benchmark, I get around 6-7 millions of records/second on my machine
-    // run_benchmarks(true);
+    let mut matches = true;
+    if let Some(expected) = expected_checksum {
+        if !expected.trim().eq_ignore_ascii_case(&checksum) {
+            eprintln!("the checksum of the report is {}", expected.trim());
+            matches = false;
+        }
+    }
+    if let Some(expected) = expected {
+        let expected = report_reader::read_report(expected)?;
+        let mismatches = event_replay::mismatches(&replayed, &expected);
+        if !mismatches.is_empty() {
+            eprintln!(
+                "{} accounts of the report don't match the events",
+                mismatches.len()
+            );
+            event_replay::write_mismatches(&mut std::io::stdout().lock(), &mismatches)?;
+            matches = false;
+        }
+    }
+    Ok(matches)
+}
+
+fn main() {
+    let cli = Cli::parse();
+    logging::init(cli.log_format, cli.trace_tx.is_some());
+
+    match &cli.command {
+        Some(Command::Repl) => {
+            let stdin = std::io::stdin();
+            if let Err(err) = Repl::new().run(stdin.lock(), &mut std::io::stdout()) {
+                eprintln!("REPL session failed: {:?}", err);
+                std::process::exit(ExitCode::Io as i32);
+            }
+            return;
+        }
+        Some(Command::Convert { input, output }) => {
+            match binary_format::convert_csv(input, output) {
+                Ok((written, parse_errors)) => eprintln!(
+                    "converted {} records, skipped {} unparseable",
+                    written, parse_errors
+                ),
+                Err(err) => {
+                    eprintln!("Conversion failed: {:?}", err);
+                    std::process::exit(ExitCode::of_error(&err) as i32);
+                }
+            }
+            return;
+        }
+        Some(Command::MergeReports {
+            reports,
+            output,
+            conflict,
+            report_format,
+        }) => {
+            let options = ReportOptions {
+                format: *report_format,
+                ..ReportOptions::default()
+            };
+            let merged = report_merge::merge_reports(reports, *conflict)
+                .and_then(|report| report.write(output.as_deref(), &options));
+            if let Err(err) = merged {
+                eprintln!("Merging the reports failed: {:?}", err);
+                std::process::exit(ExitCode::of_error(&err) as i32);
+            }
+            return;
+        }
+        Some(Command::Delta {
+            before,
+            after,
+            output,
+        }) => {
+            if let Err(err) = write_delta(before, after, output.as_deref()) {
+                eprintln!("Comparing the reports failed: {:?}", err);
+                std::process::exit(ExitCode::of_error(&err) as i32);
+            }
+            return;
+        }
+        Some(Command::Bench {
+            matrix,
+            records,
+            readers,
+            managers,
+            threads,
+            workloads,
+            format,
+            output,
+        }) => {
+            let matrix = bench_matrix(*matrix, *records, readers, managers, threads, workloads);
+            let result = bench::open_output(output.as_ref())
+                .map_err(anyhow::Error::from)
+                .and_then(|mut writer| matrix.run(*format, &mut writer));
+            if let Err(err) = result {
+                eprintln!("Benchmark failed: {:?}", err);
+                std::process::exit(ExitCode::of_error(&err) as i32);
+            }
+            return;
+        }
+        Some(Command::Generate {
+            profile,
+            records,
+            seed,
+            corrupt,
+            corrupt_rate,
+            output,
+        }) => {
+            let corrupter = Corrupter::new(corrupt.clone(), *corrupt_rate, *seed);
+            let result = bench::open_output(output.as_ref()).and_then(|mut writer| {
+                workload::write_csv(&mut writer, *profile, *records, *seed, Some(corrupter))
+            });
+            match result {
+                Ok(corrupted) if !corrupt.is_empty() => {
+                    eprintln!("wrote {} records, corrupted {} lines", records, corrupted)
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    let err = anyhow::Error::from(err);
+                    eprintln!("Generating the input failed: {:?}", err);
+                    std::process::exit(ExitCode::of_error(&err) as i32);
+                }
+            }
+            return;
+        }
+        Some(Command::ReplayEvents {
+            events,
+            report,
+            checksum,
+            output,
+        }) => {
+            match replay_events(
+                events,
+                report.as_deref(),
+                checksum.as_deref(),
+                output.as_deref(),
+            ) {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(ExitCode::Validation as i32),
+                Err(err) => {
+                    eprintln!("Replaying the events failed: {:?}", err);
+                    std::process::exit(ExitCode::of_error(&err) as i32);
+                }
+            }
+            return;
+        }
+        Some(Command::Inspect { input, sample }) => {
+            match inspect::inspect_input(input, *sample) {
+                Ok(inspection) => {
+                    let written = inspection.write(&mut std::io::stdout().lock());
+                    if let Err(err) = written {
+                        eprintln!("Writing the inspection failed: {:?}", err);
+                        std::process::exit(ExitCode::Io as i32);
+                    }
+                    if !inspection.mismatches().is_empty() {
+                        std::process::exit(ExitCode::Validation as i32);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Inspecting the input failed: {:?}", err);
+                    std::process::exit(ExitCode::of_error(&err) as i32);
+                }
+            }
+            return;
+        }
+        None => {}
+    }
+
+    // Make sure there is an input file if no subcommand is given
+    if cli.inputs.is_empty() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "At least one file name argument must be provided as input",
+            )
+            .exit();
+    }
+    info!("Starting application on the files: {:?}", cli.inputs);
+
+    let code = match run(&cli, &cli.inputs) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Failed to run the application: {:?}", err);
+            ExitCode::of_error(&err)
+        }
+    };
+    if code != ExitCode::Success {
+        std::process::exit(code as i32);
+    }
 }
@@ -0,0 +1,284 @@
+/// An equivalence suite for the implementations of `AccountManager`, built with the `testing`
+/// feature like the reader conformance suite
+/// Generated workloads are applied both to the manager under test and to a reference
+/// `STAccountManager`, and the reports have to be identical: the same accounts with the same
+/// balances, and the same rejects for each account in the same order. The rejects of
+/// different accounts can be reported in any order, the multithreaded managers interleave
+/// them. The workloads are generated from seeds, so a failure can be reproduced with the seed
+/// of its message
+use rust_decimal::Decimal;
+
+use crate::{
+    account_manager::{AccountManager, STAccountManager},
+    records::{
+        wide_id, AccountKey, ClientId, TenantId, TransactionId, TransactionRecord, TransactionType,
+    },
+    report::Report,
+    workload::Generator,
+};
+
+/// The seeds each workload is generated from in `check_all`
+const SEEDS: u64 = 4;
+/// Records of each generated workload
+const WORKLOAD_RECORDS: usize = 20_000;
+
+/// Generates the records of a workload from a seed
+pub type Workload = fn(seed: u64) -> Vec<TransactionRecord>;
+
+/// The workloads of `check_all`, by name
+pub fn workloads() -> Vec<(&'static str, Workload)> {
+    vec![
+        ("deposits and withdrawals", deposits_and_withdrawals),
+        ("disputes", disputes),
+        ("locked accounts", locked_accounts),
+        ("invalid records", invalid_records),
+        ("tenants", tenants),
+        ("many clients", many_clients),
+    ]
+}
+
+/// Runs the workloads on the managers made by `new_manager`, a new one per workload
+pub struct ManagerConformance<M> {
+    new_manager: Box<dyn Fn() -> M>,
+    new_reference: Box<dyn Fn() -> STAccountManager>,
+}
+
+impl<M: AccountManager> ManagerConformance<M> {
+    pub fn new(new_manager: impl Fn() -> M + 'static) -> Self {
+        Self {
+            new_manager: Box::new(new_manager),
+            new_reference: Box::new(STAccountManager::new),
+        }
+    }
+
+    /// The reference manager, with the same options as the managers under test
+    pub fn with_reference(
+        mut self,
+        new_reference: impl Fn() -> STAccountManager + 'static,
+    ) -> Self {
+        self.new_reference = Box::new(new_reference);
+        self
+    }
+
+    pub fn check_all(&self) {
+        for (name, workload) in workloads() {
+            for seed in 0..SEEDS {
+                self.check_workload(name, workload, seed);
+            }
+        }
+    }
+
+    /// Panics at the first difference between the reports of the manager and the reference
+    pub fn check_workload(&self, name: &str, workload: Workload, seed: u64) {
+        let check = format!("{} (seed {})", name, seed);
+        let expected =
+            (self.new_reference)().execute_transactions(Box::new(workload(seed).into_iter()));
+        let report =
+            (self.new_manager)().execute_transactions(Box::new(workload(seed).into_iter()));
+        assert_same_reports(&check, &report, &expected);
+    }
+}
+
+/// Compares the accounts and the rejects of each account of two reports
+pub fn assert_same_reports(check: &str, report: &Report, expected: &Report) {
+    let balances = |report: &Report| {
+        let mut accounts: Vec<_> = report
+            .accounts()
+            .map(|account| {
+                (
+                    account.key(),
+                    account.available(),
+                    account.held(),
+                    account.total(),
+                    account.is_locked(),
+                )
+            })
+            .collect();
+        accounts.sort_by_key(|account| account.0);
+        accounts
+    };
+    let (accounts, expected_accounts) = (balances(report), balances(expected));
+    if let Some((account, expected)) = accounts
+        .iter()
+        .zip(&expected_accounts)
+        .find(|(account, expected)| account != expected)
+    {
+        panic!(
+            "{}: the account {:?} instead of {:?}",
+            check, account, expected
+        );
+    }
+    assert_eq!(
+        accounts.len(),
+        expected_accounts.len(),
+        "{}: wrong number of accounts",
+        check
+    );
+
+    // stable, the rejects of an account keep their order
+    let rejects = |report: &Report| {
+        let mut rejects: Vec<_> = report
+            .rejects()
+            .iter()
+            .map(|reject| {
+                (
+                    AccountKey::new(reject.tenant, reject.client),
+                    reject.tx,
+                    reject.tr_type,
+                    reject.reason.code(),
+                )
+            })
+            .collect();
+        rejects.sort_by_key(|reject| reject.0);
+        rejects
+    };
+    assert_eq!(rejects(report), rejects(expected), "{}: rejects", check);
+}
+
+fn record(
+    tr_type: TransactionType,
+    key: AccountKey,
+    tx: TransactionId,
+    amount: Option<Decimal>,
+) -> TransactionRecord {
+    TransactionRecord {
+        tr_type,
+        client: key.client,
+        tenant: key.tenant,
+        merchant: None,
+        tx,
+        amount,
+        timestamp: None,
+        source: None,
+    }
+}
+
+/// Deposits and withdrawals on a few clients, some of them overdrawing
+fn deposits_and_withdrawals(seed: u64) -> Vec<TransactionRecord> {
+    let mut generator = Generator::new(seed);
+    (1..=WORKLOAD_RECORDS as TransactionId)
+        .map(|tx| {
+            let key = AccountKey::from(generator.below(50) as ClientId);
+            let tr_type = if generator.below(3) == 0 {
+                TransactionType::Withdrawal
+            } else {
+                TransactionType::Deposit
+            };
+            record(tr_type, key, tx, Some(generator.amount()))
+        })
+        .collect()
+}
+
+/// The disputes, resolves and chargebacks of earlier transactions, of the same client or not
+fn disputes(seed: u64) -> Vec<TransactionRecord> {
+    disputed(seed, 200, 1)
+}
+
+/// Many chargebacks, the accounts get locked early and reject most of their records
+fn locked_accounts(seed: u64) -> Vec<TransactionRecord> {
+    disputed(seed, 20, 4)
+}
+
+/// `chargebacks` in 10 of the settlements of the disputes
+fn disputed(seed: u64, clients: u64, chargebacks: u64) -> Vec<TransactionRecord> {
+    let mut generator = Generator::new(seed);
+    let mut clients_of: Vec<ClientId> = Vec::new();
+    let mut records = Vec::with_capacity(WORKLOAD_RECORDS);
+    for tx in 1..=WORKLOAD_RECORDS as TransactionId {
+        let client = generator.below(clients) as ClientId;
+        let record = match generator.below(10) {
+            0..=3 => record(
+                TransactionType::Deposit,
+                client.into(),
+                tx,
+                Some(generator.amount()),
+            ),
+            4 => record(
+                TransactionType::Withdrawal,
+                client.into(),
+                tx,
+                Some(generator.amount()),
+            ),
+            choice => {
+                // an earlier transaction, mostly of the same client
+                let disputed = generator.below(wide_id(tx)) as TransactionId + 1;
+                let client = match clients_of.get(disputed as usize - 1) {
+                    Some(owner) if generator.below(8) != 0 => *owner,
+                    _ => client,
+                };
+                let tr_type = match choice {
+                    5 | 6 => TransactionType::Dispute,
+                    _ if generator.below(10) < chargebacks => TransactionType::ChargeBack,
+                    _ => TransactionType::Resolve,
+                };
+                record(tr_type, client.into(), disputed, None)
+            }
+        };
+        clients_of.push(record.client);
+        records.push(record);
+    }
+    records
+}
+
+/// Duplicated ids, missing and negative amounts, amounts of disputes
+fn invalid_records(seed: u64) -> Vec<TransactionRecord> {
+    let mut generator = Generator::new(seed);
+    (1..=WORKLOAD_RECORDS as TransactionId)
+        .map(|tx| {
+            let key = AccountKey::from(generator.below(30) as ClientId);
+            let tx = if generator.below(4) == 0 {
+                generator.below(wide_id(tx)) as TransactionId
+            } else {
+                tx
+            };
+            let amount = match generator.below(6) {
+                0 => None,
+                1 => Some(-generator.amount()),
+                _ => Some(generator.amount()),
+            };
+            let tr_type = match generator.below(8) {
+                0 => TransactionType::Withdrawal,
+                1 => TransactionType::Dispute,
+                2 => TransactionType::Resolve,
+                3 => TransactionType::Settle,
+                _ => TransactionType::Deposit,
+            };
+            record(tr_type, key, tx, amount)
+        })
+        .collect()
+}
+
+/// The same clients and transaction ids in several tenants, and without a tenant
+fn tenants(seed: u64) -> Vec<TransactionRecord> {
+    let mut records = disputed(seed, 10, 2);
+    let mut generator = Generator::new(seed);
+    for record in &mut records {
+        record.tenant = match generator.below(4) {
+            0 => None,
+            tenant => Some(tenant as TenantId),
+        };
+    }
+    records
+}
+
+/// A deposit and a withdrawal for each of many clients, spread over all the workers
+fn many_clients(seed: u64) -> Vec<TransactionRecord> {
+    let mut generator = Generator::new(seed);
+    let mut records = Vec::with_capacity(WORKLOAD_RECORDS);
+    for tx in (1..=WORKLOAD_RECORDS as TransactionId).step_by(2) {
+        let key = AccountKey::from((tx / 2) as ClientId);
+        records.push(record(
+            TransactionType::Deposit,
+            key,
+            tx,
+            Some(generator.amount()),
+        ));
+        records.push(record(
+            TransactionType::Withdrawal,
+            key,
+            tx + 1,
+            Some(generator.amount()),
+        ));
+    }
+    records
+}
@@ -0,0 +1,159 @@
+/// Approximate accounting of the memory used by a run, against the `--max-memory` budget
+/// The blocks queued between the reader and the account manager are held back when the budget
+/// is reached: the reader waits for them to be processed. The transaction history can't be
+/// held back that way, so once it alone exceeds the budget the run is aborted with a clear
+/// error, instead of being killed by the OOM killer
+use std::{
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How long the reader waits before checking the budget again
+const BACKPRESSURE_DELAY: Duration = Duration::from_millis(1);
+
+pub struct MemoryBudget {
+    limit: u64,
+    /// Bytes of the transaction history of all the accounts
+    history: AtomicI64,
+    /// Bytes of the blocks being parsed or waiting to be processed
+    queued: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            history: AtomicI64::new(0),
+            queued: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    /// Waits until `bytes` more can be queued without going over the budget
+    /// A block can always be queued when nothing else is, so the run keeps going
+    /// (slowly) even if the history takes most of the budget
+    pub fn reserve_queued(&self, bytes: u64) {
+        while self.queued() > 0 && self.used() + bytes > self.limit && !self.is_exceeded() {
+            std::thread::sleep(BACKPRESSURE_DELAY);
+        }
+        self.queued.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn release_queued(&self, bytes: u64) {
+        self.queued.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// The history grew (or shrank) by `delta` bytes
+    pub fn add_history(&self, delta: i64) {
+        let history = self.history.fetch_add(delta, Ordering::Relaxed) + delta;
+        if history.max(0) as u64 > self.limit {
+            self.exceeded.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The history doesn't fit in the budget anymore, the run has to stop
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn history(&self) -> u64 {
+        self.history.load(Ordering::Relaxed).max(0) as u64
+    }
+
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.history() + self.queued()
+    }
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix (powers of 1024)
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = match size.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((index, _)) => size.split_at(index),
+        None => (size, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("unknown size unit `{}`", unit)),
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size `{}`", size))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size `{}` is too big", size))
+}
+
+/// Formats a size in bytes with the biggest unit that keeps it above 1
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert_eq!(parse_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("64KB"), Ok(64 * 1024));
+        assert!(parse_size("12T").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(100), "100.0 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget = Arc::new(MemoryBudget::new(1000));
+        budget.reserve_queued(600);
+        budget.add_history(300);
+        assert_eq!(budget.used(), 900);
+
+        // waits for the queued block to be released
+        let reserve = std::thread::spawn({
+            let budget = budget.clone();
+            move || budget.reserve_queued(600)
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(budget.queued(), 600);
+        budget.release_queued(600);
+        reserve.join().unwrap();
+        assert_eq!(budget.queued(), 600);
+
+        assert!(!budget.is_exceeded());
+        budget.add_history(800);
+        assert!(budget.is_exceeded());
+        budget.add_history(-800);
+        assert_eq!(budget.history(), 300);
+    }
+}
@@ -0,0 +1,318 @@
+/// The merchant accounts credited by the `payment` records, for `--merchant-ledger`
+/// A payment debits its client and credits its merchant in the same step, on the worker of
+/// the client, so the merchants are kept in a ledger shared by all the workers. With a
+/// settlement period, the funds received by the merchants are paid out at every multiple of
+/// the period, in the unit of the timestamps, and each payout is an entry of their ledger
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use hashbrown::HashMap;
+use rust_decimal::Decimal;
+
+use crate::records::{AccountKey, TransactionId};
+
+/// What moved the funds of a merchant
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum LedgerEntryKind {
+    /// A payment of a client, credited to the merchant
+    Payment,
+    /// The funds received since the previous settlement, paid out to the merchant
+    Settlement,
+}
+
+impl LedgerEntryKind {
+    fn name(self) -> &'static str {
+        match self {
+            LedgerEntryKind::Payment => "payment",
+            LedgerEntryKind::Settlement => "settlement",
+        }
+    }
+}
+
+/// A movement of the funds of a merchant
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct LedgerEntry {
+    pub kind: LedgerEntryKind,
+    /// The id of the payment, a settlement has none
+    pub tx: Option<TransactionId>,
+    pub timestamp: Option<u64>,
+    pub amount: Decimal,
+    /// The funds not paid out yet, after the entry
+    pub unsettled: Decimal,
+}
+
+/// The funds of a merchant and their movements
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct MerchantAccount {
+    /// Received since the last settlement
+    pub unsettled: Decimal,
+    /// Paid out by all the settlements
+    pub settled: Decimal,
+    pub entries: Vec<LedgerEntry>,
+}
+
+impl MerchantAccount {
+    /// Pays out the funds received since the previous settlement
+    fn settle(&mut self, timestamp: u64) {
+        if self.unsettled.is_zero() {
+            return;
+        }
+        self.entries.push(LedgerEntry {
+            kind: LedgerEntryKind::Settlement,
+            tx: None,
+            timestamp: Some(timestamp),
+            amount: self.unsettled,
+            unsettled: Decimal::ZERO,
+        });
+        self.settled += self.unsettled;
+        self.unsettled = Decimal::ZERO;
+    }
+}
+
+/// The first settlement after `timestamp`
+fn settlement_after(timestamp: u64, period: u64) -> u64 {
+    (timestamp / period)
+        .saturating_add(1)
+        .saturating_mul(period)
+}
+
+struct LedgerState {
+    merchants: HashMap<AccountKey, MerchantAccount>,
+    /// The time of the next settlement, known from the first timestamp
+    next_settlement: Option<u64>,
+}
+
+/// The merchants of a run, shared by the workers
+pub struct MerchantLedger {
+    settlement_period: Option<u64>,
+    state: Mutex<LedgerState>,
+}
+
+impl MerchantLedger {
+    pub fn new() -> Self {
+        Self {
+            settlement_period: None,
+            state: Mutex::new(LedgerState {
+                merchants: HashMap::new(),
+                next_settlement: None,
+            }),
+        }
+    }
+
+    /// Pays out the merchants at every multiple of `period`, in the unit of the timestamps
+    pub fn with_settlement(mut self, period: u64) -> Self {
+        self.settlement_period = Some(period);
+        self
+    }
+
+    /// Credits the merchant with a payment, which can't fail
+    /// A payment from a worker behind the others may come after its settlement, it's paid
+    /// out right away then
+    pub fn credit(
+        &self,
+        merchant: AccountKey,
+        tx: TransactionId,
+        amount: Decimal,
+        timestamp: Option<u64>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let late = match (self.settlement_period, timestamp, state.next_settlement) {
+            (Some(period), Some(timestamp), Some(next)) => {
+                Some(settlement_after(timestamp, period)).filter(|settlement| *settlement < next)
+            }
+            _ => None,
+        };
+        let account = state.merchants.entry(merchant).or_default();
+        account.unsettled += amount;
+        let unsettled = account.unsettled;
+        account.entries.push(LedgerEntry {
+            kind: LedgerEntryKind::Payment,
+            tx: Some(tx),
+            timestamp,
+            amount,
+            unsettled,
+        });
+        if let Some(settlement) = late {
+            account.settle(settlement);
+        }
+    }
+
+    /// Settles the merchants at each multiple of the period up to `now`
+    pub fn advance(&self, now: u64) {
+        let period = match self.settlement_period {
+            Some(period) => period,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let mut next = *state
+            .next_settlement
+            .get_or_insert(settlement_after(now, period));
+        while next <= now {
+            for account in state.merchants.values_mut() {
+                account.settle(next);
+            }
+            next = next.saturating_add(period);
+            // no settlement past the last representable time
+            if next == u64::MAX {
+                break;
+            }
+        }
+        state.next_settlement = Some(next);
+    }
+
+    /// A copy of the account of a merchant, if it got a payment
+    pub fn merchant(&self, merchant: AccountKey) -> Option<MerchantAccount> {
+        self.state.lock().unwrap().merchants.get(&merchant).cloned()
+    }
+
+    /// Writes the entries of the merchants, ordered by merchant
+    pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let state = self.state.lock().unwrap();
+        let mut merchants: Vec<_> = state.merchants.iter().collect();
+        merchants.sort_by_key(|(key, _)| **key);
+        writeln!(
+            writer,
+            "tenant, merchant,       type,         tx,  timestamp,        amount,     unsettled"
+        )?;
+        for (key, account) in merchants {
+            let tenant = key
+                .tenant
+                .map_or_else(String::new, |tenant| tenant.to_string());
+            for entry in &account.entries {
+                writeln!(
+                    writer,
+                    "{:>6}, {:>8}, {:>10}, {:>10}, {:>10}, {:13.4}, {:13.4}",
+                    tenant,
+                    key.client,
+                    entry.kind.name(),
+                    entry.tx.map_or_else(String::new, |tx| tx.to_string()),
+                    entry
+                        .timestamp
+                        .map_or_else(String::new, |timestamp| timestamp.to_string()),
+                    entry.amount,
+                    entry.unsettled
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_file(&self, path: &Path) -> anyhow::Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write(&mut writer)
+            .and_then(|()| writer.flush())
+            .with_context(|| format!("Failed to write the merchant ledger {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, MTAccountManager, STAccountManager},
+        records::{ClientId, MerchantId, TransactionRecord, TransactionType},
+        report::Report,
+    };
+
+    use super::*;
+
+    fn record(
+        tr_type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+        merchant: Option<MerchantId>,
+        timestamp: u64,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client,
+            tenant: None,
+            merchant,
+            tx,
+            amount: Some(amount),
+            timestamp: Some(timestamp),
+            source: None,
+        }
+    }
+
+    // Clients 1 and 2 pay the merchant 7, which is settled every 100
+    fn payments(manager: impl AccountManager) -> Report {
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, dec!(10), None, 0),
+            record(TransactionType::Deposit, 2, 2, dec!(10), None, 0),
+            record(TransactionType::Payment, 1, 3, dec!(4), Some(7), 10),
+            record(TransactionType::Payment, 2, 4, dec!(20), Some(7), 20),
+            record(TransactionType::Payment, 2, 5, dec!(1), None, 30),
+            record(TransactionType::Payment, 2, 6, dec!(2.5), Some(7), 150),
+            record(TransactionType::Deposit, 1, 7, dec!(1), None, 210),
+        ];
+        manager.execute_transactions(Box::new(records.into_iter()))
+    }
+
+    #[test]
+    fn test_payments() {
+        let check = |report: Report, ledger: &MerchantLedger| {
+            assert_eq!(report.account(1).unwrap().total(), dec!(7));
+            assert_eq!(report.account(2).unwrap().total(), dec!(7.5));
+            let reasons: Vec<_> = report
+                .rejects()
+                .iter()
+                .map(|reject| reject.reason.code())
+                .collect();
+            assert_eq!(reasons, vec!["insufficient_funds", "missing_merchant"]);
+
+            let merchant = ledger.merchant(AccountKey::from(7)).unwrap();
+            assert_eq!(merchant.settled, dec!(6.5));
+            assert_eq!(merchant.unsettled, dec!(0));
+            let kinds: Vec<_> = merchant.entries.iter().map(|entry| entry.kind).collect();
+            assert_eq!(
+                kinds,
+                vec![
+                    LedgerEntryKind::Payment,
+                    LedgerEntryKind::Settlement,
+                    LedgerEntryKind::Payment,
+                    LedgerEntryKind::Settlement
+                ]
+            );
+            assert!(ledger.merchant(AccountKey::from(1)).is_none());
+        };
+
+        let ledger = Arc::new(MerchantLedger::new().with_settlement(100));
+        let report = payments(STAccountManager::new().with_merchant_ledger(ledger.clone()));
+        check(report, &ledger);
+
+        let ledger = Arc::new(MerchantLedger::new().with_settlement(100));
+        let report = payments(MTAccountManager::new(2).with_merchant_ledger(ledger.clone()));
+        check(report, &ledger);
+    }
+
+    #[test]
+    fn test_write_ledger() {
+        let ledger = MerchantLedger::new().with_settlement(100);
+        ledger.credit(AccountKey::new(Some(2), 7), 3, dec!(4), Some(10));
+        ledger.advance(10);
+        ledger.advance(120);
+        ledger.credit(AccountKey::new(Some(2), 7), 4, dec!(1.5), None);
+
+        let mut output = Vec::new();
+        ledger.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tenant, merchant,       type,         tx,  timestamp,        amount,     unsettled\n\
+             \x20    2,        7,    payment,          3,         10,        4.0000,        4.0000\n\
+             \x20    2,        7, settlement,           ,        100,        4.0000,        0.0000\n\
+             \x20    2,        7,    payment,          4,           ,        1.5000,        1.5000\n"
+        );
+    }
+}
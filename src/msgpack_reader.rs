@@ -0,0 +1,226 @@
+/// Reads transactions from a stream of length-prefixed MessagePack frames
+/// Each frame is a big-endian u32 length followed by a record encoded as a map
+/// (or an array) with the same fields as the CSV columns.
+/// Framing makes it a better fit than CSV for message based sources such as sockets
+use std::{
+    io::{BufReader, ErrorKind, Read},
+    sync::Arc,
+};
+
+use log::*;
+
+use crate::{
+    records::{RecordSource, TransactionRecord},
+    stats::PipelineStats,
+    transactions_reader::{TransactionCSVReader, TransactionsStream},
+};
+
+/// Frames bigger than that can't be a transaction, the stream is most likely corrupted
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+/// How often the number of read records is published
+const STATS_BATCH: u64 = 64 * 1024;
+
+#[derive(Clone)]
+pub struct MsgPackReader {
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+}
+
+impl MsgPackReader {
+    pub fn new() -> Self {
+        Self {
+            stats: None,
+            source: None,
+        }
+    }
+
+    /// Publish the number of read records to the run statistics
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Reads the frames from any byte stream, until it ends or a frame is broken
+    pub fn read_frames(self, reader: impl Read + Send + 'static) -> TransactionsStream {
+        Box::new(MsgPackFrames {
+            reader,
+            frame: Vec::new(),
+            position: 0,
+            stats: self.stats,
+            source: self.source,
+            records: 0,
+            parse_errors: 0,
+        })
+    }
+}
+
+impl TransactionCSVReader for MsgPackReader {
+    fn read_from<R: Read + Send + 'static>(self, reader: R) -> anyhow::Result<TransactionsStream> {
+        Ok(self.read_frames(BufReader::new(reader)))
+    }
+
+    fn with_source(mut self, input: Arc<str>) -> Self {
+        self.source = Some(input);
+        self
+    }
+}
+
+struct MsgPackFrames<R: Read> {
+    reader: R,
+    frame: Vec<u8>,
+    /// Number of frames read so far
+    position: u64,
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+    /// Counters not published yet
+    records: u64,
+    parse_errors: u64,
+}
+
+impl<R: Read> MsgPackFrames<R> {
+    /// Reads the next frame into the buffer, returns false at the end of the stream
+    fn read_frame(&mut self) -> anyhow::Result<bool> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(anyhow::anyhow!("Frame of {} bytes is too big", len));
+        }
+        self.frame.resize(len, 0);
+        self.reader.read_exact(&mut self.frame)?;
+        Ok(true)
+    }
+
+    fn publish_stats(&mut self) {
+        if let Some(stats) = &self.stats {
+            stats.add_read(self.records, self.parse_errors);
+        }
+        self.records = 0;
+        self.parse_errors = 0;
+    }
+}
+
+impl<R: Read> Iterator for MsgPackFrames<R> {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        loop {
+            match self.read_frame() {
+                Ok(true) => self.position += 1,
+                Ok(false) => {
+                    self.publish_stats();
+                    return None;
+                }
+                Err(err) => {
+                    error!("Failed to read a MessagePack frame: {:?}", err);
+                    if let Some(stats) = &self.stats {
+                        stats.add_input_error();
+                    }
+                    self.publish_stats();
+                    return None;
+                }
+            }
+
+            // for simplicity, ignore frames that cannot be parsed
+            match rmp_serde::from_slice::<TransactionRecord>(&self.frame) {
+                Ok(mut record) => {
+                    record.source = RecordSource::tag(self.source.as_ref(), self.position);
+                    self.records += 1;
+                    if self.records + self.parse_errors >= STATS_BATCH {
+                        self.publish_stats();
+                    }
+                    return Some(record);
+                }
+                Err(_) => self.parse_errors += 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use serde::Serialize;
+
+    use crate::records::TransactionType;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Frame<'a> {
+        #[serde(rename = "type")]
+        tr_type: &'a str,
+        client: i64,
+        tx: u32,
+        amount: Option<f64>,
+    }
+
+    fn write_frame(data: &mut Vec<u8>, frame: &Frame) {
+        let frame = rmp_serde::to_vec_named(frame).unwrap();
+        data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        data.extend_from_slice(&frame);
+    }
+
+    #[test]
+    fn test_msgpack_frames() {
+        let mut data = Vec::new();
+        let frames = [
+            ("deposit", 1, 1, Some(2.5)),
+            ("withdrawal", 1, 2, Some(1.25)),
+            ("bogus", 1, 3, None),
+            ("dispute", -1, 1, None),
+            ("dispute", 1, 1, None),
+        ];
+        for (tr_type, client, tx, amount) in frames {
+            write_frame(
+                &mut data,
+                &Frame {
+                    tr_type,
+                    client,
+                    tx,
+                    amount,
+                },
+            );
+        }
+
+        let stats = Arc::new(PipelineStats::new());
+        let records: Vec<TransactionRecord> = MsgPackReader::new()
+            .with_stats(stats.clone())
+            .read_frames(std::io::Cursor::new(data))
+            .collect();
+
+        // the unknown type and the client id out of range are skipped
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].tr_type, TransactionType::Deposit);
+        assert_eq!(records[0].amount, Some(dec!(2.5)));
+        assert_eq!(records[1].amount, Some(dec!(1.25)));
+        assert_eq!(records[2].tr_type, TransactionType::Dispute);
+        assert_eq!(records[2].amount, None);
+        assert_eq!(stats.records_read(), 3);
+        assert_eq!(stats.parse_errors(), 2);
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut data = Vec::new();
+        let frame = Frame {
+            tr_type: "deposit",
+            client: 1,
+            tx: 1,
+            amount: Some(1.0),
+        };
+        write_frame(&mut data, &frame);
+        write_frame(&mut data, &frame);
+        data.truncate(data.len() - 3);
+
+        let records: Vec<TransactionRecord> = MsgPackReader::new()
+            .read_frames(std::io::Cursor::new(data))
+            .collect();
+        assert_eq!(records.len(), 1);
+    }
+}
@@ -0,0 +1,346 @@
+/// Reads several inputs as a single stream of transactions
+/// By default the inputs are read one after another, which serializes big multi-file runs
+/// on a single reader. In the parallel mode every input gets its own reader pipeline and
+/// their records are interleaved, only keeping the order of the records of each input.
+/// When the records have timestamps, the inputs can also be merged in chronological order
+use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc};
+
+use log::*;
+
+use crate::{
+    clock::Clock,
+    input::Input,
+    rate_limit,
+    records::TransactionRecord,
+    stats::PipelineStats,
+    transactions_reader::{TransactionCSVReader, TransactionsStream},
+};
+
+/// Records are forwarded in batches, to limit the contention on the merge channel
+const MERGE_BATCH_SIZE: usize = 1024;
+/// Number of batches waiting to be processed, for all the inputs
+const MERGE_QUEUE_SIZE: usize = 256;
+
+/// How the records of several inputs are combined
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ReadMode {
+    /// The inputs are read one after another, in the given order
+    Sequential,
+    /// The inputs are read concurrently, the records of each input stay in order
+    Parallel,
+    /// The records of the inputs, each one already sorted, are merged by timestamp
+    Timestamp,
+}
+
+/// Reads all the `inputs` with clones of the `reader`
+/// With `tag_sources`, the records are tagged with the input and their position in it
+/// With `input_rate`, the records of each input are limited to that many per second of the `clock`
+/// The inputs that fail after the first one are counted in the `stats`
+pub fn read_inputs<R>(
+    inputs: Vec<Input>,
+    reader: R,
+    mode: ReadMode,
+    tag_sources: bool,
+    input_rate: Option<u64>,
+    clock: Arc<dyn Clock>,
+    stats: Option<Arc<PipelineStats>>,
+) -> anyhow::Result<TransactionsStream>
+where
+    R: TransactionCSVReader + Clone + Send + 'static,
+{
+    let read = move |input: Input| -> anyhow::Result<TransactionsStream> {
+        let reader = if tag_sources {
+            reader.clone().with_source(input.name().clone())
+        } else {
+            reader.clone()
+        };
+        let transactions = input.read_with(reader)?;
+        Ok(match input_rate {
+            Some(rate) => rate_limit::limit(transactions, rate, clock.clone()),
+            None => transactions,
+        })
+    };
+
+    let mut inputs = inputs.into_iter();
+    let first = match inputs.next() {
+        Some(input) => read(input)?,
+        None => return Ok(Box::new(std::iter::empty())),
+    };
+
+    match mode {
+        ReadMode::Sequential => {
+            // the next inputs are only opened once the previous ones are processed
+            let rest = inputs.flat_map(move |input| match read(input) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Failed to read an input: {:?}", err);
+                    if let Some(stats) = &stats {
+                        stats.add_input_error();
+                    }
+                    Box::new(std::iter::empty())
+                }
+            });
+            Ok(Box::new(first.chain(rest)))
+        }
+        ReadMode::Parallel | ReadMode::Timestamp => {
+            let mut streams = vec![first];
+            for input in inputs {
+                streams.push(read(input)?);
+            }
+            Ok(if mode == ReadMode::Parallel {
+                merge_parallel(streams)
+            } else {
+                Box::new(TimestampMerge::new(streams))
+            })
+        }
+    }
+}
+
+/// Drains every stream on its own thread into a single stream
+fn merge_parallel(streams: Vec<TransactionsStream>) -> TransactionsStream {
+    let (batch_tx, batch_rx) =
+        crossbeam_channel::bounded::<Vec<TransactionRecord>>(MERGE_QUEUE_SIZE);
+    for (index, mut stream) in streams.into_iter().enumerate() {
+        let batch_tx = batch_tx.clone();
+        std::thread::Builder::new()
+            .name(format!("merge {}", index))
+            .spawn(move || loop {
+                let batch: Vec<TransactionRecord> =
+                    stream.by_ref().take(MERGE_BATCH_SIZE).collect();
+                if batch.is_empty() || batch_tx.send(batch).is_err() {
+                    break;
+                }
+            })
+            .expect("Failed to start a merge thread");
+    }
+
+    Box::new(batch_rx.into_iter().flatten())
+}
+
+/// A k-way merge of sorted streams, the record with the earliest timestamp comes first
+/// The ties are broken by the order of the inputs, so that a dispute in the second input
+/// comes after the deposit with the same timestamp in the first one
+struct TimestampMerge {
+    streams: Vec<TransactionsStream>,
+    /// The next record of each stream
+    heads: Vec<Option<TransactionRecord>>,
+    /// Timestamp and index of the streams with a head, earliest first
+    queue: BinaryHeap<Reverse<(u64, usize)>>,
+    /// Timestamp of the last record of each stream
+    last: Vec<u64>,
+}
+
+impl TimestampMerge {
+    fn new(streams: Vec<TransactionsStream>) -> Self {
+        let mut merge = Self {
+            heads: streams.iter().map(|_| None).collect(),
+            queue: BinaryHeap::with_capacity(streams.len()),
+            last: vec![0; streams.len()],
+            streams,
+        };
+        for index in 0..merge.streams.len() {
+            merge.advance(index);
+        }
+        merge
+    }
+
+    /// Loads the next record of a stream
+    fn advance(&mut self, index: usize) {
+        if let Some(record) = self.streams[index].next() {
+            // records without a timestamp stay right after the previous one of their input
+            let timestamp = record.timestamp.unwrap_or(self.last[index]);
+            if timestamp < self.last[index] {
+                warn!(
+                    "Input {} is not sorted, tx {} is earlier than the previous record",
+                    index + 1,
+                    record.tx
+                );
+            }
+            self.last[index] = timestamp;
+            self.heads[index] = Some(record);
+            self.queue.push(Reverse((timestamp, index)));
+        }
+    }
+}
+
+impl Iterator for TimestampMerge {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        let Reverse((_, index)) = self.queue.pop()?;
+        let record = self.heads[index].take();
+        self.advance(index);
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::{
+        clock,
+        records::{ClientId, TransactionId},
+        transactions_reader::{MTReader, STBulkReader},
+    };
+
+    use super::*;
+
+    /// Writes `count` deposits of the `client`, numbered from 1
+    fn write_input(name: &str, client: ClientId, count: TransactionId) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        let mut data = String::from("type, client, tx, amount\n");
+        for tx in 1..=count {
+            data += &format!("deposit, {}, {}, 1.0\n", client, tx);
+        }
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn inputs(paths: &[&Path]) -> Vec<Input> {
+        paths
+            .iter()
+            .map(|path| Input::open(path).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_sequential_inputs() {
+        let first = write_input("paytoy_test_sequential_1.csv", 1, 3);
+        let second = write_input("paytoy_test_sequential_2.csv", 2, 2);
+        let records: Vec<(ClientId, TransactionId)> = read_inputs(
+            inputs(&[&first, &second]),
+            STBulkReader::new(),
+            ReadMode::Sequential,
+            false,
+            None,
+            clock::system(),
+            None,
+        )
+        .unwrap()
+        .map(|record| (record.client, record.tx))
+        .collect();
+
+        assert_eq!(records, vec![(1, 1), (1, 2), (1, 3), (2, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn test_tagged_sources() {
+        let first = write_input("paytoy_test_tagged_1.csv", 1, 2);
+        let second = write_input("paytoy_test_tagged_2.csv", 2, 1);
+        let sources: Vec<(String, u64)> = read_inputs(
+            inputs(&[&first, &second]),
+            MTReader::new().with_threads(2),
+            ReadMode::Sequential,
+            true,
+            None,
+            clock::system(),
+            None,
+        )
+        .unwrap()
+        .map(|record| {
+            let source = record.source.unwrap();
+            (source.input.to_string(), source.line)
+        })
+        .collect();
+
+        let first = first.to_string_lossy().to_string();
+        let second = second.to_string_lossy().to_string();
+        assert_eq!(sources, vec![(first.clone(), 2), (first, 3), (second, 2)]);
+    }
+
+    #[test]
+    fn test_parallel_inputs_keep_the_order_of_each_input() {
+        let count = 20_000;
+        let paths: Vec<PathBuf> = (1..=3)
+            .map(|client| {
+                write_input(
+                    &format!("paytoy_test_parallel_{}.csv", client),
+                    client,
+                    count,
+                )
+            })
+            .collect();
+        let paths: Vec<&Path> = paths.iter().map(|path| path.as_path()).collect();
+
+        let records: Vec<TransactionRecord> = read_inputs(
+            inputs(&paths),
+            MTReader::new().with_threads(2),
+            ReadMode::Parallel,
+            false,
+            None,
+            clock::system(),
+            None,
+        )
+        .unwrap()
+        .collect();
+
+        assert_eq!(records.len(), 3 * count as usize);
+        for client in 1..=3 {
+            let txs: Vec<TransactionId> = records
+                .iter()
+                .filter(|record| record.client == client)
+                .map(|record| record.tx)
+                .collect();
+            assert_eq!(txs, (1..=count).collect::<Vec<TransactionId>>());
+        }
+    }
+
+    #[test]
+    fn test_timestamp_merge() {
+        let write = |name: &str, data: &str| {
+            let path = std::env::temp_dir().join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        let first = write(
+            "paytoy_test_timestamp_1.csv",
+            "type, client, tx, amount, timestamp\n\
+             deposit, 1, 1, 1.0, 10\n\
+             deposit, 1, 2, 2.0, 30\n\
+             withdrawal, 1, 3, 1.0, 30\n",
+        );
+        // the columns don't have to be in the same order
+        let second = write(
+            "paytoy_test_timestamp_2.csv",
+            "timestamp, type, client, tx, amount\n\
+             20, dispute, 1, 1,\n\
+             30, resolve, 1, 1,\n\
+             , deposit, 2, 4, 1.0\n",
+        );
+
+        for reader_threads in [1, 4] {
+            let records: Vec<TransactionId> = read_inputs(
+                inputs(&[&first, &second]),
+                MTReader::new().with_threads(reader_threads),
+                ReadMode::Timestamp,
+                false,
+                None,
+                clock::system(),
+                None,
+            )
+            .unwrap()
+            .map(|record| record.tx)
+            .collect();
+            assert_eq!(records, vec![1, 1, 2, 3, 1, 4]);
+        }
+    }
+
+    #[test]
+    fn test_missing_input() {
+        let first = write_input("paytoy_test_missing_1.csv", 1, 1);
+        assert!(read_inputs(
+            vec![
+                Input::open(&first).unwrap(),
+                Input::open(Path::new("tests/data/missing.csv")).unwrap()
+            ],
+            STBulkReader::new(),
+            ReadMode::Parallel,
+            false,
+            None,
+            clock::system(),
+            None,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,174 @@
+/// Streams inputs from object stores (S3, GCS, Azure)
+/// The credentials and the region are taken from the usual environment variables of each cloud
+/// A download that fails or goes silent is resumed from the last received byte
+use std::{io::Read, sync::Arc};
+
+use futures::StreamExt;
+use log::*;
+use object_store::{
+    aws::AmazonS3Builder, azure::MicrosoftAzureBuilder, gcp::GoogleCloudStorageBuilder, path::Path,
+    BackoffConfig, ClientOptions, GetOptions, GetRange, ObjectStore, ObjectStoreScheme,
+    RetryConfig,
+};
+use url::Url;
+
+use crate::{
+    errors::UsageError,
+    input::{ChannelReader, StreamPolicy},
+};
+
+/// Number of downloaded chunks waiting to be read
+const CHUNK_QUEUE_SIZE: usize = 64;
+
+pub fn open(url: &str, policy: &StreamPolicy) -> anyhow::Result<Box<dyn Read + Send>> {
+    let url = Url::parse(url)?;
+    let (scheme, path) = ObjectStoreScheme::parse(&url)?;
+    // the whole download isn't bounded in time, only the wait for each chunk
+    let options = ClientOptions::new()
+        .with_connect_timeout(policy.connect_timeout)
+        .with_timeout_disabled();
+    // the requests that fail are retried by the store itself
+    let retry = RetryConfig {
+        backoff: BackoffConfig {
+            init_backoff: policy.retry_delay,
+            max_backoff: policy.max_retry_delay,
+            base: 2.,
+        },
+        max_retries: policy.retries as usize,
+        ..RetryConfig::default()
+    };
+    let store: Arc<dyn ObjectStore> = match scheme {
+        ObjectStoreScheme::AmazonS3 => Arc::new(
+            AmazonS3Builder::from_env()
+                .with_url(url.as_str())
+                .with_client_options(options)
+                .with_retry(retry)
+                .build()?,
+        ),
+        ObjectStoreScheme::GoogleCloudStorage => Arc::new(
+            GoogleCloudStorageBuilder::from_env()
+                .with_url(url.as_str())
+                .with_client_options(options)
+                .with_retry(retry)
+                .build()?,
+        ),
+        ObjectStoreScheme::MicrosoftAzure => Arc::new(
+            MicrosoftAzureBuilder::from_env()
+                .with_url(url.as_str())
+                .with_client_options(options)
+                .with_retry(retry)
+                .build()?,
+        ),
+        _ => return Err(UsageError(format!("Unsupported object store URL `{}`", url)).into()),
+    };
+
+    read_object(store, path, policy.clone())
+}
+
+/// Downloads the object on a separate thread, the chunks are handed over as they arrive
+fn read_object(
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    policy: StreamPolicy,
+) -> anyhow::Result<Box<dyn Read + Send>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    // fail early if the object can't be read
+    let object = runtime.block_on(store.get(&path))?;
+
+    let (chunk_tx, chunk_rx) = crossbeam_channel::bounded(CHUNK_QUEUE_SIZE);
+    let download_thread = std::thread::Builder::new().name("download".to_string());
+    download_thread.spawn(move || {
+        runtime.block_on(async {
+            let mut chunks = object.into_stream();
+            // the bytes received so far, and the failed attempts since the last chunk
+            let mut offset = 0;
+            let mut attempt = 0;
+            loop {
+                let err = match tokio::time::timeout(policy.read_timeout, chunks.next()).await {
+                    Ok(None) => break,
+                    Ok(Some(Ok(chunk))) => {
+                        offset += chunk.len() as u64;
+                        attempt = 0;
+                        if chunk_tx.send(Ok(chunk.to_vec())).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(Some(Err(err))) => err.to_string(),
+                    Err(_) => format!("no data for {:?}", policy.read_timeout),
+                };
+
+                attempt += 1;
+                if attempt > policy.retries {
+                    let _ = chunk_tx.send(Err(std::io::Error::other(format!(
+                        "Download of {} failed at byte {} after {} retries: {}",
+                        path, offset, policy.retries, err
+                    ))));
+                    break;
+                }
+                let delay = policy.backoff(attempt);
+                warn!(
+                    "Download of {} interrupted at byte {}, resuming in {:?} ({}/{}): {}",
+                    path, offset, delay, attempt, policy.retries, err
+                );
+                tokio::time::sleep(delay).await;
+                let options = GetOptions {
+                    range: Some(GetRange::Offset(offset)),
+                    ..GetOptions::default()
+                };
+                // a failed request is another attempt, the stream left fails right away
+                chunks = match store.get_opts(&path, options).await {
+                    Ok(object) => object.into_stream(),
+                    Err(err) => futures::stream::once(async { Err(err) }).boxed(),
+                };
+            }
+        });
+        // the store must outlive the download
+        drop(store);
+    })?;
+
+    Ok(Box::new(ChannelReader::new(chunk_rx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::{memory::InMemory, PutPayload};
+
+    use crate::{
+        records::TransactionRecord,
+        transactions_reader::{MTReader, TransactionCSVReader},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_read_object() {
+        let store = Arc::new(InMemory::new());
+        let path = Path::from("dumps/transactions.csv");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(store.put(
+                &path,
+                PutPayload::from(std::fs::read("tests/data/test_basic.csv").unwrap()),
+            ))
+            .unwrap();
+
+        let input = read_object(store, path, StreamPolicy::default()).unwrap();
+        let records: Vec<TransactionRecord> = MTReader::new()
+            .with_threads(2)
+            .read_from(input)
+            .unwrap()
+            .collect();
+        assert_eq!(records.len(), 5);
+    }
+
+    #[test]
+    fn test_missing_object() {
+        let store = Arc::new(InMemory::new());
+        assert!(read_object(store, Path::from("missing.csv"), StreamPolicy::default()).is_err());
+    }
+}
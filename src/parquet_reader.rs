@@ -0,0 +1,273 @@
+/// Reads transactions from Parquet files with the same columns as the CSV files
+/// The column types don't have to match exactly, values are casted to the record types
+use std::{convert::TryFrom, io::Read, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use arrow_array::{
+    cast::AsArray,
+    types::{ArrowPrimitiveType, Int64Type},
+    Array, ArrayRef, RecordBatch,
+};
+use arrow_cast::cast;
+use arrow_schema::DataType;
+use log::*;
+use parquet::{arrow::arrow_reader::ParquetRecordBatchReaderBuilder, file::reader::ChunkReader};
+use rust_decimal::Decimal;
+
+use crate::{
+    records::{RecordSource, TransactionRecord, TransactionType},
+    stats::PipelineStats,
+    transactions_reader::{TransactionCSVReader, TransactionsStream},
+};
+
+/// The Arrow types of the ids, as wide as `ClientId` and `TransactionId`
+#[cfg(not(any(feature = "client-id-u32", feature = "client-id-u64")))]
+pub(crate) type ClientIdType = arrow_array::types::UInt16Type;
+#[cfg(all(feature = "client-id-u32", not(feature = "client-id-u64")))]
+pub(crate) type ClientIdType = arrow_array::types::UInt32Type;
+#[cfg(feature = "client-id-u64")]
+pub(crate) type ClientIdType = arrow_array::types::UInt64Type;
+#[cfg(not(feature = "tx-id-u64"))]
+type TransactionIdType = arrow_array::types::UInt32Type;
+#[cfg(feature = "tx-id-u64")]
+type TransactionIdType = arrow_array::types::UInt64Type;
+
+/// A single threaded reader, decoding one record batch at a time
+#[derive(Clone)]
+pub struct ParquetReader {
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+}
+
+impl ParquetReader {
+    pub fn new() -> Self {
+        Self {
+            stats: None,
+            source: None,
+        }
+    }
+
+    /// Publish the number of read records to the run statistics
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+}
+
+impl TransactionCSVReader for ParquetReader {
+    fn read_csv<P: AsRef<Path>>(self, path: P) -> anyhow::Result<TransactionsStream> {
+        self.read_chunks(std::fs::File::open(path)?)
+    }
+
+    /// The footer is at the end of a Parquet file, so streams are buffered in memory first
+    fn read_from<R: Read + Send + 'static>(
+        self,
+        mut reader: R,
+    ) -> anyhow::Result<TransactionsStream> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        self.read_chunks(bytes::Bytes::from(data))
+    }
+
+    fn with_source(mut self, input: Arc<str>) -> Self {
+        self.source = Some(input);
+        self
+    }
+}
+
+impl ParquetReader {
+    fn read_chunks(self, chunks: impl ChunkReader + 'static) -> anyhow::Result<TransactionsStream> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(chunks)?;
+
+        // fail early instead of on every batch
+        for column in ["type", "client", "tx"] {
+            builder
+                .schema()
+                .field_with_name(column)
+                .with_context(|| format!("Missing column `{}`", column))?;
+        }
+
+        let batches = builder.build()?;
+        let stats = self.stats;
+        let source = self.source;
+        let mut rows = 0;
+        Ok(Box::new(batches.flat_map(move |batch| {
+            let first_row = rows + 1;
+            let records = batch.map_err(anyhow::Error::from).and_then(|batch| {
+                rows += batch.num_rows() as u64;
+                let tag = |row| RecordSource::tag(source.as_ref(), first_row + row as u64);
+                records_from_batch(&batch, tag)
+            });
+            match records {
+                Ok((records, parse_errors)) => {
+                    if let Some(stats) = &stats {
+                        stats.add_read(records.len() as u64, parse_errors);
+                    }
+                    records
+                }
+                Err(err) => {
+                    error!("Failed to read a Parquet record batch: {:?}", err);
+                    if let Some(stats) = &stats {
+                        stats.add_input_error();
+                    }
+                    Vec::new()
+                }
+            }
+        })))
+    }
+}
+
+/// Converts a batch to transaction records
+/// Returns the records and the number of rows that couldn't be converted
+fn records_from_batch(
+    batch: &RecordBatch,
+    tag: impl Fn(usize) -> Option<RecordSource>,
+) -> anyhow::Result<(Vec<TransactionRecord>, u64)> {
+    let column = |name: &str, data_type: &DataType| -> anyhow::Result<Option<ArrayRef>> {
+        match batch.column_by_name(name) {
+            // invalid values become nulls
+            Some(column) => Ok(Some(cast(column, data_type)?)),
+            None => Ok(None),
+        }
+    };
+    let required = |name: &str, data_type: &DataType| -> anyhow::Result<ArrayRef> {
+        column(name, data_type)?.with_context(|| format!("Missing column `{}`", name))
+    };
+
+    let types = required("type", &DataType::Utf8)?;
+    let types = types.as_string::<i32>();
+    let clients = required("client", &ClientIdType::DATA_TYPE)?;
+    let clients = clients.as_primitive::<ClientIdType>();
+    let txs = required("tx", &TransactionIdType::DATA_TYPE)?;
+    let txs = txs.as_primitive::<TransactionIdType>();
+    let amounts = column("amount", &DataType::Utf8)?;
+    let amounts = amounts.as_ref().map(|amounts| amounts.as_string::<i32>());
+    let timestamps = column("timestamp", &DataType::Int64)?;
+    let timestamps = timestamps
+        .as_ref()
+        .map(|timestamps| timestamps.as_primitive::<Int64Type>());
+
+    let mut records = Vec::with_capacity(batch.num_rows());
+    let mut parse_errors = 0;
+    for row in 0..batch.num_rows() {
+        if types.is_null(row) || clients.is_null(row) || txs.is_null(row) {
+            parse_errors += 1;
+            continue;
+        }
+
+        let tr_type = match TransactionType::from_str(types.value(row).trim()) {
+            Ok(tr_type) => tr_type,
+            Err(_) => {
+                parse_errors += 1;
+                continue;
+            }
+        };
+
+        let amount = match amounts {
+            Some(amounts) if !amounts.is_null(row) => {
+                let amount = amounts.value(row).trim();
+                match Decimal::from_str(amount).or_else(|_| Decimal::from_scientific(amount)) {
+                    Ok(amount) => Some(amount),
+                    Err(_) => {
+                        parse_errors += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        records.push(TransactionRecord {
+            tr_type,
+            client: clients.value(row),
+            tenant: None,
+            merchant: None,
+            tx: txs.value(row),
+            amount,
+            timestamp: timestamps
+                .filter(|timestamps| !timestamps.is_null(row))
+                .and_then(|timestamps| u64::try_from(timestamps.value(row)).ok()),
+            source: tag(row),
+        });
+    }
+
+    Ok((records, parse_errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{Float64Array, Int64Array, StringArray};
+    use parquet::arrow::ArrowWriter;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_parquet_reader() {
+        let batch = RecordBatch::try_from_iter(vec![
+            (
+                "type",
+                Arc::new(StringArray::from(vec![
+                    "deposit",
+                    "withdrawal",
+                    "bogus",
+                    "dispute",
+                ])) as ArrayRef,
+            ),
+            (
+                "client",
+                Arc::new(Int64Array::from(vec![1, 1, 1, -1])) as ArrayRef,
+            ),
+            (
+                "tx",
+                Arc::new(Int64Array::from(vec![1, 2, 3, 1])) as ArrayRef,
+            ),
+            (
+                "amount",
+                Arc::new(Float64Array::from(vec![Some(2.5), Some(1.25), None, None])) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+
+        let path = std::env::temp_dir().join("paytoy_test_input.parquet");
+        let mut writer =
+            ArrowWriter::try_new(std::fs::File::create(&path).unwrap(), batch.schema(), None)
+                .unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let stats = Arc::new(PipelineStats::new());
+        let records: Vec<TransactionRecord> = ParquetReader::new()
+            .with_stats(stats.clone())
+            .read_csv(&path)
+            .unwrap()
+            .collect();
+
+        // the unknown type and the client id out of range are skipped
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].tr_type, TransactionType::Deposit);
+        assert_eq!(records[0].amount, Some(dec!(2.5)));
+        assert_eq!(records[1].tr_type, TransactionType::Withdrawal);
+        assert_eq!(records[1].tx, 2);
+        assert_eq!(records[1].amount, Some(dec!(1.25)));
+        assert_eq!(stats.parse_errors(), 2);
+    }
+
+    #[test]
+    fn test_missing_column() {
+        let batch = RecordBatch::try_from_iter(vec![(
+            "type",
+            Arc::new(StringArray::from(vec!["deposit"])) as ArrayRef,
+        )])
+        .unwrap();
+
+        let path = std::env::temp_dir().join("paytoy_test_missing_column.parquet");
+        let mut writer =
+            ArrowWriter::try_new(std::fs::File::create(&path).unwrap(), batch.schema(), None)
+                .unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        assert!(ParquetReader::new().read_csv(&path).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+/// Writes the report as a Parquet file, so it can be loaded by analytics tools directly
+use std::{io::Write, sync::Arc};
+
+use arrow_array::{
+    builder::{BooleanBuilder, Decimal128Builder, PrimitiveBuilder, UInt16Builder},
+    types::ArrowPrimitiveType,
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use rust_decimal::Decimal;
+
+use crate::{client_account::ClientAccount, parquet_reader::ClientIdType};
+
+/// Number of accounts in a single record batch
+const BATCH_SIZE: usize = 64 * 1024;
+/// Widest precision supported by 128 bit decimals
+const DECIMAL_PRECISION: u8 = 38;
+
+/// Writes the accounts with the amounts stored as decimals with `scale` decimal places
+/// A nullable `tenant` column comes first when any account has a tenant
+pub fn write_parquet<'a>(
+    writer: impl Write + Send,
+    accounts: impl Iterator<Item = &'a ClientAccount> + Clone,
+    scale: usize,
+) -> anyhow::Result<()> {
+    let scale = scale.min(DECIMAL_PRECISION as usize) as u32;
+    let amount_type = DataType::Decimal128(DECIMAL_PRECISION, scale as i8);
+    let with_tenant = accounts.clone().any(|account| account.tenant().is_some());
+    let mut fields = Vec::with_capacity(6);
+    if with_tenant {
+        fields.push(Field::new("tenant", DataType::UInt16, true));
+    }
+    fields.extend(vec![
+        Field::new("client", ClientIdType::DATA_TYPE, false),
+        Field::new("available", amount_type.clone(), false),
+        Field::new("held", amount_type.clone(), false),
+        Field::new("total", amount_type.clone(), false),
+        Field::new("locked", DataType::Boolean, false),
+    ]);
+    let schema = Arc::new(Schema::new(fields));
+
+    let mut parquet_writer = ArrowWriter::try_new(writer, schema.clone(), None)?;
+
+    let mut accounts = accounts.peekable();
+    while accounts.peek().is_some() {
+        let mut tenant = UInt16Builder::with_capacity(BATCH_SIZE);
+        let mut client = PrimitiveBuilder::<ClientIdType>::with_capacity(BATCH_SIZE);
+        let mut amounts = [
+            Decimal128Builder::with_capacity(BATCH_SIZE),
+            Decimal128Builder::with_capacity(BATCH_SIZE),
+            Decimal128Builder::with_capacity(BATCH_SIZE),
+        ];
+        let mut locked = BooleanBuilder::with_capacity(BATCH_SIZE);
+
+        for account in accounts.by_ref().take(BATCH_SIZE) {
+            tenant.append_option(account.tenant());
+            client.append_value(account.id());
+            amounts[0].append_value(to_fixed_point(account.available(), scale));
+            amounts[1].append_value(to_fixed_point(account.held(), scale));
+            amounts[2].append_value(to_fixed_point(account.total(), scale));
+            locked.append_value(account.is_locked());
+        }
+
+        let mut columns: Vec<ArrayRef> = Vec::with_capacity(6);
+        if with_tenant {
+            columns.push(Arc::new(tenant.finish()));
+        }
+        columns.push(Arc::new(client.finish()));
+        for mut amount in amounts {
+            columns.push(Arc::new(
+                amount.finish().with_data_type(amount_type.clone()),
+            ));
+        }
+        columns.push(Arc::new(locked.finish()));
+
+        parquet_writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+    }
+
+    parquet_writer.close()?;
+    Ok(())
+}
+
+/// Rounds the amount to `scale` decimal places and returns the scaled integer
+fn to_fixed_point(amount: Decimal, scale: u32) -> i128 {
+    let mut amount = amount.round_dp(scale);
+    amount.rescale(scale);
+    amount.mantissa()
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::records::TransactionId;
+
+    #[test]
+    fn test_fixed_point() {
+        assert_eq!(to_fixed_point(dec!(1.5), 4), 15000);
+        assert_eq!(to_fixed_point(dec!(2.123456), 4), 21235);
+        assert_eq!(to_fixed_point(dec!(-3), 2), -300);
+    }
+
+    #[test]
+    fn test_write_parquet() {
+        let accounts: Vec<ClientAccount> = (1..5)
+            .map(|id| {
+                let mut account = ClientAccount::new(id);
+                account
+                    .deposit(id as TransactionId, Decimal::from(id))
+                    .unwrap();
+                account
+            })
+            .collect();
+
+        let path = std::env::temp_dir().join("paytoy_test_report.parquet");
+        write_parquet(std::fs::File::create(&path).unwrap(), accounts.iter(), 4).unwrap();
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 4);
+        assert_eq!(metadata.file_metadata().schema_descr().num_columns(), 5);
+
+        let accounts = [
+            ClientAccount::new(1),
+            ClientAccount::new(1).with_tenant(Some(2)),
+        ];
+        write_parquet(std::fs::File::create(&path).unwrap(), accounts.iter(), 4).unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let schema = reader.metadata().file_metadata().schema_descr();
+        assert_eq!(schema.num_columns(), 6);
+        assert_eq!(schema.column(0).name(), "tenant");
+    }
+}
@@ -1,12 +1,25 @@
-use std::path::Path;
+use std::{path::Path, time::Duration};
 
-use crate::{account_manager::AccountManager, transactions_reader::TransactionCSVReader};
+use log::*;
+
+use crate::{
+    account_manager::AccountManager,
+    cancellation::CancellationToken,
+    clock::Clock,
+    input::Input,
+    report::{Report, ReportOptions},
+    snapshot::SnapshotAccount,
+    transactions_reader::{TransactionCSVReader, TransactionsStream},
+};
+
+/// The clock is read every this many records when snapshotting on an interval
+const CLOCK_CHECK_RECORDS: u64 = 256;
 
 /// The main application
 pub struct PayToyApp {}
 
 impl PayToyApp {
-    /// Runs the application for a specific file in `path`
+    /// Runs the application for a specific file in `path`, which can also be an object store URL
     /// an abstract implementation of a CSV reader and account manager is used
     /// those can be single-threaded, multi-threaded or other
     pub fn run<P: AsRef<Path>>(
@@ -15,14 +28,322 @@ impl PayToyApp {
         manager: impl AccountManager,
         report_results: bool,
     ) -> anyhow::Result<()> {
-        let transactions = reader.read_csv(path)?;
-
-        let report = manager.execute_transactions(transactions);
+        let transactions = Input::open(path.as_ref())?.read_with(reader)?;
+        let report = Self::process(transactions, manager);
 
         if report_results {
-            report.report();
+            report.report(&ReportOptions::default());
         }
 
         Ok(())
     }
+
+    /// Same as `run` on a stream of transactions, but returns the report instead of printing it
+    pub fn process(transactions: TransactionsStream, manager: impl AccountManager) -> Report {
+        manager.execute_transactions(transactions)
+    }
+
+    /// Same as `process`, but stops taking records once `token` is cancelled, e.g. from
+    /// another thread. The report then only covers the records applied before, and is flagged
+    /// with `Report::is_cancelled`
+    /// The token is checked before taking a record, so every record taken is applied, and the
+    /// managers given the token apply the records already handed to their workers: the last
+    /// record taken from `transactions` is the last one applied, see `ResumeProgress`
+    pub fn process_until_cancelled(
+        mut transactions: TransactionsStream,
+        mut manager: impl AccountManager,
+        token: &CancellationToken,
+    ) -> Report {
+        manager.execute(&mut std::iter::from_fn(|| {
+            if token.is_cancelled() {
+                None
+            } else {
+                transactions.next()
+            }
+        }));
+        let cancelled = token.is_cancelled();
+        manager.finish().with_cancelled(cancelled)
+    }
+
+    /// Same as `process`, but hands a snapshot of the accounts to `checkpoint` after every
+    /// `every` transactions, so long runs have partial results to work with
+    pub fn process_with_checkpoints(
+        mut transactions: TransactionsStream,
+        mut manager: impl AccountManager,
+        every: u64,
+        mut checkpoint: impl FnMut(&Report) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Report> {
+        let mut processed = 0;
+        loop {
+            let mut count = 0;
+            manager.execute(
+                &mut transactions.by_ref().take(every as usize).inspect(|_| {
+                    count += 1;
+                }),
+            );
+            // the last chunk is covered by the final report
+            if count < every {
+                break;
+            }
+            processed += count;
+            info!("Checkpoint after {} transactions", processed);
+            checkpoint(&manager.snapshot())?;
+        }
+        Ok(manager.finish())
+    }
+
+    /// Same as `process`, but hands the whole state of the accounts to `save` about every
+    /// `interval` of the `clock`, so a crashed run can be restored from the latest one
+    /// The time is only looked at between records, a stalled input delays the snapshot
+    pub fn process_with_snapshots(
+        mut transactions: TransactionsStream,
+        mut manager: impl AccountManager,
+        interval: Duration,
+        clock: &dyn Clock,
+        mut save: impl FnMut(Vec<SnapshotAccount>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Report> {
+        let mut processed = 0;
+        loop {
+            let deadline = clock.now() + interval;
+            let mut count = 0u64;
+            let mut due = false;
+            manager.execute(&mut std::iter::from_fn(|| {
+                count += 1;
+                if count.is_multiple_of(CLOCK_CHECK_RECORDS) && clock.now() >= deadline {
+                    due = true;
+                    return None;
+                }
+                transactions.next()
+            }));
+            // the state at the end of the input is saved with the final report
+            if !due {
+                break;
+            }
+            // the last call didn't take a record
+            processed += count - 1;
+            info!("Snapshot after {} transactions", processed);
+            save(manager.state())?;
+        }
+        Ok(manager.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{MTAccountManager, STAccountManager},
+        clock::{MockClock, SystemClock},
+        records::{
+            wide_id, AccountKey, ClientId, RecordSource, TransactionRecord, TransactionType,
+        },
+        resume_token::ResumeProgress,
+        snapshot::{read_snapshot, write_snapshot},
+        transactions_reader::STBulkReader,
+    };
+
+    use super::*;
+
+    /// Total of client 1 at each checkpoint of test_basic.csv, then in the final report
+    fn checkpoint_totals(manager: impl AccountManager) -> Vec<Decimal> {
+        let transactions = STBulkReader::new()
+            .read_csv("tests/data/test_basic.csv")
+            .unwrap();
+        let mut totals = Vec::new();
+        let report = PayToyApp::process_with_checkpoints(transactions, manager, 2, |report| {
+            totals.push(report.account(1).unwrap().total());
+            Ok(())
+        })
+        .unwrap();
+        totals.push(report.account(1).unwrap().total());
+        totals
+    }
+
+    fn deposits() -> impl Iterator<Item = TransactionRecord> {
+        (1..=600).map(|tx| TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount: Some(dec!(1)),
+            timestamp: None,
+            source: None,
+        })
+    }
+
+    #[test]
+    fn test_interval_snapshots() {
+        // every check of the clock is past the interval
+        let mut totals = Vec::new();
+        let report = PayToyApp::process_with_snapshots(
+            Box::new(deposits()),
+            MTAccountManager::new(2),
+            Duration::ZERO,
+            &SystemClock,
+            |state| {
+                totals.push(state[0].available);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(totals, vec![dec!(255), dec!(510)]);
+        assert_eq!(report.account(1).unwrap().total(), dec!(600));
+    }
+
+    #[test]
+    fn test_interval_snapshots_mock_clock() {
+        // a second for each record: the clock is past the interval at the second check
+        let clock = Arc::new(MockClock::new());
+        let deposits = {
+            let clock = clock.clone();
+            deposits().inspect(move |_| clock.advance(Duration::from_secs(1)))
+        };
+        let mut totals = Vec::new();
+        let report = PayToyApp::process_with_snapshots(
+            Box::new(deposits),
+            STAccountManager::new(),
+            Duration::from_secs(400),
+            clock.as_ref(),
+            |state| {
+                totals.push(state[0].available);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(totals, vec![dec!(511)]);
+        assert_eq!(report.account(1).unwrap().total(), dec!(600));
+    }
+
+    /// Deposits of 1 cancelled at the 100th
+    fn cancelled_run(manager: impl AccountManager, token: &CancellationToken) -> Report {
+        let deposits = {
+            let token = token.clone();
+            deposits().inspect(move |record| {
+                if record.tx == 100 {
+                    token.cancel();
+                }
+            })
+        };
+        PayToyApp::process_until_cancelled(Box::new(deposits), manager, token)
+    }
+
+    #[test]
+    fn test_process_until_cancelled() {
+        let report = PayToyApp::process_until_cancelled(
+            Box::new(deposits()),
+            STAccountManager::new(),
+            &CancellationToken::new(),
+        );
+        assert!(!report.is_cancelled());
+        assert_eq!(report.account(1).unwrap().total(), dec!(600));
+
+        // the 100th record is taken before the cancellation, it's applied
+        let token = CancellationToken::new();
+        let report = cancelled_run(STAccountManager::new(), &token);
+        assert!(report.is_cancelled());
+        assert_eq!(report.account(1).unwrap().total(), dec!(100));
+
+        let token = CancellationToken::new();
+        let manager = STAccountManager::new().with_cancellation(token.clone());
+        let report = cancelled_run(manager, &token);
+        assert!(report.is_cancelled());
+        assert_eq!(report.account(1).unwrap().total(), dec!(100));
+
+        // the records already queued for the workers are applied too
+        let token = CancellationToken::new();
+        let manager = MTAccountManager::new(2).with_cancellation(token.clone());
+        let report = cancelled_run(manager, &token);
+        assert!(report.is_cancelled());
+        assert_eq!(report.account(1).unwrap().total(), dec!(100));
+    }
+
+    /// Deposits and withdrawals of several clients, tagged with their line
+    fn tagged_records() -> Vec<TransactionRecord> {
+        let input: Arc<str> = "input.csv".into();
+        (1..=3000)
+            .map(|tx| TransactionRecord {
+                tr_type: if tx % 3 == 0 {
+                    TransactionType::Withdrawal
+                } else {
+                    TransactionType::Deposit
+                },
+                client: (tx % 7 + 1) as ClientId,
+                tenant: None,
+                merchant: None,
+                tx,
+                amount: Some(dec!(1.5)),
+                timestamp: None,
+                source: RecordSource::tag(Some(&input), wide_id(tx) + 1),
+            })
+            .collect()
+    }
+
+    fn balances(report: &Report) -> Vec<(AccountKey, Decimal, Decimal)> {
+        let mut balances: Vec<_> = report
+            .accounts()
+            .map(|account| (account.key(), account.available(), account.held()))
+            .collect();
+        balances.sort_by_key(|balance| balance.0);
+        balances
+    }
+
+    #[test]
+    fn test_resume_after_cancel_mt() {
+        // like `paytoy --mode multi --resume-token`, cancelled right after a record is taken
+        let token = CancellationToken::new();
+        let progress = Arc::new(ResumeProgress::default());
+        let transactions: TransactionsStream = {
+            let (token, progress) = (token.clone(), progress.clone());
+            let cancel = token.clone();
+            Box::new(
+                tagged_records()
+                    .into_iter()
+                    .take_while(move |_| !token.is_cancelled())
+                    .inspect(move |record| progress.applied(record))
+                    .inspect(move |record| {
+                        if record.tx == 1800 {
+                            cancel.cancel();
+                        }
+                    }),
+            )
+        };
+        let manager = MTAccountManager::new(3).with_cancellation(token.clone());
+        let interrupted = PayToyApp::process_until_cancelled(transactions, manager, &token);
+        assert!(interrupted.is_cancelled());
+        let (line, tx) = progress.last().unwrap();
+        assert_eq!((line, tx), (1801, 1800));
+
+        // resumed after the line of the token, from the accounts of the interrupted run
+        let mut state = Vec::new();
+        write_snapshot(
+            &mut state,
+            interrupted.accounts().map(SnapshotAccount::of).collect(),
+            &[],
+        )
+        .unwrap();
+        let snapshot = read_snapshot(state.as_slice()).unwrap();
+        let rest = tagged_records()
+            .into_iter()
+            .filter(move |record| record.source.as_ref().unwrap().line > line);
+        let resumed = MTAccountManager::new(3)
+            .with_snapshot(&snapshot)
+            .execute_transactions(Box::new(rest));
+
+        let full =
+            MTAccountManager::new(3).execute_transactions(Box::new(tagged_records().into_iter()));
+        assert_eq!(balances(&resumed), balances(&full));
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        // 5 transactions: checkpoints after the second and the fourth
+        let expected = vec![dec!(1.0), dec!(1.5), dec!(1.5)];
+        assert_eq!(checkpoint_totals(STAccountManager::new()), expected);
+        assert_eq!(checkpoint_totals(MTAccountManager::new(2)), expected);
+    }
 }
@@ -0,0 +1,361 @@
+/// Reports of the accounts for each accounting period, for `--period`
+/// The timestamps of the records are taken as Unix timestamps in seconds, and the run is cut
+/// in days or calendar months, in UTC. When the records cross into a new period, the previous
+/// one is closed: every account with a transaction in it gets a row with its total at the
+/// start, the deposits, withdrawals and chargebacks of the period, and its total at the end.
+/// Each worker closes its own periods, a period is written once all of them are past it, so
+/// the rows stay in time order. The records without a timestamp are in the current period
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use hashbrown::HashMap;
+use rust_decimal::Decimal;
+
+use crate::{
+    records::{AccountKey, TransactionType},
+    statement::Date,
+};
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    /// The period of a timestamp, counted in days or months since the epoch
+    pub fn index(self, timestamp: u64) -> i64 {
+        match self {
+            Period::Daily => (timestamp / SECONDS_PER_DAY) as i64,
+            Period::Monthly => {
+                let date = Date::from_unix(timestamp);
+                date.year * 12 + i64::from(date.month) - 1
+            }
+        }
+    }
+
+    /// `2024-03-05` for a day, `2024-03` for a month
+    pub fn label(self, index: i64) -> String {
+        match self {
+            Period::Daily => {
+                let date = Date::from_unix(index as u64 * SECONDS_PER_DAY);
+                format!("{:04}-{:02}-{:02}", date.year, date.month, date.day)
+            }
+            Period::Monthly => format!("{:04}-{:02}", index.div_euclid(12), index % 12 + 1),
+        }
+    }
+}
+
+/// The movements of the total of an account during a period
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct PeriodMovements {
+    pub opening: Decimal,
+    pub deposits: Decimal,
+    pub withdrawals: Decimal,
+    /// The funds taken back by the chargebacks
+    pub chargebacks: Decimal,
+}
+
+impl PeriodMovements {
+    fn new(opening: Decimal) -> Self {
+        Self {
+            opening,
+            deposits: Decimal::ZERO,
+            withdrawals: Decimal::ZERO,
+            chargebacks: Decimal::ZERO,
+        }
+    }
+
+    pub fn closing(&self) -> Decimal {
+        self.opening + self.deposits - self.withdrawals - self.chargebacks
+    }
+}
+
+type PeriodRows = Vec<(AccountKey, PeriodMovements)>;
+
+struct ReportState {
+    writer: Box<dyn Write + Send>,
+    /// For each tracker, the first period it hasn't closed yet
+    progress: Vec<i64>,
+    /// The closed periods that some trackers may still add rows to
+    pending: BTreeMap<i64, PeriodRows>,
+    /// The first write that failed, reported by `finish`
+    error: Option<std::io::Error>,
+}
+
+/// The report of the periods, shared by the workers
+pub struct PeriodReport {
+    period: Period,
+    state: Mutex<ReportState>,
+}
+
+impl PeriodReport {
+    /// Writes the periods to `writer`, starting with the header
+    pub fn new(period: Period, mut writer: Box<dyn Write + Send>) -> Self {
+        let error = writeln!(
+            writer,
+            "period, tenant, client,       opening,      deposits,   withdrawals,   chargebacks,       closing"
+        )
+        .err();
+        Self {
+            period,
+            state: Mutex::new(ReportState {
+                writer,
+                progress: Vec::new(),
+                pending: BTreeMap::new(),
+                error,
+            }),
+        }
+    }
+
+    pub fn create(period: Period, path: &Path) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self::new(period, Box::new(BufWriter::new(file))))
+    }
+
+    /// A tracker of the periods of the accounts of a manager, the report waits for all the
+    /// trackers to be past a period to write it
+    pub fn tracker(self: &Arc<Self>) -> PeriodTracker {
+        let mut state = self.state.lock().unwrap();
+        state.progress.push(i64::MIN);
+        PeriodTracker {
+            report: self.clone(),
+            participant: state.progress.len() - 1,
+            current: None,
+            movements: HashMap::new(),
+        }
+    }
+
+    /// Flushes the report, once the trackers are dropped
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let flushed = state.writer.flush();
+        match state.error.take().map_or(flushed, Err) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(anyhow::anyhow!(
+                "Failed to write the period report: {}",
+                err
+            )),
+        }
+    }
+
+    /// Adds the rows of a closed period, the tracker is now at the `next` period
+    fn close(&self, participant: usize, index: i64, rows: PeriodRows, next: i64) {
+        let mut state = self.state.lock().unwrap();
+        if !rows.is_empty() {
+            state.pending.entry(index).or_default().extend(rows);
+        }
+        state.progress[participant] = next;
+
+        let done = state.progress.iter().copied().min().unwrap_or(i64::MAX);
+        while let Some(entry) = state.pending.first_entry() {
+            if *entry.key() >= done {
+                break;
+            }
+            let index = *entry.key();
+            let mut rows = entry.remove();
+            rows.sort_by_key(|(key, _)| *key);
+            let label = self.period.label(index);
+            let mut buffer = Vec::new();
+            for (key, movements) in rows {
+                // writing to memory doesn't fail
+                let _ = write_row(&mut buffer, &label, key, &movements);
+            }
+            if let Err(err) = state.writer.write_all(&buffer) {
+                state.error.get_or_insert(err);
+            }
+        }
+    }
+}
+
+fn write_row(
+    writer: &mut impl Write,
+    label: &str,
+    key: AccountKey,
+    movements: &PeriodMovements,
+) -> std::io::Result<()> {
+    let tenant = key
+        .tenant
+        .map_or_else(String::new, |tenant| tenant.to_string());
+    writeln!(
+        writer,
+        "{}, {:>6}, {:6}, {:13.4}, {:13.4}, {:13.4}, {:13.4}, {:13.4}",
+        label,
+        tenant,
+        key.client,
+        movements.opening,
+        movements.deposits,
+        movements.withdrawals,
+        movements.chargebacks,
+        movements.closing()
+    )
+}
+
+/// The current period of the accounts of a manager, closed when its records move past it or
+/// when it's dropped at the end of the run
+pub struct PeriodTracker {
+    report: Arc<PeriodReport>,
+    participant: usize,
+    current: Option<i64>,
+    movements: HashMap<AccountKey, PeriodMovements>,
+}
+
+impl PeriodTracker {
+    /// Moves to the period of the `timestamp`, closing the current one if it's over
+    /// A record late for its period is counted in the current one
+    pub fn advance(&mut self, timestamp: u64) {
+        let index = self.report.period.index(timestamp);
+        match self.current {
+            Some(current) if index > current => {
+                self.close(current, index);
+                self.current = Some(index);
+            }
+            Some(_) => {}
+            None => self.current = Some(index),
+        }
+    }
+
+    /// Counts the change of the total of an account by a transaction
+    pub fn add(
+        &mut self,
+        key: AccountKey,
+        tr_type: TransactionType,
+        before: Decimal,
+        after: Decimal,
+    ) {
+        let movements = self
+            .movements
+            .entry(key)
+            .or_insert_with(|| PeriodMovements::new(before));
+        match tr_type {
+            TransactionType::Deposit => movements.deposits += after - before,
+            // a pending withdrawal only moves the funds until it's settled
+            TransactionType::Withdrawal | TransactionType::Settle | TransactionType::Payment => {
+                movements.withdrawals += before - after
+            }
+            TransactionType::ChargeBack => movements.chargebacks += before - after,
+            // only move the funds between available and held
+            TransactionType::Dispute | TransactionType::Resolve => {}
+        }
+    }
+
+    fn close(&mut self, index: i64, next: i64) {
+        let rows = self.movements.drain().collect();
+        self.report.close(self.participant, index, rows, next);
+    }
+}
+
+impl Drop for PeriodTracker {
+    fn drop(&mut self) {
+        match self.current {
+            Some(index) => self.close(index, i64::MAX),
+            // without a timestamp, the records aren't in any period
+            None => self
+                .report
+                .close(self.participant, i64::MIN, Vec::new(), i64::MAX),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, MTAccountManager, STAccountManager},
+        records::{ClientId, TransactionId, TransactionRecord},
+        report_stream::tests::SharedBuffer,
+    };
+
+    use super::*;
+
+    /// 2024-01-31 00:00:00 UTC
+    const JAN_31: u64 = 1_706_659_200;
+
+    #[test]
+    fn test_periods() {
+        assert_eq!(
+            Period::Daily.label(Period::Daily.index(JAN_31 + 3600)),
+            "2024-01-31"
+        );
+        let month = Period::Monthly.index(JAN_31);
+        assert_eq!(Period::Monthly.label(month), "2024-01");
+        assert_eq!(Period::Monthly.index(JAN_31 + SECONDS_PER_DAY), month + 1);
+        assert_eq!(Period::Monthly.label(month + 11), "2024-12");
+    }
+
+    fn record(
+        tr_type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+        timestamp: u64,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount,
+            timestamp: Some(timestamp),
+            source: None,
+        }
+    }
+
+    fn period_report(manager: impl AccountManager, report: Arc<PeriodReport>) {
+        let day = SECONDS_PER_DAY;
+        let records = vec![
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10)), JAN_31),
+            record(TransactionType::Deposit, 2, 2, Some(dec!(4)), JAN_31 + 10),
+            record(
+                TransactionType::Withdrawal,
+                1,
+                3,
+                Some(dec!(3)),
+                JAN_31 + day,
+            ),
+            record(TransactionType::Dispute, 2, 2, None, JAN_31 + day),
+            record(TransactionType::ChargeBack, 2, 2, None, JAN_31 + 3 * day),
+        ];
+        manager.execute_transactions(Box::new(records.into_iter()));
+        report.finish().unwrap();
+    }
+
+    #[test]
+    fn test_period_report() {
+        let expected = "\
+period, tenant, client,       opening,      deposits,   withdrawals,   chargebacks,       closing
+2024-01-31,       ,      1,        0.0000,       10.0000,        0.0000,        0.0000,       10.0000
+2024-01-31,       ,      2,        0.0000,        4.0000,        0.0000,        0.0000,        4.0000
+2024-02-01,       ,      1,       10.0000,        0.0000,        3.0000,        0.0000,        7.0000
+2024-02-01,       ,      2,        4.0000,        0.0000,        0.0000,        0.0000,        4.0000
+2024-02-03,       ,      2,        4.0000,        0.0000,        0.0000,        4.0000,        0.0000
+";
+        let output = SharedBuffer::default();
+        let report = Arc::new(PeriodReport::new(Period::Daily, Box::new(output.clone())));
+        period_report(
+            STAccountManager::new().with_period_report(report.clone()),
+            report,
+        );
+        assert_eq!(output.contents(), expected);
+
+        // the workers close their own periods, but the rows stay in order
+        let output = SharedBuffer::default();
+        let report = Arc::new(PeriodReport::new(Period::Daily, Box::new(output.clone())));
+        period_report(
+            MTAccountManager::new(2).with_period_report(report.clone()),
+            report,
+        );
+        assert_eq!(output.contents(), expected);
+    }
+}
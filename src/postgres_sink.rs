@@ -0,0 +1,122 @@
+/// Upserts the final account balances into a PostgreSQL table, for the back office
+/// The rest of the application is synchronous, so a small runtime is started just for the upload
+use std::convert::TryFrom;
+
+use sqlx::{Connection, PgConnection};
+
+use crate::{client_account::ClientAccount, errors::UsageError};
+
+/// Number of accounts sent in a single statement
+const BATCH_SIZE: usize = 10_000;
+
+/// Upserts the accounts into `table` in a single transaction, creating the table if needed
+/// The accounts missing from the report are left untouched
+pub fn upsert_accounts<'a>(
+    url: &str,
+    table: &str,
+    accounts: impl Iterator<Item = &'a ClientAccount>,
+) -> anyhow::Result<()> {
+    // the table name can't be bound as a parameter
+    if !is_valid_table(table) {
+        return Err(UsageError(format!("Invalid table name `{}`", table)).into());
+    }
+
+    let create = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            client BIGINT PRIMARY KEY,
+            available NUMERIC NOT NULL,
+            held NUMERIC NOT NULL,
+            total NUMERIC NOT NULL,
+            locked BOOLEAN NOT NULL
+        )",
+        table
+    );
+    // amounts are sent as text, so they are converted to NUMERIC without any rounding
+    let upsert = format!(
+        "INSERT INTO {} (client, available, held, total, locked)
+        SELECT * FROM UNNEST($1::BIGINT[], $2::NUMERIC[], $3::NUMERIC[], $4::NUMERIC[], $5::BOOLEAN[])
+        ON CONFLICT (client) DO UPDATE SET
+            available = EXCLUDED.available,
+            held = EXCLUDED.held,
+            total = EXCLUDED.total,
+            locked = EXCLUDED.locked",
+        table
+    );
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mut connection = PgConnection::connect(url).await?;
+        let mut transaction = connection.begin().await?;
+        sqlx::query(&create).execute(&mut *transaction).await?;
+
+        let mut accounts = accounts.peekable();
+        while accounts.peek().is_some() {
+            let mut clients = Vec::with_capacity(BATCH_SIZE);
+            let mut amounts = [
+                Vec::with_capacity(BATCH_SIZE),
+                Vec::with_capacity(BATCH_SIZE),
+                Vec::with_capacity(BATCH_SIZE),
+            ];
+            let mut locked = Vec::with_capacity(BATCH_SIZE);
+            for account in accounts.by_ref().take(BATCH_SIZE) {
+                // only the `client-id-u64` builds can have larger ids
+                let client = i64::try_from(account.id()).map_err(|_| {
+                    anyhow::anyhow!("Client {} is too large for a BIGINT column", account.id())
+                })?;
+                clients.push(client);
+                amounts[0].push(account.available().to_string());
+                amounts[1].push(account.held().to_string());
+                amounts[2].push(account.total().to_string());
+                locked.push(account.is_locked());
+            }
+
+            let [available, held, total] = amounts;
+            sqlx::query(&upsert)
+                .bind(clients)
+                .bind(available)
+                .bind(held)
+                .bind(total)
+                .bind(locked)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(())
+    })
+}
+
+/// Accepts plain identifiers, optionally qualified with a schema
+fn is_valid_table(table: &str) -> bool {
+    let parts: Vec<&str> = table.split('.').collect();
+    parts.len() <= 2
+        && parts.iter().all(|part| {
+            part.chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_names() {
+        assert!(is_valid_table("accounts"));
+        assert!(is_valid_table("back_office.client_balances"));
+        assert!(!is_valid_table(""));
+        assert!(!is_valid_table("1accounts"));
+        assert!(!is_valid_table("a.b.c"));
+        assert!(!is_valid_table("accounts; DROP TABLE accounts"));
+    }
+
+    #[test]
+    fn test_invalid_table_is_rejected_before_connecting() {
+        let err = upsert_accounts("postgres://localhost/none", "bad name", std::iter::empty());
+        assert!(err.is_err());
+    }
+}
@@ -0,0 +1,128 @@
+/// The input files already applied to the accounts of a snapshot, so a file isn't posted twice
+/// The snapshot keeps the SHA-256 digest of every local input of the runs that led to it, and
+/// the next run skips the inputs with one of these digests unless it's asked to reprocess them.
+/// The digest is taken on the bytes of the file, so a renamed file is still found and a
+/// compressed one is only the same input if it's compressed the same way. The remote inputs
+/// aren't read twice for their digest, they're always processed
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use hashbrown::HashSet;
+use sha2::{Digest, Sha256};
+
+use crate::input::Input;
+
+/// The SHA-256 digest of the data, in hexadecimal
+pub fn digest(mut reader: impl Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+pub fn digest_file(path: &Path) -> anyhow::Result<String> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    digest(BufReader::new(file)).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// The inputs of a run, without the ones already applied to its state
+#[derive(PartialEq, Debug, Default)]
+pub struct InputPlan {
+    /// The inputs to read, in their order
+    pub inputs: Vec<PathBuf>,
+    /// The inputs skipped since they were already processed
+    pub skipped: Vec<PathBuf>,
+    /// The digests of the inputs applied to the state once the run is over
+    pub processed: Vec<String>,
+}
+
+/// Picks the inputs that weren't `processed` yet, the same file given twice is skipped too
+/// With `reprocess`, all the inputs are read and their digests are still recorded
+pub fn plan(
+    inputs: &[PathBuf],
+    processed: &[String],
+    reprocess: bool,
+) -> anyhow::Result<InputPlan> {
+    let mut seen: HashSet<String> = processed.iter().cloned().collect();
+    let mut plan = InputPlan {
+        processed: processed.to_vec(),
+        ..InputPlan::default()
+    };
+    for input in inputs {
+        if Input::is_remote(input) {
+            plan.inputs.push(input.clone());
+            continue;
+        }
+        let digest = digest_file(input)?;
+        if seen.insert(digest.clone()) {
+            plan.processed.push(digest);
+        } else if !reprocess {
+            plan.skipped.push(input.clone());
+            continue;
+        }
+        plan.inputs.push(input.clone());
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(
+            digest("abc".as_bytes()).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let dir = std::env::temp_dir().join("paytoy_test_processed_inputs");
+        std::fs::create_dir_all(&dir).unwrap();
+        let monday = dir.join("monday.csv");
+        let tuesday = dir.join("tuesday.csv");
+        let copy = dir.join("monday-copy.csv");
+        std::fs::write(&monday, "type, client, tx, amount\ndeposit, 1, 1, 10\n").unwrap();
+        std::fs::write(&tuesday, "type, client, tx, amount\ndeposit, 1, 2, 10\n").unwrap();
+        std::fs::copy(&monday, &copy).unwrap();
+
+        let first = plan(std::slice::from_ref(&monday), &[], false).unwrap();
+        assert_eq!(first.inputs, vec![monday.clone()]);
+        assert_eq!(first.processed, vec![digest_file(&monday).unwrap()]);
+
+        // the next day, the file of the day before is given again under another name
+        let next = plan(&[copy.clone(), tuesday.clone()], &first.processed, false).unwrap();
+        assert_eq!(next.inputs, vec![tuesday.clone()]);
+        assert_eq!(next.skipped, vec![copy.clone()]);
+        assert_eq!(next.processed.len(), 2);
+
+        let forced = plan(&[copy.clone(), tuesday], &first.processed, true).unwrap();
+        assert_eq!(forced.inputs.len(), 2);
+        assert!(forced.skipped.is_empty());
+        assert_eq!(forced.processed.len(), 2);
+
+        // the remote inputs are always read
+        let url = PathBuf::from("https://example.com/monday.csv");
+        let remote = plan(std::slice::from_ref(&url), &[], false).unwrap();
+        assert_eq!(remote.inputs, vec![url]);
+        assert!(remote.processed.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
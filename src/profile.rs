@@ -0,0 +1,286 @@
+/// Opt-in profiling of the time spent in each stage of the pipeline, per thread
+/// Every thread times its own work with a `StageTimer` and only hands the totals over
+/// when it's done, so the threads don't contend while the run lasts.
+/// For the chrome tracing output, the spans closer than `MERGE_GAP` are merged,
+/// which keeps the trace small even when every record is timed
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::json;
+
+const MERGE_GAP: Duration = Duration::from_micros(50);
+
+/// The stages of the pipeline that are timed
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub enum Stage {
+    /// Reading the raw bytes of the input
+    Read,
+    /// Parsing the records
+    Parse,
+    /// Sending the records to the account manager workers
+    Dispatch,
+    /// Applying the records to the accounts
+    Apply,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Parse => "parse",
+            Stage::Dispatch => "dispatch",
+            Stage::Apply => "apply",
+        }
+    }
+}
+
+/// The timings of all the threads of a run
+pub struct Profile {
+    started: Instant,
+    /// The spans are only kept for the chrome tracing output
+    keep_spans: bool,
+    timings: Mutex<Vec<Timing>>,
+    /// The settings the pipeline picked for itself, printed with the breakdown
+    settings: Mutex<BTreeMap<&'static str, String>>,
+}
+
+/// The time spent by a single thread in a stage
+struct Timing {
+    stage: Stage,
+    thread: String,
+    busy: Duration,
+    calls: u64,
+    /// Start (since the start of the run) and duration of the timed spans
+    spans: Vec<(Duration, Duration)>,
+}
+
+impl Profile {
+    pub fn new(keep_spans: bool) -> Self {
+        Self {
+            started: Instant::now(),
+            keep_spans,
+            timings: Mutex::new(Vec::new()),
+            settings: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a setting of the pipeline, e.g. the auto-tuned block size of the reader
+    pub fn set(&self, name: &'static str, value: String) {
+        self.settings.lock().unwrap().insert(name, value);
+    }
+
+    /// Prints the time spent by every thread in each stage, and its share of the run time
+    pub fn write_breakdown(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let wall = self.started.elapsed();
+        let mut rows: BTreeMap<(Stage, &str), (Duration, u64)> = BTreeMap::new();
+        let timings = self.timings.lock().unwrap();
+        for timing in timings.iter() {
+            let row = rows.entry((timing.stage, &timing.thread)).or_default();
+            row.0 += timing.busy;
+            row.1 += timing.calls;
+        }
+
+        let thread_width = rows
+            .keys()
+            .map(|(_, thread)| thread.len())
+            .chain(["thread".len()])
+            .max()
+            .unwrap_or_default();
+        writeln!(
+            writer,
+            "{:<8} | {:<thread_width$} | {:>10} | {:>10} | {:>5}",
+            "stage",
+            "thread",
+            "calls",
+            "busy",
+            "share",
+            thread_width = thread_width
+        )?;
+        writeln!(
+            writer,
+            "{}-+-{}-+-{}-+-{}-+-{}",
+            "-".repeat(8),
+            "-".repeat(thread_width),
+            "-".repeat(10),
+            "-".repeat(10),
+            "-".repeat(5)
+        )?;
+        for ((stage, thread), (busy, calls)) in rows {
+            writeln!(
+                writer,
+                "{:<8} | {:<thread_width$} | {:>10} | {:>9.3}s | {:>4.0}%",
+                stage.name(),
+                thread,
+                calls,
+                busy.as_secs_f64(),
+                100.0 * busy.as_secs_f64() / wall.as_secs_f64().max(1e-9),
+                thread_width = thread_width
+            )?;
+        }
+        writeln!(writer, "wall time: {:.3}s", wall.as_secs_f64())?;
+        for (name, value) in self.settings.lock().unwrap().iter() {
+            writeln!(writer, "{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the spans in the chrome tracing format, to be opened in `chrome://tracing`
+    /// or Perfetto
+    pub fn write_trace(&self, writer: impl Write) -> anyhow::Result<()> {
+        let timings = self.timings.lock().unwrap();
+        let mut threads: HashMap<&str, usize> = HashMap::new();
+        let mut events = Vec::new();
+        for timing in timings.iter() {
+            let next_tid = threads.len() + 1;
+            let tid = *threads.entry(&timing.thread).or_insert_with(|| {
+                events.push(json!({
+                    "name": "thread_name",
+                    "ph": "M",
+                    "pid": 1,
+                    "tid": next_tid,
+                    "args": { "name": timing.thread },
+                }));
+                next_tid
+            });
+            for (start, duration) in &timing.spans {
+                events.push(json!({
+                    "name": timing.stage.name(),
+                    "ph": "X",
+                    "pid": 1,
+                    "tid": tid,
+                    "ts": start.as_secs_f64() * 1e6,
+                    "dur": duration.as_secs_f64() * 1e6,
+                }));
+            }
+        }
+
+        serde_json::to_writer(writer, &json!({ "traceEvents": events }))?;
+        Ok(())
+    }
+}
+
+/// Times the work of the current thread in a stage, a no-op without a profile
+/// The timings are added to the profile when the timer is dropped
+pub struct StageTimer {
+    profile: Option<Arc<Profile>>,
+    timing: Timing,
+}
+
+impl StageTimer {
+    /// The thread is identified by its name
+    pub fn start(profile: Option<&Arc<Profile>>, stage: Stage) -> Self {
+        let thread = std::thread::current();
+        Self {
+            profile: profile.cloned(),
+            timing: Timing {
+                stage,
+                thread: thread.name().unwrap_or("unnamed").to_string(),
+                busy: Duration::ZERO,
+                calls: 0,
+                spans: Vec::new(),
+            },
+        }
+    }
+
+    pub fn time<T>(&mut self, work: impl FnOnce() -> T) -> T {
+        let profile = match &self.profile {
+            Some(profile) => profile,
+            None => return work(),
+        };
+
+        let start = Instant::now();
+        let result = work();
+        let duration = start.elapsed();
+
+        self.timing.busy += duration;
+        self.timing.calls += 1;
+        if profile.keep_spans {
+            let start = start - profile.started;
+            match self.timing.spans.last_mut() {
+                Some((last_start, last_duration))
+                    if start < *last_start + *last_duration + MERGE_GAP =>
+                {
+                    *last_duration = start + duration - *last_start;
+                }
+                _ => self.timing.spans.push((start, duration)),
+            }
+        }
+        result
+    }
+}
+
+impl Drop for StageTimer {
+    fn drop(&mut self) {
+        if let Some(profile) = &self.profile {
+            let timing = Timing {
+                stage: self.timing.stage,
+                thread: std::mem::take(&mut self.timing.thread),
+                busy: self.timing.busy,
+                calls: self.timing.calls,
+                spans: std::mem::take(&mut self.timing.spans),
+            };
+            profile.timings.lock().unwrap().push(timing);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let profile = Arc::new(Profile::new(true));
+        std::thread::Builder::new()
+            .name("parser 1".to_string())
+            .spawn({
+                let profile = profile.clone();
+                move || {
+                    let mut timer = StageTimer::start(Some(&profile), Stage::Parse);
+                    for _ in 0..3 {
+                        timer.time(|| std::thread::sleep(Duration::from_millis(2)));
+                    }
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        let mut timer = StageTimer::start(Some(&profile), Stage::Read);
+        assert_eq!(timer.time(|| 42), 42);
+        drop(timer);
+        profile.set("block size", "64.0 KiB".to_string());
+        // a timer without a profile only runs the work
+        assert_eq!(StageTimer::start(None, Stage::Apply).time(|| 1), 1);
+
+        let mut breakdown = Vec::new();
+        profile.write_breakdown(&mut breakdown).unwrap();
+        let breakdown = String::from_utf8(breakdown).unwrap();
+        let rows: Vec<Vec<&str>> = breakdown
+            .lines()
+            .skip(2)
+            .take(2)
+            .map(|line| line.split('|').map(str::trim).collect())
+            .collect();
+        let this_thread = std::thread::current().name().unwrap().to_string();
+        assert_eq!(rows[0][..3], ["read", &this_thread, "1"]);
+        assert_eq!(rows[1][..3], ["parse", "parser 1", "3"]);
+        assert!(breakdown.ends_with("\nblock size: 64.0 KiB\n"));
+
+        let mut trace = Vec::new();
+        profile.write_trace(&mut trace).unwrap();
+        let trace: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+        let spans: Vec<&serde_json::Value> = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["name"] == "parse")
+            .collect();
+        // the consecutive sleeps are merged in a single span
+        assert_eq!(spans.len(), 1);
+        assert!(spans[0]["dur"].as_f64().unwrap() >= 6000.0);
+    }
+}
@@ -0,0 +1,120 @@
+/// Rate limiting of the records of the inputs, with token buckets
+/// A limit can be put on each input, so a single big or misbehaving one can't take all the
+/// capacity of the pipeline when the inputs are read in parallel, and on all the records, so
+/// the run doesn't take more than its share of a shared host
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{clock::Clock, transactions_reader::TransactionsStream};
+
+/// Shorter waits are accumulated, sleeping for each record would cost more than the wait
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+/// A bucket refilled at `rate` tokens per second, holding up to a second of tokens
+pub struct TokenBucket {
+    rate: f64,
+    /// Can go negative, the records that went over are waited for later
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket of `rate` records per second
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    /// Takes the token of a record, returns how long to wait for it to be in the rate
+    pub fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Passes the records of the stream at `rate` records per second at most, as told by the `clock`
+pub fn limit(
+    transactions: TransactionsStream,
+    rate: u64,
+    clock: Arc<dyn Clock>,
+) -> TransactionsStream {
+    let mut bucket = TokenBucket::new(rate, clock.now());
+    Box::new(transactions.inspect(move |_| {
+        let wait = bucket.take(clock.now());
+        if wait >= MIN_SLEEP {
+            clock.sleep(wait);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        clock::{self, MockClock},
+        records::{TransactionId, TransactionRecord, TransactionType},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, start);
+        // a second of records goes right away
+        for _ in 0..10 {
+            assert_eq!(bucket.take(start), Duration::ZERO);
+        }
+        assert_eq!(bucket.take(start), Duration::from_millis(100));
+        assert_eq!(bucket.take(start), Duration::from_millis(200));
+
+        // refilled, but never more than a second of records
+        let later = start + Duration::from_secs(5);
+        for _ in 0..10 {
+            assert_eq!(bucket.take(later), Duration::ZERO);
+        }
+        assert!(bucket.take(later) > Duration::ZERO);
+    }
+
+    fn records(count: TransactionId) -> TransactionsStream {
+        Box::new((0..count).map(|tx| TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount: None,
+            timestamp: None,
+            source: None,
+        }))
+    }
+
+    #[test]
+    fn test_limit() {
+        let start = Instant::now();
+        // the 20 records past the first second of tokens take 200ms
+        assert_eq!(limit(records(120), 100, clock::system()).count(), 120);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_limit_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        // an hour of records at 10 per second, without the wait
+        assert_eq!(limit(records(36_000), 10, clock.clone()).count(), 36_000);
+        let elapsed = clock.now() - start;
+        assert!(elapsed >= Duration::from_secs(3599) && elapsed <= Duration::from_secs(3600));
+    }
+}
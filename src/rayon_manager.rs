@@ -0,0 +1,185 @@
+/// A data-parallel account manager for the bulk replays of historical inputs, `--mode rayon`
+/// The records are only buffered as they come, and applied when the report or a snapshot is
+/// asked for: they're grouped by the shard of their account, keeping their order, and each
+/// shard is applied by its own single threaded manager on a rayon pool. There are no queues
+/// to go through and no worker waiting for its next record, but nothing is applied before the
+/// whole input is read, so it suits the replays rather than the live streams
+use hashbrown::HashMap;
+use rayon::prelude::*;
+
+use crate::{
+    account_manager::{AccountManager, STAccountManager},
+    errors::TransactionError,
+    records::{AccountKey, TransactionRecord},
+    report::Report,
+    snapshot::{Snapshot, SnapshotAccount},
+};
+
+/// Shards per thread, so that the threads done with their shards can take over the others
+const SHARDS_PER_THREAD: usize = 4;
+
+pub struct RayonAccountManager {
+    pool: rayon::ThreadPool,
+    /// The manager of each shard of the accounts
+    shards: Vec<STAccountManager>,
+    /// The records not applied yet, for each shard
+    pending: Vec<Vec<TransactionRecord>>,
+    /// The latest timestamp of the records of any shard
+    latest_timestamp: Option<u64>,
+}
+
+impl AccountManager for RayonAccountManager {
+    fn apply(&mut self, record: TransactionRecord) -> Result<(), TransactionError> {
+        if let Some(timestamp) = record.timestamp {
+            self.latest_timestamp = Some(
+                self.latest_timestamp
+                    .map_or(timestamp, |latest| latest.max(timestamp)),
+            );
+        }
+        let shard = AccountKey::of(&record).shard(self.shards.len());
+        self.pending[shard].push(record);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Report {
+        self.apply_pending();
+        let (pool, shards) = (self.pool, self.shards);
+        // a shard only knows the time of its own records
+        let now = self.latest_timestamp;
+        pool.install(|| {
+            shards
+                .into_par_iter()
+                .map(|mut shard| {
+                    if let Some(now) = now {
+                        shard.advance_time(now);
+                    }
+                    shard.finish()
+                })
+                .reduce(
+                    || Report::new(HashMap::new(), Vec::new()),
+                    |mut report, shard| {
+                        report.merge(shard);
+                        report
+                    },
+                )
+        })
+    }
+
+    fn snapshot(&mut self) -> Report {
+        self.apply_pending();
+        let mut report = Report::new(HashMap::new(), Vec::new());
+        for shard in &mut self.shards {
+            report.merge(shard.snapshot());
+        }
+        report
+    }
+
+    fn state(&mut self) -> Vec<SnapshotAccount> {
+        self.apply_pending();
+        self.shards
+            .iter_mut()
+            .flat_map(|shard| shard.state())
+            .collect()
+    }
+}
+
+impl RayonAccountManager {
+    /// Applies the records on `num_threads` threads, `new_shard` makes the manager of each
+    /// shard with the options of the run
+    pub fn new(
+        num_threads: usize,
+        new_shard: impl FnMut() -> STAccountManager,
+    ) -> Result<Self, rayon::ThreadPoolBuildError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|index| format!("rayon {}", index))
+            .build()?;
+        let num_shards = num_threads.max(1) * SHARDS_PER_THREAD;
+        Ok(Self {
+            pool,
+            shards: std::iter::repeat_with(new_shard).take(num_shards).collect(),
+            pending: std::iter::repeat_with(Vec::new).take(num_shards).collect(),
+            latest_timestamp: None,
+        })
+    }
+
+    /// Start from the accounts of a snapshot, each shard gets the accounts it owns
+    pub fn with_snapshot(mut self, snapshot: &Snapshot) -> Self {
+        let mut accounts = vec![Vec::new(); self.shards.len()];
+        for account in &snapshot.accounts {
+            accounts[account.key().shard(self.shards.len())].push(account.clone());
+        }
+        for (shard, accounts) in self.shards.iter_mut().zip(accounts) {
+            shard.restore(&accounts);
+        }
+        self
+    }
+
+    /// Applies the buffered records, the shards in parallel
+    fn apply_pending(&mut self) {
+        let pending = std::mem::replace(
+            &mut self.pending,
+            std::iter::repeat_with(Vec::new)
+                .take(self.shards.len())
+                .collect(),
+        );
+        let shards = &mut self.shards;
+        self.pool.install(|| {
+            shards
+                .par_iter_mut()
+                .zip(pending)
+                .for_each(|(shard, records)| shard.execute(&mut records.into_iter()));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::LockedPolicy, client_account::DisputePolicy,
+        manager_conformance::ManagerConformance, records::TransactionType,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_conformance() {
+        ManagerConformance::new(|| RayonAccountManager::new(3, STAccountManager::new).unwrap())
+            .check_all();
+        let new_shard = || {
+            STAccountManager::new()
+                .with_dispute_policy(DisputePolicy::AllowNegativeAvailable)
+                .with_locked_policy(LockedPolicy::allowing(&[TransactionType::Resolve]))
+        };
+        ManagerConformance::new(move || RayonAccountManager::new(2, new_shard).unwrap())
+            .with_reference(new_shard)
+            .check_all();
+    }
+
+    #[test]
+    fn test_incremental_snapshots() {
+        let mut manager = RayonAccountManager::new(2, STAccountManager::new).unwrap();
+        let deposit = |client, tx| TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount: Some(dec!(1.5)),
+            timestamp: None,
+            source: None,
+        };
+        manager.apply(deposit(1, 1)).unwrap();
+        manager.apply(deposit(2, 2)).unwrap();
+        assert_eq!(manager.snapshot().account(1).unwrap().total(), dec!(1.5));
+
+        manager.apply(deposit(1, 3)).unwrap();
+        manager.apply(deposit(1, 3)).unwrap();
+        assert_eq!(manager.state().len(), 2);
+        let report = manager.finish();
+        assert_eq!(report.account(1).unwrap().total(), dec!(3.0));
+        assert_eq!(report.rejects().len(), 1);
+    }
+}
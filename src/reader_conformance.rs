@@ -0,0 +1,261 @@
+/// A conformance suite for the implementations of `TransactionCSVReader`, built with the
+/// `testing` feature so the readers outside the crate can run it from their tests
+/// The checks feed the reader CSV inputs and panic on the first record it gets wrong: the
+/// records come out in the order of the input, the malformed rows are skipped without
+/// stopping the reading, the long rows and the extreme values are read whole, and the columns
+/// are found whatever their order, spacing and line endings. The readers of other formats are
+/// given an encoding converting the CSV inputs to their format: the rows that can't be parsed
+/// are dropped by the conversion, and the header variants only concern the CSV readers
+use std::io::Cursor;
+
+use rust_decimal::Decimal;
+
+use crate::{
+    records::{ClientId, MerchantId, TenantId, TransactionId, TransactionRecord, TransactionType},
+    transactions_reader::{STBulkReader, TransactionCSVReader},
+};
+
+/// Records in the ordering check, enough for many blocks of the multithreaded reader
+const ORDERING_RECORDS: TransactionId = 50_000;
+/// Padding of the long rows, longer than the blocks of the multithreaded reader
+const LONG_ROW_PADDING: usize = 100 * 1024;
+
+/// The fields of a record, to compare what a reader read with what it should have
+pub type RecordFields = (
+    TransactionType,
+    ClientId,
+    Option<TenantId>,
+    Option<MerchantId>,
+    TransactionId,
+    Option<Decimal>,
+    Option<u64>,
+);
+
+pub fn fields(record: &TransactionRecord) -> RecordFields {
+    (
+        record.tr_type,
+        record.client,
+        record.tenant,
+        record.merchant,
+        record.tx,
+        record.amount,
+        record.timestamp,
+    )
+}
+
+/// The fields of a record without the optional columns
+fn row(
+    tr_type: TransactionType,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Decimal>,
+) -> RecordFields {
+    (tr_type, client, None, None, tx, amount, None)
+}
+
+/// Converts a CSV input to the format of a reader
+type Encoding = Box<dyn Fn(&str) -> Vec<u8>>;
+
+/// Runs the checks on the readers made by `new_reader`, a new one per input
+pub struct ReaderConformance<R> {
+    new_reader: Box<dyn Fn() -> R>,
+    /// None for the CSV readers
+    encoding: Option<Encoding>,
+}
+
+impl<R: TransactionCSVReader> ReaderConformance<R> {
+    pub fn new(new_reader: impl Fn() -> R + 'static) -> Self {
+        Self {
+            new_reader: Box::new(new_reader),
+            encoding: None,
+        }
+    }
+
+    /// For the readers of another format than CSV, see `csv_records`
+    pub fn with_encoding(mut self, encoding: impl Fn(&str) -> Vec<u8> + 'static) -> Self {
+        self.encoding = Some(Box::new(encoding));
+        self
+    }
+
+    /// Runs all the checks of the format of the reader
+    pub fn check_all(&self) {
+        self.check_ordering();
+        self.check_malformed_rows();
+        self.check_large_records();
+        if self.encoding.is_none() {
+            self.check_headers();
+        }
+        self.check_empty_input();
+    }
+
+    /// The records come out in the order of the input
+    pub fn check_ordering(&self) {
+        let mut csv = String::from("type,client,tx,amount\n");
+        let mut expected = Vec::new();
+        for tx in 1..=ORDERING_RECORDS {
+            let client = (tx % 7) as ClientId;
+            match tx % 3 {
+                0 => {
+                    csv += &format!("withdrawal,{},{},0.5\n", client, tx);
+                    let amount = Decimal::new(5, 1);
+                    expected.push(row(TransactionType::Withdrawal, client, tx, Some(amount)));
+                }
+                1 => {
+                    csv += &format!("deposit,{},{},{}.25\n", client, tx, tx);
+                    let amount = Decimal::new(tx as i64 * 100 + 25, 2);
+                    expected.push(row(TransactionType::Deposit, client, tx, Some(amount)));
+                }
+                _ => {
+                    csv += &format!("dispute,{},{},\n", client, tx - 1);
+                    expected.push(row(TransactionType::Dispute, client, tx - 1, None));
+                }
+            }
+        }
+        let read = self.read("ordering", &csv);
+        if let Some(index) = (0..read.len().min(expected.len())).find(|i| read[*i] != expected[*i])
+        {
+            panic!(
+                "ordering: the record {} is {:?} instead of {:?}",
+                index + 1,
+                read[index],
+                expected[index]
+            );
+        }
+        assert_eq!(
+            read.len(),
+            expected.len(),
+            "ordering: wrong number of records"
+        );
+    }
+
+    /// The malformed rows are skipped, the rows around them are still read
+    pub fn check_malformed_rows(&self) {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 1.0\n\
+                   bogus, 1, 2, 1.0\n\
+                   deposit, x, 3, 1.0\n\
+                   deposit, 1, -4, 1.0\n\
+                   deposit, 1, 5, abc\n\
+                   deposit, 99999999999999999999, 6, 1.0\n\
+                   deposit, 1\n\
+                   \"deposit\",1,8,\"2.5\"\n\
+                   withdrawal, 1, 9, 0.5";
+        assert_eq!(
+            self.read("malformed rows", csv),
+            vec![
+                row(TransactionType::Deposit, 1, 1, Some(Decimal::ONE)),
+                row(TransactionType::Deposit, 1, 8, Some(Decimal::new(25, 1))),
+                row(TransactionType::Withdrawal, 1, 9, Some(Decimal::new(5, 1))),
+            ],
+            "malformed rows"
+        );
+    }
+
+    /// The rows longer than a block and the extreme values are read whole
+    pub fn check_large_records(&self) {
+        let padding = " ".repeat(LONG_ROW_PADDING);
+        // the CSV readers parse the amounts exactly, 15 digits stay exact through a float too
+        let csv = format!(
+            "type,client,tx,amount\n\
+             deposit,{},{},99999999999.9999\n\
+             deposit,1,2,{}1.5{}\n\
+             withdrawal,0,0,0.0001\n",
+            ClientId::MAX,
+            TransactionId::MAX,
+            padding,
+            padding
+        );
+        let max = Decimal::new(999_999_999_999_999, 4);
+        assert_eq!(
+            self.read("large records", &csv),
+            vec![
+                row(
+                    TransactionType::Deposit,
+                    ClientId::MAX,
+                    TransactionId::MAX,
+                    Some(max)
+                ),
+                row(TransactionType::Deposit, 1, 2, Some(Decimal::new(15, 1))),
+                row(TransactionType::Withdrawal, 0, 0, Some(Decimal::new(1, 4))),
+            ],
+            "large records"
+        );
+    }
+
+    /// The columns are found whatever their order, spacing and line endings
+    pub fn check_headers(&self) {
+        let expected = vec![
+            row(TransactionType::Deposit, 3, 10, Some(Decimal::new(125, 2))),
+            row(TransactionType::Dispute, 3, 10, None),
+        ];
+        let variants = [
+            "type,client,tx,amount\ndeposit,3,10,1.25\ndispute,3,10,\n",
+            "type, client, tx, amount\ndeposit, 3, 10, 1.25\ndispute, 3, 10,\n",
+            "  type ,client,  tx,amount  \n  deposit,3 , 10,1.25  \n dispute ,3,10 ,  \n",
+            "type,client,tx,amount\r\ndeposit,3,10,1.25\r\ndispute,3,10,\r\n",
+            "tx,amount,client,type\n10,1.25,3,deposit\n10,,3,dispute",
+            // the disputes can leave the amount column out
+            "type,client,tx,amount\ndeposit,3,10,1.25\ndispute,3,10\n",
+        ];
+        for (index, csv) in variants.iter().enumerate() {
+            let check = format!("headers {}", index + 1);
+            assert_eq!(self.read(&check, csv), expected, "{}", check);
+        }
+
+        let csv = "type,client,tenant,merchant,tx,amount,timestamp\n\
+                   deposit,3,2,,10,1.25,1000\n\
+                   payment,3,2,5,11,1,1001\n";
+        let amount = Some(Decimal::new(125, 2));
+        assert_eq!(
+            self.read("optional columns", csv),
+            vec![
+                (
+                    TransactionType::Deposit,
+                    3,
+                    Some(2),
+                    None,
+                    10,
+                    amount,
+                    Some(1000)
+                ),
+                (
+                    TransactionType::Payment,
+                    3,
+                    Some(2),
+                    Some(5),
+                    11,
+                    Some(Decimal::ONE),
+                    Some(1001)
+                ),
+            ],
+            "optional columns"
+        );
+    }
+
+    /// An input without records gives no records rather than an error
+    pub fn check_empty_input(&self) {
+        let read = self.read("empty input", "type,client,tx,amount\n");
+        assert!(read.is_empty(), "empty input");
+    }
+
+    fn read(&self, check: &str, csv: &str) -> Vec<RecordFields> {
+        let input = match &self.encoding {
+            Some(encoding) => encoding(csv),
+            None => csv.as_bytes().to_vec(),
+        };
+        (self.new_reader)()
+            .read_from(Cursor::new(input))
+            .unwrap_or_else(|err| panic!("{}: failed to read the input: {:?}", check, err))
+            .map(|record| fields(&record))
+            .collect()
+    }
+}
+
+/// The records of a CSV input, read by the single threaded reader, for the encodings of
+/// the readers of other formats
+pub fn csv_records(csv: &str) -> Vec<TransactionRecord> {
+    STBulkReader::new()
+        .read_from(Cursor::new(csv.as_bytes().to_vec()))
+        .map(Iterator::collect)
+        .unwrap_or_default()
+}
@@ -0,0 +1,100 @@
+/// The records kept by the CSV readers with `--only-types` and `--client-range`
+/// The records are filtered right after they're parsed, before they're tagged, queued and
+/// reordered, so the ones left out only cost their parsing
+use std::ops::RangeInclusive;
+
+use crate::records::{ClientId, TransactionRecord, TransactionType};
+
+/// Keeps all the records unless it's given types or clients
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct RecordFilter {
+    types: Option<Vec<TransactionType>>,
+    clients: Option<RangeInclusive<ClientId>>,
+}
+
+impl RecordFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keeps the records of these types
+    pub fn with_types(mut self, types: Vec<TransactionType>) -> Self {
+        self.types = Some(types);
+        self
+    }
+
+    /// Only keeps the records of these clients, in all the tenants
+    pub fn with_clients(mut self, clients: RangeInclusive<ClientId>) -> Self {
+        self.clients = Some(clients);
+        self
+    }
+
+    /// Checks if all the records are kept
+    pub fn keeps_all(&self) -> bool {
+        self.types.is_none() && self.clients.is_none()
+    }
+
+    pub fn keeps(&self, record: &TransactionRecord) -> bool {
+        self.types
+            .as_ref()
+            .is_none_or(|types| types.contains(&record.tr_type))
+            && self
+                .clients
+                .as_ref()
+                .is_none_or(|clients| clients.contains(&record.client))
+    }
+}
+
+/// Parses `1000-1999`, both ends included, or a single client
+pub fn parse_client_range(range: &str) -> Result<RangeInclusive<ClientId>, String> {
+    let invalid = || format!("`{}` is not a range of clients like 1000-1999", range);
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let first: ClientId = first.trim().parse().map_err(|_| invalid())?;
+    let last: ClientId = last.trim().parse().map_err(|_| invalid())?;
+    if first > last {
+        return Err(invalid());
+    }
+    Ok(first..=last)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn record(tr_type: TransactionType, client: ClientId) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client,
+            tenant: None,
+            merchant: None,
+            tx: 1,
+            amount: Some(dec!(1)),
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        assert!(RecordFilter::new().keeps_all());
+        let filter = RecordFilter::new()
+            .with_types(vec![TransactionType::Deposit, TransactionType::Withdrawal])
+            .with_clients(1000..=1999);
+        assert!(!filter.keeps_all());
+        assert!(filter.keeps(&record(TransactionType::Deposit, 1000)));
+        assert!(filter.keeps(&record(TransactionType::Withdrawal, 1999)));
+        assert!(!filter.keeps(&record(TransactionType::Dispute, 1500)));
+        assert!(!filter.keeps(&record(TransactionType::Deposit, 2000)));
+    }
+
+    #[test]
+    fn test_client_ranges() {
+        assert_eq!(parse_client_range("1000-1999"), Ok(1000..=1999));
+        assert_eq!(parse_client_range("7"), Ok(7..=7));
+        assert!(parse_client_range("1999-1000").is_err());
+        assert!(parse_client_range("1-99999999999999999999").is_err());
+        assert!(parse_client_range("-5").is_err());
+    }
+}
@@ -0,0 +1,340 @@
+/// Parses the CSV records without serde, for the CSV readers
+/// The columns are looked up once from the headers, then every field is parsed in place from
+/// the bytes of the record: nothing is allocated per record, the parsing threads reuse their
+/// `ByteRecord`, and the amounts are read exactly rather than through a float. The rows
+/// accepted and rejected are the same as with the serde derive of `TransactionRecord`.
+/// The amounts can also be written with thousands separators and a decimal comma, they're
+/// then rewritten in the plain format before being parsed, and the lenient parser strips
+/// their currency symbols and counts the amounts it had to fix
+use std::{cell::Cell, str::FromStr};
+
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+
+use crate::records::{TransactionRecord, TransactionType};
+
+/// The currency symbols stripped from the amounts by the lenient parser
+const CURRENCY_SYMBOLS: [&str; 3] = ["$", "€", "£"];
+
+/// The position of each column in the records, None if the input doesn't have it
+#[derive(Clone, Debug)]
+pub struct RecordParser {
+    tr_type: Option<usize>,
+    client: Option<usize>,
+    tenant: Option<usize>,
+    merchant: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+    amount_format: AmountFormat,
+    lenient: bool,
+    /// The amounts parsed after stripping a currency symbol or spaces
+    normalized: Cell<u64>,
+}
+
+/// How the amounts of the input are written
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum AmountFormat {
+    /// `1234.56`, or in scientific notation
+    Plain,
+    /// `1,234.56`, the thousands separated by commas (the amounts have to be quoted)
+    Thousands,
+    /// `1.234,56` or `1 234,56`, a decimal comma (the amounts have to be quoted)
+    DecimalComma,
+}
+
+impl RecordParser {
+    /// The columns can be in any order, the unknown ones are ignored
+    pub fn new(headers: &ByteRecord) -> Self {
+        let column = |name: &str| headers.iter().position(|header| header == name.as_bytes());
+        Self {
+            tr_type: column("type"),
+            client: column("client"),
+            tenant: column("tenant"),
+            merchant: column("merchant"),
+            tx: column("tx"),
+            amount: column("amount"),
+            timestamp: column("timestamp"),
+            amount_format: AmountFormat::Plain,
+            lenient: false,
+            normalized: Cell::new(0),
+        }
+    }
+
+    pub fn with_amount_format(mut self, amount_format: AmountFormat) -> Self {
+        self.amount_format = amount_format;
+        self
+    }
+
+    /// Strip the currency symbols and the spaces around the amounts, like `$ 12.50`
+    pub fn with_lenient_amounts(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    /// The amounts normalized since the last call
+    pub fn take_normalized(&self) -> u64 {
+        self.normalized.take()
+    }
+
+    /// None if a required field is missing or a field can't be parsed
+    pub fn parse(&self, record: &ByteRecord) -> Option<TransactionRecord> {
+        Some(TransactionRecord {
+            tr_type: parse_type(required(record, self.tr_type)?)?,
+            client: parse_number(required(record, self.client)?)?,
+            tenant: optional(record, self.tenant, parse_number)?,
+            merchant: optional(record, self.merchant, parse_number)?,
+            tx: parse_number(required(record, self.tx)?)?,
+            amount: optional(record, self.amount, |field| self.parse_amount(field))?,
+            timestamp: optional(record, self.timestamp, parse_number)?,
+            source: None,
+        })
+    }
+
+    fn parse_amount(&self, field: &[u8]) -> Option<Decimal> {
+        let stripped = if self.lenient {
+            strip_currency(field)
+        } else {
+            None
+        };
+        let field = stripped.as_deref().unwrap_or(field);
+        let amount = match self.amount_format {
+            AmountFormat::Plain => parse_amount(field),
+            AmountFormat::Thousands => parse_local_amount(field, b'.', b","),
+            AmountFormat::DecimalComma => parse_local_amount(field, b',', b". "),
+        }?;
+        if stripped.is_some() {
+            self.normalized.set(self.normalized.get() + 1);
+        }
+        Some(amount)
+    }
+}
+
+/// The amount without its currency symbol and spaces, None if it has neither
+/// The sign can be before the symbol, `-$5` is `-5`
+fn strip_currency(field: &[u8]) -> Option<Vec<u8>> {
+    let trimmed = field.trim_ascii();
+    let (sign, unsigned) = match trimmed.split_first() {
+        Some((sign @ (b'-' | b'+'), rest)) => (Some(*sign), rest),
+        _ => (None, trimmed),
+    };
+    let number = CURRENCY_SYMBOLS
+        .iter()
+        .find_map(|symbol| {
+            let symbol = symbol.as_bytes();
+            unsigned
+                .strip_prefix(symbol)
+                .or_else(|| unsigned.strip_suffix(symbol))
+        })
+        .unwrap_or(unsigned)
+        .trim_ascii();
+    if number.len() + usize::from(sign.is_some()) == field.len() {
+        return None;
+    }
+    let mut stripped = Vec::with_capacity(number.len() + 1);
+    stripped.extend(sign);
+    stripped.extend_from_slice(number);
+    Some(stripped)
+}
+
+fn required(record: &ByteRecord, column: Option<usize>) -> Option<&[u8]> {
+    record.get(column?)
+}
+
+/// Some(None) if the column or the field is missing or empty, None if the field is invalid
+fn optional<T>(
+    record: &ByteRecord,
+    column: Option<usize>,
+    parse: impl Fn(&[u8]) -> Option<T>,
+) -> Option<Option<T>> {
+    match column.and_then(|column| record.get(column)) {
+        None | Some(b"") => Some(None),
+        Some(field) => parse(field).map(Some),
+    }
+}
+
+pub(crate) fn parse_type(field: &[u8]) -> Option<TransactionType> {
+    match field {
+        b"deposit" => Some(TransactionType::Deposit),
+        b"withdrawal" => Some(TransactionType::Withdrawal),
+        b"dispute" => Some(TransactionType::Dispute),
+        b"resolve" => Some(TransactionType::Resolve),
+        b"chargeback" => Some(TransactionType::ChargeBack),
+        b"settle" => Some(TransactionType::Settle),
+        b"payment" => Some(TransactionType::Payment),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_number<T: FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// Without the trailing zeros, `3.0` is written back as `3` like the amounts parsed by serde
+pub(crate) fn parse_amount(field: &[u8]) -> Option<Decimal> {
+    let amount = std::str::from_utf8(field).ok()?;
+    Decimal::from_str(amount)
+        .or_else(|_| Decimal::from_scientific(amount))
+        .map(|amount| amount.normalize())
+        .ok()
+}
+
+/// Rewrites the amount in the plain format, the groups of thousands have to be complete so
+/// that `1.5` isn't read as 15 when the decimal separator is a comma
+fn parse_local_amount(field: &[u8], decimal: u8, thousands: &[u8]) -> Option<Decimal> {
+    let (sign, digits) = match field.split_first()? {
+        (sign @ (b'-' | b'+'), digits) => (Some(*sign), digits),
+        _ => (None, field),
+    };
+    let (integer, fraction) = match digits.iter().position(|byte| *byte == decimal) {
+        Some(point) => (&digits[..point], Some(&digits[point + 1..])),
+        None => (digits, None),
+    };
+    let mut plain = String::with_capacity(field.len());
+    plain.extend(sign.map(char::from));
+    let groups: Vec<&[u8]> = integer.split(|byte| thousands.contains(byte)).collect();
+    for (index, group) in groups.iter().enumerate() {
+        let complete = match index {
+            0 => !group.is_empty() && (groups.len() == 1 || group.len() <= 3),
+            _ => group.len() == 3,
+        };
+        if !complete || !group.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        plain.extend(group.iter().map(|byte| char::from(*byte)));
+    }
+    if let Some(fraction) = fraction {
+        if fraction.is_empty() || !fraction.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        plain.push('.');
+        plain.extend(fraction.iter().map(|byte| char::from(*byte)));
+    }
+    Decimal::from_str(&plain)
+        .map(|amount| amount.normalize())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::reader_conformance::{fields, RecordFields};
+
+    use super::*;
+
+    /// The fields parsed by the parser and by serde, None if the row is rejected
+    fn parse_both(headers: &str, row: &str) -> (Option<RecordFields>, Option<RecordFields>) {
+        let headers = ByteRecord::from(headers.split(',').collect::<Vec<_>>());
+        let row = ByteRecord::from(row.split(',').collect::<Vec<_>>());
+        let parsed = RecordParser::new(&headers).parse(&row);
+        let deserialized = row.deserialize::<TransactionRecord>(Some(&headers)).ok();
+        (
+            parsed.as_ref().map(fields),
+            deserialized.as_ref().map(fields),
+        )
+    }
+
+    #[test]
+    fn test_same_as_serde() {
+        let rows = [
+            ("type,client,tx,amount", "deposit,1,2,1.5"),
+            ("type,client,tx,amount", "dispute,1,2,"),
+            ("type,client,tx,amount", "dispute,1,2"),
+            ("tx,amount,client,type", "2,1.5,1,withdrawal"),
+            (
+                "type,client,tenant,merchant,tx,amount,timestamp",
+                "payment,1,2,3,4,5,6",
+            ),
+            ("type,client,tenant,tx,amount,extra", "deposit,1,,2,1.5,x"),
+            ("type,client,tx,amount", "deposit,+1,2,-1.5"),
+            ("type,client,tx,amount", "deposit,1,2,1e3"),
+            // rejected
+            ("type,client,tx,amount", "bogus,1,2,1.5"),
+            ("type,client,tx,amount", "Deposit,1,2,1.5"),
+            ("type,client,tx,amount", "deposit,70000,2,1.5"),
+            ("type,client,tx,amount", "deposit,1,-2,1.5"),
+            ("type,client,tx,amount", "deposit,1,2,abc"),
+            ("type,client,tx,amount", "deposit,1,,1.5"),
+            ("type,client,tx,amount", "deposit,1"),
+            ("type,client,tenant,tx,amount", "deposit,1,x,2,1.5"),
+            ("type,client,tx,amount,timestamp", "deposit,1,2,1.5,1.5"),
+            ("type,tx,amount", "deposit,2,1.5"),
+        ];
+        for (headers, row) in rows {
+            let (parsed, deserialized) = parse_both(headers, row);
+            assert_eq!(parsed, deserialized, "{}", row);
+        }
+    }
+
+    #[test]
+    fn test_exact_amounts() {
+        // beyond the 15 digits of a float
+        let (parsed, _) = parse_both("type,client,tx,amount", "deposit,1,2,1234567890123.456789");
+        assert_eq!(parsed.unwrap().5, Some(dec!(1234567890123.456789)));
+        let (parsed, _) = parse_both("type,client,tx,amount", "deposit,1,2,3.000");
+        assert_eq!(parsed.unwrap().5.unwrap().to_string(), "3");
+    }
+
+    #[test]
+    fn test_amount_formats() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        let amount = |format: AmountFormat, amount: &str| {
+            let row = ByteRecord::from(vec!["deposit", "1", "2", amount]);
+            RecordParser::new(&headers)
+                .with_amount_format(format)
+                .parse(&row)
+                .map(|record| record.amount.unwrap().to_string())
+        };
+        let comma = AmountFormat::DecimalComma;
+        assert_eq!(amount(comma, "1.234,56").as_deref(), Some("1234.56"));
+        assert_eq!(amount(comma, "1 234 567,5").as_deref(), Some("1234567.5"));
+        assert_eq!(amount(comma, "-12,50").as_deref(), Some("-12.5"));
+        assert_eq!(amount(comma, "1234").as_deref(), Some("1234"));
+        assert_eq!(amount(comma, "0,0001").as_deref(), Some("0.0001"));
+        let thousands = AmountFormat::Thousands;
+        assert_eq!(amount(thousands, "1,234.56").as_deref(), Some("1234.56"));
+        assert_eq!(amount(thousands, "+999.5").as_deref(), Some("999.5"));
+
+        // not a number of the format, or an ambiguous one
+        for bad in [
+            "1.5", "1.2345,6", "12,34,5", ",5", "1,", "1.234.5", "1e3", "-", "1,2,3",
+        ] {
+            assert_eq!(amount(comma, bad), None, "{}", bad);
+        }
+        for bad in ["1,5", "1234,567", "1.2.3", "1,234,56"] {
+            assert_eq!(amount(thousands, bad), None, "{}", bad);
+        }
+        assert_eq!(amount(AmountFormat::Plain, "1,5"), None);
+    }
+
+    #[test]
+    fn test_lenient_amounts() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        let parser = RecordParser::new(&headers).with_lenient_amounts();
+        let amount = |amount: &str| {
+            let row = ByteRecord::from(vec!["deposit", "1", "2", amount]);
+            parser.parse(&row).map(|record| record.amount.unwrap())
+        };
+        assert_eq!(amount("$12.50"), Some(dec!(12.5)));
+        assert_eq!(amount(" 12.50 € "), Some(dec!(12.5)));
+        assert_eq!(amount("-$ 3"), Some(dec!(-3)));
+        assert_eq!(amount("£0.25"), Some(dec!(0.25)));
+        assert_eq!(parser.take_normalized(), 4);
+        assert_eq!(amount("7.5"), Some(dec!(7.5)));
+        assert_eq!(amount("$"), None);
+        assert_eq!(amount("$$5"), None);
+        assert_eq!(amount("5 USD"), None);
+        assert_eq!(parser.take_normalized(), 0);
+
+        // with the other formats
+        let parser = parser.with_amount_format(AmountFormat::DecimalComma);
+        let row = ByteRecord::from(vec!["deposit", "1", "2", "1.234,50 €"]);
+        assert_eq!(parser.parse(&row).unwrap().amount, Some(dec!(1234.5)));
+        assert_eq!(parser.take_normalized(), 1);
+
+        // strict by default
+        let row = ByteRecord::from(vec!["deposit", "1", "2", "$12.50"]);
+        assert!(RecordParser::new(&headers).parse(&row).is_none());
+    }
+}
@@ -1,8 +1,10 @@
+use std::{str::FromStr, sync::Arc};
+
 use rust_decimal::Decimal;
 use serde::Deserialize;
 
 /// Defines a transaction type to the client's asset account
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Deserialize, PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub enum TransactionType {
     /// Deposit will increase the total funds in the client account
     #[serde(rename = "deposit")]
@@ -23,21 +25,145 @@ pub enum TransactionType {
     /// If a chargeback occurs, the account is frozen
     #[serde(rename = "chargeback")]
     ChargeBack,
+    /// Finalizes a withdrawal waiting for its settlement, its funds leave the account
+    /// Only accepted when the withdrawals are settled later, see `--settle-withdrawals`
+    #[serde(rename = "settle")]
+    Settle,
+    /// Debits the client and credits a merchant with the amount, the merchant is paid out at
+    /// its next settlement
+    #[serde(rename = "payment")]
+    Payment,
+}
+
+impl FromStr for TransactionType {
+    type Err = anyhow::Error;
+
+    /// Parses the same names that are used in the CSV files
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::ChargeBack),
+            "settle" => Ok(TransactionType::Settle),
+            "payment" => Ok(TransactionType::Payment),
+            _ => Err(anyhow::anyhow!("Unknown transaction type: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for TransactionType {
+    /// Uses the same names as the CSV files
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::ChargeBack => "chargeback",
+            TransactionType::Settle => "settle",
+            TransactionType::Payment => "payment",
+        })
+    }
 }
 
+/// The widths of the ids are picked with the `client-id-u32`, `client-id-u64` and `tx-id-u64`
+/// features, the narrow ones keep the accounts and the queued records small
+#[cfg(not(feature = "tx-id-u64"))]
 pub type TransactionId = u32;
+#[cfg(feature = "tx-id-u64")]
+pub type TransactionId = u64;
+#[cfg(not(any(feature = "client-id-u32", feature = "client-id-u64")))]
 pub type ClientId = u16;
+#[cfg(all(feature = "client-id-u32", not(feature = "client-id-u64")))]
+pub type ClientId = u32;
+#[cfg(feature = "client-id-u64")]
+pub type ClientId = u64;
+pub type TenantId = u16;
+/// The merchants have accounts like the clients
+pub type MerchantId = ClientId;
+
+/// An id as a u64 whatever its width, for the hashes and the atomic counters
+pub fn wide_id(id: impl Into<u64>) -> u64 {
+    id.into()
+}
+
+/// Identifies an account: a client, within its tenant when the records have one
+/// The tenants have their own clients and transaction ids, so they can't collide
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Clone, Copy)]
+pub struct AccountKey {
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+}
+
+impl AccountKey {
+    pub fn new(tenant: Option<TenantId>, client: ClientId) -> Self {
+        Self { tenant, client }
+    }
+
+    /// The account a record applies to
+    pub fn of(record: &TransactionRecord) -> Self {
+        Self::new(record.tenant, record.client)
+    }
+
+    /// The shard of the account among `shards`, the worker that owns it
+    pub fn shard(self, shards: usize) -> usize {
+        let tenant = self.tenant.map_or(0, |tenant| tenant as usize + 1);
+        (tenant * 31 + self.client as usize) % shards
+    }
+}
+
+/// The account of a client without a tenant
+impl From<ClientId> for AccountKey {
+    fn from(client: ClientId) -> Self {
+        Self::new(None, client)
+    }
+}
 
 /// Represents a transaction record in our CSV
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct TransactionRecord {
     /// Transaction type (can't use the type since it's a built-in keyword)
     #[serde(rename = "type")]
     pub tr_type: TransactionType,
     /// The id to uniquely identify the client
     pub client: ClientId,
+    /// The business unit the client belongs to, for inputs mixing several of them
+    #[serde(default)]
+    pub tenant: Option<TenantId>,
+    /// The merchant credited by a payment, in the tenant of the client
+    #[serde(default)]
+    pub merchant: Option<MerchantId>,
     /// Transaction id, needed for disputes
     pub tx: TransactionId,
     /// Amount of money. Only available for deposits, withdrawal and chargebacks
     pub amount: Option<Decimal>,
+    /// When the transaction happened, as a Unix timestamp in a unit common to all the inputs
+    /// Optional, only used to merge several inputs in chronological order
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Where the record comes from, only set if the reader was asked to tag the records
+    #[serde(skip)]
+    pub source: Option<RecordSource>,
+}
+
+/// The origin of a record, to trace a bad record back to its input
+#[derive(PartialEq, Debug, Clone)]
+pub struct RecordSource {
+    /// Name of the input, as given on the command line
+    pub input: Arc<str>,
+    /// Line of the record in a CSV input (the header is line 1),
+    /// or its position in the inputs of the other formats (the first record is 1)
+    pub line: u64,
+}
+
+impl RecordSource {
+    /// Source of the record at `line`, if the records of the `input` are tagged
+    pub fn tag(input: Option<&Arc<str>>, line: u64) -> Option<Self> {
+        input.map(|input| Self {
+            input: input.clone(),
+            line,
+        })
+    }
 }
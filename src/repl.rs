@@ -0,0 +1,194 @@
+/// An interactive session on top of a live set of accounts
+/// Useful for reproducing customer scenarios by hand
+use std::{
+    io::{BufRead, Write},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+use paytoy::{
+    account_manager::{AccountManager, STAccountManager},
+    records::{ClientId, TransactionId, TransactionRecord, TransactionType},
+    report::write_accounts,
+    transactions_reader::{STBulkReader, TransactionCSVReader},
+};
+
+static HELP: &str = "\
+Commands:
+  deposit <client> <tx> <amount>      deposit funds
+  withdrawal <client> <tx> <amount>   withdraw funds
+  dispute <client> <tx> [amount]      open a dispute on a deposit, or a part of it
+  resolve <client> <tx> [amount]      resolve a dispute, the one of that amount
+  chargeback <client> <tx> [amount]   chargeback a dispute, the one of that amount
+  settle <client> <tx>                settle a pending withdrawal
+  payment <client> <tx> <amount> <merchant>
+                                      pay a merchant
+  show <client>                       show a single account
+  report                              show all the accounts
+  load <file>                         apply all the transactions from a CSV file
+  help                                show this message
+  quit                                exit the session";
+
+pub struct Repl {
+    manager: STAccountManager,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            manager: STAccountManager::new(),
+        }
+    }
+
+    /// Reads commands line by line from `input` until it's closed or the user quits
+    pub fn run(&mut self, input: impl BufRead, output: &mut impl Write) -> anyhow::Result<()> {
+        write!(output, "paytoy> ")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            match self.execute(&line, output) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(err) => writeln!(output, "error: {:#}", err)?,
+            }
+            write!(output, "paytoy> ")?;
+            output.flush()?;
+        }
+
+        writeln!(output)?;
+        Ok(())
+    }
+
+    /// Executes a single command, returns `false` if the session shall end
+    fn execute(&mut self, line: &str, output: &mut impl Write) -> anyhow::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(true),
+        };
+
+        match command {
+            "quit" | "exit" => return Ok(false),
+            "help" => writeln!(output, "{}", HELP)?,
+            "report" => {
+                let accounts: Vec<_> = self.manager.accounts().collect();
+                write_accounts(output, accounts.into_iter(), None)?
+            }
+            "show" => {
+                let client: ClientId = parse_arg(args, 0, "client")?;
+                let account = self
+                    .manager
+                    .account(client)
+                    .with_context(|| format!("Client {} does not exist", client))?;
+                write_accounts(output, std::iter::once(account), None)?;
+            }
+            "load" => {
+                let path = args.first().with_context(|| "Missing file name")?;
+                let transactions = STBulkReader::new().read_csv(path)?;
+                let (mut applied, mut rejected) = (0, 0);
+                for record in transactions {
+                    match self.manager.apply(record) {
+                        Ok(_) => applied += 1,
+                        Err(_) => rejected += 1,
+                    }
+                }
+                writeln!(output, "applied {}, rejected {}", applied, rejected)?;
+            }
+            _ => {
+                let tr_type = TransactionType::from_str(command)
+                    .with_context(|| "Type 'help' to see the available commands")?;
+                let amount = match tr_type {
+                    TransactionType::Deposit
+                    | TransactionType::Withdrawal
+                    | TransactionType::Payment => Some(parse_arg(args, 2, "amount")?),
+                    // a partial dispute, or the one of that amount
+                    TransactionType::Dispute
+                    | TransactionType::Resolve
+                    | TransactionType::ChargeBack
+                        if args.len() > 2 =>
+                    {
+                        Some(parse_arg(args, 2, "amount")?)
+                    }
+                    _ => None,
+                };
+                let merchant = match tr_type {
+                    TransactionType::Payment => Some(parse_arg(args, 3, "merchant")?),
+                    _ => None,
+                };
+                let record = TransactionRecord {
+                    tr_type,
+                    client: parse_arg(args, 0, "client")?,
+                    tenant: None,
+                    merchant,
+                    tx: parse_arg::<TransactionId>(args, 1, "tx")?,
+                    amount,
+                    timestamp: None,
+                    source: None,
+                };
+
+                match self.manager.apply(record) {
+                    Ok(_) => writeln!(output, "ok")?,
+                    Err(err) => writeln!(output, "rejected: {}", err)?,
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Parses the positional argument at `index` of a command
+fn parse_arg<T: FromStr>(args: &[&str], index: usize, name: &str) -> anyhow::Result<T> {
+    let arg = args
+        .get(index)
+        .with_context(|| format!("Missing argument <{}>", name))?;
+    T::from_str(arg).map_err(|_| anyhow::anyhow!("Invalid <{}>: {}", name, arg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_session(commands: &str) -> String {
+        let mut output = Vec::new();
+        Repl::new()
+            .run(commands.as_bytes(), &mut output)
+            .expect("Session failed");
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_transactions_and_show() {
+        let output = run_session(
+            "deposit 1 1 10.0\n\
+             withdrawal 1 2 4.5\n\
+             withdrawal 1 3 40\n\
+             show 1\n",
+        );
+
+        assert!(output.contains("rejected: Insufficient funds"));
+        assert!(
+            output.contains("     1,         5.5000,         0.0000,         5.5000,     false")
+        );
+    }
+
+    #[test]
+    fn test_load_and_quit() {
+        let output = run_session("load tests/data/test_basic.csv\nquit\nshow 1\n");
+
+        assert!(output.contains("applied 4, rejected 1"));
+        // nothing is executed after quit
+        assert!(!output.contains("client,"));
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let output = run_session("transfer 1 2\ndeposit 1 x 5\nshow 7\n");
+
+        assert!(output.contains("error: Type 'help'"));
+        assert!(output.contains("error: Invalid <tx>: x"));
+        assert!(output.contains("error: Client 7 does not exist"));
+    }
+}
@@ -0,0 +1,148 @@
+/// A window of the records seen recently, to drop the ones that are delivered twice
+/// Producers that deliver at least once may repeat a batch of records after a retry. The
+/// account history only catches the repeated deposits while they're kept, so the window
+/// remembers the last records on its own: a repeated withdrawal or a deposit that was already
+/// resolved is dropped as well. A record only counts as a replay if its amount is the same, a
+/// different one goes on to be rejected by the account
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use hashbrown::HashSet;
+use rust_decimal::Decimal;
+
+use crate::records::{AccountKey, TransactionId, TransactionRecord, TransactionType};
+
+type RecordKey = (TransactionType, AccountKey, TransactionId, Option<Decimal>);
+
+fn key(record: &TransactionRecord) -> RecordKey {
+    (
+        record.tr_type,
+        AccountKey::of(record),
+        record.tx,
+        record.amount,
+    )
+}
+
+pub struct ReplayWindow {
+    /// Number of records remembered
+    capacity: usize,
+    /// How long a record is remembered, as long as it's in the window by default
+    ttl: Option<Duration>,
+    seen: HashSet<RecordKey>,
+    /// The records of the window with the time they were seen, oldest first
+    order: VecDeque<(RecordKey, Instant)>,
+}
+
+impl ReplayWindow {
+    /// Remembers the last `capacity` records
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ttl: None,
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Forgets the records after `ttl`, even if the window isn't full
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Checks if the record repeats one of the window, it's remembered otherwise
+    pub fn is_replay(&mut self, record: &TransactionRecord, now: Instant) -> bool {
+        self.expire(now);
+        if self.contains(record) {
+            return true;
+        }
+        self.remember(record, now);
+        false
+    }
+
+    /// Checks if the record repeats one of the window, without remembering it
+    pub fn contains(&self, record: &TransactionRecord) -> bool {
+        self.seen.contains(&key(record))
+    }
+
+    /// Adds the record to the window, pushing the oldest one out of a full window
+    pub fn remember(&mut self, record: &TransactionRecord, now: Instant) {
+        let key = key(record);
+        if self.seen.contains(&key) {
+            return;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key);
+        self.order.push_back((key, now));
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        while let Some((key, _)) = self
+            .order
+            .front()
+            .filter(|(_, seen_at)| now.duration_since(*seen_at) >= ttl)
+        {
+            self.seen.remove(key);
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn record(
+        tr_type: TransactionType,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount,
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let start = Instant::now();
+        let mut window = ReplayWindow::new(3);
+        let deposit = record(TransactionType::Deposit, 1, Some(dec!(2.0)));
+
+        assert!(!window.is_replay(&deposit, start));
+        assert!(window.is_replay(&deposit, start));
+        // the dispute of the deposit, and a conflicting deposit, aren't replays
+        assert!(!window.is_replay(&record(TransactionType::Dispute, 1, None), start));
+        assert!(!window.is_replay(&record(TransactionType::Deposit, 1, Some(dec!(3.0))), start));
+
+        // pushed out of the window
+        assert!(!window.is_replay(
+            &record(TransactionType::Withdrawal, 2, Some(dec!(1.0))),
+            start
+        ));
+        assert!(!window.is_replay(&deposit, start));
+
+        let mut window = ReplayWindow::new(100).with_ttl(Duration::from_secs(10));
+        assert!(!window.is_replay(&deposit, start));
+        assert!(window.is_replay(&deposit, start + Duration::from_secs(9)));
+        assert!(!window.is_replay(&deposit, start + Duration::from_secs(10)));
+    }
+}
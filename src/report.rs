@@ -0,0 +1,780 @@
+/// The final report after executing all the transactions and the ways to print it
+use std::{
+    borrow::Cow,
+    io::{IsTerminal, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use hashbrown::HashMap;
+use rust_decimal::Decimal;
+
+use crate::{
+    accounts_meta::{AccountsMeta, META_HEADERS},
+    client_account::ClientAccount,
+    errors::TransactionError,
+    records::{
+        AccountKey, ClientId, RecordSource, TenantId, TransactionId, TransactionRecord,
+        TransactionType,
+    },
+};
+
+/// Prefix of the outputs written to a SQLite database
+const SQLITE_SCHEME: &str = "sqlite://";
+/// Prefixes of the outputs upserted into a PostgreSQL table
+const POSTGRES_SCHEMES: [&str; 2] = ["postgres://", "postgresql://"];
+
+const HEADERS: [&str; 5] = ["client", "available", "held", "total", "locked"];
+/// Header of the column added when the accounts have tenants
+const TENANT_HEADER: &str = "tenant";
+/// Header of the column added when the withdrawals wait for their settlement
+const PENDING_OUT_HEADER: &str = "pending_out";
+/// Header of the column added when the records of the clients are limited
+const OVER_LIMIT_HEADER: &str = "over_limit";
+/// Reason code of the records that couldn't be parsed
+const PARSE_ERROR: &str = "parse_error";
+
+/// Checks if the output is a database URL rather than a file
+pub fn is_database(output: &Path) -> bool {
+    let url = output.to_str().unwrap_or_default();
+    url.starts_with(SQLITE_SCHEME)
+        || POSTGRES_SCHEMES
+            .iter()
+            .any(|scheme| url.starts_with(scheme))
+}
+
+/// How the report shall be formatted
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// Aligned table meant to be read by humans
+    Table,
+    /// The original CSV-like output, meant to be parsed by other tools
+    Plain,
+    /// Parquet file for analytics tools (requires the `parquet` feature)
+    Parquet,
+}
+
+/// When to highlight locked accounts
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Only when writing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    pub format: ReportFormat,
+    /// Number of decimal places of the amounts in a table or a Parquet file
+    pub precision: usize,
+    pub color: ColorChoice,
+    /// Table the accounts are upserted into for PostgreSQL outputs
+    pub table: String,
+    /// Fields joined into the accounts of the text reports and the SQLite tables
+    pub meta: Option<Arc<AccountsMeta>>,
+    /// Write the accounts of the files ordered by tenant and client, rather than in the order
+    /// of their hashes which changes at every run
+    pub sorted: bool,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            format: ReportFormat::Table,
+            precision: 4,
+            color: ColorChoice::Auto,
+            table: "accounts".to_string(),
+            meta: None,
+            sorted: false,
+        }
+    }
+}
+
+/// A transaction that couldn't be applied and the reason why
+// only the SQLite output reads the details of the rejects for now
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct Reject {
+    pub tr_type: TransactionType,
+    pub client: ClientId,
+    pub tenant: Option<TenantId>,
+    pub tx: TransactionId,
+    pub amount: Option<Decimal>,
+    pub reason: TransactionError,
+    /// Only known if the records were tagged
+    pub source: Option<RecordSource>,
+}
+
+impl Reject {
+    pub fn new(record: &TransactionRecord, reason: TransactionError) -> Self {
+        Self {
+            tr_type: record.tr_type,
+            client: record.client,
+            tenant: record.tenant,
+            tx: record.tx,
+            amount: record.amount,
+            reason,
+            source: record.source.clone(),
+        }
+    }
+}
+
+pub struct Report {
+    accounts: HashMap<AccountKey, ClientAccount>,
+    rejects: Vec<Reject>,
+    /// The run was cancelled, the report only covers the records applied before
+    cancelled: bool,
+}
+
+impl Report {
+    pub fn new(accounts: HashMap<AccountKey, ClientAccount>, rejects: Vec<Reject>) -> Self {
+        Self {
+            accounts,
+            rejects,
+            cancelled: false,
+        }
+    }
+
+    /// Flags the report of a cancelled run
+    pub fn with_cancelled(mut self, cancelled: bool) -> Self {
+        self.cancelled = cancelled;
+        self
+    }
+
+    /// Adds the accounts of another report, replacing the accounts with the same id
+    /// The merged report is cancelled if either of them is
+    pub fn merge(&mut self, other: Report) {
+        self.accounts.extend(other.accounts);
+        self.rejects.extend(other.rejects);
+        self.cancelled |= other.cancelled;
+    }
+
+    /// Whether the run was cancelled before the end of its input, see `CancellationToken`
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// The account of a client, `AccountKey::new(Some(tenant), client)` for one of a tenant
+    #[allow(dead_code)]
+    pub fn account(&self, key: impl Into<AccountKey>) -> Option<&ClientAccount> {
+        self.accounts.get(&key.into())
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &ClientAccount> + Clone {
+        self.accounts.values()
+    }
+
+    /// Takes the accounts out of the report
+    pub fn into_accounts(self) -> impl Iterator<Item = ClientAccount> {
+        self.accounts.into_iter().map(|(_, account)| account)
+    }
+
+    /// The rejected transactions, in order for each client
+    pub fn rejects(&self) -> &[Reject] {
+        &self.rejects
+    }
+
+    /// Number of dropped records for each reason code, the most frequent first
+    /// `parse_errors` is the number of records the readers couldn't parse
+    pub fn reject_summary(&self, parse_errors: u64) -> Vec<(&'static str, u64)> {
+        let mut counts: HashMap<&'static str, u64> = HashMap::new();
+        for reject in &self.rejects {
+            *counts.entry(reject.reason.code()).or_default() += 1;
+        }
+        if parse_errors > 0 {
+            counts.insert(PARSE_ERROR, parse_errors);
+        }
+
+        let mut summary: Vec<(&'static str, u64)> = counts.into_iter().collect();
+        summary.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        summary
+    }
+
+    /// Prints the report to stdout
+    pub fn report(&self, options: &ReportOptions) {
+        // nothing we can do if stdout is closed
+        let _ = self.write(None, options);
+    }
+
+    /// Writes the report to the `output` file, or to stdout if there's none
+    /// Database outputs (`sqlite://`, `postgres://`) are written as tables, whatever the format
+    pub fn write(&self, output: Option<&Path>, options: &ReportOptions) -> anyhow::Result<()> {
+        let output = match output {
+            Some(output) => output,
+            None => {
+                let stdout = std::io::stdout();
+                let is_terminal = stdout.is_terminal();
+                return self.write_to(stdout, is_terminal, options);
+            }
+        };
+
+        let url = output.to_str().unwrap_or_default();
+        if let Some(database) = url.strip_prefix(SQLITE_SCHEME) {
+            self.write_sqlite(Path::new(database), options.meta.as_deref())
+        } else if POSTGRES_SCHEMES
+            .iter()
+            .any(|scheme| url.starts_with(scheme))
+        {
+            self.write_postgres(url, &options.table)
+        } else {
+            self.write_to(std::fs::File::create(output)?, false, options)
+        }
+    }
+
+    /// Same as `write`, but a file output is replaced at once, so that the readers of the
+    /// file never see a partial report
+    pub fn write_atomic(
+        &self,
+        output: Option<&Path>,
+        options: &ReportOptions,
+    ) -> anyhow::Result<()> {
+        let path = match output {
+            Some(path) if !is_database(path) && (!path.exists() || path.is_file()) => path,
+            // stdout, a database, or a special file like a pipe
+            _ => return self.write(output, options),
+        };
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = Path::new(&partial);
+        self.write(Some(partial), options)?;
+        std::fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn write_sqlite(&self, database: &Path, meta: Option<&AccountsMeta>) -> anyhow::Result<()> {
+        crate::sqlite_sink::write_sqlite(database, self, meta)
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn write_sqlite(&self, _database: &Path, _meta: Option<&AccountsMeta>) -> anyhow::Result<()> {
+        Err(crate::errors::missing_feature("SQLite", "sqlite"))
+    }
+
+    #[cfg(feature = "postgres")]
+    fn write_postgres(&self, url: &str, table: &str) -> anyhow::Result<()> {
+        // the table is keyed on the client, the accounts of two tenants would overwrite each other
+        if has_tenants(self.accounts()) {
+            return Err(crate::errors::UsageError(
+                "The PostgreSQL output doesn't support the records with a tenant".to_string(),
+            )
+            .into());
+        }
+        crate::postgres_sink::upsert_accounts(url, table, self.accounts())
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    fn write_postgres(&self, _url: &str, _table: &str) -> anyhow::Result<()> {
+        Err(crate::errors::missing_feature("PostgreSQL", "postgres"))
+    }
+
+    fn write_to(
+        &self,
+        writer: impl Write + Send,
+        is_terminal: bool,
+        options: &ReportOptions,
+    ) -> anyhow::Result<()> {
+        let color = match options.color {
+            ColorChoice::Auto => is_terminal,
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        };
+
+        let meta = options.meta.as_deref();
+        let mut accounts: Vec<&ClientAccount> = self.accounts().collect();
+        if options.sorted {
+            accounts.sort_by_key(|account| account.key());
+        }
+        let accounts = accounts.iter().copied();
+        match options.format {
+            ReportFormat::Plain => {
+                write_accounts(&mut std::io::BufWriter::new(writer), accounts, meta)?
+            }
+            ReportFormat::Table => write_table(
+                &mut std::io::BufWriter::new(writer),
+                accounts,
+                options.precision,
+                color,
+                meta,
+            )?,
+            #[cfg(feature = "parquet")]
+            ReportFormat::Parquet => {
+                crate::parquet_report::write_parquet(writer, accounts, options.precision)?
+            }
+            #[cfg(not(feature = "parquet"))]
+            ReportFormat::Parquet => {
+                return Err(crate::errors::missing_feature("Parquet", "parquet"))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The optional columns of the accounts in the reports
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Columns {
+    /// The tenant of the accounts, before the client
+    pub tenants: bool,
+    /// The funds of the withdrawals waiting for their settlement, after `locked`
+    pub pending_out: bool,
+    /// The accounts that got more records than their limit, after `pending_out`
+    pub over_limit: bool,
+}
+
+impl Columns {
+    /// The columns needed by the accounts
+    pub fn of<'a>(accounts: impl Iterator<Item = &'a ClientAccount> + Clone) -> Self {
+        Self {
+            tenants: has_tenants(accounts.clone()),
+            pending_out: has_pending_out(accounts.clone()),
+            over_limit: has_transaction_limit(accounts),
+        }
+    }
+
+    /// Adds the columns needed by other accounts
+    pub fn merge(&mut self, other: Columns) {
+        self.tenants |= other.tenants;
+        self.pending_out |= other.pending_out;
+        self.over_limit |= other.over_limit;
+    }
+}
+
+/// Writes the report header followed by a row for each account
+/// The accounts are prefixed by their tenant if any of them has one, and followed by their
+/// pending withdrawals if they wait for settlement and their fields from `meta` if it's given
+pub fn write_accounts<'a>(
+    writer: &mut impl Write,
+    accounts: impl Iterator<Item = &'a ClientAccount> + Clone,
+    meta: Option<&AccountsMeta>,
+) -> std::io::Result<()> {
+    let columns = Columns::of(accounts.clone());
+    write_accounts_header(writer, columns, meta)?;
+    // since row ordering doens't matter, just report from individual accounts
+    for account in accounts {
+        write_account_row(writer, account, columns, meta)?;
+    }
+    Ok(())
+}
+
+/// Writes the header of the plain report, with the optional `columns`
+pub fn write_accounts_header(
+    writer: &mut impl Write,
+    columns: Columns,
+    meta: Option<&AccountsMeta>,
+) -> std::io::Result<()> {
+    if columns.tenants {
+        write!(writer, "{}, ", TENANT_HEADER)?;
+    }
+    // formatting should be nice if the values are not extremly large
+    write!(
+        writer,
+        "client,     available,          held,         total,   locked"
+    )?;
+    if columns.pending_out {
+        write!(writer, ", {:>14}", PENDING_OUT_HEADER)?;
+    }
+    if columns.over_limit {
+        write!(writer, ", {:>10}", OVER_LIMIT_HEADER)?;
+    }
+    if meta.is_some() {
+        write!(writer, ", {}", META_HEADERS.join(", "))?;
+    }
+    writeln!(writer)
+}
+
+/// Writes the row of an account in the plain report, under `write_accounts_header`
+pub fn write_account_row(
+    writer: &mut impl Write,
+    account: &ClientAccount,
+    columns: Columns,
+    meta: Option<&AccountsMeta>,
+) -> std::io::Result<()> {
+    if columns.tenants {
+        write!(writer, "{:>6}, ", tenant_cell(account))?;
+    }
+    write!(writer, "{}", account)?;
+    if columns.pending_out {
+        write!(writer, ", {:14.4}", account.pending_out())?;
+    }
+    if columns.over_limit {
+        write!(writer, ", {:>10}", account.is_over_limit())?;
+    }
+    if let Some(meta) = meta {
+        for cell in meta.cells(account.key()) {
+            write!(writer, ", {}", csv_cell(cell))?;
+        }
+    }
+    writeln!(writer)
+}
+
+/// Quotes the cell if it has a comma, a quote or a line break, like a CSV writer
+fn csv_cell(cell: &str) -> Cow<'_, str> {
+    if cell.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", cell.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(cell)
+    }
+}
+
+fn has_tenants<'a>(mut accounts: impl Iterator<Item = &'a ClientAccount>) -> bool {
+    accounts.any(|account| account.tenant().is_some())
+}
+
+fn has_pending_out<'a>(mut accounts: impl Iterator<Item = &'a ClientAccount>) -> bool {
+    accounts.any(|account| account.settles_withdrawals())
+}
+
+fn has_transaction_limit<'a>(mut accounts: impl Iterator<Item = &'a ClientAccount>) -> bool {
+    accounts.any(|account| account.has_transaction_limit())
+}
+
+/// The tenant of an account as a cell of the report, empty without a tenant
+fn tenant_cell(account: &ClientAccount) -> String {
+    account
+        .tenant()
+        .map_or_else(String::new, |tenant| tenant.to_string())
+}
+
+/// Writes the accounts as a table with the columns sized to fit all the values
+/// Locked accounts are highlighted in red if `color` is set
+pub fn write_table<'a>(
+    writer: &mut impl Write,
+    accounts: impl Iterator<Item = &'a ClientAccount> + Clone,
+    precision: usize,
+    color: bool,
+    meta: Option<&AccountsMeta>,
+) -> std::io::Result<()> {
+    let columns = Columns::of(accounts.clone());
+    let headers: Vec<&str> = columns
+        .tenants
+        .then_some(TENANT_HEADER)
+        .into_iter()
+        .chain(HEADERS)
+        .chain(columns.pending_out.then_some(PENDING_OUT_HEADER))
+        .chain(columns.over_limit.then_some(OVER_LIMIT_HEADER))
+        .chain(meta.map(|_| META_HEADERS).into_iter().flatten())
+        .collect();
+    let cells = |account: &ClientAccount| {
+        let mut cells = Vec::with_capacity(headers.len());
+        if columns.tenants {
+            cells.push(tenant_cell(account));
+        }
+        cells.extend([
+            account.id().to_string(),
+            format!("{:.*}", precision, account.available()),
+            format!("{:.*}", precision, account.held()),
+            format!("{:.*}", precision, account.total()),
+            account.is_locked().to_string(),
+        ]);
+        if columns.pending_out {
+            cells.push(format!("{:.*}", precision, account.pending_out()));
+        }
+        if columns.over_limit {
+            cells.push(account.is_over_limit().to_string());
+        }
+        if let Some(meta) = meta {
+            cells.extend(
+                meta.cells(account.key())
+                    .iter()
+                    .map(|cell| cell.to_string()),
+            );
+        }
+        cells
+    };
+
+    // first pass to find out the widths of the columns
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for account in accounts.clone() {
+        for (width, cell) in widths.iter_mut().zip(cells(account).iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let header: Vec<String> = headers
+        .iter()
+        .zip(widths.iter())
+        .map(|(name, width)| format!("{:>width$}", name, width = width))
+        .collect();
+    writeln!(writer, "{}", header.join(" | "))?;
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    writeln!(writer, "{}", rule.join("-+-"))?;
+
+    for account in accounts {
+        let row: Vec<String> = cells(account)
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+            .collect();
+        if color && account.is_locked() {
+            writeln!(writer, "\x1b[31m{}\x1b[0m", row.join(" | "))?;
+        } else {
+            writeln!(writer, "{}", row.join(" | "))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the number of dropped records per reason as a table, followed by their total
+pub fn write_reject_summary(
+    writer: &mut impl Write,
+    summary: &[(&str, u64)],
+) -> std::io::Result<()> {
+    let total: u64 = summary.iter().map(|(_, count)| count).sum();
+    let total = total.to_string();
+    let reason_width = summary
+        .iter()
+        .map(|(reason, _)| reason.len())
+        .chain(["reason".len(), "total".len()])
+        .max()
+        .unwrap_or_default();
+    let count_width = total.len().max("count".len());
+
+    writeln!(
+        writer,
+        "{:<reason_width$} | {:>count_width$}",
+        "reason",
+        "count",
+        reason_width = reason_width,
+        count_width = count_width
+    )?;
+    let rule = format!("{}-+-{}", "-".repeat(reason_width), "-".repeat(count_width));
+    writeln!(writer, "{}", rule)?;
+    for (reason, count) in summary {
+        writeln!(
+            writer,
+            "{:<reason_width$} | {:>count_width$}",
+            reason,
+            count,
+            reason_width = reason_width,
+            count_width = count_width
+        )?;
+    }
+    writeln!(writer, "{}", rule)?;
+    writeln!(
+        writer,
+        "{:<reason_width$} | {:>count_width$}",
+        "total",
+        total,
+        reason_width = reason_width,
+        count_width = count_width
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn accounts() -> Vec<ClientAccount> {
+        let mut first = ClientAccount::new(1);
+        first.deposit(1, dec!(1.5)).unwrap();
+        let mut second = ClientAccount::new(42);
+        second.deposit(2, dec!(123456.789)).unwrap();
+        second.dispute(2).unwrap();
+        second.chargeback(2).unwrap();
+        vec![first, second]
+    }
+
+    #[test]
+    fn test_plain_report() {
+        let mut output = Vec::new();
+        write_accounts(&mut output, accounts().iter(), None).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,     available,          held,         total,   locked\n\
+             \x20    1,         1.5000,         0.0000,         1.5000,     false\n\
+             \x20   42,         0.0000,         0.0000,         0.0000,     true\n"
+        );
+    }
+
+    #[test]
+    fn test_tenant_column() {
+        let mut accounts = accounts();
+        accounts[1] = ClientAccount::new(42).with_tenant(Some(7));
+
+        let mut output = Vec::new();
+        write_accounts(&mut output, accounts.iter(), None).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tenant, client,     available,          held,         total,   locked\n\
+             \x20     ,      1,         1.5000,         0.0000,         1.5000,     false\n\
+             \x20    7,     42,         0.0000,         0.0000,         0.0000,     false\n"
+        );
+
+        let mut output = Vec::new();
+        write_table(&mut output, accounts.iter(), 1, false, None).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tenant | client | available | held | total | locked\n\
+             -------+--------+-----------+------+-------+-------\n\
+             \x20      |      1 |       1.5 |  0.0 |   1.5 |  false\n\
+             \x20    7 |     42 |       0.0 |  0.0 |   0.0 |  false\n"
+        );
+    }
+
+    #[test]
+    fn test_pending_out_column() {
+        let mut accounts = accounts();
+        let mut pending = ClientAccount::new(7).with_pending_withdrawals();
+        pending.deposit(3, dec!(5)).unwrap();
+        pending.withdraw(4, dec!(2)).unwrap();
+        accounts[1] = pending;
+
+        let mut output = Vec::new();
+        write_accounts(&mut output, accounts.iter(), None).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,     available,          held,         total,   locked,    pending_out\n\
+             \x20    1,         1.5000,         0.0000,         1.5000,     false,         0.0000\n\
+             \x20    7,         3.0000,         0.0000,         5.0000,     false,         2.0000\n"
+        );
+
+        let mut output = Vec::new();
+        write_table(&mut output, accounts.iter(), 1, false, None).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client | available | held | total | locked | pending_out\n\
+             -------+-----------+------+-------+--------+------------\n\
+             \x20    1 |       1.5 |  0.0 |   1.5 |  false |         0.0\n\
+             \x20    7 |       3.0 |  0.0 |   5.0 |  false |         2.0\n"
+        );
+    }
+
+    #[test]
+    fn test_over_limit_column() {
+        let mut accounts = accounts();
+        let mut busy = ClientAccount::new(7).with_transaction_limit(1);
+        busy.count_transaction();
+        busy.count_transaction();
+        accounts[1] = busy;
+
+        let mut output = Vec::new();
+        write_accounts(&mut output, accounts.iter(), None).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,     available,          held,         total,   locked, over_limit\n\
+             \x20    1,         1.5000,         0.0000,         1.5000,     false,      false\n\
+             \x20    7,         0.0000,         0.0000,         0.0000,     false,       true\n"
+        );
+    }
+
+    #[test]
+    fn test_reject_summary() {
+        let record = TransactionRecord {
+            tr_type: TransactionType::Withdrawal,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx: 1,
+            amount: Some(dec!(10)),
+            timestamp: None,
+            source: None,
+        };
+        let insufficient_funds = TransactionError::InsufficientFunds {
+            requested: dec!(10),
+            available: dec!(0),
+        };
+        let report = Report::new(
+            HashMap::new(),
+            vec![
+                Reject::new(&record, insufficient_funds.clone()),
+                Reject::new(&record, TransactionError::UnknownTransaction),
+                Reject::new(&record, insufficient_funds),
+            ],
+        );
+
+        let summary = report.reject_summary(1);
+        assert_eq!(
+            summary,
+            vec![
+                ("insufficient_funds", 2),
+                ("parse_error", 1),
+                ("unknown_tx", 1)
+            ]
+        );
+
+        let mut output = Vec::new();
+        write_reject_summary(&mut output, &summary).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "reason             | count\n\
+             -------------------+------\n\
+             insufficient_funds |     2\n\
+             parse_error        |     1\n\
+             unknown_tx         |     1\n\
+             -------------------+------\n\
+             total              |     4\n"
+        );
+        assert!(Report::new(HashMap::new(), Vec::new())
+            .reject_summary(0)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_sorted_report() {
+        let accounts = (1..100)
+            .rev()
+            .map(|client| (AccountKey::from(client), ClientAccount::new(client)));
+        let report = Report::new(accounts.collect(), Vec::new());
+        let options = ReportOptions {
+            format: ReportFormat::Plain,
+            sorted: true,
+            ..ReportOptions::default()
+        };
+        let mut output = Vec::new();
+        report.write_to(&mut output, false, &options).unwrap();
+        let clients: Vec<ClientId> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().trim().parse().unwrap())
+            .collect();
+        assert_eq!(clients, (1..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_table_report() {
+        let accounts = accounts();
+        let mut output = Vec::new();
+        write_table(&mut output, accounts.iter(), 2, true, None).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client | available | held | total | locked\n\
+             -------+-----------+------+-------+-------\n\
+             \x20    1 |      1.50 | 0.00 |  1.50 |  false\n\
+             \x1b[31m    42 |      0.00 | 0.00 |  0.00 |   true\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_accounts_meta() {
+        let meta = AccountsMeta::from_reader(
+            "client, name, tier, country\n42,\"Doe, J\",gold,FR\n".as_bytes(),
+        )
+        .unwrap();
+        let accounts = accounts();
+
+        let mut output = Vec::new();
+        write_accounts(&mut output, accounts.iter(), Some(&meta)).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,     available,          held,         total,   locked, name, tier, country\n\
+             \x20    1,         1.5000,         0.0000,         1.5000,     false, , , \n\
+             \x20   42,         0.0000,         0.0000,         0.0000,     true, \"Doe, J\", gold, FR\n"
+        );
+
+        let mut output = Vec::new();
+        write_table(&mut output, accounts.iter(), 1, false, Some(&meta)).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client | available | held | total | locked |   name | tier | country\n\
+             -------+-----------+------+-------+--------+--------+------+--------\n\
+             \x20    1 |       1.5 |  0.0 |   1.5 |  false |        |      |        \n\
+             \x20   42 |       0.0 |  0.0 |   0.0 |   true | Doe, J | gold |      FR\n"
+        );
+    }
+}
@@ -0,0 +1,185 @@
+/// The changes between the reports of two runs, for `paytoy delta`
+/// Comparing the state before and after the file of a day shows exactly which accounts it
+/// changed and by how much. The accounts that didn't change aren't listed
+use std::io::Write;
+
+use rust_decimal::Decimal;
+
+use crate::{client_account::ClientAccount, records::AccountKey, report::Report};
+
+/// How an account differs between the two reports
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Change {
+    /// Only in the report after
+    Added,
+    /// Only in the report before
+    Removed,
+    Changed,
+}
+
+impl Change {
+    fn name(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Changed => "changed",
+        }
+    }
+}
+
+/// The change of an account, a missing account counts as an empty one
+#[derive(PartialEq, Debug, Clone)]
+pub struct AccountDelta {
+    pub key: AccountKey,
+    pub change: Change,
+    pub available: Decimal,
+    pub held: Decimal,
+    /// The new lock state if it changed
+    pub locked: Option<bool>,
+}
+
+impl AccountDelta {
+    fn between(before: Option<&ClientAccount>, after: Option<&ClientAccount>) -> Option<Self> {
+        let (key, change) = match (before, after) {
+            (None, Some(after)) => (after.key(), Change::Added),
+            (Some(before), None) => (before.key(), Change::Removed),
+            (Some(before), Some(_)) => (before.key(), Change::Changed),
+            (None, None) => return None,
+        };
+        let balances = |account: Option<&ClientAccount>| {
+            account.map_or((Decimal::ZERO, Decimal::ZERO, false), |account| {
+                (account.available(), account.held(), account.is_locked())
+            })
+        };
+        let (available_before, held_before, locked_before) = balances(before);
+        let (available_after, held_after, locked_after) = balances(after);
+        let delta = Self {
+            key,
+            change,
+            available: available_after - available_before,
+            held: held_after - held_before,
+            locked: (locked_before != locked_after).then_some(locked_after),
+        };
+        let unchanged = delta.change == Change::Changed
+            && delta.available.is_zero()
+            && delta.held.is_zero()
+            && delta.locked.is_none();
+        (!unchanged).then_some(delta)
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.available + self.held
+    }
+}
+
+/// The accounts that changed between the two reports, sorted by account
+pub fn report_delta(before: &Report, after: &Report) -> Vec<AccountDelta> {
+    let mut deltas: Vec<AccountDelta> = after
+        .accounts()
+        .filter_map(|account| AccountDelta::between(before.account(account.key()), Some(account)))
+        .chain(
+            before
+                .accounts()
+                .filter(|account| after.account(account.key()).is_none())
+                .filter_map(|account| AccountDelta::between(Some(account), None)),
+        )
+        .collect();
+    deltas.sort_by_key(|delta| delta.key);
+    deltas
+}
+
+/// Writes the deltas in the plain format, the tenant column is only there if an account has one
+pub fn write_deltas(writer: &mut impl Write, deltas: &[AccountDelta]) -> std::io::Result<()> {
+    let tenants = deltas.iter().any(|delta| delta.key.tenant.is_some());
+    if tenants {
+        write!(writer, "tenant, ")?;
+    }
+    writeln!(
+        writer,
+        "client,  change,     available,          held,         total,   locked"
+    )?;
+    for delta in deltas {
+        if tenants {
+            let tenant = delta
+                .key
+                .tenant
+                .map_or_else(String::new, |tenant| tenant.to_string());
+            write!(writer, "{:>6}, ", tenant)?;
+        }
+        let locked = delta
+            .locked
+            .map_or_else(String::new, |locked| locked.to_string());
+        writeln!(
+            writer,
+            "{:6}, {:>7}, {:14.4}, {:14.4}, {:14.4}, {:>8}",
+            delta.key.client,
+            delta.change.name(),
+            delta.available,
+            delta.held,
+            delta.total(),
+            locked
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hashbrown::HashMap;
+    use rust_decimal_macros::dec;
+
+    use crate::records::ClientId;
+
+    use super::*;
+
+    fn report(accounts: Vec<ClientAccount>) -> Report {
+        let accounts: HashMap<_, _> = accounts
+            .into_iter()
+            .map(|account| (account.key(), account))
+            .collect();
+        Report::new(accounts, Vec::new())
+    }
+
+    fn account(client: ClientId, available: Decimal, locked: bool) -> ClientAccount {
+        ClientAccount::from_balances(AccountKey::from(client), available, dec!(0), locked)
+    }
+
+    #[test]
+    fn test_report_delta() {
+        let before = report(vec![
+            account(1, dec!(10), false),
+            account(2, dec!(5), false),
+            account(3, dec!(1), false),
+        ]);
+        let after = report(vec![
+            account(1, dec!(10), false),
+            account(2, dec!(3.5), true),
+            account(4, dec!(2), false),
+        ]);
+
+        let deltas = report_delta(&before, &after);
+        assert_eq!(
+            deltas
+                .iter()
+                .map(|delta| (delta.key.client, delta.change))
+                .collect::<Vec<_>>(),
+            [
+                (2, Change::Changed),
+                (3, Change::Removed),
+                (4, Change::Added)
+            ]
+        );
+        assert_eq!(deltas[0].available, dec!(-1.5));
+        assert_eq!(deltas[0].locked, Some(true));
+        assert_eq!(deltas[1].total(), dec!(-1));
+        assert_eq!(deltas[2].locked, None);
+
+        let mut output = Vec::new();
+        write_deltas(&mut output, &deltas[..1]).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,  change,     available,          held,         total,   locked\n\
+             \x20    2, changed,        -1.5000,         0.0000,        -1.5000,     true\n"
+        );
+    }
+}
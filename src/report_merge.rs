@@ -0,0 +1,109 @@
+/// Combines the reports of independent runs, for `paytoy merge-reports`
+/// The runs may have processed the transactions of different regions, so most accounts are
+/// in a single report. The policy decides what happens to an account found in several of them
+use std::path::{Path, PathBuf};
+
+use hashbrown::{hash_map::Entry, HashMap};
+
+use crate::{client_account::ClientAccount, report::Report, report_reader::read_report};
+
+/// What to do with an account found in several reports
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum MergePolicy {
+    /// Add up the balances, the account is locked if it's locked in any report
+    Sum,
+    /// Fail the merge
+    Fail,
+}
+
+/// Reads the reports and merges their accounts
+pub fn merge_reports(paths: &[PathBuf], policy: MergePolicy) -> anyhow::Result<Report> {
+    let mut merged: HashMap<_, (ClientAccount, &Path)> = HashMap::new();
+    for path in paths {
+        let report = read_report(path)?;
+        for account in report.into_accounts() {
+            match merged.entry(account.key()) {
+                Entry::Vacant(entry) => {
+                    entry.insert((account, path));
+                }
+                Entry::Occupied(mut entry) => {
+                    let (existing, first) = entry.get_mut();
+                    match policy {
+                        MergePolicy::Sum => *existing = sum(existing, &account),
+                        MergePolicy::Fail => {
+                            return Err(anyhow::anyhow!(
+                                "Client {} is in both {} and {}",
+                                account.key().client,
+                                first.display(),
+                                path.display()
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let accounts = merged
+        .into_iter()
+        .map(|(key, (account, _))| (key, account))
+        .collect();
+    Ok(Report::new(accounts, Vec::new()))
+}
+
+fn sum(first: &ClientAccount, second: &ClientAccount) -> ClientAccount {
+    ClientAccount::from_balances(
+        first.key(),
+        first.available() + second.available(),
+        first.held() + second.held(),
+        first.is_locked() || second.is_locked(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::records::AccountKey;
+
+    use super::*;
+
+    fn write_report(name: &str, report: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, report).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_merge_reports() {
+        let paths = [
+            write_report(
+                "paytoy_test_merge_eu.csv",
+                "client, available, held, total, locked\n\
+                 1, 1.5, 0, 1.5, false\n\
+                 2, 3, 1, 4, false\n",
+            ),
+            write_report(
+                "paytoy_test_merge_us.csv",
+                "client, available, held, total, locked\n\
+                 2, 0.5, 0, 0.5, true\n\
+                 3, 7, 0, 7, false\n",
+            ),
+        ];
+
+        let report = merge_reports(&paths, MergePolicy::Sum).unwrap();
+        assert_eq!(report.accounts().count(), 3);
+        let merged = report.account(AccountKey::from(2)).unwrap();
+        assert_eq!(merged.available(), dec!(3.5));
+        assert_eq!(merged.held(), dec!(1));
+        assert!(merged.is_locked());
+        assert_eq!(
+            report.account(AccountKey::from(3)).unwrap().total(),
+            dec!(7)
+        );
+
+        match merge_reports(&paths, MergePolicy::Fail) {
+            Err(err) => assert!(err.to_string().starts_with("Client 2 is in both")),
+            Ok(_) => panic!("the conflict wasn't detected"),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+/// Reads back the reports written by paytoy, to combine or compare the results of several runs
+/// Both the plain and the table formats are read, the columns are found by their header so the
+/// tenant column and the columns of `--accounts-meta` may be there or not. The amounts of a
+/// table are only as precise as its `--precision`
+use std::{
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::Context;
+use hashbrown::HashMap;
+use rust_decimal::Decimal;
+
+use crate::{
+    client_account::ClientAccount,
+    records::{AccountKey, ClientId, TenantId},
+    report::Report,
+};
+
+/// Colors of the locked accounts in a table
+const COLOR_CODES: [&str; 2] = ["\x1b[31m", "\x1b[0m"];
+
+/// Positions of the columns read from the report
+struct Columns {
+    tenant: Option<usize>,
+    client: usize,
+    available: usize,
+    held: usize,
+    locked: usize,
+}
+
+impl Columns {
+    fn find(headers: &[&str]) -> anyhow::Result<Self> {
+        let find = |name: &str| headers.iter().position(|header| *header == name);
+        let required =
+            |name: &str| find(name).ok_or_else(|| anyhow::anyhow!("No `{}` column", name));
+        Ok(Self {
+            tenant: find("tenant"),
+            client: required("client")?,
+            available: required("available")?,
+            held: required("held")?,
+            locked: required("locked")?,
+        })
+    }
+
+    /// The columns before the metadata, which may have separators in them
+    fn count(&self) -> usize {
+        self.locked
+            .max(self.held)
+            .max(self.available)
+            .max(self.client)
+            + 1
+    }
+
+    fn account(&self, cells: &[&str]) -> anyhow::Result<ClientAccount> {
+        let tenant = match self.tenant.map(|index| cells[index]) {
+            None | Some("") => None,
+            Some(tenant) => Some(parse::<TenantId>(tenant, "tenant")?),
+        };
+        let client = parse::<ClientId>(cells[self.client], "client")?;
+        Ok(ClientAccount::from_balances(
+            AccountKey::new(tenant, client),
+            parse::<Decimal>(cells[self.available], "available")?,
+            parse::<Decimal>(cells[self.held], "held")?,
+            parse::<bool>(cells[self.locked], "locked")?,
+        ))
+    }
+}
+
+fn parse<T: FromStr>(cell: &str, column: &str) -> anyhow::Result<T> {
+    cell.parse()
+        .map_err(|_| anyhow::anyhow!("Invalid {} `{}`", column, cell))
+}
+
+/// Reads the report of a file
+pub fn read_report(path: &Path) -> anyhow::Result<Report> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    parse_report(BufReader::new(file)).with_context(|| format!("Invalid report {}", path.display()))
+}
+
+/// Parses a report in the plain or the table format, an account listed twice is an error
+pub fn parse_report(reader: impl BufRead) -> anyhow::Result<Report> {
+    let mut lines = reader.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("The report is empty"))??;
+    let separator = if header.contains('|') { '|' } else { ',' };
+    let headers: Vec<&str> = header.split(separator).map(str::trim).collect();
+    let columns = Columns::find(&headers)?;
+
+    let mut accounts = HashMap::new();
+    for (index, line) in lines.enumerate() {
+        let mut line = line?;
+        for code in COLOR_CODES {
+            line = line.replace(code, "");
+        }
+        // the rule under the header of a table
+        if line.trim().is_empty() || line.starts_with('-') {
+            continue;
+        }
+        let cells: Vec<&str> = line
+            .splitn(columns.count() + 1, separator)
+            .map(str::trim)
+            .collect();
+        if cells.len() < columns.count() {
+            return Err(anyhow::anyhow!("Line {} has missing columns", index + 2));
+        }
+        let account = columns
+            .account(&cells)
+            .with_context(|| format!("Invalid line {}", index + 2))?;
+        if let Some(account) = accounts.insert(account.key(), account) {
+            return Err(anyhow::anyhow!(
+                "Client {} is listed twice",
+                account.key().client
+            ));
+        }
+    }
+    Ok(Report::new(accounts, Vec::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        accounts_meta::AccountsMeta,
+        report::{write_accounts, write_table},
+    };
+
+    use super::*;
+
+    fn accounts() -> Vec<ClientAccount> {
+        vec![
+            ClientAccount::from_balances(AccountKey::from(1), dec!(1.5), dec!(0), false),
+            ClientAccount::from_balances(AccountKey::new(Some(7), 42), dec!(-2), dec!(3.25), true),
+        ]
+    }
+
+    fn check(report: &Report) {
+        for account in accounts() {
+            let read = report.account(account.key()).unwrap();
+            assert_eq!(read.available(), account.available());
+            assert_eq!(read.held(), account.held());
+            assert_eq!(read.is_locked(), account.is_locked());
+        }
+        assert_eq!(report.accounts().count(), 2);
+    }
+
+    #[test]
+    fn test_read_plain_report() {
+        let mut output = Vec::new();
+        write_accounts(&mut output, accounts().iter(), None).unwrap();
+        check(&parse_report(output.as_slice()).unwrap());
+
+        // the metadata columns may have commas
+        let meta = AccountsMeta::from_reader("client, name\n1,\"Doe, J\"\n".as_bytes()).unwrap();
+        let mut output = Vec::new();
+        write_accounts(&mut output, accounts().iter(), Some(&meta)).unwrap();
+        check(&parse_report(output.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn test_read_table() {
+        let mut output = Vec::new();
+        write_table(&mut output, accounts().iter(), 4, true, None).unwrap();
+        check(&parse_report(output.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_reports() {
+        assert!(parse_report("".as_bytes()).is_err());
+        assert!(parse_report("client, available, held\n".as_bytes()).is_err());
+        assert!(
+            parse_report("client, available, held, locked\n1, x, 0, false\n".as_bytes()).is_err()
+        );
+        let twice = "client, available, held, locked\n1, 1, 0, false\n1, 2, 0, false\n";
+        assert!(parse_report(twice.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+/// A plain report written by the workers as they finish, for `--stream-report`
+/// Merging the accounts of all the workers into one report holds them twice at the end of the
+/// run. With a stream, each worker writes its own accounts to the output in turn, the first
+/// worker then the second and so on, and drops them, so the accounts are never merged. The
+/// header waits for all the workers, to know if the tenant column is needed
+use std::{
+    io::Write,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::{
+    accounts_meta::AccountsMeta,
+    client_account::ClientAccount,
+    report::{write_account_row, write_accounts_header, Columns},
+};
+
+struct StreamState {
+    writer: Box<dyn Write + Send>,
+    /// Number of workers writing to the stream
+    shards: usize,
+    /// Number of workers waiting for their turn, or done
+    ready: usize,
+    /// The columns needed by the accounts of any worker, like a tenant
+    columns: Columns,
+    /// The worker whose turn it is
+    next: usize,
+    header_written: bool,
+    /// The first write that failed, reported by `finish`
+    error: Option<std::io::Error>,
+}
+
+impl StreamState {
+    fn write_header(&mut self, meta: Option<&AccountsMeta>) {
+        if self.header_written {
+            return;
+        }
+        self.header_written = true;
+        if let Err(err) = write_accounts_header(&mut self.writer, self.columns, meta) {
+            self.error.get_or_insert(err);
+        }
+    }
+}
+
+pub struct ReportStream {
+    state: Mutex<StreamState>,
+    turn: Condvar,
+    meta: Option<Arc<AccountsMeta>>,
+}
+
+impl ReportStream {
+    pub fn new(writer: Box<dyn Write + Send>, meta: Option<Arc<AccountsMeta>>) -> Self {
+        Self {
+            state: Mutex::new(StreamState {
+                writer,
+                shards: 0,
+                ready: 0,
+                columns: Columns::default(),
+                next: 0,
+                header_written: false,
+                error: None,
+            }),
+            turn: Condvar::new(),
+            meta,
+        }
+    }
+
+    /// The turns of `shards` workers, each of them has to write its accounts or be dropped
+    pub fn turns(self: &Arc<Self>, shards: usize) -> Vec<ShardTurn> {
+        self.state.lock().unwrap().shards = shards;
+        (0..shards)
+            .map(|shard| ShardTurn {
+                stream: self.clone(),
+                shard,
+                done: false,
+            })
+            .collect()
+    }
+
+    /// Waits for the turn of `shard` and writes the accounts, without accounts it only lets
+    /// the next workers go on
+    fn write_shard<'a>(
+        &self,
+        shard: usize,
+        accounts: Option<impl Iterator<Item = &'a ClientAccount> + Clone>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(accounts) = &accounts {
+            state.columns.merge(Columns::of(accounts.clone()));
+        }
+        state.ready += 1;
+        self.turn.notify_all();
+        let mut state = self
+            .turn
+            .wait_while(state, |state| {
+                state.ready < state.shards || state.next != shard
+            })
+            .unwrap();
+
+        let meta = self.meta.as_deref();
+        state.write_header(meta);
+        if let Some(accounts) = accounts {
+            let columns = state.columns;
+            for account in accounts {
+                if let Err(err) = write_account_row(&mut state.writer, account, columns, meta) {
+                    state.error.get_or_insert(err);
+                    break;
+                }
+            }
+        }
+        state.next += 1;
+        self.turn.notify_all();
+    }
+
+    /// Flushes the report once all the workers wrote their accounts
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // no worker was started if there were no records
+        state.write_header(self.meta.as_deref());
+        if let Err(err) = state.writer.flush() {
+            state.error.get_or_insert(err);
+        }
+        match state.error.take() {
+            Some(err) => Err(anyhow::anyhow!("Failed to write the report: {}", err)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The place of a worker in the stream, a worker that panics gives its turn up when it's
+/// dropped so the others don't wait forever
+pub struct ShardTurn {
+    stream: Arc<ReportStream>,
+    shard: usize,
+    done: bool,
+}
+
+impl ShardTurn {
+    /// Waits for the previous workers, then writes the accounts
+    pub fn write<'a>(mut self, accounts: impl Iterator<Item = &'a ClientAccount> + Clone) {
+        self.done = true;
+        self.stream.write_shard(self.shard, Some(accounts));
+    }
+}
+
+impl Drop for ShardTurn {
+    fn drop(&mut self) {
+        if !self.done {
+            self.stream
+                .write_shard(self.shard, None::<std::iter::Empty<&ClientAccount>>);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, MTAccountManager, STAccountManager},
+        records::{ClientId, TenantId, TransactionId, TransactionRecord, TransactionType},
+    };
+
+    use super::*;
+
+    /// A writer the test can read after the stream is done with it
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        pub(crate) fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_stream() -> (Arc<ReportStream>, SharedBuffer) {
+        let buffer = SharedBuffer::default();
+        let stream = Arc::new(ReportStream::new(Box::new(buffer.clone()), None));
+        (stream, buffer)
+    }
+
+    fn stream_report(
+        manager: impl AccountManager,
+        stream: Arc<ReportStream>,
+        buffer: SharedBuffer,
+        tenant: Option<TenantId>,
+    ) -> String {
+        let deposits = (1..=10).map(move |client| TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client,
+            tenant: if client == 5 { tenant } else { None },
+            merchant: None,
+            tx: client as TransactionId,
+            amount: Some(dec!(1.5)),
+            timestamp: None,
+            source: None,
+        });
+        let report = manager.execute_transactions(Box::new(deposits));
+        // the accounts went to the stream
+        assert_eq!(report.accounts().count(), 0);
+        stream.finish().unwrap();
+        buffer.contents()
+    }
+
+    fn check_report(report: &str, header: &str) {
+        let mut lines = report.lines();
+        assert_eq!(lines.next(), Some(header));
+        let mut clients: Vec<ClientId> = lines
+            .map(|line| {
+                let cells: Vec<&str> = line.split(',').collect();
+                cells[cells.len() - 5].trim().parse().unwrap()
+            })
+            .collect();
+        clients.sort_unstable();
+        assert_eq!(clients, (1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_report_stream() {
+        let header = "client,     available,          held,         total,   locked";
+        let (stream, buffer) = new_stream();
+        let manager = STAccountManager::new().with_report_stream(stream.clone());
+        check_report(&stream_report(manager, stream, buffer, None), header);
+
+        let (stream, buffer) = new_stream();
+        let manager = MTAccountManager::new(3).with_report_stream(stream.clone());
+        check_report(&stream_report(manager, stream, buffer, None), header);
+
+        // the tenant of an account of any worker is in the header
+        let header = "tenant, client,     available,          held,         total,   locked";
+        let (stream, buffer) = new_stream();
+        let manager = MTAccountManager::new(3).with_report_stream(stream.clone());
+        check_report(&stream_report(manager, stream, buffer, Some(2)), header);
+    }
+
+    #[test]
+    fn test_empty_stream() {
+        let (stream, buffer) = new_stream();
+        let manager = MTAccountManager::new(2).with_report_stream(stream.clone());
+        manager.execute_transactions(Box::new(std::iter::empty()));
+        stream.finish().unwrap();
+        assert_eq!(
+            buffer.contents(),
+            "client,     available,          held,         total,   locked\n"
+        );
+    }
+}
@@ -0,0 +1,288 @@
+/// The resume tokens of the interrupted runs, to go on with the rest of a CSV input
+/// A token has the line of the last record applied to the accounts, the byte offset where it
+/// starts and its transaction id. The next run starts reading the input at that offset and
+/// skips that record, after checking that the input is still the one the token was written
+/// for: the same size, the same checksum of its first bytes, and the same transaction id at
+/// the offset. The offsets are in the decoded input, like the ones of `InputSlice`
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Context;
+use csv::ReaderBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::UsageError,
+    input_encoding::{self, InputEncoding},
+    input_slice::InputSlice,
+    records::{wide_id, TransactionId, TransactionRecord},
+};
+
+/// The bytes of the input covered by the checksum
+const HEAD_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ResumeToken {
+    /// The input, only to tell which one it was
+    pub input: String,
+    pub size: u64,
+    /// The CRC32 of the first 64 KiB of the input
+    pub head_crc32: u32,
+    /// Where the last record applied starts, 0 if none was
+    pub offset: u64,
+    /// The line of the last record applied, 1 (the header) if none was
+    pub line: u64,
+    pub last_tx: Option<TransactionId>,
+}
+
+/// The last record applied to the accounts, updated as the records go by
+#[derive(Default)]
+pub struct ResumeProgress {
+    /// 0 until a record is applied
+    line: AtomicU64,
+    last_tx: AtomicU64,
+}
+
+impl ResumeProgress {
+    /// The records have to be tagged with their line
+    pub fn applied(&self, record: &TransactionRecord) {
+        if let Some(source) = &record.source {
+            self.line.store(source.line, Ordering::Relaxed);
+            self.last_tx.store(wide_id(record.tx), Ordering::Relaxed);
+        }
+    }
+
+    /// The line and the transaction id of the last record applied
+    pub fn last(&self) -> Option<(u64, TransactionId)> {
+        match self.line.load(Ordering::Relaxed) {
+            0 => None,
+            line => Some((line, self.last_tx.load(Ordering::Relaxed) as TransactionId)),
+        }
+    }
+}
+
+impl ResumeToken {
+    /// The token of the input after the record of `last` (the line and the transaction id),
+    /// or of its start if None
+    pub fn new(
+        input: &Path,
+        encoding: InputEncoding,
+        last: Option<(u64, TransactionId)>,
+    ) -> anyhow::Result<Self> {
+        let (size, head_crc32) = fingerprint(input)?;
+        let (line, last_tx) = match last {
+            Some((line, tx)) => (line, Some(tx)),
+            None => (1, None),
+        };
+        let offset = match last_tx {
+            Some(_) => line_offset(open(input, encoding)?, line)?,
+            None => 0,
+        };
+        Ok(Self {
+            input: input.to_string_lossy().to_string(),
+            size,
+            head_crc32,
+            offset,
+            line,
+            last_tx,
+        })
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open the resume token {}", path.display()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("{} is not a resume token", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Checks that `input` is the input of the token, unchanged
+    pub fn validate(&self, input: &Path, encoding: InputEncoding) -> anyhow::Result<()> {
+        let changed = |what: String| -> anyhow::Result<()> {
+            Err(UsageError(format!(
+                "{} doesn't match the resume token of {}: {}",
+                input.display(),
+                self.input,
+                what
+            ))
+            .into())
+        };
+        let (size, head_crc32) = fingerprint(input)?;
+        if size != self.size {
+            return changed(format!("{} bytes instead of {}", size, self.size));
+        }
+        if head_crc32 != self.head_crc32 {
+            return changed("its first bytes changed".to_string());
+        }
+        let last_tx = match self.last_tx {
+            Some(last_tx) => last_tx,
+            None => return Ok(()),
+        };
+        let slice = InputSlice {
+            start_offset: self.offset,
+            limit: Some(1),
+            ..InputSlice::default()
+        };
+        let (reader, _) = slice.apply(open(input, encoding)?)?;
+        let mut csv_reader = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(reader);
+        let column = csv_reader
+            .byte_headers()?
+            .iter()
+            .position(|header| header == b"tx");
+        let tx = csv_reader
+            .byte_records()
+            .next()
+            .transpose()?
+            .and_then(|record| {
+                let field = record.get(column?)?;
+                std::str::from_utf8(field)
+                    .ok()?
+                    .parse::<TransactionId>()
+                    .ok()
+            });
+        if tx != Some(last_tx) {
+            return changed(format!(
+                "the record at the byte {} isn't the transaction {}",
+                self.offset, last_tx
+            ));
+        }
+        Ok(())
+    }
+
+    /// The rest of the input, after the last record applied
+    pub fn slice(&self) -> InputSlice {
+        InputSlice {
+            start_offset: self.offset,
+            skip_records: self.last_tx.map_or(0, |_| 1),
+            limit: None,
+        }
+    }
+
+    /// The last record applied, to write the token again if no other one is
+    pub fn last(&self) -> Option<(u64, TransactionId)> {
+        self.last_tx.map(|tx| (self.line, tx))
+    }
+}
+
+fn open(input: &Path, encoding: InputEncoding) -> anyhow::Result<Box<dyn Read + Send>> {
+    let file = File::open(input).with_context(|| format!("Failed to open {}", input.display()))?;
+    Ok(input_encoding::decode(file, encoding)?)
+}
+
+/// The size of the input and the checksum of its first bytes
+fn fingerprint(input: &Path) -> anyhow::Result<(u64, u32)> {
+    let mut file =
+        File::open(input).with_context(|| format!("Failed to open {}", input.display()))?;
+    let size = file.metadata()?.len();
+    let mut head = Vec::new();
+    (&mut file).take(HEAD_SIZE).read_to_end(&mut head)?;
+    Ok((size, crc32fast::hash(&head)))
+}
+
+/// The offset of the start of the `line` (the first one is 1)
+fn line_offset(reader: impl Read, line: u64) -> std::io::Result<u64> {
+    let mut reader = BufReader::new(reader);
+    let (mut offset, mut lines) = (0, 1);
+    while lines < line {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            break;
+        }
+        let mut len = available.len();
+        for (index, _) in available
+            .iter()
+            .enumerate()
+            .filter(|(_, &byte)| byte == b'\n')
+        {
+            lines += 1;
+            if lines == line {
+                len = index + 1;
+                break;
+            }
+        }
+        offset += len as u64;
+        reader.consume(len);
+    }
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn input(name: &str, data: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        File::create(&path)
+            .unwrap()
+            .write_all(data.as_bytes())
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_token() {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,1.0\n\
+                    deposit,1,2,2.0\n\
+                    deposit,1,3,3.0\n";
+        let path = input("paytoy_test_resume_token.csv", data);
+        let token = ResumeToken::new(&path, InputEncoding::Utf8, Some((3, 2))).unwrap();
+        assert_eq!(token.offset, data.find("deposit,1,2").unwrap() as u64);
+        token.validate(&path, InputEncoding::Utf8).unwrap();
+
+        let (mut reader, skipped) = token.slice().apply(Box::new(data.as_bytes())).unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "type,client,tx,amount\ndeposit,1,3,3.0\n");
+        assert_eq!(skipped, 2);
+
+        let token_path = std::env::temp_dir().join("paytoy_test_resume_token.json");
+        token.write(&token_path).unwrap();
+        assert_eq!(ResumeToken::read(&token_path).unwrap(), token);
+
+        let start = ResumeToken::new(&path, InputEncoding::Utf8, None).unwrap();
+        assert_eq!(start.slice(), InputSlice::default());
+        start.validate(&path, InputEncoding::Utf8).unwrap();
+    }
+
+    #[test]
+    fn test_changed_input() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n";
+        let path = input("paytoy_test_resume_changed.csv", data);
+        let token = ResumeToken::new(&path, InputEncoding::Utf8, Some((2, 1))).unwrap();
+        let error = |data: &str| {
+            let path = input("paytoy_test_resume_changed.csv", data);
+            token
+                .validate(&path, InputEncoding::Utf8)
+                .unwrap_err()
+                .to_string()
+        };
+        let expected = format!("22 bytes instead of {}", data.len());
+        assert!(error("type,client,tx,amount\n").contains(&expected));
+        assert!(error(&data.replace("2,2.0", "2,3.0")).contains("first bytes changed"));
+        // the changes after the first 64 KiB are only seen in the record at the offset
+        let mut token = token.clone();
+        token.head_crc32 = fingerprint(&path).unwrap().1;
+        token.last_tx = Some(7);
+        assert!(token
+            .validate(&path, InputEncoding::Utf8)
+            .unwrap_err()
+            .to_string()
+            .contains("the record at the byte 22 isn't the transaction 7"));
+    }
+}
@@ -0,0 +1,148 @@
+/// Samples the records of a run, to smoke test a new feed in a fraction of the time
+/// The sample is taken by account: all the records of an account are kept or none, so the
+/// disputes, resolves and chargebacks of the sample find their deposits, where a sample of
+/// single records would reject most of them. The accounts are picked by a hash of their key
+/// and of the seed, the same ones at every run with the same seed whatever the order or the
+/// split of the inputs
+use std::{
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::records::{wide_id, AccountKey, TransactionRecord};
+
+pub struct Sampler {
+    rate: f64,
+    seed: u64,
+    /// The accounts whose hash is below are kept
+    threshold: u64,
+    kept: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Sampler {
+    /// Keeps about `rate` of the accounts, between 0 and 1
+    pub fn new(rate: f64, seed: u64) -> Self {
+        Self {
+            rate,
+            seed,
+            threshold: (rate * u64::MAX as f64) as u64,
+            kept: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Checks if the record is in the sample, and counts it
+    pub fn keeps(&self, record: &TransactionRecord) -> bool {
+        let kept = self.keeps_account(AccountKey::of(record));
+        let counter = if kept { &self.kept } else { &self.dropped };
+        counter.fetch_add(1, Ordering::Relaxed);
+        kept
+    }
+
+    fn keeps_account(&self, key: AccountKey) -> bool {
+        let tenant = key.tenant.map_or(0, |tenant| tenant as u64 + 1);
+        // the tenant in the high bits, above the clients of any width but the widest
+        mix((tenant << 48 ^ wide_id(key.client)) ^ mix(self.seed)) < self.threshold
+    }
+
+    pub fn kept(&self) -> u64 {
+        self.kept.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Tells that the report only covers the sample, so it isn't taken for the real balances
+    pub fn write_summary(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "SAMPLED RUN: the report only has {:.2}% of the accounts (seed {}), {} of the {} \
+             records were applied",
+            self.rate * 100.0,
+            self.seed,
+            self.kept(),
+            self.kept() + self.dropped()
+        )
+    }
+}
+
+/// The finalizer of SplitMix64, every bit of the input changes half the bits of the output
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::records::{ClientId, TenantId, TransactionId, TransactionType};
+
+    fn record(tenant: Option<TenantId>, client: ClientId, tx: TransactionId) -> TransactionRecord {
+        TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client,
+            tenant,
+            merchant: None,
+            tx,
+            amount: Some(dec!(1)),
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_sampled_accounts() {
+        let sampler = Sampler::new(0.1, 7);
+        let sampled: Vec<ClientId> = (0..=u16::MAX as ClientId)
+            .filter(|client| sampler.keeps_account((*client).into()))
+            .collect();
+        assert!((6000..7100).contains(&sampled.len()), "{}", sampled.len());
+        // the same accounts with the same seed, other ones with another seed
+        let again = Sampler::new(0.1, 7);
+        assert!(sampled
+            .iter()
+            .all(|client| again.keeps_account((*client).into())));
+        let other = Sampler::new(0.1, 8);
+        let common = sampled
+            .iter()
+            .filter(|client| other.keeps_account((**client).into()))
+            .count();
+        assert!(common < sampled.len() / 5, "{}", common);
+        // the tenants have their own clients
+        let tenant = sampled
+            .iter()
+            .filter(|client| sampler.keeps_account(AccountKey::new(Some(1), **client)))
+            .count();
+        assert!(tenant < sampled.len() / 5, "{}", tenant);
+    }
+
+    #[test]
+    fn test_records() {
+        let sampler = Sampler::new(0.5, 1);
+        let kept: Vec<bool> = (0..100)
+            .map(|tx| sampler.keeps(&record(None, (tx % 10) as ClientId, tx)))
+            .collect();
+        // all the records of a client or none
+        for client in 0..10 {
+            assert!(kept[client..]
+                .iter()
+                .step_by(10)
+                .all(|kept_tx| *kept_tx == kept[client]));
+        }
+        assert_eq!(sampler.kept() + sampler.dropped(), 100);
+        assert_eq!(
+            sampler.kept(),
+            kept.iter().filter(|kept| **kept).count() as u64
+        );
+        let mut summary = Vec::new();
+        sampler.write_summary(&mut summary).unwrap();
+        assert!(String::from_utf8(summary)
+            .unwrap()
+            .starts_with("SAMPLED RUN: the report only has 50.00% of the accounts (seed 1)"));
+    }
+}
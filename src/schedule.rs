@@ -0,0 +1,236 @@
+/// Recurring deposits and withdrawals injected into the records, for `--schedule`
+/// The schedule is a CSV with the `client`, `type`, `amount` and `period` columns, and the
+/// optional `tenant` and `start` columns. Each entry repeats every `period`, in the unit of the
+/// timestamps, from its `start` or from the first timestamp of the input. The scheduled records
+/// come right before the first record of the input that is at least as late, so the stream
+/// stays in time order, and nothing is scheduled after the last record of the input
+use std::{cmp::Reverse, collections::BinaryHeap, io::Read, path::Path, sync::Arc};
+
+use anyhow::Context;
+use csv::{ReaderBuilder, Trim};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    records::{
+        ClientId, RecordSource, TenantId, TransactionId, TransactionRecord, TransactionType,
+    },
+    transactions_reader::TransactionsStream,
+};
+
+/// A recurring transaction of the schedule
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct ScheduleEntry {
+    pub client: ClientId,
+    #[serde(default)]
+    pub tenant: Option<TenantId>,
+    #[serde(rename = "type")]
+    pub tr_type: TransactionType,
+    pub amount: Decimal,
+    pub period: u64,
+    /// The time of the first occurrence, the first timestamp of the input if there's none
+    #[serde(default)]
+    pub start: Option<u64>,
+}
+
+/// The entries of a schedule file
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    /// Name of the file, the source of the scheduled records
+    name: Arc<str>,
+    entries: Vec<ScheduleEntry>,
+}
+
+impl Schedule {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::from_reader(path.to_string_lossy().into(), file)
+            .with_context(|| format!("Invalid schedule {}", path.display()))
+    }
+
+    /// Parses the CSV, only deposits and withdrawals of a positive period can be scheduled
+    pub fn from_reader(name: Arc<str>, reader: impl Read) -> anyhow::Result<Self> {
+        let mut csv_reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+        let mut entries = Vec::new();
+        for (index, entry) in csv_reader.deserialize::<ScheduleEntry>().enumerate() {
+            let entry = entry?;
+            let line = index + 2;
+            match entry.tr_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => {}
+                tr_type => {
+                    return Err(anyhow::anyhow!(
+                        "Line {}: a {} can't be scheduled",
+                        line,
+                        tr_type
+                    ))
+                }
+            }
+            if entry.period == 0 {
+                return Err(anyhow::anyhow!("Line {}: the period can't be 0", line));
+            }
+            entries.push(entry);
+        }
+        Ok(Self { name, entries })
+    }
+
+    pub fn entries(&self) -> &[ScheduleEntry] {
+        &self.entries
+    }
+}
+
+/// Inserts the scheduled records into the stream, with the ids counting down from the last
+/// id so they don't collide with the ids of the input
+pub fn inject(transactions: TransactionsStream, schedule: Schedule) -> TransactionsStream {
+    Box::new(Injector {
+        transactions,
+        schedule,
+        next: BinaryHeap::new(),
+        started: false,
+        next_tx: TransactionId::MAX,
+        pending: None,
+    })
+}
+
+struct Injector {
+    transactions: TransactionsStream,
+    schedule: Schedule,
+    /// The time of the next occurrence of each entry, earliest first
+    next: BinaryHeap<Reverse<(u64, usize)>>,
+    /// Set at the first record with a timestamp, which starts the entries without a start
+    started: bool,
+    next_tx: TransactionId,
+    /// The record of the input waiting for the scheduled records before it
+    pending: Option<TransactionRecord>,
+}
+
+impl Injector {
+    fn start(&mut self, first: u64) {
+        for (index, entry) in self.schedule.entries.iter().enumerate() {
+            self.next
+                .push(Reverse((entry.start.unwrap_or(first), index)));
+        }
+        self.started = true;
+    }
+
+    /// The next occurrence of an entry due by `now`
+    fn due(&mut self, now: u64) -> Option<TransactionRecord> {
+        let Reverse((time, index)) = *self.next.peek()?;
+        if time > now {
+            return None;
+        }
+        self.next.pop();
+        let entry = &self.schedule.entries[index];
+        if let Some(next) = time.checked_add(entry.period) {
+            self.next.push(Reverse((next, index)));
+        }
+        let tx = self.next_tx;
+        self.next_tx = self.next_tx.saturating_sub(1);
+        Some(TransactionRecord {
+            tr_type: entry.tr_type,
+            client: entry.client,
+            tenant: entry.tenant,
+            merchant: None,
+            tx,
+            amount: Some(entry.amount),
+            timestamp: Some(time),
+            source: RecordSource::tag(Some(&self.schedule.name), index as u64 + 2),
+        })
+    }
+}
+
+impl Iterator for Injector {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        let record = match self.pending.take() {
+            Some(record) => record,
+            None => self.transactions.next()?,
+        };
+        // the records without a timestamp stay where they are
+        let now = match record.timestamp {
+            Some(now) => now,
+            None => return Some(record),
+        };
+        if !self.started {
+            self.start(now);
+        }
+        match self.due(now) {
+            Some(scheduled) => {
+                self.pending = Some(record);
+                Some(scheduled)
+            }
+            None => Some(record),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn deposit(tx: TransactionId, timestamp: Option<u64>) -> TransactionRecord {
+        TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount: Some(dec!(100)),
+            timestamp,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_inject() {
+        let schedule = Schedule::from_reader(
+            "billing.csv".into(),
+            "client, type, amount, period, start\n\
+             1, withdrawal, 9.99, 30, 5\n\
+             2, deposit, 1000, 50,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let records = vec![
+            deposit(1, Some(0)),
+            deposit(2, None),
+            deposit(3, Some(40)),
+            deposit(4, Some(100)),
+        ];
+
+        let injected: Vec<(ClientId, TransactionId, Option<u64>)> =
+            inject(Box::new(records.into_iter()), schedule)
+                .map(|record| (record.client, record.tx, record.timestamp))
+                .collect();
+        let last = TransactionId::MAX;
+        assert_eq!(
+            injected,
+            vec![
+                (2, last, Some(0)),
+                (1, 1, Some(0)),
+                (1, 2, None),
+                (1, last - 1, Some(5)),
+                (1, last - 2, Some(35)),
+                (1, 3, Some(40)),
+                (2, last - 3, Some(50)),
+                (1, last - 4, Some(65)),
+                (1, last - 5, Some(95)),
+                (2, last - 6, Some(100)),
+                (1, 4, Some(100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_schedules() {
+        let parse = |csv: &str| Schedule::from_reader("schedule.csv".into(), csv.as_bytes());
+        assert!(parse("client, type, amount, period\n1, dispute, 1, 10\n").is_err());
+        assert!(parse("client, type, amount, period\n1, deposit, 1, 0\n").is_err());
+        assert!(parse("client, type, amount\n1, deposit, 1\n").is_err());
+        let schedule = parse("client, tenant, type, amount, period\n1, 3, deposit, 1, 10\n");
+        assert_eq!(schedule.unwrap().entries()[0].tenant, Some(3));
+    }
+}
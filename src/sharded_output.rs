@@ -0,0 +1,191 @@
+/// A report split into several files by a hash of the accounts, for `--output-shards`
+/// Very large account sets don't fit a single output file well, and merging the accounts of
+/// all the workers into one report takes a single thread. With a sharded output, each worker
+/// writes its own accounts to the shards at the end of the run, in parallel with the others.
+/// The shards are in the plain format and always have the tenant column, so that all of them
+/// have the same columns whatever accounts they got. For the same reason, the `pending_out`
+/// and `over_limit` columns are picked when the shards are created
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+use crate::{
+    accounts_meta::AccountsMeta,
+    client_account::ClientAccount,
+    records::AccountKey,
+    report::{write_account_row, write_accounts_header, Columns},
+};
+
+pub struct ShardedOutput {
+    shards: Vec<Mutex<BufWriter<File>>>,
+    paths: Vec<PathBuf>,
+    columns: Columns,
+    meta: Option<Arc<AccountsMeta>>,
+    /// The first write that failed, reported by `finish`
+    error: Mutex<Option<std::io::Error>>,
+}
+
+impl ShardedOutput {
+    /// Creates the `shards` files next to `output`, with the header of the report
+    /// The `columns` are those of all the accounts, the tenant column is always there
+    pub fn create(
+        output: &Path,
+        shards: usize,
+        columns: Columns,
+        meta: Option<Arc<AccountsMeta>>,
+    ) -> anyhow::Result<Self> {
+        let columns = Columns {
+            tenants: true,
+            ..columns
+        };
+        let paths: Vec<PathBuf> = (0..shards).map(|index| shard_path(output, index)).collect();
+        let shards = paths
+            .iter()
+            .map(|path| {
+                let mut writer = BufWriter::new(
+                    File::create(path)
+                        .with_context(|| format!("Failed to create {}", path.display()))?,
+                );
+                write_accounts_header(&mut writer, columns, meta.as_deref())?;
+                Ok(Mutex::new(writer))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            shards,
+            paths,
+            columns,
+            meta,
+            error: Mutex::new(None),
+        })
+    }
+
+    /// The shard of an account
+    pub fn shard_of(&self, key: AccountKey) -> usize {
+        key.shard(self.shards.len())
+    }
+
+    /// Appends the accounts to their shards, the rows of each shard are formatted before it's
+    /// locked so the workers mostly write in parallel
+    pub fn write_accounts<'a>(&self, accounts: impl Iterator<Item = &'a ClientAccount>) {
+        let mut rows = vec![Vec::new(); self.shards.len()];
+        for account in accounts {
+            let buffer = &mut rows[self.shard_of(account.key())];
+            // writing to memory doesn't fail
+            let _ = write_account_row(buffer, account, self.columns, self.meta.as_deref());
+        }
+        for (shard, rows) in self.shards.iter().zip(rows) {
+            if rows.is_empty() {
+                continue;
+            }
+            if let Err(err) = shard.lock().unwrap().write_all(&rows) {
+                self.error.lock().unwrap().get_or_insert(err);
+            }
+        }
+    }
+
+    /// Flushes the shards, returns their paths
+    pub fn finish(&self) -> anyhow::Result<&[PathBuf]> {
+        for (shard, path) in self.shards.iter().zip(&self.paths) {
+            shard
+                .lock()
+                .unwrap()
+                .flush()
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        match self.error.lock().unwrap().take() {
+            Some(err) => Err(anyhow::anyhow!(
+                "Failed to write the report shards: {}",
+                err
+            )),
+            None => Ok(&self.paths),
+        }
+    }
+}
+
+/// `accounts.csv` becomes `accounts-0.csv`, `accounts-1.csv`, ...
+fn shard_path(output: &Path, index: usize) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map_or_else(|| "report".into(), |stem| stem.to_string_lossy());
+    let name = match output.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, index),
+    };
+    output.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, MTAccountManager, STAccountManager},
+        records::{TransactionId, TransactionRecord, TransactionType},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_shard_path() {
+        assert_eq!(
+            shard_path(Path::new("out/accounts.csv"), 2),
+            Path::new("out/accounts-2.csv")
+        );
+        assert_eq!(
+            shard_path(Path::new("accounts"), 0),
+            Path::new("accounts-0")
+        );
+    }
+
+    fn test_sharded_output(manager: impl AccountManager, output: Arc<ShardedOutput>) {
+        let deposits = (1..=10).map(|client| TransactionRecord {
+            tr_type: TransactionType::Deposit,
+            client,
+            tenant: None,
+            merchant: None,
+            tx: client as TransactionId,
+            amount: Some(dec!(1.5)),
+            timestamp: None,
+            source: None,
+        });
+        let report = manager.execute_transactions(Box::new(deposits));
+        // the accounts went to the shards
+        assert_eq!(report.accounts().count(), 0);
+
+        let paths = output.finish().unwrap();
+        assert_eq!(paths.len(), 3);
+        let mut rows = 0;
+        for (index, path) in paths.iter().enumerate() {
+            let shard = std::fs::read_to_string(path).unwrap();
+            let mut lines = shard.lines();
+            assert!(lines.next().unwrap().starts_with("tenant, client,"));
+            for line in lines {
+                let client: usize = line.split(',').nth(1).unwrap().trim().parse().unwrap();
+                assert_eq!(client % 3, index);
+                rows += 1;
+            }
+        }
+        assert_eq!(rows, 10);
+    }
+
+    #[test]
+    fn test_sharded_output_st() {
+        let path = std::env::temp_dir().join("paytoy_test_shards_st.csv");
+        let output = Arc::new(ShardedOutput::create(&path, 3, Columns::default(), None).unwrap());
+        let manager = STAccountManager::new().with_sharded_output(output.clone());
+        test_sharded_output(manager, output);
+    }
+
+    #[test]
+    fn test_sharded_output_mt() {
+        let path = std::env::temp_dir().join("paytoy_test_shards_mt.csv");
+        let output = Arc::new(ShardedOutput::create(&path, 3, Columns::default(), None).unwrap());
+        let manager = MTAccountManager::new(2).with_sharded_output(output.clone());
+        test_sharded_output(manager, output);
+    }
+}
@@ -0,0 +1,300 @@
+/// Previewing the effect of the inputs without saving anything, for `--simulate-only`
+/// The records are applied to a copy of the accounts in memory, restored from a snapshot or
+/// new, and the options that would save or publish their state are refused. Each change the
+/// records would make is logged (like each rejection) and tallied by the `Simulation`, which
+/// sits in front of the events file if there's one. Each worker of the multithreaded manager
+/// has a tally of its own, they're added up at the end
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use log::*;
+use rust_decimal::Decimal;
+
+use crate::{
+    client_account::DomainEvent,
+    events::{AccountEvent, EventSink},
+};
+
+/// Target of the logs of the would-be changes
+pub const TARGET: &str = "paytoy::simulation";
+
+/// The changes of a kind, in the order they first happened
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ChangeTally {
+    pub name: &'static str,
+    pub count: u64,
+    /// The funds moved by the changes, the holds for the disputes
+    pub funds: Decimal,
+}
+
+/// Tallies the domain events published by the managers, and forwards them to `events`
+pub struct Simulation {
+    events: Option<Arc<dyn EventSink>>,
+    /// The changes published to the simulation itself, by the single threaded manager
+    tallies: Mutex<Vec<ChangeTally>>,
+    /// The tallies of the workers, each only locked by its worker until the end
+    workers: Mutex<Vec<Arc<Mutex<Vec<ChangeTally>>>>>,
+}
+
+/// The part of a `Simulation` given to a worker
+struct WorkerSimulation {
+    events: Option<Arc<dyn EventSink>>,
+    tallies: Arc<Mutex<Vec<ChangeTally>>>,
+}
+
+impl Simulation {
+    pub fn new(events: Option<Arc<dyn EventSink>>) -> Self {
+        Self {
+            events,
+            tallies: Mutex::new(Vec::new()),
+            workers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The changes the records would have made, by kind, in the order they first happened on
+    /// the single threaded manager, then on the workers in their order
+    pub fn tallies(&self) -> Vec<ChangeTally> {
+        let mut tallies = self.tallies.lock().unwrap().clone();
+        for worker in self.workers.lock().unwrap().iter() {
+            for change in worker.lock().unwrap().iter() {
+                match tallies.iter_mut().find(|tally| tally.name == change.name) {
+                    Some(tally) => {
+                        tally.count += change.count;
+                        tally.funds += change.funds;
+                    }
+                    None => tallies.push(*change),
+                }
+            }
+        }
+        tallies
+    }
+
+    /// Tells that nothing was saved, and what the records would have changed
+    pub fn write_summary(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "SIMULATED RUN: nothing was saved, the records would make these changes"
+        )?;
+        let tallies = self.tallies();
+        let name_width = tallies
+            .iter()
+            .map(|tally| tally.name.len())
+            .chain(["change".len()])
+            .max()
+            .unwrap_or_default();
+        writeln!(
+            writer,
+            "{:<name_width$} | {:>10} | {:>16}",
+            "change",
+            "count",
+            "funds",
+            name_width = name_width
+        )?;
+        writeln!(
+            writer,
+            "{}-+-{}-+-{}",
+            "-".repeat(name_width),
+            "-".repeat(10),
+            "-".repeat(16)
+        )?;
+        for tally in tallies {
+            writeln!(
+                writer,
+                "{:<name_width$} | {:>10} | {:>16.4}",
+                tally.name,
+                tally.count,
+                tally.funds,
+                name_width = name_width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl EventSink for Simulation {
+    fn publish(&self, event: &AccountEvent) {
+        tally(&self.tallies, event);
+        if let Some(events) = &self.events {
+            events.publish(event);
+        }
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        match &self.events {
+            Some(events) => events.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn for_worker(&self) -> Option<Arc<dyn EventSink>> {
+        let tallies = Arc::new(Mutex::new(Vec::new()));
+        self.workers.lock().unwrap().push(tallies.clone());
+        let events = self.events.clone();
+        // the events file is still shared, it has an order of its own to keep
+        Some(Arc::new(WorkerSimulation { events, tallies }))
+    }
+}
+
+impl EventSink for WorkerSimulation {
+    fn publish(&self, event: &AccountEvent) {
+        tally(&self.tallies, event);
+        if let Some(events) = &self.events {
+            events.publish(event);
+        }
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        match &self.events {
+            Some(events) => events.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Logs the change of the event, if it has one, and adds it to `tallies`
+fn tally(tallies: &Mutex<Vec<ChangeTally>>, event: &AccountEvent) {
+    let change = match &event.change {
+        Some(change) => change,
+        None => return,
+    };
+    info!(
+        target: TARGET,
+        client = event.account.client, tx = event.tx, change = change.name();
+        "The record would change the account"
+    );
+    let funds = match *change {
+        DomainEvent::FundsDeposited { amount, .. }
+        | DomainEvent::FundsWithdrawn { amount, .. }
+        | DomainEvent::PaymentSent { amount, .. }
+        | DomainEvent::WithdrawalSettled { amount, .. } => amount,
+        DomainEvent::DisputeOpened { hold, .. }
+        | DomainEvent::DisputeResolved { hold, .. }
+        | DomainEvent::ChargedBack { hold, .. } => hold,
+        DomainEvent::DisputeExpired { .. } | DomainEvent::AccountLocked { .. } => Decimal::ZERO,
+    };
+    let mut tallies = tallies.lock().unwrap();
+    match tallies.iter_mut().find(|tally| tally.name == change.name()) {
+        Some(tally) => {
+            tally.count += 1;
+            tally.funds += funds;
+        }
+        None => tallies.push(ChangeTally {
+            name: change.name(),
+            count: 1,
+            funds,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, MTAccountManager, STAccountManager},
+        records::{TransactionId, TransactionRecord, TransactionType},
+    };
+
+    use super::*;
+
+    fn record(
+        tr_type: TransactionType,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount,
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    fn simulate(manager: impl AccountManager, simulation: &Simulation) {
+        let records = vec![
+            record(TransactionType::Deposit, 1, Some(dec!(2.5))),
+            record(TransactionType::Deposit, 2, Some(dec!(4))),
+            record(TransactionType::Withdrawal, 3, Some(dec!(50))),
+            record(TransactionType::Dispute, 1, None),
+            record(TransactionType::ChargeBack, 1, None),
+        ];
+        let report = manager.execute_transactions(Box::new(records.into_iter()));
+        assert_eq!(report.rejects().len(), 1);
+        simulation.flush().unwrap();
+    }
+
+    #[test]
+    fn test_tallies() {
+        let simulation = Arc::new(Simulation::new(None));
+        simulate(
+            STAccountManager::new().with_events(simulation.clone()),
+            &simulation,
+        );
+        let tallies = simulation.tallies();
+        assert_eq!(
+            tallies[0],
+            ChangeTally {
+                name: "funds_deposited",
+                count: 2,
+                funds: dec!(6.5)
+            }
+        );
+        let names: Vec<_> = tallies.iter().map(|tally| tally.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "funds_deposited",
+                "dispute_opened",
+                "charged_back",
+                "account_locked"
+            ]
+        );
+        assert_eq!(tallies[2].funds, dec!(2.5));
+
+        // the workers share the simulation
+        let shared = Arc::new(Simulation::new(None));
+        simulate(
+            MTAccountManager::new(2).with_events(shared.clone()),
+            &shared,
+        );
+        assert_eq!(shared.tallies(), tallies);
+
+        // spread over both workers, each with a tally of its own
+        let spread = Arc::new(Simulation::new(None));
+        let records: Vec<_> = (1..=8)
+            .map(|client| TransactionRecord {
+                client,
+                ..record(
+                    TransactionType::Deposit,
+                    client as TransactionId,
+                    Some(dec!(1.5)),
+                )
+            })
+            .collect();
+        MTAccountManager::new(2)
+            .with_events(spread.clone())
+            .execute_transactions(Box::new(records.into_iter()));
+        assert_eq!(spread.workers.lock().unwrap().len(), 2);
+        assert!(spread.tallies.lock().unwrap().is_empty());
+        assert_eq!(
+            spread.tallies(),
+            vec![ChangeTally {
+                name: "funds_deposited",
+                count: 8,
+                funds: dec!(12)
+            }]
+        );
+
+        let mut summary = Vec::new();
+        simulation.write_summary(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        assert!(summary.starts_with("SIMULATED RUN"));
+        assert!(summary.contains("funds_deposited |          2 |           6.5000"));
+    }
+}
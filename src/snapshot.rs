@@ -0,0 +1,569 @@
+/// The state of the accounts saved at the end of a run and restored by the next one
+/// A snapshot has the balances of the accounts and the history their disputes need. The first
+/// line is the header: the `MAGIC` word followed by a JSON object with the version of the
+/// schema, the version of paytoy that wrote it, the number of accounts and the CRC32 of the
+/// rest of the file, which has an account as JSON on each line. The header also has the digests
+/// of the input files applied to the accounts, see `processed_inputs`.
+///
+/// A snapshot written with an older schema is upgraded when it's read, by the functions of
+/// `MIGRATIONS` in order: the first one takes an account of the schema 1 to the schema 2, and so
+/// on. Changing the fields of `SnapshotAccount` means adding a migration and bumping
+/// `SCHEMA_VERSION`. The snapshots of a newer schema are refused
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use log::*;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    client_account::{ClientAccount, DisputeProgress, DisputeSlice, HistoryEntry},
+    records::{AccountKey, ClientId, TenantId, TransactionId},
+};
+
+const MAGIC: &str = "PAYTOY-SNAPSHOT";
+/// Suffix of the file a snapshot is written to before it replaces the previous one
+const PARTIAL_SUFFIX: &str = ".partial";
+/// Suffix of the snapshot replaced by the latest one
+const PREVIOUS_SUFFIX: &str = ".prev";
+/// Version of the layout of the accounts, see `MIGRATIONS`
+pub const SCHEMA_VERSION: u32 = 2;
+/// Upgrades an account of a schema to the next one, the first one upgrades the schema 1
+type Migration = fn(&mut Value);
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize - 1] = [dispute_lists];
+
+/// Schema 2: a transaction has the list of its disputes instead of a single one
+fn dispute_lists(account: &mut Value) {
+    let history = match account.get_mut("history").and_then(Value::as_array_mut) {
+        Some(history) => history,
+        None => return,
+    };
+    for transaction in history {
+        let transaction = match transaction.as_object_mut() {
+            Some(transaction) => transaction,
+            None => continue,
+        };
+        let disputed = transaction.remove("disputed") == Some(Value::Bool(true));
+        let expired = transaction.remove("expired") == Some(Value::Bool(true));
+        let disputed_amount = transaction.remove("disputed_amount");
+        let partial_hold = transaction.remove("partial_hold");
+        if !disputed {
+            continue;
+        }
+        let amount = disputed_amount
+            .or_else(|| transaction.get("amount").cloned())
+            .unwrap_or(Value::Null);
+        let hold = partial_hold.unwrap_or_else(|| amount.clone());
+        transaction.insert(
+            "disputes".to_string(),
+            serde_json::json!([{ "amount": amount, "hold": hold, "expired": expired }]),
+        );
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Header {
+    schema: u32,
+    /// The version of paytoy that wrote the snapshot
+    engine: String,
+    accounts: usize,
+    checksum: u32,
+    /// The digests of the input files applied to the accounts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    inputs: Vec<String>,
+}
+
+/// A transaction of the history of an account
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SnapshotTransaction {
+    pub tx: TransactionId,
+    pub amount: Decimal,
+    /// The disputes not settled yet, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disputes: Vec<SnapshotDispute>,
+}
+
+/// A dispute of a part of a transaction
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SnapshotDispute {
+    pub amount: Decimal,
+    /// The funds held by the dispute, less than its amount with some dispute policies
+    pub hold: Decimal,
+    /// The dispute expired, its funds stay held until it's settled
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub expired: bool,
+}
+
+/// A withdrawal of an account waiting for its settlement
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SnapshotWithdrawal {
+    pub tx: TransactionId,
+    pub amount: Decimal,
+}
+
+/// An account in a snapshot
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SnapshotAccount {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+    pub history: Vec<SnapshotTransaction>,
+    /// Only kept when the withdrawals wait for their settlement
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending_withdrawals: Vec<SnapshotWithdrawal>,
+}
+
+impl SnapshotAccount {
+    pub fn of(account: &ClientAccount) -> Self {
+        let mut history: Vec<SnapshotTransaction> = account
+            .history()
+            .map(|entry| SnapshotTransaction {
+                tx: entry.tx,
+                amount: entry.amount,
+                disputes: entry
+                    .disputes
+                    .iter()
+                    .map(|dispute| SnapshotDispute {
+                        amount: dispute.amount,
+                        hold: dispute.hold,
+                        expired: dispute.state == DisputeProgress::Expired,
+                    })
+                    .collect(),
+            })
+            .collect();
+        // the same state always gives the same snapshot
+        history.sort_by_key(|transaction| transaction.tx);
+        let mut pending_withdrawals: Vec<SnapshotWithdrawal> = account
+            .pending_withdrawals()
+            .map(|(tx, amount)| SnapshotWithdrawal { tx, amount })
+            .collect();
+        pending_withdrawals.sort_by_key(|withdrawal| withdrawal.tx);
+        Self {
+            tenant: account.tenant(),
+            client: account.id(),
+            available: account.available(),
+            held: account.held(),
+            locked: account.is_locked(),
+            history,
+            pending_withdrawals,
+        }
+    }
+
+    pub fn key(&self) -> AccountKey {
+        AccountKey::new(self.tenant, self.client)
+    }
+
+    /// Puts the balances and the history back into a new account
+    pub fn restore(&self, account: &mut ClientAccount) {
+        account.restore_balances(self.available, self.held, self.locked);
+        for transaction in &self.history {
+            let entry = HistoryEntry {
+                tx: transaction.tx,
+                amount: transaction.amount,
+                disputes: transaction
+                    .disputes
+                    .iter()
+                    .map(|dispute| DisputeSlice {
+                        // numbered by the account
+                        id: 0,
+                        amount: dispute.amount,
+                        hold: dispute.hold,
+                        state: if dispute.expired {
+                            DisputeProgress::Expired
+                        } else {
+                            DisputeProgress::InProgress
+                        },
+                    })
+                    .collect(),
+            };
+            account.restore_transaction(entry);
+        }
+        for withdrawal in &self.pending_withdrawals {
+            account.restore_pending_withdrawal(withdrawal.tx, withdrawal.amount);
+        }
+    }
+}
+
+/// The accounts of a snapshot and the version of paytoy that wrote it
+#[derive(Debug)]
+pub struct Snapshot {
+    pub engine: String,
+    pub accounts: Vec<SnapshotAccount>,
+    /// The digests of the input files applied to the accounts
+    pub inputs: Vec<String>,
+}
+
+/// Writes the snapshot of the accounts, sorted so the same state always gives the same file
+/// `inputs` are the digests of the input files applied to them
+pub fn write_snapshot(
+    writer: &mut impl Write,
+    mut accounts: Vec<SnapshotAccount>,
+    inputs: &[String],
+) -> anyhow::Result<()> {
+    accounts.sort_by_key(SnapshotAccount::key);
+    let mut body = Vec::new();
+    for account in &accounts {
+        serde_json::to_writer(&mut body, account)?;
+        body.push(b'\n');
+    }
+    let header = Header {
+        schema: SCHEMA_VERSION,
+        engine: env!("CARGO_PKG_VERSION").to_string(),
+        accounts: accounts.len(),
+        checksum: crc32fast::hash(&body),
+        inputs: inputs.to_vec(),
+    };
+    writeln!(writer, "{} {}", MAGIC, serde_json::to_string(&header)?)?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+/// Writes the snapshot to a file, replaced at once so a crash never leaves half a snapshot
+/// The snapshot it replaces is kept as the previous one, see `load_latest`
+pub fn save_snapshot(
+    path: &Path,
+    accounts: Vec<SnapshotAccount>,
+    inputs: &[String],
+) -> anyhow::Result<()> {
+    let partial = with_suffix(path, PARTIAL_SUFFIX);
+    let mut writer = BufWriter::new(
+        File::create(&partial)
+            .with_context(|| format!("Failed to create {}", partial.display()))?,
+    );
+    write_snapshot(&mut writer, accounts, inputs)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    if path.exists() {
+        std::fs::rename(path, with_suffix(path, PREVIOUS_SUFFIX))?;
+    }
+    std::fs::rename(partial, path)?;
+    Ok(())
+}
+
+/// `state.snap` becomes `state.snap.prev`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Reads a snapshot, checking its checksum and upgrading it to the current schema
+pub fn read_snapshot(mut reader: impl BufRead) -> anyhow::Result<Snapshot> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let header = line
+        .trim_end()
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| anyhow::anyhow!("Not a paytoy snapshot"))?;
+    let header: Header = serde_json::from_str(header).context("Invalid snapshot header")?;
+    if header.schema == 0 || header.schema > SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "The snapshot was written by paytoy {} with the schema {}, this version only reads \
+             the schemas up to {}",
+            header.engine,
+            header.schema,
+            SCHEMA_VERSION
+        ));
+    }
+
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    if crc32fast::hash(&body) != header.checksum {
+        return Err(anyhow::anyhow!(
+            "The snapshot is corrupted, its checksum doesn't match"
+        ));
+    }
+    let mut accounts = Vec::with_capacity(header.accounts);
+    for line in body.split(|byte| *byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let mut account: Value = serde_json::from_slice(line)?;
+        for migration in &MIGRATIONS[header.schema as usize - 1..] {
+            migration(&mut account);
+        }
+        accounts.push(serde_json::from_value(account)?);
+    }
+    if accounts.len() != header.accounts {
+        return Err(anyhow::anyhow!(
+            "The snapshot has {} accounts instead of {}",
+            accounts.len(),
+            header.accounts
+        ));
+    }
+    Ok(Snapshot {
+        engine: header.engine,
+        accounts,
+        inputs: header.inputs,
+    })
+}
+
+pub fn load_snapshot(path: &Path) -> anyhow::Result<Snapshot> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    read_snapshot(BufReader::new(file))
+        .with_context(|| format!("Invalid snapshot {}", path.display()))
+}
+
+/// The latest valid snapshot saved to `path`: the snapshot itself, or the previous one if it's
+/// corrupted. `None` if no snapshot was saved yet
+pub fn load_latest(path: &Path) -> anyhow::Result<Option<Snapshot>> {
+    let previous = with_suffix(path, PREVIOUS_SUFFIX);
+    let mut error = None;
+    for path in [path, previous.as_path()] {
+        if !path.exists() {
+            continue;
+        }
+        match load_snapshot(path) {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(err) => {
+                warn!("Skipping the snapshot {}: {:#}", path.display(), err);
+                error.get_or_insert(err);
+            }
+        }
+    }
+    match error {
+        Some(err) => Err(err.context("No valid snapshot to restore")),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, MTAccountManager, STAccountManager},
+        client_account::DisputePolicy,
+        records::{TransactionRecord, TransactionType},
+        report::Report,
+        transactions_reader::TransactionsStream,
+    };
+
+    use super::*;
+
+    fn record(tr_type: TransactionType, client: ClientId, tx: TransactionId) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount: match tr_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => Some(dec!(10)),
+                _ => None,
+            },
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    fn snapshot_bytes(accounts: &[ClientAccount]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let accounts = accounts.iter().map(SnapshotAccount::of).collect();
+        write_snapshot(&mut output, accounts, &[]).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut account =
+            ClientAccount::new(1).with_dispute_policy(DisputePolicy::HoldUpToAvailable);
+        account.deposit(1, dec!(10)).unwrap();
+        account.deposit(2, dec!(5)).unwrap();
+        account.withdraw(3, dec!(12)).unwrap();
+        account.dispute(1).unwrap();
+        let locked =
+            ClientAccount::from_balances(AccountKey::new(Some(2), 1), dec!(1), dec!(0), true);
+
+        let output = snapshot_bytes(&[account, locked]);
+        let snapshot = read_snapshot(output.as_slice()).unwrap();
+        assert_eq!(snapshot.engine, env!("CARGO_PKG_VERSION"));
+        assert_eq!(snapshot.accounts.len(), 2);
+        let restored = &snapshot.accounts[0];
+        assert_eq!(restored.available, dec!(0));
+        assert_eq!(restored.held, dec!(3));
+        assert_eq!(
+            restored.history[0],
+            SnapshotTransaction {
+                tx: 1,
+                amount: dec!(10),
+                disputes: vec![SnapshotDispute {
+                    amount: dec!(10),
+                    hold: dec!(3),
+                    expired: false
+                }],
+            }
+        );
+
+        // the restored account resolves the dispute like the original one
+        let mut account = ClientAccount::new(1);
+        restored.restore(&mut account);
+        account.resolve(1).unwrap();
+        assert_eq!(account.available(), dec!(3));
+        assert_eq!(account.held(), dec!(0));
+        assert_eq!(
+            account.deposit(2, dec!(1)),
+            Err(crate::errors::TransactionError::DuplicateTransaction)
+        );
+        assert!(snapshot.accounts[1].locked);
+    }
+
+    #[test]
+    fn test_snapshot_partial_dispute() {
+        let mut account = ClientAccount::new(1);
+        account.deposit(1, dec!(10)).unwrap();
+        account.dispute_amount(1, dec!(4)).unwrap();
+
+        let output = snapshot_bytes(&[account]);
+        let snapshot = read_snapshot(output.as_slice()).unwrap();
+        assert_eq!(snapshot.accounts[0].history[0].disputes[0].amount, dec!(4));
+
+        // the chargeback takes the disputed part, the rest can still be disputed
+        let mut account = ClientAccount::new(1);
+        snapshot.accounts[0].restore(&mut account);
+        account.chargeback(1).unwrap();
+        assert_eq!(account.total(), dec!(6));
+        account.dispute(1).unwrap();
+        assert_eq!(account.held(), dec!(6));
+    }
+
+    #[test]
+    fn test_migrate_schema_1() {
+        let body = "{\"client\":1,\"available\":\"6\",\"held\":\"4\",\"locked\":false,\
+                    \"history\":[{\"tx\":1,\"amount\":\"10\",\"disputed\":true,\
+                    \"disputed_amount\":\"4\",\"expired\":true},\
+                    {\"tx\":2,\"amount\":\"3\",\"disputed\":false}]}\n";
+        let text = format!(
+            "{} {{\"schema\":1,\"engine\":\"0.1.0\",\"accounts\":1,\"checksum\":{}}}\n{}",
+            MAGIC,
+            crc32fast::hash(body.as_bytes()),
+            body
+        );
+        let snapshot = read_snapshot(text.as_bytes()).unwrap();
+        let history = &snapshot.accounts[0].history;
+        assert_eq!(
+            history[0].disputes,
+            vec![SnapshotDispute {
+                amount: dec!(4),
+                hold: dec!(4),
+                expired: true
+            }]
+        );
+        assert!(history[1].disputes.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_pending_withdrawals() {
+        let mut account = ClientAccount::new(1).with_pending_withdrawals();
+        account.deposit(1, dec!(10)).unwrap();
+        account.withdraw(2, dec!(4)).unwrap();
+
+        let output = snapshot_bytes(&[account]);
+        let snapshot = read_snapshot(output.as_slice()).unwrap();
+        assert_eq!(
+            snapshot.accounts[0].pending_withdrawals,
+            vec![SnapshotWithdrawal {
+                tx: 2,
+                amount: dec!(4)
+            }]
+        );
+
+        let mut account = ClientAccount::new(1).with_pending_withdrawals();
+        snapshot.accounts[0].restore(&mut account);
+        assert_eq!(account.total(), dec!(10));
+        account.settle(2).unwrap();
+        assert_eq!(account.total(), dec!(6));
+
+        // the accounts without pending withdrawals don't have the field
+        let output = snapshot_bytes(&[account]);
+        assert!(!String::from_utf8(output)
+            .unwrap()
+            .contains("pending_withdrawals"));
+    }
+
+    #[test]
+    fn test_invalid_snapshots() {
+        let output = snapshot_bytes(&[ClientAccount::from_balances(
+            AccountKey::from(1),
+            dec!(1),
+            dec!(0),
+            false,
+        )]);
+        let text = String::from_utf8(output).unwrap();
+
+        let corrupted = text.replace("\"available\":\"1\"", "\"available\":\"9\"");
+        let err = read_snapshot(corrupted.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+
+        let newer = text.replace(
+            &format!("\"schema\":{}", SCHEMA_VERSION),
+            &format!("\"schema\":{}", SCHEMA_VERSION + 1),
+        );
+        let err = read_snapshot(newer.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("only reads"));
+
+        assert!(read_snapshot("client, available\n".as_bytes()).is_err());
+    }
+
+    /// A snapshot after a deposit and a dispute in progress
+    fn first_run() -> Snapshot {
+        let report = STAccountManager::new().execute_transactions(Box::new(
+            vec![
+                record(TransactionType::Deposit, 1, 1),
+                record(TransactionType::Deposit, 2, 2),
+                record(TransactionType::Dispute, 2, 2),
+            ]
+            .into_iter(),
+        ));
+        let mut output = Vec::new();
+        write_snapshot(
+            &mut output,
+            report.accounts().map(SnapshotAccount::of).collect(),
+            &["ba7816bf".to_string()],
+        )
+        .unwrap();
+        read_snapshot(output.as_slice()).unwrap()
+    }
+
+    fn next_records() -> TransactionsStream {
+        Box::new(
+            vec![
+                record(TransactionType::Deposit, 1, 1),
+                record(TransactionType::ChargeBack, 2, 2),
+                record(TransactionType::Deposit, 3, 3),
+            ]
+            .into_iter(),
+        )
+    }
+
+    fn check_next_run(report: &Report) {
+        // the repeated deposit is a duplicate, the dispute goes on
+        assert_eq!(report.account(1).unwrap().available(), dec!(10));
+        assert!(report.account(2).unwrap().is_locked());
+        assert_eq!(report.account(2).unwrap().total(), dec!(0));
+        assert_eq!(report.account(3).unwrap().total(), dec!(10));
+    }
+
+    #[test]
+    fn test_restore_snapshot() {
+        let snapshot = first_run();
+        assert_eq!(snapshot.inputs, vec!["ba7816bf".to_string()]);
+        let manager = STAccountManager::new().with_snapshot(&snapshot);
+        check_next_run(&manager.execute_transactions(next_records()));
+        let manager = MTAccountManager::new(3).with_snapshot(&snapshot);
+        check_next_run(&manager.execute_transactions(next_records()));
+
+        // the restored accounts are reported even without records
+        let manager = MTAccountManager::new(3).with_snapshot(&snapshot);
+        let report = manager.execute_transactions(Box::new(std::iter::empty()));
+        assert_eq!(report.accounts().count(), 2);
+    }
+}
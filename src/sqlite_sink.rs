@@ -0,0 +1,150 @@
+/// Writes the report into a SQLite database, so each run leaves a queryable artifact
+/// Amounts are stored as text, SQLite has no exact decimal type. The tenant is NULL for the
+/// records without one
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+
+use crate::{accounts_meta::AccountsMeta, report::Report};
+
+const SCHEMA: &str = "
+    DROP TABLE IF EXISTS accounts;
+    DROP TABLE IF EXISTS rejects;
+    CREATE TABLE accounts (
+        tenant INTEGER,
+        client INTEGER NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        locked INTEGER NOT NULL,
+        name TEXT,
+        tier TEXT,
+        country TEXT,
+        UNIQUE (tenant, client)
+    );
+    CREATE TABLE rejects (
+        type TEXT NOT NULL,
+        tenant INTEGER,
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        amount TEXT,
+        reason TEXT NOT NULL,
+        input TEXT,
+        line INTEGER
+    );
+";
+
+/// Replaces the `accounts` and `rejects` tables of the database with the report
+/// Other tables in the database are left untouched. The fields of `meta` are NULL for the
+/// accounts it doesn't list
+pub fn write_sqlite(
+    path: &Path,
+    report: &Report,
+    meta: Option<&AccountsMeta>,
+) -> anyhow::Result<()> {
+    let mut connection = Connection::open(path)?;
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(SCHEMA)?;
+
+    {
+        let mut insert = transaction.prepare(
+            "INSERT INTO accounts (tenant, client, available, held, total, locked, name, tier, country) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        for account in report.accounts() {
+            let fields = meta.and_then(|meta| meta.get(account.key()));
+            insert.execute(params![
+                account.tenant(),
+                account.id(),
+                account.available().to_string(),
+                account.held().to_string(),
+                account.total().to_string(),
+                account.is_locked(),
+                fields.map(|fields| &fields.name),
+                fields.map(|fields| &fields.tier),
+                fields.map(|fields| &fields.country),
+            ])?;
+        }
+
+        let mut insert = transaction.prepare(
+            "INSERT INTO rejects (type, tenant, client, tx, amount, reason, input, line) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for reject in report.rejects() {
+            insert.execute(params![
+                reject.tr_type.to_string(),
+                reject.tenant,
+                reject.client,
+                reject.tx,
+                reject.amount.map(|amount| amount.to_string()),
+                reject.reason.to_string(),
+                reject.source.as_ref().map(|source| source.input.as_ref()),
+                reject.source.as_ref().map(|source| source.line),
+            ])?;
+        }
+    }
+
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        account_manager::{AccountManager, STAccountManager},
+        transactions_reader::{STBulkReader, TransactionCSVReader},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_write_sqlite() {
+        let transactions = STBulkReader::new()
+            .with_source("test_basic.csv".into())
+            .read_csv("tests/data/test_basic.csv")
+            .unwrap();
+        let report = STAccountManager::new().execute_transactions(transactions);
+
+        let path = std::env::temp_dir().join("paytoy_test_report.db");
+        // written twice to check that the tables are replaced
+        let meta = AccountsMeta::from_reader("client, name\n1, Alice\n".as_bytes()).unwrap();
+        write_sqlite(&path, &report, None).unwrap();
+        write_sqlite(&path, &report, Some(&meta)).unwrap();
+
+        let connection = Connection::open(&path).unwrap();
+        let (available, locked): (String, bool) = connection
+            .query_row(
+                "SELECT available, locked FROM accounts WHERE client = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(available, "1.5");
+        assert!(!locked);
+        let names: Vec<Option<String>> = connection
+            .prepare("SELECT name FROM accounts ORDER BY client")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(names, vec![Some("Alice".to_string()), None]);
+
+        // the withdrawal of 3.0 from client 2 is rejected
+        let (client, tx, amount, input, line): (u16, u32, String, String, u64) = connection
+            .query_row(
+                "SELECT client, tx, amount, input, line FROM rejects",
+                [],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!((client, tx, amount.as_str()), (2, 5, "3"));
+        assert_eq!((input.as_str(), line), ("test_basic.csv", 6));
+    }
+}
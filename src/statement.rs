@@ -0,0 +1,474 @@
+/// Statements of the accounts, for the back-office software that can't read the CSV report
+/// A statement is written per account from its journal: the opening balance (always zero, the
+/// runs start from empty accounts), every applied transaction and the closing balances. The
+/// amounts are in the `XXX` currency, the inputs don't say which one they use. The timestamps
+/// of the records are in an unknown unit, so the OFX and QIF entries are dated on the day of
+/// the run
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+
+use crate::{
+    accounts_meta::AccountsMeta,
+    client_account::{ClientAccount, JournalEntry},
+    records::AccountKey,
+    report::Report,
+};
+
+/// ISO 4217 code for "no currency"
+const CURRENCY: &str = "XXX";
+const CAMT053_NAMESPACE: &str = "urn:iso:std:iso:20022:tech:xsd:camt.053.001.02";
+
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum StatementFormat {
+    /// ISO 20022 bank to customer statement (camt.053), with only the mandatory elements
+    /// that can be filled from the inputs
+    #[value(name = "camt053")]
+    Camt053,
+    /// Open Financial Exchange 2.2 bank statement, for the accounting tools
+    Ofx,
+    /// Quicken Interchange Format, for the personal finance tools
+    Qif,
+}
+
+impl StatementFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            StatementFormat::Camt053 => "xml",
+            StatementFormat::Ofx => "ofx",
+            StatementFormat::Qif => "qif",
+        }
+    }
+}
+
+/// A day of the proleptic Gregorian calendar, in UTC
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Date {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    pub fn today() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Self::from_unix(secs)
+    }
+
+    /// The day of a Unix timestamp in seconds
+    pub fn from_unix(secs: u64) -> Self {
+        // days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+        let days = (secs / 86400) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        Self { year, month, day }
+    }
+
+    /// `YYYYMMDD`, as in the OFX files
+    fn compact(self) -> String {
+        format!("{:04}{:02}{:02}", self.year, self.month, self.day)
+    }
+
+    /// `MM/DD/YYYY`, as in the QIF files
+    fn us(self) -> String {
+        format!("{:02}/{:02}/{:04}", self.month, self.day, self.year)
+    }
+}
+
+/// Writes the statement of every account of the report to `dir`, returns how many were written
+/// The owners are named after their `meta` if it's given
+pub fn write_statements(
+    dir: &Path,
+    report: &Report,
+    format: StatementFormat,
+    meta: Option<&AccountsMeta>,
+) -> anyhow::Result<usize> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create the directory {}", dir.display()))?;
+    let date = Date::today();
+    let mut written = 0;
+    for account in report.accounts() {
+        let path = statement_path(dir, account.key(), format);
+        let file = std::fs::File::create(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let owner = meta
+            .and_then(|meta| meta.get(account.key()))
+            .map(|fields| fields.name.as_str())
+            .filter(|name| !name.is_empty());
+        let mut writer = BufWriter::new(file);
+        match format {
+            StatementFormat::Camt053 => write_camt053(&mut writer, account, owner)?,
+            StatementFormat::Ofx => write_ofx(&mut writer, account, date)?,
+            StatementFormat::Qif => write_qif(&mut writer, account, owner, date)?,
+        }
+        writer.flush()?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// `client-1.xml`, or `tenant-2-client-1.xml` for an account of a tenant
+fn statement_path(dir: &Path, key: AccountKey, format: StatementFormat) -> PathBuf {
+    let name = match key.tenant {
+        Some(tenant) => format!("tenant-{}-client-{}", tenant, key.client),
+        None => format!("client-{}", key.client),
+    };
+    dir.join(name).with_extension(format.extension())
+}
+
+/// The id of the account in the statements, `tenant-client` for an account of a tenant
+fn account_id(key: AccountKey) -> String {
+    match key.tenant {
+        Some(tenant) => format!("{}-{}", tenant, key.client),
+        None => key.client.to_string(),
+    }
+}
+
+/// Writes a camt.053 document with a single statement
+/// The deposits, withdrawals and chargebacks are booked entries. The disputes and resolves
+/// don't change the total, they're informational entries of the funds moved between the
+/// available and the held balances
+pub fn write_camt053(
+    writer: &mut impl Write,
+    account: &ClientAccount,
+    owner: Option<&str>,
+) -> std::io::Result<()> {
+    let id = account_id(account.key());
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<Document xmlns="{}">"#, CAMT053_NAMESPACE)?;
+    writeln!(writer, "  <BkToCstmrStmt>")?;
+    writeln!(writer, "    <GrpHdr>")?;
+    writeln!(writer, "      <MsgId>paytoy-{}</MsgId>", id)?;
+    writeln!(writer, "    </GrpHdr>")?;
+    writeln!(writer, "    <Stmt>")?;
+    writeln!(writer, "      <Id>{}</Id>", id)?;
+    writeln!(writer, "      <Acct>")?;
+    writeln!(writer, "        <Id><Othr><Id>{}</Id></Othr></Id>", id)?;
+    if let Some(owner) = owner {
+        writeln!(
+            writer,
+            "        <Ownr><Nm>{}</Nm></Ownr>",
+            xml_escape(owner)
+        )?;
+    }
+    writeln!(writer, "      </Acct>")?;
+    write_balance(writer, "OPBD", Decimal::ZERO)?;
+    write_balance(writer, "CLBD", account.total())?;
+    write_balance(writer, "CLAV", account.available())?;
+    for entry in account.journal() {
+        let (amount, status) = if entry.total_change().is_zero() {
+            (entry.available_change, "INFO")
+        } else {
+            (entry.total_change(), "BOOK")
+        };
+        writeln!(writer, "      <Ntry>")?;
+        writeln!(writer, "        <NtryRef>{}</NtryRef>", entry.tx)?;
+        writeln!(
+            writer,
+            r#"        <Amt Ccy="{}">{}</Amt>"#,
+            CURRENCY,
+            amount.abs().normalize()
+        )?;
+        writeln!(
+            writer,
+            "        <CdtDbtInd>{}</CdtDbtInd>",
+            credit_debit(amount)
+        )?;
+        writeln!(writer, "        <Sts>{}</Sts>", status)?;
+        writeln!(
+            writer,
+            "        <AddtlNtryInf>{}</AddtlNtryInf>",
+            entry.tr_type
+        )?;
+        writeln!(writer, "      </Ntry>")?;
+    }
+    writeln!(writer, "    </Stmt>")?;
+    writeln!(writer, "  </BkToCstmrStmt>")?;
+    writeln!(writer, "</Document>")
+}
+
+/// Writes an OFX 2.2 document with the statement of a checking account
+/// Only the transactions that change the total are listed, as credits and debits
+pub fn write_ofx(
+    writer: &mut impl Write,
+    account: &ClientAccount,
+    date: Date,
+) -> std::io::Result<()> {
+    let date = date.compact();
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        writer,
+        r#"<?OFX OFXHEADER="200" VERSION="220" SECURITY="NONE" OLDFILEUID="NONE" NEWFILEUID="NONE"?>"#
+    )?;
+    writeln!(writer, "<OFX>")?;
+    writeln!(writer, "  <SIGNONMSGSRSV1>")?;
+    writeln!(writer, "    <SONRS>")?;
+    writeln!(
+        writer,
+        "      <STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>"
+    )?;
+    writeln!(writer, "      <DTSERVER>{}</DTSERVER>", date)?;
+    writeln!(writer, "      <LANGUAGE>ENG</LANGUAGE>")?;
+    writeln!(writer, "    </SONRS>")?;
+    writeln!(writer, "  </SIGNONMSGSRSV1>")?;
+    writeln!(writer, "  <BANKMSGSRSV1>")?;
+    writeln!(writer, "    <STMTTRNRS>")?;
+    writeln!(writer, "      <TRNUID>0</TRNUID>")?;
+    writeln!(
+        writer,
+        "      <STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>"
+    )?;
+    writeln!(writer, "      <STMTRS>")?;
+    writeln!(writer, "        <CURDEF>{}</CURDEF>", CURRENCY)?;
+    writeln!(writer, "        <BANKACCTFROM>")?;
+    writeln!(writer, "          <BANKID>paytoy</BANKID>")?;
+    writeln!(
+        writer,
+        "          <ACCTID>{}</ACCTID>",
+        account_id(account.key())
+    )?;
+    writeln!(writer, "          <ACCTTYPE>CHECKING</ACCTTYPE>")?;
+    writeln!(writer, "        </BANKACCTFROM>")?;
+    writeln!(writer, "        <BANKTRANLIST>")?;
+    writeln!(writer, "          <DTSTART>{}</DTSTART>", date)?;
+    writeln!(writer, "          <DTEND>{}</DTEND>", date)?;
+    for entry in booked(account) {
+        let amount = entry.total_change();
+        writeln!(writer, "          <STMTTRN>")?;
+        writeln!(
+            writer,
+            "            <TRNTYPE>{}</TRNTYPE>",
+            if amount.is_sign_negative() {
+                "DEBIT"
+            } else {
+                "CREDIT"
+            }
+        )?;
+        writeln!(writer, "            <DTPOSTED>{}</DTPOSTED>", date)?;
+        writeln!(
+            writer,
+            "            <TRNAMT>{}</TRNAMT>",
+            amount.normalize()
+        )?;
+        writeln!(writer, "            <FITID>{}</FITID>", entry_id(entry))?;
+        writeln!(writer, "            <NAME>{}</NAME>", entry.tr_type)?;
+        writeln!(writer, "          </STMTTRN>")?;
+    }
+    writeln!(writer, "        </BANKTRANLIST>")?;
+    for (tag, amount) in [
+        ("LEDGERBAL", account.total()),
+        ("AVAILBAL", account.available()),
+    ] {
+        writeln!(writer, "        <{}>", tag)?;
+        writeln!(writer, "          <BALAMT>{}</BALAMT>", amount.normalize())?;
+        writeln!(writer, "          <DTASOF>{}</DTASOF>", date)?;
+        writeln!(writer, "        </{}>", tag)?;
+    }
+    writeln!(writer, "      </STMTRS>")?;
+    writeln!(writer, "    </STMTTRNRS>")?;
+    writeln!(writer, "  </BANKMSGSRSV1>")?;
+    writeln!(writer, "</OFX>")
+}
+
+/// Writes a QIF bank account with a record per transaction that changes the total
+/// The owner is the payee of the records if it's known
+pub fn write_qif(
+    writer: &mut impl Write,
+    account: &ClientAccount,
+    owner: Option<&str>,
+    date: Date,
+) -> std::io::Result<()> {
+    let date = date.us();
+    writeln!(writer, "!Type:Bank")?;
+    for entry in booked(account) {
+        writeln!(writer, "D{}", date)?;
+        writeln!(writer, "T{}", entry.total_change().normalize())?;
+        writeln!(writer, "N{}", entry_id(entry))?;
+        if let Some(owner) = owner {
+            // a line break would start a new field
+            writeln!(writer, "P{}", owner.replace(['\n', '\r'], " "))?;
+        }
+        writeln!(writer, "M{}", entry.tr_type)?;
+        writeln!(writer, "^")?;
+    }
+    Ok(())
+}
+
+/// The entries of the journal that change the total, the disputes and resolves only move funds
+fn booked(account: &ClientAccount) -> impl Iterator<Item = &JournalEntry> {
+    account
+        .journal()
+        .iter()
+        .filter(|entry| !entry.total_change().is_zero())
+}
+
+/// Unique id of an entry of the account, a chargeback has the id of the deposit it reverses
+fn entry_id(entry: &JournalEntry) -> String {
+    format!("{}-{}", entry.tx, entry.tr_type)
+}
+
+fn write_balance(writer: &mut impl Write, code: &str, amount: Decimal) -> std::io::Result<()> {
+    writeln!(writer, "      <Bal>")?;
+    writeln!(
+        writer,
+        "        <Tp><CdOrPrtry><Cd>{}</Cd></CdOrPrtry></Tp>",
+        code
+    )?;
+    writeln!(
+        writer,
+        r#"        <Amt Ccy="{}">{}</Amt>"#,
+        CURRENCY,
+        amount.abs().normalize()
+    )?;
+    writeln!(
+        writer,
+        "        <CdtDbtInd>{}</CdtDbtInd>",
+        credit_debit(amount)
+    )?;
+    writeln!(writer, "      </Bal>")
+}
+
+fn credit_debit(amount: Decimal) -> &'static str {
+    if amount.is_sign_negative() && !amount.is_zero() {
+        "DBIT"
+    } else {
+        "CRDT"
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        account_manager::{AccountManager, STAccountManager},
+        client_account::DisputePolicy,
+        records::{TransactionId, TransactionRecord, TransactionType},
+    };
+
+    use super::*;
+
+    fn record(
+        tr_type: TransactionType,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tr_type,
+            client: 1,
+            tenant: None,
+            merchant: None,
+            tx,
+            amount,
+            timestamp: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_camt053() {
+        let mut manager = STAccountManager::new()
+            .with_dispute_policy(DisputePolicy::AllowNegativeAvailable)
+            .with_journal();
+        for record in [
+            record(TransactionType::Deposit, 1, Some(dec!(10.50))),
+            record(TransactionType::Withdrawal, 2, Some(dec!(3))),
+            record(TransactionType::Withdrawal, 3, Some(dec!(30))),
+            record(TransactionType::Dispute, 1, None),
+        ] {
+            let _ = manager.apply(record);
+        }
+        let report = manager.finish();
+        let account = report.account(1).unwrap();
+        // the rejected withdrawal isn't journaled
+        assert_eq!(account.journal().len(), 3);
+
+        let mut output = Vec::new();
+        write_camt053(&mut output, account, Some("Tom & Jerry")).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("<Ownr><Nm>Tom &amp; Jerry</Nm></Ownr>"));
+        assert!(output.contains(
+            "<Tp><CdOrPrtry><Cd>CLBD</Cd></CdOrPrtry></Tp>\n        \
+             <Amt Ccy=\"XXX\">7.5</Amt>\n        <CdtDbtInd>CRDT</CdtDbtInd>"
+        ));
+        assert!(output.contains(
+            "<NtryRef>2</NtryRef>\n        <Amt Ccy=\"XXX\">3</Amt>\n        \
+             <CdtDbtInd>DBIT</CdtDbtInd>\n        <Sts>BOOK</Sts>"
+        ));
+        // the dispute makes the available balance negative
+        assert!(output.contains(
+            "<Amt Ccy=\"XXX\">10.5</Amt>\n        <CdtDbtInd>DBIT</CdtDbtInd>\n        \
+             <Sts>INFO</Sts>\n        <AddtlNtryInf>dispute</AddtlNtryInf>"
+        ));
+        assert_eq!(output.matches("<Ntry>").count(), 3);
+
+        let date = Date::from_unix(1_700_000_000);
+        let mut output = Vec::new();
+        write_ofx(&mut output, account, date).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("<DTPOSTED>20231114</DTPOSTED>"));
+        assert!(output.contains(
+            "<TRNTYPE>DEBIT</TRNTYPE>\n            <DTPOSTED>20231114</DTPOSTED>\n            \
+             <TRNAMT>-3</TRNAMT>\n            <FITID>2-withdrawal</FITID>"
+        ));
+        // the dispute isn't listed
+        assert_eq!(output.matches("<STMTTRN>").count(), 2);
+        assert!(output.contains("<LEDGERBAL>\n          <BALAMT>7.5</BALAMT>"));
+
+        let mut output = Vec::new();
+        write_qif(&mut output, account, Some("Tom"), date).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "!Type:Bank\n\
+             D11/14/2023\nT10.5\nN1-deposit\nPTom\nMdeposit\n^\n\
+             D11/14/2023\nT-3\nN2-withdrawal\nPTom\nMwithdrawal\n^\n"
+        );
+
+        let dir = std::env::temp_dir().join("paytoy_test_statements");
+        assert_eq!(
+            write_statements(&dir, &report, StatementFormat::Camt053, None).unwrap(),
+            1
+        );
+        assert!(dir.join("client-1.xml").is_file());
+        assert_eq!(
+            statement_path(&dir, AccountKey::new(Some(2), 1), StatementFormat::Camt053),
+            dir.join("tenant-2-client-1.xml")
+        );
+    }
+
+    #[test]
+    fn test_dates() {
+        let date = |year, month, day| Date { year, month, day };
+        assert_eq!(Date::from_unix(0), date(1970, 1, 1));
+        assert_eq!(Date::from_unix(951_782_400), date(2000, 2, 29));
+        assert_eq!(Date::from_unix(1_704_067_199), date(2023, 12, 31));
+        assert_eq!(Date::from_unix(1_704_067_200).us(), "01/01/2024");
+        assert_eq!(date(2024, 3, 5).compact(), "20240305");
+    }
+}
@@ -0,0 +1,469 @@
+/// Live statistics of a run, shared between the stages of the pipeline
+/// Stages update them in batches, so keeping them enabled is cheap
+use std::{
+    cmp::Reverse,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rust_decimal::Decimal;
+
+use crate::{client_account::ClientAccount, latency::ApplyLatencies, records::ClientId};
+
+/// How many top accounts each worker publishes
+pub const TOP_ACCOUNTS: usize = 10;
+
+/// A worker applying more than this many times its fair share of the records is skewed
+const SKEW_FACTOR: f64 = 2.0;
+
+/// Depth of a bounded queue between two stages of the pipeline
+pub struct QueueGauge {
+    name: String,
+    capacity: usize,
+    depth: AtomicUsize,
+}
+
+impl QueueGauge {
+    pub fn set(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+/// Statistics published by a single account manager worker
+#[derive(Default)]
+pub struct WorkerStats {
+    /// The worker id of the multithreaded manager, the registration order otherwise
+    id: AtomicUsize,
+    /// Records applied or rejected by the worker
+    records: AtomicU64,
+    /// Accounts owned by the worker
+    accounts: AtomicUsize,
+    /// Nanoseconds spent waiting for a record on an empty queue
+    queue_wait: AtomicU64,
+    /// Accounts with the biggest total funds, sorted in descending order
+    top_accounts: Mutex<Vec<(ClientId, Decimal)>>,
+}
+
+impl WorkerStats {
+    pub fn set_id(&self, id: usize) {
+        self.id.store(id, Ordering::Relaxed);
+    }
+
+    pub fn add_records(&self, records: u64) {
+        self.records.fetch_add(records, Ordering::Relaxed);
+    }
+
+    pub fn set_accounts(&self, accounts: usize) {
+        self.accounts.store(accounts, Ordering::Relaxed);
+    }
+
+    pub fn add_queue_wait(&self, wait: Duration) {
+        self.queue_wait
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn id(&self) -> usize {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records.load(Ordering::Relaxed)
+    }
+
+    pub fn accounts(&self) -> usize {
+        self.accounts.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_wait(&self) -> Duration {
+        Duration::from_nanos(self.queue_wait.load(Ordering::Relaxed))
+    }
+
+    /// Recomputes the top accounts from all the accounts owned by the worker
+    pub fn publish_top_accounts<'a>(&self, accounts: impl Iterator<Item = &'a ClientAccount>) {
+        let mut top: Vec<(ClientId, Decimal)> = Vec::with_capacity(TOP_ACCOUNTS + 1);
+        for account in accounts {
+            let total = account.total();
+            if top.len() == TOP_ACCOUNTS && total <= top[TOP_ACCOUNTS - 1].1 {
+                continue;
+            }
+            let pos = top.partition_point(|(_, other)| *other >= total);
+            top.insert(pos, (account.id(), total));
+            top.truncate(TOP_ACCOUNTS);
+        }
+
+        if let Ok(mut top_accounts) = self.top_accounts.lock() {
+            *top_accounts = top;
+        }
+    }
+}
+
+pub struct PipelineStats {
+    started: Instant,
+    finished: AtomicBool,
+
+    records_read: AtomicU64,
+    parse_errors: AtomicU64,
+    /// Amounts read after stripping their currency symbol, with `--lenient-amounts`
+    amounts_normalized: AtomicU64,
+    /// Inputs that failed before their end
+    input_errors: AtomicU64,
+    /// Records handed to the account manager, only counted with the watchdog
+    records_dispatched: AtomicU64,
+    records_applied: AtomicU64,
+    records_rejected: AtomicU64,
+    /// Records dropped as replays by the replay window
+    records_replayed: AtomicU64,
+    /// Records of the live inputs dropped as repeating the end of the backfill
+    records_overlapping: AtomicU64,
+    /// Records left out by the filters of the readers
+    records_filtered: AtomicU64,
+    /// New clients sent to another worker than their shard, with `--spill-clients`
+    clients_spilled: AtomicU64,
+
+    queues: Mutex<Vec<Arc<QueueGauge>>>,
+    workers: Mutex<Vec<Arc<WorkerStats>>>,
+    /// The latencies of the records applied, only measured with `--latency`
+    latencies: Mutex<ApplyLatencies>,
+}
+
+impl PipelineStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            finished: AtomicBool::new(false),
+            records_read: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            amounts_normalized: AtomicU64::new(0),
+            input_errors: AtomicU64::new(0),
+            records_dispatched: AtomicU64::new(0),
+            records_applied: AtomicU64::new(0),
+            records_rejected: AtomicU64::new(0),
+            records_replayed: AtomicU64::new(0),
+            records_overlapping: AtomicU64::new(0),
+            records_filtered: AtomicU64::new(0),
+            clients_spilled: AtomicU64::new(0),
+            queues: Mutex::new(Vec::new()),
+            workers: Mutex::new(Vec::new()),
+            latencies: Mutex::new(ApplyLatencies::new()),
+        }
+    }
+
+    pub fn add_read(&self, records: u64, parse_errors: u64) {
+        self.records_read.fetch_add(records, Ordering::Relaxed);
+        self.parse_errors.fetch_add(parse_errors, Ordering::Relaxed);
+    }
+
+    pub fn add_filtered(&self, records: u64) {
+        self.records_filtered.fetch_add(records, Ordering::Relaxed);
+    }
+
+    pub fn add_normalized(&self, amounts: u64) {
+        self.amounts_normalized
+            .fetch_add(amounts, Ordering::Relaxed);
+    }
+
+    /// An input couldn't be read to the end, the run is only partial
+    pub fn add_input_error(&self) {
+        self.input_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_dispatched(&self, records: u64) {
+        self.records_dispatched
+            .fetch_add(records, Ordering::Relaxed);
+    }
+
+    pub fn add_processed(&self, applied: u64, rejected: u64) {
+        self.records_applied.fetch_add(applied, Ordering::Relaxed);
+        self.records_rejected.fetch_add(rejected, Ordering::Relaxed);
+    }
+
+    pub fn add_latencies(&self, latencies: &ApplyLatencies) {
+        if let Ok(mut total) = self.latencies.lock() {
+            total.merge(latencies);
+        }
+    }
+
+    pub fn add_replayed(&self) {
+        self.records_replayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_overlapping(&self) {
+        self.records_overlapping.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_spilled(&self, clients: u64) {
+        self.clients_spilled.fetch_add(clients, Ordering::Relaxed);
+    }
+
+    pub fn register_queue(&self, name: impl Into<String>, capacity: usize) -> Arc<QueueGauge> {
+        let gauge = Arc::new(QueueGauge {
+            name: name.into(),
+            capacity,
+            depth: AtomicUsize::new(0),
+        });
+        if let Ok(mut queues) = self.queues.lock() {
+            queues.push(gauge.clone());
+        }
+        gauge
+    }
+
+    pub fn register_worker(&self) -> Arc<WorkerStats> {
+        let worker = Arc::new(WorkerStats::default());
+        if let Ok(mut workers) = self.workers.lock() {
+            worker.set_id(workers.len());
+            workers.push(worker.clone());
+        }
+        worker
+    }
+
+    /// Marks the run as done, so the observers can stop
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    pub fn elapsed_secs(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    pub fn records_read(&self) -> u64 {
+        self.records_read.load(Ordering::Relaxed)
+    }
+
+    pub fn records_filtered(&self) -> u64 {
+        self.records_filtered.load(Ordering::Relaxed)
+    }
+
+    pub fn parse_errors(&self) -> u64 {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn amounts_normalized(&self) -> u64 {
+        self.amounts_normalized.load(Ordering::Relaxed)
+    }
+
+    pub fn input_errors(&self) -> u64 {
+        self.input_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn records_dispatched(&self) -> u64 {
+        self.records_dispatched.load(Ordering::Relaxed)
+    }
+
+    pub fn records_applied(&self) -> u64 {
+        self.records_applied.load(Ordering::Relaxed)
+    }
+
+    pub fn records_rejected(&self) -> u64 {
+        self.records_rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn records_replayed(&self) -> u64 {
+        self.records_replayed.load(Ordering::Relaxed)
+    }
+
+    pub fn records_overlapping(&self) -> u64 {
+        self.records_overlapping.load(Ordering::Relaxed)
+    }
+
+    pub fn clients_spilled(&self) -> u64 {
+        self.clients_spilled.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of all the registered queues as (name, depth, capacity)
+    pub fn queue_depths(&self) -> Vec<(String, usize, usize)> {
+        match self.queues.lock() {
+            Ok(queues) => queues
+                .iter()
+                .map(|q| (q.name().to_string(), q.depth(), q.capacity()))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// The latencies published so far by all the workers
+    pub fn latencies(&self) -> ApplyLatencies {
+        self.latencies
+            .lock()
+            .map(|latencies| latencies.clone())
+            .unwrap_or_default()
+    }
+
+    /// Merges the top accounts published by all the workers
+    pub fn top_accounts(&self) -> Vec<(ClientId, Decimal)> {
+        let mut top = Vec::new();
+        if let Ok(workers) = self.workers.lock() {
+            for worker in workers.iter() {
+                if let Ok(accounts) = worker.top_accounts.lock() {
+                    top.extend(accounts.iter().cloned());
+                }
+            }
+        }
+        top.sort_by_key(|(_, total)| Reverse(*total));
+        top.truncate(TOP_ACCOUNTS);
+        top
+    }
+
+    /// The statistics of all the workers, sorted by id
+    pub fn workers(&self) -> Vec<Arc<WorkerStats>> {
+        let mut workers = self
+            .workers
+            .lock()
+            .map(|workers| workers.clone())
+            .unwrap_or_default();
+        workers.sort_by_key(|worker| worker.id());
+        workers
+    }
+
+    /// Writes the records, accounts, throughput and queue wait of each worker, and warns when
+    /// a worker applied much more than its share of the records
+    pub fn write_worker_summary(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let workers = self.workers();
+        let total: u64 = workers.iter().map(|worker| worker.records()).sum();
+        let elapsed = self.elapsed_secs();
+        let share = |records: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                records as f64 * 100.0 / total as f64
+            }
+        };
+        writeln!(
+            writer,
+            "{:>6} | {:>12} | {:>7} | {:>10} | {:>12} | {:>14}",
+            "worker", "records", "share", "accounts", "records/s", "queue wait (s)"
+        )?;
+        writeln!(
+            writer,
+            "{}-+-{}-+-{}-+-{}-+-{}-+-{}",
+            "-".repeat(6),
+            "-".repeat(12),
+            "-".repeat(7),
+            "-".repeat(10),
+            "-".repeat(12),
+            "-".repeat(14)
+        )?;
+        for worker in &workers {
+            let wait = worker.queue_wait().as_secs_f64();
+            // the throughput while the worker had records to apply
+            let busy = elapsed - wait;
+            let rate = if busy > 0.0 {
+                worker.records() as f64 / busy
+            } else {
+                0.0
+            };
+            writeln!(
+                writer,
+                "{:>6} | {:>12} | {:>6.1}% | {:>10} | {:>12.0} | {:>14.3}",
+                worker.id(),
+                worker.records(),
+                share(worker.records()),
+                worker.accounts(),
+                rate,
+                wait
+            )?;
+        }
+        if workers.len() > 1 {
+            let fair = 100.0 / workers.len() as f64;
+            if let Some(busiest) = workers.iter().max_by_key(|worker| worker.records()) {
+                let busiest_share = share(busiest.records());
+                if busiest_share > fair * SKEW_FACTOR {
+                    writeln!(
+                        writer,
+                        "Worker {} applied {:.0}% of the records, its fair share is {:.0}%: the \
+                         client ids are clustered on its shard",
+                        busiest.id(),
+                        busiest_share,
+                        fair
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::records::TransactionId;
+
+    #[test]
+    fn test_top_accounts() {
+        let stats = PipelineStats::new();
+        let worker1 = stats.register_worker();
+        let worker2 = stats.register_worker();
+
+        let accounts: Vec<ClientAccount> = (1..30)
+            .map(|id| {
+                let mut account = ClientAccount::new(id);
+                account
+                    .deposit(id as TransactionId, Decimal::from(id))
+                    .unwrap();
+                account
+            })
+            .collect();
+
+        worker1.publish_top_accounts(accounts.iter().filter(|a| a.id() % 2 == 0));
+        worker2.publish_top_accounts(accounts.iter().filter(|a| a.id() % 2 == 1));
+
+        let top = stats.top_accounts();
+        assert_eq!(top.len(), TOP_ACCOUNTS);
+        assert_eq!(top[0], (29, dec!(29)));
+        assert_eq!(top[9], (20, dec!(20)));
+    }
+
+    #[test]
+    fn test_worker_summary() {
+        let stats = PipelineStats::new();
+        let workers: Vec<_> = (0..4).map(|_| stats.register_worker()).collect();
+        // registered out of order, listed by id
+        workers[0].set_id(3);
+        workers[3].set_id(0);
+        for (worker, records) in workers.iter().zip([100, 10, 10, 880]) {
+            worker.add_records(records);
+            worker.set_accounts(records as usize / 10);
+        }
+        workers[0].add_queue_wait(Duration::from_millis(1500));
+        let ids: Vec<_> = stats.workers().iter().map(|worker| worker.id()).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+
+        let mut summary = Vec::new();
+        stats.write_worker_summary(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        let lines: Vec<_> = summary.lines().collect();
+        assert!(lines[2].starts_with("     0 |          880 |   88.0% |         88 |"));
+        assert!(lines[5].ends_with("|          1.500"));
+        assert!(lines[6].starts_with("Worker 0 applied 88% of the records"));
+
+        // no warning when the records are spread evenly
+        let even = PipelineStats::new();
+        for _ in 0..4 {
+            even.register_worker().add_records(250);
+        }
+        let mut summary = Vec::new();
+        even.write_worker_summary(&mut summary).unwrap();
+        assert_eq!(String::from_utf8(summary).unwrap().lines().count(), 6);
+    }
+}
@@ -0,0 +1,76 @@
+/// Follows a single transaction id through the pipeline, for `--trace-tx`
+/// Every step of the records with that id is logged under the `paytoy::trace` target, from the
+/// input they were read from to the worker they were routed to and the outcome of applying
+/// them, so the story of one transaction can be told without the logs of all the others
+use log::*;
+
+use crate::records::{TransactionId, TransactionRecord};
+
+/// Target of the trace events, enabled on its own by the logger
+pub const TARGET: &str = "paytoy::trace";
+
+/// The traced transaction id
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct TxTrace {
+    tx: TransactionId,
+}
+
+impl TxTrace {
+    pub fn new(tx: TransactionId) -> Self {
+        Self { tx }
+    }
+
+    /// Checks if the record refers to the traced id
+    pub fn matches(&self, record: &TransactionRecord) -> bool {
+        record.tx == self.tx
+    }
+
+    /// Logs a step of a record, if it refers to the traced id
+    pub fn step(&self, record: &TransactionRecord, step: std::fmt::Arguments) {
+        if self.matches(record) {
+            info!(
+                target: TARGET, client = record.client, tx = record.tx;
+                "{} {} of client {}{}: {}",
+                record.tr_type, record.tx, record.client, location(record), step
+            );
+        }
+    }
+}
+
+/// Where the record was read, if the records are tagged
+fn location(record: &TransactionRecord) -> String {
+    match &record.source {
+        Some(source) => format!(" ({} line {})", source.input, source.line),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::records::{RecordSource, TransactionType};
+
+    use super::*;
+
+    #[test]
+    fn test_tx_trace() {
+        let mut record = TransactionRecord {
+            tr_type: TransactionType::Dispute,
+            client: 3,
+            tenant: None,
+            merchant: None,
+            tx: 42,
+            amount: None,
+            timestamp: None,
+            source: None,
+        };
+        let trace = TxTrace::new(42);
+        assert!(trace.matches(&record));
+        assert!(!TxTrace::new(41).matches(&record));
+
+        assert_eq!(location(&record), "");
+        record.source = RecordSource::tag(Some(&Arc::from("input.csv")), 7);
+        assert_eq!(location(&record), " (input.csv line 7)");
+    }
+}
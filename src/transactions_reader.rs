@@ -3,78 +3,291 @@
 /// such as reading from a non-CSV file and so on
 use std::{
     collections::HashMap,
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, Read},
     path::Path,
+    sync::Arc,
 };
 
 use anyhow::Context;
 use crossbeam_channel::{Receiver, Sender};
 use csv::{ByteRecord, ReaderBuilder, Trim};
 
-use crate::records::TransactionRecord;
+use crate::{
+    affinity::CoreList,
+    cancellation::CancellationToken,
+    dead_letter::{raw_line, DeadLetters},
+    input_encoding::{self, InputEncoding},
+    input_slice::InputSlice,
+    memory::{format_size, MemoryBudget},
+    profile::{Profile, Stage, StageTimer},
+    record_filter::RecordFilter,
+    record_parser::{AmountFormat, RecordParser},
+    records::{AccountKey, RecordSource, TransactionRecord},
+    stats::{PipelineStats, QueueGauge},
+};
 
 use log::*;
 
 /// A type that represents a stream of transactions arriving into the system
 /// Many channels (such as crossbeam) implement iterator interface, so can be used for multithreading
-pub type TransactionsStream = Box<dyn Iterator<Item = TransactionRecord>>;
+pub type TransactionsStream = Box<dyn Iterator<Item = TransactionRecord> + Send>;
 
 /// Trait to read CSV files into a `TransactionsStream`
-pub trait TransactionCSVReader {
+pub trait TransactionCSVReader: Sized {
     /// Read transactions from a CSV file
     /// Returns a vector with all the transactions nicely packet into structs
-    fn read_csv<P: AsRef<Path>>(self, path: P) -> anyhow::Result<TransactionsStream>;
+    fn read_csv<P: AsRef<Path>>(self, path: P) -> anyhow::Result<TransactionsStream> {
+        self.read_from(std::fs::File::open(path)?)
+    }
+
+    /// Read transactions from any byte stream, such as a decompressed or a remote file
+    fn read_from<R: Read + Send + 'static>(self, reader: R) -> anyhow::Result<TransactionsStream>;
+
+    /// Tag the records with their position in the `input`, at a small cost per record
+    fn with_source(self, input: Arc<str>) -> Self;
 }
 
 /// A single threaded bulk reader
 /// Reads and parses everything upfront and returns a stream to the records
-pub struct STBulkReader {}
+#[derive(Clone)]
+pub struct STBulkReader {
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+    dead_letters: Option<Arc<DeadLetters>>,
+    profile: Option<Arc<Profile>>,
+    encoding: InputEncoding,
+    amount_format: AmountFormat,
+    lenient_amounts: bool,
+    slice: InputSlice,
+    filter: RecordFilter,
+    cancellation: Option<CancellationToken>,
+}
 
 impl STBulkReader {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            stats: None,
+            source: None,
+            dead_letters: None,
+            profile: None,
+            encoding: InputEncoding::Utf8,
+            amount_format: AmountFormat::Plain,
+            lenient_amounts: false,
+            slice: InputSlice::default(),
+            filter: RecordFilter::new(),
+            cancellation: None,
+        }
+    }
+
+    /// Publish the number of read records to the run statistics
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Copy the lines that can't be parsed to the dead letters
+    /// The whole input is then read in memory before being parsed
+    pub fn with_dead_letters(mut self, dead_letters: Arc<DeadLetters>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Time the reading and the parsing, the input is parsed while it's read
+    /// so both count as parsing unless the input is read in memory first
+    pub fn with_profile(mut self, profile: Arc<Profile>) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// UTF-8 by default, see `input_encoding`
+    pub fn with_encoding(mut self, encoding: InputEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Plain amounts by default
+    pub fn with_amount_format(mut self, amount_format: AmountFormat) -> Self {
+        self.amount_format = amount_format;
+        self
+    }
+
+    /// Strip the currency symbols of the amounts, see `RecordParser::with_lenient_amounts`
+    pub fn with_lenient_amounts(mut self) -> Self {
+        self.lenient_amounts = true;
+        self
+    }
+
+    /// Only read a slice of the records, the whole input by default
+    pub fn with_slice(mut self, slice: InputSlice) -> Self {
+        self.slice = slice;
+        self
+    }
+
+    /// Leave out the records the filter doesn't keep, all of them are kept by default
+    pub fn with_filter(mut self, filter: RecordFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Stop reading once `token` is cancelled, the records read before are still streamed
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
     }
 }
 
 impl TransactionCSVReader for STBulkReader {
-    fn read_csv<P: AsRef<Path>>(self, path: P) -> anyhow::Result<TransactionsStream> {
-        let start_time = std::time::Instant::now();
+    fn read_from<R: Read + Send + 'static>(self, reader: R) -> anyhow::Result<TransactionsStream> {
+        let reader = input_encoding::decode(reader, self.encoding)?;
+        let (mut reader, skipped_lines) = self.slice.apply(reader)?;
+        match &self.dead_letters {
+            // the raw lines are sliced from the input
+            Some(_) => {
+                let mut data = Vec::new();
+                StageTimer::start(self.profile.as_ref(), Stage::Read)
+                    .time(|| reader.read_to_end(&mut data))?;
+                let mut timer = StageTimer::start(self.profile.as_ref(), Stage::Parse);
+                timer.time(|| self.read_records(data.as_slice(), Some(&data), skipped_lines))
+            }
+            None => {
+                let mut timer = StageTimer::start(self.profile.as_ref(), Stage::Parse);
+                timer.time(|| self.read_records(reader, None, skipped_lines))
+            }
+        }
+    }
+
+    fn with_source(mut self, input: Arc<str>) -> Self {
+        self.source = Some(input);
+        self
+    }
+}
+
+impl STBulkReader {
+    /// Parses all the records, `data` is the whole input if the dead letters are kept
+    /// The lines of the records are counted after the `skipped_lines` of a slice
+    fn read_records(
+        self,
+        reader: impl Read,
+        data: Option<&[u8]>,
+        skipped_lines: u64,
+    ) -> anyhow::Result<TransactionsStream> {
         info!("STBulkReader reading the transactions");
         let mut csv_reader = ReaderBuilder::new()
             .trim(Trim::All)
             .flexible(true)
-            .from_path(path)?;
+            .from_reader(reader);
 
         // Read as byte records, that should improve the performance without a lot of reallocations
         let mut raw_record = csv::ByteRecord::new();
-        let headers = csv_reader.byte_headers()?.clone();
+        let headers = csv_reader.byte_headers()?;
+        let parser = record_parser(headers, self.amount_format, self.lenient_amounts);
+        let header_end = csv_reader.position().byte() as usize;
 
         let mut transactions = Vec::new();
-        while csv_reader.read_byte_record(&mut raw_record)? {
-            let record = raw_record.deserialize::<TransactionRecord>(Some(&headers));
+        let mut parse_errors = 0;
+        let mut filtered = 0;
+        let mut dead_letters = Vec::new();
+        while !is_cancelled(self.cancellation.as_ref())
+            && csv_reader.read_byte_record(&mut raw_record)?
+        {
             // for simplicity, ignore transactions that cannot be parsed
-            if let Ok(record) = record {
-                transactions.push(record);
+            match parser.parse(&raw_record) {
+                Some(record) if !self.filter.keeps(&record) => filtered += 1,
+                Some(mut record) => {
+                    let line = raw_record
+                        .position()
+                        .map_or(0, |position| position.line() + skipped_lines);
+                    record.source = RecordSource::tag(self.source.as_ref(), line);
+                    transactions.push(record)
+                }
+                None => {
+                    parse_errors += 1;
+                    if let (Some(data), Some(position)) = (data, raw_record.position()) {
+                        let end = csv_reader.position().byte();
+                        dead_letters.push(raw_line(data, position.byte(), end));
+                    }
+                }
             }
         }
 
-        info!(
-            "Read {} records in {:?}. Throughput: {} millions/second",
-            transactions.len(),
-            start_time.elapsed(),
-            transactions.len() as f32 / (1000000.0 * start_time.elapsed().as_secs_f32())
-        );
+        if let (Some(writer), Some(data)) = (&self.dead_letters, data) {
+            writer.write_header(&data[..header_end])?;
+            writer.write_lines(dead_letters)?;
+        }
+
+        if let Some(stats) = &self.stats {
+            stats.add_read(transactions.len() as u64, parse_errors);
+            stats.add_filtered(filtered);
+            stats.add_normalized(parser.take_normalized());
+        }
 
         Ok(Box::new(transactions.into_iter()))
     }
 }
 
+const BLOCK_QUEUE_SIZE: usize = 1000;
+const PARSED_QUEUE_SIZE: usize = 1000;
+const REORDER_QUEUE_SIZE: usize = 100000;
+const SHARD_QUEUE_SIZE: usize = 1000;
+
+/// Block size of the inputs of unknown size, the streams: small so that a block is soon full
+const STREAM_BLOCK_SIZE: usize = 16 * 1024;
+const MIN_BLOCK_SIZE: usize = 32 * 1024;
+const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+/// The blocks of an input for each parser, enough for the parsers to stay evenly busy
+const BLOCKS_PER_PARSER: u64 = 256;
+
+/// The block size of the multithreaded reader for inputs of `input_size` bytes (unknown for
+/// the streams) parsed by `num_threads` threads: the huge files are read in bigger blocks,
+/// so there are less of them to send and reorder
+pub fn auto_block_size(input_size: Option<u64>, num_threads: usize) -> usize {
+    match input_size {
+        Some(size) => {
+            let block = size / (num_threads.max(1) as u64 * BLOCKS_PER_PARSER);
+            (block as usize)
+                .next_power_of_two()
+                .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+        }
+        None => STREAM_BLOCK_SIZE,
+    }
+}
+
+/// Id of a parsed block, its records, the raw lines that couldn't be parsed
+/// (only kept if there are dead letters) and its footprint in the memory budget
+type ParsedBlock = (u32, Vec<TransactionRecord>, Vec<Vec<u8>>, u64);
+
+/// Approximate memory taken by a block until its records are processed:
+/// the raw bytes, then the parsed records which are about twice as big
+fn block_footprint(len: usize) -> u64 {
+    3 * len as u64
+}
+
+fn is_cancelled(cancellation: Option<&CancellationToken>) -> bool {
+    cancellation.is_some_and(CancellationToken::is_cancelled)
+}
+
 /// A multithreaded reader
 /// Reads blocks of raw bytes from a file (sequentially)
 /// And then forwards those blocks to a thread pool for deserialization
+#[derive(Clone)]
 pub struct MTReader {
     num_threads: usize,
     block_size: usize,
+    stats: Option<Arc<PipelineStats>>,
+    source: Option<Arc<str>>,
+    dead_letters: Option<Arc<DeadLetters>>,
+    profile: Option<Arc<Profile>>,
+    memory: Option<Arc<MemoryBudget>>,
+    reader_cores: Option<CoreList>,
+    parser_cores: Option<CoreList>,
+    encoding: InputEncoding,
+    amount_format: AmountFormat,
+    lenient_amounts: bool,
+    slice: InputSlice,
+    /// The lines skipped before the slice, the first block starts after them
+    skipped_lines: u64,
+    filter: RecordFilter,
+    cancellation: Option<CancellationToken>,
 }
 
 impl MTReader {
@@ -82,126 +295,460 @@ impl MTReader {
         Self {
             num_threads: num_cpus::get(),
             block_size: 32 * 1024,
+            stats: None,
+            source: None,
+            dead_letters: None,
+            profile: None,
+            memory: None,
+            reader_cores: None,
+            parser_cores: None,
+            encoding: InputEncoding::Utf8,
+            amount_format: AmountFormat::Plain,
+            lenient_amounts: false,
+            slice: InputSlice::default(),
+            skipped_lines: 0,
+            filter: RecordFilter::new(),
+            cancellation: None,
         }
     }
 
+    /// Publish the number of read records and the queue depths to the run statistics
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
     pub fn with_threads(mut self, num_threads: usize) -> Self {
         self.num_threads = num_threads;
         self
     }
 
-    #[allow(dead_code)]
+    /// Copy the lines that can't be parsed to the dead letters
+    pub fn with_dead_letters(mut self, dead_letters: Arc<DeadLetters>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Time the reading of the blocks and their parsing
+    pub fn with_profile(mut self, profile: Arc<Profile>) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Hold the reading back while the blocks in flight don't fit in the memory budget
+    pub fn with_memory(mut self, memory: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Pin the reader thread and the reorder thread to these cores
+    pub fn with_reader_cores(mut self, cores: CoreList) -> Self {
+        self.reader_cores = Some(cores);
+        self
+    }
+
+    /// Pin the parser threads to these cores, in turn
+    pub fn with_parser_cores(mut self, cores: CoreList) -> Self {
+        self.parser_cores = Some(cores);
+        self
+    }
+
+    /// 32 KiB by default, see `auto_block_size`
     pub fn block_size(mut self, block_size: usize) -> Self {
         self.block_size = block_size;
         self
     }
+
+    /// UTF-8 by default, see `input_encoding`
+    pub fn with_encoding(mut self, encoding: InputEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Plain amounts by default
+    pub fn with_amount_format(mut self, amount_format: AmountFormat) -> Self {
+        self.amount_format = amount_format;
+        self
+    }
+
+    /// Strip the currency symbols of the amounts, see `RecordParser::with_lenient_amounts`
+    pub fn with_lenient_amounts(mut self) -> Self {
+        self.lenient_amounts = true;
+        self
+    }
+
+    /// Only read a slice of the records, the whole input by default
+    pub fn with_slice(mut self, slice: InputSlice) -> Self {
+        self.slice = slice;
+        self
+    }
+
+    /// Leave out the records the filter doesn't keep, all of them are kept by default
+    pub fn with_filter(mut self, filter: RecordFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Stop reading and parsing the blocks once `token` is cancelled, the records already
+    /// parsed are still streamed
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
 }
 
 impl TransactionCSVReader for MTReader {
-    fn read_csv<P: AsRef<Path>>(mut self, path: P) -> anyhow::Result<TransactionsStream> {
-        let mut file_reader =
-            BufReader::with_capacity(2 * self.block_size, std::fs::File::open(path)?);
-        let mut headers = vec![];
+    /// Local files are read ahead with io_uring, so the disk stays busy while parsing
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn read_csv<P: AsRef<Path>>(self, path: P) -> anyhow::Result<TransactionsStream> {
+        let file = crate::uring_input::open(std::fs::File::open(path)?)?;
+        self.read_from(file)
+    }
+
+    fn read_from<R: Read + Send + 'static>(
+        mut self,
+        reader: R,
+    ) -> anyhow::Result<TransactionsStream> {
+        let (file_reader, headers) = self.read_headers(reader)?;
+
+        let (parsed_tx, parsed_rx) = crossbeam_channel::bounded::<ParsedBlock>(PARSED_QUEUE_SIZE);
+
+        let (reorder_tx, reorder_rx) =
+            crossbeam_channel::bounded::<TransactionRecord>(REORDER_QUEUE_SIZE);
+        let (block_tx, block_rx) =
+            crossbeam_channel::bounded::<(u32, u64, Vec<u8>)>(BLOCK_QUEUE_SIZE);
+
+        let block_gauge = self.gauge("raw blocks", BLOCK_QUEUE_SIZE);
+        let parsed_gauge = self.gauge("parsed blocks", PARSED_QUEUE_SIZE);
+        let reorder_gauge = self.gauge("reordered records", REORDER_QUEUE_SIZE);
+
+        Self::start_reorder(
+            parsed_rx,
+            reorder_tx,
+            self.dead_letters.clone(),
+            self.memory.clone(),
+            reorder_gauge,
+            self.reader_cores.clone(),
+            self.cancellation.clone(),
+        );
+        self.start_dispatcher(headers, parsed_tx, block_rx, parsed_gauge)?;
+        self.start_reader(file_reader, block_tx, block_gauge)?;
+
+        Ok(Box::new(reorder_rx.into_iter()))
+    }
+
+    fn with_source(mut self, input: Arc<str>) -> Self {
+        self.source = Some(input);
+        self
+    }
+}
+
+impl MTReader {
+    /// Parses the blocks into per-shard queues, for the fused pipeline: there's a stream of
+    /// records for each of the `shards`, with the records of its accounts in the order of the
+    /// input. The blocks are put back in order by the threads consuming the streams, there's
+    /// no reorder thread in between
+    pub fn read_shards<R: Read + Send + 'static>(
+        mut self,
+        reader: R,
+        shards: usize,
+    ) -> anyhow::Result<Vec<TransactionsStream>> {
+        let (file_reader, headers) = self.read_headers(reader)?;
+        let (block_tx, block_rx) =
+            crossbeam_channel::bounded::<(u32, u64, Vec<u8>)>(BLOCK_QUEUE_SIZE);
+        let block_gauge = self.gauge("raw blocks", BLOCK_QUEUE_SIZE);
+
+        let mut queues = Vec::with_capacity(shards);
+        let mut streams: Vec<TransactionsStream> = Vec::with_capacity(shards);
+        for shard in 0..shards {
+            let (shard_tx, shard_rx) = crossbeam_channel::bounded::<ParsedBlock>(SHARD_QUEUE_SIZE);
+            let gauge = self.gauge(format!("shard {}", shard), SHARD_QUEUE_SIZE);
+            queues.push((shard_tx, gauge));
+            streams.push(Box::new(ShardStream {
+                parsed_rx: shard_rx,
+                waiting_for: 1,
+                queue: HashMap::new(),
+                current: Vec::new().into_iter(),
+                // the first shard writes the dead letters and releases the memory of the blocks
+                dead_letters: self.dead_letters.clone().filter(|_| shard == 0),
+                memory: self.memory.clone().filter(|_| shard == 0),
+                cancellation: self.cancellation.clone(),
+            }));
+        }
+
+        self.start_parsers(
+            headers,
+            block_rx,
+            move |block_id, transactions, bad_lines, footprint| {
+                let mut batches: Vec<Vec<TransactionRecord>> = std::iter::repeat_with(Vec::new)
+                    .take(queues.len())
+                    .collect();
+                for record in transactions {
+                    batches[AccountKey::of(&record).shard(queues.len())].push(record);
+                }
+                // every shard gets every block, even empty, so it knows which block comes next
+                let mut first = Some((bad_lines, footprint));
+                batches
+                    .into_iter()
+                    .zip(&queues)
+                    .all(|(batch, (shard_tx, gauge))| {
+                        let (bad_lines, footprint) = first.take().unwrap_or_default();
+                        let sent = shard_tx
+                            .send((block_id, batch, bad_lines, footprint))
+                            .is_ok();
+                        if let Some(gauge) = gauge {
+                            gauge.set(shard_tx.len());
+                        }
+                        sent
+                    })
+            },
+        )?;
+        self.start_reader(file_reader, block_tx, block_gauge)?;
+
+        Ok(streams)
+    }
+
+    /// Reads the header line, the columns can be in any order
+    fn read_headers<R: Read + Send + 'static>(
+        &mut self,
+        reader: R,
+    ) -> anyhow::Result<(BufReader<Box<dyn Read + Send>>, ByteRecord)> {
+        if let Some(profile) = &self.profile {
+            profile.set("block size", format_size(self.block_size as u64));
+        }
+        let reader = input_encoding::decode(reader, self.encoding)?;
+        let (reader, skipped_lines) = self.slice.apply(reader)?;
+        self.skipped_lines = skipped_lines;
+        let mut file_reader = BufReader::with_capacity(2 * self.block_size, reader);
+        let mut header_line = vec![];
 
         // read first row
         file_reader
-            .read_until(b'\n', &mut headers)
+            .read_until(b'\n', &mut header_line)
             .with_context(|| "Failed to read the headers")?;
+        if let Some(dead_letters) = &self.dead_letters {
+            dead_letters.write_header(&header_line)?;
+        }
+        // the columns can be in any order, and an optional timestamp column can be present
+        let mut headers = ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(header_line.as_slice())
+            .byte_records()
+            .next()
+            .transpose()?
+            .unwrap_or_default();
+        headers.trim();
+        Ok((file_reader, headers))
+    }
 
-        let (parsed_tx, parsed_rx) =
-            crossbeam_channel::bounded::<(u32, Vec<TransactionRecord>)>(1000);
-
-        let (reorder_tx, reorder_rx) = crossbeam_channel::bounded::<TransactionRecord>(100000);
-        let (block_tx, block_rx) = crossbeam_channel::bounded::<(u32, Vec<u8>)>(1000);
-
-        Self::start_reorder(parsed_rx, reorder_tx);
-        Self::start_dispatcher(self.num_threads, parsed_tx, block_rx);
+    fn gauge(&self, name: impl Into<String>, capacity: usize) -> Option<Arc<QueueGauge>> {
+        self.stats
+            .as_ref()
+            .map(|stats| stats.register_queue(name, capacity))
+    }
 
-        // Read blocks of transactions
-        let _ = std::thread::spawn(move || {
+    /// Read blocks of transactions
+    fn start_reader<R: Read + Send + 'static>(
+        mut self,
+        mut file_reader: BufReader<R>,
+        block_tx: Sender<(u32, u64, Vec<u8>)>,
+        block_gauge: Option<Arc<QueueGauge>>,
+    ) -> std::io::Result<()> {
+        let reader_thread = std::thread::Builder::new().name("reader".to_string());
+        reader_thread.spawn(move || {
+            if let Some(cores) = &self.reader_cores {
+                cores.pin(0);
+            }
+            let mut timer = StageTimer::start(self.profile.as_ref(), Stage::Read);
             let mut block_id = 0;
-            while let Some(block) = self.read_block(&mut file_reader) {
+            // the line the block starts at, only counted if the records are tagged
+            let mut line = 2 + self.skipped_lines;
+            while !is_cancelled(self.cancellation.as_ref()) {
+                let block = match timer.time(|| self.read_block(&mut file_reader)) {
+                    Some(block) => block,
+                    None => break,
+                };
                 block_id += 1;
+                let first_line = line;
+                if self.source.is_some() {
+                    line += block.iter().filter(|&&byte| byte == b'\n').count() as u64;
+                }
+                if let Some(memory) = &self.memory {
+                    memory.reserve_queued(block_footprint(block.len()));
+                }
                 // send them to the thread pool dispatcher
-                if block_tx.send((block_id, block)).is_err() {
+                if block_tx.send((block_id, first_line, block)).is_err() {
                     break;
                 }
+                if let Some(gauge) = &block_gauge {
+                    gauge.set(block_tx.len());
+                }
                 // the parsed blocks may arrive out of order, so we need to perform a reordering
             }
-        });
-
-        Ok(Box::new(reorder_rx.into_iter()))
+        })?;
+        Ok(())
     }
-}
 
-impl MTReader {
     /// Dispatch a CSV raw block for parsing
     fn start_dispatcher(
-        num_threads: usize,
-        parsed_tx: Sender<(u32, Vec<TransactionRecord>)>,
-        block_rx: Receiver<(u32, Vec<u8>)>,
-    ) {
-        for _ in 0..num_threads {
+        &self,
+        headers: ByteRecord,
+        parsed_tx: Sender<ParsedBlock>,
+        block_rx: Receiver<(u32, u64, Vec<u8>)>,
+        parsed_gauge: Option<Arc<QueueGauge>>,
+    ) -> std::io::Result<()> {
+        self.start_parsers(
+            headers,
+            block_rx,
+            move |block_id, transactions, bad_lines, footprint| {
+                // Will ignore the channel closed for now
+                let _ = parsed_tx.send((block_id, transactions, bad_lines, footprint));
+                if let Some(gauge) = &parsed_gauge {
+                    gauge.set(parsed_tx.len());
+                }
+                true
+            },
+        )
+    }
+
+    /// Starts the parser threads, `send` forwards each parsed block and returns false once
+    /// nothing consumes them anymore
+    fn start_parsers(
+        &self,
+        headers: ByteRecord,
+        block_rx: Receiver<(u32, u64, Vec<u8>)>,
+        send: impl Fn(u32, Vec<TransactionRecord>, Vec<Vec<u8>>, u64) -> bool + Clone + Send + 'static,
+    ) -> std::io::Result<()> {
+        for parser_id in 0..self.num_threads {
             let block_rx = block_rx.clone();
-            let parsed_tx = parsed_tx.clone();
-            // For now consider that the headers if read then they're OK and equal to below
-            let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
-            std::thread::spawn(move || {
-                while let Ok((block_id, block)) = block_rx.recv() {
-                    let mut csv_reader = ReaderBuilder::new()
-                        .trim(Trim::All)
-                        .has_headers(true)
-                        .flexible(true)
-                        .from_reader(block.as_slice());
-
-                    let mut raw_record = csv::ByteRecord::new();
-                    // Looks like I have found a bug in CSV library
-                    // It doesn't trim the first row if has_headers = false and the headers are supplied to deserialize
-                    // I'll open a bug on github
-                    csv_reader.set_byte_headers(headers.clone());
-                    let mut transactions = Vec::new();
-                    while let Ok(true) = csv_reader.read_byte_record(&mut raw_record) {
-                        let record = raw_record.deserialize::<TransactionRecord>(Some(&headers));
-                        if let Ok(record) = record {
-                            transactions.push(record);
+            let send = send.clone();
+            let stats = self.stats.clone();
+            let headers = headers.clone();
+            let source = self.source.clone();
+            let dead_letters = self.dead_letters.is_some();
+            let profile = self.profile.clone();
+            let cores = self.parser_cores.clone();
+            let parser = record_parser(&headers, self.amount_format, self.lenient_amounts);
+            let filter = self.filter.clone();
+            let cancellation = self.cancellation.clone();
+            let parser_thread = std::thread::Builder::new().name(format!("parser {}", parser_id));
+            parser_thread.spawn(move || {
+                if let Some(cores) = cores {
+                    cores.pin(parser_id);
+                }
+                let mut timer = StageTimer::start(profile.as_ref(), Stage::Parse);
+                while let Ok((block_id, first_line, block)) = block_rx.recv() {
+                    // the blocks queued for a cancelled run are sent empty, the blocks are
+                    // put back in order by their ids so none can be left out
+                    if is_cancelled(cancellation.as_ref()) {
+                        let footprint = block_footprint(block.len());
+                        if !send(block_id, Vec::new(), Vec::new(), footprint) {
+                            break;
                         }
+                        continue;
+                    }
+                    let (transactions, parse_errors, filtered, bad_lines) = timer.time(|| {
+                        let source = source.as_ref();
+                        parse_block(
+                            &headers,
+                            &parser,
+                            &filter,
+                            &block,
+                            first_line,
+                            source,
+                            dead_letters,
+                        )
+                    });
+                    if let Some(stats) = &stats {
+                        stats.add_read(transactions.len() as u64, parse_errors);
+                        stats.add_filtered(filtered);
+                        stats.add_normalized(parser.take_normalized());
+                    }
+                    let footprint = block_footprint(block.len());
+                    if !send(block_id, transactions, bad_lines, footprint) {
+                        break;
                     }
-                    // Will ignore the channel closed for now
-                    let _ = parsed_tx.send((block_id, transactions));
                 }
-            });
+            })?;
         }
+        Ok(())
     }
 
     /// Reorders transaction blocks from different thread
     /// So in the end everything is chronologically in order
+    /// The dead letters are written in order too, before the records of their block are sent
+    /// Once cancelled the stream ends before the next block, so no block is left out of it
+    /// but the last ones
     fn start_reorder(
-        parsed_rx: Receiver<(u32, Vec<TransactionRecord>)>,
+        parsed_rx: Receiver<ParsedBlock>,
         reorder_tx: Sender<TransactionRecord>,
+        dead_letters: Option<Arc<DeadLetters>>,
+        memory: Option<Arc<MemoryBudget>>,
+        reorder_gauge: Option<Arc<QueueGauge>>,
+        cores: Option<CoreList>,
+        cancellation: Option<CancellationToken>,
     ) {
+        let budget = memory.clone();
+        let send = move |(transactions, bad_lines, footprint): (
+            Vec<TransactionRecord>,
+            Vec<Vec<u8>>,
+            u64,
+        )| {
+            if let Some(dead_letters) = &dead_letters {
+                if let Err(err) = dead_letters.write_lines(bad_lines.iter().map(Vec::as_slice)) {
+                    error!("Failed to write the dead letters: {:?}", err);
+                }
+            }
+            let sent = transactions
+                .into_iter()
+                .all(|record| reorder_tx.send(record).is_ok());
+            if let Some(gauge) = &reorder_gauge {
+                gauge.set(reorder_tx.len());
+            }
+            // the records are in the bounded queues of the account manager now
+            if let Some(memory) = &memory {
+                memory.release_queued(footprint);
+            }
+            sent
+        };
+
         // Ignore the join handle, since the lifetime of the thread is tied to the lifetime of the input and output channels
-        let _ = std::thread::spawn(move || {
+        let reorder_thread = std::thread::Builder::new().name("reorder".to_string());
+        let _ = reorder_thread.spawn(move || {
+            // the next core of the list after the reader's, if there's one
+            if let Some(cores) = cores {
+                cores.pin(1);
+            }
             let mut waiting_for = 1;
             let mut queue = HashMap::new();
-            while let Ok(block) = parsed_rx.recv() {
-                if block.0 == waiting_for {
-                    for record in block.1 {
-                        if reorder_tx.send(record).is_err() {
-                            return;
-                        };
-                    }
-                    waiting_for += 1;
+            while let Ok((block_id, transactions, bad_lines, footprint)) = parsed_rx.recv() {
+                if block_id == waiting_for {
+                    queue.insert(block_id, (transactions, bad_lines, footprint));
                     // Clear backlog
-                    while let Some(transactions) = queue.remove(&waiting_for) {
-                        for record in transactions {
-                            if reorder_tx.send(record).is_err() {
-                                return;
-                            };
+                    while let Some(block) = queue.remove(&waiting_for) {
+                        if is_cancelled(cancellation.as_ref()) {
+                            queue.insert(waiting_for, block);
+                            break;
+                        }
+                        if !send(block) {
+                            return;
                         }
                         waiting_for += 1;
                     }
-                } else if block.0 > waiting_for {
-                    queue.insert(block.0, block.1);
+                } else if block_id > waiting_for {
+                    queue.insert(block_id, (transactions, bad_lines, footprint));
+                }
+                if is_cancelled(cancellation.as_ref()) {
+                    break;
+                }
+            }
+            // the stream ends, the blocks still coming are only released until the reader
+            // and the parsers stop
+            drop(send);
+            let queued = queue.into_values().map(|(_, _, footprint)| footprint);
+            for footprint in queued.chain(parsed_rx.iter().map(|(_, _, _, footprint)| footprint)) {
+                if let Some(memory) = &budget {
+                    memory.release_queued(footprint);
                 }
             }
         });
@@ -221,17 +768,150 @@ impl MTReader {
                 let _ = reader.read_until(b'\n', &mut block);
                 Some(block)
             }
-            Err(_) => None,
+            Err(err) => {
+                error!("Failed to read the input: {:?}", err);
+                if let Some(stats) = &self.stats {
+                    stats.add_input_error();
+                }
+                None
+            }
+        }
+    }
+}
+
+fn record_parser(headers: &ByteRecord, amount_format: AmountFormat, lenient: bool) -> RecordParser {
+    let parser = RecordParser::new(headers).with_amount_format(amount_format);
+    if lenient {
+        parser.with_lenient_amounts()
+    } else {
+        parser
+    }
+}
+
+/// Parses a block of CSV lines starting at the line `first_line` of the input
+/// Returns the records kept by the filter, the number of parse errors and of records left
+/// out, and the lines that couldn't be parsed (only kept if `keep_bad_lines`)
+fn parse_block(
+    headers: &ByteRecord,
+    parser: &RecordParser,
+    filter: &RecordFilter,
+    block: &[u8],
+    first_line: u64,
+    source: Option<&Arc<str>>,
+    keep_bad_lines: bool,
+) -> (Vec<TransactionRecord>, u64, u64, Vec<Vec<u8>>) {
+    let mut csv_reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(block);
+
+    let mut raw_record = csv::ByteRecord::new();
+    // Looks like I have found a bug in CSV library
+    // It doesn't trim the first row if has_headers = false and the headers are supplied to deserialize
+    // I'll open a bug on github
+    csv_reader.set_byte_headers(headers.clone());
+    let mut transactions = Vec::new();
+    let mut parse_errors = 0;
+    let mut filtered = 0;
+    let mut bad_lines = Vec::new();
+    while let Ok(true) = csv_reader.read_byte_record(&mut raw_record) {
+        match parser.parse(&raw_record) {
+            Some(record) if !filter.keeps(&record) => filtered += 1,
+            Some(mut record) => {
+                let line = raw_record
+                    .position()
+                    .map_or(0, |position| first_line + position.line() - 1);
+                record.source = RecordSource::tag(source, line);
+                transactions.push(record)
+            }
+            None => {
+                parse_errors += 1;
+                if let (true, Some(position)) = (keep_bad_lines, raw_record.position()) {
+                    let end = csv_reader.position().byte();
+                    bad_lines.push(raw_line(block, position.byte(), end).to_vec());
+                }
+            }
+        }
+    }
+    (transactions, parse_errors, filtered, bad_lines)
+}
+
+/// The records of a shard of the fused pipeline, in the order of the input
+/// The parsed blocks are reordered by the thread consuming the stream
+struct ShardStream {
+    parsed_rx: Receiver<ParsedBlock>,
+    waiting_for: u32,
+    /// The blocks parsed before the one waited for
+    queue: HashMap<u32, ParsedBlock>,
+    /// The records of the current block
+    current: std::vec::IntoIter<TransactionRecord>,
+    dead_letters: Option<Arc<DeadLetters>>,
+    memory: Option<Arc<MemoryBudget>>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl ShardStream {
+    /// Releases the blocks of a cancelled run until the reader and the parsers stop
+    fn drain(&mut self) {
+        let queued = self.queue.drain().map(|(_, block)| block);
+        for (_, _, _, footprint) in queued.chain(self.parsed_rx.iter()) {
+            if let Some(memory) = &self.memory {
+                memory.release_queued(footprint);
+            }
+        }
+    }
+}
+
+impl Iterator for ShardStream {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        loop {
+            if let Some(record) = self.current.next() {
+                return Some(record);
+            }
+            // the stream ends before the next block, so no block is left out of it
+            if is_cancelled(self.cancellation.as_ref()) {
+                self.drain();
+                return None;
+            }
+            let block = match self.queue.remove(&self.waiting_for) {
+                Some(block) => block,
+                None => {
+                    let block = self.parsed_rx.recv().ok()?;
+                    if block.0 != self.waiting_for {
+                        self.queue.insert(block.0, block);
+                        continue;
+                    }
+                    block
+                }
+            };
+            self.waiting_for += 1;
+            let (_, transactions, bad_lines, footprint) = block;
+            if let Some(dead_letters) = &self.dead_letters {
+                if let Err(err) = dead_letters.write_lines(bad_lines.iter().map(Vec::as_slice)) {
+                    error!("Failed to write the dead letters: {:?}", err);
+                }
+            }
+            if let Some(memory) = &self.memory {
+                memory.release_queued(footprint);
+            }
+            self.current = transactions.into_iter();
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
 
     use super::*;
-    use crate::records::TransactionType;
+    use crate::{
+        reader_conformance::ReaderConformance,
+        records::{wide_id, TransactionId, TransactionType},
+    };
 
     #[test]
     fn test_no_file_exists() {
@@ -240,7 +920,7 @@ mod tests {
     }
 
     fn test_transaction_reader(reader: impl TransactionCSVReader, path: &str) {
-        let mut transactions = reader.read_csv(&path).expect("Test file is not found");
+        let mut transactions = reader.read_csv(path).expect("Test file is not found");
 
         // Validate a few fields to give us enough confidence that parsing is successful
         let trans = transactions.next().unwrap();
@@ -252,7 +932,7 @@ mod tests {
         assert_eq!(trans.tx, 5);
         assert_eq!(trans.amount, Some(dec!(9.0)));
 
-        let trans = transactions.skip(2).next().unwrap();
+        let trans = transactions.nth(2).unwrap();
         assert_eq!(trans.tr_type, TransactionType::ChargeBack);
         assert_eq!(trans.amount, None);
     }
@@ -270,6 +950,14 @@ mod tests {
         test_transaction_reader(reader, "tests/data/test_serde.csv");
     }
 
+    #[test]
+    fn test_readers_conformance() {
+        ReaderConformance::new(STBulkReader::new).check_all();
+        ReaderConformance::new(MTReader::new).check_all();
+        // the rows span several blocks
+        ReaderConformance::new(|| MTReader::new().with_threads(3).block_size(64)).check_all();
+    }
+
     #[test]
     fn test_mt_reader_transaction_reader_big() {
         let reader = MTReader::new();
@@ -281,4 +969,311 @@ mod tests {
         }
         assert!(transactions.next().is_none());
     }
+
+    #[test]
+    fn test_cancellation() {
+        let path = "tests/data/test_mt_reader.csv";
+        let token = CancellationToken::new();
+        token.cancel();
+        let st = STBulkReader::new().with_cancellation(token.clone());
+        assert_eq!(st.read_csv(path).unwrap().count(), 0);
+        let mt = MTReader::new().with_cancellation(token);
+        assert_eq!(mt.read_csv(path).unwrap().count(), 0);
+
+        // cancelled while reading: the records parsed before still come in order, and the
+        // blocks left out don't hold the stream back
+        let token = CancellationToken::new();
+        let transactions = MTReader::new()
+            .with_threads(3)
+            .block_size(64)
+            .with_cancellation(token.clone())
+            .read_csv(path)
+            .unwrap();
+        let read: Vec<_> = transactions
+            .inspect(|record| {
+                if record.tx == 100 {
+                    token.cancel();
+                }
+            })
+            .map(|record| record.tx)
+            .collect();
+        assert!(read.len() >= 100);
+        assert!(read.iter().zip(1..).all(|(&tx, expected)| tx == expected));
+    }
+
+    #[test]
+    fn test_auto_block_size() {
+        assert_eq!(auto_block_size(None, 4), 16 * 1024);
+        assert_eq!(auto_block_size(Some(1000), 4), 32 * 1024);
+        // 16 GiB on 8 parsers, 2048 blocks of 8 MiB, capped
+        assert_eq!(auto_block_size(Some(16 << 30), 8), 4 << 20);
+        // 600 MB on 4 parsers, rounded up to a power of two
+        assert_eq!(auto_block_size(Some(600_000_000), 4), 1 << 20);
+        assert_eq!(auto_block_size(Some(600_000_000), 16), 256 << 10);
+
+        // the records are the same whatever the block size
+        for block_size in [auto_block_size(None, 2), auto_block_size(Some(1 << 30), 2)] {
+            let transactions = MTReader::new()
+                .block_size(block_size)
+                .read_csv("tests/data/test_mt_reader.csv")
+                .unwrap();
+            assert!(transactions.map(|record| record.tx).eq(1..20001));
+        }
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let input = std::env::temp_dir().join("paytoy_test_dead_letters_input.csv");
+        let mut data = String::from("type, client, tx, amount\n");
+        for tx in 1..=2000 {
+            data += &format!("deposit, 1, {}, 1.0\n", tx);
+            if tx % 500 == 0 {
+                data += &format!("deposit, x, {},  1.0\r\n", tx);
+            }
+        }
+        data += "bogus, 1, 2001, \"1.0\"";
+        std::fs::write(&input, data).unwrap();
+
+        let expected = "type, client, tx, amount\n\
+                        deposit, x, 500,  1.0\r\n\
+                        deposit, x, 1000,  1.0\r\n\
+                        deposit, x, 1500,  1.0\r\n\
+                        deposit, x, 2000,  1.0\r\n\
+                        bogus, 1, 2001, \"1.0\"\n";
+
+        let output = std::env::temp_dir().join("paytoy_test_dead_letters_st.csv");
+        let dead_letters = Arc::new(DeadLetters::create(&output).unwrap());
+        let records = STBulkReader::new()
+            .with_dead_letters(dead_letters.clone())
+            .read_csv(&input)
+            .unwrap();
+        assert_eq!(records.count(), 2000);
+        assert_eq!(dead_letters.lines(), 5);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), expected);
+
+        // small blocks, so the lines come from many of them
+        let output = std::env::temp_dir().join("paytoy_test_dead_letters_mt.csv");
+        let dead_letters = Arc::new(DeadLetters::create(&output).unwrap());
+        let records = MTReader::new()
+            .block_size(1024)
+            .with_dead_letters(dead_letters)
+            .read_csv(&input)
+            .unwrap();
+        assert_eq!(records.count(), 2000);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_tagged_lines() {
+        let path = "tests/data/test_mt_reader.csv";
+        let lines = |reader: Box<dyn Iterator<Item = TransactionRecord> + Send>| {
+            reader
+                .map(|record| record.source.unwrap().line)
+                .collect::<Vec<u64>>()
+        };
+        let st_lines = lines(
+            STBulkReader::new()
+                .with_source("test".into())
+                .read_csv(path)
+                .unwrap(),
+        );
+        // small blocks, so the lines are counted over many of them
+        let mt_lines = lines(
+            MTReader::new()
+                .block_size(1024)
+                .with_source("test".into())
+                .read_csv(path)
+                .unwrap(),
+        );
+
+        assert_eq!(st_lines.first(), Some(&2));
+        assert_eq!(st_lines.len(), 20000);
+        assert_eq!(st_lines, mt_lines);
+    }
+
+    #[test]
+    fn test_tenant_column() {
+        let path = "tests/data/test_tenants.csv";
+        let tenants = |reader: TransactionsStream| {
+            reader
+                .map(|record| record.tenant)
+                .collect::<Vec<Option<u16>>>()
+        };
+        let expected = vec![Some(1), Some(2), None, Some(2)];
+        assert_eq!(
+            tenants(STBulkReader::new().read_csv(path).unwrap()),
+            expected
+        );
+        assert_eq!(tenants(MTReader::new().read_csv(path).unwrap()), expected);
+    }
+
+    #[test]
+    fn test_spreadsheet_exports() {
+        // an accented name in a column that isn't read, the way Excel writes them
+        let lines = |bom: &[u8], line_end: &[u8], name: &[u8]| {
+            let mut data = bom.to_vec();
+            data.extend_from_slice(b"type,client,tx,amount,name");
+            for tx in 1..=2000 {
+                data.extend_from_slice(line_end);
+                data.extend_from_slice(format!("deposit,{},{},1.5,", tx % 7, tx).as_bytes());
+                data.extend_from_slice(name);
+            }
+            data.extend_from_slice(line_end);
+            data
+        };
+        let txs = |reader: TransactionsStream| reader.map(|record| record.tx).collect::<Vec<_>>();
+        let expected: Vec<TransactionId> = (1..=2000).collect();
+
+        for (data, encoding) in [
+            (
+                lines(b"\xef\xbb\xbf", b"\r\n", "René".as_bytes()),
+                InputEncoding::Utf8,
+            ),
+            (
+                lines(b"\xef\xbb\xbf", b"\r", "René".as_bytes()),
+                InputEncoding::Utf8,
+            ),
+            (lines(b"", b"\r", b"Ren\xe9"), InputEncoding::Latin1),
+        ] {
+            let st = STBulkReader::new()
+                .with_encoding(encoding)
+                .read_from(std::io::Cursor::new(data.clone()))
+                .unwrap();
+            assert_eq!(txs(st), expected);
+            let mt = MTReader::new()
+                .with_threads(3)
+                .block_size(1024)
+                .with_encoding(encoding)
+                .read_from(std::io::Cursor::new(data))
+                .unwrap();
+            assert_eq!(txs(mt), expected);
+        }
+    }
+
+    #[test]
+    fn test_amount_format() {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,\"1.234,56\"\n\
+                    deposit,1,2,\"1.5\"\n\
+                    dispute,1,1,\n";
+        let amounts = |reader: TransactionsStream| {
+            reader
+                .map(|record| record.amount)
+                .collect::<Vec<Option<Decimal>>>()
+        };
+        let expected = vec![Some(dec!(1234.56)), None];
+        let st = STBulkReader::new()
+            .with_amount_format(AmountFormat::DecimalComma)
+            .read_from(data.as_bytes())
+            .unwrap();
+        assert_eq!(amounts(st), expected);
+        let mt = MTReader::new()
+            .with_amount_format(AmountFormat::DecimalComma)
+            .read_from(data.as_bytes())
+            .unwrap();
+        assert_eq!(amounts(mt), expected);
+    }
+
+    #[test]
+    fn test_lenient_amounts() {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,$12.50\n\
+                    deposit,1,2,7 €\n\
+                    deposit,1,3,1.5\n\
+                    deposit,1,4,5 USD\n";
+        let expected = vec![Some(dec!(12.50)), Some(dec!(7)), Some(dec!(1.5))];
+        let amounts = |reader: TransactionsStream| {
+            reader
+                .map(|record| record.amount)
+                .collect::<Vec<Option<Decimal>>>()
+        };
+        let stats = Arc::new(PipelineStats::new());
+        let st = STBulkReader::new()
+            .with_lenient_amounts()
+            .with_stats(stats.clone())
+            .read_from(data.as_bytes())
+            .unwrap();
+        assert_eq!(amounts(st), expected);
+        assert_eq!(stats.amounts_normalized(), 2);
+        assert_eq!(stats.parse_errors(), 1);
+
+        let stats = Arc::new(PipelineStats::new());
+        let mt = MTReader::new()
+            .with_lenient_amounts()
+            .with_stats(stats.clone())
+            .read_from(data.as_bytes())
+            .unwrap();
+        assert_eq!(amounts(mt), expected);
+        assert_eq!(stats.amounts_normalized(), 2);
+        assert_eq!(stats.parse_errors(), 1);
+    }
+
+    #[test]
+    fn test_slice() {
+        let data: String = std::iter::once("type,client,tx,amount\n".to_string())
+            .chain((1..=100).map(|tx| format!("deposit,1,{},1.0\n", tx)))
+            .collect();
+        // the 10th record is cut, then 5 are skipped
+        let slice = InputSlice {
+            start_offset: data.find("deposit,1,10,").unwrap() as u64 + 3,
+            skip_records: 5,
+            limit: Some(20),
+        };
+        let records = |reader: TransactionsStream| {
+            reader
+                .map(|record| (record.tx, record.source.unwrap().line))
+                .collect::<Vec<(TransactionId, u64)>>()
+        };
+        // the header is the line 1, the record 16 the line 17
+        let expected: Vec<(TransactionId, u64)> =
+            (16..36).map(|tx| (tx, wide_id(tx) + 1)).collect();
+        let st = STBulkReader::new()
+            .with_slice(slice)
+            .with_source("test".into())
+            .read_from(std::io::Cursor::new(data.clone()))
+            .unwrap();
+        assert_eq!(records(st), expected);
+        let mt = MTReader::new()
+            .with_slice(slice)
+            .block_size(64)
+            .with_source("test".into())
+            .read_from(std::io::Cursor::new(data))
+            .unwrap();
+        assert_eq!(records(mt), expected);
+    }
+
+    #[test]
+    fn test_filter() {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,1.0\n\
+                    deposit,5,2,1.0\n\
+                    withdrawal,2,3,1.0\n\
+                    dispute,2,3,\n\
+                    deposit,3,4,1.0\n";
+        let filter = RecordFilter::new()
+            .with_types(vec![TransactionType::Deposit, TransactionType::Withdrawal])
+            .with_clients(1..=3);
+        let txs = |reader: TransactionsStream| {
+            reader
+                .map(|record| record.tx)
+                .collect::<Vec<TransactionId>>()
+        };
+        let stats = Arc::new(PipelineStats::new());
+        let st = STBulkReader::new()
+            .with_filter(filter.clone())
+            .with_stats(stats.clone())
+            .read_from(data.as_bytes())
+            .unwrap();
+        assert_eq!(txs(st), vec![1, 3, 4]);
+        assert_eq!(stats.records_filtered(), 2);
+        let stats = Arc::new(PipelineStats::new());
+        let mt = MTReader::new()
+            .with_filter(filter)
+            .with_stats(stats.clone())
+            .read_from(data.as_bytes())
+            .unwrap();
+        assert_eq!(txs(mt), vec![1, 3, 4]);
+        assert_eq!(stats.records_filtered(), 2);
+        assert_eq!(stats.records_read(), 3);
+    }
 }
@@ -0,0 +1,301 @@
+/// The history of the transactions of an account, packed for the replays of hundreds of
+/// millions of deposits
+/// A transaction that isn't disputed takes a single `u64`: its amount in minor units (a
+/// ten-thousandth) with two flags in the low bits. The disputed transactions and the amounts
+/// that can't be packed (more than 4 decimal places, trailing zeros to keep, or too large)
+/// spill whole to a map of their own, they're few. The accounts with a few transactions keep
+/// them in a vector, searched in order, and only switch to a hashmap once it grows
+use std::convert::TryFrom;
+
+use hashbrown::HashMap;
+use rust_decimal::Decimal;
+
+use crate::{client_account::DisputeSlice, records::TransactionId};
+
+/// Decimal places of the packed amounts
+const MINOR_SCALE: u32 = 4;
+/// Transactions kept in a vector before switching to a hashmap
+const SMALL_HISTORY: usize = 8;
+/// The transaction is disputed, its entry is in the spilled map
+const DISPUTED: u64 = 0b01;
+/// The amount can't be packed, the entry is in the spilled map
+const WIDE: u64 = 0b10;
+const FLAGS: u32 = 2;
+
+/// Approximate memory taken by a transaction of the history, without the hashmap overhead
+pub const ENTRY_SIZE: usize = std::mem::size_of::<(TransactionId, Packed)>();
+
+/// A transaction of the history, unpacked
+#[derive(PartialEq, Debug, Clone)]
+pub struct TransactionHist {
+    /// Amount of money involved, less the parts of it whose disputes are settled
+    pub amount: Decimal,
+    /// The disputes of the transaction not settled yet, oldest first, most often none or one
+    pub disputes: Vec<DisputeSlice>,
+}
+
+impl TransactionHist {
+    pub fn new(amount: Decimal) -> Self {
+        Self {
+            amount,
+            disputes: Vec::new(),
+        }
+    }
+
+    /// The part of the amount that can still be disputed
+    pub fn undisputed(&self) -> Decimal {
+        self.amount
+            - self
+                .disputes
+                .iter()
+                .map(|dispute| dispute.amount)
+                .sum::<Decimal>()
+    }
+}
+
+/// The amount in minor units in the high bits, the flags in the low ones
+#[derive(Clone, Copy, Debug)]
+struct Packed(u64);
+
+impl Packed {
+    /// None if the amount doesn't round trip through the minor units
+    fn amount(amount: Decimal) -> Option<Self> {
+        let scale = amount.scale();
+        // 1.50 would come back as 1.5
+        if scale > MINOR_SCALE || (scale > 0 && amount.mantissa() % 10 == 0) {
+            return None;
+        }
+        let minor = amount
+            .mantissa()
+            .checked_mul(10i128.pow(MINOR_SCALE - scale))?;
+        let minor = i64::try_from(minor).ok()?;
+        if !(i64::MIN >> FLAGS..=i64::MAX >> FLAGS).contains(&minor) {
+            return None;
+        }
+        Some(Self((minor << FLAGS) as u64))
+    }
+
+    fn spilled(flags: u64) -> Self {
+        Self(flags)
+    }
+
+    fn flags(self) -> u64 {
+        self.0 & (DISPUTED | WIDE)
+    }
+
+    fn unpack(self) -> Decimal {
+        Decimal::new(self.0 as i64 >> FLAGS, MINOR_SCALE).normalize()
+    }
+}
+
+enum Entries {
+    Small(Vec<(TransactionId, Packed)>),
+    Large(HashMap<TransactionId, Packed>),
+}
+
+pub struct TxHistory {
+    entries: Entries,
+    /// The disputed transactions and those with a wide amount
+    spilled: HashMap<TransactionId, TransactionHist>,
+}
+
+impl Default for TxHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxHistory {
+    pub fn new() -> Self {
+        Self {
+            entries: Entries::Small(Vec::new()),
+            spilled: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.entries {
+            Entries::Small(entries) => entries.len(),
+            Entries::Large(entries) => entries.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, tx: TransactionId) -> bool {
+        self.packed(tx).is_some()
+    }
+
+    /// Check if the transaction is disputed without unpacking it
+    pub fn is_disputed(&self, tx: TransactionId) -> bool {
+        self.packed(tx)
+            .is_some_and(|packed| packed.flags() & DISPUTED != 0)
+    }
+
+    pub fn get(&self, tx: TransactionId) -> Option<TransactionHist> {
+        self.packed(tx).map(|packed| self.unpack(tx, packed))
+    }
+
+    /// Adds the transaction, or replaces it
+    pub fn insert(&mut self, tx: TransactionId, transaction: TransactionHist) {
+        let packed = match Packed::amount(transaction.amount) {
+            Some(packed) if transaction.disputes.is_empty() => {
+                self.spilled.remove(&tx);
+                packed
+            }
+            packed => {
+                let mut flags = if packed.is_none() { WIDE } else { 0 };
+                if !transaction.disputes.is_empty() {
+                    flags |= DISPUTED;
+                }
+                self.spilled.insert(tx, transaction);
+                Packed::spilled(flags)
+            }
+        };
+        match &mut self.entries {
+            Entries::Small(entries) => match entries.iter().position(|(id, _)| *id == tx) {
+                Some(index) => entries[index].1 = packed,
+                None if entries.len() < SMALL_HISTORY => entries.push((tx, packed)),
+                None => {
+                    let mut large: HashMap<_, _> = entries.drain(..).collect();
+                    large.insert(tx, packed);
+                    self.entries = Entries::Large(large);
+                }
+            },
+            Entries::Large(entries) => {
+                entries.insert(tx, packed);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, tx: TransactionId) {
+        let removed = match &mut self.entries {
+            Entries::Small(entries) => entries
+                .iter()
+                .position(|(id, _)| *id == tx)
+                .map(|index| entries.swap_remove(index).1),
+            Entries::Large(entries) => entries.remove(&tx),
+        };
+        if removed.is_some_and(|packed| packed.flags() != 0) {
+            self.spilled.remove(&tx);
+        }
+    }
+
+    /// The transactions in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (TransactionId, TransactionHist)> + '_ {
+        let entries: Box<dyn Iterator<Item = (TransactionId, Packed)>> = match &self.entries {
+            Entries::Small(entries) => Box::new(entries.iter().copied()),
+            Entries::Large(entries) => Box::new(entries.iter().map(|(tx, packed)| (*tx, *packed))),
+        };
+        entries.map(move |(tx, packed)| (tx, self.unpack(tx, packed)))
+    }
+
+    fn packed(&self, tx: TransactionId) -> Option<Packed> {
+        match &self.entries {
+            Entries::Small(entries) => entries
+                .iter()
+                .find(|(id, _)| *id == tx)
+                .map(|(_, packed)| *packed),
+            Entries::Large(entries) => entries.get(&tx).copied(),
+        }
+    }
+
+    fn unpack(&self, tx: TransactionId, packed: Packed) -> TransactionHist {
+        match packed.flags() {
+            0 => TransactionHist::new(packed.unpack()),
+            _ => self.spilled[&tx].clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::client_account::DisputeProgress;
+
+    use super::*;
+
+    #[test]
+    fn test_packed_amounts() {
+        for amount in [
+            dec!(0),
+            dec!(1.5),
+            dec!(-2.0001),
+            dec!(100),
+            dec!(230584300921369.3951),
+            dec!(-230584300921369.3952),
+        ] {
+            let packed = Packed::amount(amount).unwrap();
+            assert_eq!(packed.flags(), 0);
+            assert_eq!(packed.unpack().to_string(), amount.to_string());
+        }
+        for amount in [
+            dec!(1.50),
+            dec!(0.00001),
+            dec!(230584300921369.3952),
+            Decimal::MAX,
+        ] {
+            assert!(Packed::amount(amount).is_none(), "{}", amount);
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = TxHistory::new();
+        for tx in 0..20 {
+            history.insert(tx, TransactionHist::new(Decimal::from(tx)));
+            assert_eq!(history.len(), tx as usize + 1);
+        }
+        // the trailing zeros are kept
+        history.insert(20, TransactionHist::new(dec!(1.50)));
+        let dispute = DisputeSlice {
+            id: 1,
+            amount: dec!(0.5),
+            hold: dec!(0.5),
+            state: DisputeProgress::InProgress,
+        };
+        let disputed = TransactionHist {
+            amount: dec!(0.5),
+            disputes: vec![dispute],
+        };
+        history.insert(5, disputed.clone());
+        assert_eq!(history.len(), 21);
+        assert!(history.is_disputed(5));
+        assert!(!history.is_disputed(6));
+        assert_eq!(history.get(5), Some(disputed));
+        assert_eq!(history.get(20).unwrap().amount.to_string(), "1.50");
+        assert_eq!(history.get(7), Some(TransactionHist::new(dec!(7))));
+
+        // settled, back to packed
+        history.insert(5, TransactionHist::new(dec!(0.5)));
+        assert!(!history.is_disputed(5));
+        assert_eq!(history.spilled.len(), 1);
+        history.remove(20);
+        history.remove(3);
+        history.remove(3);
+        assert!(history.spilled.is_empty());
+        assert!(!history.contains(3));
+        assert_eq!(history.len(), 19);
+        let mut all: Vec<_> = history.iter().map(|(tx, _)| tx).collect();
+        all.sort_unstable();
+        assert_eq!(all, (0..20).filter(|tx| *tx != 3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_small_history() {
+        let mut history = TxHistory::new();
+        for tx in (0..SMALL_HISTORY as TransactionId).rev() {
+            history.insert(tx, TransactionHist::new(dec!(1)));
+        }
+        history.insert(2, TransactionHist::new(dec!(2)));
+        assert!(matches!(history.entries, Entries::Small(_)));
+        assert_eq!(history.get(2).unwrap().amount, dec!(2));
+        history.remove(0);
+        assert_eq!(history.len(), SMALL_HISTORY - 1);
+        assert_eq!(history.get(7).unwrap().amount, dec!(1));
+        assert!(history.get(0).is_none());
+    }
+}
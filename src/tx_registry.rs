@@ -0,0 +1,78 @@
+/// A registry of the transaction ids of all the clients, to enforce their global uniqueness
+/// The accounts only see their own history, so two clients can reuse the same id unnoticed.
+/// The registry is shared by the account manager workers, and split in shards by id so the
+/// workers seldom wait for each other
+use std::sync::Mutex;
+
+use hashbrown::HashMap;
+
+use crate::{
+    errors::TransactionError,
+    records::{AccountKey, ClientId, TenantId, TransactionId},
+};
+
+const NUM_SHARDS: usize = 64;
+
+/// The ids are only unique within a tenant
+type Shard = Mutex<HashMap<(Option<TenantId>, TransactionId), ClientId>>;
+
+pub struct TxRegistry {
+    /// The client that owns each id
+    shards: Vec<Shard>,
+}
+
+impl TxRegistry {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..NUM_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    /// Claims the id for the account, unless another client of its tenant already owns it
+    /// Returns whether the id was newly claimed
+    pub fn claim(&self, tx: TransactionId, account: AccountKey) -> Result<bool, TransactionError> {
+        let mut shard = self.shard(tx).lock().unwrap();
+        match shard.get(&(account.tenant, tx)) {
+            Some(&owner) if owner != account.client => Err(TransactionError::TxIdReused { owner }),
+            Some(_) => Ok(false),
+            None => {
+                shard.insert((account.tenant, tx), account.client);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Gives up an id claimed by a transaction of the account that was rejected
+    pub fn release(&self, tx: TransactionId, account: AccountKey) {
+        self.shard(tx).lock().unwrap().remove(&(account.tenant, tx));
+    }
+
+    fn shard(&self, tx: TransactionId) -> &Shard {
+        &self.shards[tx as usize % NUM_SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tx_registry() {
+        let registry = TxRegistry::new();
+        assert_eq!(registry.claim(1, 10.into()), Ok(true));
+        assert_eq!(registry.claim(65, 11.into()), Ok(true));
+        // the same client can use it again, the account decides if it's a duplicate
+        assert_eq!(registry.claim(1, 10.into()), Ok(false));
+        assert_eq!(
+            registry.claim(1, 11.into()),
+            Err(TransactionError::TxIdReused { owner: 10 })
+        );
+        // another tenant has its own ids
+        assert_eq!(registry.claim(1, AccountKey::new(Some(1), 11)), Ok(true));
+
+        registry.release(1, 10.into());
+        assert_eq!(registry.claim(1, 11.into()), Ok(true));
+    }
+}
@@ -0,0 +1,188 @@
+/// Reads local files with io_uring, keeping several reads in flight
+/// A buffered reader waits for every read before asking for the next one, which leaves
+/// fast NVMe disks mostly idle. Here the chunks are read ahead at increasing offsets on
+/// a separate thread and handed over in order, while the parsers work on the previous ones
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    os::unix::{fs::FileExt, io::AsRawFd},
+};
+
+use crossbeam_channel::Sender;
+use io_uring::{opcode, types, IoUring};
+use log::*;
+
+use crate::input::ChannelReader;
+
+/// Number of reads in flight
+const QUEUE_DEPTH: usize = 8;
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// Number of read chunks waiting to be parsed
+const CHUNK_QUEUE_SIZE: usize = 16;
+
+/// Reads the file with io_uring, or as a plain file if the kernel doesn't allow it
+pub fn open(file: File) -> anyhow::Result<Box<dyn Read + Send>> {
+    let ring = match IoUring::new(QUEUE_DEPTH as u32) {
+        Ok(ring) => ring,
+        Err(err) => {
+            // e.g. blocked by the seccomp profile of a container
+            warn!(
+                "io_uring is not available, reading the file sequentially: {:?}",
+                err
+            );
+            return Ok(Box::new(file));
+        }
+    };
+    let size = file.metadata()?.len();
+
+    let (chunk_tx, chunk_rx) = crossbeam_channel::bounded(CHUNK_QUEUE_SIZE);
+    std::thread::Builder::new()
+        .name("uring reader".to_string())
+        .spawn(move || {
+            let mut reads = UringReads {
+                ring,
+                file,
+                size,
+                next_offset: 0,
+                in_flight: HashMap::new(),
+            };
+            if let Err(err) = reads.run(&chunk_tx) {
+                let _ = chunk_tx.send(Err(err));
+            }
+            reads.drain();
+        })?;
+
+    Ok(Box::new(ChannelReader::new(chunk_rx)))
+}
+
+struct UringReads {
+    ring: IoUring,
+    file: File,
+    size: u64,
+    /// Offset of the next chunk to read
+    next_offset: u64,
+    /// Buffers of the submitted reads by offset, they must outlive the reads
+    in_flight: HashMap<u64, Vec<u8>>,
+}
+
+impl UringReads {
+    /// Reads the whole file, until the reading end of the channel is dropped
+    fn run(&mut self, chunk_tx: &Sender<std::io::Result<Vec<u8>>>) -> std::io::Result<()> {
+        // the reads can complete out of order
+        let mut completed = HashMap::new();
+        let mut sent_offset = 0;
+        while sent_offset < self.size {
+            while self.in_flight.len() < QUEUE_DEPTH && self.next_offset < self.size {
+                self.submit()?;
+            }
+            self.ring.submit_and_wait(1)?;
+
+            let results: Vec<(u64, i32)> = self
+                .ring
+                .completion()
+                .map(|entry| (entry.user_data(), entry.result()))
+                .collect();
+            for (offset, result) in results {
+                let mut chunk = self
+                    .in_flight
+                    .remove(&offset)
+                    .expect("Invariant: every completed read was submitted");
+                if result < 0 {
+                    return Err(std::io::Error::from_raw_os_error(-result));
+                }
+                let read = result as usize;
+                if read < chunk.len() {
+                    // short reads are rare on regular files, finish them synchronously
+                    self.file
+                        .read_exact_at(&mut chunk[read..], offset + read as u64)?;
+                }
+                completed.insert(offset, chunk);
+            }
+
+            while let Some(chunk) = completed.remove(&sent_offset) {
+                sent_offset += chunk.len() as u64;
+                if chunk_tx.send(Ok(chunk)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn submit(&mut self) -> std::io::Result<()> {
+        let len = CHUNK_SIZE.min((self.size - self.next_offset) as usize);
+        let mut buffer = vec![0; len];
+        let entry = opcode::Read::new(
+            types::Fd(self.file.as_raw_fd()),
+            buffer.as_mut_ptr(),
+            len as u32,
+        )
+        .offset(self.next_offset)
+        .build()
+        .user_data(self.next_offset);
+
+        // SAFETY: the buffer is kept in `in_flight` until its read completes
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| std::io::Error::other("The io_uring submission queue is full"))?;
+        self.in_flight.insert(self.next_offset, buffer);
+        self.next_offset += len as u64;
+        Ok(())
+    }
+
+    /// Waits for the reads still in flight, so their buffers can be freed
+    fn drain(&mut self) {
+        while !self.in_flight.is_empty() {
+            if self.ring.submit_and_wait(1).is_err() {
+                // better leak the buffers than free them while the kernel writes to them
+                for (_, buffer) in self.in_flight.drain() {
+                    std::mem::forget(buffer);
+                }
+                return;
+            }
+            let offsets: Vec<u64> = self
+                .ring
+                .completion()
+                .map(|entry| entry.user_data())
+                .collect();
+            for offset in offsets {
+                self.in_flight.remove(&offset);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_ahead() {
+        let path = std::env::temp_dir().join("paytoy_test_uring.csv");
+        // not a multiple of the chunk size, so the last read is shorter
+        let data: Vec<u8> = (0..5 * CHUNK_SIZE + 12345)
+            .map(|index| (index % 251) as u8)
+            .collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let mut read = Vec::new();
+        open(File::open(&path).unwrap())
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read == data);
+    }
+
+    #[test]
+    fn test_early_drop() {
+        let path = std::env::temp_dir().join("paytoy_test_uring_drop.csv");
+        std::fs::write(&path, vec![b'x'; 40 * CHUNK_SIZE]).unwrap();
+
+        let mut reader = open(File::open(&path).unwrap()).unwrap();
+        let mut start = [0u8; 16];
+        reader.read_exact(&mut start).unwrap();
+        assert_eq!(start, [b'x'; 16]);
+        // the reading thread stops and waits for its reads before freeing the buffers
+        drop(reader);
+    }
+}
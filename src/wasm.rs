@@ -0,0 +1,102 @@
+/// JavaScript bindings of the engine, for the browser demo and the tests in JS environments
+/// The transactions are given as a CSV document and the report is returned as JSON. The amounts
+/// are strings, since JS numbers would round them
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    account_manager::{AccountManager, STAccountManager},
+    records::TenantId,
+    transactions_reader::{STBulkReader, TransactionCSVReader},
+};
+
+/// Applies the transactions of a CSV document and returns the report as a JSON string
+/// Like the CLI, the records that can't be parsed are skipped
+#[wasm_bindgen(js_name = processCsv)]
+pub fn process_csv(csv: &str) -> Result<String, JsError> {
+    report_json(csv)
+        .map(|report| report.to_string())
+        .map_err(|err| JsError::new(&format!("{:#}", err)))
+}
+
+/// The report as `{ accounts: [...], rejects: [...] }`, the accounts sorted by tenant and client
+fn report_json(csv: &str) -> anyhow::Result<Value> {
+    let transactions =
+        STBulkReader::new().read_from(std::io::Cursor::new(csv.as_bytes().to_vec()))?;
+    let report = STAccountManager::new().execute_transactions(transactions);
+
+    let mut accounts: Vec<_> = report.accounts().collect();
+    accounts.sort_by_key(|account| account.key());
+    let amount = |amount: Decimal| amount.normalize().to_string();
+    let accounts: Vec<Value> = accounts
+        .into_iter()
+        .map(|account| {
+            with_tenant(
+                json!({
+                    "client": account.id(),
+                    "available": amount(account.available()),
+                    "held": amount(account.held()),
+                    "total": amount(account.total()),
+                    "locked": account.is_locked(),
+                }),
+                account.tenant(),
+            )
+        })
+        .collect();
+    let rejects: Vec<Value> = report
+        .rejects()
+        .iter()
+        .map(|reject| {
+            with_tenant(
+                json!({
+                    "type": reject.tr_type.to_string(),
+                    "client": reject.client,
+                    "tx": reject.tx,
+                    "reason": reject.reason.code(),
+                }),
+                reject.tenant,
+            )
+        })
+        .collect();
+
+    Ok(json!({ "accounts": accounts, "rejects": rejects }))
+}
+
+/// Adds the tenant to the object, the records without a tenant don't get the field
+fn with_tenant(mut object: Value, tenant: Option<TenantId>) -> Value {
+    if let (Some(tenant), Some(fields)) = (tenant, object.as_object_mut()) {
+        fields.insert("tenant".to_string(), tenant.into());
+    }
+    object
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_json() {
+        let report = report_json(
+            "type, client, tx, amount\n\
+             deposit, 2, 1, 1.50\n\
+             deposit, 1, 2, 2.0\n\
+             withdrawal, 1, 3, 3.0\n\
+             dispute, 2, 1,\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            report,
+            json!({
+                "accounts": [
+                    { "client": 1, "available": "2", "held": "0", "total": "2", "locked": false },
+                    { "client": 2, "available": "0", "held": "1.5", "total": "1.5", "locked": false },
+                ],
+                "rejects": [
+                    { "type": "withdrawal", "client": 1, "tx": 3, "reason": "insufficient_funds" },
+                ],
+            })
+        );
+    }
+}
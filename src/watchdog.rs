@@ -0,0 +1,145 @@
+/// Detects a pipeline that stopped making progress, e.g. a hung reader or a wedged channel
+/// The watchdog thread watches the number of records handed to the account manager. When it
+/// doesn't move for the timeout, the state of the queues is logged to help finding the stage
+/// that is stuck, and the run can be aborted instead of hanging forever
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::*;
+
+use paytoy::{errors::ExitCode, stats::PipelineStats};
+
+/// Longest sleep between two checks, so the thread stops soon after the run
+const MAX_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct Watchdog {
+    stats: Arc<PipelineStats>,
+    timeout: Duration,
+    /// Records dispatched at the last check
+    dispatched: u64,
+    /// When the records last moved
+    last_progress: Instant,
+    /// The current stall was already reported
+    reported: bool,
+}
+
+impl Watchdog {
+    pub fn new(stats: Arc<PipelineStats>, timeout: Duration) -> Self {
+        Self {
+            dispatched: stats.records_dispatched(),
+            stats,
+            timeout,
+            last_progress: Instant::now(),
+            reported: false,
+        }
+    }
+
+    /// Checks the run until it's finished, the process exits on a stall with `abort`
+    pub fn start(mut self, abort: bool) -> std::io::Result<()> {
+        let interval = (self.timeout / 10).min(MAX_CHECK_INTERVAL);
+        std::thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || {
+                while !self.stats.is_finished() {
+                    std::thread::sleep(interval);
+                    if !self.check(Instant::now()) {
+                        continue;
+                    }
+                    let report = format!(
+                        "No record was processed for {:.0?}. {}",
+                        self.timeout,
+                        diagnostics(&self.stats)
+                    );
+                    // the stalls are reported even when the logs are off
+                    if log_enabled!(Level::Error) {
+                        error!("{}", report);
+                    } else {
+                        eprintln!("{}", report);
+                    }
+                    if abort {
+                        eprintln!(
+                            "The pipeline made no progress for {:.0?}, the run was aborted",
+                            self.timeout
+                        );
+                        std::process::exit(ExitCode::Stalled as i32);
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Returns true when the run is found stalled, once per stall
+    fn check(&mut self, now: Instant) -> bool {
+        let dispatched = self.stats.records_dispatched();
+        if dispatched != self.dispatched {
+            self.dispatched = dispatched;
+            self.last_progress = now;
+            self.reported = false;
+            return false;
+        }
+        if self.reported || now.duration_since(self.last_progress) < self.timeout {
+            return false;
+        }
+        self.reported = true;
+        true
+    }
+}
+
+/// The counters of the stages and the depths of the queues, as last published
+fn diagnostics(stats: &PipelineStats) -> String {
+    let depths = stats.queue_depths();
+    let queues: Vec<String> = depths
+        .iter()
+        .map(|(name, depth, capacity)| format!("{} {}/{}", name, depth, capacity))
+        .collect();
+    let hint = if depths.is_empty() {
+        ""
+    } else if depths.iter().any(|(_, depth, capacity)| depth >= capacity) {
+        " A full queue points to a stuck consumer."
+    } else {
+        " Empty queues point to a stuck reader."
+    };
+    format!(
+        "Records read: {}, dispatched: {}, applied: {}, rejected: {}. Queues: [{}].{}",
+        stats.records_read(),
+        stats.records_dispatched(),
+        stats.records_applied(),
+        stats.records_rejected(),
+        queues.join(", "),
+        hint
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let stats = Arc::new(PipelineStats::new());
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(stats.clone(), Duration::from_secs(10));
+        watchdog.last_progress = start;
+
+        assert!(!watchdog.check(start + Duration::from_secs(5)));
+        stats.add_dispatched(3);
+        assert!(!watchdog.check(start + Duration::from_secs(8)));
+        // 10s after the last progress
+        assert!(!watchdog.check(start + Duration::from_secs(17)));
+        assert!(watchdog.check(start + Duration::from_secs(18)));
+        // reported once
+        assert!(!watchdog.check(start + Duration::from_secs(30)));
+
+        stats.add_dispatched(1);
+        assert!(!watchdog.check(start + Duration::from_secs(31)));
+        assert!(watchdog.check(start + Duration::from_secs(41)));
+
+        stats.register_queue("worker 0", 4).set(4);
+        let diagnostics = diagnostics(&stats);
+        assert!(diagnostics.contains("dispatched: 4"));
+        assert!(diagnostics.contains("worker 0 4/4"));
+        assert!(diagnostics.contains("stuck consumer"));
+    }
+}
@@ -0,0 +1,552 @@
+/// Synthetic workloads for the benchmarks and `paytoy generate`
+/// Each profile draws its records from a seeded generator, so a profile and a seed always
+/// give the same records on every platform and release. The profiles differ by how the
+/// records are spread over the clients and by their mix of transaction types: the disputes,
+/// resolves and chargebacks refer to earlier deposits, and the adversarial profile replays
+/// records and reuses the ids of other transactions. The CSV lines can also be corrupted at
+/// a rate, to test the readers on broken inputs
+use std::io::Write;
+
+use rust_decimal::Decimal;
+
+use crate::records::{wide_id, ClientId, TransactionId, TransactionRecord, TransactionType};
+
+/// Exponent of the zipfian distribution of the clients, the `n`th client gets `1/n` of the
+/// records of the first one
+const ZIPF_EXPONENT: f64 = 1.0;
+/// Deposits kept to be disputed later
+const DISPUTABLE: usize = 4096;
+/// The clients are drawn among the first ids whatever their width, so a seed generates the
+/// same workload in all the builds
+const CLIENTS: u64 = 1 << 16;
+/// The client of `hot-single-client`
+const HOT_CLIENT: ClientId = 1;
+
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum WorkloadProfile {
+    /// The records spread evenly over all the clients, with a few disputes
+    Uniform,
+    /// The clients drawn from a zipfian distribution, a few of them get most of the records
+    ZipfianClients,
+    /// Half of the records are disputes of earlier deposits and their settlements
+    DisputeHeavy,
+    /// All the records on a single client
+    HotSingleClient,
+    /// A quarter of the records replay earlier ones or reuse their transaction ids
+    AdversarialDuplicates,
+}
+
+impl WorkloadProfile {
+    pub const ALL: [WorkloadProfile; 5] = [
+        WorkloadProfile::Uniform,
+        WorkloadProfile::ZipfianClients,
+        WorkloadProfile::DisputeHeavy,
+        WorkloadProfile::HotSingleClient,
+        WorkloadProfile::AdversarialDuplicates,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WorkloadProfile::Uniform => "uniform",
+            WorkloadProfile::ZipfianClients => "zipfian-clients",
+            WorkloadProfile::DisputeHeavy => "dispute-heavy",
+            WorkloadProfile::HotSingleClient => "hot-single-client",
+            WorkloadProfile::AdversarialDuplicates => "adversarial-duplicates",
+        }
+    }
+
+    /// The share of each transaction type in percent
+    fn mix(self) -> Mix {
+        match self {
+            WorkloadProfile::DisputeHeavy => Mix {
+                deposit: 35,
+                withdrawal: 15,
+                dispute: 28,
+                resolve: 17,
+                chargeback: 5,
+            },
+            // a chargeback would lock the only account
+            WorkloadProfile::HotSingleClient => Mix {
+                deposit: 55,
+                withdrawal: 39,
+                dispute: 4,
+                resolve: 2,
+                chargeback: 0,
+            },
+            _ => Mix {
+                deposit: 55,
+                withdrawal: 37,
+                dispute: 4,
+                resolve: 3,
+                chargeback: 1,
+            },
+        }
+    }
+}
+
+struct Mix {
+    deposit: u64,
+    withdrawal: u64,
+    dispute: u64,
+    resolve: u64,
+    chargeback: u64,
+}
+
+/// A xorshift generator, the workloads have to be the same on every platform
+pub(crate) struct Generator(u64);
+
+impl Generator {
+    pub(crate) fn new(seed: u64) -> Self {
+        // the state can't be zero
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// In `0..bound`
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    /// Up to 100 with 4 decimal places
+    pub(crate) fn amount(&mut self) -> Decimal {
+        Decimal::new(self.below(1_000_000) as i64 + 1, 4)
+    }
+
+    /// In `0.0..1.0`
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The endless records of a profile, take as many as needed
+pub struct WorkloadGenerator {
+    profile: WorkloadProfile,
+    mix: Mix,
+    generator: Generator,
+    /// The cumulative distribution of the clients of `zipfian-clients`, by rank
+    zipf: Vec<f64>,
+    next_tx: TransactionId,
+    /// Recent deposits that can be disputed, with their client
+    deposits: Vec<(ClientId, TransactionId)>,
+    /// The disputes not settled yet
+    disputed: Vec<(ClientId, TransactionId)>,
+    previous: Option<TransactionRecord>,
+}
+
+impl WorkloadGenerator {
+    pub fn new(profile: WorkloadProfile, seed: u64) -> Self {
+        let zipf = match profile {
+            WorkloadProfile::ZipfianClients => {
+                let weights = (1..=CLIENTS).map(|rank| 1.0 / (rank as f64).powf(ZIPF_EXPONENT));
+                let mut total = 0.0;
+                let mut cumulative: Vec<f64> = weights
+                    .map(|weight| {
+                        total += weight;
+                        total
+                    })
+                    .collect();
+                cumulative.iter_mut().for_each(|sum| *sum /= total);
+                cumulative
+            }
+            _ => Vec::new(),
+        };
+        Self {
+            profile,
+            mix: profile.mix(),
+            generator: Generator::new(seed),
+            zipf,
+            next_tx: 1,
+            deposits: Vec::new(),
+            disputed: Vec::new(),
+            previous: None,
+        }
+    }
+
+    fn client(&mut self) -> ClientId {
+        match self.profile {
+            WorkloadProfile::HotSingleClient => HOT_CLIENT,
+            WorkloadProfile::ZipfianClients => {
+                let unit = self.generator.unit();
+                let rank = self.zipf.partition_point(|sum| *sum < unit);
+                // the hot clients are spread over the ids, an odd factor is a permutation
+                (rank as u64 * 40503 % CLIENTS) as ClientId
+            }
+            _ => self.generator.below(CLIENTS) as ClientId,
+        }
+    }
+
+    fn new_tx(&mut self) -> TransactionId {
+        let tx = self.next_tx;
+        self.next_tx = self.next_tx.wrapping_add(1).max(1);
+        tx
+    }
+
+    /// A deposit, a withdrawal or a dispute by the mix of the profile
+    fn regular(&mut self) -> TransactionRecord {
+        let mix = &self.mix;
+        let choice = self.generator.below(100);
+        let tr_type = [
+            (mix.deposit, TransactionType::Deposit),
+            (mix.withdrawal, TransactionType::Withdrawal),
+            (mix.dispute, TransactionType::Dispute),
+            (mix.resolve, TransactionType::Resolve),
+            (mix.chargeback, TransactionType::ChargeBack),
+        ]
+        .iter()
+        .scan(0, |sum, (share, tr_type)| {
+            *sum += share;
+            Some((*sum, *tr_type))
+        })
+        .find(|(sum, _)| choice < *sum)
+        .map_or(TransactionType::Deposit, |(_, tr_type)| tr_type);
+
+        match tr_type {
+            TransactionType::Dispute if !self.deposits.is_empty() => {
+                let index = self.generator.below(self.deposits.len() as u64) as usize;
+                let (client, tx) = self.deposits.swap_remove(index);
+                self.disputed.push((client, tx));
+                record(TransactionType::Dispute, client, tx, None)
+            }
+            TransactionType::Resolve | TransactionType::ChargeBack if !self.disputed.is_empty() => {
+                let index = self.generator.below(self.disputed.len() as u64) as usize;
+                let (client, tx) = self.disputed.swap_remove(index);
+                record(tr_type, client, tx, None)
+            }
+            TransactionType::Withdrawal => {
+                let (client, tx) = (self.client(), self.new_tx());
+                let amount = self.generator.amount() / Decimal::from(2);
+                record(tr_type, client, tx, Some(amount))
+            }
+            // a deposit, also when there is nothing to dispute or to settle
+            _ => {
+                let (client, tx) = (self.client(), self.new_tx());
+                if self.deposits.len() < DISPUTABLE {
+                    self.deposits.push((client, tx));
+                } else {
+                    let index = self.generator.below(DISPUTABLE as u64) as usize;
+                    self.deposits[index] = (client, tx);
+                }
+                let amount = self.generator.amount();
+                record(TransactionType::Deposit, client, tx, Some(amount))
+            }
+        }
+    }
+
+    /// The previous record again, or a new one with the id of an earlier transaction
+    fn duplicate(&mut self) -> Option<TransactionRecord> {
+        match self.generator.below(3) {
+            0 => self.previous.clone(),
+            choice => {
+                let tx = self.generator.below(wide_id(self.next_tx)) as TransactionId + 1;
+                let tr_type = if choice == 1 {
+                    TransactionType::Deposit
+                } else {
+                    TransactionType::Withdrawal
+                };
+                let (client, amount) = (self.client(), self.generator.amount());
+                Some(record(tr_type, client, tx, Some(amount)))
+            }
+        }
+    }
+}
+
+impl Iterator for WorkloadGenerator {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        let duplicate = match self.profile {
+            WorkloadProfile::AdversarialDuplicates if self.generator.below(4) == 0 => {
+                self.duplicate()
+            }
+            _ => None,
+        };
+        let record = duplicate.unwrap_or_else(|| self.regular());
+        self.previous = Some(record.clone());
+        Some(record)
+    }
+}
+
+fn record(
+    tr_type: TransactionType,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Decimal>,
+) -> TransactionRecord {
+    TransactionRecord {
+        tr_type,
+        client,
+        tenant: None,
+        merchant: None,
+        tx,
+        amount,
+        timestamp: None,
+        source: None,
+    }
+}
+
+/// Writes `records` records of the profile as a CSV input, some of their lines corrupted by
+/// `corrupter`. Returns the lines corrupted
+pub fn write_csv(
+    writer: &mut impl Write,
+    profile: WorkloadProfile,
+    records: usize,
+    seed: u64,
+    mut corrupter: Option<Corrupter>,
+) -> std::io::Result<usize> {
+    writeln!(writer, "type,client,tx,amount")?;
+    let mut corrupted = 0;
+    for record in WorkloadGenerator::new(profile, seed).take(records) {
+        let amount = record.amount.map(|amount| amount.to_string());
+        let line = format!(
+            "{},{},{},{}",
+            record.tr_type,
+            record.client,
+            record.tx,
+            amount.unwrap_or_default()
+        );
+        match corrupter
+            .as_mut()
+            .and_then(|corrupter| corrupter.corrupt(&line))
+        {
+            Some(line) => {
+                corrupted += 1;
+                writer.write_all(&line)?;
+            }
+            None => writeln!(writer, "{}", line)?,
+        }
+    }
+    writer.flush()?;
+    Ok(corrupted)
+}
+
+/// The ways a line of the input can be corrupted
+#[derive(PartialEq, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Corruption {
+    /// The line cut short
+    Truncated,
+    /// A byte of the line replaced by one that isn't valid UTF-8
+    BadUtf8,
+    /// The amount padded to 64 KiB
+    HugeFields,
+    /// The last columns dropped
+    MissingColumns,
+    /// The line ended with `\r\n` instead of `\n`
+    Crlf,
+    /// A quote in the middle of a field
+    Quotes,
+}
+
+/// The length of the fields of `huge-fields`
+const HUGE_FIELD: usize = 64 * 1024;
+
+/// Corrupts lines of the generated inputs at a rate, with one of its corruptions each
+pub struct Corrupter {
+    corruptions: Vec<Corruption>,
+    rate: f64,
+    /// Not the generator of the records, they're the same with and without corruptions
+    generator: Generator,
+}
+
+impl Corrupter {
+    pub fn new(corruptions: Vec<Corruption>, rate: f64, seed: u64) -> Self {
+        Self {
+            corruptions,
+            rate,
+            generator: Generator::new(!seed),
+        }
+    }
+
+    /// The line with its newline, None when it's left as it is
+    fn corrupt(&mut self, line: &str) -> Option<Vec<u8>> {
+        if self.corruptions.is_empty() || self.generator.unit() >= self.rate {
+            return None;
+        }
+        let corruption =
+            self.corruptions[self.generator.below(self.corruptions.len() as u64) as usize];
+        let mut bytes = line.as_bytes().to_vec();
+        match corruption {
+            Corruption::Truncated => {
+                let len = self.generator.below(bytes.len() as u64 - 1) + 1;
+                bytes.truncate(len as usize);
+            }
+            Corruption::BadUtf8 => {
+                let index = self.generator.below(bytes.len() as u64) as usize;
+                bytes[index] = 0xff;
+            }
+            Corruption::HugeFields => {
+                bytes.truncate(line.rfind(',').map_or(0, |comma| comma + 1));
+                bytes.resize(bytes.len() + HUGE_FIELD, b'9');
+            }
+            Corruption::MissingColumns => {
+                let columns = self.generator.below(3) as usize + 1;
+                let end = line.match_indices(',').rev().nth(columns - 1);
+                bytes.truncate(end.map_or(bytes.len(), |(comma, _)| comma));
+            }
+            Corruption::Crlf => bytes.push(b'\r'),
+            Corruption::Quotes => {
+                let index = self.generator.below(bytes.len() as u64 - 1) as usize + 1;
+                bytes.insert(index, b'"');
+            }
+        }
+        bytes.push(b'\n');
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use crate::{
+        account_manager::STAccountManager,
+        paytoy::PayToyApp,
+        reader_conformance::{fields, RecordFields},
+        transactions_reader::{STBulkReader, TransactionCSVReader},
+    };
+
+    use super::*;
+
+    fn records(profile: WorkloadProfile) -> Vec<TransactionRecord> {
+        WorkloadGenerator::new(profile, 7).take(20_000).collect()
+    }
+
+    fn all_fields(
+        profile: WorkloadProfile,
+        seed: u64,
+    ) -> Vec<crate::reader_conformance::RecordFields> {
+        WorkloadGenerator::new(profile, seed)
+            .take(1000)
+            .map(|record| fields(&record))
+            .collect()
+    }
+
+    fn count(records: &[TransactionRecord], tr_type: TransactionType) -> usize {
+        records
+            .iter()
+            .filter(|record| record.tr_type == tr_type)
+            .count()
+    }
+
+    #[test]
+    fn test_profiles() {
+        let uniform = records(WorkloadProfile::Uniform);
+        let clients: HashSet<_> = uniform.iter().map(|record| record.client).collect();
+        assert!(clients.len() > 10_000, "{}", clients.len());
+        assert!(count(&uniform, TransactionType::Withdrawal) > 6_000);
+        assert!(count(&uniform, TransactionType::Dispute) > 400);
+
+        let zipfian = records(WorkloadProfile::ZipfianClients);
+        let mut per_client: HashMap<ClientId, usize> = HashMap::new();
+        for record in &zipfian {
+            *per_client.entry(record.client).or_default() += 1;
+        }
+        let mut counts: Vec<usize> = per_client.values().copied().collect();
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        // the top 10 clients get about a quarter of the records
+        let top: usize = counts.iter().take(10).sum();
+        assert!(top > 3_000, "{}", top);
+
+        let disputes = records(WorkloadProfile::DisputeHeavy);
+        assert!(count(&disputes, TransactionType::Dispute) > 4_000);
+        assert!(count(&disputes, TransactionType::ChargeBack) > 500);
+
+        let hot = records(WorkloadProfile::HotSingleClient);
+        assert!(hot.iter().all(|record| record.client == HOT_CLIENT));
+        assert_eq!(count(&hot, TransactionType::ChargeBack), 0);
+
+        let duplicates = records(WorkloadProfile::AdversarialDuplicates);
+        let ids: HashSet<_> = duplicates
+            .iter()
+            .filter(|record| record.amount.is_some())
+            .map(|record| record.tx)
+            .collect();
+        let with_amount = duplicates
+            .iter()
+            .filter(|record| record.amount.is_some())
+            .count();
+        assert!(with_amount - ids.len() > 3_000, "{}", ids.len());
+    }
+
+    #[test]
+    fn test_same_records() {
+        for profile in WorkloadProfile::ALL {
+            let first = all_fields(profile, 3);
+            assert_eq!(first, all_fields(profile, 3), "{}", profile.name());
+            assert_ne!(first, all_fields(profile, 4), "{}", profile.name());
+        }
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let path = std::env::temp_dir().join("paytoy_test_workload.csv");
+        let mut file = std::fs::File::create(&path).unwrap();
+        let corrupted = write_csv(&mut file, WorkloadProfile::DisputeHeavy, 5000, 1, None);
+        assert_eq!(corrupted.unwrap(), 0);
+        assert_eq!(STBulkReader::new().read_csv(&path).unwrap().count(), 5000);
+        let report = PayToyApp::process(
+            STBulkReader::new().read_csv(&path).unwrap(),
+            STAccountManager::new(),
+        );
+        assert!(report.accounts().any(|account| account.is_locked()));
+        assert!(!report.rejects().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// The records read back from an input of the profile with a corruption
+    fn read_corrupted(corruption: Corruption, rate: f64) -> (usize, Vec<RecordFields>) {
+        let path = std::env::temp_dir().join(format!("paytoy_test_corrupt_{:?}.csv", corruption));
+        let mut file = std::fs::File::create(&path).unwrap();
+        let corrupter = Corrupter::new(vec![corruption], rate, 1);
+        let corrupted = write_csv(
+            &mut file,
+            WorkloadProfile::Uniform,
+            2000,
+            1,
+            Some(corrupter),
+        );
+        let read = STBulkReader::new()
+            .read_csv(&path)
+            .unwrap()
+            .map(|record| fields(&record))
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        (corrupted.unwrap(), read)
+    }
+
+    #[test]
+    fn test_corruptions() {
+        let (_, expected) = read_corrupted(Corruption::Crlf, 0.0);
+        assert_eq!(expected.len(), 2000);
+
+        // the readers take the line endings as they come
+        let (corrupted, read) = read_corrupted(Corruption::Crlf, 0.5);
+        assert!((800..1200).contains(&corrupted), "{}", corrupted);
+        assert_eq!(read, expected);
+
+        // the corrupted lines are skipped, the others are read
+        let (corrupted, read) = read_corrupted(Corruption::BadUtf8, 0.1);
+        assert!((100..300).contains(&corrupted), "{}", corrupted);
+        assert_eq!(read.len(), 2000 - corrupted);
+        assert!(read.iter().all(|fields| expected.contains(fields)));
+
+        // some of the corrupted lines are still valid records, they're changed: the huge
+        // amounts are read as 10^28 by rust_decimal
+        for corruption in [
+            Corruption::Truncated,
+            Corruption::HugeFields,
+            Corruption::Quotes,
+            Corruption::MissingColumns,
+        ] {
+            let (corrupted, read) = read_corrupted(corruption, 0.1);
+            assert!(corrupted > 100, "{:?}", corruption);
+            assert!(read.len() <= 2000, "{:?}", corruption);
+            assert_ne!(read, expected, "{:?}", corruption);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+/// Reads all the CSV files of a zip archive as a single input
+/// The entries are decompressed one after another on a separate thread, in archive order,
+/// and the header of every entry but the first one is dropped
+use std::io::{BufRead, BufReader, Read, Seek};
+
+use glob::Pattern;
+use log::*;
+use zip::ZipArchive;
+
+use crate::{errors::UsageError, input::ChannelReader};
+
+/// Number of decompressed chunks waiting to be read
+const CHUNK_QUEUE_SIZE: usize = 64;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Opens the entries of the archive matching the glob `entries`, or all of them
+pub fn open<R: Read + Seek + Send + 'static>(
+    archive: R,
+    entries: Option<&str>,
+) -> anyhow::Result<Box<dyn Read + Send>> {
+    let mut archive = ZipArchive::new(archive)?;
+    let pattern = entries
+        .map(Pattern::new)
+        .transpose()
+        .map_err(|err| UsageError(format!("Invalid zip entries pattern: {}", err)))?;
+
+    let mut indices = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index)?;
+        if entry.is_file()
+            && pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.matches(entry.name()))
+        {
+            indices.push(index);
+        }
+    }
+    if indices.is_empty() {
+        return Err(anyhow::anyhow!("No entry of the archive matches"));
+    }
+
+    let (chunk_tx, chunk_rx) = crossbeam_channel::bounded(CHUNK_QUEUE_SIZE);
+    let unzip_thread = std::thread::Builder::new().name("unzip".to_string());
+    unzip_thread.spawn(move || {
+        let mut ends_with_newline = true;
+        for (position, index) in indices.into_iter().enumerate() {
+            let result = archive
+                .by_index(index)
+                .map_err(std::io::Error::other)
+                .and_then(|entry| {
+                    info!("Reading the zip entry {}", entry.name());
+                    let mut entry = BufReader::with_capacity(CHUNK_SIZE, entry);
+                    if position > 0 {
+                        // same header as the first entry
+                        entry.read_until(b'\n', &mut Vec::new())?;
+                        if !ends_with_newline {
+                            let _ = chunk_tx.send(Ok(b"\n".to_vec()));
+                        }
+                    }
+
+                    loop {
+                        let chunk = entry.fill_buf()?.to_vec();
+                        if chunk.is_empty() {
+                            return Ok(());
+                        }
+                        entry.consume(chunk.len());
+                        ends_with_newline = chunk.ends_with(b"\n");
+                        if chunk_tx.send(Ok(chunk)).is_err() {
+                            return Ok(());
+                        }
+                    }
+                });
+
+            if let Err(err) = result {
+                let _ = chunk_tx.send(Err(err));
+                return;
+            }
+        }
+    })?;
+
+    Ok(Box::new(ChannelReader::new(chunk_rx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    fn archive(entries: &[(&str, &str)]) -> Cursor<Vec<u8>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        let mut archive = writer.finish().unwrap();
+        archive.set_position(0);
+        archive
+    }
+
+    #[test]
+    fn test_concatenated_entries() {
+        let archive = archive(&[
+            (
+                "2021-07/a.csv",
+                "type, client, tx, amount\ndeposit, 1, 1, 1.0\n",
+            ),
+            ("readme.txt", "not a csv"),
+            (
+                "2021-07/b.csv",
+                "type, client, tx, amount\ndeposit, 1, 2, 2.0",
+            ),
+            (
+                "2021-07/c.csv",
+                "type, client, tx, amount\ndeposit, 1, 3, 3.0\n",
+            ),
+        ]);
+
+        let mut content = String::new();
+        open(archive, Some("*.csv"))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(
+            content,
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 1.0\n\
+             deposit, 1, 2, 2.0\n\
+             deposit, 1, 3, 3.0\n"
+        );
+    }
+
+    #[test]
+    fn test_no_matching_entry() {
+        let archive = archive(&[("a.csv", "type, client, tx, amount\n")]);
+        assert!(open(archive, Some("*.parquet")).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+/* Withdrawals settled through the C API, built and run by tests/ffi.rs */
+#include <stdio.h>
+#include <string.h>
+
+#include "paytoy.h"
+
+static int failures = 0;
+
+static void check(int ok, const char *what) {
+  if (!ok) {
+    fprintf(stderr, "failed: %s\n", what);
+    failures++;
+  }
+}
+
+static int error_is(const PaytoyEngine *engine, const char *code) {
+  const char *err = paytoy_engine_last_error(engine);
+  return err != NULL && strcmp(err, code) == 0;
+}
+
+int main(void) {
+  PaytoyAccount account;
+  PaytoyEngine *engine = paytoy_engine_new_with_pending_withdrawals();
+
+  check(paytoy_engine_push(engine, PAYTOY_DEPOSIT, 1, 1, "10") == PAYTOY_OK, "deposit");
+  check(paytoy_engine_push(engine, PAYTOY_WITHDRAWAL, 1, 2, "4") == PAYTOY_OK, "withdrawal");
+  check(paytoy_engine_get_account(engine, 1, &account), "account");
+  check(strcmp(account.available, "6") == 0, "available while pending");
+  check(strcmp(account.pending_out, "4") == 0, "pending_out while pending");
+  check(strcmp(account.total, "10") == 0, "total while pending");
+
+  check(paytoy_engine_push(engine, PAYTOY_SETTLE, 1, 2, NULL) == PAYTOY_OK, "settle");
+  check(paytoy_engine_get_account(engine, 1, &account), "account");
+  check(strcmp(account.pending_out, "0") == 0, "pending_out once settled");
+  check(strcmp(account.total, "6") == 0, "total once settled");
+
+  check(paytoy_engine_push(engine, PAYTOY_SETTLE, 1, 2, NULL) == PAYTOY_REJECTED, "settle again");
+  check(error_is(engine, "not_pending"), "settle again error");
+  paytoy_engine_free(engine);
+
+  engine = paytoy_engine_new();
+  check(paytoy_engine_push(engine, PAYTOY_DEPOSIT, 1, 1, "10") == PAYTOY_OK, "deposit");
+  check(paytoy_engine_push(engine, PAYTOY_WITHDRAWAL, 1, 2, "4") == PAYTOY_OK, "withdrawal");
+  check(paytoy_engine_push(engine, PAYTOY_SETTLE, 1, 2, NULL) == PAYTOY_REJECTED, "settle");
+  check(error_is(engine, "not_pending"), "settle error");
+  paytoy_engine_free(engine);
+
+  return failures == 0 ? 0 : 1;
+}
@@ -0,0 +1,46 @@
+//! Builds the C programs of `tests/c` against `include/paytoy.h` and runs them, with the
+//! cdylib of this build. The header has the default widths of the ids, so they're skipped
+//! with the `client-id-*` and `tx-id-u64` features
+#![cfg(all(
+    feature = "ffi",
+    not(any(
+        feature = "client-id-u32",
+        feature = "client-id-u64",
+        feature = "tx-id-u64"
+    ))
+))]
+
+use std::{env, path::Path, process::Command};
+
+fn run_c(name: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // The cdylib is built next to the binaries of the tests
+    let deps = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg(root.join("tests/c").join(format!("{}.c", name)))
+        .arg("-o")
+        .arg(&program)
+        .arg("-L")
+        .arg(&deps)
+        .arg("-lpaytoy")
+        .status()
+        .expect("no C compiler");
+    assert!(status.success(), "{}.c doesn't build", name);
+
+    let status = Command::new(&program)
+        .env("LD_LIBRARY_PATH", &deps)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{}.c failed", name);
+}
+
+#[test]
+fn test_settle() {
+    run_c("settle");
+}